@@ -0,0 +1,292 @@
+// `montague bench`: a load-testing client along the lines of dnsperf. It sends the queries from a
+// file to a server over and over, keeping a set number outstanding, and reports throughput,
+// latency percentiles, and how the responses' rcodes were spread out. Each outstanding query gets
+// its own socket and thread, which is plenty to saturate a toy server.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::dns::protocol::{
+    name_from_string, DnsClass, DnsFlags, DnsOpcode, DnsPacket, DnsQuestion, DnsRCode, DnsRRType,
+};
+use super::Result;
+
+const USAGE: &str = "Usage: montague bench SERVER[:PORT] QUERY_FILE [OPTIONS]
+Sends the queries in QUERY_FILE, one \"NAME TYPE\" per line, to SERVER again
+and again and reports how it kept up. Lines starting with ; or # are ignored.
+
+  --concurrency=N     queries to keep outstanding at once (default 10)
+  --duration=SECONDS  how long to run for (default 10)
+  --timeout=MS        how long to wait before counting a query as lost
+                      (default 1000)";
+
+pub fn run(args: &[String]) -> Result<()> {
+    let mut positional = Vec::new();
+    let mut concurrency = 10;
+    let mut duration = Duration::from_secs(10);
+    let mut timeout = Duration::from_millis(1000);
+    for arg in args {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            _ if arg.starts_with("--concurrency=") => {
+                concurrency = arg["--concurrency=".len()..].parse()?;
+            }
+            _ if arg.starts_with("--duration=") => {
+                duration = Duration::from_secs(arg["--duration=".len()..].parse()?);
+            }
+            _ if arg.starts_with("--timeout=") => {
+                timeout = Duration::from_millis(arg["--timeout=".len()..].parse()?);
+            }
+            _ if arg.starts_with("--") => return Err(USAGE.into()),
+            _ => positional.push(arg),
+        }
+    }
+    let (server, path) = match positional[..] {
+        [server, path] => (server, path),
+        _ => return Err(USAGE.into()),
+    };
+    let server = match server.parse::<SocketAddr>() {
+        Ok(server) => server,
+        Err(_) => SocketAddr::new(server.parse::<IpAddr>()?, 53),
+    };
+    if concurrency == 0 {
+        return Err("--concurrency has to be at least 1".into());
+    }
+    let workload = Arc::new(parse_workload(&fs::read_to_string(path)?)?);
+    if workload.is_empty() {
+        return Err(format!("{} has no queries in it", path).into());
+    }
+
+    println!(
+        "Sending {} queries to {} with {} outstanding for {}s",
+        workload.len(),
+        server,
+        concurrency,
+        duration.as_secs()
+    );
+    let next = Arc::new(AtomicUsize::new(0));
+    let start = Instant::now();
+    let deadline = start + duration;
+    let workers: Vec<_> = (0..concurrency)
+        .map(|_| {
+            let workload = Arc::clone(&workload);
+            let next = Arc::clone(&next);
+            thread::spawn(move || send_until(server, &workload, &next, deadline, timeout))
+        })
+        .collect();
+    let mut results = Results::default();
+    for worker in workers {
+        results.merge(worker.join().map_err(|_| "A benchmark thread panicked")??);
+    }
+    print!("{}", results.report(start.elapsed()));
+    Ok(())
+}
+
+// Each query as it goes on the wire, apart from its ID
+fn parse_workload(text: &str) -> Result<Vec<Vec<u8>>> {
+    let mut queries = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (name, qtype) = match fields[..] {
+            [name] => (name, DnsRRType::A),
+            [name, qtype] => (
+                name,
+                qtype
+                    .parse()
+                    .map_err(|e| format!("line {}: {}", number + 1, e))?,
+            ),
+            _ => return Err(format!("line {}: expected NAME TYPE", number + 1).into()),
+        };
+        let qname = name_from_string(name).map_err(|e| format!("line {}: {}", number + 1, e))?;
+        queries.push(query_bytes(qname, qtype));
+    }
+    Ok(queries)
+}
+
+// A recursive query for one question, with an ID of 0 to be filled in when it's sent
+pub fn query_bytes(qname: Vec<String>, qtype: DnsRRType) -> Vec<u8> {
+    DnsPacket {
+        id: 0,
+        flags: DnsFlags {
+            qr_bit: false,
+            opcode: DnsOpcode::Query,
+            aa_bit: false,
+            tc_bit: false,
+            rd_bit: true,
+            ra_bit: false,
+            ad_bit: false,
+            cd_bit: false,
+            rcode: DnsRCode::NoError,
+        },
+        questions: vec![DnsQuestion {
+            qname,
+            qtype,
+            qclass: DnsClass::IN,
+        }],
+        answers: Vec::new(),
+        nameservers: Vec::new(),
+        addl_recs: Vec::new(),
+    }
+    .to_bytes()
+}
+
+// Send queries one at a time until the deadline, taking turns through the workload with the
+// other threads
+fn send_until(
+    server: SocketAddr,
+    workload: &[Vec<u8>],
+    next: &AtomicUsize,
+    deadline: Instant,
+    timeout: Duration,
+) -> std::result::Result<Results, String> {
+    let local = match server {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let socket = UdpSocket::bind((local, 0)).map_err(|e| e.to_string())?;
+    socket.connect(server).map_err(|e| e.to_string())?;
+    let mut results = Results::default();
+    let mut id: u16 = 0;
+    let mut buf = [0u8; 65535];
+    while Instant::now() < deadline {
+        let mut query = workload[next.fetch_add(1, Ordering::Relaxed) % workload.len()].to_owned();
+        id = id.wrapping_add(1);
+        query[..2].copy_from_slice(&id.to_be_bytes());
+        let sent = Instant::now();
+        socket.send(&query).map_err(|e| e.to_string())?;
+        results.sent += 1;
+        // Answers to queries we've already given up on can still turn up, so skip anything
+        // that isn't for this one
+        loop {
+            let waited = sent.elapsed();
+            if waited >= timeout {
+                results.lost += 1;
+                break;
+            }
+            socket
+                .set_read_timeout(Some(timeout - waited))
+                .map_err(|e| e.to_string())?;
+            let size = match socket.recv(&mut buf) {
+                Ok(size) => size,
+                Err(_) => continue,
+            };
+            if size < 12 || buf[..2] != id.to_be_bytes() {
+                continue;
+            }
+            results.answered(sent.elapsed(), &buf[..size]);
+            break;
+        }
+    }
+    Ok(results)
+}
+
+// What happened to the queries sent, for `bench` and `replay` to report on
+#[derive(Default)]
+pub struct Results {
+    pub sent: usize,
+    pub lost: usize,
+    latencies: Vec<Duration>,
+    rcodes: BTreeMap<String, usize>,
+}
+
+impl Results {
+    pub fn answered(&mut self, latency: Duration, response: &[u8]) {
+        self.latencies.push(latency);
+        // The rcode is the low four bits of the fourth byte, whether or not the rest parses
+        let rcode = match DnsPacket::from_bytes(response) {
+            Ok(response) => format!("{:?}", response.flags.rcode),
+            Err(_) => format!("unparseable (rcode {})", response[3] & 0xf),
+        };
+        *self.rcodes.entry(rcode).or_insert(0) += 1;
+    }
+
+    pub fn merge(&mut self, other: Results) {
+        self.sent += other.sent;
+        self.lost += other.lost;
+        self.latencies.extend(other.latencies);
+        for (rcode, count) in other.rcodes {
+            *self.rcodes.entry(rcode).or_insert(0) += count;
+        }
+    }
+
+    pub fn report(&mut self, elapsed: Duration) -> String {
+        self.latencies.sort();
+        let completed = self.latencies.len();
+        let share = |count: usize| 100.0 * count as f64 / self.sent.max(1) as f64;
+        let mut report = format!(
+            "Queries sent:       {}\n\
+             Queries completed:  {} ({:.2}%)\n\
+             Queries lost:       {} ({:.2}%)\n\
+             Run time:           {:.2}s\n\
+             Queries per second: {:.1}\n",
+            self.sent,
+            completed,
+            share(completed),
+            self.lost,
+            share(self.lost),
+            elapsed.as_secs_f64(),
+            completed as f64 / elapsed.as_secs_f64(),
+        );
+        if completed > 0 {
+            let ms = |latency: Duration| latency.as_secs_f64() * 1000.0;
+            report.push_str(&format!(
+                "Latency (ms):       min {:.3}, p50 {:.3}, p90 {:.3}, p99 {:.3}, max {:.3}\n",
+                ms(self.latencies[0]),
+                ms(percentile(&self.latencies, 50.0)),
+                ms(percentile(&self.latencies, 90.0)),
+                ms(percentile(&self.latencies, 99.0)),
+                ms(self.latencies[completed - 1]),
+            ));
+            report.push_str("Response codes:\n");
+            for (rcode, count) in &self.rcodes {
+                report.push_str(&format!(
+                    "  {:<20}{} ({:.2}%)\n",
+                    rcode,
+                    count,
+                    100.0 * *count as f64 / completed as f64
+                ));
+            }
+        }
+        report
+    }
+}
+
+// The nearest-rank percentile of some sorted, non-empty samples
+fn percentile(sorted: &[Duration], percent: f64) -> Duration {
+    let rank = (percent / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn workloads_and_percentiles() {
+        let workload =
+            parse_workload("; a comment\nexample.com A\n\nexample.com mx\nexample.org\n").unwrap();
+        assert_eq!(workload.len(), 3);
+        let query = DnsPacket::from_bytes(&workload[1]).unwrap();
+        assert_eq!(query.questions[0].qtype, DnsRRType::MX);
+        assert!(query.flags.rd_bit);
+        assert!(parse_workload("example.com BOGUS\n").is_err());
+
+        let samples: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&samples, 50.0), Duration::from_millis(50));
+        assert_eq!(percentile(&samples, 99.0), Duration::from_millis(99));
+        assert_eq!(percentile(&samples, 100.0), Duration::from_millis(100));
+        assert_eq!(percentile(&samples[..1], 1.0), Duration::from_millis(1));
+    }
+}
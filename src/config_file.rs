@@ -0,0 +1,114 @@
+// Server settings from a file, given with --config=PATH, so a long list of flags doesn't have to
+// live in a service definition. Each line is a flag without its leading dashes, and with its value
+// after an equals sign, which can have spaces around it and be quoted:
+//
+//     # Answer the LAN, and forward to a resolver over TLS
+//     listen = 192.168.1.1:53
+//     listen = 192.168.1.1:53/tcp
+//     upstream = "9.9.9.9:853#dns.quad9.net"
+//     sandbox
+//
+// The flags are read as if they'd been given in the file's place on the command line, so flags
+// that can be repeated add to those in the file, and ones that can't override them if they come
+// after.
+
+use std::fs;
+
+const FLAG: &str = "--config=";
+
+// The arguments with every --config replaced by the flags in its file
+pub fn expand(args: &[String]) -> Result<Vec<String>, String> {
+    let mut expanded = Vec::new();
+    for arg in args {
+        match arg.strip_prefix(FLAG) {
+            Some(path) => {
+                let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+                expanded.extend(parse(&text).map_err(|e| format!("{}: {}", path, e))?);
+            }
+            None => expanded.push(arg.to_owned()),
+        }
+    }
+    Ok(expanded)
+}
+
+fn parse(text: &str) -> Result<Vec<String>, String> {
+    let mut flags = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        // Only whole lines are comments, since values like upstreams can have a # in them
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let flag = parse_line(line).map_err(|e| format!("line {}: {}", number + 1, e))?;
+        flags.push(flag);
+    }
+    Ok(flags)
+}
+
+fn parse_line(line: &str) -> Result<String, String> {
+    let (name, value) = match line.split_once('=') {
+        Some((name, value)) => (name.trim_end(), Some(value.trim_start())),
+        None => (line, None),
+    };
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+    if !valid || name.starts_with('-') {
+        return Err(format!("{:?} isn't a setting", name));
+    }
+    if name == &FLAG[2..FLAG.len() - 1] {
+        return Err(String::from("config files can't include others"));
+    }
+    match value {
+        None => Ok(format!("--{}", name)),
+        Some(value) => {
+            let unquoted = value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value);
+            Ok(format!("--{}={}", name, unquoted))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_become_flags() {
+        let text = r#"
+# The LAN listener
+listen = 192.168.1.1:53?allow=192.168.1.0/24
+upstream="9.9.9.9:853#dns.quad9.net"
+  sandbox
+zone-default-ttl = example.test=300
+"#;
+        assert_eq!(
+            parse(text).unwrap(),
+            vec![
+                "--listen=192.168.1.1:53?allow=192.168.1.0/24",
+                "--upstream=9.9.9.9:853#dns.quad9.net",
+                "--sandbox",
+                "--zone-default-ttl=example.test=300",
+            ]
+        );
+        assert!(parse("--sandbox").unwrap_err().starts_with("line 1"));
+        assert!(parse("\nlisten 127.0.0.1:53").is_err());
+        assert!(parse("config = other.conf").is_err());
+
+        let path = std::env::temp_dir().join(format!("montague-{}.conf", std::process::id()));
+        fs::write(&path, "listen = 127.0.0.1:53\n").unwrap();
+        let args = vec![
+            format!("--config={}", path.display()),
+            String::from("--sandbox"),
+        ];
+        assert_eq!(
+            expand(&args).unwrap(),
+            vec!["--listen=127.0.0.1:53", "--sandbox"]
+        );
+        fs::remove_file(&path).unwrap();
+        assert!(expand(&args).is_err());
+    }
+}
@@ -0,0 +1,96 @@
+// `montague decode`: parse a DNS message given as hex or base64 and print it. Handy for turning
+// packet captures (or a hex dump from --debug-packets) into something readable.
+
+use std::fs;
+use std::io::{self, Read};
+use std::process;
+
+use super::dns::protocol;
+use super::Result;
+
+const USAGE: &str = "Usage: montague decode [HEX_OR_BASE64 | -f FILE]
+Decodes a DNS message in wire format, given as hex or base64. Reads from stdin
+if no message or file is given. Whitespace in the input is ignored.";
+
+pub fn run(args: &[String]) -> Result<()> {
+    let input = match args {
+        [] => {
+            let mut input = String::new();
+            io::stdin().read_to_string(&mut input)?;
+            input
+        }
+        [flag, path] if flag == "-f" || flag == "--file" => fs::read_to_string(path)?,
+        [flag] if flag == "-h" || flag == "--help" => {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+        [message] => message.to_owned(),
+        _ => return Err(USAGE.into()),
+    };
+
+    let bytes = parse_input(&input)?;
+    match protocol::DnsPacket::from_bytes(&bytes) {
+        Ok(packet) => {
+            print!("{}", packet);
+            Ok(())
+        }
+        Err(e) => {
+            // Show how far we got; the annotation stops right where the parse went wrong
+            eprintln!("{}", protocol::hex_dump(&bytes));
+            eprintln!("{}", protocol::annotate_packet(&bytes));
+            // Returning the error would print its Debug form, partial packet and all
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+// Figure out if the input is hex or base64 and decode it. Anything that's entirely hex digits is
+// treated as hex; a base64 string being only hex digits is possible, but unlikely for a message
+// long enough to have a DNS header.
+fn parse_input(input: &str) -> Result<Vec<u8>> {
+    let compact: String = input.chars().filter(|c| !c.is_whitespace()).collect();
+    if compact.is_empty() {
+        return Err("No message to decode".into());
+    }
+    if compact.chars().all(|c| c.is_ascii_hexdigit()) {
+        return decode_hex(&compact);
+    }
+    // Accept both the standard and URL-safe alphabets, since DoH uses the latter
+    let config = if compact.contains('-') || compact.contains('_') {
+        base64::URL_SAFE_NO_PAD
+    } else {
+        base64::STANDARD
+    };
+    match base64::decode_config(compact.trim_end_matches('='), config.pad(false)) {
+        Ok(bytes) => Ok(bytes),
+        Err(e) => Err(format!("Input is neither hex nor valid base64: {}", e).into()),
+    }
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return Err(format!("Hex input has an odd number of digits ({})", hex.len()).into());
+    }
+    let mut bytes = Vec::with_capacity(hex.len() / 2);
+    for i in (0..hex.len()).step_by(2) {
+        bytes.push(u8::from_str_radix(&hex[i..i + 2], 16)?);
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_input_works() {
+        let expected = vec![0x00u8, 0x2a, 0x01, 0x00];
+        assert_eq!(parse_input("002a0100").unwrap(), expected);
+        assert_eq!(parse_input("00 2a\n01 00\n").unwrap(), expected);
+        assert_eq!(parse_input("ACoBAA==").unwrap(), expected);
+        assert_eq!(parse_input("ACoBAA").unwrap(), expected);
+        assert!(parse_input("002a010").is_err());
+        assert!(parse_input("not!base64").is_err());
+    }
+}
@@ -0,0 +1,231 @@
+// Who's allowed to do the things to a zone that aren't for just anyone, like transferring or
+// updating it. Nobody is by default. A zone lists the addresses allowed, and can also require
+// requests be signed with a TSIG key.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::pipeline::error_response;
+use super::protocol::{
+    add_tsig_error, name_to_string, names_equal, verify_tsig, DnsPacket, DnsRCode, NameKey,
+    SignedQuery, TsigError, TsigKey,
+};
+
+// A range of addresses, like 192.0.2.0/24. A bare address is a range of one.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct AddressPrefix {
+    address: IpAddr,
+    length: u8,
+}
+
+impl AddressPrefix {
+    // The first `length` bits of `address`, with the rest cleared so that equal prefixes compare
+    // equal. Lengths past the end of the address are taken as the whole of it.
+    pub fn new(address: IpAddr, length: u8) -> AddressPrefix {
+        let (address, length) = match address {
+            IpAddr::V4(ip) => {
+                let length = length.min(32);
+                let ip = u32::from(ip) & v4_mask(length);
+                (IpAddr::V4(Ipv4Addr::from(ip)), length)
+            }
+            IpAddr::V6(ip) => {
+                let length = length.min(128);
+                let ip = u128::from(ip) & v6_mask(length);
+                (IpAddr::V6(Ipv6Addr::from(ip)), length)
+            }
+        };
+        AddressPrefix { address, length }
+    }
+
+    pub fn length(&self) -> u8 {
+        self.length
+    }
+
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self.address, address) {
+            (IpAddr::V4(prefix), IpAddr::V4(address)) => {
+                let mask = v4_mask(self.length);
+                u32::from(prefix) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(prefix), IpAddr::V6(address)) => {
+                let mask = v6_mask(self.length);
+                u128::from(prefix) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+
+    // Whether every address in `other` is in this range too
+    pub fn covers(&self, other: &AddressPrefix) -> bool {
+        self.length <= other.length && self.contains(other.address)
+    }
+}
+
+fn v4_mask(length: u8) -> u32 {
+    u32::MAX.checked_shl(32 - length as u32).unwrap_or(0)
+}
+
+fn v6_mask(length: u8) -> u128 {
+    u128::MAX.checked_shl(128 - length as u32).unwrap_or(0)
+}
+
+impl FromStr for AddressPrefix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<AddressPrefix, String> {
+        let (address, length) = match s.split_once('/') {
+            Some((address, length)) => (address, Some(length)),
+            None => (s, None),
+        };
+        let address: IpAddr = address
+            .parse()
+            .map_err(|e| format!("Bad address {:?}: {}", address, e))?;
+        let max = if address.is_ipv4() { 32 } else { 128 };
+        let length = match length {
+            Some(length) => length
+                .parse()
+                .ok()
+                .filter(|length| *length <= max)
+                .ok_or_else(|| format!("Bad prefix length in {:?}", s))?,
+            None => max,
+        };
+        Ok(AddressPrefix { address, length })
+    }
+}
+
+// Who may do something to one zone
+#[derive(Clone, Debug, Default)]
+struct ZoneAcl {
+    allow: Vec<AddressPrefix>,
+    key: Option<Vec<String>>,
+}
+
+#[derive(Clone, Default)]
+pub struct AccessPolicy {
+    acls: HashMap<NameKey, ZoneAcl>,
+    keys: Vec<TsigKey>,
+}
+
+impl AccessPolicy {
+    pub fn new(keys: Vec<TsigKey>) -> AccessPolicy {
+        AccessPolicy {
+            acls: HashMap::new(),
+            keys,
+        }
+    }
+
+    pub fn allow(&mut self, zone: &[String], prefixes: Vec<AddressPrefix>) {
+        let acl = self.acls.entry(NameKey::new(zone)).or_default();
+        acl.allow.extend(prefixes);
+    }
+
+    // Requests for the zone have to be signed with the named key
+    pub fn require_key(&mut self, zone: &[String], key: &[String]) -> Result<(), String> {
+        if !self.keys.iter().any(|known| names_equal(&known.name, key)) {
+            return Err(format!("No TSIG key named {}", name_to_string(key)));
+        }
+        let acl = self.acls.entry(NameKey::new(zone)).or_default();
+        acl.key = Some(key.to_owned());
+        Ok(())
+    }
+
+    // Whether a client can do this to a zone. The request's signature, if it has one that checks
+    // out, is returned so the response can be signed with the same key.
+    pub fn check(
+        &self,
+        zone: &[String],
+        message: &[u8],
+        client: IpAddr,
+        now: u64,
+    ) -> Result<Option<SignedQuery>, Refusal> {
+        let acl = self
+            .acls
+            .get(&NameKey::new(zone))
+            .filter(|acl| acl.allow.iter().any(|prefix| prefix.contains(client)))
+            .ok_or_else(|| Refusal {
+                rcode: DnsRCode::Refused,
+                tsig_error: None,
+                reason: String::from("address not allowed"),
+            })?;
+        let signed = match verify_tsig(message, &self.keys, now) {
+            Ok(signed) => Some(signed),
+            Err(TsigError::Unsigned) if acl.key.is_none() => None,
+            Err(e) => {
+                return Err(Refusal {
+                    rcode: DnsRCode::NotAuth,
+                    reason: e.to_string(),
+                    tsig_error: Some(e),
+                })
+            }
+        };
+        match (&acl.key, signed) {
+            (Some(required), Some(signed)) if !names_equal(required, &signed.key.name) => {
+                Err(Refusal {
+                    rcode: DnsRCode::NotAuth,
+                    tsig_error: None,
+                    reason: format!(
+                        "signed with key {} rather than {}",
+                        name_to_string(&signed.key.name),
+                        name_to_string(required)
+                    ),
+                })
+            }
+            (_, signed) => Ok(signed),
+        }
+    }
+}
+
+// Why a request was turned away
+#[derive(Clone, Debug)]
+pub struct Refusal {
+    pub rcode: DnsRCode,
+    // Said back to the client, if its signature was the problem
+    tsig_error: Option<TsigError>,
+    // For the log
+    pub reason: String,
+}
+
+impl Refusal {
+    pub fn response(&self, query: &DnsPacket, message: &[u8], now: u64) -> DnsPacket {
+        let mut response = error_response(query, self.rcode.to_owned());
+        if let Some(e) = &self.tsig_error {
+            add_tsig_error(&mut response, message, e, now);
+        }
+        response
+    }
+}
+
+// Seconds since the epoch, which is what TSIG signatures are timed in
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefixes_match_addresses() {
+        let lan: AddressPrefix = "192.168.1.0/24".parse().unwrap();
+        assert!(lan.contains("192.168.1.77".parse().unwrap()));
+        assert!(!lan.contains("192.168.2.1".parse().unwrap()));
+        assert!(!lan.contains("::1".parse().unwrap()));
+        let one: AddressPrefix = "2001:db8::53".parse().unwrap();
+        assert!(one.contains("2001:db8::53".parse().unwrap()));
+        assert!(!one.contains("2001:db8::54".parse().unwrap()));
+        let all: AddressPrefix = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains("203.0.113.9".parse().unwrap()));
+        assert!("192.0.2.0/33".parse::<AddressPrefix>().is_err());
+
+        // Made from an address, the bits past the prefix don't count
+        let subnet = AddressPrefix::new("192.168.1.77".parse().unwrap(), 24);
+        assert_eq!(subnet, lan);
+        assert!(lan.covers(&AddressPrefix::new("192.168.1.77".parse().unwrap(), 28)));
+        assert!(!lan.covers(&AddressPrefix::new("192.168.1.77".parse().unwrap(), 16)));
+        assert!("lan".parse::<AddressPrefix>().is_err());
+    }
+}
@@ -0,0 +1,309 @@
+// Rolling counts of what's being asked and by whom: the most queried names, the busiest clients,
+// the names getting the most NXDOMAINs, the lookalike names being asked about, and how many
+// queries were blocked, each over the last few minutes or hours. Nothing is written to disk; this
+// is for a look at what the server is doing right now, without the cost (or privacy concerns) of
+// logging every query.
+//
+// Counts are kept in a bucket per minute, so windows are whole minutes and roll forward a minute
+// at a time. Each bucket keeps at most MAX_KEYS names and clients of each kind; past that, the
+// queries are counted but not who or what they were for.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::error::ResolveError;
+use super::pipeline::{Middleware, Next};
+use super::protocol::{name_to_string, DnsPacket, DnsRCode};
+
+const BUCKET: Duration = Duration::from_secs(60);
+const MAX_KEYS: usize = 10_000;
+// How many of each kind of leader to report
+const TOP: usize = 10;
+
+#[derive(Default)]
+struct Counts {
+    counts: HashMap<String, u64>,
+}
+
+impl Counts {
+    fn add(&mut self, key: String) {
+        if let Some(count) = self.counts.get_mut(&key) {
+            *count += 1;
+        } else if self.counts.len() < MAX_KEYS {
+            self.counts.insert(key, 1);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Bucket {
+    // Minutes since the analytics started
+    minute: u64,
+    queries: u64,
+    blocked: u64,
+    names: Counts,
+    clients: Counts,
+    nxdomains: Counts,
+    lookalikes: Counts,
+}
+
+// What happened over one window, leaders most frequent first
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WindowSummary {
+    pub window: Duration,
+    pub queries: u64,
+    pub blocked: u64,
+    pub top_names: Vec<(String, u64)>,
+    pub top_clients: Vec<(String, u64)>,
+    pub top_nxdomains: Vec<(String, u64)>,
+    pub top_lookalikes: Vec<(String, u64)>,
+}
+
+pub struct Analytics {
+    windows: Vec<Duration>,
+    started: Instant,
+    // Oldest first, only as many as the longest window covers
+    buckets: Mutex<VecDeque<Bucket>>,
+}
+
+impl Analytics {
+    // Windows are rounded up to whole minutes
+    pub fn new(windows: &[Duration]) -> Analytics {
+        let mut windows: Vec<Duration> = windows
+            .iter()
+            .map(|window| BUCKET * window.as_secs().div_ceil(BUCKET.as_secs()).max(1) as u32)
+            .collect();
+        windows.sort();
+        windows.dedup();
+        Analytics {
+            windows,
+            started: Instant::now(),
+            buckets: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    // Count a query from `client`. Whatever it asked is counted once it's been answered.
+    pub fn client(&self, client: IpAddr, now: Instant) {
+        self.update(now, |bucket| bucket.clients.add(client.to_string()));
+    }
+
+    // Count a query and how it was answered
+    pub fn answered(
+        &self,
+        query: &DnsPacket,
+        result: &Result<DnsPacket, ResolveError>,
+        now: Instant,
+    ) {
+        let name = match query.questions.first() {
+            Some(question) => name_to_string(&question.qname).to_ascii_lowercase(),
+            None => return,
+        };
+        self.update(now, |bucket| {
+            bucket.queries += 1;
+            match result {
+                Ok(response) if response.flags.rcode == DnsRCode::NXDomain => {
+                    bucket.nxdomains.add(name.to_owned())
+                }
+                Err(ResolveError::PolicyBlocked(_)) => bucket.blocked += 1,
+                _ => (),
+            }
+            bucket.names.add(name);
+        });
+    }
+
+    // Count a query for a name the lookalike check flagged
+    pub fn lookalike(&self, name: String, now: Instant) {
+        self.update(now, |bucket| bucket.lookalikes.add(name));
+    }
+
+    fn update<F: FnOnce(&mut Bucket)>(&self, now: Instant, f: F) {
+        let minute = self.minute(now);
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.back().map(|bucket| bucket.minute) != Some(minute) {
+            buckets.push_back(Bucket {
+                minute,
+                ..Bucket::default()
+            });
+        }
+        let longest = self
+            .windows
+            .last()
+            .map_or(1, |window| window.as_secs() / 60);
+        while buckets
+            .front()
+            .is_some_and(|bucket| bucket.minute + longest <= minute)
+        {
+            buckets.pop_front();
+        }
+        f(buckets.back_mut().unwrap());
+    }
+
+    fn minute(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.started).as_secs() / BUCKET.as_secs()
+    }
+
+    // A summary for each window, shortest first
+    pub fn summaries(&self, now: Instant) -> Vec<WindowSummary> {
+        let minute = self.minute(now);
+        let buckets = self.buckets.lock().unwrap();
+        self.windows
+            .iter()
+            .map(|window| {
+                let minutes = window.as_secs() / 60;
+                let recent: Vec<&Bucket> = buckets
+                    .iter()
+                    .filter(|bucket| bucket.minute + minutes > minute)
+                    .collect();
+                let top = |counts: fn(&Bucket) -> &Counts| {
+                    let mut totals: HashMap<&str, u64> = HashMap::new();
+                    for bucket in &recent {
+                        for (key, count) in &counts(bucket).counts {
+                            *totals.entry(key).or_default() += count;
+                        }
+                    }
+                    let mut totals: Vec<(String, u64)> = totals
+                        .into_iter()
+                        .map(|(key, count)| (key.to_owned(), count))
+                        .collect();
+                    // Ties go alphabetically, so the report doesn't shuffle between runs
+                    totals.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+                    totals.truncate(TOP);
+                    totals
+                };
+                WindowSummary {
+                    window: *window,
+                    queries: recent.iter().map(|bucket| bucket.queries).sum(),
+                    blocked: recent.iter().map(|bucket| bucket.blocked).sum(),
+                    top_names: top(|bucket| &bucket.names),
+                    top_clients: top(|bucket| &bucket.clients),
+                    top_nxdomains: top(|bucket| &bucket.nxdomains),
+                    top_lookalikes: top(|bucket| &bucket.lookalikes),
+                }
+            })
+            .collect()
+    }
+
+    // The summaries as text, for the SIGUSR1 report
+    pub fn report(&self, now: Instant) -> String {
+        let mut out = String::new();
+        for summary in self.summaries(now) {
+            let minutes = summary.window.as_secs() / 60;
+            writeln!(
+                out,
+                "last {}m: {} queries, {} blocked",
+                minutes, summary.queries, summary.blocked
+            )
+            .unwrap();
+            let leaders = [
+                ("names", &summary.top_names),
+                ("clients", &summary.top_clients),
+                ("nxdomain", &summary.top_nxdomains),
+                ("lookalikes", &summary.top_lookalikes),
+            ];
+            for (label, top) in leaders {
+                let top: Vec<String> = top
+                    .iter()
+                    .map(|(key, count)| format!("{} ({})", key, count))
+                    .collect();
+                writeln!(out, "  top {}: {}", label, top.join(", ")).unwrap();
+            }
+        }
+        out
+    }
+}
+
+// Sits just inside the question check, so it sees every well formed query and whatever the rest
+// of the pipeline made of it, errors included
+impl Middleware for Analytics {
+    fn name(&self) -> &str {
+        "analytics"
+    }
+
+    fn handle(&self, query: &DnsPacket, next: Next) -> Result<DnsPacket, ResolveError> {
+        let result = next.run(query);
+        self.answered(query, &result, Instant::now());
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::dns::mock;
+    use crate::dns::protocol::DnsRRType;
+
+    #[test]
+    fn windows_roll_over() {
+        let analytics = Analytics::new(&[Duration::from_secs(300), Duration::from_secs(90)]);
+        let start = analytics.started;
+        let query = |name| mock::query(name, DnsRRType::A);
+        let answer = |name, rcode| {
+            let mut response = mock::query(name, DnsRRType::A);
+            response.flags.rcode = rcode;
+            Ok(response)
+        };
+        let client: IpAddr = "192.0.2.10".parse().unwrap();
+
+        analytics.client(client, start);
+        analytics.answered(
+            &query("Old.example"),
+            &answer("old.example", DnsRCode::NXDomain),
+            start,
+        );
+        let later = start + Duration::from_secs(200);
+        for _ in 0..2 {
+            analytics.client(client, later);
+            analytics.answered(
+                &query("www.example"),
+                &answer("www.example", DnsRCode::NoError),
+                later,
+            );
+        }
+        analytics.lookalike(String::from("xn--pypal-4ve.example."), later);
+        analytics.answered(
+            &query("ads.example"),
+            &Err(ResolveError::PolicyBlocked(String::from("ads"))),
+            later,
+        );
+
+        let summaries = analytics.summaries(later);
+        // 90 seconds rounds up to two minutes
+        assert_eq!(summaries[0].window, Duration::from_secs(120));
+        assert_eq!(summaries[0].queries, 3);
+        assert_eq!(summaries[0].blocked, 1);
+        assert!(summaries[0].top_nxdomains.is_empty());
+        assert_eq!(
+            summaries[0].top_names,
+            vec![
+                (String::from("www.example."), 2),
+                (String::from("ads.example."), 1)
+            ]
+        );
+        assert_eq!(summaries[1].queries, 4);
+        assert_eq!(
+            summaries[1].top_nxdomains,
+            vec![(String::from("old.example."), 1)]
+        );
+        assert_eq!(
+            summaries[1].top_clients,
+            vec![(String::from("192.0.2.10"), 3)]
+        );
+        assert_eq!(
+            summaries[0].top_lookalikes,
+            vec![(String::from("xn--pypal-4ve.example."), 1)]
+        );
+        assert!(analytics
+            .report(later)
+            .contains("  top clients: 192.0.2.10 (3)\n"));
+
+        // Once the longest window has passed, the old buckets are gone
+        let much_later = start + Duration::from_secs(600);
+        analytics.client(client, much_later);
+        assert_eq!(analytics.buckets.lock().unwrap().len(), 1);
+        assert_eq!(analytics.summaries(much_later)[1].queries, 0);
+    }
+}
@@ -0,0 +1,201 @@
+// An async version of the client API in resolver.rs. Lookups return futures instead of blocking,
+// so they can be awaited from tokio (or any other executor) without tying up one of its worker
+// threads. Under the hood each lookup runs the ordinary Resolver on its own thread, the same way
+// the server handles each query, and wakes the future when it's done; that keeps us independent
+// of any particular runtime, and means the async and sync APIs share everything below them.
+
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use super::error::ResolveError;
+use super::protocol::{DnsPacket, DnsRRType};
+use super::recursive::RootHints;
+use super::resolver::{MxRecord, Resolver};
+
+pub type LookupResult<T> = Result<T, ResolveError>;
+
+#[derive(Clone, Debug)]
+pub struct AsyncResolver {
+    resolver: Resolver,
+}
+
+impl AsyncResolver {
+    pub fn recursive() -> AsyncResolver {
+        AsyncResolver::new(Resolver::recursive())
+    }
+
+    pub fn with_root_hints(hints: RootHints) -> AsyncResolver {
+        AsyncResolver::new(Resolver::with_root_hints(hints))
+    }
+
+    pub fn upstream(addr: SocketAddr) -> AsyncResolver {
+        AsyncResolver::new(Resolver::upstream(addr))
+    }
+
+    // Wrap an existing Resolver; lookups through either go the same place
+    pub fn new(resolver: Resolver) -> AsyncResolver {
+        AsyncResolver { resolver }
+    }
+
+    pub fn lookup_ip(&self, name: &str) -> Lookup<Vec<IpAddr>> {
+        let name = name.to_owned();
+        self.spawn(move |resolver| resolver.lookup_ip(&name))
+    }
+
+    pub fn lookup_mx(&self, name: &str) -> Lookup<Vec<MxRecord>> {
+        let name = name.to_owned();
+        self.spawn(move |resolver| resolver.lookup_mx(&name))
+    }
+
+    pub fn lookup_txt(&self, name: &str) -> Lookup<Vec<String>> {
+        let name = name.to_owned();
+        self.spawn(move |resolver| resolver.lookup_txt(&name))
+    }
+
+    pub fn reverse_lookup(&self, ip: IpAddr) -> Lookup<Vec<String>> {
+        self.spawn(move |resolver| resolver.reverse_lookup(ip))
+    }
+
+    pub fn query(&self, name: &str, qtype: DnsRRType) -> Lookup<DnsPacket> {
+        let name = name.to_owned();
+        self.spawn(move |resolver| resolver.query(&name, qtype))
+    }
+
+    fn spawn<T, F>(&self, lookup: F) -> Lookup<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Resolver) -> LookupResult<T> + Send + 'static,
+    {
+        let shared = Arc::new(Mutex::new(Shared {
+            result: None,
+            waker: None,
+        }));
+        let resolver = self.resolver.to_owned();
+        let thread_shared = shared.clone();
+        thread::spawn(move || {
+            let result = lookup(&resolver);
+            let mut shared = thread_shared.lock().unwrap();
+            shared.result = Some(result);
+            if let Some(waker) = shared.waker.take() {
+                waker.wake();
+            }
+        });
+        Lookup { shared }
+    }
+}
+
+// A lookup in progress. Dropping it doesn't cancel the lookup, it just discards the result.
+pub struct Lookup<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+struct Shared<T> {
+    result: Option<LookupResult<T>>,
+    // The waker from the most recent poll, if the lookup wasn't done yet
+    waker: Option<Waker>,
+}
+
+impl<T> Future for Lookup<T> {
+    type Output = LookupResult<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<LookupResult<T>> {
+        let mut shared = self.shared.lock().unwrap();
+        match shared.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                shared.waker = Some(cx.waker().to_owned());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+    use std::task::Wake;
+    use std::thread::Thread;
+
+    use crate::dns::mock::{self, Behavior, MockNetwork, Script};
+    use crate::dns::protocol::{DnsClass, DnsRCode, DnsRecordData, DnsResourceRecord, SoaData};
+
+    const ROOT: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 2);
+
+    // Just enough of an executor to run one future to completion on this thread
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn lookups_complete() {
+        let ip = Ipv4Addr::new(192, 0, 2, 1);
+        let soa = DnsResourceRecord {
+            name: mock::labels("example.com"),
+            rr_type: DnsRRType::SOA,
+            class: DnsClass::IN,
+            ttl: 300,
+            record: DnsRecordData::SOA(SoaData {
+                mname: mock::labels("ns.example.com"),
+                rname: mock::labels("hostmaster.example.com"),
+                serial: 1,
+                refresh: 3600,
+                retry: 600,
+                expire: 86400,
+                minimum: 300,
+            }),
+        };
+        let network = MockNetwork::new();
+        let _root = network.serve(
+            ROOT,
+            Script::new()
+                .on(
+                    "www.example.com",
+                    Some(DnsRRType::A),
+                    Behavior::Answer(vec![mock::a("www.example.com", ip)]),
+                )
+                // No IPv6 address (NODATA)
+                .on(
+                    "www.example.com",
+                    Some(DnsRRType::AAAA),
+                    Behavior::NoData(vec![soa.to_owned()]),
+                )
+                .on("nope.example.com", None, Behavior::NXDomain(vec![])),
+        );
+        let resolver = AsyncResolver::with_root_hints(network.hints(ROOT));
+
+        // Both lookups are in flight at once
+        let found = resolver.lookup_ip("www.example.com");
+        let missing = resolver.lookup_ip("nope.example.com");
+        assert_eq!(block_on(found).unwrap(), vec![IpAddr::V4(ip)]);
+        let error = block_on(missing).unwrap_err();
+        assert!(matches!(error, ResolveError::NotFound(_)));
+
+        let nodata = block_on(resolver.query("www.example.com", DnsRRType::AAAA)).unwrap();
+        assert_eq!(nodata.flags.rcode, DnsRCode::NoError);
+        assert!(nodata.answers.is_empty());
+        assert_eq!(nodata.nameservers, vec![soa]);
+    }
+}
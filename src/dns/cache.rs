@@ -0,0 +1,501 @@
+// A cache of complete responses, keyed by question. Entries expire with the smallest TTL in the
+// response, and the cache is bounded both by entry count and by an approximate memory budget;
+// when either is exceeded, the least recently used entries go first. Each response's records are
+// kept by RRset, so an RRset that came with mixed TTLs is cached with the lowest of them (RFC
+// 2181 section 5.2) and counts down as one.
+//
+// An answer an authority scoped to a client subnet with EDNS Client Subnet (RFC 7871) is only for
+// clients in that subnet, so it's cached under the subnet as well as the question, and a client
+// gets the answer scoped to the most specific subnet it's in. Answers without a scope are for
+// everyone.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use super::access::AddressPrefix;
+use super::protocol::{
+    client_subnet, DnsPacket, DnsQuestion, DnsRCode, DnsRRType, DnsResourceRecord, NameKey, RRset,
+};
+
+// Rough per-entry cost on top of the response's wire size: the key, map slots, and the parsed
+// packet's allocations. Only needs to be in the right ballpark for the memory budget to mean
+// something.
+const ENTRY_OVERHEAD: usize = 256;
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+struct CacheKey {
+    name: NameKey,
+    qtype: u16,
+    qclass: u16,
+    // The client subnet the answer is scoped to, if it isn't for everyone
+    scope: Option<AddressPrefix>,
+}
+
+impl CacheKey {
+    fn new(question: &DnsQuestion) -> CacheKey {
+        CacheKey {
+            name: NameKey::new(&question.qname),
+            qtype: question.qtype as u16,
+            qclass: question.qclass.to_u16(),
+            scope: None,
+        }
+    }
+
+    fn scoped(&self, scope: Option<AddressPrefix>) -> CacheKey {
+        CacheKey {
+            scope,
+            ..self.to_owned()
+        }
+    }
+}
+
+struct Entry {
+    question: DnsQuestion,
+    // The response with its records taken out, except for any OPT, which isn't part of an RRset
+    // and whose TTL field isn't a TTL
+    response: DnsPacket,
+    answers: Vec<RRset>,
+    authority: Vec<RRset>,
+    additional: Vec<RRset>,
+    inserted: Instant,
+    expires: Instant,
+    size: usize,
+    // Position in the recency order; higher is more recent
+    last_used: u64,
+}
+
+impl Entry {
+    // The response with its TTLs counted down by the time it's been cached
+    fn counted_down(&self, now: Instant) -> DnsPacket {
+        let elapsed = now.duration_since(self.inserted).as_secs() as u32;
+        let records = |sets: &[RRset]| -> Vec<DnsResourceRecord> {
+            sets.iter()
+                .flat_map(|set| {
+                    let mut set = set.to_owned();
+                    set.ttl = set.ttl.saturating_sub(elapsed);
+                    set.to_records()
+                })
+                .collect()
+        };
+        let mut response = self.response.to_owned();
+        response.answers = records(&self.answers);
+        response.nameservers = records(&self.authority);
+        let opt = std::mem::replace(&mut response.addl_recs, records(&self.additional));
+        response.addl_recs.extend(opt);
+        response
+    }
+}
+
+// Counters for the metrics surface. These only ever go up.
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub insertions: u64,
+    // Entries dropped to stay under the entry or memory limit
+    pub evictions: u64,
+    // Entries dropped because their TTL ran out
+    pub expirations: u64,
+}
+
+pub struct Cache {
+    max_entries: usize,
+    max_memory: usize,
+    entries: HashMap<CacheKey, Entry>,
+    // The subnets each question has scoped answers cached for, by its unscoped key
+    scopes: HashMap<CacheKey, Vec<AddressPrefix>>,
+    // Keys by last use, least recent first
+    recency: BTreeMap<u64, CacheKey>,
+    clock: u64,
+    memory: usize,
+    stats: CacheStats,
+}
+
+impl Cache {
+    // A limit of zero on either entries or memory disables caching
+    pub fn new(max_entries: usize, max_memory: usize) -> Cache {
+        Cache {
+            max_entries,
+            max_memory,
+            entries: HashMap::new(),
+            scopes: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+            memory: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    // The cached response to `question`, with TTLs counted down by the time it's been cached. For
+    // a client in `subnet`, that's the one scoped to the most specific subnet covering it, or else
+    // the one for everyone (RFC 7871 section 7.3.1).
+    pub fn get(
+        &mut self,
+        question: &DnsQuestion,
+        subnet: Option<&AddressPrefix>,
+        now: Instant,
+    ) -> Option<DnsPacket> {
+        let key = CacheKey::new(question);
+        let mut scopes: Vec<AddressPrefix> = match (subnet, self.scopes.get(&key)) {
+            (Some(subnet), Some(scopes)) => scopes
+                .iter()
+                .filter(|scope| scope.covers(subnet))
+                .copied()
+                .collect(),
+            _ => Vec::new(),
+        };
+        scopes.sort_by_key(|scope| std::cmp::Reverse(scope.length()));
+        let candidates = scopes
+            .into_iter()
+            .map(Some)
+            .chain([None])
+            .map(|scope| key.scoped(scope));
+
+        for key in candidates {
+            let expired = match self.entries.get(&key) {
+                None => continue,
+                Some(entry) => entry.expires <= now,
+            };
+            if expired {
+                self.remove(&key);
+                self.stats.expirations += 1;
+                continue;
+            }
+
+            self.clock += 1;
+            let clock = self.clock;
+            let entry = self.entries.get_mut(&key).unwrap();
+            self.recency.remove(&entry.last_used);
+            self.recency.insert(clock, key);
+            entry.last_used = clock;
+            self.stats.hits += 1;
+            return Some(entry.counted_down(now));
+        }
+        self.stats.misses += 1;
+        None
+    }
+
+    // Like `get`, for everyone, but without counting towards the stats or making the entry any
+    // less likely to be evicted. For looking around the cache rather than answering from it.
+    pub fn peek(&self, question: &DnsQuestion, now: Instant) -> Option<DnsPacket> {
+        self.entries
+            .get(&CacheKey::new(question))
+            .filter(|entry| entry.expires > now)
+            .map(|entry| entry.counted_down(now))
+    }
+
+    // Every unexpired entry as a question and the response to it, least recently used first.
+    // Inserting them in this order into another cache leaves it in the same state.
+    pub fn entries(&self, now: Instant) -> Vec<(DnsQuestion, DnsPacket)> {
+        self.recency
+            .values()
+            .map(|key| &self.entries[key])
+            .filter(|entry| entry.expires > now)
+            .map(|entry| (entry.question.to_owned(), entry.counted_down(now)))
+            .collect()
+    }
+
+    // Cache `response` as the answer to `question`, if it's the kind of response worth caching.
+    // It's for the subnet it's scoped to, if it has a client subnet option saying so.
+    pub fn insert(&mut self, question: &DnsQuestion, response: &DnsPacket, now: Instant) {
+        let ttl = match cache_ttl(response) {
+            Some(ttl) if ttl > 0 => ttl,
+            _ => return,
+        };
+        let size = response.wire_size() + ENTRY_OVERHEAD;
+        if self.max_entries == 0 || size > self.max_memory {
+            return;
+        }
+
+        let mut stored = response.to_owned();
+        let answers = RRset::group(std::mem::take(&mut stored.answers));
+        let authority = RRset::group(std::mem::take(&mut stored.nameservers));
+        let (opt, additional): (Vec<DnsResourceRecord>, Vec<DnsResourceRecord>) =
+            std::mem::take(&mut stored.addl_recs)
+                .into_iter()
+                .partition(|rr| rr.rr_type == DnsRRType::OPT);
+        stored.addl_recs = opt;
+
+        let key = CacheKey::new(question).scoped(response_scope(response));
+        self.remove(&key);
+        if let Some(scope) = key.scope {
+            self.scopes.entry(key.scoped(None)).or_default().push(scope);
+        }
+        self.clock += 1;
+        self.recency.insert(self.clock, key.to_owned());
+        self.entries.insert(
+            key,
+            Entry {
+                question: question.to_owned(),
+                response: stored,
+                answers,
+                authority,
+                additional: RRset::group(additional),
+                inserted: now,
+                expires: now + Duration::from_secs(ttl as u64),
+                size,
+                last_used: self.clock,
+            },
+        );
+        self.memory += size;
+        self.stats.insertions += 1;
+
+        while self.entries.len() > self.max_entries || self.memory > self.max_memory {
+            let oldest = match self.recency.keys().next() {
+                Some(clock) => *clock,
+                None => break,
+            };
+            let key = self.recency[&oldest].to_owned();
+            self.remove(&key);
+            self.stats.evictions += 1;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Approximate bytes used by cached entries
+    pub fn memory(&self) -> usize {
+        self.memory
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.last_used);
+            self.memory -= entry.size;
+        }
+        if let Some(scope) = key.scope {
+            let unscoped = key.scoped(None);
+            if let Some(scopes) = self.scopes.get_mut(&unscoped) {
+                scopes.retain(|cached| *cached != scope);
+                if scopes.is_empty() {
+                    self.scopes.remove(&unscoped);
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Cache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Cache")
+            .field("entries", &self.entries.len())
+            .field("memory", &self.memory)
+            .field("stats", &self.stats)
+            .finish()
+    }
+}
+
+// The client subnet an answer is for. A scope of zero means it's the same for everyone.
+fn response_scope(response: &DnsPacket) -> Option<AddressPrefix> {
+    client_subnet(response)
+        .filter(|subnet| subnet.scope_prefix > 0)
+        .map(|subnet| AddressPrefix::new(subnet.address, subnet.scope_prefix))
+}
+
+// How long a response can be cached: the smallest TTL of any record in it. Only successful
+// answers and NXDOMAINs are cached; anything else is likely to be a transient failure. A
+// response with no records at all has nothing to take a TTL from, so it isn't cached either.
+fn cache_ttl(response: &DnsPacket) -> Option<u32> {
+    match response.flags.rcode {
+        DnsRCode::NoError | DnsRCode::NXDomain => (),
+        _ => return None,
+    }
+    response
+        .answers
+        .iter()
+        .chain(response.nameservers.iter())
+        .map(|rr| rr.ttl)
+        .min()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    use crate::dns::mock;
+    use crate::dns::protocol::{opt_record, ClientSubnet, DnsRecordData, EdnsOption};
+
+    fn response(name: &str, ttl: u32) -> (DnsQuestion, DnsPacket) {
+        let question = mock::question(name, DnsRRType::A);
+        let mut response = DnsPacket::from_bytes(&[0x00, 0x2a, 0x81, 0x80, 0, 0, 0, 0, 0, 0, 0, 0])
+            .expect("header should parse");
+        response.questions = vec![question.to_owned()];
+        let mut record = mock::a(name, Ipv4Addr::new(192, 0, 2, 1));
+        record.ttl = ttl;
+        response.answers = vec![record];
+        (question, response)
+    }
+
+    #[test]
+    fn entries_expire() {
+        let mut cache = Cache::new(10, 1 << 20);
+        let now = Instant::now();
+        let (question, answer) = response("www.example.com", 60);
+        cache.insert(&question, &answer, now);
+
+        // Case doesn't matter, and TTLs count down
+        let cached = cache
+            .get(
+                &mock::question("WWW.example.com", DnsRRType::A),
+                None,
+                now + Duration::from_secs(20),
+            )
+            .expect("should be cached");
+        assert_eq!(cached.answers[0].ttl, 40);
+        assert!(cache
+            .get(
+                &mock::question("www.example.com", DnsRRType::AAAA),
+                None,
+                now
+            )
+            .is_none());
+
+        assert!(cache
+            .get(&question, None, now + Duration::from_secs(60))
+            .is_none());
+        assert_eq!(cache.len(), 0);
+        assert_eq!(cache.memory(), 0);
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 1,
+                misses: 2,
+                insertions: 1,
+                evictions: 0,
+                expirations: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn records_are_cached_by_rrset() {
+        let mut cache = Cache::new(10, 1 << 20);
+        let now = Instant::now();
+        let (question, mut answer) = response("www.example.com", 60);
+        let mut second = mock::a("www.example.com", Ipv4Addr::new(192, 0, 2, 2));
+        second.ttl = 30;
+        answer.answers.push(second.to_owned());
+        // A duplicate, which the RRset only keeps once
+        answer.answers.push(answer.answers[0].to_owned());
+        answer
+            .addl_recs
+            .push(mock::a("ns.example.com", Ipv4Addr::new(192, 0, 2, 53)));
+        cache.insert(&question, &answer, now);
+
+        let cached = cache
+            .get(&question, None, now + Duration::from_secs(10))
+            .expect("should be cached");
+        let ttls: Vec<u32> = cached.answers.iter().map(|rr| rr.ttl).collect();
+        assert_eq!(ttls, vec![20, 20]);
+        assert_eq!(cached.answers[1].record, second.record);
+        assert_eq!(cached.addl_recs[0].ttl, 3590);
+    }
+
+    #[test]
+    fn answers_are_scoped_to_client_subnets() {
+        let mut cache = Cache::new(10, 1 << 20);
+        let now = Instant::now();
+        // An answer of 192.0.2.N, scoped by the authority to the first `scope` bits of `subnet`
+        let answer = |last: u8, subnet: &str, scope: u8| {
+            let (question, mut answer) = response("www.example.com", 60);
+            answer.answers[0].record = DnsRecordData::A(Ipv4Addr::new(192, 0, 2, last));
+            let mut opt = opt_record(1232);
+            opt.record = DnsRecordData::OPT(vec![EdnsOption::ClientSubnet(ClientSubnet {
+                address: subnet.parse().unwrap(),
+                source_prefix: 24,
+                scope_prefix: scope,
+            })]);
+            answer.addl_recs.push(opt);
+            (question, answer)
+        };
+        let (question, everyone) = answer(1, "0.0.0.0", 0);
+        cache.insert(&question, &everyone, now);
+        cache.insert(&question, &answer(24, "198.51.100.0", 24).1, now);
+        cache.insert(&question, &answer(16, "198.51.0.0", 16).1, now);
+        assert_eq!(cache.len(), 3);
+
+        let mut ask = |subnet: Option<&str>| {
+            let subnet = subnet.map(|subnet| subnet.parse::<AddressPrefix>().unwrap());
+            let cached = cache.get(&question, subnet.as_ref(), now).unwrap();
+            match cached.answers[0].record {
+                DnsRecordData::A(ip) => ip.octets()[3],
+                _ => unreachable!(),
+            }
+        };
+        // The most specific scope covering the client wins
+        assert_eq!(ask(Some("198.51.100.7")), 24);
+        assert_eq!(ask(Some("198.51.7.0/24")), 16);
+        // A client that only says which /16 it's in can't have an answer for one /24 of it
+        assert_eq!(ask(Some("198.51.100.0/16")), 16);
+        assert_eq!(ask(Some("203.0.113.0/24")), 1);
+        assert_eq!(ask(None), 1);
+
+        // Scopes go with their entries
+        let later = now + Duration::from_secs(60);
+        let subnet = "198.51.100.7".parse::<AddressPrefix>().unwrap();
+        assert!(cache.get(&question, Some(&subnet), later).is_none());
+        assert!(cache.scopes.is_empty());
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let now = Instant::now();
+        let (a, a_answer) = response("a.test", 60);
+        let (b, b_answer) = response("b.test", 60);
+        let (c, c_answer) = response("c.test", 60);
+
+        let mut cache = Cache::new(2, 1 << 20);
+        cache.insert(&a, &a_answer, now);
+        cache.insert(&b, &b_answer, now);
+        // Touching a makes b the least recently used
+        assert!(cache.get(&a, None, now).is_some());
+        cache.insert(&c, &c_answer, now);
+        assert!(cache.get(&b, None, now).is_none());
+        assert!(cache.get(&a, None, now).is_some());
+        assert!(cache.get(&c, None, now).is_some());
+        assert_eq!(cache.stats().evictions, 1);
+        let names: Vec<String> = cache
+            .entries(now)
+            .iter()
+            .map(|(question, _)| question.to_string())
+            .collect();
+        assert_eq!(names, vec![a.to_string(), c.to_string()]);
+
+        // Same again, but limited by memory instead of entry count
+        let entry_size = a_answer.wire_size() + ENTRY_OVERHEAD;
+        let mut cache = Cache::new(100, entry_size * 2);
+        cache.insert(&a, &a_answer, now);
+        cache.insert(&b, &b_answer, now);
+        cache.insert(&c, &c_answer, now);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.memory() <= entry_size * 2);
+        assert!(cache.get(&a, None, now).is_none());
+        assert_eq!(cache.stats().evictions, 1);
+    }
+
+    #[test]
+    fn zero_limits_disable_caching() {
+        let now = Instant::now();
+        let (question, answer) = response("www.example.com", 60);
+        let mut cache = Cache::new(0, 1 << 20);
+        cache.insert(&question, &answer, now);
+        assert!(cache.get(&question, None, now).is_none());
+        let mut cache = Cache::new(10, 0);
+        cache.insert(&question, &answer, now);
+        assert!(cache.get(&question, None, now).is_none());
+    }
+}
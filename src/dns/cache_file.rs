@@ -0,0 +1,297 @@
+// The cache written out as text, so a new instance can start with what an old one had cached, and
+// so the cache can be looked through with grep and friends. Records are in master file format;
+// lines starting with $ say which entry and section the records after them belong to:
+//
+//     ; montague cache
+//     $EXPORTED 1760745600
+//     $ENTRY www.example.com. IN A NOERROR aa
+//     $ANSWER
+//     www.example.com.	40	IN	CNAME	example.com.
+//     example.com.	40	IN	A	192.0.2.1
+//     $AUTHORITY
+//     $ADDITIONAL
+//
+// $EXPORTED is when the file was written, in seconds since the epoch. TTLs are what was left of
+// them then, and are counted down further by however long the file sits before it's imported.
+// Each $ENTRY is a cached response: the question it answers, its response code (NOERROR or
+// NXDOMAIN, the only ones cached), and which of the aa and ad flags it had. Records of types the
+// master file parser doesn't know, or in classes other than IN, use the RFC 3597 generic syntax.
+
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::protocol::{
+    name_from_string, name_to_string, DnsClass, DnsFlags, DnsOpcode, DnsPacket, DnsQuestion,
+    DnsRCode, DnsRRType, DnsRecordData, DnsResourceRecord,
+};
+use super::zone;
+
+pub fn write(entries: &[(DnsQuestion, DnsPacket)], now: SystemTime) -> String {
+    let mut text = String::from("; montague cache\n");
+    writeln!(text, "$EXPORTED {}", seconds(now)).unwrap();
+    for (question, response) in entries {
+        let rcode = match response.flags.rcode {
+            DnsRCode::NXDomain => "NXDOMAIN",
+            _ => "NOERROR",
+        };
+        write!(
+            text,
+            "$ENTRY {} {} {} {}",
+            name_to_string(&question.qname),
+            question.qclass,
+            question.qtype,
+            rcode
+        )
+        .unwrap();
+        if response.flags.aa_bit {
+            text.push_str(" aa");
+        }
+        if response.flags.ad_bit {
+            text.push_str(" ad");
+        }
+        text.push('\n');
+        let sections = [
+            ("$ANSWER", &response.answers),
+            ("$AUTHORITY", &response.nameservers),
+            ("$ADDITIONAL", &response.addl_recs),
+        ];
+        for (directive, records) in sections {
+            writeln!(text, "{}", directive).unwrap();
+            // OPT isn't really a record, and gets made fresh for each response anyway
+            for rr in records.iter().filter(|rr| rr.rr_type != DnsRRType::OPT) {
+                writeln!(text, "{}", record_line(rr)).unwrap();
+            }
+        }
+    }
+    text
+}
+
+fn record_line(rr: &DnsResourceRecord) -> String {
+    if rr.class == DnsClass::IN && zone::is_supported_type(rr.rr_type) {
+        return rr.to_string();
+    }
+    let data = match &rr.record {
+        DnsRecordData::Other(data) => data.to_owned(),
+        _ => rr.record.to_bytes(),
+    };
+    format!(
+        "{}\t{}\tCLASS{}\tTYPE{}\t{}",
+        name_to_string(&rr.name),
+        rr.ttl,
+        rr.class.to_u16(),
+        rr.rr_type as u16,
+        DnsRecordData::Other(data)
+    )
+}
+
+// The entries in a cache file, least recently used first, with their TTLs counted down to `now`
+pub fn parse(text: &str, now: SystemTime) -> Result<Vec<(DnsQuestion, DnsPacket)>, String> {
+    let mut elapsed = 0;
+    let mut entries: Vec<(DnsQuestion, DnsPacket)> = Vec::new();
+    // 0 for answers, 1 for authority and 2 for additional
+    let mut section = None;
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with(';') {
+            continue;
+        }
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let result = match tokens[0] {
+            "$EXPORTED" => match tokens[1..] {
+                [time] => time
+                    .parse::<u64>()
+                    .map(|time| elapsed = seconds(now).saturating_sub(time))
+                    .map_err(|_| format!("{} isn't a time", time)),
+                _ => Err(String::from("$EXPORTED needs a time")),
+            },
+            "$ENTRY" => parse_entry(&tokens[1..]).map(|entry| {
+                entries.push(entry);
+                section = None;
+            }),
+            "$ANSWER" => {
+                section = Some(0);
+                Ok(())
+            }
+            "$AUTHORITY" => {
+                section = Some(1);
+                Ok(())
+            }
+            "$ADDITIONAL" => {
+                section = Some(2);
+                Ok(())
+            }
+            directive if directive.starts_with('$') => {
+                Err(format!("{} isn't supported", directive))
+            }
+            _ => match (entries.last_mut(), section) {
+                (Some((_, response)), Some(section)) => {
+                    parse_record(line, &tokens).map(|mut rr| {
+                        rr.ttl = rr.ttl.saturating_sub(elapsed as u32);
+                        let records = match section {
+                            0 => &mut response.answers,
+                            1 => &mut response.nameservers,
+                            _ => &mut response.addl_recs,
+                        };
+                        records.push(rr);
+                    })
+                }
+                _ => Err(String::from("Record outside of an entry's sections")),
+            },
+        };
+        result.map_err(|e| format!("line {}: {}", number + 1, e))?;
+    }
+    Ok(entries)
+}
+
+fn parse_entry(tokens: &[&str]) -> Result<(DnsQuestion, DnsPacket), String> {
+    let (qname, qclass, qtype, rcode, flags) = match tokens {
+        [qname, qclass, qtype, rcode, flags @ ..] => (qname, qclass, qtype, rcode, flags),
+        _ => return Err(String::from("$ENTRY needs NAME CLASS TYPE RCODE [FLAGS]")),
+    };
+    let question = DnsQuestion {
+        qname: name_from_string(qname)?,
+        qtype: qtype.parse()?,
+        qclass: parse_class(qclass)?,
+    };
+    let rcode = match *rcode {
+        "NOERROR" => DnsRCode::NoError,
+        "NXDOMAIN" => DnsRCode::NXDomain,
+        _ => return Err(format!("{} responses aren't cached", rcode)),
+    };
+    let mut response = DnsPacket {
+        id: 0,
+        flags: DnsFlags {
+            qr_bit: true,
+            opcode: DnsOpcode::Query,
+            aa_bit: false,
+            tc_bit: false,
+            rd_bit: false,
+            ra_bit: false,
+            ad_bit: false,
+            cd_bit: false,
+            rcode,
+        },
+        questions: vec![question.to_owned()],
+        answers: Vec::new(),
+        nameservers: Vec::new(),
+        addl_recs: Vec::new(),
+    };
+    for flag in flags {
+        match *flag {
+            "aa" => response.flags.aa_bit = true,
+            "ad" => response.flags.ad_bit = true,
+            _ => return Err(format!("Unknown flag {}", flag)),
+        }
+    }
+    Ok((question, response))
+}
+
+fn parse_record(line: &str, tokens: &[&str]) -> Result<DnsResourceRecord, String> {
+    match tokens {
+        [name, ttl, class, rr_type, "\\#", length, data @ ..] => {
+            let data = data.concat();
+            let bytes = (0..data.len())
+                .step_by(2)
+                .map(|i| {
+                    data.get(i..i + 2)
+                        .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                        .ok_or_else(|| format!("Invalid hex {:?}", data))
+                })
+                .collect::<Result<Vec<u8>, String>>()?;
+            if length.parse() != Ok(bytes.len()) {
+                return Err(format!("Record data isn't {} bytes long", length));
+            }
+            let rr_type: DnsRRType = rr_type.parse()?;
+            // Read the data back the way it would have come off the wire
+            let record = match DnsRecordData::from_bytes(&bytes, 0, &rr_type, bytes.len() as u16) {
+                Ok((record, _)) => record,
+                Err(_) => DnsRecordData::Other(bytes),
+            };
+            Ok(DnsResourceRecord {
+                name: name_from_string(name)?,
+                rr_type,
+                class: parse_class(class)?,
+                ttl: zone::parse_ttl(ttl)?,
+                record,
+            })
+        }
+        _ => zone::parse_record(&[], line),
+    }
+}
+
+// A class mnemonic, or CLASS followed by its number (RFC 3597 section 5)
+fn parse_class(token: &str) -> Result<DnsClass, String> {
+    let upper = token.to_ascii_uppercase();
+    let number = match upper.as_str() {
+        "IN" => 1,
+        "CS" => 2,
+        "CH" => 3,
+        "HS" => 4,
+        _ => upper
+            .strip_prefix("CLASS")
+            .and_then(|n| n.parse().ok())
+            .ok_or_else(|| format!("Unknown class {}", token))?,
+    };
+    DnsClass::from_u16(number).ok_or_else(|| format!("Unsupported class {}", token))
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use crate::dns::mock;
+
+    #[test]
+    fn cache_files_round_trip() {
+        let exported = UNIX_EPOCH + Duration::from_secs(1_760_745_600);
+        let question = mock::question("www.example.com", DnsRRType::A);
+        let mut response = mock::query("www.example.com", DnsRRType::A);
+        response.id = 0;
+        response.flags.qr_bit = true;
+        response.flags.rd_bit = false;
+        response.flags.aa_bit = true;
+        response.answers = vec![
+            mock::cname("www.example.com", "example.com"),
+            mock::a("example.com", Ipv4Addr::new(192, 0, 2, 1)),
+        ];
+        response.nameservers = vec![mock::txt("example.com", &["two words", "\"quoted\""])];
+        // A type the master file parser doesn't know, and a class it doesn't take
+        let mut ds = mock::a("example.com", Ipv4Addr::new(192, 0, 2, 1));
+        ds.rr_type = DnsRRType::DS;
+        ds.record = DnsRecordData::Other(vec![0x12, 0x34, 8, 2]);
+        let mut chaos = mock::a("example.com", Ipv4Addr::new(192, 0, 2, 2));
+        chaos.class = DnsClass::CH;
+        response.addl_recs = vec![ds, chaos];
+        let mut nxdomain = mock::query("gone.example.com", DnsRRType::AAAA);
+        nxdomain.id = 0;
+        nxdomain.flags.qr_bit = true;
+        nxdomain.flags.rd_bit = false;
+        nxdomain.flags.rcode = DnsRCode::NXDomain;
+        let entries = vec![
+            (question, response),
+            (nxdomain.questions[0].to_owned(), nxdomain),
+        ];
+
+        let text = write(&entries, exported);
+        assert!(text.contains("$ENTRY www.example.com. IN A NOERROR aa\n$ANSWER\n"));
+        assert!(text.contains("\tCLASS1\tTYPE43\t\\# 4 12340802\n"));
+        assert!(text.contains("$ENTRY gone.example.com. IN AAAA NXDOMAIN\n"));
+        assert_eq!(parse(&text, exported), Ok(entries.clone()));
+
+        // Time spent on disk comes off the TTLs
+        let later = parse(&text, exported + Duration::from_secs(100)).unwrap();
+        assert_eq!(later[0].1.answers[0].ttl, entries[0].1.answers[0].ttl - 100);
+
+        assert!(parse("www.example.com. 60 IN A 192.0.2.1", exported).is_err());
+        assert!(parse("$ENTRY example.com. IN A SERVFAIL", exported).is_err());
+    }
+}
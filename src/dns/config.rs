@@ -0,0 +1,293 @@
+// Settings shared by the server and the client API. ResolverConfig says where queries go;
+// ResolverOpts tunes how they're made.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::pki_types::ServerName;
+
+use super::error_report::ErrorReports;
+use super::outbound::{Outbound, Source};
+use super::protocol::DnsResourceRecord;
+use super::recursive::{RootHints, UpstreamSockets};
+use super::ttl::TtlOverrides;
+use super::zone::Zone;
+
+#[derive(Clone, Debug, Default)]
+pub struct ResolverConfig {
+    // Where recursion starts
+    pub root_hints: RootHints,
+    // If there are any, forward every query to these instead of resolving it ourselves. They're
+    // failover groups in priority order; see dns::upstream for how one is picked.
+    pub upstreams: Vec<Vec<Upstream>>,
+    // Zones we answer for authoritatively. These take precedence over everything else.
+    pub zones: Vec<Zone>,
+    // Individual records to answer with as if we were authoritative for them, e.g. names for
+    // hosts on the local network. Names with local data are never looked up elsewhere.
+    pub local_data: Vec<DnsResourceRecord>,
+    // TTLs to use for names instead of the ones their records have
+    pub ttl_overrides: TtlOverrides,
+}
+
+impl ResolverConfig {
+    pub fn recursive(root_hints: RootHints) -> ResolverConfig {
+        ResolverConfig {
+            root_hints,
+            ..ResolverConfig::default()
+        }
+    }
+
+    // A single failover group
+    pub fn upstreams(addrs: &[SocketAddr]) -> ResolverConfig {
+        ResolverConfig {
+            upstreams: vec![addrs.iter().copied().map(Upstream::from).collect()],
+            ..ResolverConfig::default()
+        }
+    }
+}
+
+// A recursive resolver to forward to: plain DNS, or DNS over TLS (RFC 7858) when written with a
+// privacy profile after a #, as in 1.1.1.1:853#cloudflare-dns.com. An address or interface after
+// an @, as in 10.8.0.1:53@wg0, is where queries to it go out from.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Upstream {
+    pub addr: SocketAddr,
+    pub source: Option<Source>,
+    pub tls: Option<PrivacyProfile>,
+}
+
+// How an upstream is asked over TLS, and how hard we insist (RFC 8310 section 5). The strict
+// profile only sends queries to a server that's authenticated, by its certificate being valid for
+// `name`, its public key matching one of `spki_pins`, or both when both are given. The
+// opportunistic profile would rather have that but settles for an unauthenticated server, and if
+// TLS can't be had at all, for plain DNS on port 53.
+//
+// With `dane`, the certificate also has to match one of the TLSA records for the name (RFC 6698)
+// that narrow down which CA-signed certificates are acceptable.
+//
+// Written NAME?OPTION&OPTION..., where the options are `pin=BASE64` for the base64 SHA-256 of the
+// DER encoded SubjectPublicKeyInfo (as in RFC 7469), `dane`, and `opportunistic`. Either of NAME
+// and the options can be left out, though the strict profile needs something to authenticate
+// with, and DANE needs a name to look up.
+#[derive(Clone, PartialEq, Debug)]
+pub struct PrivacyProfile {
+    pub name: Option<String>,
+    pub spki_pins: Vec<Vec<u8>>,
+    pub dane: bool,
+    pub strict: bool,
+}
+
+impl From<SocketAddr> for Upstream {
+    fn from(addr: SocketAddr) -> Upstream {
+        Upstream {
+            addr,
+            source: None,
+            tls: None,
+        }
+    }
+}
+
+impl FromStr for Upstream {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, tls) = match s.split_once('#') {
+            Some((addr, profile)) => (addr, Some(profile.parse()?)),
+            None => (s, None),
+        };
+        let (addr, source) = match addr.split_once('@') {
+            Some((addr, source)) => (addr, Some(source.parse()?)),
+            None => (addr, None),
+        };
+        Ok(Upstream {
+            addr: addr
+                .parse()
+                .map_err(|_| format!("{} isn't an ADDR:PORT", addr))?,
+            source,
+            tls,
+        })
+    }
+}
+
+impl FromStr for PrivacyProfile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, options) = s.split_once('?').unwrap_or((s, ""));
+        let mut profile = PrivacyProfile {
+            name: None,
+            spki_pins: Vec::new(),
+            dane: false,
+            strict: true,
+        };
+        if !name.is_empty() {
+            ServerName::try_from(name).map_err(|_| format!("{} isn't a valid TLS name", name))?;
+            profile.name = Some(name.to_owned());
+        }
+        for option in options.split('&').filter(|option| !option.is_empty()) {
+            match option.split_once('=') {
+                Some(("pin", pin)) => match base64::decode(pin) {
+                    Ok(digest) if digest.len() == 32 => profile.spki_pins.push(digest),
+                    _ => return Err(format!("{} isn't a base64 SHA-256 digest", pin)),
+                },
+                None if option == "dane" => profile.dane = true,
+                None if option == "opportunistic" => profile.strict = false,
+                _ => return Err(format!("Unknown TLS option {}", option)),
+            }
+        }
+        if profile.dane && profile.name.is_none() {
+            return Err(format!("{} needs a name to look up TLSA records for", s));
+        }
+        if profile.strict && profile.name.is_none() && profile.spki_pins.is_empty() {
+            return Err(format!(
+                "{} needs a name or pin to authenticate the upstream with, or to be opportunistic",
+                s
+            ));
+        }
+        Ok(profile)
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.addr)?;
+        if let Some(source) = &self.source {
+            write!(f, "@{}", source)?;
+        }
+        if let Some(profile) = &self.tls {
+            write!(f, "#{}", profile)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for PrivacyProfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut options: Vec<String> = self
+            .spki_pins
+            .iter()
+            .map(|pin| format!("pin={}", base64::encode(pin)))
+            .collect();
+        if self.dane {
+            options.push(String::from("dane"));
+        }
+        if !self.strict {
+            options.push(String::from("opportunistic"));
+        }
+        write!(f, "{}", self.name.as_deref().unwrap_or(""))?;
+        if !options.is_empty() {
+            write!(f, "?{}", options.join("&"))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DnssecMode {
+    // Ignore DNSSEC entirely
+    Off,
+    // Set the AD bit on queries to an upstream so it tells us whether it validated the answer
+    // (RFC 6840 section 5.7), and pass that along. Nothing is validated locally.
+    TrustUpstream,
+}
+
+// Which of a nameserver's addresses to send queries to, when it has both IPv4 and IPv6 ones. Either
+// way, the other family is there to fall back on.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AddressPreference {
+    Ipv4,
+    Ipv6,
+    // Ask over IPv6, and over IPv4 too if there's no answer within a moment, going with whichever
+    // answers first: happy eyeballs (RFC 8305), for queries rather than connections
+    Race,
+}
+
+impl AddressPreference {
+    // Put the family we'd rather use first, keeping the order within each family
+    pub fn sort(self, addresses: &mut [IpAddr]) {
+        let ipv6_first = self != AddressPreference::Ipv4;
+        addresses.sort_by_key(|address| address.is_ipv6() != ipv6_first);
+    }
+}
+
+impl FromStr for AddressPreference {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<AddressPreference, String> {
+        match s {
+            "ipv4" => Ok(AddressPreference::Ipv4),
+            "ipv6" => Ok(AddressPreference::Ipv6),
+            "race" => Ok(AddressPreference::Race),
+            _ => Err(format!(
+                "Unknown address preference {:?} (expected ipv4, ipv6, or race)",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ResolverOpts {
+    // How long to wait for each reply from a nameserver
+    pub timeout: Duration,
+    // How many times to send a query before giving up on a nameserver
+    pub attempts: usize,
+    // Maximum number of cached responses
+    pub cache_size: usize,
+    // Roughly how many bytes the cache may use
+    pub cache_memory: usize,
+    // The largest reply we're prepared to receive over UDP
+    pub edns_payload_size: u16,
+    // Whether lookup_ip lists IPv6 addresses ahead of IPv4 ones
+    pub prefer_ipv6: bool,
+    // Which address family to reach nameservers over
+    pub address_preference: AddressPreference,
+    pub dnssec: DnssecMode,
+    // How often to probe upstreams
+    pub health_check_interval: Duration,
+    // Consecutive failed queries or probes before an upstream is taken out of rotation
+    pub unhealthy_after: u32,
+    // How long every upstream in the active failover group has to be unhealthy before the next
+    // group takes over
+    pub failover_after: Duration,
+    // How long a higher priority group has to be healthy again before it takes back over
+    pub failback_after: Duration,
+    // Whether to ask plain upstreams for encrypted resolvers to use instead (RFC 9462)
+    pub discover_designated: bool,
+    // What queries to other servers go out on over UDP, shared by every copy of the options
+    pub sockets: Arc<UpstreamSockets>,
+    // Where those queries go out from, when it isn't up to the routing table
+    pub outbound: Outbound,
+    // Where to keep track of the errors we report to the agents authorities name (RFC 9567), if
+    // we report them at all
+    pub error_reports: Option<Arc<ErrorReports>>,
+}
+
+impl Default for ResolverOpts {
+    fn default() -> ResolverOpts {
+        ResolverOpts {
+            timeout: Duration::from_secs(5),
+            attempts: 2,
+            cache_size: 1024,
+            cache_memory: 16 * 1024 * 1024,
+            // The size recommended by DNS Flag Day 2020, which avoids IP fragmentation on
+            // practically every path
+            edns_payload_size: 1232,
+            prefer_ipv6: false,
+            address_preference: AddressPreference::Ipv4,
+            dnssec: DnssecMode::Off,
+            health_check_interval: Duration::from_secs(30),
+            unhealthy_after: 3,
+            failover_after: Duration::from_secs(30),
+            failback_after: Duration::from_secs(30),
+            discover_designated: false,
+            sockets: Arc::default(),
+            outbound: Outbound::default(),
+            error_reports: None,
+        }
+    }
+}
@@ -0,0 +1,98 @@
+// What we know about a query besides what's in it: who sent it, how, and when, what EDNS they
+// spoke, and which policies apply to them. It's made once as the query comes in and rides along
+// with it through the pipeline (see `Next::context`), so a stage that needs to know about the
+// client reads it from here rather than it being added to every function on the way down.
+
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use super::protocol::{DnsPacket, EdnsOptions};
+
+// Stages read only what they need of it so far; the rest is for the policy, logging, and metrics
+// stages to come
+#[derive(Clone, Debug)]
+pub struct QueryContext {
+    pub client: SocketAddr,
+    pub transport: Transport,
+    pub received: Instant,
+    // None for queries without an OPT record
+    pub edns: Option<EdnsInfo>,
+    // What the query's log lines are tagged with
+    pub trace_id: u64,
+    // The named set of policies this client gets, when it doesn't get the defaults
+    pub profile: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    // DNS over TLS, which is TCP once the handshake is done
+    Tls,
+    // DNS over HTTPS
+    Https,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct EdnsInfo {
+    pub version: u8,
+    pub payload_size: u16,
+    pub dnssec_ok: bool,
+}
+
+impl QueryContext {
+    // The context of a query just received, before any policy has been looked up for the client
+    pub fn new(
+        query: &DnsPacket,
+        client: SocketAddr,
+        transport: Transport,
+        trace_id: u64,
+    ) -> QueryContext {
+        let edns = EdnsOptions::from_packet(query).map(|edns| EdnsInfo {
+            version: edns.version,
+            payload_size: edns.payload_size,
+            dnssec_ok: edns.dnssec_ok,
+        });
+        QueryContext {
+            client,
+            transport,
+            received: Instant::now(),
+            edns,
+            trace_id,
+            profile: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::dns::mock;
+    use crate::dns::protocol::{opt_record, DnsRRType};
+
+    #[test]
+    fn edns_is_read_from_the_query() {
+        let mut query = mock::query("www.example.com", DnsRRType::A);
+        let client = "192.0.2.1:53000".parse().unwrap();
+        assert_eq!(
+            QueryContext::new(&query, client, Transport::Udp, 7).edns,
+            None
+        );
+
+        let mut opt = opt_record(4096);
+        opt.ttl = 0x8000;
+        query.addl_recs.push(opt);
+        let context = QueryContext::new(&query, client, Transport::Tcp, 7);
+        assert_eq!(
+            context.edns,
+            Some(EdnsInfo {
+                version: 0,
+                payload_size: 4096,
+                dnssec_ok: true
+            })
+        );
+        assert_eq!(context.client, client);
+        assert_eq!(context.profile, None);
+    }
+}
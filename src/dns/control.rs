@@ -0,0 +1,222 @@
+// The control socket: a Unix socket, given with --control=PATH, through which whoever runs the
+// server can change which zones it serves without editing flags and restarting it. Commands are
+// sent a line at a time, and each gets a line back starting with "ok" or "error":
+//
+//   zone add ORIGIN PATH [DEFAULT_TTL]   Load a zone file and serve it, in place of any zone
+//                                        already at ORIGIN
+//   zone remove ORIGIN                   Stop serving a zone
+//   zone reload ORIGIN                   Load a zone again from the file it came from
+//   zone list                            Every zone served, with its serial
+//
+// Changes last until the server stops; they aren't written back anywhere.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use super::protocol::{name_from_string, name_to_string, NameKey};
+use super::resolver::Resolver;
+use super::trace::{error, info, warning};
+use super::zone;
+use super::zone_watch::ZoneFile;
+
+pub struct Control {
+    resolver: Resolver,
+    // Where each zone was loaded from, for reloading it
+    files: Mutex<HashMap<NameKey, ZoneFile>>,
+    // As with --zone-auto-serial, so a reloaded zone's secondaries notice the change
+    bump_serial: bool,
+}
+
+impl Control {
+    pub fn new(resolver: Resolver, files: &[ZoneFile], bump_serial: bool) -> Control {
+        let files = files
+            .iter()
+            .map(|file| (NameKey::new(&file.origin), file.to_owned()))
+            .collect();
+        Control {
+            resolver,
+            files: Mutex::new(files),
+            bump_serial,
+        }
+    }
+
+    // Carry out one command, returning the line to answer it with
+    pub fn handle(&self, line: &str) -> String {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let result = match words.as_slice() {
+            ["zone", args @ ..] => self.zone(args),
+            _ => Err(format!("Unknown command {:?}", line.trim())),
+        };
+        match result {
+            Ok(reply) if reply.is_empty() => String::from("ok"),
+            Ok(reply) => format!("ok {}", reply),
+            Err(e) => format!("error {}", e),
+        }
+    }
+
+    fn zone(&self, args: &[&str]) -> Result<String, String> {
+        match args {
+            ["add", origin, path, rest @ ..] => {
+                let default_ttl = match rest {
+                    [] => None,
+                    [ttl] => Some(zone::parse_ttl(ttl)?),
+                    _ => return Err(String::from("Expected zone add ORIGIN PATH [DEFAULT_TTL]")),
+                };
+                let file = ZoneFile {
+                    origin: name_from_string(origin)?,
+                    path: PathBuf::from(path),
+                    default_ttl,
+                };
+                let serial = self.load(&file)?;
+                info!("Added zone {} from {}", origin, path);
+                self.files
+                    .lock()
+                    .unwrap()
+                    .insert(NameKey::new(&file.origin), file);
+                Ok(format!("serial {}", serial))
+            }
+            ["remove", origin] => {
+                let origin = name_from_string(origin)?;
+                if !self.resolver.remove_zone(&origin) {
+                    return Err(format!("Not serving {}", name_to_string(&origin)));
+                }
+                info!("Removed zone {}", name_to_string(&origin));
+                self.files.lock().unwrap().remove(&NameKey::new(&origin));
+                Ok(String::new())
+            }
+            ["reload", origin] => {
+                let origin = name_from_string(origin)?;
+                let file = self
+                    .files
+                    .lock()
+                    .unwrap()
+                    .get(&NameKey::new(&origin))
+                    .cloned();
+                let file =
+                    file.ok_or_else(|| format!("No zone file for {}", name_to_string(&origin)))?;
+                let serial = self.load(&file)?;
+                info!("Reloaded zone {}", name_to_string(&origin));
+                Ok(format!("serial {}", serial))
+            }
+            ["list"] => Ok(self
+                .resolver
+                .zone_serials()
+                .iter()
+                .map(|(origin, serial)| format!("{}={}", name_to_string(origin), serial))
+                .collect::<Vec<_>>()
+                .join(" ")),
+            _ => Err(String::from("Expected zone add, remove, reload, or list")),
+        }
+    }
+
+    fn load(&self, file: &ZoneFile) -> Result<u32, String> {
+        let zone = file
+            .load()
+            .map_err(|e| format!("{}: {}", file.path.display(), e))?;
+        Ok(self.resolver.replace_zone(zone, self.bump_serial))
+    }
+}
+
+// Take commands on a socket at `path` in the background, for as long as the server runs
+pub fn listen(control: Control, path: &Path) -> io::Result<()> {
+    // A socket left behind by a server that didn't get to clean up would stop us binding
+    if fs::symlink_metadata(path).is_ok() {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    let control = Arc::new(control);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let control = control.clone();
+                    thread::spawn(move || serve(&control, stream));
+                }
+                Err(e) => error!("Error accepting a control connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn serve(control: &Control, stream: UnixStream) {
+    let mut replies = match stream.try_clone() {
+        Ok(replies) => replies,
+        Err(e) => return warning!("Error setting up a control connection: {}", e),
+    };
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        if writeln!(replies, "{}", control.handle(&line)).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    use crate::dns::config::{ResolverConfig, ResolverOpts};
+    use crate::dns::mock;
+    use crate::dns::protocol::{DnsRRType, DnsRecordData};
+
+    #[test]
+    fn zones_come_and_go_on_command() {
+        let dir = std::env::temp_dir().join(format!("montague-control-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("example.test.zone");
+        fs::write(
+            &path,
+            "@ 300 SOA ns hostmaster 7 2 3 4 5\nwww 300 A 192.0.2.1\n",
+        )
+        .unwrap();
+        let resolver = Resolver::new(ResolverConfig::default(), ResolverOpts::default());
+        let control = Control::new(resolver.to_owned(), &[], false);
+        let www = || {
+            let question = mock::question("www.example.test", DnsRRType::A);
+            let response = resolver.answer_locally(&question).unwrap();
+            response.map(|response| response.answers[0].record.to_owned())
+        };
+
+        let add = format!("zone add example.test {}", path.display());
+        assert_eq!(control.handle(&add), "ok serial 7");
+        assert_eq!(www(), Some(DnsRecordData::A(Ipv4Addr::new(192, 0, 2, 1))));
+        assert_eq!(control.handle("zone list"), "ok example.test.=7");
+
+        fs::write(
+            &path,
+            "@ 300 SOA ns hostmaster 8 2 3 4 5\nwww 300 A 192.0.2.2\n",
+        )
+        .unwrap();
+        assert_eq!(control.handle("zone reload EXAMPLE.test"), "ok serial 8");
+        assert_eq!(www(), Some(DnsRecordData::A(Ipv4Addr::new(192, 0, 2, 2))));
+
+        assert_eq!(control.handle("zone remove example.test"), "ok");
+        assert_eq!(www(), None);
+        assert!(control
+            .handle("zone remove example.test")
+            .starts_with("error"));
+        assert!(control
+            .handle("zone reload example.test")
+            .starts_with("error"));
+        assert!(control
+            .handle("zone add example.test /nonexistent.zone")
+            .starts_with("error"));
+        assert!(control.handle("shutdown").starts_with("error"));
+        assert_eq!(control.handle("zone list"), "ok");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
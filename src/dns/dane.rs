@@ -0,0 +1,113 @@
+// DANE (RFC 6698, updated by RFC 7671): TLSA records published in the DNS saying which
+// certificate or public key a TLS server should present. The records are looked up starting from
+// the root rather than through an upstream, since the upstream being checked can't be asked before
+// it's been checked.
+//
+// montague doesn't validate DNSSEC itself yet, so TLSA records are only as trustworthy as the
+// path to the authorities that serve them, rather than covered by the signatures DANE relies on.
+// That's why only the PKIX usages are acted on, to narrow down what the public CAs vouch for;
+// the DANE usages, which would let a resolver hosting its own endpoint do without a CA, have to
+// wait for validation.
+
+use std::convert::TryFrom;
+use std::time::{Duration, Instant};
+
+use rustls::pki_types::CertificateDer;
+use sha2::{Digest, Sha256, Sha512};
+
+use super::config::ResolverOpts;
+use super::protocol::{
+    name_to_string, tlsa_owner, DnsClass, DnsQuestion, DnsRCode, DnsRRType, DnsRecordData,
+};
+use super::recursive::{self, RootHints};
+
+// What a TLSA record constrains (RFC 7218 names)
+pub const PKIX_TA: u8 = 0;
+pub const PKIX_EE: u8 = 1;
+pub const DANE_TA: u8 = 2;
+pub const DANE_EE: u8 = 3;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tlsa {
+    pub usage: u8,
+    // 0 for the whole certificate, 1 for its SubjectPublicKeyInfo
+    pub selector: u8,
+    // 0 for the selected bytes themselves, 1 for their SHA-256, 2 for their SHA-512
+    pub matching_type: u8,
+    pub data: Vec<u8>,
+}
+
+impl Tlsa {
+    pub fn from_bytes(bytes: &[u8]) -> Option<Tlsa> {
+        match bytes {
+            [usage, selector, matching_type, data @ ..] if !data.is_empty() => Some(Tlsa {
+                usage: *usage,
+                selector: *selector,
+                matching_type: *matching_type,
+                data: data.to_vec(),
+            }),
+            _ => None,
+        }
+    }
+
+    // Whether `cert` is the one this record describes. Records with parameters we don't know
+    // match nothing, as RFC 6698 section 4.1 says.
+    pub fn matches(&self, cert: &CertificateDer<'_>) -> bool {
+        let selected = match self.selector {
+            0 => cert.to_vec(),
+            1 => match webpki::EndEntityCert::try_from(cert) {
+                Ok(cert) => cert.subject_public_key_info().to_vec(),
+                Err(_) => return false,
+            },
+            _ => return false,
+        };
+        match self.matching_type {
+            0 => selected == self.data,
+            1 => Sha256::digest(&selected)[..] == self.data[..],
+            2 => Sha512::digest(&selected)[..] == self.data[..],
+            _ => false,
+        }
+    }
+}
+
+// The usable TLSA records for a TLS service over TCP, and how long they can be kept for. A name
+// without any is an error: an upstream that's meant to be checked with DANE and can't be isn't
+// authenticated.
+pub fn lookup(
+    port: u16,
+    name: &[String],
+    hints: &RootHints,
+    opts: &ResolverOpts,
+) -> Result<(Vec<Tlsa>, Instant), String> {
+    let question = DnsQuestion {
+        qname: tlsa_owner(port, "tcp", name),
+        qtype: DnsRRType::TLSA,
+        qclass: DnsClass::IN,
+    };
+    let response = recursive::resolve_question(&question, hints, opts)
+        .map_err(|e| format!("Looking up TLSA records failed: {}", e))?;
+    let mut ttl = u32::MAX;
+    let mut records = Vec::new();
+    for rr in &response.answers {
+        if rr.rr_type != DnsRRType::TLSA {
+            continue;
+        }
+        if let DnsRecordData::Other(bytes) = &rr.record {
+            if let Some(tlsa) = Tlsa::from_bytes(bytes) {
+                ttl = ttl.min(rr.ttl);
+                records.push(tlsa);
+            }
+        }
+    }
+    if response.flags.rcode != DnsRCode::NoError || records.is_empty() {
+        return Err(format!(
+            "No TLSA records for port {} on {}",
+            port,
+            name_to_string(name)
+        ));
+    }
+    Ok((
+        records,
+        Instant::now() + Duration::from_secs(u64::from(ttl)),
+    ))
+}
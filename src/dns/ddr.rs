@@ -0,0 +1,476 @@
+// Discovery of Designated Resolvers (RFC 9462). A resolver we only know a plain DNS address for
+// can tell us where it also answers over an encrypted transport: asked for the SVCB records of
+// _dns.resolver.arpa, it lists its encrypted endpoints with their names, ports, and addresses.
+// We only speak DNS over TLS, so only endpoints offering the "dot" ALPN are of use.
+//
+// Anyone on the path could answer that query, so an endpoint is only used once it's been verified
+// (section 4.2): its certificate has to be valid both for the name the SVCB record gives and for
+// the address of the resolver we asked, which only that resolver's operator could arrange.
+//
+// On the serving side we answer for resolver.arpa ourselves, listing the encrypted endpoints
+// we've been told about. Those are our own TLS listeners or whatever the operator runs in front of
+// us (a TLS terminator, say), with a certificate valid for the name given and for the addresses
+// clients reach us at.
+
+use std::convert::{TryFrom, TryInto};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+use rustls::RootCertStore;
+
+use super::config::ResolverOpts;
+use super::dot::DotPool;
+use super::error::ResolveError;
+use super::pipeline::{error_response, Middleware, Next};
+use super::protocol::{
+    deserialize_name, is_subdomain, name_from_string, name_to_string, names_equal, serialize_name,
+    DnsClass, DnsPacket, DnsQuestion, DnsRCode, DnsRRType, DnsRecordData, DnsResourceRecord,
+};
+use super::recursive::{self, RootHints};
+use super::trace::warning;
+
+pub const DESIGNATION_NAME: &str = "_dns.resolver.arpa";
+const SPECIAL_USE_ZONE: &str = "resolver.arpa";
+// DNS over TLS's port, for endpoints that don't give one
+const DOT_PORT: u16 = 853;
+// How long clients keep our designations before asking again
+const DESIGNATION_TTL: u32 = 300;
+
+// SvcParamKeys (RFC 9460 section 14.3.2)
+const MANDATORY: u16 = 0;
+const ALPN: u16 = 1;
+const PORT: u16 = 3;
+const IPV4_HINT: u16 = 4;
+const IPV6_HINT: u16 = 6;
+// The URI template for DNS over HTTPS (RFC 9461)
+const DOHPATH: u16 = 7;
+
+// One ServiceMode SVCB record for a designated resolver
+#[derive(Clone, Debug, PartialEq)]
+pub struct Designation {
+    pub priority: u16,
+    pub target: Vec<String>,
+    pub alpn: Vec<String>,
+    pub port: Option<u16>,
+    pub addresses: Vec<IpAddr>,
+    pub dohpath: Option<String>,
+}
+
+impl Designation {
+    // None for AliasMode records, which don't apply to DDR, and for records we can't use: ones
+    // that don't parse, or that make a parameter we don't know mandatory
+    pub fn from_bytes(bytes: &[u8]) -> Option<Designation> {
+        let priority = u16::from_be_bytes([*bytes.first()?, *bytes.get(1)?]);
+        if priority == 0 {
+            return None;
+        }
+        let (target, mut pos) = deserialize_name(bytes, 2).ok()?;
+        let mut designation = Designation {
+            priority,
+            target,
+            alpn: Vec::new(),
+            port: None,
+            addresses: Vec::new(),
+            dohpath: None,
+        };
+        while pos < bytes.len() {
+            let key = u16::from_be_bytes([*bytes.get(pos)?, *bytes.get(pos + 1)?]);
+            let length = u16::from_be_bytes([*bytes.get(pos + 2)?, *bytes.get(pos + 3)?]) as usize;
+            let value = bytes.get(pos + 4..pos + 4 + length)?;
+            pos += 4 + length;
+            match key {
+                MANDATORY => {
+                    let understood = value
+                        .chunks(2)
+                        .all(|key| matches!(key, [0, 1] | [0, 3] | [0, 4] | [0, 6] | [0, 7]));
+                    if !understood {
+                        return None;
+                    }
+                }
+                ALPN => {
+                    let mut rest = value;
+                    while let Some((length, tail)) = rest.split_first() {
+                        let id = tail.get(..*length as usize)?;
+                        designation
+                            .alpn
+                            .push(String::from_utf8_lossy(id).into_owned());
+                        rest = &tail[*length as usize..];
+                    }
+                }
+                PORT => designation.port = Some(u16::from_be_bytes(value.try_into().ok()?)),
+                IPV4_HINT => designation.addresses.extend(
+                    value
+                        .chunks_exact(4)
+                        .map(|ip| IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(ip).unwrap()))),
+                ),
+                IPV6_HINT => designation.addresses.extend(
+                    value
+                        .chunks_exact(16)
+                        .map(|ip| IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(ip).unwrap()))),
+                ),
+                DOHPATH => designation.dohpath = Some(String::from_utf8(value.to_vec()).ok()?),
+                _ => (),
+            }
+        }
+        Some(designation)
+    }
+
+    // SvcParams go in increasing order of their keys
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut params: Vec<(u16, Vec<u8>)> = Vec::new();
+        if !self.alpn.is_empty() {
+            let ids = self
+                .alpn
+                .iter()
+                .flat_map(|id| [&[id.len() as u8], id.as_bytes()].concat())
+                .collect();
+            params.push((ALPN, ids));
+        }
+        if let Some(port) = self.port {
+            params.push((PORT, port.to_be_bytes().to_vec()));
+        }
+        let (v4, v6): (Vec<IpAddr>, Vec<IpAddr>) =
+            self.addresses.iter().partition(|ip| ip.is_ipv4());
+        for (key, hints) in [(IPV4_HINT, v4), (IPV6_HINT, v6)] {
+            if !hints.is_empty() {
+                let bytes = hints
+                    .iter()
+                    .flat_map(|ip| match ip {
+                        IpAddr::V4(ip) => ip.octets().to_vec(),
+                        IpAddr::V6(ip) => ip.octets().to_vec(),
+                    })
+                    .collect();
+                params.push((key, bytes));
+            }
+        }
+        if let Some(dohpath) = &self.dohpath {
+            params.push((DOHPATH, dohpath.as_bytes().to_vec()));
+        }
+
+        let mut bytes = self.priority.to_be_bytes().to_vec();
+        bytes.extend(serialize_name(&self.target));
+        for (key, value) in params {
+            bytes.extend(key.to_be_bytes());
+            bytes.extend((value.len() as u16).to_be_bytes());
+            bytes.extend(value);
+        }
+        bytes
+    }
+}
+
+// An encrypted endpoint to designate, written ALPN:NAME[:PORT][/PATH]. The ALPN is dot (DNS over
+// TLS), doq (DNS over QUIC), or h2 or h3 (DNS over HTTPS), which also take the URI template for
+// their path, e.g. h2:dns.example.com/dns-query{?dns}. Without a port, clients use the
+// protocol's default.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Endpoint {
+    pub alpn: String,
+    pub name: Vec<String>,
+    pub port: Option<u16>,
+    pub dohpath: Option<String>,
+}
+
+impl FromStr for Endpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Endpoint, String> {
+        let (alpn, rest) = s
+            .split_once(':')
+            .ok_or_else(|| format!("Expected ALPN:NAME[:PORT][/PATH], got {}", s))?;
+        let (authority, dohpath) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], Some(rest[slash..].to_string())),
+            None => (rest, None),
+        };
+        let (name, port) = match authority.split_once(':') {
+            Some((name, port)) => {
+                let port = port
+                    .parse()
+                    .map_err(|_| format!("Invalid port {} in {}", port, s))?;
+                (name, Some(port))
+            }
+            None => (authority, None),
+        };
+        let name = name_from_string(name)?;
+        if name.is_empty() {
+            return Err(format!("Designated endpoint {} needs a name", s));
+        }
+        match (alpn, &dohpath) {
+            ("dot" | "doq", None) => (),
+            ("h2" | "h3", Some(path)) if path.contains("{?dns}") => (),
+            ("dot" | "doq", Some(_)) => {
+                return Err(format!("Only DNS over HTTPS endpoints have a path: {}", s))
+            }
+            ("h2" | "h3", _) => {
+                return Err(format!(
+                    "DNS over HTTPS endpoints need a path with {{?dns}} in it: {}",
+                    s
+                ))
+            }
+            _ => {
+                return Err(format!(
+                    "Unknown ALPN {} in {}, expected dot, doq, h2, or h3",
+                    alpn, s
+                ))
+            }
+        }
+        Ok(Endpoint {
+            alpn: alpn.to_string(),
+            name,
+            port,
+            dohpath,
+        })
+    }
+}
+
+// Answers for resolver.arpa, which only means something to whichever resolver is asked (RFC
+// 9462 section 6.4), so nothing under it is ever looked up elsewhere. SVCB queries for
+// _dns.resolver.arpa get one record per endpoint, preferring them in the order given; with no
+// endpoints, and for everything else there, the answer is empty.
+pub struct Designations {
+    zone: Vec<String>,
+    name: Vec<String>,
+    records: Vec<DnsResourceRecord>,
+}
+
+impl Designations {
+    pub fn new(endpoints: &[Endpoint]) -> Designations {
+        let name = name_from_string(DESIGNATION_NAME).unwrap();
+        let records = endpoints
+            .iter()
+            .enumerate()
+            .map(|(index, endpoint)| {
+                let designation = Designation {
+                    priority: index as u16 + 1,
+                    target: endpoint.name.to_owned(),
+                    alpn: vec![endpoint.alpn.to_owned()],
+                    port: endpoint.port,
+                    addresses: Vec::new(),
+                    dohpath: endpoint.dohpath.to_owned(),
+                };
+                DnsResourceRecord {
+                    name: name.to_owned(),
+                    rr_type: DnsRRType::SVCB,
+                    class: DnsClass::IN,
+                    ttl: DESIGNATION_TTL,
+                    record: DnsRecordData::Other(designation.to_bytes()),
+                }
+            })
+            .collect();
+        Designations {
+            zone: name_from_string(SPECIAL_USE_ZONE).unwrap(),
+            name,
+            records,
+        }
+    }
+}
+
+impl Middleware for Designations {
+    fn name(&self) -> &str {
+        "designations"
+    }
+
+    fn handle(&self, query: &DnsPacket, next: Next) -> Result<DnsPacket, ResolveError> {
+        let question = &query.questions[0];
+        if !is_subdomain(&question.qname, &self.zone) {
+            return next.run(query);
+        }
+        let designation = names_equal(&question.qname, &self.name);
+        let exists = designation || names_equal(&question.qname, &self.zone);
+        let mut response = error_response(
+            query,
+            if exists {
+                DnsRCode::NoError
+            } else {
+                DnsRCode::NXDomain
+            },
+        );
+        response.flags.aa_bit = true;
+        if designation && question.qtype == DnsRRType::SVCB {
+            response.answers = self.records.to_owned();
+        }
+        Ok(response)
+    }
+}
+
+// Ask `resolver` for its designated resolvers, and return a pool of connections to the first
+// that offers DNS over TLS and verifies, with how long the designation lasts. None if there's
+// nothing to upgrade to.
+pub fn discover(
+    resolver: SocketAddr,
+    roots: RootCertStore,
+    hints: &RootHints,
+    opts: &ResolverOpts,
+) -> Result<Option<(DotPool, Duration)>, String> {
+    let question = DnsQuestion {
+        qname: name_from_string(DESIGNATION_NAME)?,
+        qtype: DnsRRType::SVCB,
+        qclass: DnsClass::IN,
+    };
+    let response = recursive::forward_question(&question, resolver, opts)
+        .map_err(|e| format!("Asking {} for designated resolvers failed: {}", resolver, e))?;
+    let mut designations: Vec<(Designation, u32)> = response
+        .answers
+        .iter()
+        .filter(|rr| rr.rr_type == DnsRRType::SVCB)
+        .filter_map(|rr| match &rr.record {
+            DnsRecordData::Other(bytes) => Designation::from_bytes(bytes).map(|d| (d, rr.ttl)),
+            _ => None,
+        })
+        .filter(|(designation, _)| {
+            designation.alpn.iter().any(|alpn| alpn == "dot") && !designation.target.is_empty()
+        })
+        .collect();
+    designations.sort_by_key(|(designation, _)| designation.priority);
+
+    let probe = DnsQuestion {
+        qname: Vec::new(),
+        qtype: DnsRRType::NS,
+        qclass: DnsClass::IN,
+    };
+    for (designation, ttl) in designations {
+        let name = name_to_string(&designation.target);
+        let name = name.trim_end_matches('.');
+        let addresses = if designation.addresses.is_empty() {
+            lookup_addresses(&designation.target, resolver, opts)
+        } else {
+            designation.addresses.to_owned()
+        };
+        let port = designation.port.unwrap_or(DOT_PORT);
+        for ip in addresses {
+            let addr = SocketAddr::new(ip, port);
+            let pool = DotPool::designated(addr, name, resolver, roots.to_owned(), hints, opts)?;
+            // Connecting is what verifies it
+            match pool.query_tls(&probe, opts) {
+                Ok(_) => return Ok(Some((pool, Duration::from_secs(ttl.into())))),
+                Err(e) => warning!(
+                    "Designated resolver {} at {} didn't verify: {}",
+                    name,
+                    addr,
+                    e
+                ),
+            }
+        }
+    }
+    Ok(None)
+}
+
+// A designation without address hints is reached at its target name's addresses, which the
+// designating resolver can tell us
+fn lookup_addresses(target: &[String], resolver: SocketAddr, opts: &ResolverOpts) -> Vec<IpAddr> {
+    let mut addresses = Vec::new();
+    for qtype in [DnsRRType::A, DnsRRType::AAAA] {
+        let question = DnsQuestion {
+            qname: target.to_vec(),
+            qtype,
+            qclass: DnsClass::IN,
+        };
+        if let Ok(response) = recursive::forward_question(&question, resolver, opts) {
+            addresses.extend(response.answers.iter().filter_map(|rr| match rr.record {
+                DnsRecordData::A(ip) => Some(IpAddr::V4(ip)),
+                DnsRecordData::AAAA(ip) => Some(IpAddr::V6(ip)),
+                _ => None,
+            }));
+        }
+    }
+    addresses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::dns::mock;
+    use crate::dns::pipeline::Pipeline;
+
+    #[test]
+    fn designates_endpoints_for_resolver_arpa() {
+        let endpoints: Vec<Endpoint> = ["dot:dns.example.com", "h2:dns.example.com:8443/q{?dns}"]
+            .iter()
+            .map(|endpoint| endpoint.parse().unwrap())
+            .collect();
+        for bad in [
+            "dns.example.com",
+            "dot:dns.example.com/dns-query{?dns}",
+            "h2:dns.example.com",
+            "h3:dns.example.com/dns-query",
+            "doh:dns.example.com",
+            "dot:dns.example.com:http",
+        ] {
+            assert!(bad.parse::<Endpoint>().is_err(), "{} parsed", bad);
+        }
+        // Nothing after the stage, so anything it passes on is an error
+        let pipeline = Pipeline::new().then(Arc::new(Designations::new(&endpoints)));
+
+        let response =
+            mock::run(&pipeline, &mock::query(DESIGNATION_NAME, DnsRRType::SVCB)).unwrap();
+        assert!(response.flags.aa_bit);
+        let designations: Vec<Designation> = response
+            .answers
+            .iter()
+            .map(|rr| match &rr.record {
+                DnsRecordData::Other(bytes) => Designation::from_bytes(bytes).unwrap(),
+                other => panic!("not SVCB: {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            designations,
+            vec![
+                Designation {
+                    priority: 1,
+                    target: mock::labels("dns.example.com"),
+                    alpn: vec![String::from("dot")],
+                    port: None,
+                    addresses: Vec::new(),
+                    dohpath: None,
+                },
+                Designation {
+                    priority: 2,
+                    target: mock::labels("dns.example.com"),
+                    alpn: vec![String::from("h2")],
+                    port: Some(8443),
+                    addresses: Vec::new(),
+                    dohpath: Some(String::from("/q{?dns}")),
+                },
+            ]
+        );
+
+        let response = mock::run(&pipeline, &mock::query(DESIGNATION_NAME, DnsRRType::A)).unwrap();
+        assert_eq!(response.flags.rcode, DnsRCode::NoError);
+        assert!(response.answers.is_empty());
+        let response = mock::run(
+            &pipeline,
+            &mock::query("other.resolver.arpa", DnsRRType::SVCB),
+        )
+        .unwrap();
+        assert_eq!(response.flags.rcode, DnsRCode::NXDomain);
+        assert!(mock::run(&pipeline, &mock::query("_dns.example.com", DnsRRType::SVCB)).is_err());
+    }
+
+    #[test]
+    fn skips_designations_it_cannot_use() {
+        let designation = Designation {
+            priority: 1,
+            target: mock::labels("dns.example.com"),
+            alpn: vec![String::from("dot"), String::from("doq")],
+            port: Some(853),
+            addresses: vec!["192.0.2.1".parse().unwrap(), "2001:db8::1".parse().unwrap()],
+            dohpath: None,
+        };
+        let bytes = designation.to_bytes();
+        assert_eq!(Designation::from_bytes(&bytes), Some(designation));
+
+        // AliasMode
+        assert_eq!(
+            Designation::from_bytes(&[&[0, 0][..], &bytes[2..]].concat()),
+            None
+        );
+        // A mandatory key we don't know
+        let mandatory = [&bytes[..], &[0, 0, 0, 2, 0, 5]].concat();
+        assert_eq!(Designation::from_bytes(&mandatory), None);
+        // Truncated
+        assert_eq!(Designation::from_bytes(&bytes[..bytes.len() - 1]), None);
+    }
+}
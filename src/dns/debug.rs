@@ -0,0 +1,29 @@
+// Packet debugging. When turned on (with --debug-packets), every packet we send or receive is
+// logged as a hex dump along with a field-by-field breakdown, which is invaluable when some
+// authority out there is sending us something odd.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use super::protocol;
+use super::trace::info;
+
+static PACKET_DEBUG: AtomicBool = AtomicBool::new(false);
+
+pub fn set_packet_debug(enabled: bool) {
+    PACKET_DEBUG.store(enabled, Ordering::Relaxed);
+}
+
+// Log a raw packet if packet debugging is on. `description` says where it came from or is
+// going, e.g. "Received from 127.0.0.1:5353".
+pub fn log_packet(description: &str, bytes: &[u8]) {
+    if !PACKET_DEBUG.load(Ordering::Relaxed) {
+        return;
+    }
+    info!(
+        "{} ({} bytes)\n{}{}",
+        description,
+        bytes.len(),
+        protocol::hex_dump(bytes),
+        protocol::annotate_packet(bytes)
+    );
+}
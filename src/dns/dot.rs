@@ -0,0 +1,664 @@
+// DNS over TLS (RFC 7858) to upstreams that support it. A TLS handshake costs a couple of round
+// trips on top of TCP's, which would more than double the time of a typical forwarded query, so
+// each upstream keeps a few connections open between queries (as RFC 7766 section 6.2.1 suggests)
+// and resumes its TLS session when it does have to reconnect, which skips the certificate
+// exchange and verification.
+//
+// Connections sit idle for at most IDLE_TIMEOUT, well under the time most resolvers keep idle
+// clients around for, so we're the one to close them. A server can still close one first; if a
+// pooled connection fails, the query is retried once on a fresh one.
+//
+// Each upstream has a privacy profile (RFC 8310) saying how its certificate is checked and what
+// happens when it can't be: see PrivacyProfile.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{
+    CertificateError, ClientConfig, ClientConnection, DigitallySignedStruct, HandshakeKind,
+    RootCertStore, SignatureScheme, StreamOwned,
+};
+use sha2::{Digest, Sha256};
+
+use super::config::{DnssecMode, PrivacyProfile, ResolverOpts};
+use super::dane::{self, Tlsa};
+use super::debug;
+use super::error::ResolveError;
+use super::protocol::{name_from_string, DnsPacket, DnsQuestion};
+use super::recursive::{self, RootHints};
+use super::telemetry::{self, SpanKind};
+use super::trace::{log, warning};
+
+// Connections kept open per upstream. More than this can be open at once under load; the extras
+// are closed once their query is answered.
+const MAX_IDLE: usize = 4;
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+pub struct DotPool {
+    addr: SocketAddr,
+    name: ServerName<'static>,
+    // Sessions to resume are kept here, so every connection to the upstream shares one
+    config: Arc<ClientConfig>,
+    // Where to ask over plain DNS when TLS fails, for the opportunistic profile
+    fallback: Option<SocketAddr>,
+    idle_timeout: Duration,
+    // Most recently used last, with when each was last used
+    idle: Mutex<Vec<(TlsStream, Instant)>>,
+}
+
+impl fmt::Debug for DotPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DotPool({}#{})", self.addr, self.name.to_str())
+    }
+}
+
+impl DotPool {
+    // Checks certificates against the Mozilla roots that ship with webpki-roots. TLSA records are
+    // looked up starting at `hints`.
+    pub fn new(
+        addr: SocketAddr,
+        profile: &PrivacyProfile,
+        hints: &RootHints,
+        opts: &ResolverOpts,
+    ) -> Result<DotPool, String> {
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        DotPool::with_roots(addr, profile, roots, hints, opts)
+    }
+
+    pub fn with_roots(
+        addr: SocketAddr,
+        profile: &PrivacyProfile,
+        roots: RootCertStore,
+        hints: &RootHints,
+        opts: &ResolverOpts,
+    ) -> Result<DotPool, String> {
+        DotPool::build(addr, profile, roots, None, hints, opts)
+    }
+
+    // A resolver's designated encrypted endpoint (RFC 9462 section 4.2), whose certificate has to
+    // be valid for the address of the plain resolver that designated it as well as for `name`.
+    // Should TLS to it fail, queries go back to that resolver.
+    pub fn designated(
+        addr: SocketAddr,
+        name: &str,
+        resolver: SocketAddr,
+        roots: RootCertStore,
+        hints: &RootHints,
+        opts: &ResolverOpts,
+    ) -> Result<DotPool, String> {
+        let profile = PrivacyProfile {
+            name: Some(name.to_owned()),
+            spki_pins: Vec::new(),
+            dane: false,
+            strict: true,
+        };
+        let mut pool = DotPool::build(addr, &profile, roots, Some(resolver.ip()), hints, opts)?;
+        pool.fallback = Some(resolver);
+        Ok(pool)
+    }
+
+    fn build(
+        addr: SocketAddr,
+        profile: &PrivacyProfile,
+        roots: RootCertStore,
+        designated_by: Option<IpAddr>,
+        hints: &RootHints,
+        opts: &ResolverOpts,
+    ) -> Result<DotPool, String> {
+        // Without a name there's no SNI to send, and the certificate is checked some other way
+        let name = match &profile.name {
+            Some(name) => ServerName::try_from(name.to_owned())
+                .map_err(|_| format!("{} isn't a valid TLS name", name))?,
+            None => ServerName::IpAddress(addr.ip().into()),
+        };
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut verifier = ProfileVerifier::new(addr, profile, roots, &provider, hints, opts)?;
+        verifier.designated_by = designated_by;
+        let config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth();
+        Ok(DotPool {
+            addr,
+            name,
+            config: Arc::new(config),
+            fallback: if profile.strict {
+                None
+            } else {
+                Some(SocketAddr::new(addr.ip(), 53))
+            },
+            idle_timeout: IDLE_TIMEOUT,
+            idle: Mutex::new(Vec::new()),
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn query(
+        &self,
+        question: &DnsQuestion,
+        opts: &ResolverOpts,
+    ) -> Result<DnsPacket, ResolveError> {
+        let result = self.query_tls(question, opts);
+        match (result, self.fallback) {
+            (Err(e), Some(fallback)) => {
+                warning!(
+                    "Couldn't ask {} over TLS ({}); falling back to plain DNS",
+                    self.addr,
+                    e
+                );
+                recursive::forward_question(question, fallback, opts)
+            }
+            (result, _) => result,
+        }
+    }
+
+    // Without falling back to plain DNS
+    pub fn query_tls(
+        &self,
+        question: &DnsQuestion,
+        opts: &ResolverOpts,
+    ) -> Result<DnsPacket, ResolveError> {
+        let mut span = telemetry::span("dns.send", SpanKind::Client);
+        span.attribute("server.address", self.addr.ip().to_string());
+        span.attribute("server.port", i64::from(self.addr.port()));
+        span.attribute("network.transport", "tcp");
+        span.attribute("network.protocol.name", "dot");
+        span.question(question);
+        // An upstream that validates will only tell us so if we ask (RFC 6840 section 5.7)
+        let authentic_data = opts.dnssec == DnssecMode::TrustUpstream;
+        let query_bytes = recursive::query_packet(question, true, authentic_data, opts).to_bytes();
+        let result = match self.take_idle() {
+            Some(stream) => match self.exchange(stream, &query_bytes, opts) {
+                Ok(response) => {
+                    span.attribute("dns.connection.reused", true);
+                    Ok(response)
+                }
+                Err(e) => {
+                    log!(
+                        "Pooled connection to {} failed ({}); reconnecting",
+                        self.addr,
+                        e
+                    );
+                    self.connect(opts)
+                        .and_then(|stream| self.exchange(stream, &query_bytes, opts))
+                }
+            },
+            None => self
+                .connect(opts)
+                .and_then(|stream| self.exchange(stream, &query_bytes, opts)),
+        };
+        match &result {
+            Ok(response) => {
+                span.attribute("dns.response.code", format!("{:?}", response.flags.rcode))
+            }
+            Err(e) => span.error(&e.to_string()),
+        }
+        result
+    }
+
+    // The most recently used idle connection, closing any that have been idle too long
+    fn take_idle(&self) -> Option<TlsStream> {
+        let mut idle = self.idle.lock().unwrap();
+        let now = Instant::now();
+        idle.retain(|(_, used)| now.saturating_duration_since(*used) < self.idle_timeout);
+        idle.pop().map(|(stream, _)| stream)
+    }
+
+    fn connect(&self, opts: &ResolverOpts) -> Result<TlsStream, ResolveError> {
+        let socket = opts
+            .outbound
+            .binding(self.addr)
+            .connect(self.addr, opts.timeout)?;
+        socket.set_read_timeout(Some(opts.timeout))?;
+        let connection = ClientConnection::new(self.config.clone(), self.name.to_owned())
+            .map_err(|e| ResolveError::Malformed(format!("TLS with {}: {}", self.addr, e)))?;
+        let mut stream = StreamOwned::new(connection, socket);
+        while stream.conn.is_handshaking() {
+            stream.conn.complete_io(&mut stream.sock)?;
+        }
+        let handshake = match stream.conn.handshake_kind() {
+            Some(HandshakeKind::Resumed) => "resumed",
+            _ => "new",
+        };
+        log!(
+            "Connected to {} over TLS ({} session)",
+            self.addr,
+            handshake
+        );
+        Ok(stream)
+    }
+
+    // Send a query and read its reply, then put the connection back in the pool
+    fn exchange(
+        &self,
+        mut stream: TlsStream,
+        query_bytes: &[u8],
+        opts: &ResolverOpts,
+    ) -> Result<DnsPacket, ResolveError> {
+        stream.sock.set_read_timeout(Some(opts.timeout))?;
+        let mut framed = (query_bytes.len() as u16).to_be_bytes().to_vec();
+        framed.extend_from_slice(query_bytes);
+        debug::log_packet(
+            &format!("Sending query to {} over TLS", self.addr),
+            query_bytes,
+        );
+        stream.write_all(&framed)?;
+        stream.flush()?;
+        let mut length = [0u8; 2];
+        stream.read_exact(&mut length)?;
+        let mut reply = vec![0u8; u16::from_be_bytes(length) as usize];
+        stream.read_exact(&mut reply)?;
+        debug::log_packet(
+            &format!("Received reply from {} over TLS", self.addr),
+            &reply,
+        );
+        let response = DnsPacket::from_bytes(&reply)?;
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < MAX_IDLE {
+            idle.push((stream, Instant::now()));
+        }
+        Ok(response)
+    }
+}
+
+// Authenticates an upstream the way its privacy profile says to. The opportunistic profile lets
+// a server that fails through, since an encrypted connection to an unauthenticated server still
+// beats plain DNS against a passive attacker.
+#[derive(Debug)]
+struct ProfileVerifier {
+    addr: SocketAddr,
+    profile: PrivacyProfile,
+    // Only when there's a name to check the certificate against
+    webpki: Option<Arc<WebPkiServerVerifier>>,
+    provider: Arc<CryptoProvider>,
+    // For looking up TLSA records, which are kept until they expire
+    hints: RootHints,
+    opts: ResolverOpts,
+    tlsa: Mutex<Option<(Vec<Tlsa>, Instant)>>,
+    // For a designated resolver, the address of the one that designated it
+    designated_by: Option<IpAddr>,
+}
+
+impl ProfileVerifier {
+    fn new(
+        addr: SocketAddr,
+        profile: &PrivacyProfile,
+        roots: RootCertStore,
+        provider: &Arc<CryptoProvider>,
+        hints: &RootHints,
+        opts: &ResolverOpts,
+    ) -> Result<ProfileVerifier, String> {
+        let webpki = match profile.name {
+            Some(_) => Some(
+                WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                    .build()
+                    .map_err(|e| e.to_string())?,
+            ),
+            None => None,
+        };
+        Ok(ProfileVerifier {
+            addr,
+            profile: profile.to_owned(),
+            webpki,
+            provider: provider.clone(),
+            hints: hints.to_owned(),
+            opts: opts.to_owned(),
+            tlsa: Mutex::new(None),
+            designated_by: None,
+        })
+    }
+
+    fn authenticate(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<(), rustls::Error> {
+        if self.profile.dane {
+            self.authenticate_dane(end_entity, intermediates, server_name, ocsp_response, now)?;
+        } else if let Some(webpki) = &self.webpki {
+            webpki.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                ocsp_response,
+                now,
+            )?;
+        }
+        if !self.profile.spki_pins.is_empty() {
+            let cert = webpki::EndEntityCert::try_from(end_entity)
+                .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+            let digest = Sha256::digest(cert.subject_public_key_info().as_ref());
+            if !self
+                .profile
+                .spki_pins
+                .iter()
+                .any(|pin| pin[..] == digest[..])
+            {
+                return Err(rustls::Error::General(String::from(
+                    "public key doesn't match any pin",
+                )));
+            }
+        }
+        if let Some(ip) = self.designated_by {
+            let cert = webpki::EndEntityCert::try_from(end_entity)
+                .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+            if cert
+                .verify_is_valid_for_subject_name(&ServerName::IpAddress(ip.into()))
+                .is_err()
+            {
+                return Err(rustls::Error::General(format!(
+                    "certificate isn't valid for {}, which designated it",
+                    ip
+                )));
+            }
+        }
+        if self.webpki.is_none() && self.profile.spki_pins.is_empty() {
+            return Err(rustls::Error::General(String::from(
+                "nothing to authenticate it with",
+            )));
+        }
+        Ok(())
+    }
+
+    // Whether the certificate is one the public CAs vouch for and one of the TLSA records
+    // describes. Without DNSSEC validation the records can't be trusted on their own (RFC 7671
+    // section 4), so only the PKIX usages count, and only as a further constraint on what WebPKI
+    // accepts; records with the DANE-TA and DANE-EE usages, which replace the CAs, are ignored.
+    fn authenticate_dane(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<(), rustls::Error> {
+        // A profile with `dane` always has a name, so there's always a WebPKI verifier
+        let webpki = self.webpki.as_ref().ok_or_else(|| {
+            rustls::Error::General(String::from("DANE needs a name to authenticate"))
+        })?;
+        webpki.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        let records = self.tlsa().map_err(rustls::Error::General)?;
+        let authenticated = records.iter().any(|tlsa| match tlsa.usage {
+            dane::PKIX_EE => tlsa.matches(end_entity),
+            dane::PKIX_TA => intermediates.iter().any(|ca| tlsa.matches(ca)),
+            _ => false,
+        });
+        if !authenticated {
+            return Err(rustls::Error::General(String::from(
+                "certificate doesn't match any usable TLSA record",
+            )));
+        }
+        Ok(())
+    }
+
+    fn tlsa(&self) -> Result<Vec<Tlsa>, String> {
+        let mut cached = self.tlsa.lock().unwrap();
+        if let Some((records, expires)) = &*cached {
+            if Instant::now() < *expires {
+                return Ok(records.to_owned());
+            }
+        }
+        let name = name_from_string(self.profile.name.as_deref().unwrap_or(""))?;
+        let (records, expires) = dane::lookup(self.addr.port(), &name, &self.hints, &self.opts)?;
+        *cached = Some((records.to_owned(), expires));
+        Ok(records)
+    }
+}
+
+impl ServerCertVerifier for ProfileVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match self.authenticate(end_entity, intermediates, server_name, ocsp_response, now) {
+            Ok(()) => Ok(ServerCertVerified::assertion()),
+            Err(e) if !self.profile.strict => {
+                warning!(
+                    "Couldn't authenticate {} ({}); using it unauthenticated",
+                    self.addr,
+                    e
+                );
+                Ok(ServerCertVerified::assertion())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::{Ipv4Addr, TcpListener};
+
+    use crate::dns::config::Upstream;
+    use crate::dns::mock::{self, Behavior, MockNetwork, MockTlsServer, Script};
+    use crate::dns::protocol::{DnsRRType, DnsRecordData};
+
+    const ANSWER: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 53);
+
+    fn profile(s: &str) -> PrivacyProfile {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn pools_connections_and_resumes_sessions() {
+        let server = MockTlsServer::start(&["dot.test"], ANSWER);
+        let opts = ResolverOpts::default();
+        let hints = RootHints::default();
+        let profile = profile("dot.test");
+        let mut pool =
+            DotPool::with_roots(server.addr, &profile, server.roots(), &hints, &opts).unwrap();
+        let question = mock::question("www.example.com", DnsRRType::A);
+
+        // Queries one after another share a connection
+        for _ in 0..3 {
+            let response = pool.query(&question, &opts).unwrap();
+            assert_eq!(response.answers, vec![mock::a("www.example.com", ANSWER)]);
+        }
+        assert_eq!(server.handshakes(), (1, 0));
+        assert_eq!(pool.idle.lock().unwrap().len(), 1);
+
+        // Once it's been idle too long it's closed, and the next connection resumes the session
+        pool.idle_timeout = Duration::ZERO;
+        pool.query(&question, &opts).unwrap();
+        assert_eq!(server.handshakes(), (1, 1));
+
+        // A connection the server closed is replaced without the query failing
+        pool.idle_timeout = IDLE_TIMEOUT;
+        let (stream, _) = pool.idle.lock().unwrap().pop().unwrap();
+        stream.sock.shutdown(std::net::Shutdown::Both).unwrap();
+        pool.idle.lock().unwrap().push((stream, Instant::now()));
+        pool.query(&question, &opts).unwrap();
+        assert_eq!(server.handshakes(), (1, 2));
+    }
+
+    #[test]
+    fn privacy_profiles_authenticate_or_fall_back() {
+        let server = MockTlsServer::start(&["dot.test"], ANSWER);
+        let addr = server.addr;
+        let spki = webpki::EndEntityCert::try_from(&server.cert)
+            .unwrap()
+            .subject_public_key_info();
+        let pin = base64::encode(Sha256::digest(spki.as_ref()));
+        let opts = ResolverOpts::default();
+        let hints = RootHints::default();
+        let question = mock::question("www.example.com", DnsRRType::A);
+        let ask = |profile: &str| {
+            let pool = DotPool::new(addr, &profile.parse().unwrap(), &hints, &opts).unwrap();
+            pool.query(&question, &opts)
+        };
+        let other_pin = base64::encode([0u8; 32]);
+
+        // A self-signed certificate can't be authenticated by name, only by its public key
+        assert!(ask("dot.test").is_err());
+        assert!(ask(&format!("?pin={}", pin)).is_ok());
+        assert!(ask(&format!("dot.test?pin={}&pin={}", other_pin, pin)).is_err());
+        let pool = DotPool::with_roots(
+            addr,
+            &profile(&format!("dot.test?pin={}", pin)),
+            server.roots(),
+            &hints,
+            &opts,
+        );
+        assert!(pool.unwrap().query(&question, &opts).is_ok());
+        assert!(ask(&format!("?pin={}", other_pin)).is_err());
+        // Unless that's not required
+        assert!(ask("dot.test?opportunistic").is_ok());
+        assert!(ask(&format!("?pin={}&opportunistic", other_pin)).is_ok());
+
+        // When TLS doesn't work at all, the opportunistic profile settles for plain DNS
+        let network = MockNetwork::new();
+        let plain = network.serve(
+            Ipv4Addr::new(127, 0, 0, 7),
+            Script::new().otherwise(Behavior::Answer(vec![mock::a("www.example.com", ANSWER)])),
+        );
+        let closed = TcpListener::bind("127.0.0.7:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut pool = DotPool::new(closed, &profile("?opportunistic"), &hints, &opts).unwrap();
+        assert_eq!(pool.fallback, Some(SocketAddr::new(closed.ip(), 53)));
+        pool.fallback = Some(plain.addr);
+        assert!(pool.query(&question, &opts).is_ok());
+        assert_eq!(plain.queries().len(), 1);
+        let strict = DotPool::new(closed, &profile("dot.test"), &hints, &opts).unwrap();
+        assert!(strict.query(&question, &opts).is_err());
+
+        // Profiles that couldn't work are refused up front
+        assert!("?opportunistic&pin=AAAA".parse::<PrivacyProfile>().is_err());
+        assert!("".parse::<PrivacyProfile>().is_err());
+        assert!("not a name".parse::<PrivacyProfile>().is_err());
+        let upstream: Upstream = "1.1.1.1:853#cloudflare-dns.com?opportunistic"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            upstream.tls,
+            Some(profile("cloudflare-dns.com?opportunistic"))
+        );
+        assert_eq!(
+            upstream.to_string(),
+            "1.1.1.1:853#cloudflare-dns.com?opportunistic"
+        );
+    }
+
+    #[test]
+    fn dane_checks_certificates_against_tlsa_records() {
+        let server =
+            MockTlsServer::start(&["ee.test", "pkix.test", "wrong.test", "none.test"], ANSWER);
+        let addr = server.addr;
+        let spki = webpki::EndEntityCert::try_from(&server.cert)
+            .unwrap()
+            .subject_public_key_info();
+        let tlsa = |name: &str, usage: u8, data: &[u8]| {
+            let mut rr = mock::a(
+                &format!("_{}._tcp.{}", addr.port(), name),
+                Ipv4Addr::LOCALHOST,
+            );
+            rr.rr_type = DnsRRType::TLSA;
+            rr.record = DnsRecordData::Other([&[usage, 1, 1], data].concat());
+            rr
+        };
+        let digest = Sha256::digest(spki.as_ref());
+        let network = MockNetwork::new();
+        let mut script = Script::new();
+        for (name, usage, data) in [
+            ("ee.test", dane::DANE_EE, &digest[..]),
+            ("pkix.test", dane::PKIX_EE, &digest[..]),
+            ("wrong.test", dane::DANE_EE, &[0u8; 32][..]),
+        ] {
+            script = script.on(
+                &format!("_{}._tcp.{}", addr.port(), name),
+                Some(DnsRRType::TLSA),
+                Behavior::Answer(vec![tlsa(name, usage, data)]),
+            );
+        }
+        let root = network.serve(Ipv4Addr::new(127, 0, 0, 8), script);
+        let hints = network.hints(Ipv4Addr::new(127, 0, 0, 8));
+        let opts = ResolverOpts::default();
+        let question = mock::question("www.example.com", DnsRRType::A);
+        let ask = |profile: &str, roots: Option<RootCertStore>| {
+            let profile = profile.parse().unwrap();
+            let pool = match roots {
+                Some(roots) => DotPool::with_roots(addr, &profile, roots, &hints, &opts),
+                None => DotPool::new(addr, &profile, &hints, &opts),
+            };
+            pool.unwrap().query(&question, &opts)
+        };
+
+        // PKIX-EE needs a CA as well as the record to match. DANE-EE would do without the CA,
+        // but isn't trusted while the records aren't validated.
+        assert!(ask("pkix.test?dane", None).is_err());
+        assert!(ask("pkix.test?dane", Some(server.roots())).is_ok());
+        assert!(ask("ee.test?dane", None).is_err());
+        assert!(ask("ee.test?dane", Some(server.roots())).is_err());
+        // A record for some other key, or none at all, fails the strict profile
+        assert!(ask("wrong.test?dane", Some(server.roots())).is_err());
+        assert!(ask("none.test?dane", Some(server.roots())).is_err());
+        assert!(ask("none.test?dane&opportunistic", None).is_ok());
+        assert!(root
+            .queries()
+            .iter()
+            .all(|(_, question)| question.qtype == DnsRRType::TLSA));
+
+        assert!("?dane&opportunistic".parse::<PrivacyProfile>().is_err());
+    }
+}
@@ -0,0 +1,137 @@
+// Why resolving a question failed. Each kind of failure maps to what the client should be told:
+// the response code, and an extended error (RFC 8914) explaining it, or nothing at all when the
+// query was too broken to answer.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+
+use super::protocol::{DnsFormatError, DnsRCode, EdeCode};
+
+#[derive(Debug)]
+pub enum ResolveError {
+    // A server never replied, after every retry
+    Timeout(SocketAddr),
+    // Every server that could have answered failed; says how the last one did
+    AllServersFailed(String),
+    // Resolution went in circles, e.g. a nameserver whose address can only be found by asking
+    // itself
+    Loop(String),
+    // A server's reply couldn't be parsed, or made no sense
+    Malformed(String),
+    // A server answered with an error response code
+    ServerFailure(SocketAddr, DnsRCode),
+    // The name doesn't exist; what a lookup gets for an NXDOMAIN answer
+    NotFound(String),
+    // A lookup was answered with some other error response code
+    ErrorResponse(String, DnsRCode),
+    // The name is blocked by local policy
+    PolicyBlocked(String),
+    // Sending or receiving failed
+    Network(io::Error),
+    // The client's question can't be resolved as asked
+    InvalidQuestion(String),
+    // The query is too broken to send any response to
+    Unanswerable(String),
+    // Something that should never happen; a bug
+    Internal(String),
+}
+
+impl ResolveError {
+    // The response code to answer with, or None to send no response at all
+    pub fn rcode(&self) -> Option<DnsRCode> {
+        match self {
+            ResolveError::Timeout(_)
+            | ResolveError::AllServersFailed(_)
+            | ResolveError::Loop(_)
+            | ResolveError::Malformed(_)
+            | ResolveError::ServerFailure(_, _)
+            | ResolveError::Network(_)
+            | ResolveError::Internal(_) => Some(DnsRCode::ServFail),
+            ResolveError::NotFound(_) => Some(DnsRCode::NXDomain),
+            ResolveError::ErrorResponse(_, rcode) => Some(rcode.to_owned()),
+            ResolveError::PolicyBlocked(_) => Some(DnsRCode::Refused),
+            ResolveError::InvalidQuestion(_) => Some(DnsRCode::FormError),
+            ResolveError::Unanswerable(_) => None,
+        }
+    }
+
+    // The extended error to attach to the response, if there's one that fits
+    pub fn ede(&self) -> Option<EdeCode> {
+        match self {
+            ResolveError::Timeout(_)
+            | ResolveError::AllServersFailed(_)
+            | ResolveError::ServerFailure(_, _) => Some(EdeCode::NoReachableAuthority),
+            ResolveError::Network(_) => Some(EdeCode::NetworkError),
+            ResolveError::Malformed(_) => Some(EdeCode::InvalidData),
+            ResolveError::PolicyBlocked(_) => Some(EdeCode::Blocked),
+            ResolveError::Loop(_) | ResolveError::Internal(_) => Some(EdeCode::Other),
+            ResolveError::NotFound(_)
+            | ResolveError::ErrorResponse(_, _)
+            | ResolveError::InvalidQuestion(_)
+            | ResolveError::Unanswerable(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResolveError::Timeout(server) => write!(f, "No reply from {}", server),
+            ResolveError::AllServersFailed(last) => write!(f, "All servers failed: {}", last),
+            ResolveError::Loop(description) => write!(f, "Resolution loop: {}", description),
+            ResolveError::Malformed(description) => write!(f, "Malformed reply: {}", description),
+            ResolveError::ServerFailure(server, rcode) => {
+                write!(f, "{} answered {:?}", server, rcode)
+            }
+            ResolveError::NotFound(name) => write!(f, "{} does not exist", name),
+            ResolveError::ErrorResponse(name, rcode) => {
+                write!(f, "Looking up {} failed: {:?}", name, rcode)
+            }
+            ResolveError::PolicyBlocked(reason) => write!(f, "Blocked by policy: {}", reason),
+            ResolveError::Network(e) => write!(f, "Network error: {}", e),
+            ResolveError::InvalidQuestion(reason) => write!(f, "Invalid question: {}", reason),
+            ResolveError::Unanswerable(reason) => write!(f, "Unanswerable query: {}", reason),
+            ResolveError::Internal(description) => write!(f, "Internal error: {}", description),
+        }
+    }
+}
+
+impl Error for ResolveError {}
+
+impl From<io::Error> for ResolveError {
+    fn from(e: io::Error) -> ResolveError {
+        ResolveError::Network(e)
+    }
+}
+
+impl From<DnsFormatError> for ResolveError {
+    fn from(e: DnsFormatError) -> ResolveError {
+        ResolveError::Malformed(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_map_to_responses() {
+        let server: SocketAddr = "192.0.2.1:53".parse().unwrap();
+        let timeout = ResolveError::Timeout(server);
+        assert_eq!(timeout.rcode(), Some(DnsRCode::ServFail));
+        assert_eq!(timeout.ede(), Some(EdeCode::NoReachableAuthority));
+        assert_eq!(timeout.to_string(), "No reply from 192.0.2.1:53");
+
+        let network = ResolveError::from(io::Error::from(io::ErrorKind::ConnectionRefused));
+        assert_eq!(network.ede(), Some(EdeCode::NetworkError));
+        let blocked = ResolveError::PolicyBlocked(String::from("ads.example"));
+        assert_eq!(blocked.rcode(), Some(DnsRCode::Refused));
+        assert_eq!(blocked.ede(), Some(EdeCode::Blocked));
+        assert_eq!(ResolveError::Unanswerable(String::new()).rcode(), None);
+        let missing = ResolveError::NotFound(String::from("nope.example"));
+        assert_eq!(missing.rcode(), Some(DnsRCode::NXDomain));
+        assert_eq!(missing.to_string(), "nope.example does not exist");
+    }
+}
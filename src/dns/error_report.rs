@@ -0,0 +1,283 @@
+// DNS error reporting (RFC 9567). An authority can name an agent domain in a Report-Channel EDNS
+// option on its responses; a resolver that then fails to resolve something in the authority's
+// zones tells the agent so by looking up a TXT record under it, named for what failed and why:
+//
+//     _er.<qtype>.<qname>.<extended error code>._er.<agent domain>
+//
+// The lookup is the report, so whoever runs the agent's nameservers learns about broken zones
+// from the queries they get. Both halves are here: ReportChannel advertises our agent domain on
+// the answers we give from our own zones, and ErrorReports sends reports while resolving.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::config::ResolverOpts;
+use super::error::ResolveError;
+use super::pipeline::{Middleware, Next};
+use super::protocol::{
+    add_option, name_to_string, supports_edns, DnsClass, DnsPacket, DnsQuestion, DnsRRType,
+    EdeCode, EdnsOption,
+};
+use super::recursive::{self, RootHints};
+use super::trace::log;
+
+// The label report names start with, and that the agent domain is put under
+const REPORT_LABEL: &str = "_er";
+
+// How long to wait before sending the same report again. The agent's negative answers would tell
+// us, if we cached them down here; an hour is in line with what agents are expected to use.
+const REPORT_AGAIN_AFTER: Duration = Duration::from_secs(60 * 60);
+
+// Past this many reports remembered, no more are sent until old ones have expired. Each report is
+// a lookup on a thread of its own, so a flood of queries for broken names mustn't be able to turn
+// into unlimited threads and memory.
+const MAX_REPORTS: usize = 10_000;
+
+// How often reports older than REPORT_AGAIN_AFTER are cleared out
+const SWEEP_EVERY: Duration = Duration::from_secs(60);
+
+// Names are at most 255 bytes on the wire (RFC 1035 section 2.3.4)
+const MAX_NAME_LENGTH: usize = 255;
+
+// The name a report about `question` failing with `code` is looked up as, or None if it would be
+// too long to be a name
+pub fn report_name(question: &DnsQuestion, code: EdeCode, agent: &[String]) -> Option<Vec<String>> {
+    let mut name = vec![
+        String::from(REPORT_LABEL),
+        (question.qtype as u16).to_string(),
+    ];
+    name.extend(question.qname.iter().cloned());
+    name.push((code as u16).to_string());
+    name.push(String::from(REPORT_LABEL));
+    name.extend(agent.iter().cloned());
+    let length: usize = name.iter().map(|label| label.len() + 1).sum::<usize>() + 1;
+    Some(name).filter(|_| length <= MAX_NAME_LENGTH)
+}
+
+fn is_report(name: &[String]) -> bool {
+    name.first()
+        .is_some_and(|label| label.eq_ignore_ascii_case(REPORT_LABEL))
+}
+
+// Reports sent while resolving, remembered so a zone that stays broken isn't reported on every
+// query for it
+#[derive(Debug, Default)]
+pub struct ErrorReports {
+    sent: Mutex<Sent>,
+}
+
+#[derive(Debug, Default)]
+struct Sent {
+    // When each report was last sent, by its lowercased name
+    reports: HashMap<Vec<String>, Instant>,
+    // When expired reports were last cleared out
+    swept: Option<Instant>,
+}
+
+impl ErrorReports {
+    // Tell `agent` that resolving `question` failed with `error`, in the background. Failures
+    // looking up reports aren't themselves reported, or a broken agent would be told about forever.
+    pub fn report(
+        &self,
+        question: &DnsQuestion,
+        error: &ResolveError,
+        agent: &[String],
+        hints: &RootHints,
+        opts: &ResolverOpts,
+    ) {
+        let code = match error.ede() {
+            Some(code) => code,
+            None => return,
+        };
+        // Agents have to be somewhere below the root
+        if is_report(&question.qname) || agent.is_empty() {
+            return;
+        }
+        let name = match report_name(question, code, agent) {
+            Some(name) => name,
+            None => return,
+        };
+        let key: Vec<String> = name
+            .iter()
+            .map(|label| label.to_ascii_lowercase())
+            .collect();
+        if !self.remember(key, Instant::now()) {
+            return;
+        }
+        let report = DnsQuestion {
+            qname: name,
+            qtype: DnsRRType::TXT,
+            qclass: DnsClass::IN,
+        };
+        log!("Reporting {:?} to {}", code, name_to_string(agent));
+        let hints = hints.to_owned();
+        let opts = opts.to_owned();
+        // Whatever the agent says back doesn't matter
+        thread::spawn(move || {
+            if let Err(e) = recursive::resolve_question(&report, &hints, &opts) {
+                log!("Couldn't send error report {}: {}", report, e);
+            }
+        });
+    }
+
+    // Note a report named `key` as sent at `now`, or false if it shouldn't be sent: it already was
+    // recently, or too many others have been
+    fn remember(&self, key: Vec<String>, now: Instant) -> bool {
+        let mut sent = self.sent.lock().unwrap();
+        let due = sent
+            .swept
+            .is_none_or(|swept| now.saturating_duration_since(swept) >= SWEEP_EVERY);
+        if due {
+            sent.reports
+                .retain(|_, at| now.saturating_duration_since(*at) < REPORT_AGAIN_AFTER);
+            sent.swept = Some(now);
+        }
+        match sent.reports.get(&key) {
+            Some(at) if now.saturating_duration_since(*at) < REPORT_AGAIN_AFTER => return false,
+            Some(_) => (),
+            None if sent.reports.len() >= MAX_REPORTS => {
+                log!("Too many error reports sent recently, not sending another");
+                return false;
+            }
+            None => (),
+        }
+        sent.reports.insert(key, now);
+        true
+    }
+}
+
+// Puts our agent domain on answers from our own zones, to clients that speak EDNS. Answers to
+// report lookups go without, since they're the one thing reports aren't sent about anyway.
+pub struct ReportChannel {
+    agent: Vec<String>,
+}
+
+impl ReportChannel {
+    pub fn new(agent: Vec<String>) -> ReportChannel {
+        ReportChannel { agent }
+    }
+}
+
+impl Middleware for ReportChannel {
+    fn name(&self) -> &str {
+        "report-channel"
+    }
+
+    fn handle(&self, query: &DnsPacket, next: Next) -> Result<DnsPacket, ResolveError> {
+        let mut response = next.run(query)?;
+        let about_report = query
+            .questions
+            .first()
+            .is_some_and(|question| is_report(&question.qname));
+        if response.flags.aa_bit && supports_edns(query) && !about_report {
+            add_option(
+                &mut response,
+                EdnsOption::ReportChannel(self.agent.to_owned()),
+            );
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+
+    use crate::dns::config::ResolverConfig;
+    use crate::dns::mock::{self, Behavior, MockNetwork, Script};
+    use crate::dns::pipeline::Pipeline;
+    use crate::dns::protocol::{opt_record, report_channel, DnsRCode, EdnsRegistry};
+    use crate::dns::resolver::Resolver;
+    use crate::dns::validation::HostnameValidation;
+
+    const ROOT: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 2);
+    const BROKEN: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 3);
+
+    #[test]
+    fn failures_are_reported_to_the_agent() {
+        let network = MockNetwork::new();
+        let root = network.serve(
+            ROOT,
+            Script::new()
+                .under(
+                    "broken.test",
+                    Behavior::Referral(
+                        vec![mock::ns("broken.test", "ns.broken.test")],
+                        vec![mock::a("ns.broken.test", BROKEN)],
+                    ),
+                )
+                .under("agent.test", Behavior::NoData(Vec::new())),
+        );
+        let _broken = network.serve(
+            BROKEN,
+            Script::new().otherwise(Behavior::Options(
+                vec![EdnsOption::ReportChannel(mock::labels("agent.test"))],
+                Box::new(Behavior::Rcode(DnsRCode::ServFail)),
+            )),
+        );
+        let hints = network.hints(ROOT);
+        let opts = ResolverOpts {
+            error_reports: Some(Arc::default()),
+            ..ResolverOpts::default()
+        };
+        let question = mock::question("www.broken.test", DnsRRType::A);
+        let report = mock::question("_er.1.www.broken.test.22._er.agent.test", DnsRRType::TXT);
+        let reports = || {
+            root.queries()
+                .iter()
+                .filter(|(_, asked)| *asked == report)
+                .count()
+        };
+        for _ in 0..2 {
+            assert!(recursive::resolve_question(&question, &hints, &opts).is_err());
+        }
+        // Reports go out in the background, and only the once
+        let started = Instant::now();
+        while reports() == 0 && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(reports(), 1);
+
+        // Only so many different reports are remembered, and so sent, at once
+        let reports = ErrorReports::default();
+        let now = Instant::now();
+        for n in 0..MAX_REPORTS {
+            assert!(reports.remember(vec![n.to_string()], now));
+        }
+        assert!(!reports.remember(vec![String::from("0")], now));
+        assert!(!reports.remember(vec![String::from("new")], now));
+        let later = now + REPORT_AGAIN_AFTER;
+        assert!(reports.remember(vec![String::from("new")], later));
+        assert!(reports.remember(vec![String::from("0")], later));
+
+        let long = mock::question(&vec!["a".repeat(60); 4].join("."), DnsRRType::A);
+        assert!(report_name(&long, EdeCode::Other, &mock::labels("agent.test")).is_none());
+
+        // Our own answers name our agent, to clients that speak EDNS
+        let resolver = Resolver::new(
+            ResolverConfig {
+                local_data: vec![mock::a("printer.lan", Ipv4Addr::new(192, 168, 1, 20))],
+                ..ResolverConfig::default()
+            },
+            ResolverOpts::default(),
+        );
+        let mut pipeline =
+            Pipeline::standard(resolver, HostnameValidation::default(), EdnsRegistry::new());
+        let agent = mock::labels("agent.lan");
+        pipeline
+            .insert_before("finish", Arc::new(ReportChannel::new(agent.to_owned())))
+            .unwrap();
+        let mut query = mock::query("printer.lan", DnsRRType::A);
+        let response = mock::run(&pipeline, &query).unwrap();
+        assert_eq!(report_channel(&response), None);
+        query.addl_recs.push(opt_record(1232));
+        let response = mock::run(&pipeline, &query).unwrap();
+        assert_eq!(report_channel(&response), Some(&agent[..]));
+    }
+}
@@ -0,0 +1,241 @@
+// Changes dynamic updates make to a zone, kept in a journal file next to the zone's file so they
+// outlast a restart. Each entry is one update's ZoneDiff, written as a DNS message whose answers
+// are the diff in IXFR order, after its length as a 32 bit number. Loading a zone replays whatever
+// in its journal follows on from the zone file's serial, and compacting writes the result back to
+// the zone file and empties the journal.
+
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use super::protocol::{DnsFlags, DnsOpcode, DnsPacket, DnsRCode, DnsRRType, DnsResourceRecord};
+use super::zone::{Zone, ZoneDiff};
+
+// Where the journal for the zone file at `zone_path` goes
+pub fn path_for(zone_path: &Path) -> PathBuf {
+    let mut path = zone_path.as_os_str().to_owned();
+    path.push(".jnl");
+    PathBuf::from(path)
+}
+
+pub fn append(path: &Path, diff: &ZoneDiff) -> io::Result<()> {
+    let message = DnsPacket {
+        id: 0,
+        flags: DnsFlags {
+            qr_bit: false,
+            opcode: DnsOpcode::Update,
+            aa_bit: false,
+            tc_bit: false,
+            rd_bit: false,
+            ra_bit: false,
+            ad_bit: false,
+            cd_bit: false,
+            rcode: DnsRCode::NoError,
+        },
+        questions: Vec::new(),
+        answers: diff.removed.iter().chain(&diff.added).cloned().collect(),
+        nameservers: Vec::new(),
+        addl_recs: Vec::new(),
+    }
+    .to_bytes();
+    let mut entry = (message.len() as u32).to_be_bytes().to_vec();
+    entry.extend_from_slice(&message);
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(&entry)?;
+    // An update isn't acknowledged until it's on disk
+    file.sync_data()
+}
+
+// Every change in the journal, oldest first. A journal that doesn't exist has none.
+pub fn read(path: &Path) -> Result<Vec<ZoneDiff>, String> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("{}: {}", path.display(), e)),
+    };
+    let mut diffs = Vec::new();
+    let mut pos = 0;
+    while pos + 4 <= bytes.len() {
+        let mut length = [0; 4];
+        length.copy_from_slice(&bytes[pos..pos + 4]);
+        let length = u32::from_be_bytes(length) as usize;
+        let message = match bytes.get(pos + 4..pos + 4 + length) {
+            Some(message) => message,
+            // Cut off partway through writing it, so it was never acknowledged
+            None => break,
+        };
+        let message = DnsPacket::from_bytes(message)
+            .map_err(|e| format!("{}: entry at byte {}: {}", path.display(), pos, e))?;
+        diffs.push(
+            split_diff(message.answers).ok_or_else(|| {
+                format!("{}: entry at byte {} isn't a change", path.display(), pos)
+            })?,
+        );
+        pos += 4 + length;
+    }
+    Ok(diffs)
+}
+
+// Records in IXFR order back into the two halves of a diff, split at the second SOA
+fn split_diff(mut records: Vec<DnsResourceRecord>) -> Option<ZoneDiff> {
+    if records.first()?.rr_type != DnsRRType::SOA {
+        return None;
+    }
+    let split = records
+        .iter()
+        .skip(1)
+        .position(|rr| rr.rr_type == DnsRRType::SOA)?
+        + 1;
+    let added = records.split_off(split);
+    Some(ZoneDiff {
+        removed: records,
+        added,
+    })
+}
+
+// Apply the changes in the journal that follow on from the zone's serial, returning how many
+// there were
+pub fn replay(zone: &mut Zone, path: &Path) -> Result<usize, String> {
+    let mut applied = 0;
+    for diff in read(path)? {
+        if diff.old_serial() == Some(zone.serial()) {
+            zone.apply(&diff)
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            applied += 1;
+        }
+    }
+    Ok(applied)
+}
+
+// The changes that take the zone from `serial` to `current`, if the journal has all of them
+pub fn changes_since(path: &Path, serial: u32, current: u32) -> Option<Vec<ZoneDiff>> {
+    let diffs = read(path).ok()?;
+    let start = diffs
+        .iter()
+        .position(|diff| diff.old_serial() == Some(serial))?;
+    let mut chain: Vec<ZoneDiff> = Vec::new();
+    for diff in diffs.into_iter().skip(start) {
+        let follows = match chain.last() {
+            Some(last) => last.new_serial() == diff.old_serial(),
+            None => true,
+        };
+        if follows {
+            chain.push(diff);
+        }
+    }
+    match chain.last() {
+        Some(last) if last.new_serial() == Some(current) => Some(chain),
+        _ => None,
+    }
+}
+
+// Fold the journal into the zone file, returning how many changes it held and the zone's serial
+pub fn compact(
+    origin: &[String],
+    zone_path: &Path,
+    default_ttl: Option<u32>,
+) -> Result<(usize, u32), Box<dyn Error>> {
+    let journal = path_for(zone_path);
+    let mut zone = Zone::load(origin, zone_path, default_ttl)?;
+    let applied = replay(&mut zone, &journal)?;
+    // Written alongside and renamed over, so a crash leaves either the old file or the new one
+    let mut temp = zone_path.as_os_str().to_owned();
+    temp.push(".compacting");
+    fs::write(&temp, zone.to_master_file())?;
+    fs::rename(&temp, zone_path)?;
+    if journal.exists() {
+        fs::remove_file(&journal)?;
+    }
+    Ok((applied, zone.serial()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use crate::dns::access::AccessPolicy;
+    use crate::dns::config::{ResolverConfig, ResolverOpts};
+    use crate::dns::mock;
+    use crate::dns::protocol::DnsRecordData;
+    use crate::dns::resolver::Resolver;
+    use crate::dns::zone_watch::ZoneFile;
+    use crate::dns::{transfer, update};
+
+    #[test]
+    fn updates_outlast_restarts() {
+        let dir = std::env::temp_dir().join(format!("montague-journal-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = ZoneFile {
+            origin: mock::labels("example.test"),
+            path: dir.join("example.test.zone"),
+            default_ttl: None,
+        };
+        fs::write(
+            &file.path,
+            "$TTL 300\n@ SOA ns hostmaster 5 2 3 4 5\n@ NS ns\nns A 192.0.2.53\n",
+        )
+        .unwrap();
+        let config = ResolverConfig {
+            zones: vec![file.load().unwrap()],
+            ..ResolverConfig::default()
+        };
+        let resolver = Resolver::new(config, ResolverOpts::default());
+        let mut policy = AccessPolicy::new(Vec::new());
+        policy.allow(&file.origin, vec!["127.0.0.1".parse().unwrap()]);
+        let client = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        for last in 1..=2 {
+            let mut message = mock::query("example.test", DnsRRType::SOA);
+            message.flags.opcode = DnsOpcode::Update;
+            message.nameservers = vec![mock::a("www.example.test", Ipv4Addr::new(192, 0, 2, last))];
+            let response =
+                update::answer(&message.to_bytes(), &message, client, &policy, &resolver);
+            assert_eq!(response.flags.rcode, DnsRCode::NoError);
+        }
+
+        // The zone file hasn't changed, but the journal brings a fresh load up to date
+        let reloaded = file.load().unwrap();
+        assert_eq!(reloaded.serial(), 7);
+        assert_eq!(
+            reloaded
+                .records_named(&mock::labels("www.example.test"))
+                .len(),
+            2
+        );
+
+        // A secondary at serial 6 only needs the second update
+        let mut ixfr = mock::query("example.test", DnsRRType::IXFR);
+        let mut soa = reloaded.soa().to_owned();
+        if let DnsRecordData::SOA(data) = &mut soa.record {
+            data.serial = 6;
+        }
+        ixfr.nameservers = vec![soa];
+        let transferred = transfer::answer(&ixfr.to_bytes(), &ixfr, client, &policy, &resolver);
+        let types: Vec<DnsRRType> = transferred.answers.iter().map(|rr| rr.rr_type).collect();
+        assert_eq!(
+            types,
+            vec![
+                DnsRRType::SOA,
+                DnsRRType::SOA,
+                DnsRRType::SOA,
+                DnsRRType::A,
+                DnsRRType::SOA
+            ]
+        );
+
+        // Compacting folds the journal into the file, leaving the zone as it was
+        assert_eq!(compact(&file.origin, &file.path, None).unwrap(), (2, 7));
+        assert!(!path_for(&file.path).exists());
+        let compacted = file.load().unwrap();
+        assert_eq!(compacted.serial(), 7);
+        let mut before: Vec<String> = reloaded.records().map(|rr| rr.to_string()).collect();
+        let mut after: Vec<String> = compacted.records().map(|rr| rr.to_string()).collect();
+        before.sort();
+        after.sort();
+        assert_eq!(before, after);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,151 @@
+// Round trip times to every server we send queries to, authorities and upstreams alike, kept as
+// histograms rather than averages: an average hides a server that's usually quick but regularly
+// stalls, which is exactly the one worth knowing about.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Upper bounds of the buckets, in milliseconds. Anything slower lands in one more bucket after
+// these.
+const BUCKETS_MS: [u64; 12] = [1, 2, 5, 10, 20, 50, 100, 200, 500, 1000, 2000, 5000];
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LatencyHistogram {
+    counts: [u64; BUCKETS_MS.len() + 1],
+    // Queries that got no usable answer, which have no round trip time to count
+    pub failures: u64,
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, rtt: Duration) {
+        let ms = rtt.as_secs_f64() * 1000.0;
+        let bucket = BUCKETS_MS
+            .iter()
+            .position(|bound| ms <= *bound as f64)
+            .unwrap_or(BUCKETS_MS.len());
+        self.counts[bucket] += 1;
+    }
+
+    pub fn answered(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    // The upper bound of the bucket the percentile falls in, or None if it's past the last
+    // bound (or nothing's been answered)
+    pub fn percentile(&self, percent: f64) -> Option<Duration> {
+        let answered = self.answered();
+        if answered == 0 {
+            return None;
+        }
+        let rank = ((percent / 100.0 * answered as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return BUCKETS_MS.get(bucket).map(|ms| Duration::from_millis(*ms));
+            }
+        }
+        None
+    }
+}
+
+// e.g. "120 answered, 2 failed, p50 <=20ms, p90 <=50ms, p99 >5000ms [<=10ms: 40, ...]"
+impl fmt::Display for LatencyHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} answered, {} failed", self.answered(), self.failures)?;
+        if self.answered() == 0 {
+            return Ok(());
+        }
+        for percent in &[50, 90, 99] {
+            match self.percentile(f64::from(*percent)) {
+                Some(bound) => write!(f, ", p{} <={}ms", percent, bound.as_millis())?,
+                None => write!(f, ", p{} >{}ms", percent, BUCKETS_MS[BUCKETS_MS.len() - 1])?,
+            }
+        }
+        let buckets: Vec<String> = self
+            .counts
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(bucket, count)| match BUCKETS_MS.get(bucket) {
+                Some(ms) => format!("<={}ms: {}", ms, count),
+                None => format!(">{}ms: {}", BUCKETS_MS[BUCKETS_MS.len() - 1], count),
+            })
+            .collect();
+        write!(f, " [{}]", buckets.join(", "))
+    }
+}
+
+// A histogram for each server, shared by everything that sends queries
+#[derive(Debug, Default)]
+pub struct ServerLatencies {
+    servers: Mutex<BTreeMap<SocketAddr, LatencyHistogram>>,
+}
+
+impl ServerLatencies {
+    // Record how a query to `addr` went: its round trip time, or None if it failed
+    pub fn record(&self, addr: SocketAddr, outcome: Option<Duration>) {
+        let mut servers = self.servers.lock().unwrap();
+        let histogram = servers.entry(addr).or_default();
+        match outcome {
+            Some(rtt) => histogram.record(rtt),
+            None => histogram.failures += 1,
+        }
+    }
+
+    // Every server we've sent a query to, in address order
+    pub fn snapshot(&self) -> Vec<(SocketAddr, LatencyHistogram)> {
+        self.servers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(addr, histogram)| (*addr, histogram.to_owned()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histograms_find_slow_tails() {
+        let latencies = ServerLatencies::default();
+        let slow: SocketAddr = "192.0.2.1:53".parse().unwrap();
+        let fast: SocketAddr = "192.0.2.2:53".parse().unwrap();
+        for _ in 0..95 {
+            latencies.record(slow, Some(Duration::from_millis(8)));
+        }
+        for _ in 0..4 {
+            latencies.record(slow, Some(Duration::from_millis(1500)));
+        }
+        latencies.record(slow, Some(Duration::from_secs(9)));
+        latencies.record(slow, None);
+        latencies.record(fast, Some(Duration::from_micros(300)));
+
+        let snapshot = latencies.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        let (addr, histogram) = &snapshot[0];
+        assert_eq!(*addr, slow);
+        assert_eq!(histogram.answered(), 100);
+        assert_eq!(histogram.failures, 1);
+        assert_eq!(histogram.percentile(50.0), Some(Duration::from_millis(10)));
+        assert_eq!(
+            histogram.percentile(99.0),
+            Some(Duration::from_millis(2000))
+        );
+        assert_eq!(histogram.percentile(100.0), None);
+        assert_eq!(
+            histogram.to_string(),
+            "100 answered, 1 failed, p50 <=10ms, p90 <=10ms, p99 <=2000ms \
+             [<=10ms: 95, <=2000ms: 4, >5000ms: 1]"
+        );
+        assert_eq!(
+            snapshot[1].1.percentile(50.0),
+            Some(Duration::from_millis(1))
+        );
+    }
+}
@@ -0,0 +1,336 @@
+// Spotting names made to look like other names, the way phishing links do: internationalized
+// labels that mix scripts whose letters look alike (a Cyrillic "а" among Latin letters), labels
+// written entirely in Cyrillic or Greek letters that spell out a Latin word, and labels that
+// resemble one of a list of protected names, like "paypa1" or "xn--pypal-4ve" for "paypal".
+//
+// Nothing is blocked; this only logs what it finds and counts it in the analytics, for a look at
+// who on a home or office network might be getting phished. Internationalized labels are decoded
+// from punycode (RFC 3492) first. The scripts and confusable letters are a small hand-picked set,
+// not Unicode's full tables (UTS #39), so this catches the common tricks rather than all of them.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Instant;
+
+use super::analytics::Analytics;
+use super::error::ResolveError;
+use super::pipeline::{Middleware, Next};
+use super::protocol::{name_to_string, DnsPacket};
+use super::trace::log;
+
+// Protected names at least this long also match labels one edit away, like "paypall"
+const MIN_FUZZY_LENGTH: usize = 5;
+
+// Punycode's parameters (RFC 3492 section 5)
+const BASE: u32 = 36;
+const TMIN: u32 = 1;
+const TMAX: u32 = 26;
+const SKEW: u32 = 38;
+const DAMP: u32 = 700;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Script {
+    // Digits, hyphens, and combining marks, which go with any script
+    Common,
+    Latin,
+    Greek,
+    Cyrillic,
+    Armenian,
+    Other,
+}
+
+fn script(c: char) -> Script {
+    match c as u32 {
+        0x30..=0x39 | 0x2D | 0x5F | 0x300..=0x36F => Script::Common,
+        0x41..=0x5A | 0x61..=0x7A | 0xC0..=0x24F | 0x250..=0x2AF | 0x1E00..=0x1EFF
+            if c != '×' && c != '÷' =>
+        {
+            Script::Latin
+        }
+        0x370..=0x3FF | 0x1F00..=0x1FFF => Script::Greek,
+        0x400..=0x52F | 0x1C80..=0x1C8F | 0x2DE0..=0x2DFF | 0xA640..=0xA69F => Script::Cyrillic,
+        0x530..=0x58F => Script::Armenian,
+        _ => Script::Other,
+    }
+}
+
+// The Latin letters (or letter pairs) a character could pass for. Characters not listed stand for
+// themselves.
+fn confusable(c: char) -> Option<&'static str> {
+    Some(match c {
+        // Cyrillic and Greek letters, and digits
+        'а' | 'α' => "a",
+        'в' => "b",
+        'с' | 'ϲ' => "c",
+        'ԁ' => "d",
+        'е' | 'ё' => "e",
+        'һ' => "h",
+        'ј' => "j",
+        'к' | 'κ' => "k",
+        'і' | 'ї' | 'ӏ' | 'ι' | '1' => "l",
+        'о' | 'ο' | '0' => "o",
+        'р' | 'ρ' => "p",
+        'ԛ' => "q",
+        'ѕ' => "s",
+        'υ' => "u",
+        'ν' => "v",
+        'ԝ' => "w",
+        'х' | 'χ' => "x",
+        'у' | 'ү' => "y",
+        // Latin letters with diacritics, or that are easily mistaken for others
+        'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ā' | 'ă' | 'ą' | 'ɑ' => "a",
+        'ç' | 'ć' | 'č' => "c",
+        'đ' | 'ď' => "d",
+        'è' | 'é' | 'ê' | 'ë' | 'ē' | 'ė' | 'ę' | 'ě' => "e",
+        'ğ' | 'ģ' | 'ɡ' => "g",
+        'ķ' => "k",
+        'i' | 'ì' | 'í' | 'î' | 'ï' | 'ī' | 'į' | 'ı' | 'ɩ' => "l",
+        'ł' | 'ĺ' | 'ľ' | 'ļ' => "l",
+        'ñ' | 'ń' | 'ň' => "n",
+        'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' | 'ō' => "o",
+        'ř' => "r",
+        'ś' | 'š' | 'ş' => "s",
+        'ť' | 'ţ' => "t",
+        'ù' | 'ú' | 'û' | 'ü' | 'ū' | 'ů' => "u",
+        'ý' | 'ÿ' => "y",
+        'ź' | 'ż' | 'ž' => "z",
+        _ => return None,
+    })
+}
+
+// What a label looks like, near enough: confusable letters replaced by the Latin ones they pass
+// for, and letter pairs that read as one letter merged. "i", "l", and "1" all become "l", since
+// in many fonts there's no telling them apart.
+fn skeleton(label: &str) -> String {
+    let mapped: String = label
+        .chars()
+        .flat_map(|c| c.to_lowercase())
+        .map(|c| confusable(c).map_or_else(|| c.to_string(), String::from))
+        .collect();
+    mapped.replace("rn", "m").replace("vv", "w")
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Lookalike {
+    // Letters from more than one of the scripts that share lookalike letters
+    MixedScripts(String),
+    // All Cyrillic or Greek, but reads as this Latin word
+    WholeScript(String, String),
+    // Resembles this protected name without being it
+    Resembles(String, String),
+}
+
+impl fmt::Display for Lookalike {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Lookalike::MixedScripts(label) => write!(f, "{} mixes scripts", label),
+            Lookalike::WholeScript(label, word) => write!(f, "{} reads as {}", label, word),
+            Lookalike::Resembles(label, name) => write!(f, "{} resembles {}", label, name),
+        }
+    }
+}
+
+pub struct Lookalikes {
+    // Each protected name, with its skeleton
+    protected: Vec<(String, String)>,
+    analytics: Option<Arc<Analytics>>,
+}
+
+impl Lookalikes {
+    pub fn new(protected: &[String], analytics: Option<Arc<Analytics>>) -> Lookalikes {
+        Lookalikes {
+            protected: protected
+                .iter()
+                .map(|name| (name.to_ascii_lowercase(), skeleton(name)))
+                .collect(),
+            analytics,
+        }
+    }
+
+    // The first thing suspicious about a name, checking its labels left to right
+    pub fn check(&self, name: &[String]) -> Option<Lookalike> {
+        name.iter().find_map(|label| self.check_label(label))
+    }
+
+    fn check_label(&self, label: &str) -> Option<Lookalike> {
+        let label = label.to_ascii_lowercase();
+        let decoded = match label.strip_prefix("xn--") {
+            Some(encoded) => decode_punycode(encoded)?,
+            None => label.to_owned(),
+        };
+        let scripts: BTreeSet<Script> = decoded
+            .chars()
+            .map(script)
+            .filter(|script| *script != Script::Common)
+            .collect();
+        let confusing = scripts
+            .iter()
+            .filter(|script| {
+                matches!(
+                    script,
+                    Script::Latin | Script::Greek | Script::Cyrillic | Script::Armenian
+                )
+            })
+            .count();
+        if confusing > 1 {
+            return Some(Lookalike::MixedScripts(decoded));
+        }
+
+        let look = skeleton(&decoded);
+        let whole_script = scripts.len() == 1
+            && (scripts.contains(&Script::Cyrillic) || scripts.contains(&Script::Greek));
+        if whole_script && look.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Some(Lookalike::WholeScript(decoded, look));
+        }
+        for (name, protected) in &self.protected {
+            if decoded == *name {
+                continue;
+            }
+            let close = look == *protected
+                || (protected.len() >= MIN_FUZZY_LENGTH && within_one_edit(&look, protected));
+            if close {
+                return Some(Lookalike::Resembles(decoded, name.to_owned()));
+            }
+        }
+        None
+    }
+}
+
+impl Middleware for Lookalikes {
+    fn name(&self) -> &str {
+        "lookalikes"
+    }
+
+    fn handle(&self, query: &DnsPacket, next: Next) -> Result<DnsPacket, ResolveError> {
+        let name = &query.questions[0].qname;
+        if let Some(lookalike) = self.check(name) {
+            let name = name_to_string(name).to_ascii_lowercase();
+            log!("Lookalike name {}: {}", name, lookalike);
+            if let Some(analytics) = &self.analytics {
+                analytics.lookalike(name, Instant::now());
+            }
+        }
+        next.run(query)
+    }
+}
+
+// Whether one insertion, deletion, or substitution turns `a` into `b`, or they're the same
+fn within_one_edit(a: &str, b: &str) -> bool {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    let (short, long) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    if long.len() - short.len() > 1 {
+        return false;
+    }
+    let prefix = short.iter().zip(&long).take_while(|(x, y)| x == y).count();
+    if short.len() == long.len() {
+        short[prefix..]
+            .iter()
+            .skip(1)
+            .eq(long[prefix..].iter().skip(1))
+    } else {
+        short[prefix..] == long[prefix + 1..]
+    }
+}
+
+// RFC 3492 section 6.2, for the part of a label after "xn--"
+fn decode_punycode(input: &str) -> Option<String> {
+    let (basic, extended) = match input.rfind('-') {
+        Some(dash) => (&input[..dash], &input[dash + 1..]),
+        None => ("", input),
+    };
+    if !basic.is_ascii() {
+        return None;
+    }
+    let mut output: Vec<char> = basic.chars().collect();
+    let (mut n, mut i, mut bias) = (128u32, 0u32, 72u32);
+    let mut digits = extended.bytes().peekable();
+    while digits.peek().is_some() {
+        let old_i = i;
+        let mut weight = 1u32;
+        let mut k = BASE;
+        loop {
+            let digit = match digits.next()? {
+                byte @ b'a'..=b'z' => byte - b'a',
+                byte @ b'0'..=b'9' => byte - b'0' + 26,
+                _ => return None,
+            } as u32;
+            i = i.checked_add(digit.checked_mul(weight)?)?;
+            let threshold = if k <= bias {
+                TMIN
+            } else if k >= bias + TMAX {
+                TMAX
+            } else {
+                k - bias
+            };
+            if digit < threshold {
+                break;
+            }
+            weight = weight.checked_mul(BASE - threshold)?;
+            k += BASE;
+        }
+        let length = output.len() as u32 + 1;
+        bias = adapt(i - old_i, length, old_i == 0);
+        n = n.checked_add(i / length)?;
+        i %= length;
+        output.insert(i as usize, char::from_u32(n)?);
+        i += 1;
+    }
+    Some(output.into_iter().collect())
+}
+
+fn adapt(delta: u32, points: u32, first: bool) -> u32 {
+    let mut delta = if first { delta / DAMP } else { delta / 2 };
+    delta += delta / points;
+    let mut k = 0;
+    while delta > ((BASE - TMIN) * TMAX) / 2 {
+        delta /= BASE - TMIN;
+        k += BASE;
+    }
+    k + (BASE - TMIN + 1) * delta / (delta + SKEW)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::dns::mock::labels;
+
+    #[test]
+    fn flags_lookalike_names() {
+        assert_eq!(decode_punycode("bcher-kva").unwrap(), "bücher");
+        assert_eq!(decode_punycode("80ak6aa92e").unwrap(), "аррӏе");
+        assert_eq!(decode_punycode("!!"), None);
+
+        let lookalikes = Lookalikes::new(&[String::from("paypal"), String::from("ibm")], None);
+        let check = |name: &str| lookalikes.check(&labels(name)).map(|l| l.to_string());
+
+        // Cyrillic letters passing for "apple"
+        assert_eq!(
+            check("www.xn--80ak6aa92e.com"),
+            Some(String::from("аррӏе reads as apple"))
+        );
+        // A Cyrillic "а" in among Latin letters
+        assert_eq!(
+            check("xn--pypal-4ve.com"),
+            Some(String::from("pаypal mixes scripts"))
+        );
+        assert_eq!(
+            check("login.PAYPA1.example"),
+            Some(String::from("paypa1 resembles paypal"))
+        );
+        assert_eq!(
+            check("paypall.example"),
+            Some(String::from("paypall resembles paypal"))
+        );
+        assert_eq!(
+            check("lbm.example"),
+            Some(String::from("lbm resembles ibm"))
+        );
+        // Short names only match exactly
+        assert_eq!(check("ibn.example"), None);
+        assert_eq!(check("www.paypal.com"), None);
+        assert_eq!(check("xn--bcher-kva.example"), None);
+        assert_eq!(check("example.org"), None);
+    }
+}
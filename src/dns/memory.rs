@@ -0,0 +1,144 @@
+// Approximate accounting of the memory the server is using, so it can shed load when it gets
+// close to a limit rather than growing until it's OOM killed. This is bookkeeping, not an
+// allocator: callers say roughly how much each thing costs and the budget adds it up.
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// Past the first fraction of the limit, UDP clients are told to retry over TCP; past the second,
+// new queries are refused; past the limit, they're dropped without a response, since even
+// building a REFUSED costs something.
+const TRUNCATE_PERCENT: usize = 80;
+const SHED_PERCENT: usize = 90;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Pressure {
+    Normal,
+    // Answer new UDP queries with an empty truncated response, so the client retries over TCP.
+    // Queries over connections are still resolved.
+    Truncate,
+    // Answer new queries with REFUSED instead of resolving them
+    Shed,
+    // Don't even respond
+    Drop,
+}
+
+#[derive(Debug)]
+pub struct MemoryBudget {
+    // Zero means no limit
+    limit: usize,
+    in_flight: AtomicUsize,
+    truncated: AtomicU64,
+    shed: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> MemoryBudget {
+        MemoryBudget {
+            limit,
+            in_flight: AtomicUsize::new(0),
+            truncated: AtomicU64::new(0),
+            shed: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    // How loaded we are, given what's in flight plus `other` bytes accounted elsewhere (e.g. the
+    // cache, which tracks its own size). Counts the query being decided about as truncated, shed
+    // or dropped if that's the answer.
+    pub fn pressure(&self, other: usize) -> Pressure {
+        if self.limit == 0 {
+            return Pressure::Normal;
+        }
+        let used = self.in_flight() + other;
+        if used >= self.limit {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            Pressure::Drop
+        } else if used >= self.limit / 100 * SHED_PERCENT {
+            self.shed.fetch_add(1, Ordering::Relaxed);
+            Pressure::Shed
+        } else if used >= self.limit / 100 * TRUNCATE_PERCENT {
+            self.truncated.fetch_add(1, Ordering::Relaxed);
+            Pressure::Truncate
+        } else {
+            Pressure::Normal
+        }
+    }
+
+    // Account for `bytes` until the returned reservation is dropped
+    pub fn reserve(self: &Arc<Self>, bytes: usize) -> Reservation {
+        self.in_flight.fetch_add(bytes, Ordering::Relaxed);
+        Reservation {
+            budget: self.clone(),
+            bytes,
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    // Queries answered with an empty truncated response because of memory pressure
+    pub fn truncated_count(&self) -> u64 {
+        self.truncated.load(Ordering::Relaxed)
+    }
+
+    // Queries answered with REFUSED because of memory pressure
+    pub fn shed_count(&self) -> u64 {
+        self.shed.load(Ordering::Relaxed)
+    }
+
+    // Queries dropped because of memory pressure
+    pub fn dropped_count(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+pub struct Reservation {
+    budget: Arc<MemoryBudget>,
+    bytes: usize,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget
+            .in_flight
+            .fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pressure_follows_usage() {
+        let budget = Arc::new(MemoryBudget::new(1000));
+        assert_eq!(budget.pressure(0), Pressure::Normal);
+        let first = budget.reserve(500);
+        assert_eq!(budget.pressure(200), Pressure::Normal);
+        assert_eq!(budget.pressure(300), Pressure::Truncate);
+        assert_eq!(budget.pressure(400), Pressure::Shed);
+        let second = budget.reserve(500);
+        assert_eq!(budget.in_flight(), 1000);
+        assert_eq!(budget.pressure(0), Pressure::Drop);
+        drop(first);
+        drop(second);
+        assert_eq!(budget.in_flight(), 0);
+        assert_eq!(budget.pressure(0), Pressure::Normal);
+        assert_eq!(
+            (
+                budget.truncated_count(),
+                budget.shed_count(),
+                budget.dropped_count()
+            ),
+            (1, 1, 1)
+        );
+
+        // No limit, no pressure
+        let unlimited = Arc::new(MemoryBudget::new(0));
+        let _held = unlimited.reserve(1 << 30);
+        assert_eq!(unlimited.pressure(1 << 30), Pressure::Normal);
+    }
+}
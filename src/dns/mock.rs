@@ -0,0 +1,647 @@
+// Scripted nameservers for tests. Each mock listens on UDP and TCP at its own loopback address
+// (127.0.0.2, 127.0.0.3, ...) and answers from a script of canned behaviors, including the ways
+// real authorities misbehave. All the mocks in a MockNetwork share a port, so glue records (which
+// can only carry an address) point at them correctly once the resolver is told to use that port.
+//
+// Binding addresses other than 127.0.0.1 works out of the box on Linux; other platforms may need
+// loopback aliases configured.
+
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer};
+use rustls::{HandshakeKind, RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+
+use super::context::QueryContext;
+pub use super::context::Transport;
+use super::error::ResolveError;
+use super::pipeline::Pipeline;
+use super::protocol::{
+    add_option, is_subdomain, name_to_string, names_equal, supports_edns, DnsClass, DnsFlags,
+    DnsOpcode, DnsPacket, DnsQuestion, DnsRCode, DnsRRType, DnsRecordData, DnsResourceRecord,
+    EdnsOption, ExtendedDnsError, SrvData,
+};
+use super::recursive::RootHints;
+use super::suffix::SuffixTrie;
+
+// How often server threads wake up to check if they've been shut down
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+#[derive(Clone, Debug)]
+pub enum Behavior {
+    // An authoritative answer containing these records
+    Answer(Vec<DnsResourceRecord>),
+    // A referral: NS records for the authority section and glue for the additional section
+    Referral(Vec<DnsResourceRecord>, Vec<DnsResourceRecord>),
+    // An authoritative NXDOMAIN, with whatever records (usually an SOA) in the authority section
+    NXDomain(Vec<DnsResourceRecord>),
+    // An authoritative NOERROR with no answers (NODATA), and these records in the authority
+    // section
+    NoData(Vec<DnsResourceRecord>),
+    // An empty response with this rcode, e.g. REFUSED from a server that isn't authoritative
+    Rcode(DnsRCode),
+    // The same, with these extended errors (RFC 8914) explaining it
+    Extended(DnsRCode, Vec<ExtendedDnsError>),
+    // A lame server: NOERROR, but not authoritative and with nothing in any section
+    Lame,
+    // Over UDP, an empty response with the TC bit set. Over TCP, the wrapped behavior.
+    Truncated(Box<Behavior>),
+    // Over UDP, a reply cut off partway through its header, as from a mangling middlebox. Over
+    // TCP, the wrapped behavior.
+    Garbled(Box<Behavior>),
+    // The wrapped behavior, after sleeping
+    Delay(Duration, Box<Behavior>),
+    // The wrapped behavior, with these EDNS options added to the response
+    Options(Vec<EdnsOption>, Box<Behavior>),
+    // A server from before EDNS: FORMERR for queries with an OPT record, the wrapped behavior
+    // for the rest
+    NoEdns(Box<Behavior>),
+    // Never respond at all
+    Silent,
+}
+
+#[derive(Clone, Debug)]
+struct Rule {
+    name: Vec<String>,
+    // Whether the rule also matches names below `name`, as for a zone cut
+    subdomains: bool,
+    qtype: Option<DnsRRType>,
+    behavior: Behavior,
+}
+
+// What a mock nameserver does for each question it's asked. Rules are checked in the order
+// they're added and the first match wins; anything unmatched gets the fallback (REFUSED, unless
+// changed with `otherwise`).
+#[derive(Clone, Debug)]
+pub struct Script {
+    rules: Vec<Rule>,
+    fallback: Behavior,
+}
+
+impl Default for Script {
+    fn default() -> Script {
+        Script::new()
+    }
+}
+
+impl Script {
+    pub fn new() -> Script {
+        Script {
+            rules: Vec::new(),
+            fallback: Behavior::Rcode(DnsRCode::Refused),
+        }
+    }
+
+    // Respond to questions for exactly `name` (and `qtype`, if given) with `behavior`
+    pub fn on(mut self, name: &str, qtype: Option<DnsRRType>, behavior: Behavior) -> Script {
+        self.rules.push(Rule {
+            name: labels(name),
+            subdomains: false,
+            qtype,
+            behavior,
+        });
+        self
+    }
+
+    // Respond to questions for `zone` or any name below it with `behavior`
+    pub fn under(mut self, zone: &str, behavior: Behavior) -> Script {
+        self.rules.push(Rule {
+            name: labels(zone),
+            subdomains: true,
+            qtype: None,
+            behavior,
+        });
+        self
+    }
+
+    // Shorthand for a referral of `zone` to a single nameserver, with a glue record for it
+    pub fn delegate(self, zone: &str, ns_name: &str, ns_ip: Ipv4Addr) -> Script {
+        self.under(
+            zone,
+            Behavior::Referral(vec![ns(zone, ns_name)], vec![a(ns_name, ns_ip)]),
+        )
+    }
+
+    pub fn otherwise(mut self, behavior: Behavior) -> Script {
+        self.fallback = behavior;
+        self
+    }
+
+    fn behavior_for(&self, question: &DnsQuestion) -> &Behavior {
+        for rule in &self.rules {
+            let name_matches = if rule.subdomains {
+                is_subdomain(&question.qname, &rule.name)
+            } else {
+                names_equal(&question.qname, &rule.name)
+            };
+            let type_matches = match rule.qtype {
+                Some(qtype) => qtype == question.qtype,
+                None => true,
+            };
+            if name_matches && type_matches {
+                return &rule.behavior;
+            }
+        }
+        &self.fallback
+    }
+}
+
+pub struct MockNameserver {
+    pub addr: SocketAddr,
+    queries: Arc<Mutex<Vec<(Transport, DnsQuestion)>>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl MockNameserver {
+    fn start(addr: SocketAddr, script: Script) -> MockNameserver {
+        let udp = UdpSocket::bind(addr).expect("could not bind mock UDP socket");
+        udp.set_read_timeout(Some(POLL_INTERVAL)).unwrap();
+        let tcp = TcpListener::bind(addr).expect("could not bind mock TCP listener");
+        tcp.set_nonblocking(true).unwrap();
+
+        let script = Arc::new(script);
+        let queries = Arc::new(Mutex::new(Vec::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let (udp_script, udp_queries, udp_stop) = (script.clone(), queries.clone(), stop.clone());
+        thread::spawn(move || serve_udp(udp, &udp_script, &udp_queries, &udp_stop));
+        let (tcp_queries, tcp_stop) = (queries.clone(), stop.clone());
+        thread::spawn(move || serve_tcp(tcp, &script, &tcp_queries, &tcp_stop));
+
+        MockNameserver {
+            addr,
+            queries,
+            stop,
+        }
+    }
+
+    // Every question this server has been asked so far, in order, with how it was asked
+    pub fn queries(&self) -> Vec<(Transport, DnsQuestion)> {
+        self.queries.lock().unwrap().clone()
+    }
+}
+
+impl Drop for MockNameserver {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+// A set of mock nameservers sharing a port, along with root hints pointing at one of them
+pub struct MockNetwork {
+    port: u16,
+    // Held for the life of the network so no other MockNetwork can be handed the same port
+    _port_reservation: UdpSocket,
+}
+
+impl Default for MockNetwork {
+    fn default() -> MockNetwork {
+        MockNetwork::new()
+    }
+}
+
+impl MockNetwork {
+    pub fn new() -> MockNetwork {
+        let reservation = UdpSocket::bind("127.0.0.1:0").expect("could not reserve a port");
+        MockNetwork {
+            port: reservation.local_addr().unwrap().port(),
+            _port_reservation: reservation,
+        }
+    }
+
+    // Start a nameserver at `ip` (which should be a loopback address other than 127.0.0.1, or ::1)
+    pub fn serve(&self, ip: impl Into<IpAddr>, script: Script) -> MockNameserver {
+        MockNameserver::start(SocketAddr::new(ip.into(), self.port), script)
+    }
+
+    pub fn hints(&self, root: Ipv4Addr) -> RootHints {
+        RootHints {
+            roots: Arc::new(RwLock::new(vec![IpAddr::V4(root)])),
+            port: self.port,
+            mirror: None,
+            stub_zones: SuffixTrie::new(),
+            latencies: Arc::default(),
+            delegations: Arc::default(),
+        }
+    }
+}
+
+fn serve_udp(
+    socket: UdpSocket,
+    script: &Arc<Script>,
+    queries: &Arc<Mutex<Vec<(Transport, DnsQuestion)>>>,
+    stop: &AtomicBool,
+) {
+    let mut buf = [0; 4096];
+    while !stop.load(Ordering::Relaxed) {
+        let (amt, src) = match socket.recv_from(&mut buf) {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        let query = match DnsPacket::from_bytes(&buf[..amt]) {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        // Respond from another thread so a delayed answer doesn't hold up other queries
+        let (socket, script, queries) =
+            (socket.try_clone().unwrap(), script.clone(), queries.clone());
+        thread::spawn(move || {
+            if let Some(bytes) = respond(&script, &queries, &query, Transport::Udp) {
+                let _ = socket.send_to(&bytes, src);
+            }
+        });
+    }
+}
+
+fn serve_tcp(
+    listener: TcpListener,
+    script: &Arc<Script>,
+    queries: &Arc<Mutex<Vec<(Transport, DnsQuestion)>>>,
+    stop: &AtomicBool,
+) {
+    while !stop.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                let (script, queries) = (script.clone(), queries.clone());
+                thread::spawn(move || serve_tcp_connection(stream, &script, &queries));
+            }
+            Err(_) => thread::sleep(POLL_INTERVAL),
+        }
+    }
+}
+
+// Serve length-prefixed messages on one connection until the client hangs up
+fn serve_tcp_connection(
+    mut stream: TcpStream,
+    script: &Script,
+    queries: &Mutex<Vec<(Transport, DnsQuestion)>>,
+) {
+    stream.set_nonblocking(false).unwrap();
+    loop {
+        let mut length = [0u8; 2];
+        if stream.read_exact(&mut length).is_err() {
+            return;
+        }
+        let mut message = vec![0u8; ((length[0] as usize) << 8) + length[1] as usize];
+        if stream.read_exact(&mut message).is_err() {
+            return;
+        }
+        let query = match DnsPacket::from_bytes(&message) {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+        if let Some(bytes) = respond(script, queries, &query, Transport::Tcp) {
+            let mut framed = vec![(bytes.len() >> 8) as u8, bytes.len() as u8];
+            framed.extend_from_slice(&bytes);
+            if stream.write_all(&framed).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+fn respond(
+    script: &Script,
+    queries: &Mutex<Vec<(Transport, DnsQuestion)>>,
+    query: &DnsPacket,
+    transport: Transport,
+) -> Option<Vec<u8>> {
+    let question = query.questions.first()?;
+    queries
+        .lock()
+        .unwrap()
+        .push((transport, question.to_owned()));
+    let behavior = script.behavior_for(question);
+    let mut bytes = build_response(behavior, query, transport)?.to_bytes();
+    if let (Behavior::Garbled(_), Transport::Udp) = (behavior, transport) {
+        bytes.truncate(7);
+    }
+    Some(bytes)
+}
+
+fn build_response(
+    behavior: &Behavior,
+    query: &DnsPacket,
+    transport: Transport,
+) -> Option<DnsPacket> {
+    let mut response = DnsPacket {
+        id: query.id,
+        flags: DnsFlags {
+            qr_bit: true,
+            aa_bit: false,
+            tc_bit: false,
+            ra_bit: false,
+            ad_bit: false,
+            cd_bit: false,
+            rcode: DnsRCode::NoError,
+            ..query.flags.to_owned()
+        },
+        questions: query.questions.to_owned(),
+        answers: Vec::new(),
+        nameservers: Vec::new(),
+        addl_recs: Vec::new(),
+    };
+    match behavior {
+        Behavior::Answer(records) => {
+            response.flags.aa_bit = true;
+            response.answers = records.to_owned();
+        }
+        Behavior::Referral(nameservers, glue) => {
+            response.nameservers = nameservers.to_owned();
+            response.addl_recs = glue.to_owned();
+        }
+        Behavior::NXDomain(authority) => {
+            response.flags.aa_bit = true;
+            response.flags.rcode = DnsRCode::NXDomain;
+            response.nameservers = authority.to_owned();
+        }
+        Behavior::NoData(authority) => {
+            response.flags.aa_bit = true;
+            response.nameservers = authority.to_owned();
+        }
+        Behavior::Rcode(rcode) => response.flags.rcode = rcode.to_owned(),
+        Behavior::Extended(rcode, errors) => {
+            response.flags.rcode = rcode.to_owned();
+            for error in errors {
+                error.attach(&mut response);
+            }
+        }
+        Behavior::Lame => (),
+        Behavior::Truncated(inner) => {
+            if transport == Transport::Tcp {
+                return build_response(inner, query, transport);
+            }
+            response.flags.tc_bit = true;
+        }
+        Behavior::Garbled(inner) => return build_response(inner, query, transport),
+        Behavior::Delay(delay, inner) => {
+            thread::sleep(*delay);
+            return build_response(inner, query, transport);
+        }
+        Behavior::Options(options, inner) => {
+            let mut response = build_response(inner, query, transport)?;
+            for option in options {
+                add_option(&mut response, option.to_owned());
+            }
+            return Some(response);
+        }
+        Behavior::NoEdns(inner) => {
+            if !supports_edns(query) {
+                return build_response(inner, query, transport);
+            }
+            response.flags.rcode = DnsRCode::FormError;
+        }
+        Behavior::Silent => return None,
+    }
+    Some(response)
+}
+
+// A DNS over TLS server with a self-signed certificate for `names` (hostnames or IP addresses),
+// answering every query with an A record for `answer`. Unlike the mock nameservers it listens at
+// 127.0.0.1, on a port of its own.
+pub struct MockTlsServer {
+    pub addr: SocketAddr,
+    pub cert: CertificateDer<'static>,
+    full_handshakes: Arc<AtomicUsize>,
+    resumed_handshakes: Arc<AtomicUsize>,
+}
+
+impl MockTlsServer {
+    pub fn start(names: &[&str], answer: Ipv4Addr) -> MockTlsServer {
+        let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        let certified = rcgen::generate_simple_self_signed(names).expect("could not make a cert");
+        let cert = certified.cert.der().to_owned();
+        let key = PrivatePkcs8KeyDer::from(certified.signing_key.serialize_der());
+        let config =
+            ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .and_then(|builder| {
+                    builder
+                        .with_no_client_auth()
+                        .with_single_cert(vec![cert.to_owned()], key.into())
+                })
+                .expect("could not configure TLS");
+        let config = Arc::new(config);
+        let listener = TcpListener::bind("127.0.0.1:0").expect("could not bind");
+        let server = MockTlsServer {
+            addr: listener.local_addr().unwrap(),
+            cert,
+            full_handshakes: Arc::default(),
+            resumed_handshakes: Arc::default(),
+        };
+        let (full, resumed) = (
+            server.full_handshakes.clone(),
+            server.resumed_handshakes.clone(),
+        );
+        thread::spawn(move || {
+            for socket in listener.incoming().flatten() {
+                let connection = ServerConnection::new(config.clone()).unwrap();
+                let stream = StreamOwned::new(connection, socket);
+                let (full, resumed) = (full.clone(), resumed.clone());
+                thread::spawn(move || serve_tls(stream, answer, &full, &resumed));
+            }
+        });
+        server
+    }
+
+    // Trusts this server's certificate and nothing else
+    pub fn roots(&self) -> RootCertStore {
+        let mut roots = RootCertStore::empty();
+        roots.add(self.cert.to_owned()).unwrap();
+        roots
+    }
+
+    // Full handshakes and resumed ones, for connections that got as far as a query
+    pub fn handshakes(&self) -> (usize, usize) {
+        (
+            self.full_handshakes.load(Ordering::SeqCst),
+            self.resumed_handshakes.load(Ordering::SeqCst),
+        )
+    }
+}
+
+fn serve_tls(
+    mut stream: StreamOwned<ServerConnection, TcpStream>,
+    answer: Ipv4Addr,
+    full: &AtomicUsize,
+    resumed: &AtomicUsize,
+) {
+    let mut counted = false;
+    let mut length = [0u8; 2];
+    while stream.read_exact(&mut length).is_ok() {
+        if !counted {
+            counted = true;
+            match stream.conn.handshake_kind() {
+                Some(HandshakeKind::Resumed) => resumed,
+                _ => full,
+            }
+            .fetch_add(1, Ordering::SeqCst);
+        }
+        let mut query = vec![0u8; u16::from_be_bytes(length) as usize];
+        if stream.read_exact(&mut query).is_err() {
+            return;
+        }
+        let mut response = match DnsPacket::from_bytes(&query) {
+            Ok(query) => query,
+            Err(_) => return,
+        };
+        response.flags.qr_bit = true;
+        response.flags.ra_bit = true;
+        let qname = name_to_string(&response.questions[0].qname);
+        response.answers = vec![a(&qname, answer)];
+        let bytes = response.to_bytes();
+        let mut framed = (bytes.len() as u16).to_be_bytes().to_vec();
+        framed.extend_from_slice(&bytes);
+        if stream.write_all(&framed).is_err() {
+            return;
+        }
+    }
+}
+
+// Helpers for building records in scripts and assertions
+
+pub fn labels(name: &str) -> Vec<String> {
+    name.split('.')
+        .filter(|label| !label.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+pub fn a(name: &str, ip: Ipv4Addr) -> DnsResourceRecord {
+    record(name, DnsRRType::A, DnsRecordData::A(ip))
+}
+
+pub fn ns(zone: &str, ns_name: &str) -> DnsResourceRecord {
+    record(zone, DnsRRType::NS, DnsRecordData::NS(labels(ns_name)))
+}
+
+pub fn cname(name: &str, target: &str) -> DnsResourceRecord {
+    record(name, DnsRRType::CNAME, DnsRecordData::CNAME(labels(target)))
+}
+
+pub fn aaaa(name: &str, ip: Ipv6Addr) -> DnsResourceRecord {
+    record(name, DnsRRType::AAAA, DnsRecordData::AAAA(ip))
+}
+
+pub fn mx(name: &str, preference: u16, exchange: &str) -> DnsResourceRecord {
+    record(
+        name,
+        DnsRRType::MX,
+        DnsRecordData::MX(preference, labels(exchange)),
+    )
+}
+
+pub fn srv(name: &str, priority: u16, port: u16, target: &str) -> DnsResourceRecord {
+    let srv = SrvData {
+        priority,
+        weight: 0,
+        port,
+        target: labels(target),
+    };
+    record(name, DnsRRType::SRV, DnsRecordData::SRV(srv))
+}
+
+pub fn txt(name: &str, strings: &[&str]) -> DnsResourceRecord {
+    let strings = strings.iter().map(|s| s.as_bytes().to_vec()).collect();
+    record(name, DnsRRType::TXT, DnsRecordData::TXT(strings))
+}
+
+pub fn ptr(name: &str, target: &str) -> DnsResourceRecord {
+    record(name, DnsRRType::PTR, DnsRecordData::PTR(labels(target)))
+}
+
+pub fn question(name: &str, qtype: DnsRRType) -> DnsQuestion {
+    DnsQuestion {
+        qname: labels(name),
+        qtype,
+        qclass: DnsClass::IN,
+    }
+}
+
+// A client's query for one question, with recursion desired
+pub fn query(name: &str, qtype: DnsRRType) -> DnsPacket {
+    DnsPacket {
+        id: 1,
+        flags: DnsFlags {
+            qr_bit: false,
+            opcode: DnsOpcode::Query,
+            aa_bit: false,
+            tc_bit: false,
+            rd_bit: true,
+            ra_bit: false,
+            ad_bit: false,
+            cd_bit: false,
+            rcode: DnsRCode::NoError,
+        },
+        questions: vec![question(name, qtype)],
+        answers: Vec::new(),
+        nameservers: Vec::new(),
+        addl_recs: Vec::new(),
+    }
+}
+
+// Run `query` through `pipeline` as if it came from a client on localhost over UDP
+pub fn run(pipeline: &Pipeline, query: &DnsPacket) -> Result<DnsPacket, ResolveError> {
+    let client = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 53000);
+    pipeline.run(query, &QueryContext::new(query, client, Transport::Udp, 0))
+}
+
+fn record(name: &str, rr_type: DnsRRType, data: DnsRecordData) -> DnsResourceRecord {
+    DnsResourceRecord {
+        name: labels(name),
+        rr_type,
+        class: DnsClass::IN,
+        ttl: 3600,
+        record: data,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncates_over_udp_only() {
+        let network = MockNetwork::new();
+        let answer = vec![a("big.test", Ipv4Addr::new(192, 0, 2, 1))];
+        let server = network.serve(
+            Ipv4Addr::new(127, 0, 0, 2),
+            Script::new().otherwise(Behavior::Truncated(Box::new(Behavior::Answer(
+                answer.to_owned(),
+            )))),
+        );
+        // An all zero header is a query with no flags set
+        let mut query = DnsPacket::from_bytes(&[0; 12]).unwrap();
+        query.questions = vec![question("big.test", DnsRRType::A)];
+        let query_bytes = query.to_bytes();
+
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        udp.send_to(&query_bytes, server.addr).unwrap();
+        let mut buf = [0; 512];
+        let amt = udp.recv(&mut buf).unwrap();
+        let response = DnsPacket::from_bytes(&buf[..amt]).unwrap();
+        assert!(response.flags.tc_bit);
+        assert!(response.answers.is_empty());
+
+        let mut tcp = TcpStream::connect(server.addr).unwrap();
+        let mut framed = vec![0, query_bytes.len() as u8];
+        framed.extend_from_slice(&query_bytes);
+        tcp.write_all(&framed).unwrap();
+        let mut length = [0u8; 2];
+        tcp.read_exact(&mut length).unwrap();
+        let mut message = vec![0u8; length[1] as usize];
+        tcp.read_exact(&mut message).unwrap();
+        let response = DnsPacket::from_bytes(&message).unwrap();
+        assert!(!response.flags.tc_bit);
+        assert_eq!(response.answers, answer);
+
+        assert_eq!(
+            server.queries().iter().map(|(t, _)| *t).collect::<Vec<_>>(),
+            vec![Transport::Udp, Transport::Tcp]
+        );
+    }
+}
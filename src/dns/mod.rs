@@ -1,2 +1,44 @@
+pub mod access;
+pub mod analytics;
+pub mod async_resolver;
+pub mod cache;
+pub mod cache_file;
+pub mod config;
+pub mod context;
+#[cfg(unix)]
+pub mod control;
+pub mod dane;
+pub mod ddr;
+pub mod debug;
+pub mod dot;
+pub mod error;
+pub mod error_report;
+pub mod journal;
+pub mod latency;
+pub mod lookalike;
+pub mod memory;
+#[cfg(test)]
+pub mod mock;
+pub mod outbound;
+pub mod pipeline;
 pub mod protocol;
+pub mod query_log;
+pub mod rate_limit;
 pub mod recursive;
+pub mod resolver;
+#[cfg(feature = "scripting")]
+pub mod script;
+pub mod sinkhole;
+pub mod stats;
+pub mod status;
+pub mod suffix;
+pub mod telemetry;
+pub mod trace;
+pub mod transfer;
+pub mod ttl;
+pub mod update;
+pub mod upstream;
+pub mod validation;
+pub mod warm;
+pub mod zone;
+pub mod zone_watch;
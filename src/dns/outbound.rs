@@ -0,0 +1,203 @@
+// Where queries to other servers go out from. Normally that's up to the routing table, but on a
+// host with more than one way out, like one with a VPN up, the default route isn't always the
+// one DNS should take. A source is either a local address to send from, or a network interface
+// to send through (SO_BINDTODEVICE, Linux only), and can be given for every server or just for
+// one upstream.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::str::FromStr;
+use std::time::Duration;
+
+use socket2::{Domain, Socket, Type};
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Source {
+    Address(IpAddr),
+    Interface(String),
+}
+
+// The sources for every server, at most one used for each: the first with the server's address
+// family, or the first interface. Upstreams with a source of their own use that instead.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Outbound {
+    pub sources: Vec<Source>,
+    pub upstreams: HashMap<SocketAddr, Source>,
+}
+
+// What a socket to a server is bound to: an address, unspecified to let the routing table pick,
+// and maybe an interface
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Binding {
+    pub address: IpAddr,
+    pub interface: Option<String>,
+}
+
+impl Outbound {
+    pub fn binding(&self, server: SocketAddr) -> Binding {
+        let matches = |source: &&Source| match source {
+            Source::Address(address) => address.is_ipv4() == server.is_ipv4(),
+            Source::Interface(_) => true,
+        };
+        let source = match self.upstreams.get(&server) {
+            Some(source) => Some(source).filter(matches),
+            None => self.sources.iter().find(matches),
+        };
+        let unspecified = match server {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        match source {
+            Some(Source::Address(address)) => Binding {
+                address: *address,
+                interface: None,
+            },
+            Some(Source::Interface(interface)) => Binding {
+                address: unspecified,
+                interface: Some(interface.to_owned()),
+            },
+            None => Binding {
+                address: unspecified,
+                interface: None,
+            },
+        }
+    }
+}
+
+impl Binding {
+    pub fn udp_socket(&self) -> io::Result<UdpSocket> {
+        let socket = self.socket(Type::dgram())?;
+        Ok(socket.into_udp_socket())
+    }
+
+    pub fn connect(&self, server: SocketAddr, timeout: Duration) -> io::Result<TcpStream> {
+        let socket = self.socket(Type::stream())?;
+        socket.connect_timeout(&server.into(), timeout)?;
+        Ok(socket.into_tcp_stream())
+    }
+
+    fn socket(&self, kind: Type) -> io::Result<Socket> {
+        let domain = if self.address.is_ipv4() {
+            Domain::ipv4()
+        } else {
+            Domain::ipv6()
+        };
+        let socket = Socket::new(domain, kind, None)?;
+        if let Some(interface) = &self.interface {
+            bind_device(&socket, interface)?;
+        }
+        socket.bind(&SocketAddr::new(self.address, 0).into())?;
+        Ok(socket)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn bind_device(socket: &Socket, interface: &str) -> io::Result<()> {
+    let name = CString::new(interface)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "bad interface name"))?;
+    socket
+        .bind_device(Some(&name))
+        .map_err(|e| io::Error::new(e.kind(), format!("can't send through {}: {}", interface, e)))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_device(_socket: &Socket, _interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "sending through an interface is only supported on Linux",
+    ))
+}
+
+impl FromStr for Source {
+    type Err = String;
+
+    // An address, or failing that, the name of an interface
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(address) = s.parse() {
+            return Ok(Source::Address(address));
+        }
+        let valid = !s.is_empty()
+            && s.chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+        if !valid {
+            return Err(format!("{} isn't an address or interface name", s));
+        }
+        Ok(Source::Interface(s.to_owned()))
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Address(address) => write!(f, "{}", address),
+            Source::Interface(interface) => write!(f, "{}", interface),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::dns::config::Upstream;
+
+    #[test]
+    fn queries_go_out_from_the_source_for_the_server() {
+        let v4: SocketAddr = "192.0.2.53:53".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::53]:53".parse().unwrap();
+        let special: SocketAddr = "198.51.100.53:53".parse().unwrap();
+        let mut outbound = Outbound::default();
+        assert_eq!(
+            outbound.binding(v6),
+            Binding {
+                address: IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                interface: None
+            }
+        );
+
+        outbound.sources = vec!["10.0.0.2".parse().unwrap()];
+        outbound.upstreams.insert(special, "wg0".parse().unwrap());
+        assert_eq!(
+            outbound.binding(v4).address,
+            "10.0.0.2".parse::<IpAddr>().unwrap()
+        );
+        // An IPv4 source is no use to an IPv6 server
+        assert_eq!(
+            outbound.binding(v6).address,
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        );
+        assert_eq!(
+            outbound.binding(special),
+            Binding {
+                address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                interface: Some(String::from("wg0"))
+            }
+        );
+
+        // Sockets really are bound where they're meant to be
+        let binding = Binding {
+            address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            interface: None,
+        };
+        let socket = binding.udp_socket().unwrap();
+        assert_eq!(socket.local_addr().unwrap().ip(), binding.address);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = binding
+            .connect(listener.local_addr().unwrap(), Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(stream.local_addr().unwrap().ip(), binding.address);
+
+        let upstream: Upstream = "10.8.0.1:853@wg0#dns.example".parse().unwrap();
+        assert_eq!(
+            upstream.source,
+            Some(Source::Interface(String::from("wg0")))
+        );
+        assert_eq!(upstream.to_string(), "10.8.0.1:853@wg0#dns.example");
+        assert!("10.8.0.1:53@".parse::<Upstream>().is_err());
+        assert!("eth0 ".parse::<Source>().is_err());
+        assert!("".parse::<Source>().is_err());
+    }
+}
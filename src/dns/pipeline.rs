@@ -0,0 +1,889 @@
+// The server's query path as an ordered chain of stages. Each stage gets the client's query and
+// the rest of the chain: it can answer on its own (short-circuiting everything after it), or
+// call `next.run` and then look over or rewrite whatever comes back. Features like access
+// control, blocklists, and logging become stages slotted in where they belong, rather than more
+// code wired into the middle of one function.
+//
+// The standard chain, outermost first:
+//   finish     turns errors into responses, answers queries with bad EDNS, and gives the
+//              response the client's id and EDNS
+//   question   deals with queries without exactly one question, as --multiple-questions says
+//   hostnames  checks names in the question and answer, if hostname validation is on
+//   local      answers from zones and local data
+//   norecurse  answers queries with RD clear from the cache, or else with a referral
+//   cache      answers from the cache, and caches what comes back from the network
+//   resolve    recursion or forwarding
+//
+// Alongside the query, every stage can see its context: the client, when it asked, and so on.
+
+use std::str::FromStr;
+use std::sync::Arc;
+
+use super::access::AddressPrefix;
+use super::context::QueryContext;
+use super::error::ResolveError;
+use super::protocol::{
+    client_subnet, is_subdomain, names_equal, opt_count, set_bad_version, set_response_opt,
+    supports_edns, DnsFlags, DnsFormatError, DnsPacket, DnsQuestion, DnsRCode, DnsRRType,
+    DnsRecordData, EdnsRegistry, ExtendedDnsError,
+};
+use super::resolver::Resolver;
+use super::trace::{error, log};
+use super::validation::{self, HostnameValidation};
+
+pub trait Middleware: Send + Sync {
+    // Short and unique in a pipeline, for finding a stage to insert others around
+    fn name(&self) -> &str;
+
+    fn handle(&self, query: &DnsPacket, next: Next) -> Result<DnsPacket, ResolveError>;
+}
+
+// The stages after the current one, and the context of the query they're running for
+pub struct Next<'a> {
+    stages: &'a [Arc<dyn Middleware>],
+    context: &'a QueryContext,
+}
+
+impl<'a> Next<'a> {
+    pub fn context(&self) -> &'a QueryContext {
+        self.context
+    }
+
+    pub fn run(self, query: &DnsPacket) -> Result<DnsPacket, ResolveError> {
+        let context = self.context;
+        match self.stages.split_first() {
+            Some((stage, rest)) => stage.handle(
+                query,
+                Next {
+                    stages: rest,
+                    context,
+                },
+            ),
+            None => Err(ResolveError::Internal(String::from(
+                "No stage in the pipeline answered",
+            ))),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Pipeline {
+    stages: Vec<Arc<dyn Middleware>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Pipeline {
+        Pipeline::default()
+    }
+
+    pub fn standard(
+        resolver: Resolver,
+        validation: HostnameValidation,
+        edns: EdnsRegistry,
+    ) -> Pipeline {
+        Pipeline::new()
+            .then(Arc::new(Finish { edns }))
+            .then(Arc::new(OneQuestion::default()))
+            .then(Arc::new(Hostnames { validation }))
+            .then(Arc::new(Local {
+                resolver: resolver.to_owned(),
+            }))
+            .then(Arc::new(NoRecursion {
+                resolver: resolver.to_owned(),
+            }))
+            .then(Arc::new(Cached {
+                resolver: resolver.to_owned(),
+            }))
+            .then(Arc::new(Resolve { resolver }))
+    }
+
+    // Add a stage after all the others
+    pub fn then(mut self, stage: Arc<dyn Middleware>) -> Pipeline {
+        self.stages.push(stage);
+        self
+    }
+
+    // Add a stage just before the one named `name`
+    pub fn insert_before(&mut self, name: &str, stage: Arc<dyn Middleware>) -> Result<(), String> {
+        let index = self.position(name)?;
+        self.stages.insert(index, stage);
+        Ok(())
+    }
+
+    pub fn insert_after(&mut self, name: &str, stage: Arc<dyn Middleware>) -> Result<(), String> {
+        let index = self.position(name)?;
+        self.stages.insert(index + 1, stage);
+        Ok(())
+    }
+
+    // Put a stage in place of the one named `name`
+    pub fn replace(&mut self, name: &str, stage: Arc<dyn Middleware>) -> Result<(), String> {
+        let index = self.position(name)?;
+        self.stages[index] = stage;
+        Ok(())
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.stages.iter().map(|stage| stage.name()).collect()
+    }
+
+    fn position(&self, name: &str) -> Result<usize, String> {
+        self.stages
+            .iter()
+            .position(|stage| stage.name() == name)
+            .ok_or_else(|| format!("No pipeline stage named {}", name))
+    }
+
+    // A response for the client, or an Err if the query should be dropped
+    pub fn run(
+        &self,
+        query: &DnsPacket,
+        context: &QueryContext,
+    ) -> Result<DnsPacket, ResolveError> {
+        Next {
+            stages: &self.stages,
+            context,
+        }
+        .run(query)
+    }
+}
+
+// An empty response to the given query with the given error code
+pub fn error_response(query: &DnsPacket, rcode: DnsRCode) -> DnsPacket {
+    DnsPacket {
+        id: query.id,
+        flags: DnsFlags {
+            qr_bit: true,
+            aa_bit: false,
+            tc_bit: false,
+            ra_bit: true,
+            ad_bit: false,
+            rcode,
+            ..query.flags.to_owned()
+        },
+        questions: query.questions.to_owned(),
+        answers: Vec::new(),
+        nameservers: Vec::new(),
+        addl_recs: Vec::new(),
+    }
+}
+
+struct Finish {
+    edns: EdnsRegistry,
+}
+
+impl Middleware for Finish {
+    fn name(&self) -> &str {
+        "finish"
+    }
+
+    fn handle(&self, query: &DnsPacket, next: Next) -> Result<DnsPacket, ResolveError> {
+        let mut response = match self.answer(query, next) {
+            Ok(response) => response,
+            Err(e) => {
+                let rcode = match e.rcode() {
+                    Some(rcode) => rcode,
+                    None => return Err(e),
+                };
+                if let ResolveError::Internal(_) = e {
+                    error!("BUG: {}", e);
+                } else {
+                    log!("Resolution failed: {}", e);
+                }
+                // Say what went wrong, to clients that can hear it
+                let mut response = error_response(query, rcode);
+                if let (Some(code), true) = (e.ede(), supports_edns(query)) {
+                    ExtendedDnsError::new(code, &e.to_string()).attach(&mut response);
+                }
+                response
+            }
+        };
+        // Whatever EDNS we used getting the answer is between us and the upstream
+        set_response_opt(query, &mut response);
+        self.edns.respond(query, &mut response);
+        // Whoever answered, the header is ours: the client's id and flags, and recursion
+        // available. AA is left as the stage that answered set it, which is only ever for our own
+        // zones and local data.
+        response.id = query.id;
+        response.flags.qr_bit = true;
+        response.flags.opcode = query.flags.opcode;
+        response.flags.rd_bit = query.flags.rd_bit;
+        response.flags.cd_bit = query.flags.cd_bit;
+        response.flags.ra_bit = true;
+        Ok(response)
+    }
+}
+
+impl Finish {
+    // Queries whose EDNS we can't make sense of are answered here; the rest go down the chain
+    fn answer(&self, query: &DnsPacket, next: Next) -> Result<DnsPacket, ResolveError> {
+        // RFC 6891 sections 6.1.1 and 6.1.3
+        let opts = opt_count(query);
+        if opts > 1 {
+            log!("Query has {} OPT records, where only one is allowed", opts);
+            return Ok(error_response(query, DnsRCode::FormError));
+        }
+        match next.context().edns.map(|edns| edns.version) {
+            Some(version) if version > 0 => {
+                log!("Query uses EDNS version {}, but we only know 0", version);
+                let mut response = error_response(query, DnsRCode::NoError);
+                set_bad_version(&mut response);
+                Ok(response)
+            }
+            _ => next.run(query),
+        }
+    }
+}
+
+// The exact semantics of what to do with multiple questions as part of the same query is unclear.
+// Technically, they're allowed by RFC 1035, but there's practical issues (e.g. if two different
+// domains are queried for, what does an NXDOMAIN status code in the header indicate?). RFC 9619
+// has since said there can only be one. Some nameservers answer the first question and ignore the
+// rest; most say FORMERR, which is our default. A query with no question at all is a FORMERR
+// whatever the policy, unless it's to drop them.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum QuestionPolicy {
+    // Answer the first question, as if it were the only one
+    First,
+    #[default]
+    FormError,
+    NotImp,
+    // Don't respond at all
+    Drop,
+}
+
+impl FromStr for QuestionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<QuestionPolicy, String> {
+        match s {
+            "first" => Ok(QuestionPolicy::First),
+            "formerr" => Ok(QuestionPolicy::FormError),
+            "notimp" => Ok(QuestionPolicy::NotImp),
+            "drop" => Ok(QuestionPolicy::Drop),
+            _ => Err(format!(
+                "Unknown multiple question policy {:?} (expected first, formerr, notimp, or drop)",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct OneQuestion {
+    policy: QuestionPolicy,
+}
+
+impl OneQuestion {
+    pub fn new(policy: QuestionPolicy) -> OneQuestion {
+        OneQuestion { policy }
+    }
+}
+
+impl Middleware for OneQuestion {
+    fn name(&self) -> &str {
+        "question"
+    }
+
+    fn handle(&self, query: &DnsPacket, next: Next) -> Result<DnsPacket, ResolveError> {
+        let count = query.questions.len();
+        if count == 1 {
+            return next.run(query);
+        }
+        log!("Question count was {}, we require it be 1", count);
+        match self.policy {
+            QuestionPolicy::Drop => Err(ResolveError::Unanswerable(format!("{} questions", count))),
+            _ if count == 0 => Ok(error_response(query, DnsRCode::FormError)),
+            QuestionPolicy::First => {
+                let mut first = query.to_owned();
+                first.questions.truncate(1);
+                next.run(&first)
+            }
+            QuestionPolicy::FormError => Ok(error_response(query, DnsRCode::FormError)),
+            QuestionPolicy::NotImp => Ok(error_response(query, DnsRCode::NotImp)),
+        }
+    }
+}
+
+struct Hostnames {
+    validation: HostnameValidation,
+}
+
+impl Middleware for Hostnames {
+    fn name(&self) -> &str {
+        "hostnames"
+    }
+
+    fn handle(&self, query: &DnsPacket, next: Next) -> Result<DnsPacket, ResolveError> {
+        if self.validation == HostnameValidation::Off {
+            return next.run(query);
+        }
+        let violations = validation::question_violations(query);
+        for violation in &violations {
+            log!("Hostname validation failed for {}", violation);
+        }
+        if self.validation == HostnameValidation::Strict && !violations.is_empty() {
+            // This is a format error on the client's part, so respond the same way we would to
+            // a packet we couldn't parse
+            let mut error = DnsFormatError::make_error(violations.join("; "));
+            error.set_partial(query.to_owned());
+            return Ok(error.get_error_response().unwrap());
+        }
+
+        let response = next.run(query)?;
+        let violations = validation::record_violations(&response);
+        for violation in &violations {
+            log!("Hostname validation failed for {}", violation);
+        }
+        if self.validation == HostnameValidation::Strict && !violations.is_empty() {
+            // The client asked a perfectly good question; it's the answer we can't vouch for
+            return Ok(error_response(query, DnsRCode::ServFail));
+        }
+        Ok(response)
+    }
+}
+
+struct Local {
+    resolver: Resolver,
+}
+
+impl Middleware for Local {
+    fn name(&self) -> &str {
+        "local"
+    }
+
+    fn handle(&self, query: &DnsPacket, next: Next) -> Result<DnsPacket, ResolveError> {
+        match self.resolver.answer_locally(&query.questions[0])? {
+            Some(response) => Ok(response),
+            None => next.run(query),
+        }
+    }
+}
+
+// A client that clears RD is asking about what we already know, like it would an authority, so
+// it doesn't get recursion: the zone and local data stages before this one have had their turn,
+// then there's the cache, and otherwise a referral to the closest nameservers we know of.
+struct NoRecursion {
+    resolver: Resolver,
+}
+
+impl Middleware for NoRecursion {
+    fn name(&self) -> &str {
+        "norecurse"
+    }
+
+    fn handle(&self, query: &DnsPacket, next: Next) -> Result<DnsPacket, ResolveError> {
+        if query.flags.rd_bit {
+            return next.run(query);
+        }
+        let question = &query.questions[0];
+        if let Some(response) = self
+            .resolver
+            .cached_for(question, query_subnet(query).as_ref())
+        {
+            return Ok(response);
+        }
+        log!(
+            "Referring {} instead of recursing, since RD is clear",
+            question
+        );
+        let (nameservers, addresses) = self.resolver.closest_delegation(&question.qname);
+        let mut response = error_response(query, DnsRCode::NoError);
+        response.nameservers = nameservers;
+        response.addl_recs = addresses;
+        Ok(response)
+    }
+}
+
+struct Cached {
+    resolver: Resolver,
+}
+
+impl Middleware for Cached {
+    fn name(&self) -> &str {
+        "cache"
+    }
+
+    fn handle(&self, query: &DnsPacket, next: Next) -> Result<DnsPacket, ResolveError> {
+        let question = &query.questions[0];
+        if let Some(response) = self
+            .resolver
+            .cached_for(question, query_subnet(query).as_ref())
+        {
+            return Ok(response);
+        }
+        let response = next.run(query)?;
+        self.resolver.cache_response(question, &response);
+        Ok(response)
+    }
+}
+
+// The subnet a query is asking on behalf of, from its client subnet option
+fn query_subnet(query: &DnsPacket) -> Option<AddressPrefix> {
+    client_subnet(query).map(|subnet| AddressPrefix::new(subnet.address, subnet.source_prefix))
+}
+
+struct Resolve {
+    resolver: Resolver,
+}
+
+impl Middleware for Resolve {
+    fn name(&self) -> &str {
+        "resolve"
+    }
+
+    fn handle(&self, query: &DnsPacket, _next: Next) -> Result<DnsPacket, ResolveError> {
+        let response = self.resolver.resolve_remotely(&query.questions[0])?;
+        for error in ExtendedDnsError::from_packet(&response) {
+            log!("Upstream reported {}", error);
+        }
+        Ok(response)
+    }
+}
+
+// Collapses CNAME chains in answers for names under `zones`, so the records at the end of the
+// chain are answered as if they were at the name asked about. This is how a zone apex, which
+// can't have a CNAME, gets to point at a name hosted elsewhere; it's also for clients that can't
+// follow CNAMEs. Answers whose chain doesn't end in records of the type asked for are left
+// alone.
+pub struct FlattenCnames {
+    zones: Vec<Vec<String>>,
+}
+
+impl FlattenCnames {
+    pub fn new(zones: Vec<Vec<String>>) -> FlattenCnames {
+        FlattenCnames { zones }
+    }
+}
+
+impl Middleware for FlattenCnames {
+    fn name(&self) -> &str {
+        "flatten"
+    }
+
+    fn handle(&self, query: &DnsPacket, next: Next) -> Result<DnsPacket, ResolveError> {
+        let mut response = next.run(query)?;
+        let question = &query.questions[0];
+        if self
+            .zones
+            .iter()
+            .any(|zone| is_subdomain(&question.qname, zone))
+        {
+            flatten_cnames(question, &mut response);
+        }
+        Ok(response)
+    }
+}
+
+// Returns whether there was a chain to flatten
+pub fn flatten_cnames(question: &DnsQuestion, response: &mut DnsPacket) -> bool {
+    if question.qtype == DnsRRType::CNAME || question.qtype == DnsRRType::ANY {
+        return false;
+    }
+    // Follow the chain from the name asked about, keeping the lowest TTL along it
+    let mut name = question.qname.to_owned();
+    let mut ttl = u32::MAX;
+    let mut chased = 0;
+    while let Some(cname) = response
+        .answers
+        .iter()
+        .find(|rr| rr.rr_type == DnsRRType::CNAME && names_equal(&rr.name, &name))
+    {
+        // A loop can't be longer than the answer section
+        if chased > response.answers.len() {
+            return false;
+        }
+        if let DnsRecordData::CNAME(target) = &cname.record {
+            ttl = ttl.min(cname.ttl);
+            name = target.to_owned();
+            chased += 1;
+        }
+    }
+    let mut answers: Vec<_> = response
+        .answers
+        .iter()
+        .filter(|rr| rr.rr_type == question.qtype && names_equal(&rr.name, &name))
+        .cloned()
+        .collect();
+    if chased == 0 || answers.is_empty() {
+        return false;
+    }
+    for rr in answers.iter_mut() {
+        rr.name = question.qname.to_owned();
+        rr.ttl = rr.ttl.min(ttl);
+    }
+    // Signatures over the records we've renamed don't hold any more
+    response.answers = answers;
+    response.flags.ad_bit = false;
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use crate::dns::config::{ResolverConfig, ResolverOpts};
+    use crate::dns::mock;
+    use crate::dns::protocol::{
+        name_to_string, opt_record, DnsClass, DnsResourceRecord, EdnsOption, SoaData,
+    };
+    use crate::dns::zone;
+
+    // Answers everything with a fixed address, like a blocklist would
+    struct Sinkhole;
+
+    impl Middleware for Sinkhole {
+        fn name(&self) -> &str {
+            "sinkhole"
+        }
+
+        fn handle(&self, query: &DnsPacket, _next: Next) -> Result<DnsPacket, ResolveError> {
+            let mut response = error_response(query, DnsRCode::NoError);
+            let name = name_to_string(&query.questions[0].qname);
+            response
+                .answers
+                .push(mock::a(&name, Ipv4Addr::new(192, 0, 2, 1)));
+            Ok(response)
+        }
+    }
+
+    #[test]
+    fn stages_short_circuit_and_finish() {
+        // No root servers at all, so anything that makes it to resolution fails
+        let mut config = ResolverConfig::recursive(
+            mock::MockNetwork::new().hints(Ipv4Addr::new(127, 0, 0, 250)),
+        );
+        config
+            .local_data
+            .push(zone::parse_record(&[], "printer.lan 300 A 192.168.1.20").unwrap());
+        let opts = ResolverOpts {
+            timeout: Duration::from_millis(50),
+            attempts: 1,
+            ..ResolverOpts::default()
+        };
+        let resolver = Resolver::new(config, opts);
+        let mut pipeline =
+            Pipeline::standard(resolver, HostnameValidation::Strict, EdnsRegistry::new());
+
+        let mut query = mock::query("printer.lan", DnsRRType::A);
+        query.id = 77;
+        query.flags.cd_bit = true;
+        let response = mock::run(&pipeline, &query).unwrap();
+        assert_eq!(response.id, 77);
+        assert_eq!(response.answers.len(), 1);
+        assert!(response.flags.ra_bit && response.flags.rd_bit && response.flags.cd_bit);
+        assert!(response.flags.aa_bit);
+
+        let mut query = mock::query("elsewhere.test", DnsRRType::A);
+        let response = mock::run(&pipeline, &query).unwrap();
+        assert_eq!(response.flags.rcode, DnsRCode::ServFail);
+        assert!(!response.flags.aa_bit);
+
+        pipeline.insert_before("local", Arc::new(Sinkhole)).unwrap();
+        assert_eq!(
+            pipeline.names(),
+            vec![
+                "finish",
+                "question",
+                "hostnames",
+                "sinkhole",
+                "local",
+                "norecurse",
+                "cache",
+                "resolve"
+            ]
+        );
+        let response = mock::run(&pipeline, &query).unwrap();
+        assert_eq!(response.flags.rcode, DnsRCode::NoError);
+        assert_eq!(response.answers.len(), 1);
+
+        // Problems with the query itself still come first
+        query.questions[0].qname = mock::labels("bad_name.test");
+        let response = mock::run(&pipeline, &query).unwrap();
+        assert_eq!(response.flags.rcode, DnsRCode::FormError);
+        query.questions.clear();
+        let response = mock::run(&pipeline, &query).unwrap();
+        assert_eq!(response.flags.rcode, DnsRCode::FormError);
+
+        // As do extra questions, which are only answered if that's the policy
+        query.questions = vec![
+            mock::question("printer.lan", DnsRRType::A),
+            mock::question("other.lan", DnsRRType::A),
+        ];
+        let response = mock::run(&pipeline, &query).unwrap();
+        assert_eq!(response.flags.rcode, DnsRCode::FormError);
+        assert_eq!(response.questions.len(), 2);
+        let policy = |policy| Arc::new(OneQuestion::new(policy));
+        pipeline
+            .replace("question", policy(QuestionPolicy::First))
+            .unwrap();
+        let response = mock::run(&pipeline, &query).unwrap();
+        assert_eq!(response.flags.rcode, DnsRCode::NoError);
+        assert_eq!(response.questions, query.questions[..1]);
+        assert_eq!(response.answers.len(), 1);
+        pipeline
+            .replace("question", policy(QuestionPolicy::NotImp))
+            .unwrap();
+        let response = mock::run(&pipeline, &query).unwrap();
+        assert_eq!(response.flags.rcode, DnsRCode::NotImp);
+        pipeline
+            .replace("question", policy(QuestionPolicy::Drop))
+            .unwrap();
+        assert!(mock::run(&pipeline, &query).is_err());
+        assert!("all".parse::<QuestionPolicy>().is_err());
+    }
+
+    // The probes from RFC 8906 section 8, against a name we answer locally
+    #[test]
+    fn answers_rfc_8906_probes() {
+        let mut config = ResolverConfig::recursive(
+            mock::MockNetwork::new().hints(Ipv4Addr::new(127, 0, 0, 250)),
+        );
+        config
+            .local_data
+            .push(zone::parse_record(&[], "printer.lan 300 A 192.168.1.20").unwrap());
+        let pipeline = Pipeline::standard(
+            Resolver::new(config, ResolverOpts::default()),
+            HostnameValidation::Off,
+            EdnsRegistry::new(),
+        );
+        // A query with an OPT record whose TTL field (version and flags) is `ttl`
+        let probe = |ttl: u32, options: Vec<EdnsOption>| {
+            let mut query = mock::query("printer.lan", DnsRRType::A);
+            let mut opt = opt_record(4096);
+            opt.ttl = ttl;
+            opt.record = DnsRecordData::OPT(options);
+            query.addl_recs.push(opt);
+            query
+        };
+        let opt_of = |response: &DnsPacket| {
+            let opts: Vec<_> = response
+                .addl_recs
+                .iter()
+                .filter(|rr| rr.rr_type == DnsRRType::OPT)
+                .cloned()
+                .collect();
+            assert!(opts.len() <= 1);
+            opts.into_iter().next()
+        };
+        let unknown = || EdnsOption::Unknown(100, Vec::new());
+
+        // dns: no EDNS in, none out
+        let response = mock::run(&pipeline, &mock::query("printer.lan", DnsRRType::A)).unwrap();
+        assert_eq!(response.answers.len(), 1);
+        assert!(opt_of(&response).is_none());
+
+        // edns: a plain OPT record gets one back, even for a locally answered name
+        let response = mock::run(&pipeline, &probe(0, vec![])).unwrap();
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(opt_of(&response).unwrap().ttl, 0);
+
+        // edns1 and edns1opt: a version we don't know is BADVERS, answered at version 0
+        for options in [vec![], vec![unknown()]] {
+            let response = mock::run(&pipeline, &probe(1 << 16, options)).unwrap();
+            assert_eq!(response.flags.rcode, DnsRCode::NoError);
+            assert!(response.answers.is_empty());
+            let opt = opt_of(&response).unwrap();
+            assert_eq!(opt.ttl, 1 << 24);
+            assert_eq!(opt.record, DnsRecordData::OPT(vec![]));
+        }
+
+        // ednsopt and optlist: options we don't know, or don't do anything with, are ignored
+        let options = vec![
+            unknown(),
+            EdnsOption::Nsid(Vec::new()),
+            EdnsOption::Cookie(vec![1; 8], Vec::new()),
+            EdnsOption::Unknown(9, Vec::new()),
+        ];
+        let response = mock::run(&pipeline, &probe(0, options)).unwrap();
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(
+            opt_of(&response).unwrap().record,
+            DnsRecordData::OPT(vec![])
+        );
+
+        // do and ednsflags: DO is echoed back, flags we don't know aren't
+        let response = mock::run(&pipeline, &probe(0x8000, vec![])).unwrap();
+        assert_eq!(opt_of(&response).unwrap().ttl, 0x8000);
+        let response = mock::run(&pipeline, &probe(0x0080, vec![])).unwrap();
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(opt_of(&response).unwrap().ttl, 0);
+
+        // Two OPT records are a format error (RFC 6891 section 6.1.1)
+        let mut query = probe(0, vec![]);
+        query.addl_recs.push(opt_record(512));
+        let response = mock::run(&pipeline, &query).unwrap();
+        assert_eq!(response.flags.rcode, DnsRCode::FormError);
+    }
+
+    #[test]
+    fn queries_without_rd_are_not_recursed() {
+        // Recursing for these would fail, since there's no root server
+        let resolver = Resolver::new(
+            ResolverConfig::recursive(
+                mock::MockNetwork::new().hints(Ipv4Addr::new(127, 0, 0, 250)),
+            ),
+            ResolverOpts {
+                timeout: Duration::from_millis(50),
+                attempts: 1,
+                ..ResolverOpts::default()
+            },
+        );
+        let pipeline = Pipeline::standard(
+            resolver.to_owned(),
+            HostnameValidation::Off,
+            EdnsRegistry::new(),
+        );
+        let mut query = mock::query("www.example.test", DnsRRType::A);
+        query.flags.rd_bit = false;
+
+        // Nothing's cached, so the best we can do is the root
+        let response = mock::run(&pipeline, &query).unwrap();
+        assert_eq!(response.flags.rcode, DnsRCode::NoError);
+        assert!(!response.flags.rd_bit);
+        assert!(response.answers.is_empty());
+        assert_eq!(response.nameservers.len(), 13);
+        assert!(response.nameservers[0].name.is_empty());
+
+        // Once the zone's nameservers are cached, we can do better than that
+        let ns_query = mock::query("example.test", DnsRRType::NS);
+        let mut delegation = error_response(&ns_query, DnsRCode::NoError);
+        delegation.answers = vec![mock::ns("example.test", "ns1.example.test")];
+        resolver.cache_response(&ns_query.questions[0], &delegation);
+        let glue_query = mock::query("ns1.example.test", DnsRRType::A);
+        let mut glue = error_response(&glue_query, DnsRCode::NoError);
+        glue.answers = vec![mock::a("ns1.example.test", Ipv4Addr::new(192, 0, 2, 53))];
+        resolver.cache_response(&glue_query.questions[0], &glue);
+        let response = mock::run(&pipeline, &query).unwrap();
+        assert_eq!(response.nameservers, delegation.answers);
+        assert_eq!(response.addl_recs, glue.answers);
+
+        // What is cached is answered as usual
+        let mut query = glue_query;
+        query.flags.rd_bit = false;
+        let response = mock::run(&pipeline, &query).unwrap();
+        assert_eq!(response.answers, glue.answers);
+        assert!(response.nameservers.is_empty());
+    }
+
+    #[test]
+    fn answers_questions_about_the_root() {
+        let soa = DnsResourceRecord {
+            name: Vec::new(),
+            rr_type: DnsRRType::SOA,
+            class: DnsClass::IN,
+            ttl: 86400,
+            record: DnsRecordData::SOA(SoaData {
+                mname: mock::labels("a.root-servers.net"),
+                rname: mock::labels("nstld.verisign-grs.com"),
+                serial: 2025101700,
+                refresh: 1800,
+                retry: 900,
+                expire: 604800,
+                minimum: 86400,
+            }),
+        };
+        let dnskey = DnsResourceRecord {
+            name: Vec::new(),
+            rr_type: DnsRRType::DNSKEY,
+            class: DnsClass::IN,
+            ttl: 172800,
+            record: DnsRecordData::Other(vec![1, 1, 3, 8, 0xaa, 0xbb]),
+        };
+        let network = mock::MockNetwork::new();
+        let _root = network.serve(
+            Ipv4Addr::new(127, 0, 0, 2),
+            mock::Script::new()
+                .on(
+                    "",
+                    Some(DnsRRType::NS),
+                    mock::Behavior::Answer(vec![mock::ns("", "a.root-servers.net")]),
+                )
+                .on(
+                    "",
+                    Some(DnsRRType::SOA),
+                    mock::Behavior::Answer(vec![soa.to_owned()]),
+                )
+                .on(
+                    "",
+                    Some(DnsRRType::DNSKEY),
+                    mock::Behavior::Answer(vec![dnskey.to_owned()]),
+                )
+                .on("", None, mock::Behavior::NoData(vec![soa.to_owned()])),
+        );
+        let resolver = Resolver::new(
+            ResolverConfig::recursive(network.hints(Ipv4Addr::new(127, 0, 0, 2))),
+            ResolverOpts::default(),
+        );
+        let pipeline = Pipeline::standard(
+            resolver.to_owned(),
+            HostnameValidation::Strict,
+            EdnsRegistry::new(),
+        );
+
+        let ask = |qtype| {
+            // The whole way from the wire and back, where the root is a single zero byte
+            let bytes = mock::query(".", qtype).to_bytes();
+            assert_eq!(&bytes[12..17], &[0, 0, qtype as u8, 0, 1]);
+            let query = DnsPacket::from_bytes(&bytes).unwrap();
+            assert!(query.questions[0].qname.is_empty());
+            let response = mock::run(&pipeline, &query).unwrap();
+            DnsPacket::from_bytes(&response.to_bytes()).unwrap()
+        };
+        for (qtype, record) in [
+            (DnsRRType::NS, mock::ns("", "a.root-servers.net")),
+            (DnsRRType::SOA, soa.to_owned()),
+            (DnsRRType::DNSKEY, dnskey),
+        ] {
+            let response = ask(qtype);
+            assert_eq!(response.flags.rcode, DnsRCode::NoError, "{}", qtype);
+            assert_eq!(response.answers, vec![record]);
+            assert_eq!(name_to_string(&response.questions[0].qname), ".");
+        }
+        let nodata = ask(DnsRRType::AAAA);
+        assert_eq!(nodata.flags.rcode, DnsRCode::NoError);
+        assert!(nodata.answers.is_empty());
+        assert_eq!(nodata.nameservers, vec![soa]);
+
+        // And the second time, from the cache
+        let hits = resolver.cache_stats().hits;
+        assert_eq!(ask(DnsRRType::NS).answers.len(), 1);
+        assert_eq!(resolver.cache_stats().hits, hits + 1);
+    }
+
+    #[test]
+    fn cname_chains_flatten() {
+        let question = mock::question("example.com", DnsRRType::A);
+        let mut response =
+            error_response(&mock::query("example.com", DnsRRType::A), DnsRCode::NoError);
+        let mut first = mock::cname("example.com", "lb.cdn.test");
+        first.ttl = 60;
+        response.answers = vec![
+            first,
+            mock::cname("lb.cdn.test", "edge.cdn.test"),
+            mock::a("edge.cdn.test", Ipv4Addr::new(192, 0, 2, 1)),
+            mock::a("edge.cdn.test", Ipv4Addr::new(192, 0, 2, 2)),
+        ];
+        let mut unflattened = response.to_owned();
+        assert!(flatten_cnames(&question, &mut response));
+        let mut expected = vec![
+            mock::a("example.com", Ipv4Addr::new(192, 0, 2, 1)),
+            mock::a("example.com", Ipv4Addr::new(192, 0, 2, 2)),
+        ];
+        for rr in expected.iter_mut() {
+            rr.ttl = 60;
+        }
+        assert_eq!(response.answers, expected);
+
+        // A chain that goes nowhere is left for the client to see
+        unflattened.answers.truncate(2);
+        let before = unflattened.to_owned();
+        assert!(!flatten_cnames(&question, &mut unflattened));
+        assert_eq!(unflattened, before);
+        let cname = mock::question("example.com", DnsRRType::CNAME);
+        assert!(!flatten_cnames(&cname, &mut unflattened));
+    }
+}
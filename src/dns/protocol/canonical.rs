@@ -0,0 +1,186 @@
+use std::cmp::Ordering;
+
+use super::{names, DnsRecordData, DnsResourceRecord, SoaData, SrvData};
+
+// Canonical form and ordering of names and records, from RFC 4034 section 6. DNSSEC signatures,
+// ZONEMD digests, and TSIG are all computed over this form, and since it's deterministic it's
+// also a handy way to compare record sets in tests.
+
+// The canonical wire form of a name: uncompressed, with ASCII letters lowercased (6.2)
+pub fn canonical_name_bytes(name: &[String]) -> Vec<u8> {
+    names::serialize_name(&lowercase_name(name))
+}
+
+// Canonical name order (6.1): names are sorted by their labels starting from the root, with each
+// label compared as a lowercased string of bytes and a missing label sorting first. So
+// "example" < "a.example" < "Z.a.example" < "z.example".
+pub fn compare_names(a: &[String], b: &[String]) -> Ordering {
+    for (x, y) in a.iter().rev().zip(b.iter().rev()) {
+        let order = x
+            .to_ascii_lowercase()
+            .as_bytes()
+            .cmp(y.to_ascii_lowercase().as_bytes());
+        if order != Ordering::Equal {
+            return order;
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+// The canonical wire form of a record (6.2): owner and any names embedded in the rdata are
+// lowercased and uncompressed. The TTL is left as is; when signing or validating, callers should
+// set it to the original TTL from the RRSIG first.
+pub fn canonical_rr_bytes(rr: &DnsResourceRecord) -> Vec<u8> {
+    DnsResourceRecord {
+        name: lowercase_name(&rr.name),
+        record: canonical_rdata(&rr.record),
+        ..rr.to_owned()
+    }
+    .to_bytes()
+}
+
+// Sort records into canonical order (6.3) and drop duplicates, which RFC 4034 doesn't allow in
+// an RRset. Records are ordered by owner name, then class and type, then by their canonical
+// rdata compared as a left justified string of bytes. Records differing only in TTL count as
+// duplicates; the first one in the input is kept.
+pub fn sort_canonical(records: &mut Vec<DnsResourceRecord>) {
+    records.sort_by(|a, b| {
+        compare_names(&a.name, &b.name)
+            .then(a.class.to_u16().cmp(&b.class.to_u16()))
+            .then((a.rr_type as u16).cmp(&(b.rr_type as u16)))
+            .then(
+                canonical_rdata(&a.record)
+                    .to_bytes()
+                    .cmp(&canonical_rdata(&b.record).to_bytes()),
+            )
+    });
+    records.dedup_by(|a, b| {
+        names::names_equal(&a.name, &b.name)
+            && a.class == b.class
+            && a.rr_type == b.rr_type
+            && canonical_rdata(&a.record) == canonical_rdata(&b.record)
+    });
+}
+
+fn lowercase_name(name: &[String]) -> Vec<String> {
+    name.iter()
+        .map(|label| label.to_ascii_lowercase())
+        .collect()
+}
+
+// RFC 4034 lists the types whose embedded names get lowercased (as amended by RFC 6840, which
+// took NSEC and RRSIG off the list). Of those, only NS, CNAME, PTR, MX, SOA, SRV, and RP are
+// parsed so far; anything we keep as raw bytes is already in its canonical form.
+pub fn canonical_rdata(record: &DnsRecordData) -> DnsRecordData {
+    match record {
+        DnsRecordData::NS(name) => DnsRecordData::NS(lowercase_name(name)),
+        DnsRecordData::CNAME(name) => DnsRecordData::CNAME(lowercase_name(name)),
+        DnsRecordData::PTR(name) => DnsRecordData::PTR(lowercase_name(name)),
+        DnsRecordData::MX(preference, name) => DnsRecordData::MX(*preference, lowercase_name(name)),
+        DnsRecordData::SOA(soa) => DnsRecordData::SOA(SoaData {
+            mname: lowercase_name(&soa.mname),
+            rname: lowercase_name(&soa.rname),
+            ..soa.to_owned()
+        }),
+        DnsRecordData::SRV(srv) => DnsRecordData::SRV(SrvData {
+            target: lowercase_name(&srv.target),
+            ..srv.to_owned()
+        }),
+        DnsRecordData::RP(mbox, txt) => {
+            DnsRecordData::RP(lowercase_name(mbox), lowercase_name(txt))
+        }
+        _ => record.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dns::protocol::canonical::*;
+    use crate::dns::protocol::*;
+
+    use std::net::Ipv4Addr;
+
+    fn name(name: &str) -> Vec<String> {
+        name.split('.').map(String::from).collect()
+    }
+
+    fn rr(owner: &str, ttl: u32, record: DnsRecordData) -> DnsResourceRecord {
+        let rr_type = match record {
+            DnsRecordData::A(_) => DnsRRType::A,
+            DnsRecordData::NS(_) => DnsRRType::NS,
+            _ => DnsRRType::TXT,
+        };
+        DnsResourceRecord {
+            name: name(owner),
+            rr_type,
+            class: DnsClass::IN,
+            ttl,
+            record,
+        }
+    }
+
+    #[test]
+    fn name_order_matches_rfc() {
+        // The example from RFC 4034 section 6.1, minus \200.z.example since our labels have to
+        // be valid UTF-8
+        let expected = vec![
+            name("example"),
+            name("a.example"),
+            name("yljkjljk.a.example"),
+            name("Z.a.example"),
+            name("zABC.a.EXAMPLE"),
+            name("z.example"),
+            name("\u{1}.z.example"),
+            name("*.z.example"),
+        ];
+        let mut names = expected.to_owned();
+        names.reverse();
+        names.sort_by(|a, b| compare_names(a, b));
+        assert_eq!(names, expected);
+    }
+
+    #[test]
+    fn canonical_form_lowercases_names() {
+        assert_eq!(
+            canonical_name_bytes(&name("Www.EXAMPLE.com")),
+            b"\x03www\x07example\x03com\x00".to_vec()
+        );
+        let record = rr(
+            "Example.COM",
+            300,
+            DnsRecordData::NS(name("NS1.Example.COM")),
+        );
+        let lowered = rr(
+            "example.com",
+            300,
+            DnsRecordData::NS(name("ns1.example.com")),
+        );
+        assert_eq!(canonical_rr_bytes(&record), lowered.to_bytes());
+    }
+
+    #[test]
+    fn sort_orders_rdata_and_drops_duplicates() {
+        let a = |owner: &str, ttl: u32, last_octet: u8| {
+            rr(
+                owner,
+                ttl,
+                DnsRecordData::A(Ipv4Addr::new(192, 0, 2, last_octet)),
+            )
+        };
+        let mut records = vec![
+            a("example.com", 300, 10),
+            a("example.com", 300, 9),
+            a("EXAMPLE.com", 60, 10),
+            a("a.example.com", 300, 1),
+        ];
+        sort_canonical(&mut records);
+        assert_eq!(
+            records,
+            vec![
+                a("example.com", 300, 9),
+                a("example.com", 300, 10),
+                a("a.example.com", 300, 1),
+            ]
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use std::fmt;
+
 #[allow(dead_code)]
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub enum DnsClass {
@@ -48,3 +50,13 @@ impl DnsClass {
         }
     }
 }
+
+impl fmt::Display for DnsClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            // There's no mnemonic for a payload size, so use the RFC 3597 generic class syntax
+            DnsClass::EdnsPayloadSize(payload) => write!(f, "CLASS{}", payload),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
@@ -0,0 +1,309 @@
+use std::fmt::Write;
+
+use super::{bigendians, names, DnsClass, DnsFlags, DnsRRType};
+
+// Human readable dumps of raw DNS packets, for debugging interop problems. These deliberately
+// work on the raw bytes rather than a parsed DnsPacket: the packets we most want to look at are
+// the ones we can't parse, so the annotator walks as far as it can and then says where it gave up.
+
+// Classic hex dump: offset, sixteen bytes of hex, then the printable ASCII for those bytes
+pub fn hex_dump(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for (line, chunk) in bytes.chunks(16).enumerate() {
+        let mut hex = String::new();
+        let mut ascii = String::new();
+        for (i, byte) in chunk.iter().enumerate() {
+            if i == 8 {
+                hex.push(' ');
+            }
+            write!(hex, "{:02x} ", byte).unwrap();
+            ascii.push(if byte.is_ascii_graphic() || *byte == b' ' {
+                *byte as char
+            } else {
+                '.'
+            });
+        }
+        writeln!(out, "{:04x}  {:<49} |{}|", line * 16, hex, ascii).unwrap();
+    }
+    out
+}
+
+// Field-by-field breakdown of a packet, one field per line, each prefixed with its offset
+pub fn annotate_packet(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    if let Err(message) = annotate_sections(bytes, &mut out) {
+        writeln!(out, "!! annotation stopped: {}", message).unwrap();
+    }
+    out
+}
+
+fn annotate_sections(bytes: &[u8], out: &mut String) -> Result<(), String> {
+    if bytes.len() < 12 {
+        return Err(format!(
+            "packet has incomplete header; only {} bytes",
+            bytes.len()
+        ));
+    }
+
+    let id = bigendians::to_u16(&bytes[0..2]);
+    writeln!(out, "{:04x}  id        0x{:04x} ({})", 0, id, id).unwrap();
+    // Annotate the flags bit by bit, since a malformed flags field is exactly the kind of thing
+    // this dump is for; we don't want DnsFlags::from_bytes to reject it first.
+    let raw_flags = bigendians::to_u16(&bytes[2..4]);
+    write!(
+        out,
+        "{:04x}  flags     0x{:04x} qr={} opcode={} aa={} tc={} rd={} ra={} z={} ad={} cd={} rcode={}",
+        2,
+        raw_flags,
+        (bytes[2] >> 7) & 1,
+        (bytes[2] >> 3) & 0b1111,
+        (bytes[2] >> 2) & 1,
+        (bytes[2] >> 1) & 1,
+        bytes[2] & 1,
+        (bytes[3] >> 7) & 1,
+        (bytes[3] >> 6) & 1,
+        (bytes[3] >> 5) & 1,
+        (bytes[3] >> 4) & 1,
+        bytes[3] & 0b1111,
+    )
+    .unwrap();
+    match DnsFlags::from_bytes(&bytes[2..4]) {
+        Ok(flags) => writeln!(out, " ({:?}, {:?})", flags.opcode, flags.rcode).unwrap(),
+        Err(e) => writeln!(out, " (invalid: {})", e.get_message()).unwrap(),
+    }
+
+    let mut counts = [0u16; 4];
+    let count_names = ["qdcount", "ancount", "nscount", "arcount"];
+    for (i, count) in counts.iter_mut().enumerate() {
+        let offset = 4 + i * 2;
+        *count = bigendians::to_u16(&bytes[offset..offset + 2]);
+        writeln!(out, "{:04x}  {:<9} {}", offset, count_names[i], count).unwrap();
+    }
+
+    let mut pos = 12;
+    for i in 0..counts[0] {
+        writeln!(out, "{:04x}  question {}", pos, i + 1).unwrap();
+        pos = annotate_name(bytes, pos, out)?;
+        if pos + 4 > bytes.len() {
+            return Err(format!("end of packet at offset {} reading question", pos));
+        }
+        let qtype = bigendians::to_u16(&bytes[pos..pos + 2]);
+        writeln!(
+            out,
+            "{:04x}    qtype   {} ({})",
+            pos,
+            qtype,
+            type_name(qtype)
+        )
+        .unwrap();
+        let qclass = bigendians::to_u16(&bytes[pos + 2..pos + 4]);
+        writeln!(
+            out,
+            "{:04x}    qclass  {} ({})",
+            pos + 2,
+            qclass,
+            class_name(qclass)
+        )
+        .unwrap();
+        pos += 4;
+    }
+
+    let sections = ["answer", "nameserver", "additional"];
+    for (section, count) in sections.iter().zip(counts[1..].iter()) {
+        for i in 0..*count {
+            writeln!(out, "{:04x}  {} {}", pos, section, i + 1).unwrap();
+            pos = annotate_record(bytes, pos, out)?;
+        }
+    }
+
+    if pos < bytes.len() {
+        writeln!(
+            out,
+            "{:04x}  {} trailing bytes after last record",
+            pos,
+            bytes.len() - pos
+        )
+        .unwrap();
+    }
+    Ok(())
+}
+
+fn annotate_record(bytes: &[u8], start: usize, out: &mut String) -> Result<usize, String> {
+    let mut pos = annotate_name(bytes, start, out)?;
+    if pos + 10 > bytes.len() {
+        return Err(format!(
+            "end of packet at offset {} reading resource record",
+            pos
+        ));
+    }
+    let rr_type = bigendians::to_u16(&bytes[pos..pos + 2]);
+    let class = bigendians::to_u16(&bytes[pos + 2..pos + 4]);
+    let ttl = bigendians::to_u32(&bytes[pos + 4..pos + 8]);
+    let rd_length = bigendians::to_u16(&bytes[pos + 8..pos + 10]) as usize;
+    writeln!(
+        out,
+        "{:04x}    type    {} ({})",
+        pos,
+        rr_type,
+        type_name(rr_type)
+    )
+    .unwrap();
+    if rr_type == DnsRRType::OPT as u16 {
+        writeln!(
+            out,
+            "{:04x}    class   {} (EDNS payload size)",
+            pos + 2,
+            class
+        )
+        .unwrap();
+        writeln!(out, "{:04x}    ttl     0x{:08x} (EDNS flags)", pos + 4, ttl).unwrap();
+    } else {
+        writeln!(
+            out,
+            "{:04x}    class   {} ({})",
+            pos + 2,
+            class,
+            class_name(class)
+        )
+        .unwrap();
+        writeln!(out, "{:04x}    ttl     {}", pos + 4, ttl).unwrap();
+    }
+    writeln!(out, "{:04x}    rdlen   {}", pos + 8, rd_length).unwrap();
+    pos += 10;
+
+    if pos + rd_length > bytes.len() {
+        return Err(format!(
+            "rdata at offset {} claims {} bytes but only {} remain",
+            pos,
+            rd_length,
+            bytes.len() - pos
+        ));
+    }
+    let rdata = &bytes[pos..pos + rd_length];
+    writeln!(out, "{:04x}    rdata   {}", pos, hex_string(rdata)).unwrap();
+    // Names inside rdata can be compressed too, and pointers there are a classic source of
+    // trouble, so walk them the same way we walk owner names.
+    if rr_type == DnsRRType::NS as u16
+        || rr_type == DnsRRType::CNAME as u16
+        || rr_type == DnsRRType::PTR as u16
+    {
+        annotate_name(bytes, pos, out)?;
+    } else if rr_type == DnsRRType::MX as u16 && rd_length > 2 {
+        // Skip the preference
+        annotate_name(bytes, pos + 2, out)?;
+    } else if rr_type == DnsRRType::SOA as u16 {
+        let rname = annotate_name(bytes, pos, out)?;
+        annotate_name(bytes, rname, out)?;
+    }
+    Ok(pos + rd_length)
+}
+
+// Walk a name label by label, showing each label and any compression pointer with its target
+fn annotate_name(bytes: &[u8], start: usize, out: &mut String) -> Result<usize, String> {
+    let mut pos = start;
+    loop {
+        if pos >= bytes.len() {
+            return Err(format!("end of packet at offset {} reading name", pos));
+        }
+        let len_byte = bytes[pos];
+        match len_byte >> 6 {
+            0b11 => {
+                if pos + 1 >= bytes.len() {
+                    return Err(format!("end of packet at offset {} reading pointer", pos));
+                }
+                let target = (((len_byte & 0b111111) as usize) << 8) + bytes[pos + 1] as usize;
+                let resolved = match names::deserialize_name(bytes, target) {
+                    Ok(_) if target >= start => String::from("invalid: doesn't point back"),
+                    Ok((labels, _)) => format!("\"{}\"", labels.join(".")),
+                    Err(e) => format!("invalid: {}", e.get_message()),
+                };
+                writeln!(
+                    out,
+                    "{:04x}    pointer -> 0x{:04x} {}",
+                    pos, target, resolved
+                )
+                .unwrap();
+                return Ok(pos + 2);
+            }
+            0b00 => {
+                let length = len_byte as usize;
+                if length == 0 {
+                    writeln!(out, "{:04x}    root label", pos).unwrap();
+                    return Ok(pos + 1);
+                }
+                if pos + 1 + length > bytes.len() {
+                    return Err(format!(
+                        "label at offset {} has length {} past end of packet",
+                        pos, length
+                    ));
+                }
+                let label = String::from_utf8_lossy(&bytes[pos + 1..pos + 1 + length]);
+                writeln!(out, "{:04x}    label   \"{}\" (len {})", pos, label, length).unwrap();
+                pos += 1 + length;
+            }
+            _ => {
+                return Err(format!(
+                    "unsupported label type 0x{:02x} at offset {}",
+                    len_byte, pos
+                ));
+            }
+        }
+    }
+}
+
+fn type_name(rr_type: u16) -> String {
+    let parsed: Option<DnsRRType> = num::FromPrimitive::from_u16(rr_type);
+    match parsed {
+        Some(x) => format!("{:?}", x),
+        None => String::from("unknown"),
+    }
+}
+
+fn class_name(class: u16) -> String {
+    match DnsClass::from_u16(class) {
+        Some(x) => format!("{:?}", x),
+        None => String::from("unknown"),
+    }
+}
+
+fn hex_string(bytes: &[u8]) -> String {
+    let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    hex.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dns::protocol::dump::*;
+
+    #[test]
+    fn hex_dump_works() {
+        let bytes = b"\x00\x2aabcdefghijklmnop";
+        assert_eq!(
+            hex_dump(bytes),
+            "0000  00 2a 61 62 63 64 65 66  67 68 69 6a 6b 6c 6d 6e  |.*abcdefghijklmn|\n\
+             0010  6f 70                                             |op|\n"
+        );
+    }
+
+    #[test]
+    fn annotate_shows_pointers() {
+        // Query for example.com A with one answer whose owner name is a pointer back to the
+        // question name
+        let mut packet = vec![
+            0x00, 0x2a, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+        ];
+        packet.extend_from_slice(b"\x07example\x03com\x00\x00\x01\x00\x01");
+        packet.extend_from_slice(&[0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x0e, 0x10]);
+        packet.extend_from_slice(&[0x00, 0x04, 93, 184, 216, 34]);
+
+        let annotated = annotate_packet(&packet);
+        assert!(annotated.contains("000c    label   \"example\" (len 7)"));
+        assert!(annotated.contains("001d    pointer -> 0x000c \"example.com\""));
+        assert!(annotated.contains("rdata   5d b8 d8 22"));
+        assert!(!annotated.contains("!!"));
+
+        // Chop the rdata off and make sure we say where we stopped
+        let annotated = annotate_packet(&packet[..packet.len() - 2]);
+        assert!(annotated.contains("!! annotation stopped: rdata at offset 41"));
+    }
+}
@@ -0,0 +1,204 @@
+// Extended DNS Errors (RFC 8914): an EDNS option that says why a query failed, so a client can
+// tell e.g. "this domain's DNSSEC is broken" apart from "the network is broken" when all the
+// rcode tells it is SERVFAIL. Each one is an info code plus optional human readable text.
+
+use std::fmt;
+
+use num::FromPrimitive;
+
+use super::opt::add_option;
+use super::{DnsPacket, DnsRecordData, EdnsOption};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum EdeCode {
+    Other = 0,
+    // 1-12 are DNSSEC validation failures
+    UnsupportedDnskeyAlgorithm = 1,
+    UnsupportedDsDigestType = 2,
+    StaleAnswer = 3,
+    ForgedAnswer = 4,
+    DnssecIndeterminate = 5,
+    DnssecBogus = 6,
+    SignatureExpired = 7,
+    SignatureNotYetValid = 8,
+    DnskeyMissing = 9,
+    RrsigsMissing = 10,
+    NoZoneKeyBitSet = 11,
+    NsecMissing = 12,
+    CachedError = 13,
+    NotReady = 14,
+    Blocked = 15,
+    Censored = 16,
+    Filtered = 17,
+    Prohibited = 18,
+    StaleNxdomainAnswer = 19,
+    NotAuthoritative = 20,
+    NotSupported = 21,
+    NoReachableAuthority = 22,
+    NetworkError = 23,
+    InvalidData = 24,
+}
+
+// Written out rather than derived, since num-derive's derive puts the impl somewhere rustc now
+// warns about
+impl FromPrimitive for EdeCode {
+    fn from_i64(n: i64) -> Option<EdeCode> {
+        if n < 0 {
+            return None;
+        }
+        EdeCode::from_u64(n as u64)
+    }
+
+    fn from_u64(n: u64) -> Option<EdeCode> {
+        use EdeCode::*;
+        Some(match n {
+            0 => Other,
+            1 => UnsupportedDnskeyAlgorithm,
+            2 => UnsupportedDsDigestType,
+            3 => StaleAnswer,
+            4 => ForgedAnswer,
+            5 => DnssecIndeterminate,
+            6 => DnssecBogus,
+            7 => SignatureExpired,
+            8 => SignatureNotYetValid,
+            9 => DnskeyMissing,
+            10 => RrsigsMissing,
+            11 => NoZoneKeyBitSet,
+            12 => NsecMissing,
+            13 => CachedError,
+            14 => NotReady,
+            15 => Blocked,
+            16 => Censored,
+            17 => Filtered,
+            18 => Prohibited,
+            19 => StaleNxdomainAnswer,
+            20 => NotAuthoritative,
+            21 => NotSupported,
+            22 => NoReachableAuthority,
+            23 => NetworkError,
+            24 => InvalidData,
+            _ => return None,
+        })
+    }
+}
+
+impl EdeCode {
+    // Whether this code means DNSSEC validation failed. Stale and forged answers (3 and 4) sit in
+    // the middle of the DNSSEC codes but aren't about DNSSEC.
+    pub fn is_dnssec(self) -> bool {
+        matches!(self as u16, 1..=2 | 5..=12)
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct ExtendedDnsError {
+    // Kept as a number, since new codes get assigned faster than we'll add them
+    pub info_code: u16,
+    pub extra_text: String,
+}
+
+impl ExtendedDnsError {
+    pub fn new(code: EdeCode, extra_text: &str) -> ExtendedDnsError {
+        ExtendedDnsError {
+            info_code: code as u16,
+            extra_text: extra_text.to_string(),
+        }
+    }
+
+    pub fn code(&self) -> Option<EdeCode> {
+        EdeCode::from_u16(self.info_code)
+    }
+
+    pub fn is_dnssec(&self) -> bool {
+        self.code().is_some_and(EdeCode::is_dnssec)
+    }
+
+    // Every extended error in a packet's OPT record
+    pub fn from_packet(packet: &DnsPacket) -> Vec<ExtendedDnsError> {
+        packet
+            .addl_recs
+            .iter()
+            .filter_map(|rr| match &rr.record {
+                DnsRecordData::OPT(options) => Some(options),
+                _ => None,
+            })
+            .flatten()
+            .filter_map(|option| match option {
+                EdnsOption::ExtendedError(error) => Some(error.to_owned()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Add this error to a packet's OPT record, adding one if it has none
+    pub fn attach(&self, packet: &mut DnsPacket) {
+        add_option(packet, EdnsOption::ExtendedError(self.to_owned()));
+    }
+}
+
+impl fmt::Display for ExtendedDnsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.code() {
+            Some(code) => write!(f, "EDE {} ({:?})", self.info_code, code)?,
+            None => write!(f, "EDE {}", self.info_code)?,
+        }
+        if !self.extra_text.is_empty() {
+            write!(f, ": {}", self.extra_text)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::dns::mock;
+    use crate::dns::protocol::{supports_edns, DnsFlags, DnsOpcode, DnsRCode, DnsRRType};
+
+    #[test]
+    fn extended_errors_round_trip() {
+        let mut packet = DnsPacket {
+            id: 1,
+            flags: DnsFlags {
+                qr_bit: true,
+                opcode: DnsOpcode::Query,
+                aa_bit: false,
+                tc_bit: false,
+                rd_bit: true,
+                ra_bit: true,
+                ad_bit: false,
+                cd_bit: false,
+                rcode: DnsRCode::ServFail,
+            },
+            questions: vec![mock::question("example.com", DnsRRType::A)],
+            answers: Vec::new(),
+            nameservers: Vec::new(),
+            addl_recs: Vec::new(),
+        };
+        assert!(!supports_edns(&packet));
+        let expired = ExtendedDnsError::new(EdeCode::SignatureExpired, "RRSIG expired");
+        let other = ExtendedDnsError {
+            info_code: 4242,
+            extra_text: String::new(),
+        };
+        expired.attach(&mut packet);
+        other.attach(&mut packet);
+        assert!(supports_edns(&packet));
+        assert_eq!(packet.addl_recs.len(), 1);
+
+        let parsed = DnsPacket::from_bytes(&packet.to_bytes()).unwrap();
+        let errors = ExtendedDnsError::from_packet(&parsed);
+        assert_eq!(errors, vec![expired, other]);
+        assert!(errors[0].is_dnssec());
+        assert!(!errors[1].is_dnssec());
+        assert!(!ExtendedDnsError::new(EdeCode::StaleAnswer, "").is_dnssec());
+        assert!((0..25).all(|n| EdeCode::from_u16(n).map(|code| code as u16) == Some(n)));
+        assert_eq!(EdeCode::from_u16(25), None);
+        assert_eq!(
+            errors[0].to_string(),
+            "EDE 7 (SignatureExpired): RRSIG expired"
+        );
+        assert_eq!(errors[1].to_string(), "EDE 4242");
+    }
+}
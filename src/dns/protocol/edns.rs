@@ -0,0 +1,501 @@
+// The options carried in an OPT record's rdata (RFC 6891 section 6.1.2). Each is a code, a length,
+// and that many bytes. The ones we know about are parsed into something typed; anything else is
+// kept as its raw bytes so it survives being passed along.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+
+use super::names::{name_to_string, serialize_name};
+use super::{bigendians, opt, DnsFormatError, DnsPacket, DnsRecordData, ExtendedDnsError};
+use crate::dns::trace::log;
+
+// Option codes, from the IANA "DNS EDNS0 Option Codes (OPT)" registry
+const NSID: u16 = 3;
+const CLIENT_SUBNET: u16 = 8;
+const COOKIE: u16 = 10;
+const KEEPALIVE: u16 = 11;
+const PADDING: u16 = 12;
+const EXTENDED_ERROR: u16 = 15;
+const REPORT_CHANNEL: u16 = 18;
+
+// Address families for client subnets, from the IANA address family numbers registry
+const FAMILY_IPV4: u16 = 1;
+const FAMILY_IPV6: u16 = 2;
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum EdnsOption {
+    // Name server identifier (RFC 5001): empty in a query, the server's chosen id in a response
+    Nsid(Vec<u8>),
+    // EDNS Client Subnet (RFC 7871)
+    ClientSubnet(ClientSubnet),
+    // DNS cookies (RFC 7873): an 8 byte client cookie, and the server's 8 to 32 byte cookie if
+    // we've been given one
+    Cookie(Vec<u8>, Vec<u8>),
+    // TCP keepalive (RFC 7828), in units of 100ms. Clients send it without a timeout.
+    Keepalive(Option<u16>),
+    // Padding (RFC 7830), by length; the bytes themselves should be zero
+    Padding(usize),
+    // Extended DNS Errors (RFC 8914)
+    ExtendedError(ExtendedDnsError),
+    // DNS error reporting (RFC 9567): the agent domain an authority wants resolvers to report
+    // the errors they run into with its zones to
+    ReportChannel(Vec<String>),
+    Unknown(u16, Vec<u8>),
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct ClientSubnet {
+    pub address: IpAddr,
+    // How many leading bits of the address the client's subnet is
+    pub source_prefix: u8,
+    // How many of those bits the answer depends on; zero in queries
+    pub scope_prefix: u8,
+}
+
+impl EdnsOption {
+    pub fn code(&self) -> u16 {
+        match self {
+            EdnsOption::Nsid(_) => NSID,
+            EdnsOption::ClientSubnet(_) => CLIENT_SUBNET,
+            EdnsOption::Cookie(..) => COOKIE,
+            EdnsOption::Keepalive(_) => KEEPALIVE,
+            EdnsOption::Padding(_) => PADDING,
+            EdnsOption::ExtendedError(_) => EXTENDED_ERROR,
+            EdnsOption::ReportChannel(_) => REPORT_CHANNEL,
+            EdnsOption::Unknown(code, _) => *code,
+        }
+    }
+
+    // Parse an OPT record's rdata. The options have to fill it exactly. An option we know whose
+    // contents don't make sense is kept as Unknown rather than failing the whole packet over it.
+    pub fn parse_all(rdata: &[u8], start: usize) -> Result<Vec<EdnsOption>, DnsFormatError> {
+        let mut options = Vec::new();
+        let mut pos = 0;
+        while pos < rdata.len() {
+            if pos + 4 > rdata.len() {
+                return Err(DnsFormatError::make_error_at(
+                    String::from("EDNS option header runs past end of OPT record"),
+                    start + pos,
+                ));
+            }
+            let code = bigendians::to_u16(&rdata[pos..pos + 2]);
+            let len = bigendians::to_u16(&rdata[pos + 2..pos + 4]) as usize;
+            let data = match rdata.get(pos + 4..pos + 4 + len) {
+                Some(data) => data,
+                None => {
+                    return Err(DnsFormatError::make_error_at(
+                        format!(
+                            "EDNS option {} of length {} runs past end of OPT record",
+                            code, len
+                        ),
+                        start + pos,
+                    ))
+                }
+            };
+            options.push(
+                EdnsOption::parse(code, data)
+                    .unwrap_or_else(|| EdnsOption::Unknown(code, data.to_vec())),
+            );
+            pos += 4 + len;
+        }
+        Ok(options)
+    }
+
+    fn parse(code: u16, data: &[u8]) -> Option<EdnsOption> {
+        let option = match code {
+            NSID => EdnsOption::Nsid(data.to_vec()),
+            CLIENT_SUBNET => EdnsOption::ClientSubnet(ClientSubnet::parse(data)?),
+            COOKIE => match data.len() {
+                8 => EdnsOption::Cookie(data.to_vec(), Vec::new()),
+                16..=40 => EdnsOption::Cookie(data[..8].to_vec(), data[8..].to_vec()),
+                _ => return None,
+            },
+            KEEPALIVE => match data.len() {
+                0 => EdnsOption::Keepalive(None),
+                2 => EdnsOption::Keepalive(Some(bigendians::to_u16(data))),
+                _ => return None,
+            },
+            PADDING => EdnsOption::Padding(data.len()),
+            EXTENDED_ERROR if data.len() >= 2 => EdnsOption::ExtendedError(ExtendedDnsError {
+                info_code: bigendians::to_u16(&data[0..2]),
+                extra_text: String::from_utf8_lossy(&data[2..]).into_owned(),
+            }),
+            REPORT_CHANNEL => EdnsOption::ReportChannel(parse_agent(data)?),
+            _ => return None,
+        };
+        Some(option)
+    }
+
+    // The option's data, without the code and length
+    fn data(&self) -> Vec<u8> {
+        match self {
+            EdnsOption::Nsid(id) => id.to_owned(),
+            EdnsOption::ClientSubnet(subnet) => subnet.to_bytes(),
+            EdnsOption::Cookie(client, server) => [&client[..], &server[..]].concat(),
+            EdnsOption::Keepalive(timeout) => match timeout {
+                Some(timeout) => timeout.to_be_bytes().to_vec(),
+                None => Vec::new(),
+            },
+            EdnsOption::Padding(len) => vec![0; *len],
+            EdnsOption::ExtendedError(error) => {
+                let mut bytes = error.info_code.to_be_bytes().to_vec();
+                bytes.extend_from_slice(error.extra_text.as_bytes());
+                bytes
+            }
+            EdnsOption::ReportChannel(agent) => serialize_name(agent),
+            EdnsOption::Unknown(_, data) => data.to_owned(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let data = self.data();
+        let mut bytes = Vec::with_capacity(4 + data.len());
+        bytes.extend_from_slice(&self.code().to_be_bytes());
+        bytes.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&data);
+        bytes
+    }
+
+    pub fn all_to_bytes(options: &[EdnsOption]) -> Vec<u8> {
+        options.iter().flat_map(EdnsOption::to_bytes).collect()
+    }
+
+    // Options we don't parse ourselves come through as Unknown; these convert to and from a type
+    // of the caller's own
+    pub fn custom<T: CustomEdnsOption>(option: &T) -> EdnsOption {
+        EdnsOption::Unknown(T::CODE, option.to_data())
+    }
+
+    pub fn to_custom<T: CustomEdnsOption>(&self) -> Option<Result<T, String>> {
+        match self {
+            EdnsOption::Unknown(code, data) if *code == T::CODE => Some(T::from_data(data)),
+            _ => None,
+        }
+    }
+
+    // Whether this is an option we parse ourselves, and so can't be taken over by an extension
+    fn is_builtin(code: u16) -> bool {
+        matches!(
+            code,
+            NSID | CLIENT_SUBNET | COOKIE | KEEPALIVE | PADDING | EXTENDED_ERROR
+        )
+    }
+}
+
+// A vendor-specific option's data, as a type of its own
+pub trait CustomEdnsOption: Sized {
+    const CODE: u16;
+
+    fn from_data(data: &[u8]) -> Result<Self, String>;
+    fn to_data(&self) -> Vec<u8>;
+}
+
+// Server side handling for an option code we don't know about, registered with an EdnsRegistry
+pub trait EdnsOptionHandler: Send + Sync {
+    fn code(&self) -> u16;
+
+    // Check the data of the option as a client sent it
+    fn validate(&self, data: &[u8]) -> Result<(), String>;
+
+    // The data for an option of our code to put in the response to `query`, given the data of
+    // the one it came with. None leaves the response alone.
+    fn respond(&self, data: &[u8], query: &DnsPacket) -> Option<Vec<u8>>;
+}
+
+// The extension options the server answers to, by code
+#[derive(Clone, Default)]
+pub struct EdnsRegistry {
+    handlers: HashMap<u16, Arc<dyn EdnsOptionHandler>>,
+}
+
+impl EdnsRegistry {
+    pub fn new() -> EdnsRegistry {
+        EdnsRegistry::default()
+    }
+
+    // Options we parse ourselves can't be registered, and neither can a code twice
+    pub fn register(&mut self, handler: Arc<dyn EdnsOptionHandler>) -> Result<(), String> {
+        let code = handler.code();
+        if EdnsOption::is_builtin(code) {
+            return Err(format!(
+                "EDNS option {} is handled by montague itself",
+                code
+            ));
+        }
+        if self.handlers.contains_key(&code) {
+            return Err(format!("EDNS option {} already has a handler", code));
+        }
+        self.handlers.insert(code, handler);
+        Ok(())
+    }
+
+    // Let the handlers for any registered options in `query` add theirs to `response`
+    pub fn respond(&self, query: &DnsPacket, response: &mut DnsPacket) {
+        if self.handlers.is_empty() {
+            return;
+        }
+        let options = query.addl_recs.iter().filter_map(|rr| match &rr.record {
+            DnsRecordData::OPT(options) => Some(options),
+            _ => None,
+        });
+        for option in options.flatten() {
+            let (code, data) = match option {
+                EdnsOption::Unknown(code, data) => (*code, data),
+                _ => continue,
+            };
+            let handler = match self.handlers.get(&code) {
+                Some(handler) => handler,
+                None => continue,
+            };
+            if let Err(e) = handler.validate(data) {
+                log!("Ignoring malformed EDNS option {}: {}", code, e);
+                continue;
+            }
+            if let Some(data) = handler.respond(data, query) {
+                opt::add_option(response, EdnsOption::Unknown(code, data));
+            }
+        }
+    }
+}
+
+// A report channel's agent domain, which is always spelled out in full: exactly one name, with
+// no compression pointers
+fn parse_agent(data: &[u8]) -> Option<Vec<String>> {
+    let mut agent = Vec::new();
+    let mut pos = 0;
+    loop {
+        let len = *data.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        // Longer lengths are really pointers or other label types
+        if len > 63 {
+            return None;
+        }
+        let label = data.get(pos..pos + len)?;
+        agent.push(String::from_utf8(label.to_vec()).ok()?);
+        pos += len;
+    }
+    Some(agent).filter(|_| pos == data.len())
+}
+
+// The agent domain in a message's report channel option, if it has one
+pub fn report_channel(packet: &DnsPacket) -> Option<&[String]> {
+    let options = packet.addl_recs.iter().filter_map(|rr| match &rr.record {
+        DnsRecordData::OPT(options) => Some(options),
+        _ => None,
+    });
+    options.flatten().find_map(|option| match option {
+        EdnsOption::ReportChannel(agent) => Some(&agent[..]),
+        _ => None,
+    })
+}
+
+// The client subnet option in a message, if it has one
+pub fn client_subnet(packet: &DnsPacket) -> Option<&ClientSubnet> {
+    let options = packet.addl_recs.iter().filter_map(|rr| match &rr.record {
+        DnsRecordData::OPT(options) => Some(options),
+        _ => None,
+    });
+    options.flatten().find_map(|option| match option {
+        EdnsOption::ClientSubnet(subnet) => Some(subnet),
+        _ => None,
+    })
+}
+
+impl ClientSubnet {
+    fn parse(data: &[u8]) -> Option<ClientSubnet> {
+        if data.len() < 4 {
+            return None;
+        }
+        let family = bigendians::to_u16(&data[0..2]);
+        let source_prefix = data[2];
+        let scope_prefix = data[3];
+        let address = &data[4..];
+        // Only as many bytes as the prefix covers are sent (RFC 7871 section 6)
+        if address.len() != (source_prefix as usize).div_ceil(8) {
+            return None;
+        }
+        let address = match family {
+            FAMILY_IPV4 if source_prefix <= 32 => {
+                let mut octets = [0; 4];
+                octets[..address.len()].copy_from_slice(address);
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            FAMILY_IPV6 if source_prefix <= 128 => {
+                let mut octets = [0; 16];
+                octets[..address.len()].copy_from_slice(address);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => return None,
+        };
+        Some(ClientSubnet {
+            address,
+            source_prefix,
+            scope_prefix,
+        })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let (family, octets) = match self.address {
+            IpAddr::V4(ip) => (FAMILY_IPV4, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (FAMILY_IPV6, ip.octets().to_vec()),
+        };
+        let len = (self.source_prefix as usize).div_ceil(8);
+        let mut bytes = family.to_be_bytes().to_vec();
+        bytes.push(self.source_prefix);
+        bytes.push(self.scope_prefix);
+        bytes.extend_from_slice(&octets[..len.min(octets.len())]);
+        bytes
+    }
+}
+
+impl fmt::Display for EdnsOption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let hex = |bytes: &[u8]| -> String { bytes.iter().map(|b| format!("{:02x}", b)).collect() };
+        match self {
+            EdnsOption::Nsid(id) => write!(f, "NSID {}", hex(id)),
+            EdnsOption::ClientSubnet(subnet) => write!(
+                f,
+                "ECS {}/{}/{}",
+                subnet.address, subnet.source_prefix, subnet.scope_prefix
+            ),
+            EdnsOption::Cookie(client, server) => {
+                write!(f, "COOKIE {}{}", hex(client), hex(server))
+            }
+            EdnsOption::Keepalive(Some(timeout)) => {
+                write!(f, "KEEPALIVE {}ms", *timeout as u32 * 100)
+            }
+            EdnsOption::Keepalive(None) => write!(f, "KEEPALIVE"),
+            EdnsOption::Padding(len) => write!(f, "PADDING {}", len),
+            EdnsOption::ExtendedError(error) => write!(f, "{}", error),
+            EdnsOption::ReportChannel(agent) => {
+                write!(f, "REPORT-CHANNEL {}", name_to_string(agent))
+            }
+            EdnsOption::Unknown(code, data) => write!(f, "OPT{} {}", code, hex(data)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::dns::mock;
+    use crate::dns::protocol::{DnsRRType, EdeCode};
+
+    #[test]
+    fn options_round_trip() {
+        let options = vec![
+            EdnsOption::Nsid(b"ns1".to_vec()),
+            EdnsOption::ClientSubnet(ClientSubnet {
+                address: "192.0.2.0".parse().unwrap(),
+                source_prefix: 24,
+                scope_prefix: 0,
+            }),
+            EdnsOption::ClientSubnet(ClientSubnet {
+                address: "2001:db8::".parse().unwrap(),
+                source_prefix: 56,
+                scope_prefix: 48,
+            }),
+            EdnsOption::Cookie(vec![1; 8], Vec::new()),
+            EdnsOption::Cookie(vec![1; 8], vec![2; 16]),
+            EdnsOption::Keepalive(None),
+            EdnsOption::Keepalive(Some(1200)),
+            EdnsOption::Padding(7),
+            EdnsOption::ExtendedError(ExtendedDnsError::new(EdeCode::Blocked, "ads")),
+            EdnsOption::ReportChannel(mock::labels("agent.example")),
+            EdnsOption::Unknown(65001, vec![0xde, 0xad]),
+        ];
+        let bytes = EdnsOption::all_to_bytes(&options);
+        // ECS only sends as many address bytes as the prefix covers
+        assert_eq!(&bytes[7..18], &[0, 8, 0, 7, 0, 1, 24, 0, 192, 0, 2]);
+        assert_eq!(EdnsOption::parse_all(&bytes, 0).unwrap(), options);
+        assert_eq!(options[2].to_string(), "ECS 2001:db8::/56/48");
+        assert_eq!(options[6].to_string(), "KEEPALIVE 120000ms");
+
+        // Nonsense in an option we know is kept as is
+        let short_cookie = [0, 10, 0, 3, 1, 2, 3];
+        assert_eq!(
+            EdnsOption::parse_all(&short_cookie, 0).unwrap(),
+            vec![EdnsOption::Unknown(10, vec![1, 2, 3])]
+        );
+        // but options have to fit in the record
+        assert!(EdnsOption::parse_all(&[0, 3, 0, 5, 1], 0).is_err());
+        assert!(EdnsOption::parse_all(&[0, 3, 0], 0).is_err());
+        // and a report channel has to be a name, without pointers or anything after it
+        let pointer = [0, 18, 0, 4, 1, b'a', 0xc0, 0];
+        assert_eq!(
+            EdnsOption::parse_all(&pointer, 0).unwrap(),
+            vec![EdnsOption::Unknown(18, vec![1, b'a', 0xc0, 0])]
+        );
+    }
+
+    // A made up option that asks the server which datacenter answered
+    #[derive(Debug, PartialEq)]
+    struct Datacenter(String);
+
+    impl CustomEdnsOption for Datacenter {
+        const CODE: u16 = 65001;
+
+        fn from_data(data: &[u8]) -> Result<Datacenter, String> {
+            String::from_utf8(data.to_vec())
+                .map(Datacenter)
+                .map_err(|e| e.to_string())
+        }
+
+        fn to_data(&self) -> Vec<u8> {
+            self.0.as_bytes().to_vec()
+        }
+    }
+
+    struct DatacenterHandler(u16);
+
+    impl EdnsOptionHandler for DatacenterHandler {
+        fn code(&self) -> u16 {
+            self.0
+        }
+
+        fn validate(&self, data: &[u8]) -> Result<(), String> {
+            Datacenter::from_data(data).map(|_| ())
+        }
+
+        fn respond(&self, _data: &[u8], _query: &DnsPacket) -> Option<Vec<u8>> {
+            Some(Datacenter(String::from("sfo")).to_data())
+        }
+    }
+
+    #[test]
+    fn registered_options_are_answered() {
+        let mut registry = EdnsRegistry::new();
+        registry
+            .register(Arc::new(DatacenterHandler(Datacenter::CODE)))
+            .unwrap();
+        assert!(registry
+            .register(Arc::new(DatacenterHandler(Datacenter::CODE)))
+            .is_err());
+        assert!(registry
+            .register(Arc::new(DatacenterHandler(COOKIE)))
+            .is_err());
+
+        let mut query = mock::query("example.com", DnsRRType::A);
+        let mut response = query.to_owned();
+        registry.respond(&query, &mut response);
+        assert!(!crate::dns::protocol::supports_edns(&response));
+
+        let asked = EdnsOption::custom(&Datacenter(String::new()));
+        let mut opt = crate::dns::protocol::opt_record(1232);
+        opt.record = DnsRecordData::OPT(vec![EdnsOption::Padding(4), asked]);
+        query.addl_recs.push(opt);
+        registry.respond(&query, &mut response);
+        let parsed = DnsPacket::from_bytes(&response.to_bytes()).unwrap();
+        let answered = match &parsed.addl_recs[0].record {
+            DnsRecordData::OPT(options) => options[0].to_custom::<Datacenter>(),
+            _ => None,
+        };
+        assert_eq!(answered, Some(Ok(Datacenter(String::from("sfo")))));
+    }
+}
@@ -6,6 +6,8 @@ use super::{DnsFlags, DnsPacket, DnsRCode};
 #[derive(Debug)]
 pub struct DnsFormatError {
     message: String,
+    // Offset into the packet of the element we failed to parse, if known
+    offset: Option<usize>,
     partial: Option<DnsPacket>,
 }
 
@@ -13,6 +15,15 @@ impl DnsFormatError {
     pub fn make_error(message: String) -> DnsFormatError {
         DnsFormatError {
             message,
+            offset: None,
+            partial: None,
+        }
+    }
+
+    pub fn make_error_at(message: String, offset: usize) -> DnsFormatError {
+        DnsFormatError {
+            message,
+            offset: Some(offset),
             partial: None,
         }
     }
@@ -21,6 +32,14 @@ impl DnsFormatError {
         &self.message
     }
 
+    // Errors bubble up through several parsers; the innermost one to know where it was gets to
+    // set the offset, so this doesn't overwrite an offset that's already been set.
+    pub fn set_offset(&mut self, offset: usize) {
+        if self.offset.is_none() {
+            self.offset = Some(offset);
+        }
+    }
+
     // A partial packet should not contain answers, nameservers, or ARs in it,
     // even if they were in the query and successfully decoded. For now, at least;
     // TODO figure out what a DNS server does and does not send back on FormErr
@@ -67,7 +86,11 @@ impl DnsFormatError {
 
 impl fmt::Display for DnsFormatError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "DNS packet had format error: {}", self.message)
+        write!(f, "DNS packet had format error: {}", self.message)?;
+        if let Some(offset) = self.offset {
+            write!(f, " (at offset {})", offset)?;
+        }
+        Ok(())
     }
 }
 
@@ -1,3 +1,5 @@
+use std::fmt;
+
 use super::{DnsFormatError, DnsOpcode, DnsRCode};
 
 #[derive(Clone, PartialEq, Debug)]
@@ -36,6 +38,12 @@ pub struct DnsFlags {
 
 impl DnsFlags {
     pub fn from_bytes(bytes: &[u8]) -> Result<DnsFlags, DnsFormatError> {
+        if bytes.len() < 2 {
+            return Err(DnsFormatError::make_error(format!(
+                "Flags need two bytes, got {}",
+                bytes.len()
+            )));
+        }
         let qr_bit: bool = (bytes[0] >> 7) & 1 == 1;
         let aa_bit: bool = (bytes[0] >> 2) & 1 == 1;
         let tc_bit: bool = (bytes[0] >> 1) & 1 == 1;
@@ -118,6 +126,28 @@ impl DnsFlags {
     }
 }
 
+// Display just lists the names of the bits which are set, like dig does; the opcode and rcode
+// are left to the caller since they're usually shown separately.
+impl fmt::Display for DnsFlags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bits = [
+            (self.qr_bit, "qr"),
+            (self.aa_bit, "aa"),
+            (self.tc_bit, "tc"),
+            (self.rd_bit, "rd"),
+            (self.ra_bit, "ra"),
+            (self.ad_bit, "ad"),
+            (self.cd_bit, "cd"),
+        ];
+        let set: Vec<&str> = bits
+            .iter()
+            .filter(|(set, _)| *set)
+            .map(|(_, n)| *n)
+            .collect();
+        write!(f, "{}", set.join(" "))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::dns::protocol::flags::*;
@@ -1,27 +1,60 @@
 mod bigendians;
+mod canonical;
 mod class;
+mod dump;
+mod ede;
+mod edns;
 mod errors;
 mod flags;
 mod names;
 mod opcode;
+mod opt;
 mod packet;
 mod question;
 mod rcode;
 mod rdata;
 mod rr;
+mod rrset;
 mod rrtype;
+mod tsig;
+mod writer;
 
 // Reference RFC 1035 ( https://tools.ietf.org/html/rfc1035) and a bajillion
 // others that have made updates to it. I've put comments where the element
 // isn't coming directly from RFC 1035. RFC 6985 summarizes some updates too.
 // See: https://www.iana.org/assignments/dns-parameters/dns-parameters.xhtml
+pub use canonical::{canonical_rr_bytes, compare_names, sort_canonical};
 pub use class::DnsClass;
+pub use dump::{annotate_packet, hex_dump};
+pub use ede::{EdeCode, ExtendedDnsError};
+// The traits are for extensions defining options of their own
+#[allow(unused_imports)]
+pub use edns::{
+    client_subnet, report_channel, ClientSubnet, CustomEdnsOption, EdnsOption, EdnsOptionHandler,
+    EdnsRegistry,
+};
 pub use errors::DnsFormatError;
 pub use flags::DnsFlags;
+pub use names::{
+    check_hostname, deserialize_name, is_subdomain, name_from_string, name_to_string, names_equal,
+    serialize_name, NameKey,
+};
+// For building the owner names of service records
+#[allow(unused_imports)]
+pub use names::{is_service_label, srv_owner, tlsa_owner};
 pub use opcode::DnsOpcode;
+pub use opt::{
+    add_option, opt_count, remove_opt, set_bad_version, set_response_opt, supports_edns,
+    udp_response_limit, EdnsOptions,
+};
+// For building EDNS messages by hand
+#[allow(unused_imports)]
+pub use opt::opt_record;
 pub use packet::DnsPacket;
 pub use question::DnsQuestion;
 pub use rcode::DnsRCode;
-pub use rdata::DnsRecordData;
+pub use rdata::{DnsRecordData, SoaData, SrvData};
 pub use rr::DnsResourceRecord;
+pub use rrset::{merge_rrsets, RRset};
 pub use rrtype::DnsRRType;
+pub use tsig::{add_tsig_error, sign_tsig, verify_tsig, SignedQuery, TsigError, TsigKey};
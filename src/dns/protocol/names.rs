@@ -1,5 +1,17 @@
+use std::collections::HashMap;
+
 use super::DnsFormatError;
 
+// Pointers are 14 bits, so only names in the first 16KB of a message can be pointed to
+const MAX_POINTER: usize = 0x3fff;
+
+// The longest a name can be on the wire, counting each label's length byte and the root's
+// (RFC 1035 section 3.1)
+const MAX_NAME_LENGTH: usize = 255;
+
+// A name has at most 127 labels, so a well formed one never needs more pointers than that
+const MAX_NAME_POINTERS: usize = 127;
+
 // Functions for handling DNS names
 
 // Unlike the other functions, `bytes` here must be the WHOLE dns packet,
@@ -11,45 +23,68 @@ pub fn deserialize_name(
     bytes: &[u8],
     start: usize,
 ) -> Result<(Vec<String>, usize), DnsFormatError> {
-    // TODO: This function doesn't handle malformed packets yet
     let mut labels = Vec::new();
     let mut pos = start;
     let packet_len = bytes.len();
+    // Where the name ends, once a pointer has taken us somewhere else to read the rest of it
+    let mut end = None;
+    // Pointers have to point before anything of the name read so far, so following them always
+    // gets closer to the start of the packet, and a packet can't send us round in circles
+    let mut earliest = start;
+    let mut pointers = 0;
+    // The root label's length byte counts too
+    let mut name_length = 1;
     loop {
         // This check catches two separate cases: the case where the last label we read was the end
         // of the packet, but was not the root label (so we didn't return), and the case where a
         // pointer jumped us beyond the end of the packet
         if pos >= packet_len {
-            return Err(DnsFormatError::make_error(format!(
-                "Reached end of packet while parsing label or label pointer jumped beyond packet"
-            )));
+            return Err(DnsFormatError::make_error_at(
+                String::from(
+                    "Reached end of packet while parsing label or label pointer jumped beyond packet",
+                ),
+                pos,
+            ));
         }
         let len_byte = bytes[pos];
         // If the length begins with the bits 11, it is a pointer
-        // If it begins with the bits 00, it is a length
+        // If it begins with the bits 00, it is a length, which is what keeps labels to 63 bytes
         // Otherwise, it is invalid
         match (len_byte >> 6) & 0b11u8 {
             0b11 => {
                 // We're about to read two bytes, so we need to check that the next byte is also
                 // valid
                 if pos + 1 >= packet_len {
-                    return Err(DnsFormatError::make_error(format!(
-                        "Unexpected end of packet at label pointer start"
-                    )));
+                    return Err(DnsFormatError::make_error_at(
+                        String::from("Unexpected end of packet at label pointer start"),
+                        pos,
+                    ));
                 }
                 // The pointer includes the lower 6 bits of the "length" and
                 // the entirety of the next byte
                 let pointer_start: usize =
                     (((len_byte & 0b111111u8) as usize) << 8) + (bytes[pos + 1] as usize);
-
-                // We don't care where the other name ends, just what is there
-                let (mut remainder, _) = deserialize_name(bytes, pointer_start)?;
-                labels.append(&mut remainder);
-
-                // A pointer always is the end of a label; we can advance the
-                // position by the two bytes we've read and return.
-                pos += 2;
-                break;
+                if pointer_start >= earliest {
+                    return Err(DnsFormatError::make_error_at(
+                        format!(
+                            "Label pointer to {} doesn't point back before the name",
+                            pointer_start
+                        ),
+                        pos,
+                    ));
+                }
+                pointers += 1;
+                if pointers > MAX_NAME_POINTERS {
+                    return Err(DnsFormatError::make_error_at(
+                        String::from("Name follows too many label pointers"),
+                        pos,
+                    ));
+                }
+                // A pointer always is the end of the name where it is; the rest of it is wherever
+                // the pointer points
+                end.get_or_insert(pos + 2);
+                earliest = pointer_start;
+                pos = pointer_start;
             }
             0b00 => {
                 // Read the next `len_byte` bytes as a label
@@ -62,31 +97,291 @@ pub fn deserialize_name(
                 }
                 // Ensure the label we're about to read exists
                 if pos + length >= packet_len {
-                    return Err(DnsFormatError::make_error(format!(
-                        "Label length is longer than remainder of packet"
-                    )));
+                    return Err(DnsFormatError::make_error_at(
+                        String::from("Label length is longer than remainder of packet"),
+                        pos - 1,
+                    ));
+                }
+                name_length += 1 + length;
+                if name_length > MAX_NAME_LENGTH {
+                    return Err(DnsFormatError::make_error_at(
+                        format!("Name is longer than {} bytes", MAX_NAME_LENGTH),
+                        start,
+                    ));
                 }
                 // TODO the spec is kind of annoying here. It talks a lot about
                 // ASCII but doesn't ever require a domain is made of only ASCII
                 // characters. Further, it talks about "case insensitivity" but
                 // then seems to suggest that if any byte is not alphanumeric
-                // ASCII that's out the window. Let's treat it as a case
-                // sensitive UTF-8 string for now.
-                let label = String::from_utf8(bytes[pos..pos + length].to_vec())
-                    .expect("Label was not UTF-8");
+                // ASCII that's out the window. Let's treat it as a UTF-8
+                // string for now (compared ASCII case insensitively, see
+                // names_equal) and reject labels that aren't valid UTF-8.
+                let label = match String::from_utf8(bytes[pos..pos + length].to_vec()) {
+                    Ok(x) => x,
+                    Err(_) => {
+                        return Err(DnsFormatError::make_error_at(
+                            String::from("Label is not valid UTF-8"),
+                            pos - 1,
+                        ));
+                    }
+                };
                 labels.push(label);
                 pos += length;
             }
             _ => {
                 // Technically, there is another label type possible here, proposed in RFC6891.
                 // It's unclear if this is worth supporting in practice.
-                return Err(DnsFormatError::make_error(format!(
-                    "Unsupported or invalid label pointer type"
-                )));
+                return Err(DnsFormatError::make_error_at(
+                    String::from("Unsupported or invalid label pointer type"),
+                    pos,
+                ));
+            }
+        }
+    }
+    Ok((labels, end.unwrap_or(pos)))
+}
+
+// Names are case insensitive (RFC 4343), but only for ASCII letters: any other byte has to match
+// exactly. Anything comparing names that came off the wire should use these rather than `==`,
+// since authorities are free to change capitalization on us.
+pub fn names_equal(a: &[String], b: &[String]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b.iter())
+            .all(|(x, y)| x.eq_ignore_ascii_case(y))
+}
+
+// True if `name` is `zone` or a name below it
+pub fn is_subdomain(name: &[String], zone: &[String]) -> bool {
+    name.len() >= zone.len() && names_equal(&name[name.len() - zone.len()..], zone)
+}
+
+// A name normalized for use as a map key (e.g. in a cache), so that names differing only in case
+// hash and compare the same. Keeps the lowercased labels rather than the original ones.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct NameKey(Vec<String>);
+
+impl NameKey {
+    pub fn new(name: &[String]) -> NameKey {
+        NameKey(
+            name.iter()
+                .map(|label| label.to_ascii_lowercase())
+                .collect(),
+        )
+    }
+}
+
+// Underscore labels (RFC 8552) name a service or protocol rather than a host, as in
+// _dmarc.example.com or _443._tcp.example.com. They're ordinary labels everywhere else: compared,
+// cached, and looked up like any other.
+pub fn is_service_label(label: &str) -> bool {
+    label.starts_with('_')
+}
+
+// The owner name of a service's SRV records (RFC 2782), e.g. _imaps._tcp.example.com for
+// ("imaps", "tcp", example.com). Either part may be given with or without its underscore.
+pub fn srv_owner(service: &str, protocol: &str, name: &[String]) -> Vec<String> {
+    service_owner(&[service, protocol], name)
+}
+
+// The owner name of the TLSA records for a TLS service (RFC 6698 section 3), e.g.
+// _443._tcp.www.example.com for port 443 over TCP on www.example.com
+pub fn tlsa_owner(port: u16, protocol: &str, name: &[String]) -> Vec<String> {
+    service_owner(&[&port.to_string(), protocol], name)
+}
+
+fn service_owner(prefix: &[&str], name: &[String]) -> Vec<String> {
+    prefix
+        .iter()
+        .map(|label| format!("_{}", label.trim_start_matches('_')))
+        .chain(name.iter().cloned())
+        .collect()
+}
+
+// Check a name against the hostname rules of RFC 952 and RFC 1123: each label is letters, digits,
+// and hyphens (LDH), and doesn't start or end with a hyphen. A label may also start with an
+// underscore, since service names (RFC 8552; _dmarc, _443._tcp and friends) live in the same
+// tree as hostnames. Returns a description of the first problem found.
+pub fn check_hostname(name: &[String]) -> Result<(), String> {
+    let wire_length: usize = name.iter().map(|label| label.len() + 1).sum::<usize>() + 1;
+    if wire_length > 255 {
+        return Err(format!("name is {} bytes long, limit is 255", wire_length));
+    }
+    for label in name {
+        let bytes = label.as_bytes();
+        if bytes.is_empty() || bytes.len() > 63 {
+            return Err(format!("label \"{}\" has length {}", label, bytes.len()));
+        }
+        let ldh_part = if is_service_label(label) {
+            &bytes[1..]
+        } else {
+            bytes
+        };
+        if let Some(bad) = ldh_part
+            .iter()
+            .find(|b| !(b.is_ascii_alphanumeric() || **b == b'-'))
+        {
+            return Err(format!(
+                "label \"{}\" contains {:?}",
+                label,
+                String::from_utf8_lossy(&[*bad])
+            ));
+        }
+        if ldh_part.first() == Some(&b'-') || ldh_part.last() == Some(&b'-') {
+            return Err(format!("label \"{}\" starts or ends with a hyphen", label));
+        }
+    }
+    Ok(())
+}
+
+// Presentation format for a name, as in zone files and dig output: labels joined by dots with a
+// trailing dot for the root. Dots and backslashes inside a label are escaped, as is anything
+// that isn't printable ASCII (as \DDD, per RFC 1035 section 5.1).
+pub fn name_to_string(name: &[String]) -> String {
+    if name.is_empty() {
+        return String::from(".");
+    }
+    let mut out = String::new();
+    for label in name {
+        for byte in label.as_bytes() {
+            match byte {
+                b'.' | b'\\' => {
+                    out.push('\\');
+                    out.push(*byte as char);
+                }
+                0x21..=0x7e => out.push(*byte as char),
+                _ => out.push_str(&format!("\\{:03}", byte)),
+            }
+        }
+        out.push('.');
+    }
+    out
+}
+
+// The reverse of name_to_string: parse a name in presentation format into labels. The trailing
+// dot is optional, since names typed by people are usually relative to the root anyway. Accepts
+// the same \. and \DDD escapes name_to_string produces.
+pub fn name_from_string(name: &str) -> Result<Vec<String>, String> {
+    if name == "." || name.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut labels = Vec::new();
+    let mut label: Vec<u8> = Vec::new();
+    let mut bytes = name.bytes();
+    // Whether the last thing we read was an unescaped dot, i.e. the name is fully qualified
+    let mut ended_with_dot = false;
+    while let Some(byte) = bytes.next() {
+        ended_with_dot = false;
+        match byte {
+            b'.' => {
+                if label.is_empty() {
+                    return Err(format!("empty label in {:?}", name));
+                }
+                labels.push(label_from_bytes(&label, name)?);
+                label.clear();
+                ended_with_dot = true;
+            }
+            b'\\' => match bytes.next() {
+                Some(digit) if digit.is_ascii_digit() => {
+                    let digits = [digit, bytes.next().unwrap_or(0), bytes.next().unwrap_or(0)];
+                    if !digits.iter().all(|d| d.is_ascii_digit()) {
+                        return Err(format!("bad \\DDD escape in {:?}", name));
+                    }
+                    let value = digits
+                        .iter()
+                        .fold(0u32, |acc, d| acc * 10 + (d - b'0') as u32);
+                    if value > 255 {
+                        return Err(format!("escape \\{} out of range in {:?}", value, name));
+                    }
+                    label.push(value as u8);
+                }
+                Some(escaped) => label.push(escaped),
+                None => return Err(format!("trailing backslash in {:?}", name)),
+            },
+            _ => label.push(byte),
+        }
+    }
+    if !ended_with_dot {
+        if label.is_empty() {
+            return Err(format!("empty label in {:?}", name));
+        }
+        labels.push(label_from_bytes(&label, name)?);
+    }
+    let wire_length: usize = labels.iter().map(|l| l.len() + 1).sum::<usize>() + 1;
+    if wire_length > 255 {
+        return Err(format!("name is {} bytes long, limit is 255", wire_length));
+    }
+    Ok(labels)
+}
+
+fn label_from_bytes(label: &[u8], name: &str) -> Result<String, String> {
+    if label.len() > 63 {
+        return Err(format!("label longer than 63 bytes in {:?}", name));
+    }
+    // Labels are kept as strings (see deserialize_name), so they have to be valid UTF-8
+    String::from_utf8(label.to_vec()).map_err(|_| format!("label is not valid UTF-8 in {:?}", name))
+}
+
+// The length of a name on the wire, uncompressed
+pub fn name_wire_len(name: &[String]) -> usize {
+    name.iter().map(|label| label.len() + 1).sum::<usize>() + 1
+}
+
+// Where names have been written in a message so far, so that later names can end in a pointer to
+// an earlier copy of their suffix rather than spelling it out again (RFC 1035 section 4.1.4)
+#[derive(Default, Debug)]
+pub struct NameOffsets(HashMap<NameKey, usize>);
+
+impl NameOffsets {
+    pub fn new() -> NameOffsets {
+        NameOffsets::default()
+    }
+
+    // The offset of the longest suffix of `name` written already, and how many of `name`'s labels
+    // come before it
+    pub fn find(&self, name: &[String]) -> Option<(usize, usize)> {
+        (0..name.len()).find_map(|skip| {
+            self.0
+                .get(&NameKey::new(&name[skip..]))
+                .map(|offset| (skip, *offset))
+        })
+    }
+
+    // Note that `name` was written at `pos` with its first `written` labels spelled out, so later
+    // names can point to any of those labels' suffixes
+    pub fn add(&mut self, name: &[String], pos: usize, written: usize) {
+        let mut offset = pos;
+        for skip in 0..written {
+            if offset > MAX_POINTER {
+                break;
+            }
+            self.0.entry(NameKey::new(&name[skip..])).or_insert(offset);
+            offset += name[skip].len() + 1;
+        }
+    }
+
+    // Forget names written from `pos` on, once they've been taken back out of the message
+    pub fn forget_from(&mut self, pos: usize) {
+        self.0.retain(|_, offset| *offset < pos);
+    }
+
+    // How many bytes `name` takes written at `pos` with compression, noting it for later names
+    pub fn compressed_len(&mut self, name: &[String], pos: usize) -> usize {
+        match self.find(name) {
+            Some((written, _)) => {
+                self.add(name, pos, written);
+                name[..written]
+                    .iter()
+                    .map(|label| label.len() + 1)
+                    .sum::<usize>()
+                    + 2
+            }
+            None => {
+                self.add(name, pos, name.len());
+                name_wire_len(name)
             }
         }
     }
-    Ok((labels, pos))
 }
 
 // This serialize doesn't take possible label compression into account
@@ -165,4 +460,114 @@ mod tests {
         assert_eq!(labels, Vec::<String>::new());
         assert_eq!(pos, 93);
     }
+
+    #[test]
+    fn pointers_only_go_back() {
+        // "a" then a pointer back to it, which would read "a.a.a..." forever
+        let looped = [0, 0, 1, b'a', 0b11000000, 2];
+        assert!(deserialize_name(&looped, 2).is_err());
+        // Pointing at itself, or forward
+        assert!(deserialize_name(&[0b11000000, 0], 0).is_err());
+        assert!(deserialize_name(&[0b11000000, 2, 0], 0).is_err());
+        // Each pointer further back than the last is fine
+        let chained = [0, 1, b'b', 0b11000000, 0, 1, b'a', 0b11000000, 1];
+        let (labels, pos) = deserialize_name(&chained, 5).unwrap();
+        assert_eq!(labels, vec!["a", "b"]);
+        assert_eq!(pos, 9);
+
+        // 127 labels of one byte are 255 bytes with the root, which is as long as a name gets
+        let mut long = b"\x01a".repeat(127);
+        long.push(0);
+        assert_eq!(deserialize_name(&long, 0).unwrap().0.len(), 127);
+        long.splice(0..0, b"\x01a".iter().copied());
+        assert!(deserialize_name(&long, 0).is_err());
+    }
+
+    #[test]
+    fn name_comparison_ignores_ascii_case() {
+        let lower = vec!["ns1".to_owned(), "example".to_owned(), "com".to_owned()];
+        let mixed = vec!["NS1".to_owned(), "ExAmPlE".to_owned(), "com".to_owned()];
+        assert!(names_equal(&lower, &mixed));
+        assert!(!names_equal(&lower, &mixed[1..]));
+        assert!(is_subdomain(&mixed, &lower[1..]));
+        assert!(is_subdomain(&mixed, &[]));
+        assert!(!is_subdomain(&lower[1..], &mixed));
+        assert_eq!(NameKey::new(&lower), NameKey::new(&mixed));
+
+        // Only ASCII gets case folded
+        let upper = vec!["ÉCOLE".to_owned()];
+        let lower = vec!["école".to_owned()];
+        assert!(!names_equal(&upper, &lower));
+        assert_ne!(NameKey::new(&upper), NameKey::new(&lower));
+    }
+
+    #[test]
+    fn hostname_check_works() {
+        let name = |s: &str| -> Vec<String> { s.split('.').map(String::from).collect() };
+        assert!(check_hostname(&[]).is_ok());
+        assert!(check_hostname(&name("www.example.com")).is_ok());
+        assert!(check_hostname(&name("xn--bcher-kva.example")).is_ok());
+        assert!(check_hostname(&name("_dmarc.example.com")).is_ok());
+        assert!(check_hostname(&name("_443._tcp.example.com")).is_ok());
+        assert!(check_hostname(&name("a_b.example.com")).is_err());
+        assert!(check_hostname(&name("-foo.example.com")).is_err());
+        assert!(check_hostname(&name("foo-.example.com")).is_err());
+        assert!(check_hostname(&name("_-foo.example.com")).is_err());
+        assert!(check_hostname(&name("foo bar.example.com")).is_err());
+        assert!(check_hostname(&name("*.example.com")).is_err());
+        assert!(check_hostname(&[String::from("a").repeat(64)]).is_err());
+        assert!(check_hostname(&vec![String::from("a").repeat(63); 4]).is_err());
+    }
+
+    #[test]
+    fn service_names_are_built_and_kept_intact() {
+        let host = name_from_string("www.example.com").unwrap();
+        let tlsa = tlsa_owner(443, "tcp", &host);
+        assert_eq!(name_to_string(&tlsa), "_443._tcp.www.example.com.");
+        assert_eq!(
+            srv_owner("_imaps", "_tcp", &host[1..]),
+            name_from_string("_imaps._tcp.example.com").unwrap()
+        );
+        assert!(is_service_label(&tlsa[0]) && is_service_label(&tlsa[1]));
+        assert!(!is_service_label(&tlsa[2]));
+
+        // Underscores survive the wire, presentation format, and case folding untouched
+        let bytes = serialize_name(&tlsa);
+        assert_eq!(&bytes[..5], b"\x04_443");
+        assert_eq!(deserialize_name(&bytes, 0).unwrap().0, tlsa);
+        assert_eq!(name_from_string(&name_to_string(&tlsa)).unwrap(), tlsa);
+        let dmarc = name_from_string("_DMARC.Example.com").unwrap();
+        assert_eq!(
+            NameKey::new(&dmarc),
+            NameKey::new(&name_from_string("_dmarc.example.com").unwrap())
+        );
+        assert!(is_subdomain(&tlsa, &host));
+        assert!(check_hostname(&dmarc).is_ok());
+    }
+
+    #[test]
+    fn name_to_string_works() {
+        assert_eq!(name_to_string(&[]), ".");
+        let name = vec!["blog".to_owned(), "example".to_owned(), "com".to_owned()];
+        assert_eq!(name_to_string(&name), "blog.example.com.");
+        let name = vec!["a.b".to_owned(), "c d".to_owned()];
+        assert_eq!(name_to_string(&name), "a\\.b.c\\032d.");
+    }
+
+    #[test]
+    fn name_from_string_works() {
+        assert_eq!(name_from_string(".").unwrap(), Vec::<String>::new());
+        let name = vec!["blog".to_owned(), "example".to_owned(), "com".to_owned()];
+        assert_eq!(name_from_string("blog.example.com").unwrap(), name);
+        assert_eq!(name_from_string("blog.example.com.").unwrap(), name);
+        let name = vec!["a.b".to_owned(), "c d".to_owned()];
+        assert_eq!(name_from_string("a\\.b.c\\032d.").unwrap(), name);
+        assert_eq!(name_from_string(&name_to_string(&name)).unwrap(), name);
+        assert!(name_from_string("a..b").is_err());
+        assert!(name_from_string(".com").is_err());
+        assert!(name_from_string("a\\").is_err());
+        assert!(name_from_string("a\\25").is_err());
+        assert!(name_from_string("a\\256").is_err());
+        assert!(name_from_string(&"a".repeat(64)).is_err());
+    }
 }
@@ -0,0 +1,238 @@
+// The OPT pseudo-record (RFC 6891), which is how a message says it speaks EDNS. It goes in the
+// additional section, but isn't really a record: its class is the largest UDP payload the sender
+// can take, and its TTL is the upper bits of an extended rcode, the EDNS version, and flags.
+// EdnsOptions is those fields pulled out of the record, along with the options in its rdata.
+
+use super::{DnsClass, DnsPacket, DnsRRType, DnsRecordData, DnsResourceRecord, EdnsOption};
+
+// Payload size advertised in an OPT record we have to add to a response
+const DEFAULT_PAYLOAD_SIZE: u16 = 1232;
+
+// What every client can take over UDP, EDNS or not (RFC 1035 section 4.2.1)
+const MIN_PAYLOAD_SIZE: u16 = 512;
+
+// Where the fields sit in an OPT record's TTL (RFC 6891 section 6.1.3). Of the flags, only DO is
+// defined (RFC 3225).
+const EXTENDED_RCODE_SHIFT: u32 = 24;
+const VERSION_SHIFT: u32 = 16;
+const DO_FLAG: u32 = 1 << 15;
+
+// Extended rcode 16, BADVERS: 1 in the OPT record's upper bits, with 0 in the header
+const BADVERS: u8 = 1;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct EdnsOptions {
+    // The largest UDP message the sender can take
+    pub payload_size: u16,
+    // The upper eight bits of a twelve bit rcode; the lower four are in the header
+    pub extended_rcode: u8,
+    pub version: u8,
+    // Whether the sender wants DNSSEC records
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
+}
+
+impl EdnsOptions {
+    // Version 0 with no flags or options, which is all it takes to tell a server we speak EDNS
+    pub fn new(payload_size: u16) -> EdnsOptions {
+        EdnsOptions {
+            payload_size,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: Vec::new(),
+        }
+    }
+
+    // The fields of an OPT record, or None if it's some other kind of record
+    pub fn from_record(rr: &DnsResourceRecord) -> Option<EdnsOptions> {
+        if rr.rr_type != DnsRRType::OPT {
+            return None;
+        }
+        let options = match &rr.record {
+            DnsRecordData::OPT(options) => options.to_owned(),
+            _ => Vec::new(),
+        };
+        Some(EdnsOptions {
+            // Parsing always reads an OPT record's class as its payload size
+            payload_size: match rr.class {
+                DnsClass::EdnsPayloadSize(size) => size,
+                _ => DEFAULT_PAYLOAD_SIZE,
+            },
+            extended_rcode: (rr.ttl >> EXTENDED_RCODE_SHIFT) as u8,
+            version: (rr.ttl >> VERSION_SHIFT) as u8,
+            dnssec_ok: rr.ttl & DO_FLAG != 0,
+            options,
+        })
+    }
+
+    // A message's EDNS, if it has any. Only the first OPT record counts; a message with more
+    // than one is malformed anyway.
+    pub fn from_packet(packet: &DnsPacket) -> Option<EdnsOptions> {
+        opt_records(packet)
+            .next()
+            .and_then(EdnsOptions::from_record)
+    }
+
+    pub fn to_record(&self) -> DnsResourceRecord {
+        let mut ttl = (u32::from(self.extended_rcode) << EXTENDED_RCODE_SHIFT)
+            | (u32::from(self.version) << VERSION_SHIFT);
+        if self.dnssec_ok {
+            ttl |= DO_FLAG;
+        }
+        DnsResourceRecord {
+            name: Vec::new(),
+            rr_type: DnsRRType::OPT,
+            class: DnsClass::EdnsPayloadSize(self.payload_size),
+            ttl,
+            record: DnsRecordData::OPT(self.options.to_owned()),
+        }
+    }
+
+    // Put this in a message, in place of any OPT record it already has
+    pub fn attach(&self, packet: &mut DnsPacket) {
+        remove_opt(packet);
+        packet.addl_recs.push(self.to_record());
+    }
+}
+
+// An OPT record with no options
+pub fn opt_record(payload_size: u16) -> DnsResourceRecord {
+    EdnsOptions::new(payload_size).to_record()
+}
+
+// Add an option to a packet's OPT record, adding one if it has none
+pub fn add_option(packet: &mut DnsPacket, option: EdnsOption) {
+    let existing = packet
+        .addl_recs
+        .iter()
+        .position(|rr| rr.rr_type == DnsRRType::OPT);
+    let index = match existing {
+        Some(index) => index,
+        None => {
+            packet.addl_recs.push(opt_record(DEFAULT_PAYLOAD_SIZE));
+            packet.addl_recs.len() - 1
+        }
+    };
+    match &mut packet.addl_recs[index].record {
+        DnsRecordData::OPT(options) => options.push(option),
+        record => *record = DnsRecordData::OPT(vec![option]),
+    }
+}
+
+pub fn remove_opt(packet: &mut DnsPacket) {
+    packet.addl_recs.retain(|rr| rr.rr_type != DnsRRType::OPT);
+}
+
+// The most a response to `query` can take up over UDP: 512 bytes without EDNS, and otherwise what
+// the client says it can take (RFC 6891 section 6.2.5), up to the size we advertise ourselves,
+// since bigger messages risk being fragmented on the way
+pub fn udp_response_limit(query: &DnsPacket) -> usize {
+    let size = match EdnsOptions::from_packet(query) {
+        Some(edns) => edns
+            .payload_size
+            .clamp(MIN_PAYLOAD_SIZE, DEFAULT_PAYLOAD_SIZE),
+        None => MIN_PAYLOAD_SIZE,
+    };
+    size.into()
+}
+
+// Whether a query carried an OPT record. Responders mustn't add one to the response otherwise
+// (RFC 6891 section 7).
+pub fn supports_edns(query: &DnsPacket) -> bool {
+    opt_records(query).next().is_some()
+}
+
+fn opt_records(packet: &DnsPacket) -> impl Iterator<Item = &DnsResourceRecord> {
+    packet
+        .addl_recs
+        .iter()
+        .filter(|rr| rr.rr_type == DnsRRType::OPT)
+}
+
+// How many OPT records a message has; more than one is a format error (RFC 6891 section 6.1.1)
+pub fn opt_count(packet: &DnsPacket) -> usize {
+    opt_records(packet).count()
+}
+
+// Mark a response as BADVERS, for a query using a version of EDNS we don't speak
+pub fn set_bad_version(response: &mut DnsPacket) {
+    let mut edns = EdnsOptions::new(DEFAULT_PAYLOAD_SIZE);
+    edns.extended_rcode = BADVERS;
+    edns.attach(response);
+}
+
+// Give a response to `query` the OPT record it should have. That's none if the query had none,
+// and otherwise one of our own, whatever EDNS the answer came with: version 0, our payload size,
+// the query's DO flag and no other (RFC 8906 section 3.2.4), and of the answer's options only its
+// extended errors. Options we don't know are ignored rather than copied back (section 3.2.5).
+pub fn set_response_opt(query: &DnsPacket, response: &mut DnsPacket) {
+    let answered = EdnsOptions::from_packet(response);
+    remove_opt(response);
+    let asked = match EdnsOptions::from_packet(query) {
+        Some(asked) => asked,
+        None => return,
+    };
+    let mut edns = EdnsOptions::new(DEFAULT_PAYLOAD_SIZE);
+    edns.dnssec_ok = asked.dnssec_ok;
+    if let Some(answered) = answered {
+        // The upper bits of the rcode are still part of the answer
+        edns.extended_rcode = answered.extended_rcode;
+        edns.options = answered
+            .options
+            .into_iter()
+            .filter(|option| matches!(option, EdnsOption::ExtendedError(_)))
+            .collect();
+    }
+    edns.attach(response);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::dns::mock;
+    use crate::dns::protocol::DnsRCode;
+
+    #[test]
+    fn opt_records_round_trip() {
+        let mut packet = mock::query("example.com", DnsRRType::A);
+        assert_eq!(EdnsOptions::from_packet(&packet), None);
+        assert_eq!(udp_response_limit(&packet), 512);
+        let edns = EdnsOptions {
+            payload_size: 4096,
+            extended_rcode: 0xab,
+            version: 1,
+            dnssec_ok: true,
+            options: vec![EdnsOption::Nsid(Vec::new()), EdnsOption::Padding(3)],
+        };
+        edns.attach(&mut packet);
+        let parsed = DnsPacket::from_bytes(&packet.to_bytes()).unwrap();
+        assert_eq!(EdnsOptions::from_packet(&parsed), Some(edns));
+        assert_eq!(udp_response_limit(&parsed), 1232);
+        // The fields are packed into the record's TTL
+        assert_eq!(parsed.addl_recs[0].ttl, 0xab01_8000);
+        assert_eq!(
+            EdnsOptions::from_record(&mock::a("a.test", [192, 0, 2, 1].into())),
+            None
+        );
+
+        // A response only gets EDNS if its query had it, and then on our terms
+        let mut response = packet.to_owned();
+        response.flags.rcode = DnsRCode::NoError;
+        set_response_opt(&packet, &mut response);
+        let answered = EdnsOptions::from_packet(&response).unwrap();
+        assert_eq!(answered.payload_size, DEFAULT_PAYLOAD_SIZE);
+        assert_eq!((answered.version, answered.extended_rcode), (0, 0xab));
+        assert!(answered.dnssec_ok);
+        assert!(answered.options.is_empty());
+        set_bad_version(&mut response);
+        assert_eq!(opt_count(&response), 1);
+        assert_eq!(
+            EdnsOptions::from_packet(&response).unwrap().extended_rcode,
+            1
+        );
+        set_response_opt(&mock::query("example.com", DnsRRType::A), &mut response);
+        assert!(!supports_edns(&response));
+    }
+}
@@ -1,4 +1,11 @@
-use super::{bigendians, DnsFlags, DnsFormatError, DnsQuestion, DnsResourceRecord};
+use std::fmt;
+
+use super::names::{name_wire_len, names_equal};
+use super::writer::PacketWriter;
+use super::{bigendians, DnsFlags, DnsFormatError, DnsQuestion, DnsRRType, DnsResourceRecord};
+
+// The ID, flags, and four section counts
+const HEADER_SIZE: usize = 12;
 
 #[derive(Clone, PartialEq, Debug)]
 pub struct DnsPacket {
@@ -37,7 +44,6 @@ impl DnsPacket {
             )));
         }
 
-        // TODO(dylan): Error checking, e.g. DNS request too short
         // Read the first two bytes as a big-endian u16 containing transaction id
         id = bigendians::to_u16(&bytes[0..2]);
         // Next two bytes are flags
@@ -64,6 +70,7 @@ impl DnsPacket {
                     questions.push(question);
                 }
                 Err(mut form_err) => {
+                    form_err.set_offset(pos);
                     form_err.set_partial(DnsPacket {
                         id,
                         flags,
@@ -84,6 +91,7 @@ impl DnsPacket {
                     answers.push(rr);
                 }
                 Err(mut form_err) => {
+                    form_err.set_offset(pos);
                     form_err.set_partial(DnsPacket {
                         id,
                         flags,
@@ -104,6 +112,7 @@ impl DnsPacket {
                     nameservers.push(rr);
                 }
                 Err(mut form_err) => {
+                    form_err.set_offset(pos);
                     form_err.set_partial(DnsPacket {
                         id,
                         flags,
@@ -124,6 +133,7 @@ impl DnsPacket {
                     addl_recs.push(rr);
                 }
                 Err(mut form_err) => {
+                    form_err.set_offset(pos);
                     form_err.set_partial(DnsPacket {
                         id,
                         flags,
@@ -147,28 +157,255 @@ impl DnsPacket {
         })
     }
 
+    // The message as it goes out, with names compressed
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::<u8>::new();
-        bytes.extend_from_slice(&bigendians::from_u16(self.id));
-        bytes.extend_from_slice(&self.flags.to_bytes());
-        bytes.extend_from_slice(&bigendians::from_u16(self.questions.len() as u16));
-        bytes.extend_from_slice(&bigendians::from_u16(self.answers.len() as u16));
-        bytes.extend_from_slice(&bigendians::from_u16(self.nameservers.len() as u16));
-        bytes.extend_from_slice(&bigendians::from_u16(self.addl_recs.len() as u16));
+        self.to_bytes_within(usize::MAX)
+    }
 
+    // The message compressed to fit in `limit` bytes, as for a UDP client with a buffer that
+    // size. RRsets that don't fit are left out whole, and everything after them with them. If
+    // that's anything from the answer or authority sections the TC bit is set, so the client
+    // knows to ask again over TCP (RFC 2181 section 9); leaving out additional records is fine.
+    // OPT and TSIG records always go in, since they say how to read the rest (RFC 6891 section 7,
+    // RFC 8945 section 5.3), with the TSIG last.
+    pub fn to_bytes_within(&self, limit: usize) -> Vec<u8> {
+        let (required, additional): (Vec<&DnsResourceRecord>, Vec<&DnsResourceRecord>) = self
+            .addl_recs
+            .iter()
+            .partition(|rr| matches!(rr.rr_type, DnsRRType::OPT | DnsRRType::TSIG));
+        let room = limit.saturating_sub(
+            required
+                .iter()
+                .map(|rr| name_wire_len(&rr.name) + 10 + rr.record.wire_len())
+                .sum(),
+        );
+
+        let mut writer = PacketWriter::new();
+        writer.u16(self.id);
+        writer.bytes(&self.flags.to_bytes());
+        // The counts are filled in once we know how many records fit
+        let counts = writer.len();
+        writer.bytes(&[0; 8]);
         for question in &self.questions {
-            bytes.extend_from_slice(&question.to_bytes());
+            question.write(&mut writer);
+        }
+        let answers = write_rrsets(&mut writer, self.answers.iter(), room);
+        let nameservers = match answers == self.answers.len() {
+            true => write_rrsets(&mut writer, self.nameservers.iter(), room),
+            false => 0,
+        };
+        let truncated = answers < self.answers.len() || nameservers < self.nameservers.len();
+        let mut addl_recs = match truncated {
+            true => 0,
+            false => write_rrsets(&mut writer, additional.into_iter(), room),
+        };
+        for rr in required {
+            rr.write(&mut writer);
+            addl_recs += 1;
         }
-        for answer in &self.answers {
-            bytes.extend_from_slice(&answer.to_bytes());
+
+        for (i, count) in [self.questions.len(), answers, nameservers, addl_recs]
+            .iter()
+            .enumerate()
+        {
+            writer.set_u16(counts + 2 * i, *count as u16);
         }
-        for nameserver in &self.nameservers {
-            bytes.extend_from_slice(&nameserver.to_bytes());
+        if truncated {
+            let mut flags = self.flags.clone();
+            flags.tc_bit = true;
+            writer.set_u16(2, bigendians::to_u16(&flags.to_bytes()));
         }
-        for addl_rec in &self.addl_recs {
-            bytes.extend_from_slice(&addl_rec.to_bytes());
+        writer.into_bytes()
+    }
+
+    // The message's size with every name spelled out, worked out without building it, so what
+    // fits in a message can be decided before writing it
+    pub fn wire_size(&self) -> usize {
+        let questions: usize = self
+            .questions
+            .iter()
+            .map(|question| name_wire_len(&question.qname) + 4)
+            .sum();
+        let records: usize = self
+            .records()
+            .map(|rr| name_wire_len(&rr.name) + 10 + rr.record.wire_len())
+            .sum();
+        HEADER_SIZE + questions + records
+    }
+
+    // The size of `to_bytes()`, with names compressed (RFC 1035 section 4.1.4), for checking the
+    // writer against. `to_bytes_within` finds out what fits by writing it.
+    #[cfg(test)]
+    pub fn compressed_size(&self) -> usize {
+        let mut names = super::names::NameOffsets::new();
+        let mut size = HEADER_SIZE;
+        for question in &self.questions {
+            size += names.compressed_len(&question.qname, size) + 4;
         }
+        for rr in self.records() {
+            size += names.compressed_len(&rr.name, size) + 10;
+            size += rr.record.compressed_len(&mut names, size);
+        }
+        size
+    }
+
+    fn records(&self) -> impl Iterator<Item = &DnsResourceRecord> {
+        self.answers
+            .iter()
+            .chain(self.nameservers.iter())
+            .chain(self.addl_recs.iter())
+    }
+}
+
+// Write records for as long as whole RRsets fit under `limit`, returning how many were written
+fn write_rrsets<'a>(
+    writer: &mut PacketWriter,
+    records: impl Iterator<Item = &'a DnsResourceRecord>,
+    limit: usize,
+) -> usize {
+    let mut written = 0;
+    // Where the RRset being written started, and how many records came before it
+    let mut rrset_start = (writer.len(), 0);
+    let mut previous: Option<&DnsResourceRecord> = None;
+    for rr in records {
+        let same_rrset = previous.is_some_and(|previous| {
+            previous.rr_type == rr.rr_type
+                && previous.class == rr.class
+                && names_equal(&previous.name, &rr.name)
+        });
+        if !same_rrset {
+            rrset_start = (writer.len(), written);
+        }
+        rr.write(writer);
+        if writer.len() > limit {
+            writer.truncate(rrset_start.0);
+            return rrset_start.1;
+        }
+        written += 1;
+        previous = Some(rr);
+    }
+    written
+}
 
-        bytes
+// Presentation format for a whole packet, laid out like dig's output
+impl fmt::Display for DnsPacket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            ";; opcode: {:?}, status: {:?}, id: {}",
+            self.flags.opcode, self.flags.rcode, self.id
+        )?;
+        writeln!(
+            f,
+            ";; flags: {}; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+            self.flags,
+            self.questions.len(),
+            self.answers.len(),
+            self.nameservers.len(),
+            self.addl_recs.len()
+        )?;
+
+        if !self.questions.is_empty() {
+            writeln!(f, "\n;; QUESTION SECTION:")?;
+            for question in &self.questions {
+                writeln!(f, ";{}", question)?;
+            }
+        }
+        let sections = [
+            ("ANSWER", &self.answers),
+            ("AUTHORITY", &self.nameservers),
+            ("ADDITIONAL", &self.addl_recs),
+        ];
+        for (section, records) in sections.iter() {
+            if records.is_empty() {
+                continue;
+            }
+            writeln!(f, "\n;; {} SECTION:", section)?;
+            for record in records.iter() {
+                writeln!(f, "{}", record)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use crate::dns::mock;
+    use crate::dns::protocol::{opt_record, DnsPacket, DnsRRType};
+
+    #[test]
+    fn messages_are_sized_and_fit_to_limits() {
+        let mut packet = mock::query("www.example.com", DnsRRType::A);
+        packet.answers = vec![
+            mock::cname("www.example.com", "web.example.com"),
+            mock::a("web.example.com", Ipv4Addr::new(192, 0, 2, 1)),
+        ];
+        packet.nameservers = vec![mock::ns("example.com", "ns1.example.com")];
+        packet.addl_recs = vec![
+            mock::a("NS1.example.com", Ipv4Addr::new(192, 0, 2, 53)),
+            opt_record(1232),
+        ];
+        assert_eq!(packet.wire_size(), 190);
+        // Every owner name after the question's is a pointer, as is the example.com. ending the
+        // CNAME's and NS's targets
+        assert_eq!(packet.compressed_size(), 112);
+        assert_eq!(packet.to_bytes().len(), 112);
+        // A pointer spells a name the way it was first written, which names don't care about
+        let read = DnsPacket::from_bytes(&packet.to_bytes()).unwrap();
+        assert_eq!(read.addl_recs[0].name, mock::labels("ns1.example.com"));
+        assert_eq!(read.answers, packet.answers);
+
+        // Leaving out additional records doesn't need the TC bit, but the OPT record stays
+        let fitted = DnsPacket::from_bytes(&packet.to_bytes_within(100)).unwrap();
+        assert_eq!(fitted.addl_recs, vec![opt_record(1232)]);
+        assert_eq!(fitted.nameservers, packet.nameservers);
+        assert!(!fitted.flags.tc_bit);
+
+        // Leaving out the authority section does, and there's no room for additional records after
+        let bytes = packet.to_bytes_within(80);
+        assert_eq!(bytes.len(), 78);
+        let fitted = DnsPacket::from_bytes(&bytes).unwrap();
+        assert_eq!(fitted.answers, packet.answers);
+        assert!(fitted.nameservers.is_empty());
+        assert_eq!(fitted.addl_recs, vec![opt_record(1232)]);
+        assert!(fitted.flags.tc_bit);
+    }
+
+    #[test]
+    fn cut_short_or_garbled_packets_are_errors() {
+        let mut packet = mock::query("www.ex.test", DnsRRType::A);
+        packet.answers = vec![
+            mock::cname("www.ex.test", "web.ex.test"),
+            mock::a("web.ex.test", Ipv4Addr::new(192, 0, 2, 1)),
+            mock::aaaa("web.ex.test", Ipv6Addr::LOCALHOST),
+            mock::mx("ex.test", 10, "mail.ex.test"),
+            mock::txt("ex.test", &["v=spf1", "-all"]),
+            mock::srv("_sip._udp.ex.test", 1, 5060, "sip.ex.test"),
+        ];
+        packet.nameservers = vec![mock::ns("ex.test", "ns.ex.test")];
+        packet.addl_recs = vec![opt_record(1232)];
+        let bytes = packet.to_bytes();
+        assert_eq!(DnsPacket::from_bytes(&bytes).unwrap(), packet);
+
+        // Every record is counted in the header, so a packet cut off anywhere is missing something
+        for end in 0..bytes.len() {
+            assert!(
+                DnsPacket::from_bytes(&bytes[..end]).is_err(),
+                "cut at {}",
+                end
+            );
+        }
+
+        // Any byte anywhere either parses or doesn't, including ones that make pointers loop
+        for pos in 0..bytes.len() {
+            for byte in 0..=u8::MAX {
+                let mut garbled = bytes.to_owned();
+                garbled[pos] = byte;
+                let _ = DnsPacket::from_bytes(&garbled);
+            }
+        }
     }
 }
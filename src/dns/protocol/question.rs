@@ -1,3 +1,6 @@
+use std::fmt;
+
+use super::writer::PacketWriter;
 use super::{bigendians, names, DnsClass, DnsFormatError, DnsRRType};
 
 #[derive(Clone, PartialEq, Debug)]
@@ -25,9 +28,10 @@ impl DnsQuestion {
     ) -> Result<(DnsQuestion, usize), DnsFormatError> {
         let (qname, new_pos) = names::deserialize_name(&packet_bytes, pos)?;
         if new_pos + 4 > packet_bytes.len() {
-            return Err(DnsFormatError::make_error(format!(
-                "End of packet parsing question"
-            )));
+            return Err(DnsFormatError::make_error_at(
+                String::from("End of packet parsing question"),
+                new_pos,
+            ));
         }
         let qtype_num = bigendians::to_u16(&packet_bytes[new_pos..new_pos + 2]);
         let qclass_num = bigendians::to_u16(&packet_bytes[new_pos + 2..new_pos + 4]);
@@ -58,13 +62,21 @@ impl DnsQuestion {
         Ok((question, pos))
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::new();
-
-        bytes.append(&mut names::serialize_name(&self.qname));
-        bytes.extend_from_slice(&bigendians::from_u16(self.qtype.to_owned() as u16));
-        bytes.extend_from_slice(&bigendians::from_u16(self.qclass.to_u16()));
+    pub fn write(&self, writer: &mut PacketWriter) {
+        writer.name(&self.qname);
+        writer.u16(self.qtype as u16);
+        writer.u16(self.qclass.to_u16());
+    }
+}
 
-        bytes
+impl fmt::Display for DnsQuestion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}",
+            names::name_to_string(&self.qname),
+            self.qclass,
+            self.qtype
+        )
     }
 }
@@ -1,6 +1,9 @@
+use std::fmt;
 use std::net::{Ipv4Addr, Ipv6Addr};
 
-use super::{bigendians, names, DnsFormatError, DnsRRType};
+use super::names::NameOffsets;
+use super::writer::PacketWriter;
+use super::{bigendians, names, DnsFormatError, DnsRRType, EdnsOption};
 
 #[derive(Clone, PartialEq, Debug)]
 pub enum DnsRecordData {
@@ -8,9 +11,89 @@ pub enum DnsRecordData {
     NS(Vec<String>),
     AAAA(Ipv6Addr),
     CNAME(Vec<String>),
+    // Pointer to another name, mostly used for reverse lookups
+    PTR(Vec<String>),
+    // Mail exchange: a preference (lower is preferred) and the name of the mail server
+    MX(u16, Vec<String>),
+    // One or more character-strings. These are arbitrary bytes, not necessarily text.
+    TXT(Vec<Vec<u8>>),
+    // Host information: two character-strings for the CPU and operating system. Rarely used for
+    // that any more, but it's what RFC 8482 has servers answer ANY queries with.
+    HINFO(Vec<u8>, Vec<u8>),
+    // Start of authority: the zone's primary nameserver and contact, plus its timers
+    SOA(SoaData),
+    // Where a service is (RFC 2782)
+    SRV(SrvData),
+    // Responsible person (RFC 1183): a mailbox written as a name, like SOA's rname, and a name
+    // with TXT records saying more about them. Either can be the root for "none".
+    RP(Vec<String>, Vec<String>),
+    // The EDNS options in an OPT pseudo-record
+    OPT(Vec<EdnsOption>),
+    // Where something is on the globe (version 0 only; other versions are kept as Other)
+    LOC(LocData),
+    // A service's URI (RFC 7553): a priority and weight, chosen between as with SRV, and the URI
+    // itself, which unlike TXT data is the rest of the record rather than a character-string
+    URI(u16, u16, Vec<u8>),
+    // Hardware addresses (RFC 7043), mostly seen in ISP and IoT deployments
+    EUI48([u8; 6]),
+    EUI64([u8; 8]),
     Other(Vec<u8>),
 }
 
+#[derive(Clone, PartialEq, Debug)]
+pub struct SoaData {
+    // The primary nameserver for the zone
+    pub mname: Vec<String>,
+    // The zone contact's mailbox, with the @ replaced by a dot (hostmaster.example.com.)
+    pub rname: Vec<String>,
+    pub serial: u32,
+    // Timers for secondaries, in seconds
+    pub refresh: u32,
+    pub retry: u32,
+    pub expire: u32,
+    // The TTL for negative answers (RFC 2308)
+    pub minimum: u32,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct SrvData {
+    // Lower priorities are tried first; among equal ones, servers are picked in proportion to
+    // their weights
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    // The host providing the service, or the root if the service isn't offered here
+    pub target: Vec<String>,
+}
+
+// A LOC record (RFC 1876), with its fields as they are on the wire, so it round trips exactly
+#[derive(Clone, PartialEq, Debug)]
+pub struct LocData {
+    // The diameter of a sphere enclosing the thing, and how precisely its position is known
+    // horizontally and vertically, in centimeters. Each is a byte holding a mantissa in the high
+    // nibble and a power of ten in the low one, so 0x13 is 1 * 10^3cm (10m).
+    pub size: u8,
+    pub horiz_pre: u8,
+    pub vert_pre: u8,
+    // Thousandths of an arc second, offset so that 2^31 is the equator or the prime meridian and
+    // bigger values are north or east of it
+    pub latitude: u32,
+    pub longitude: u32,
+    // Centimeters above a point 100,000m below the WGS 84 reference spheroid
+    pub altitude: u32,
+}
+
+// Where the equator and prime meridian are, and the altitude of the reference spheroid
+const LOC_ORIGIN: i64 = 1 << 31;
+const LOC_ALTITUDE_BASE: i64 = 10_000_000;
+
+impl LocData {
+    // A size or precision byte in centimeters
+    pub fn centimeters(encoded: u8) -> u64 {
+        u64::from(encoded >> 4) * 10u64.pow(u32::from(encoded & 0x0f))
+    }
+}
+
 impl DnsRecordData {
     pub fn from_bytes(
         packet_bytes: &[u8],
@@ -18,46 +101,707 @@ impl DnsRecordData {
         rr_type: &DnsRRType,
         rd_length: u16,
     ) -> Result<(DnsRecordData, usize), DnsFormatError> {
-        let record_bytes = packet_bytes[pos..pos + (rd_length as usize)].to_vec();
+        let end = pos + (rd_length as usize);
+        if end > packet_bytes.len() {
+            return Err(DnsFormatError::make_error_at(
+                format!(
+                    "Record data length {} runs past end of packet ({} bytes left)",
+                    rd_length,
+                    packet_bytes.len().saturating_sub(pos)
+                ),
+                pos,
+            ));
+        }
+        let record_bytes = &packet_bytes[pos..end];
         let record = match rr_type {
-            DnsRRType::A => DnsRecordData::A(Ipv4Addr::new(
-                record_bytes[0],
-                record_bytes[1],
-                record_bytes[2],
-                record_bytes[3],
-            )),
-            DnsRRType::AAAA => DnsRecordData::AAAA(Ipv6Addr::new(
-                bigendians::to_u16(&record_bytes[0..2]),
-                bigendians::to_u16(&record_bytes[2..4]),
-                bigendians::to_u16(&record_bytes[4..6]),
-                bigendians::to_u16(&record_bytes[6..8]),
-                bigendians::to_u16(&record_bytes[8..10]),
-                bigendians::to_u16(&record_bytes[10..12]),
-                bigendians::to_u16(&record_bytes[12..14]),
-                bigendians::to_u16(&record_bytes[14..16]),
-            )),
-            DnsRRType::NS => {
-                let (name, _) = names::deserialize_name(&packet_bytes, pos)?;
-                DnsRecordData::NS(name)
-            }
-            DnsRRType::CNAME => {
-                let (name, _) = names::deserialize_name(&packet_bytes, pos)?;
-                DnsRecordData::CNAME(name)
-            }
-            _ => DnsRecordData::Other(record_bytes),
+            DnsRRType::A => {
+                check_length(rr_type, record_bytes, 4, pos)?;
+                DnsRecordData::A(Ipv4Addr::new(
+                    record_bytes[0],
+                    record_bytes[1],
+                    record_bytes[2],
+                    record_bytes[3],
+                ))
+            }
+            DnsRRType::AAAA => {
+                check_length(rr_type, record_bytes, 16, pos)?;
+                DnsRecordData::AAAA(Ipv6Addr::new(
+                    bigendians::to_u16(&record_bytes[0..2]),
+                    bigendians::to_u16(&record_bytes[2..4]),
+                    bigendians::to_u16(&record_bytes[4..6]),
+                    bigendians::to_u16(&record_bytes[6..8]),
+                    bigendians::to_u16(&record_bytes[8..10]),
+                    bigendians::to_u16(&record_bytes[10..12]),
+                    bigendians::to_u16(&record_bytes[12..14]),
+                    bigendians::to_u16(&record_bytes[14..16]),
+                ))
+            }
+            DnsRRType::NS => DnsRecordData::NS(read_name(packet_bytes, pos, end)?),
+            DnsRRType::CNAME => DnsRecordData::CNAME(read_name(packet_bytes, pos, end)?),
+            DnsRRType::PTR => DnsRecordData::PTR(read_name(packet_bytes, pos, end)?),
+            DnsRRType::MX => {
+                if record_bytes.len() < 2 {
+                    return Err(DnsFormatError::make_error_at(
+                        String::from("MX record too short for preference"),
+                        pos,
+                    ));
+                }
+                let preference = bigendians::to_u16(&record_bytes[0..2]);
+                DnsRecordData::MX(preference, read_name(packet_bytes, pos + 2, end)?)
+            }
+            DnsRRType::TXT => DnsRecordData::TXT(read_character_strings(record_bytes, pos)?),
+            DnsRRType::HINFO => match read_character_strings(record_bytes, pos)?.as_slice() {
+                [cpu, os] => DnsRecordData::HINFO(cpu.to_owned(), os.to_owned()),
+                strings => {
+                    return Err(DnsFormatError::make_error_at(
+                        format!("HINFO record has {} strings, expected 2", strings.len()),
+                        pos,
+                    ))
+                }
+            },
+            DnsRRType::SOA => {
+                let (mname, rname_pos) = names::deserialize_name(&packet_bytes[..end], pos)?;
+                let (rname, timers_pos) = names::deserialize_name(&packet_bytes[..end], rname_pos)?;
+                check_length(rr_type, &packet_bytes[timers_pos..end], 20, timers_pos)?;
+                let timer = |i: usize| bigendians::to_u32(&packet_bytes[timers_pos + 4 * i..]);
+                DnsRecordData::SOA(SoaData {
+                    mname,
+                    rname,
+                    serial: timer(0),
+                    refresh: timer(1),
+                    retry: timer(2),
+                    expire: timer(3),
+                    minimum: timer(4),
+                })
+            }
+            DnsRRType::SRV => {
+                if record_bytes.len() < 6 {
+                    return Err(DnsFormatError::make_error_at(
+                        String::from("SRV record too short for priority, weight, and port"),
+                        pos,
+                    ));
+                }
+                DnsRecordData::SRV(SrvData {
+                    priority: bigendians::to_u16(&record_bytes[0..2]),
+                    weight: bigendians::to_u16(&record_bytes[2..4]),
+                    port: bigendians::to_u16(&record_bytes[4..6]),
+                    target: read_name(packet_bytes, pos + 6, end)?,
+                })
+            }
+            DnsRRType::RP => {
+                let (mbox, txt_pos) = names::deserialize_name(&packet_bytes[..end], pos)?;
+                DnsRecordData::RP(mbox, read_name(packet_bytes, txt_pos, end)?)
+            }
+            DnsRRType::OPT => DnsRecordData::OPT(EdnsOption::parse_all(record_bytes, pos)?),
+            // Other versions have a different layout that isn't defined yet (RFC 1876 section 2)
+            DnsRRType::LOC if record_bytes.first() == Some(&0) => {
+                check_length(rr_type, record_bytes, 16, pos)?;
+                DnsRecordData::LOC(LocData {
+                    size: record_bytes[1],
+                    horiz_pre: record_bytes[2],
+                    vert_pre: record_bytes[3],
+                    latitude: bigendians::to_u32(&record_bytes[4..8]),
+                    longitude: bigendians::to_u32(&record_bytes[8..12]),
+                    altitude: bigendians::to_u32(&record_bytes[12..16]),
+                })
+            }
+            DnsRRType::URI => {
+                if record_bytes.len() < 5 {
+                    return Err(DnsFormatError::make_error_at(
+                        String::from("URI record too short for priority, weight, and target"),
+                        pos,
+                    ));
+                }
+                DnsRecordData::URI(
+                    bigendians::to_u16(&record_bytes[0..2]),
+                    bigendians::to_u16(&record_bytes[2..4]),
+                    record_bytes[4..].to_vec(),
+                )
+            }
+            DnsRRType::EUI4 => {
+                check_length(rr_type, record_bytes, 6, pos)?;
+                let mut address = [0; 6];
+                address.copy_from_slice(record_bytes);
+                DnsRecordData::EUI48(address)
+            }
+            DnsRRType::EUI64 => {
+                check_length(rr_type, record_bytes, 8, pos)?;
+                let mut address = [0; 8];
+                address.copy_from_slice(record_bytes);
+                DnsRecordData::EUI64(address)
+            }
+            _ => DnsRecordData::Other(record_bytes.to_vec()),
         };
-        pos += rd_length as usize;
+        pos = end;
 
         Ok((record, pos))
     }
 
+    // The record data with every name spelled out, as for canonical forms
     pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = PacketWriter::uncompressed();
+        self.write(&mut writer);
+        writer.into_bytes()
+    }
+
+    // Only the names in the types from RFC 1035 are compressed; anyone reading a newer type might
+    // not know where its names are, so pointers in it couldn't be followed (RFC 3597 section 4)
+    pub fn write(&self, writer: &mut PacketWriter) {
+        match &self {
+            DnsRecordData::A(ipv4) => writer.bytes(&ipv4.octets()),
+            DnsRecordData::AAAA(ipv6) => writer.bytes(&ipv6.octets()),
+            DnsRecordData::NS(labels) => writer.name(labels),
+            DnsRecordData::CNAME(labels) => writer.name(labels),
+            DnsRecordData::PTR(labels) => writer.name(labels),
+            DnsRecordData::MX(preference, labels) => {
+                writer.u16(*preference);
+                writer.name(labels);
+            }
+            DnsRecordData::TXT(strings) => write_character_strings(writer, strings),
+            DnsRecordData::HINFO(cpu, os) => write_character_strings(writer, &[cpu, os]),
+            DnsRecordData::SOA(soa) => {
+                writer.name(&soa.mname);
+                writer.name(&soa.rname);
+                for timer in &[soa.serial, soa.refresh, soa.retry, soa.expire, soa.minimum] {
+                    writer.u32(*timer);
+                }
+            }
+            // RFC 2782 forbids compressing the target, though some servers do anyway
+            DnsRecordData::SRV(srv) => {
+                writer.u16(srv.priority);
+                writer.u16(srv.weight);
+                writer.u16(srv.port);
+                writer.name_uncompressed(&srv.target);
+            }
+            DnsRecordData::RP(mbox, txt) => {
+                writer.name_uncompressed(mbox);
+                writer.name_uncompressed(txt);
+            }
+            DnsRecordData::OPT(options) => {
+                for option in options {
+                    writer.bytes(&option.to_bytes());
+                }
+            }
+            DnsRecordData::LOC(loc) => {
+                writer.bytes(&[0, loc.size, loc.horiz_pre, loc.vert_pre]);
+                for field in &[loc.latitude, loc.longitude, loc.altitude] {
+                    writer.u32(*field);
+                }
+            }
+            DnsRecordData::URI(priority, weight, target) => {
+                writer.u16(*priority);
+                writer.u16(*weight);
+                writer.bytes(target);
+            }
+            DnsRecordData::EUI48(address) => writer.bytes(address),
+            DnsRecordData::EUI64(address) => writer.bytes(address),
+            DnsRecordData::Other(record_bytes) => writer.bytes(record_bytes),
+        }
+    }
+}
+
+impl DnsRecordData {
+    // The length of `to_bytes()`, without building it
+    pub fn wire_len(&self) -> usize {
+        match &self {
+            DnsRecordData::A(_) => 4,
+            DnsRecordData::AAAA(_) => 16,
+            DnsRecordData::NS(labels)
+            | DnsRecordData::CNAME(labels)
+            | DnsRecordData::PTR(labels) => names::name_wire_len(labels),
+            DnsRecordData::MX(_, labels) => 2 + names::name_wire_len(labels),
+            DnsRecordData::TXT(strings) => strings.iter().map(|s| character_string_len(s)).sum(),
+            DnsRecordData::HINFO(cpu, os) => character_string_len(cpu) + character_string_len(os),
+            DnsRecordData::SOA(soa) => {
+                names::name_wire_len(&soa.mname) + names::name_wire_len(&soa.rname) + 20
+            }
+            DnsRecordData::SRV(srv) => 6 + names::name_wire_len(&srv.target),
+            DnsRecordData::RP(mbox, txt) => names::name_wire_len(mbox) + names::name_wire_len(txt),
+            DnsRecordData::OPT(options) => EdnsOption::all_to_bytes(options).len(),
+            DnsRecordData::LOC(_) => 16,
+            DnsRecordData::URI(_, _, target) => 4 + target.len(),
+            DnsRecordData::EUI48(_) => 6,
+            DnsRecordData::EUI64(_) => 8,
+            DnsRecordData::Other(record_bytes) => record_bytes.len(),
+        }
+    }
+
+    // The length `write` takes at `pos` in a message that compresses names
+    pub fn compressed_len(&self, names: &mut NameOffsets, pos: usize) -> usize {
+        match &self {
+            DnsRecordData::NS(labels)
+            | DnsRecordData::CNAME(labels)
+            | DnsRecordData::PTR(labels) => names.compressed_len(labels, pos),
+            DnsRecordData::MX(_, labels) => 2 + names.compressed_len(labels, pos + 2),
+            DnsRecordData::SOA(soa) => {
+                let mname = names.compressed_len(&soa.mname, pos);
+                mname + names.compressed_len(&soa.rname, pos + mname) + 20
+            }
+            _ => self.wire_len(),
+        }
+    }
+}
+
+// Fixed size records have to be exactly their size; a short A record can't be read, and a long
+// one means something is wrong with the packet.
+fn check_length(
+    rr_type: &DnsRRType,
+    record_bytes: &[u8],
+    expected: usize,
+    pos: usize,
+) -> Result<(), DnsFormatError> {
+    if record_bytes.len() != expected {
+        return Err(DnsFormatError::make_error_at(
+            format!(
+                "{:?} record has length {}, expected {}",
+                rr_type,
+                record_bytes.len(),
+                expected
+            ),
+            pos,
+        ));
+    }
+    Ok(())
+}
+
+// Names inside record data can still point elsewhere in the packet, which is why this takes the
+// whole packet, but the name itself has to fill the record data exactly.
+fn read_name(packet_bytes: &[u8], pos: usize, end: usize) -> Result<Vec<String>, DnsFormatError> {
+    // Don't let the name parser wander past the end of the record; a pointer can still jump
+    // anywhere earlier in the packet.
+    let (name, name_end) = names::deserialize_name(&packet_bytes[..end], pos)?;
+    if name_end != end {
+        return Err(DnsFormatError::make_error_at(
+            format!(
+                "Name in record data ends at {} but record data ends at {}",
+                name_end, end
+            ),
+            pos,
+        ));
+    }
+    Ok(name)
+}
+
+// TXT rdata is a series of character-strings, each a length byte followed by that many bytes
+fn read_character_strings(
+    record_bytes: &[u8],
+    start: usize,
+) -> Result<Vec<Vec<u8>>, DnsFormatError> {
+    let mut strings = Vec::new();
+    let mut pos = 0;
+    while pos < record_bytes.len() {
+        let length = record_bytes[pos] as usize;
+        if pos + 1 + length > record_bytes.len() {
+            return Err(DnsFormatError::make_error_at(
+                format!(
+                    "Character-string of length {} runs past end of record data",
+                    length
+                ),
+                start + pos,
+            ));
+        }
+        strings.push(record_bytes[pos + 1..pos + 1 + length].to_vec());
+        pos += 1 + length;
+    }
+    Ok(strings)
+}
+
+fn write_character_strings<S: AsRef<[u8]>>(writer: &mut PacketWriter, strings: &[S]) {
+    for string in strings {
+        let string = string.as_ref();
+        if string.is_empty() {
+            writer.u8(0);
+        }
+        // Character-strings are limited to 255 bytes by their one byte length prefix, so longer
+        // ones go out as several consecutive strings the way zone files split long TXT records
+        for chunk in string.chunks(255) {
+            writer.u8(chunk.len() as u8);
+            writer.bytes(chunk);
+        }
+    }
+}
+
+// Wire length of what write_character_strings produces for a single string
+fn character_string_len(string: &[u8]) -> usize {
+    string.len() + std::cmp::max(1, string.len().div_ceil(255))
+}
+
+// Presentation format for a character-string: quoted, with quotes and backslashes escaped and
+// anything unprintable written as \DDD
+fn quote_character_string(bytes: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for byte in bytes {
+        match byte {
+            b'"' | b'\\' => {
+                out.push('\\');
+                out.push(*byte as char);
+            }
+            0x20..=0x7e => out.push(*byte as char),
+            _ => out.push_str(&format!("\\{:03}", byte)),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// Degrees, minutes, and seconds to the thousandth, then the hemisphere, as in RFC 1876 section 3
+fn write_coordinate(
+    f: &mut fmt::Formatter,
+    encoded: u32,
+    positive: char,
+    negative: char,
+) -> fmt::Result {
+    let offset = encoded as i64 - LOC_ORIGIN;
+    let hemisphere = if offset < 0 { negative } else { positive };
+    let thousandths = offset.abs();
+    write!(
+        f,
+        "{} {} {}.{:03} {}",
+        thousandths / 3_600_000,
+        thousandths / 60_000 % 60,
+        thousandths / 1000 % 60,
+        thousandths % 1000,
+        hemisphere
+    )
+}
+
+// Meters, with centimeters only when there are any
+fn write_meters(f: &mut fmt::Formatter, cm: i64) -> fmt::Result {
+    let sign = if cm < 0 { "-" } else { "" };
+    match cm.abs() % 100 {
+        0 => write!(f, "{}{}m", sign, cm.abs() / 100),
+        rest => write!(f, "{}{}.{:02}m", sign, cm.abs() / 100, rest),
+    }
+}
+
+// Colon separated, the way hardware addresses are usually written, rather than RFC 7043's hyphens
+fn write_hardware_address(f: &mut fmt::Formatter, address: &[u8]) -> fmt::Result {
+    let octets: Vec<String> = address
+        .iter()
+        .map(|octet| format!("{:02x}", octet))
+        .collect();
+    write!(f, "{}", octets.join(":"))
+}
+
+impl fmt::Display for DnsRecordData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match &self {
-            DnsRecordData::A(ipv4) => ipv4.octets().to_vec(),
-            DnsRecordData::AAAA(ipv6) => ipv6.octets().to_vec(),
-            DnsRecordData::NS(labels) => names::serialize_name(&labels),
-            DnsRecordData::CNAME(labels) => names::serialize_name(&labels),
-            DnsRecordData::Other(record_bytes) => record_bytes.to_vec(),
+            DnsRecordData::A(ipv4) => write!(f, "{}", ipv4),
+            DnsRecordData::AAAA(ipv6) => write!(f, "{}", ipv6),
+            DnsRecordData::NS(labels) => write!(f, "{}", names::name_to_string(labels)),
+            DnsRecordData::CNAME(labels) => write!(f, "{}", names::name_to_string(labels)),
+            DnsRecordData::PTR(labels) => write!(f, "{}", names::name_to_string(labels)),
+            DnsRecordData::MX(preference, labels) => {
+                write!(f, "{} {}", preference, names::name_to_string(labels))
+            }
+            DnsRecordData::TXT(strings) => {
+                let quoted: Vec<String> =
+                    strings.iter().map(|s| quote_character_string(s)).collect();
+                write!(f, "{}", quoted.join(" "))
+            }
+            DnsRecordData::HINFO(cpu, os) => write!(
+                f,
+                "{} {}",
+                quote_character_string(cpu),
+                quote_character_string(os)
+            ),
+            DnsRecordData::SOA(soa) => write!(
+                f,
+                "{} {} {} {} {} {} {}",
+                names::name_to_string(&soa.mname),
+                names::name_to_string(&soa.rname),
+                soa.serial,
+                soa.refresh,
+                soa.retry,
+                soa.expire,
+                soa.minimum
+            ),
+            DnsRecordData::SRV(srv) => write!(
+                f,
+                "{} {} {} {}",
+                srv.priority,
+                srv.weight,
+                srv.port,
+                names::name_to_string(&srv.target)
+            ),
+            DnsRecordData::RP(mbox, txt) => write!(
+                f,
+                "{} {}",
+                names::name_to_string(mbox),
+                names::name_to_string(txt)
+            ),
+            DnsRecordData::OPT(options) => {
+                let options: Vec<String> = options.iter().map(|o| o.to_string()).collect();
+                write!(f, "{}", options.join("; "))
+            }
+            DnsRecordData::LOC(loc) => {
+                write_coordinate(f, loc.latitude, 'N', 'S')?;
+                write!(f, " ")?;
+                write_coordinate(f, loc.longitude, 'E', 'W')?;
+                for cm in [
+                    loc.altitude as i64 - LOC_ALTITUDE_BASE,
+                    LocData::centimeters(loc.size) as i64,
+                    LocData::centimeters(loc.horiz_pre) as i64,
+                    LocData::centimeters(loc.vert_pre) as i64,
+                ] {
+                    write!(f, " ")?;
+                    write_meters(f, cm)?;
+                }
+                Ok(())
+            }
+            DnsRecordData::URI(priority, weight, target) => write!(
+                f,
+                "{} {} {}",
+                priority,
+                weight,
+                quote_character_string(target)
+            ),
+            DnsRecordData::EUI48(address) => write_hardware_address(f, address),
+            DnsRecordData::EUI64(address) => write_hardware_address(f, address),
+            // RFC 3597 generic rdata: \# followed by the length and the data in hex
+            DnsRecordData::Other(record_bytes) => {
+                write!(f, "\\# {}", record_bytes.len())?;
+                if !record_bytes.is_empty() {
+                    write!(f, " ")?;
+                    for byte in record_bytes {
+                        write!(f, "{:02x}", byte)?;
+                    }
+                }
+                Ok(())
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::dns::protocol::rdata::*;
+
+    #[test]
+    fn rdata_length_is_checked() {
+        // Two bytes of junk followed by an A record
+        let packet = [0xffu8, 0xff, 192, 0, 2, 1];
+        let (record, pos) =
+            DnsRecordData::from_bytes(&packet, 2, &DnsRRType::A, 4).expect("should parse");
+        assert_eq!(record, DnsRecordData::A(Ipv4Addr::new(192, 0, 2, 1)));
+        assert_eq!(pos, 6);
+
+        // Record data running off the end of the packet
+        assert!(DnsRecordData::from_bytes(&packet, 2, &DnsRRType::A, 5).is_err());
+        assert!(DnsRecordData::from_bytes(&packet, 2, &DnsRRType::TXT, 5).is_err());
+        // Records of the wrong size for their type
+        assert!(DnsRecordData::from_bytes(&packet, 2, &DnsRRType::A, 3).is_err());
+        assert!(DnsRecordData::from_bytes(&packet, 2, &DnsRRType::AAAA, 4).is_err());
+    }
+
+    #[test]
+    fn names_must_fit_record_data() {
+        let packet = b"\x02ns\x07example\x00";
+        let (record, _) =
+            DnsRecordData::from_bytes(packet, 0, &DnsRRType::NS, 12).expect("should parse");
+        assert_eq!(
+            record,
+            DnsRecordData::NS(vec!["ns".to_owned(), "example".to_owned()])
+        );
+
+        // The name is fine, but runs past the end of the record data
+        assert!(DnsRecordData::from_bytes(packet, 0, &DnsRRType::NS, 10).is_err());
+        // Or doesn't fill it
+        let packet = b"\x02ns\x07example\x00\x00";
+        assert!(DnsRecordData::from_bytes(packet, 0, &DnsRRType::CNAME, 13).is_err());
+    }
+
+    #[test]
+    fn character_string_records_round_trip() {
+        // MX record whose exchange is a pointer back to a name earlier in the packet
+        let packet = b"\x07example\x00\x00\x0a\x04mail\xc0\x00";
+        let (record, pos) =
+            DnsRecordData::from_bytes(packet, 9, &DnsRRType::MX, 9).expect("should parse");
+        assert_eq!(
+            record,
+            DnsRecordData::MX(10, vec!["mail".to_owned(), "example".to_owned()])
+        );
+        assert_eq!(pos, 18);
+        assert_eq!(
+            record.to_bytes(),
+            b"\x00\x0a\x04mail\x07example\x00".to_vec()
+        );
+        assert_eq!(format!("{}", record), "10 mail.example.");
+
+        let packet = b"\x05v=spf\x00\x03a\"b";
+        let (record, _) =
+            DnsRecordData::from_bytes(packet, 0, &DnsRRType::TXT, 11).expect("should parse");
+        assert_eq!(
+            record,
+            DnsRecordData::TXT(vec![b"v=spf".to_vec(), vec![], b"a\"b".to_vec()])
+        );
+        assert_eq!(record.to_bytes(), packet.to_vec());
+        assert_eq!(format!("{}", record), "\"v=spf\" \"\" \"a\\\"b\"");
+
+        // Strings too long for one length byte are split rather than having their length wrap
+        let record = DnsRecordData::TXT(vec![vec![b'a'; 300]]);
+        let bytes = record.to_bytes();
+        assert_eq!(bytes.len(), record.wire_len());
+        let (parsed, _) = DnsRecordData::from_bytes(&bytes, 0, &DnsRRType::TXT, bytes.len() as u16)
+            .expect("should parse");
+        assert_eq!(
+            parsed,
+            DnsRecordData::TXT(vec![vec![b'a'; 255], vec![b'a'; 45]])
+        );
+
+        // A character-string claiming more bytes than the record has
+        assert!(DnsRecordData::from_bytes(packet, 0, &DnsRRType::TXT, 10).is_err());
+
+        // RFC 8482's answer to ANY queries
+        let packet = b"\x07RFC8482\x00";
+        let (record, _) =
+            DnsRecordData::from_bytes(packet, 0, &DnsRRType::HINFO, 9).expect("should parse");
+        assert_eq!(record, DnsRecordData::HINFO(b"RFC8482".to_vec(), vec![]));
+        assert_eq!(record.to_bytes(), packet.to_vec());
+        assert_eq!(format!("{}", record), "\"RFC8482\" \"\"");
+        // It takes exactly two strings
+        assert!(DnsRecordData::from_bytes(packet, 0, &DnsRRType::HINFO, 8).is_err());
+        assert!(DnsRecordData::from_bytes(b"\x00\x00\x00", 0, &DnsRRType::HINFO, 3).is_err());
+
+        let packet = b"\x00\x0a\x00\x01ftp://ftp1.example.com/public";
+        let (record, _) =
+            DnsRecordData::from_bytes(packet, 0, &DnsRRType::URI, 33).expect("should parse");
+        assert_eq!(
+            record,
+            DnsRecordData::URI(10, 1, b"ftp://ftp1.example.com/public".to_vec())
+        );
+        assert_eq!(record.to_bytes(), packet.to_vec());
+        assert_eq!(
+            format!("{}", record),
+            "10 1 \"ftp://ftp1.example.com/public\""
+        );
+        // The target can't be empty
+        assert!(DnsRecordData::from_bytes(packet, 0, &DnsRRType::URI, 4).is_err());
+    }
+
+    #[test]
+    fn soa_and_rp_round_trip() {
+        // mname is a pointer back to the start of the packet
+        let mut packet = b"\x07example\x00\xc0\x00\x0ahostmaster\xc0\x00".to_vec();
+        for timer in &[2021010101u32, 7200, 3600, 1209600, 300] {
+            packet.extend_from_slice(&timer.to_be_bytes());
+        }
+        let (record, pos) =
+            DnsRecordData::from_bytes(&packet, 9, &DnsRRType::SOA, 35).expect("should parse");
+        assert_eq!(pos, packet.len());
+        assert_eq!(
+            format!("{}", record),
+            "example. hostmaster.example. 2021010101 7200 3600 1209600 300"
+        );
+        let (reparsed, _) = DnsRecordData::from_bytes(
+            &record.to_bytes(),
+            0,
+            &DnsRRType::SOA,
+            record.to_bytes().len() as u16,
+        )
+        .expect("should parse");
+        assert_eq!(reparsed, record);
+
+        // Missing the last timer
+        assert!(DnsRecordData::from_bytes(&packet, 9, &DnsRRType::SOA, 31).is_err());
+
+        // SRV targets are compressed by some servers, but never by us
+        let packet = b"\x07example\x00\x00\x0a\x00\x05\x14\x95\x03sip\xc0\x00";
+        let (record, _) =
+            DnsRecordData::from_bytes(packet, 9, &DnsRRType::SRV, 12).expect("should parse");
+        assert_eq!(format!("{}", record), "10 5 5269 sip.example.");
+        assert_eq!(
+            record.to_bytes(),
+            b"\x00\x0a\x00\x05\x14\x95\x03sip\x07example\x00".to_vec()
+        );
+        assert!(DnsRecordData::from_bytes(packet, 9, &DnsRRType::SRV, 5).is_err());
+
+        // An RP record pointing at the same names, with no TXT records about the person
+        let packet = b"\x07example\x00\x0ahostmaster\xc0\x00\x00";
+        let (record, _) =
+            DnsRecordData::from_bytes(packet, 9, &DnsRRType::RP, 14).expect("should parse");
+        assert_eq!(
+            record,
+            DnsRecordData::RP(vec!["hostmaster".to_owned(), "example".to_owned()], vec![])
+        );
+        assert_eq!(format!("{}", record), "hostmaster.example. .");
+        assert_eq!(
+            record.to_bytes(),
+            b"\x0ahostmaster\x07example\x00\x00".to_vec()
+        );
+        // Missing the second name
+        assert!(DnsRecordData::from_bytes(packet, 9, &DnsRRType::RP, 13).is_err());
+    }
+
+    #[test]
+    fn eui_addresses_round_trip() {
+        let packet = b"\x00\x00\x5e\x00\x53\x2a\xff\xff";
+        let (record, _) =
+            DnsRecordData::from_bytes(packet, 0, &DnsRRType::EUI4, 6).expect("should parse");
+        assert_eq!(
+            record,
+            DnsRecordData::EUI48([0x00, 0x00, 0x5e, 0x00, 0x53, 0x2a])
+        );
+        assert_eq!(format!("{}", record), "00:00:5e:00:53:2a");
+        assert_eq!(record.to_bytes(), packet[..6].to_vec());
+
+        let (record, _) =
+            DnsRecordData::from_bytes(packet, 0, &DnsRRType::EUI64, 8).expect("should parse");
+        assert_eq!(format!("{}", record), "00:00:5e:00:53:2a:ff:ff");
+        assert_eq!(record.to_bytes(), packet.to_vec());
+
+        // They're fixed size
+        assert!(DnsRecordData::from_bytes(packet, 0, &DnsRRType::EUI4, 8).is_err());
+        assert!(DnsRecordData::from_bytes(packet, 0, &DnsRRType::EUI64, 6).is_err());
+    }
+
+    #[test]
+    fn loc_round_trips() {
+        // RFC 1876's example: cambridge-net.kei.com. LOC 42 21 54 N 71 06 18 W -24m 30m, with
+        // the default precisions
+        let mut rdata = vec![0u8, 0x33, 0x16, 0x13];
+        for field in &[2299997648u32, 1891505648, 9997600] {
+            rdata.extend_from_slice(&field.to_be_bytes());
+        }
+        let (record, _) =
+            DnsRecordData::from_bytes(&rdata, 0, &DnsRRType::LOC, 16).expect("should parse");
+        assert_eq!(
+            record,
+            DnsRecordData::LOC(LocData {
+                size: 0x33,
+                horiz_pre: 0x16,
+                vert_pre: 0x13,
+                latitude: 2299997648,
+                longitude: 1891505648,
+                altitude: 9997600,
+            })
+        );
+        assert_eq!(record.to_bytes(), rdata);
+        assert_eq!(
+            format!("{}", record),
+            "42 21 54.000 N 71 6 18.000 W -24m 30m 10000m 10m"
+        );
+
+        // Just south of the equator, just above the spheroid, and a centimeter across
+        let point = DnsRecordData::LOC(LocData {
+            size: 0x10,
+            horiz_pre: 0x10,
+            vert_pre: 0x10,
+            latitude: (1 << 31) - 1500,
+            longitude: 1 << 31,
+            altitude: 10_000_050,
+        });
+        assert_eq!(
+            format!("{}", point),
+            "0 0 1.500 S 0 0 0.000 E 0.50m 0.01m 0.01m 0.01m"
+        );
+
+        // A version we can't read is kept as it is, but version 0 has to be the right size
+        rdata[0] = 1;
+        let (record, _) =
+            DnsRecordData::from_bytes(&rdata, 0, &DnsRRType::LOC, 16).expect("should parse");
+        assert_eq!(record, DnsRecordData::Other(rdata.to_owned()));
+        rdata[0] = 0;
+        assert!(DnsRecordData::from_bytes(&rdata, 0, &DnsRRType::LOC, 15).is_err());
+    }
+}
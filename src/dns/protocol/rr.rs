@@ -1,3 +1,6 @@
+use std::fmt;
+
+use super::writer::PacketWriter;
 use super::{bigendians, names, DnsClass, DnsFormatError, DnsRRType, DnsRecordData};
 
 #[derive(Clone, PartialEq, Debug)]
@@ -23,9 +26,10 @@ impl DnsResourceRecord {
     ) -> Result<(DnsResourceRecord, usize), DnsFormatError> {
         let (name, new_pos) = names::deserialize_name(&packet_bytes, pos)?;
         if new_pos + 10 > packet_bytes.len() {
-            return Err(DnsFormatError::make_error(format!(
-                "End of packet parsing resource record"
-            )));
+            return Err(DnsFormatError::make_error_at(
+                String::from("End of packet parsing resource record"),
+                new_pos,
+            ));
         }
         let rrtype_num = bigendians::to_u16(&packet_bytes[new_pos..new_pos + 2]);
         let class_num = bigendians::to_u16(&packet_bytes[new_pos + 2..new_pos + 4]);
@@ -53,7 +57,14 @@ impl DnsResourceRecord {
             }?
         };
 
-        let (record, pos) = DnsRecordData::from_bytes(packet_bytes, pos, &rr_type, rd_length)?;
+        // Dynamic updates (RFC 2136) use class ANY and NONE records with no data to mean "all the
+        // records of this type", which wouldn't parse as the type they claim to be
+        let no_data = matches!(class, DnsClass::ANY | DnsClass::NONE) && rd_length == 0;
+        let (record, pos) = if no_data {
+            (DnsRecordData::Other(Vec::new()), pos)
+        } else {
+            DnsRecordData::from_bytes(packet_bytes, pos, &rr_type, rd_length)?
+        };
         let rr = DnsResourceRecord {
             name,
             rr_type,
@@ -65,34 +76,74 @@ impl DnsResourceRecord {
         Ok((rr, pos))
     }
 
+    // The record on its own, with every name spelled out
     pub fn to_bytes(&self) -> Vec<u8> {
-        // Some of these copies feel unnecessary; the issue is that though a RR object already has
-        // the exact bytes for, say, an A record, it doesn't for records which contain a DNS name.
-        // One option would be to _special case_ those records; i.e. detect if we're in a "just use
-        // a reference" case and only alloc/copy data here if we need to. I'm not convinced the
-        // complexity of the code would be worth saving, like, one 16 byte copy per AAAA record.
-        let record = &self.record.to_bytes();
+        let mut writer = PacketWriter::uncompressed();
+        self.write(&mut writer);
+        writer.into_bytes()
+    }
+
+    pub fn write(&self, writer: &mut PacketWriter) {
+        writer.name(&self.name);
+        writer.u16(self.rr_type as u16);
+        writer.u16(self.class.to_u16());
+        writer.u32(self.ttl);
+        // The length goes before the data, but isn't known until the data's been written
+        let length_pos = writer.len();
+        writer.u16(0);
+        self.record.write(writer);
+        let record_length = writer.len() - length_pos - 2;
 
         // Bounds check that the record isn't too large to fit in a u16.
-        let record_length = if record.len() <= std::u16::MAX as usize {
-            record.len() as u16
-        } else {
+        if record_length > u16::MAX as usize {
             // There's not a way for our server to _receive_ a record this large, but this isn't
             // theoretically impossible to happen; if we got a record that contained a very long
             // DNS name, but was shorter because of label compression, we might "wind up" with a
             // super long one. Of course, that would already be well beyond the name length limits
             // in RFC 1035, which limit a name to 255 bytes, but "malicious authority input causes
             // this panic" is not, like, totally impossible.
-            panic!("ResourceRecord of size {} is too large to be transmitted. This is almost certainly an error with this server and not the record.", record.len());
-        };
+            panic!("ResourceRecord of size {} is too large to be transmitted. This is almost certainly an error with this server and not the record.", record_length);
+        }
+        writer.set_u16(length_pos, record_length as u16);
+    }
+}
+
+impl fmt::Display for DnsResourceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}",
+            names::name_to_string(&self.name),
+            self.ttl,
+            self.class,
+            self.rr_type,
+            self.record
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::dns::mock;
+
+    #[test]
+    fn record_length_follows_the_data() {
+        // Nothing about the length is kept from parsing, so a rewritten record serializes with
+        // the length of its new data
+        let original = mock::ns("example.com", "ns.example.com");
+        let (mut record, end) = DnsResourceRecord::from_bytes(&original.to_bytes(), 0).unwrap();
+        assert_eq!(record, original);
+        assert_eq!(end, original.to_bytes().len());
 
-        let mut bytes = Vec::new();
-        bytes.append(&mut names::serialize_name(&self.name));
-        bytes.extend_from_slice(&bigendians::from_u16(self.rr_type.to_owned() as u16));
-        bytes.extend_from_slice(&bigendians::from_u16(self.class.to_u16()));
-        bytes.extend_from_slice(&bigendians::from_u32(self.ttl));
-        bytes.extend_from_slice(&bigendians::from_u16(record_length));
-        bytes.extend_from_slice(&record);
-        bytes
+        record.record = DnsRecordData::NS(mock::labels("a.much.longer.nameserver.example.net"));
+        let bytes = record.to_bytes();
+        let rdata_start = names::serialize_name(&record.name).len() + 10;
+        assert_eq!(
+            bigendians::to_u16(&bytes[rdata_start - 2..rdata_start]) as usize,
+            bytes.len() - rdata_start
+        );
+        assert_eq!(DnsResourceRecord::from_bytes(&bytes, 0).unwrap().0, record);
     }
 }
@@ -0,0 +1,221 @@
+use std::fmt;
+
+use super::canonical::canonical_rdata;
+use super::{names, DnsClass, DnsRRType, DnsRecordData, DnsResourceRecord};
+
+// The records with the same owner, type, and class, which the DNS treats as a unit (RFC 2181
+// section 5): they're answered, cached, signed, and rotated together, and never partly.
+//
+// An RRset has one TTL. RFC 2181 section 5.2 says records that arrive together with different
+// TTLs should be treated as an error, and that anyone keeping them anyway should use the lowest,
+// which is what joining them into an RRset does. It can't hold the same data twice either (5.1);
+// data differing only in the case of an embedded name is the same data.
+#[derive(Clone, PartialEq, Debug)]
+pub struct RRset {
+    pub name: Vec<String>,
+    pub rr_type: DnsRRType,
+    pub class: DnsClass,
+    pub ttl: u32,
+    records: Vec<DnsRecordData>,
+}
+
+impl RRset {
+    pub fn new(rr: DnsResourceRecord) -> RRset {
+        RRset {
+            name: rr.name,
+            rr_type: rr.rr_type,
+            class: rr.class,
+            ttl: rr.ttl,
+            records: vec![rr.record],
+        }
+    }
+
+    // Records grouped into RRsets, in the order each RRset first appears
+    pub fn group(records: impl IntoIterator<Item = DnsResourceRecord>) -> Vec<RRset> {
+        let mut sets = Vec::new();
+        for rr in records {
+            RRset::add_to(&mut sets, rr);
+        }
+        sets
+    }
+
+    // Add a record to whichever of `sets` it belongs in, or to a new RRset of its own. Returns
+    // the RRset it went into.
+    pub fn add_to(sets: &mut Vec<RRset>, rr: DnsResourceRecord) -> &mut RRset {
+        match sets.iter().position(|set| set.holds(&rr)) {
+            Some(index) => {
+                sets[index].insert(rr);
+                &mut sets[index]
+            }
+            None => {
+                sets.push(RRset::new(rr));
+                sets.last_mut().unwrap()
+            }
+        }
+    }
+
+    // Whether `rr` has this RRset's owner, type, and class, whatever its data
+    pub fn holds(&self, rr: &DnsResourceRecord) -> bool {
+        self.rr_type == rr.rr_type
+            && self.class == rr.class
+            && names::names_equal(&self.name, &rr.name)
+    }
+
+    // Add a record with this RRset's owner, type, and class, lowering the RRset's TTL to the
+    // record's if it's lower. Returns whether the data was new.
+    pub fn insert(&mut self, rr: DnsResourceRecord) -> bool {
+        debug_assert!(self.holds(&rr), "{} doesn't belong in this RRset", rr);
+        self.ttl = self.ttl.min(rr.ttl);
+        if self.position(&rr.record).is_some() {
+            return false;
+        }
+        self.records.push(rr.record);
+        true
+    }
+
+    // Take out the record with this data, returning whether there was one
+    pub fn remove(&mut self, record: &DnsRecordData) -> bool {
+        match self.position(record) {
+            Some(index) => {
+                self.records.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    // The RRset as records, each with its TTL, for a response or a master file
+    pub fn to_records(&self) -> Vec<DnsResourceRecord> {
+        self.records
+            .iter()
+            .map(|record| DnsResourceRecord {
+                name: self.name.to_owned(),
+                rr_type: self.rr_type,
+                class: self.class,
+                ttl: self.ttl,
+                record: record.to_owned(),
+            })
+            .collect()
+    }
+
+    fn position(&self, record: &DnsRecordData) -> Option<usize> {
+        let canonical = canonical_rdata(record);
+        self.records
+            .iter()
+            .position(|existing| canonical_rdata(existing) == canonical)
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn rdata(&self) -> &[DnsRecordData] {
+        &self.records
+    }
+
+    // Whether this RRset has `rr`'s data, ignoring its TTL
+    pub fn contains(&self, rr: &DnsResourceRecord) -> bool {
+        self.holds(rr) && self.position(&rr.record).is_some()
+    }
+
+    // Add everything in another copy of this RRset
+    pub fn merge(&mut self, other: RRset) {
+        for rr in other.to_records() {
+            self.insert(rr);
+        }
+    }
+}
+
+// Records from more than one source, like a CNAME's answers added to the original ones, joined up
+// into whole RRsets: each with the lowest TTL any copy of it had and no data twice. RRsets stay
+// in the order they first appear, so a CNAME chain reads the same. OPT records aren't part of
+// any RRset and are passed through as they are, after the rest.
+pub fn merge_rrsets(records: Vec<DnsResourceRecord>) -> Vec<DnsResourceRecord> {
+    let (opt, records): (Vec<DnsResourceRecord>, Vec<DnsResourceRecord>) = records
+        .into_iter()
+        .partition(|rr| rr.rr_type == DnsRRType::OPT);
+    let mut merged: Vec<DnsResourceRecord> = RRset::group(records)
+        .iter()
+        .flat_map(RRset::to_records)
+        .collect();
+    merged.extend(opt);
+    merged
+}
+
+impl fmt::Display for RRset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, rr) in self.to_records().iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", rr)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    use crate::dns::mock;
+    use crate::dns::protocol::opt_record;
+
+    #[test]
+    fn records_group_into_rrsets() {
+        let a = |name: &str, last: u8, ttl: u32| DnsResourceRecord {
+            ttl,
+            ..mock::a(name, Ipv4Addr::new(192, 0, 2, last))
+        };
+        let mut sets = RRset::group(vec![
+            a("www.example.com", 1, 300),
+            mock::ns("example.com", "ns1.example.com"),
+            a("WWW.example.com", 2, 60),
+            // The same data again, which doesn't count twice
+            a("www.example.com", 1, 300),
+            mock::ns("example.com", "NS1.example.com"),
+        ]);
+        assert_eq!(sets.len(), 2);
+        let www = &sets[0];
+        // The lowest TTL wins
+        assert_eq!(www.ttl, 60);
+        assert_eq!(
+            www.rdata(),
+            &[
+                DnsRecordData::A(Ipv4Addr::new(192, 0, 2, 1)),
+                DnsRecordData::A(Ipv4Addr::new(192, 0, 2, 2))
+            ]
+        );
+        assert_eq!(
+            www.to_string(),
+            "www.example.com.\t60\tIN\tA\t192.0.2.1\nwww.example.com.\t60\tIN\tA\t192.0.2.2"
+        );
+        assert_eq!(sets[1].len(), 1);
+        assert!(sets[1].contains(&mock::ns("Example.com", "ns1.example.com")));
+        assert!(!sets[1].contains(&mock::ns("example.com", "ns2.example.com")));
+
+        let mut more = RRset::new(a("www.example.com", 3, 30));
+        more.insert(a("www.example.com", 2, 30));
+        sets[0].merge(more);
+        assert_eq!(sets[0].len(), 3);
+        assert_eq!(sets[0].ttl, 30);
+        assert!(sets[0].remove(&DnsRecordData::A(Ipv4Addr::new(192, 0, 2, 1))));
+        assert!(!sets[0].remove(&DnsRecordData::A(Ipv4Addr::new(192, 0, 2, 1))));
+        assert_eq!(sets[0].to_records()[0], a("www.example.com", 2, 30));
+
+        // Merging keeps OPT records out of it, at the end
+        let opt = opt_record(1232);
+        let merged = merge_rrsets(vec![
+            opt.to_owned(),
+            a("www.example.com", 1, 300),
+            a("www.example.com", 1, 60),
+        ]);
+        assert_eq!(merged, vec![a("www.example.com", 1, 60), opt]);
+    }
+}
@@ -1,3 +1,6 @@
+use std::fmt;
+use std::str::FromStr;
+
 use num_derive::FromPrimitive;
 
 #[allow(dead_code)]
@@ -132,7 +135,11 @@ pub enum DnsRRType {
     CSYNC = 62,
     // 63: ZONEMD - message digest for DNS zone
     ZONEMD = 63,
-    // 64-98: Unassigned
+    // 64: SVCB - General-purpose service binding
+    SVCB = 64,
+    // 65: HTTPS - SVCB-compatible type for use with HTTP
+    HTTPS = 65,
+    // 66-98: Unassigned
     // 99: SPF
     SPF = 99,
     // 100: UINFO
@@ -189,3 +196,49 @@ pub enum DnsRRType {
     // 65280-65534: Private Use
     // 65535: Reserved
 }
+
+impl fmt::Display for DnsRRType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The variant names are mostly the mnemonics, except where Rust identifiers can't have
+        // the character (NSAP-PTR) or the variant name is just off
+        match self {
+            DnsRRType::NSAPPTR => write!(f, "NSAP-PTR"),
+            DnsRRType::EUI4 => write!(f, "EUI48"),
+            DnsRRType::AXF => write!(f, "AXFR"),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+// A type written as its mnemonic, in any case, or as TYPE followed by its number for types without
+// one (RFC 3597 section 5)
+impl FromStr for DnsRRType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let upper = s.to_ascii_uppercase();
+        let found = match upper.strip_prefix("TYPE") {
+            Some(number) => number.parse().ok().and_then(num::FromPrimitive::from_u16),
+            // The assigned types are spread out, so look through all of them for this name
+            None => (0..=u16::MAX)
+                .filter_map(num::FromPrimitive::from_u16)
+                .find(|rr_type: &DnsRRType| rr_type.to_string() == upper),
+        };
+        found.ok_or_else(|| format!("Unknown record type {}", s))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn types_parse_from_mnemonics_and_numbers() {
+        assert_eq!("aaaa".parse(), Ok(DnsRRType::AAAA));
+        assert_eq!("AXFR".parse(), Ok(DnsRRType::AXF));
+        assert_eq!("NSAP-PTR".parse(), Ok(DnsRRType::NSAPPTR));
+        assert_eq!("TYPE15".parse(), Ok(DnsRRType::MX));
+        assert!("TYPE1000".parse::<DnsRRType>().is_err());
+        assert!("A6A".parse::<DnsRRType>().is_err());
+    }
+}
@@ -0,0 +1,415 @@
+use std::fmt;
+use std::str::FromStr;
+
+use hmac::{Hmac, Mac};
+use sha2::{Sha256, Sha512};
+
+use super::canonical::canonical_name_bytes;
+use super::{
+    bigendians, name_from_string, name_to_string, names, names_equal, DnsClass, DnsPacket,
+    DnsQuestion, DnsRRType, DnsRecordData, DnsResourceRecord,
+};
+
+// Transaction signatures (RFC 8945): a shared secret HMAC over the whole message, carried in a
+// TSIG record at the very end of it. Only the signing side of a response is done here; we don't
+// send signed queries of our own.
+
+// Signatures more than this many seconds off our clock are rejected
+const FUDGE: u16 = 300;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TsigAlgorithm {
+    HmacSha256,
+    HmacSha512,
+}
+
+impl TsigAlgorithm {
+    fn name(&self) -> &'static str {
+        match self {
+            TsigAlgorithm::HmacSha256 => "hmac-sha256",
+            TsigAlgorithm::HmacSha512 => "hmac-sha512",
+        }
+    }
+
+    fn from_name(name: &[String]) -> Option<TsigAlgorithm> {
+        [TsigAlgorithm::HmacSha256, TsigAlgorithm::HmacSha512]
+            .iter()
+            .copied()
+            .find(|algorithm| names_equal(name, &[algorithm.name().to_owned()]))
+    }
+
+    fn mac(&self, secret: &[u8], data: &[u8]) -> Vec<u8> {
+        // HMAC takes keys of any length, so these can't fail
+        match self {
+            TsigAlgorithm::HmacSha256 => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+            TsigAlgorithm::HmacSha512 => {
+                let mut mac = Hmac::<Sha512>::new_from_slice(secret).unwrap();
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct TsigKey {
+    pub name: Vec<String>,
+    pub algorithm: TsigAlgorithm,
+    pub secret: Vec<u8>,
+}
+
+// "[ALGORITHM:]NAME:SECRET" with the secret in base64, the same as dig's -y option. The algorithm
+// defaults to hmac-sha256.
+impl FromStr for TsigKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TsigKey, String> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let (algorithm, name, secret) = match parts.as_slice() {
+            [name, secret] => (TsigAlgorithm::HmacSha256, name, secret),
+            [algorithm, name, secret] => (
+                TsigAlgorithm::from_name(&name_from_string(algorithm)?)
+                    .ok_or_else(|| format!("Unsupported TSIG algorithm {}", algorithm))?,
+                name,
+                secret,
+            ),
+            _ => return Err(format!("Expected [ALGORITHM:]NAME:SECRET, got {:?}", s)),
+        };
+        let secret = base64::decode(secret).map_err(|e| format!("Bad TSIG secret: {}", e))?;
+        Ok(TsigKey {
+            name: name_from_string(name)?,
+            algorithm,
+            secret,
+        })
+    }
+}
+
+// Why a message's signature wasn't accepted. All but Unsigned have a TSIG error code to send back.
+#[derive(Clone, PartialEq, Debug)]
+pub enum TsigError {
+    Unsigned,
+    Malformed(String),
+    BadKey(Vec<String>),
+    BadSig(Vec<String>),
+    BadTime(u64),
+}
+
+impl TsigError {
+    fn code(&self) -> u16 {
+        match self {
+            TsigError::Unsigned | TsigError::Malformed(_) | TsigError::BadSig(_) => 16,
+            TsigError::BadKey(_) => 17,
+            TsigError::BadTime(_) => 18,
+        }
+    }
+}
+
+impl fmt::Display for TsigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TsigError::Unsigned => write!(f, "not signed"),
+            TsigError::Malformed(e) => write!(f, "malformed TSIG record: {}", e),
+            TsigError::BadKey(name) => write!(f, "unknown key {}", name_to_string(name)),
+            TsigError::BadSig(name) => {
+                write!(f, "signature doesn't match key {}", name_to_string(name))
+            }
+            TsigError::BadTime(time) => write!(f, "signed at {}, too far from now", time),
+        }
+    }
+}
+
+// The rdata of a TSIG record (RFC 8945 section 4.2)
+#[derive(Clone, PartialEq, Debug)]
+struct TsigRecord {
+    algorithm: Vec<String>,
+    time_signed: u64,
+    fudge: u16,
+    mac: Vec<u8>,
+    original_id: u16,
+    error: u16,
+    other: Vec<u8>,
+}
+
+impl TsigRecord {
+    fn from_rdata(rdata: &[u8]) -> Result<TsigRecord, String> {
+        let (algorithm, mut pos) = names::deserialize_name(rdata, 0).map_err(|e| e.to_string())?;
+        let field = |pos: usize, len: usize| {
+            rdata
+                .get(pos..pos + len)
+                .ok_or_else(|| String::from("TSIG record is cut short"))
+        };
+        let time_signed = field(pos, 6)?
+            .iter()
+            .fold(0u64, |time, byte| time << 8 | *byte as u64);
+        let fudge = bigendians::to_u16(field(pos + 6, 2)?);
+        let mac_len = bigendians::to_u16(field(pos + 8, 2)?) as usize;
+        let mac = field(pos + 10, mac_len)?.to_vec();
+        pos += 10 + mac_len;
+        let original_id = bigendians::to_u16(field(pos, 2)?);
+        let error = bigendians::to_u16(field(pos + 2, 2)?);
+        let other_len = bigendians::to_u16(field(pos + 4, 2)?) as usize;
+        let other = field(pos + 6, other_len)?.to_vec();
+        Ok(TsigRecord {
+            algorithm,
+            time_signed,
+            fudge,
+            mac,
+            original_id,
+            error,
+            other,
+        })
+    }
+
+    fn to_rdata(&self) -> Vec<u8> {
+        let mut bytes = names::serialize_name(&self.algorithm);
+        bytes.extend_from_slice(&self.time_signed.to_be_bytes()[2..]);
+        bytes.extend_from_slice(&bigendians::from_u16(self.fudge));
+        bytes.extend_from_slice(&bigendians::from_u16(self.mac.len() as u16));
+        bytes.extend_from_slice(&self.mac);
+        bytes.extend_from_slice(&bigendians::from_u16(self.original_id));
+        bytes.extend_from_slice(&bigendians::from_u16(self.error));
+        bytes.extend_from_slice(&bigendians::from_u16(self.other.len() as u16));
+        bytes.extend_from_slice(&self.other);
+        bytes
+    }
+
+    // The "TSIG variables" that go into the MAC after the message (section 4.3.3)
+    fn variables(&self, key_name: &[String]) -> Vec<u8> {
+        let mut bytes = canonical_name_bytes(key_name);
+        bytes.extend_from_slice(&bigendians::from_u16(DnsClass::ANY.to_u16()));
+        bytes.extend_from_slice(&bigendians::from_u32(0));
+        bytes.extend_from_slice(&canonical_name_bytes(&self.algorithm));
+        bytes.extend_from_slice(&self.time_signed.to_be_bytes()[2..]);
+        bytes.extend_from_slice(&bigendians::from_u16(self.fudge));
+        bytes.extend_from_slice(&bigendians::from_u16(self.error));
+        bytes.extend_from_slice(&bigendians::from_u16(self.other.len() as u16));
+        bytes.extend_from_slice(&self.other);
+        bytes
+    }
+
+    fn to_rr(&self, key_name: &[String]) -> DnsResourceRecord {
+        DnsResourceRecord {
+            name: key_name.to_owned(),
+            rr_type: DnsRRType::TSIG,
+            class: DnsClass::ANY,
+            ttl: 0,
+            record: DnsRecordData::Other(self.to_rdata()),
+        }
+    }
+}
+
+// A query whose signature checked out, kept to sign the response with
+#[derive(Clone, Debug)]
+pub struct SignedQuery {
+    pub key: TsigKey,
+    mac: Vec<u8>,
+}
+
+// Check the signature on a message, given as it came off the wire since that's what was signed.
+// `now` is in seconds since the epoch.
+pub fn verify_tsig(message: &[u8], keys: &[TsigKey], now: u64) -> Result<SignedQuery, TsigError> {
+    let (start, rr) = last_record(message)?;
+    if rr.rr_type != DnsRRType::TSIG {
+        return Err(TsigError::Unsigned);
+    }
+    let tsig = match &rr.record {
+        DnsRecordData::Other(rdata) => {
+            TsigRecord::from_rdata(rdata).map_err(TsigError::Malformed)?
+        }
+        _ => return Err(TsigError::Malformed(String::from("unexpected record data"))),
+    };
+    let key = keys
+        .iter()
+        .find(|key| {
+            names_equal(&key.name, &rr.name)
+                && TsigAlgorithm::from_name(&tsig.algorithm) == Some(key.algorithm)
+        })
+        .ok_or_else(|| TsigError::BadKey(rr.name.to_owned()))?;
+
+    // The message as it was before the TSIG record was added
+    let mut signed = message[..start].to_vec();
+    signed[0..2].copy_from_slice(&bigendians::from_u16(tsig.original_id));
+    let ar_count = bigendians::to_u16(&signed[10..12]) - 1;
+    signed[10..12].copy_from_slice(&bigendians::from_u16(ar_count));
+    signed.extend_from_slice(&tsig.variables(&rr.name));
+    let expected = key.algorithm.mac(&key.secret, &signed);
+    if !constant_time_eq(&expected, &tsig.mac) {
+        return Err(TsigError::BadSig(rr.name.to_owned()));
+    }
+    if now.abs_diff(tsig.time_signed) > tsig.fudge as u64 {
+        return Err(TsigError::BadTime(tsig.time_signed));
+    }
+    Ok(SignedQuery {
+        key: key.to_owned(),
+        mac: tsig.mac,
+    })
+}
+
+// Sign a response to a signed query, which has to be the last change made to it
+pub fn sign_tsig(response: &mut DnsPacket, query: &SignedQuery, now: u64) {
+    let mut tsig = TsigRecord {
+        algorithm: vec![query.key.algorithm.name().to_owned()],
+        time_signed: now,
+        fudge: FUDGE,
+        mac: Vec::new(),
+        original_id: response.id,
+        error: 0,
+        other: Vec::new(),
+    };
+    let mut signed = bigendians::from_u16(query.mac.len() as u16).to_vec();
+    signed.extend_from_slice(&query.mac);
+    signed.extend_from_slice(&response.to_bytes());
+    signed.extend_from_slice(&tsig.variables(&query.key.name));
+    tsig.mac = query.key.algorithm.mac(&query.key.secret, &signed);
+    response.addl_recs.push(tsig.to_rr(&query.key.name));
+}
+
+// Tell the client why its signature was rejected, in an unsigned TSIG record (section 5.3.2).
+// Unsigned queries just get the response as it is.
+pub fn add_tsig_error(response: &mut DnsPacket, query: &[u8], error: &TsigError, now: u64) {
+    let rr = match (error, last_record(query)) {
+        (TsigError::Unsigned, _) | (TsigError::Malformed(_), _) | (_, Err(_)) => return,
+        (_, Ok((_, rr))) => rr,
+    };
+    let algorithm = match &rr.record {
+        DnsRecordData::Other(rdata) => match TsigRecord::from_rdata(rdata) {
+            Ok(tsig) => tsig.algorithm,
+            Err(_) => return,
+        },
+        _ => return,
+    };
+    // A time error says what time it is here, so the client can tell how far off its clock is
+    let other = match error {
+        TsigError::BadTime(_) => now.to_be_bytes()[2..].to_vec(),
+        _ => Vec::new(),
+    };
+    let tsig = TsigRecord {
+        algorithm,
+        time_signed: now,
+        fudge: FUDGE,
+        mac: Vec::new(),
+        original_id: response.id,
+        error: error.code(),
+        other,
+    };
+    response.addl_recs.push(tsig.to_rr(&rr.name));
+}
+
+// Where the message's last record starts, and the record. A TSIG record is always last.
+fn last_record(message: &[u8]) -> Result<(usize, DnsResourceRecord), TsigError> {
+    let malformed = |e: super::DnsFormatError| TsigError::Malformed(e.to_string());
+    if message.len() < 12 {
+        return Err(TsigError::Malformed(String::from("message too short")));
+    }
+    let count = |at: usize| bigendians::to_u16(&message[at..at + 2]) as usize;
+    let records = count(6) + count(8) + count(10);
+    if count(10) == 0 {
+        return Err(TsigError::Unsigned);
+    }
+    let mut pos = 12;
+    for _ in 0..count(4) {
+        pos = DnsQuestion::from_bytes(message, pos).map_err(malformed)?.1;
+    }
+    for _ in 0..records - 1 {
+        pos = DnsResourceRecord::from_bytes(message, pos)
+            .map_err(malformed)?
+            .1;
+    }
+    let (rr, _) = DnsResourceRecord::from_bytes(message, pos).map_err(malformed)?;
+    Ok((pos, rr))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::dns::mock;
+
+    // Signs a query the way a client would
+    fn signed_query(key: &TsigKey, time: u64) -> Vec<u8> {
+        let query = mock::query("example.test", DnsRRType::AXF);
+        let tsig = TsigRecord {
+            algorithm: vec![key.algorithm.name().to_owned()],
+            time_signed: time,
+            fudge: FUDGE,
+            mac: Vec::new(),
+            original_id: query.id,
+            error: 0,
+            other: Vec::new(),
+        };
+        let mut signed = query.to_bytes();
+        signed.extend_from_slice(&tsig.variables(&key.name));
+        let mut query = query;
+        query.addl_recs.push(
+            TsigRecord {
+                mac: key.algorithm.mac(&key.secret, &signed),
+                ..tsig
+            }
+            .to_rr(&key.name),
+        );
+        query.to_bytes()
+    }
+
+    #[test]
+    fn signatures_are_checked() {
+        let key: TsigKey = "transfer.key:c2VjcmV0".parse().unwrap();
+        assert_eq!(key.algorithm, TsigAlgorithm::HmacSha256);
+        let other: TsigKey = "hmac-sha512:other.key:c2VjcmV0".parse().unwrap();
+        assert!("hmac-md5:old.key:c2VjcmV0".parse::<TsigKey>().is_err());
+        assert!("no.secret".parse::<TsigKey>().is_err());
+
+        let now = 1_700_000_000;
+        let message = signed_query(&key, now);
+        let signed = verify_tsig(&message, &[other.to_owned(), key.to_owned()], now + 10).unwrap();
+        assert_eq!(signed.key, key);
+        assert_eq!(
+            verify_tsig(&message, &[other], now).unwrap_err(),
+            TsigError::BadKey(mock::labels("transfer.key"))
+        );
+        assert_eq!(
+            verify_tsig(&message, &[key.to_owned()], now + 1000).unwrap_err(),
+            TsigError::BadTime(now)
+        );
+        let wrong = TsigKey {
+            secret: b"guess".to_vec(),
+            ..key.to_owned()
+        };
+        assert_eq!(
+            verify_tsig(&message, &[wrong], now).unwrap_err(),
+            TsigError::BadSig(mock::labels("transfer.key"))
+        );
+        let mut tampered = message.to_owned();
+        tampered[13] ^= 0x20;
+        assert!(verify_tsig(&tampered, &[key.to_owned()], now).is_err());
+        let unsigned = mock::query("example.test", DnsRRType::AXF).to_bytes();
+        assert_eq!(
+            verify_tsig(&unsigned, &[key.to_owned()], now).unwrap_err(),
+            TsigError::Unsigned
+        );
+
+        // The response's MAC covers the query's, so only the server that checked it could sign it
+        let mut response = mock::query("example.test", DnsRRType::AXF);
+        response.flags.qr_bit = true;
+        sign_tsig(&mut response, &signed, now);
+        let tsig = response.addl_recs.last().unwrap();
+        assert_eq!(tsig.rr_type, DnsRRType::TSIG);
+        let record = match &tsig.record {
+            DnsRecordData::Other(rdata) => TsigRecord::from_rdata(rdata).unwrap(),
+            _ => panic!("Not a TSIG record"),
+        };
+        let mut expected = bigendians::from_u16(signed.mac.len() as u16).to_vec();
+        expected.extend_from_slice(&signed.mac);
+        response.addl_recs.pop();
+        expected.extend_from_slice(&response.to_bytes());
+        expected.extend_from_slice(&record.variables(&key.name));
+        assert_eq!(record.mac, key.algorithm.mac(&key.secret, &expected));
+    }
+}
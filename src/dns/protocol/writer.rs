@@ -0,0 +1,136 @@
+use super::bigendians;
+use super::names::NameOffsets;
+
+// Writes a message into one buffer, front to back, instead of having each part build bytes of its
+// own to be joined up. Names can be compressed into pointers to names written earlier (RFC 1035
+// section 4.1.4), lengths and counts can be filled in once what they cover has been written, and
+// anything written can be taken back out again, as when a record turns out not to fit.
+#[derive(Debug)]
+pub struct PacketWriter {
+    bytes: Vec<u8>,
+    // None for writers that spell every name out, as canonical forms and lone records need
+    names: Option<NameOffsets>,
+}
+
+impl PacketWriter {
+    pub fn new() -> PacketWriter {
+        PacketWriter {
+            bytes: Vec::new(),
+            names: Some(NameOffsets::new()),
+        }
+    }
+
+    pub fn uncompressed() -> PacketWriter {
+        PacketWriter {
+            bytes: Vec::new(),
+            names: None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&bigendians::from_u16(value));
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&bigendians::from_u32(value));
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    // Fill in a u16 written earlier as a placeholder, like a length or a count
+    pub fn set_u16(&mut self, pos: usize, value: u16) {
+        self.bytes[pos..pos + 2].copy_from_slice(&bigendians::from_u16(value));
+    }
+
+    // A name, ending in a pointer to the longest suffix of it already in the message if this
+    // writer compresses
+    pub fn name(&mut self, name: &[String]) {
+        let found = self.names.as_ref().and_then(|names| names.find(name));
+        let written = found.map_or(name.len(), |(written, _)| written);
+        self.labels(name, written);
+        match found {
+            // Pointers start with two set bits; the offset fits in the other 14
+            Some((_, offset)) => self.u16(0xc000 | offset as u16),
+            None => self.u8(0),
+        }
+    }
+
+    // A name spelled out even when this writer compresses, for names in the data of record types
+    // that readers might not know how to find pointers in (RFC 3597 section 4). Later names can
+    // still point into it.
+    pub fn name_uncompressed(&mut self, name: &[String]) {
+        self.labels(name, name.len());
+        self.u8(0);
+    }
+
+    // Take back everything written from `len` on
+    pub fn truncate(&mut self, len: usize) {
+        self.bytes.truncate(len);
+        if let Some(names) = &mut self.names {
+            names.forget_from(len);
+        }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    // The first `count` labels of `name`, noted so later names can point to them
+    fn labels(&mut self, name: &[String], count: usize) {
+        if let Some(names) = &mut self.names {
+            names.add(name, self.bytes.len(), count);
+        }
+        for label in &name[..count] {
+            // Labels are at most 63 bytes, which the names we parse and build all keep to
+            self.bytes.push(label.len() as u8);
+            self.bytes.extend_from_slice(label.as_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::dns::mock::labels;
+    use crate::dns::protocol::deserialize_name;
+
+    #[test]
+    fn names_point_back_to_earlier_ones() {
+        let mut writer = PacketWriter::new();
+        writer.bytes(&[0; 12]);
+        writer.name(&labels("www.example.com"));
+        writer.name(&labels("mail.EXAMPLE.com"));
+        writer.name(&labels("www.example.com"));
+        writer.name_uncompressed(&labels("example.com"));
+        writer.name(&[]);
+        let bytes = writer.into_bytes();
+        assert_eq!(
+            &bytes[12..],
+            &b"\x03www\x07example\x03com\x00\x04mail\xc0\x10\xc0\x0c\x07example\x03com\x00\x00"[..]
+        );
+        assert_eq!(
+            deserialize_name(&bytes, 29).unwrap(),
+            (labels("mail.example.com"), 36)
+        );
+
+        // Taking a name back out means nothing can point to it any more
+        let mut writer = PacketWriter::new();
+        writer.name(&labels("example.com"));
+        let end = writer.len();
+        writer.name(&labels("www.example.org"));
+        writer.truncate(end);
+        writer.name(&labels("ftp.example.org"));
+        assert_eq!(writer.len(), end + 17);
+    }
+}
@@ -0,0 +1,108 @@
+// A log of every question the server is asked, one line each, for `montague replay` to send
+// again later. A line is the time it arrived (seconds since the epoch, to the microsecond), the
+// client's address, and the question, as in
+//
+//     1760745600.123456 192.0.2.10 www.example.com. IN AAAA
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, LineWriter, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::protocol::{name_from_string, name_to_string, DnsQuestion, DnsRRType};
+use super::trace::warning;
+
+pub struct QueryLog {
+    file: Mutex<LineWriter<File>>,
+}
+
+impl QueryLog {
+    // Queries are added to the end of whatever the file already has
+    pub fn open(path: &Path) -> io::Result<QueryLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(QueryLog {
+            file: Mutex::new(LineWriter::new(file)),
+        })
+    }
+
+    pub fn record(&self, client: IpAddr, question: &DnsQuestion) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let line = format!(
+            "{}.{:06} {} {} {} {}\n",
+            now.as_secs(),
+            now.subsec_micros(),
+            client,
+            name_to_string(&question.qname),
+            question.qclass,
+            question.qtype
+        );
+        // Losing a line of the log isn't worth failing the query over
+        if let Err(e) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            warning!("Error writing to the query log: {}", e);
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct LoggedQuery {
+    // Since the epoch
+    pub time: Duration,
+    pub client: IpAddr,
+    pub qname: Vec<String>,
+    pub qtype: DnsRRType,
+}
+
+pub fn parse_line(line: &str) -> Result<LoggedQuery, String> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let (time, client, qname, qtype) = match fields[..] {
+        [time, client, qname, _class, qtype] => (time, client, qname, qtype),
+        _ => return Err(String::from("expected TIME CLIENT NAME CLASS TYPE")),
+    };
+    let time = time
+        .parse::<f64>()
+        .ok()
+        .filter(|time| *time >= 0.0)
+        .ok_or_else(|| format!("{} isn't a time", time))?;
+    Ok(LoggedQuery {
+        time: Duration::from_secs_f64(time),
+        client: client
+            .parse()
+            .map_err(|_| format!("{} isn't an address", client))?,
+        qname: name_from_string(qname)?,
+        qtype: qtype.parse()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use crate::dns::protocol::DnsClass;
+
+    #[test]
+    fn logged_queries_parse_back() {
+        let path = std::env::temp_dir().join(format!("montague-querylog-{}", std::process::id()));
+        let log = QueryLog::open(&path).unwrap();
+        let question = DnsQuestion {
+            qname: name_from_string("www.example.com").unwrap(),
+            qtype: DnsRRType::AAAA,
+            qclass: DnsClass::IN,
+        };
+        log.record("192.0.2.10".parse().unwrap(), &question);
+        let text = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let logged = parse_line(text.trim_end()).unwrap();
+        assert_eq!(logged.client, "192.0.2.10".parse::<IpAddr>().unwrap());
+        assert_eq!(logged.qname, question.qname);
+        assert_eq!(logged.qtype, DnsRRType::AAAA);
+        assert!(logged.time > Duration::from_secs(1_600_000_000));
+        assert!(parse_line("yesterday 192.0.2.10 example.com. IN A").is_err());
+    }
+}
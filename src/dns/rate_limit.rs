@@ -0,0 +1,310 @@
+// Rate limits for queries over UDP, where the source address can be forged, so that we can't be
+// used to flood someone else with answers bigger than the queries asking for them. Clients are
+// grouped by network (a /24 for IPv4, a /56 for IPv6), since whoever's forging addresses can just
+// as easily spread them over a range.
+//
+// There are two limits, each a token bucket holding a second's worth of its rate:
+//
+//   Queries per second from a network. Past this, queries are dropped.
+//
+//   Responses per second to a network that are the same answer (response rate limiting, as BIND
+//   and Knot do it): the same name, type and rcode, or for NXDOMAIN the same zone, so asking for
+//   random names that don't exist can't get around it. Past this, responses are dropped, but every
+//   `slip`th one goes out as an empty truncated response instead. A real client behind a forged
+//   flood still gets an answer that way, by retrying over TCP, which can't be forged; the target
+//   of the flood only gets responses no bigger than the queries.
+//
+// Neither applies over TCP, TLS or HTTPS, where the handshake proves the address is real.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::access::AddressPrefix;
+use super::protocol::{DnsPacket, DnsRCode, DnsRRType, NameKey};
+
+// How much of an address counts as one client
+const IPV4_PREFIX: u8 = 24;
+const IPV6_PREFIX: u8 = 56;
+
+// Past this many buckets, new clients are over the limit until some of the old ones have refilled.
+// Someone forging addresses from enough networks could fill the table; they shouldn't be able to
+// turn the limits off by doing so.
+const MAX_BUCKETS: usize = 100_000;
+
+// How long an empty bucket takes to fill back up
+const REFILL: Duration = Duration::from_secs(1);
+
+// Slip every other response, BIND's default
+pub const DEFAULT_SLIP: u32 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verdict {
+    Send,
+    // Send an empty response with TC set, so the client retries over TCP
+    Slip,
+    Drop,
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    // Zero means no limit
+    queries_per_second: u32,
+    responses_per_second: u32,
+    // Every this many responses over the limit goes out truncated; zero means none do
+    slip: u32,
+    queries: Buckets<AddressPrefix>,
+    responses: Buckets<ResponseKey>,
+}
+
+// What makes two responses the same answer
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+struct ResponseKey {
+    network: AddressPrefix,
+    name: NameKey,
+    qtype: u16,
+    rcode: u8,
+}
+
+impl RateLimiter {
+    pub fn new(queries_per_second: u32, responses_per_second: u32, slip: u32) -> RateLimiter {
+        RateLimiter {
+            queries_per_second,
+            responses_per_second,
+            slip,
+            queries: Buckets::default(),
+            responses: Buckets::default(),
+        }
+    }
+
+    // Whether to take a query from `client`
+    pub fn allow_query(&self, client: IpAddr, now: Instant) -> bool {
+        if self.queries_per_second == 0 {
+            return true;
+        }
+        self.queries
+            .take(network(client), self.queries_per_second, now)
+            .is_none()
+    }
+
+    // What to do with `response` to `client`
+    pub fn check_response(&self, client: IpAddr, response: &DnsPacket, now: Instant) -> Verdict {
+        if self.responses_per_second == 0 {
+            return Verdict::Send;
+        }
+        let key = response_key(network(client), response);
+        match self.responses.take(key, self.responses_per_second, now) {
+            None => Verdict::Send,
+            Some(over) if self.slip != 0 && over % u64::from(self.slip) == 0 => Verdict::Slip,
+            Some(_) => Verdict::Drop,
+        }
+    }
+}
+
+fn network(client: IpAddr) -> AddressPrefix {
+    match client {
+        IpAddr::V4(_) => AddressPrefix::new(client, IPV4_PREFIX),
+        IpAddr::V6(_) => AddressPrefix::new(client, IPV6_PREFIX),
+    }
+}
+
+fn response_key(network: AddressPrefix, response: &DnsPacket) -> ResponseKey {
+    let question = response.questions.first();
+    // NXDOMAINs for names in one zone are all the same answer: the zone's SOA
+    let zone = response
+        .nameservers
+        .iter()
+        .find(|rr| rr.rr_type == DnsRRType::SOA)
+        .filter(|_| response.flags.rcode == DnsRCode::NXDomain);
+    let name = match (zone, question) {
+        (Some(soa), _) => NameKey::new(&soa.name),
+        (None, Some(question)) => NameKey::new(&question.qname),
+        (None, None) => NameKey::new(&[]),
+    };
+    ResponseKey {
+        network,
+        name,
+        qtype: question.map_or(0, |question| question.qtype as u16),
+        rcode: response.flags.rcode.to_owned() as u8,
+    }
+}
+
+// What goes out in place of a response that slips: nothing but the header and question, marked
+// truncated
+pub fn slipped(response: &DnsPacket) -> DnsPacket {
+    let mut slipped = response.to_owned();
+    slipped.flags.tc_bit = true;
+    slipped.answers.clear();
+    slipped.nameservers.clear();
+    slipped.addl_recs.clear();
+    slipped
+}
+
+#[derive(Debug)]
+struct Buckets<K> {
+    table: Mutex<Table<K>>,
+}
+
+#[derive(Debug)]
+struct Table<K> {
+    buckets: HashMap<K, Bucket>,
+    // When refilled buckets were last cleared out of a full table
+    swept: Option<Instant>,
+    // How many new keys have been turned away from a full table
+    full: u64,
+}
+
+impl<K> Default for Buckets<K> {
+    fn default() -> Buckets<K> {
+        Buckets {
+            table: Mutex::new(Table {
+                buckets: HashMap::new(),
+                swept: None,
+                full: 0,
+            }),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    // How many times in a row it's been found empty
+    over: u64,
+}
+
+impl<K: Eq + Hash> Buckets<K> {
+    // Take a token from `key`'s bucket, which refills at `rate` a second. None if there was one,
+    // or else how many times in a row there hasn't been.
+    fn take(&self, key: K, rate: u32, now: Instant) -> Option<u64> {
+        let rate = f64::from(rate);
+        let mut table = self.table.lock().unwrap();
+        let table = &mut *table;
+        if table.buckets.len() >= MAX_BUCKETS && !table.buckets.contains_key(&key) {
+            // Buckets hold a second's worth, so ones untouched for that long are as good as new.
+            // Sweeping is the whole table's worth of work, so it's done at most once a refill.
+            let due = table
+                .swept
+                .is_none_or(|swept| now.saturating_duration_since(swept) >= REFILL);
+            if due {
+                table
+                    .buckets
+                    .retain(|_, bucket| now.saturating_duration_since(bucket.updated) < REFILL);
+                table.swept = Some(now);
+            }
+            if table.buckets.len() >= MAX_BUCKETS {
+                table.full += 1;
+                return Some(table.full);
+            }
+        }
+        let bucket = table.buckets.entry(key).or_insert(Bucket {
+            tokens: rate,
+            updated: now,
+            over: 0,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.over = 0;
+            None
+        } else {
+            bucket.over += 1;
+            Some(bucket.over)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    use crate::dns::mock;
+    use crate::dns::protocol::{DnsClass, DnsRecordData, DnsResourceRecord, SoaData};
+
+    #[test]
+    fn floods_are_dropped_or_slipped() {
+        let limiter = RateLimiter::new(3, 2, 2);
+        let now = Instant::now();
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let neighbor: IpAddr = "192.0.2.200".parse().unwrap();
+        let elsewhere: IpAddr = "198.51.100.1".parse().unwrap();
+
+        // A network shares its queries per second
+        assert!(limiter.allow_query(client, now));
+        assert!(limiter.allow_query(neighbor, now));
+        assert!(limiter.allow_query(client, now));
+        assert!(!limiter.allow_query(neighbor, now));
+        assert!(limiter.allow_query(elsewhere, now));
+        assert!(limiter.allow_query(client, now + Duration::from_millis(400)));
+
+        // Past the limit, every other identical response slips
+        let mut response = mock::query("www.example.com", DnsRRType::A);
+        response.answers = vec![mock::a("www.example.com", Ipv4Addr::new(192, 0, 2, 1))];
+        let verdicts: Vec<Verdict> = (0..5)
+            .map(|_| limiter.check_response(client, &response, now))
+            .collect();
+        use Verdict::*;
+        assert_eq!(verdicts, vec![Send, Send, Drop, Slip, Drop]);
+        let other = mock::query("mail.example.com", DnsRRType::A);
+        assert_eq!(limiter.check_response(client, &other, now), Send);
+        let later = now + Duration::from_secs(1);
+        assert_eq!(limiter.check_response(client, &response, later), Send);
+
+        // Names that don't exist are all one answer from their zone
+        let soa = DnsResourceRecord {
+            name: mock::labels("example.org"),
+            rr_type: DnsRRType::SOA,
+            class: DnsClass::IN,
+            ttl: 300,
+            record: DnsRecordData::SOA(SoaData {
+                mname: mock::labels("ns.example.org"),
+                rname: mock::labels("admin.example.org"),
+                serial: 1,
+                refresh: 3600,
+                retry: 600,
+                expire: 86400,
+                minimum: 300,
+            }),
+        };
+        let nxdomain = |name: &str| {
+            let mut response = mock::query(name, DnsRRType::A);
+            response.flags.rcode = DnsRCode::NXDomain;
+            response.nameservers = vec![soa.to_owned()];
+            limiter.check_response(elsewhere, &response, now)
+        };
+        assert_eq!(nxdomain("a1.example.org"), Send);
+        assert_eq!(nxdomain("b2.example.org"), Send);
+        assert_eq!(nxdomain("c3.example.org"), Drop);
+
+        let truncated = slipped(&response);
+        assert!(truncated.flags.tc_bit && truncated.answers.is_empty());
+        assert_eq!(truncated.questions, response.questions);
+        assert!(RateLimiter::new(0, 0, 2).allow_query(client, now));
+    }
+
+    #[test]
+    fn full_tables_stay_limited() {
+        let limiter = RateLimiter::new(10, 10, 2);
+        let now = Instant::now();
+        // One query from each of as many networks as there's room for
+        let networks = (0..MAX_BUCKETS as u32).map(|n| IpAddr::V4(Ipv4Addr::from(n << 8)));
+        for client in networks {
+            assert!(limiter.allow_query(client, now));
+        }
+        let newcomer: IpAddr = "203.0.113.1".parse().unwrap();
+        assert!(!limiter.allow_query(newcomer, now));
+        // Nor does sweeping again right away make room
+        let soon = now + Duration::from_millis(500);
+        assert!(!limiter.allow_query(newcomer, soon));
+        assert!(limiter.allow_query(Ipv4Addr::from(0).into(), soon));
+        // Once the old buckets have refilled, there's room again
+        assert!(limiter.allow_query(newcomer, now + REFILL));
+    }
+}
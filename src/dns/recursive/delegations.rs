@@ -0,0 +1,111 @@
+// Referrals we've followed, so that resolving another name in a zone we've been pointed to before
+// starts at that zone's nameserver rather than back at the root. The answers themselves are
+// cached by the resolver; this is what keeps a miss there from walking the whole hierarchy again.
+// Each zone is kept with the address of the nameserver we went on to ask, until the NS records'
+// TTL runs out.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::dns::protocol::{is_subdomain, NameKey};
+
+// Past this many zones, new ones aren't remembered until some expire
+const MAX_DELEGATIONS: usize = 10_000;
+
+#[derive(Debug, Default)]
+pub struct Delegations {
+    zones: Mutex<HashMap<NameKey, Delegation>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Delegation {
+    pub zone: Vec<String>,
+    pub server: IpAddr,
+    expires: Instant,
+}
+
+impl Delegations {
+    // Remember that `zone`'s nameservers include `server`, as a server for `parent` told us. Only
+    // a zone's parents can delegate it, so a referral from anywhere else isn't taken.
+    pub fn insert(
+        &self,
+        zone: &[String],
+        parent: &[String],
+        server: IpAddr,
+        ttl: u32,
+        now: Instant,
+    ) {
+        // The root is where we'd start anyway
+        if zone.is_empty() || ttl == 0 {
+            return;
+        }
+        if zone.len() <= parent.len() || !is_subdomain(zone, parent) {
+            return;
+        }
+        let mut zones = self.zones.lock().unwrap();
+        if zones.len() >= MAX_DELEGATIONS {
+            zones.retain(|_, delegation| delegation.expires > now);
+            if zones.len() >= MAX_DELEGATIONS {
+                return;
+            }
+        }
+        zones.insert(
+            NameKey::new(zone),
+            Delegation {
+                zone: zone.to_vec(),
+                server,
+                expires: now + Duration::from_secs(ttl.into()),
+            },
+        );
+    }
+
+    // The delegation for the closest zone containing `name` that hasn't expired
+    pub fn closest(&self, name: &[String], now: Instant) -> Option<Delegation> {
+        let zones = self.zones.lock().unwrap();
+        (0..name.len())
+            .filter_map(|start| zones.get(&NameKey::new(&name[start..])))
+            .find(|delegation| delegation.expires > now)
+            .cloned()
+    }
+
+    // Stop starting at a zone whose server has let us down, going back to whatever's above it
+    pub fn forget(&self, zone: &[String]) {
+        self.zones.lock().unwrap().remove(&NameKey::new(zone));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    use crate::dns::mock;
+
+    #[test]
+    fn closest_live_delegation_wins() {
+        let delegations = Delegations::default();
+        let now = Instant::now();
+        let com = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let example = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        delegations.insert(&mock::labels("com"), &[], com, 3600, now);
+        let com_zone = mock::labels("com");
+        delegations.insert(&mock::labels("example.com"), &com_zone, example, 60, now);
+        delegations.insert(&[], &[], com, 3600, now);
+        // Only com's servers can say where example.com is
+        let elsewhere = mock::labels("example.net");
+        delegations.insert(&mock::labels("example.com"), &elsewhere, com, 3600, now);
+        delegations.insert(&com_zone, &mock::labels("example.com"), example, 3600, now);
+
+        let www = mock::labels("www.EXAMPLE.com");
+        assert_eq!(delegations.closest(&www, now).unwrap().server, example);
+        // Once example.com's runs out, com's is next best
+        let later = now + Duration::from_secs(61);
+        assert_eq!(delegations.closest(&www, later).unwrap().server, com);
+        delegations.forget(&mock::labels("com"));
+        assert_eq!(delegations.closest(&www, later), None);
+        assert_eq!(delegations.closest(&mock::labels("example.org"), now), None);
+    }
+}
@@ -0,0 +1,339 @@
+// A local copy of the root zone (RFC 8806). Instead of asking a root server where to find a TLD,
+// we transfer the whole root zone every so often and answer those referrals ourselves, which
+// saves a round trip to the other side of the world and keeps the names we look up away from the
+// root operators.
+//
+// RFC 8806 wants the copy validated (DNSSEC, or ZONEMD these days) before it's used. We don't do
+// DNSSEC yet, so transfers are only ever made from the servers listed in ROOT_ZONE_SERVERS.
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::super::config::ResolverOpts;
+use super::super::error::ResolveError;
+use super::super::protocol::{
+    names_equal, DnsClass, DnsFlags, DnsOpcode, DnsPacket, DnsQuestion, DnsRCode, DnsRRType,
+    DnsRecordData, DnsResourceRecord, NameKey,
+};
+use super::random_id;
+use crate::dns::trace::{info, warning};
+
+// ICANN-operated servers that allow transfers of the root zone, from RFC 8806 appendix A
+// (lax.xfr.dns.icann.org and iad.xfr.dns.icann.org)
+pub const ROOT_ZONE_SERVERS: [IpAddr; 2] = [
+    IpAddr::V4(Ipv4Addr::new(192, 0, 32, 132)),
+    IpAddr::V4(Ipv4Addr::new(192, 0, 47, 132)),
+];
+
+// How often to transfer a fresh copy. The root zone is published about twice a day.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+// How long to keep using a copy we haven't been able to refresh; the root SOA's expire value
+const EXPIRY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+// How long to wait before trying again after a failed transfer
+const RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug)]
+struct RootZone {
+    // Every record in the zone, by owner name
+    records: HashMap<NameKey, Vec<DnsResourceRecord>>,
+    transferred: Instant,
+}
+
+#[derive(Debug, Default)]
+pub struct RootMirror {
+    zone: RwLock<Option<RootZone>>,
+}
+
+impl RootMirror {
+    pub fn new() -> RootMirror {
+        RootMirror::default()
+    }
+
+    // Replace our copy of the zone with these records, as from a transfer
+    pub fn load(&self, records: Vec<DnsResourceRecord>) {
+        let mut by_name: HashMap<NameKey, Vec<DnsResourceRecord>> = HashMap::new();
+        for rr in records {
+            by_name.entry(NameKey::new(&rr.name)).or_default().push(rr);
+        }
+        info!("Loaded root zone mirror with {} names", by_name.len());
+        *self.zone.write().unwrap() = Some(RootZone {
+            records: by_name,
+            transferred: Instant::now(),
+        });
+    }
+
+    // The root's answer to a question, if our copy of the zone is current and can give it: the
+    // root's own records for a question about the root itself, a referral to the TLD's
+    // nameservers, or NXDOMAIN if there's no such TLD
+    pub fn answer(&self, question: &DnsQuestion) -> Option<DnsPacket> {
+        let zone = self.zone.read().unwrap();
+        let zone = zone.as_ref()?;
+        if zone.transferred.elapsed() > EXPIRY {
+            return None;
+        }
+        let mut response = DnsPacket {
+            id: 0,
+            flags: DnsFlags {
+                qr_bit: true,
+                opcode: DnsOpcode::Query,
+                aa_bit: false,
+                tc_bit: false,
+                rd_bit: false,
+                ra_bit: false,
+                ad_bit: false,
+                cd_bit: false,
+                rcode: DnsRCode::NoError,
+            },
+            questions: vec![question.to_owned()],
+            answers: Vec::new(),
+            nameservers: Vec::new(),
+            addl_recs: Vec::new(),
+        };
+        let apex = zone
+            .records
+            .get(&NameKey::new(&[]))
+            .map_or(&[][..], Vec::as_slice);
+        let soa = apex
+            .iter()
+            .filter(|rr| rr.rr_type == DnsRRType::SOA)
+            .cloned();
+        if question.qname.is_empty() {
+            response.flags.aa_bit = true;
+            response.answers = apex
+                .iter()
+                .filter(|rr| question.qtype == DnsRRType::ANY || rr.rr_type == question.qtype)
+                .cloned()
+                .collect();
+            // NODATA, for a type the root doesn't have
+            if response.answers.is_empty() {
+                response.nameservers.extend(soa);
+            }
+            return Some(response);
+        }
+        let tld = &question.qname[question.qname.len() - 1..];
+        let delegation: Vec<&DnsResourceRecord> = zone
+            .records
+            .get(&NameKey::new(tld))
+            .map(|records| {
+                records
+                    .iter()
+                    .filter(|rr| rr.rr_type == DnsRRType::NS)
+                    .collect()
+            })
+            .unwrap_or_default();
+        if delegation.is_empty() {
+            response.flags.aa_bit = true;
+            response.flags.rcode = DnsRCode::NXDomain;
+            response.nameservers.extend(soa);
+            return Some(response);
+        }
+        for ns in delegation {
+            response.nameservers.push(ns.to_owned());
+            if let DnsRecordData::NS(ns_name) = &ns.record {
+                let glue = zone
+                    .records
+                    .get(&NameKey::new(ns_name))
+                    .into_iter()
+                    .flatten();
+                response.addl_recs.extend(
+                    glue.filter(|rr| {
+                        names_equal(&rr.name, ns_name)
+                            && matches!(rr.rr_type, DnsRRType::A | DnsRRType::AAAA)
+                    })
+                    .cloned(),
+                );
+            }
+        }
+        Some(response)
+    }
+
+    // Transfer the zone from the first of `servers` that'll give it to us now, then keep it
+    // fresh on a background thread for as long as the mirror is in use
+    pub fn start(self: &Arc<Self>, servers: Vec<SocketAddr>, opts: &ResolverOpts) {
+        let mirror: Weak<RootMirror> = Arc::downgrade(self);
+        let opts = opts.to_owned();
+        thread::spawn(move || loop {
+            let mirror = match mirror.upgrade() {
+                Some(mirror) => mirror,
+                None => return,
+            };
+            let wait = match mirror.refresh(&servers, &opts) {
+                Ok(()) => REFRESH_INTERVAL,
+                Err(e) => {
+                    warning!("Root zone transfer failed: {}", e);
+                    RETRY_INTERVAL
+                }
+            };
+            drop(mirror);
+            thread::sleep(wait);
+        });
+    }
+
+    fn refresh(&self, servers: &[SocketAddr], opts: &ResolverOpts) -> Result<(), ResolveError> {
+        let mut last_error = String::from("No root zone servers configured");
+        for server in servers {
+            match transfer(&[], *server, opts) {
+                Ok(records) => {
+                    self.load(records);
+                    return Ok(());
+                }
+                Err(e) => last_error = format!("{}: {}", server, e),
+            }
+        }
+        Err(ResolveError::AllServersFailed(last_error))
+    }
+}
+
+// Transfer a whole zone (AXFR, RFC 5936) over TCP. The records come in one or more messages,
+// starting and ending with the zone's SOA.
+pub fn transfer(
+    zone: &[String],
+    server: SocketAddr,
+    opts: &ResolverOpts,
+) -> Result<Vec<DnsResourceRecord>, ResolveError> {
+    let query = DnsPacket {
+        id: random_id(),
+        flags: DnsFlags {
+            qr_bit: false,
+            opcode: DnsOpcode::Query,
+            aa_bit: false,
+            tc_bit: false,
+            rd_bit: false,
+            ra_bit: false,
+            ad_bit: false,
+            cd_bit: false,
+            rcode: DnsRCode::NoError,
+        },
+        questions: vec![DnsQuestion {
+            qname: zone.to_vec(),
+            qtype: DnsRRType::AXF,
+            qclass: DnsClass::IN,
+        }],
+        answers: Vec::new(),
+        nameservers: Vec::new(),
+        addl_recs: Vec::new(),
+    };
+    let mut stream = opts
+        .outbound
+        .binding(server)
+        .connect(server, opts.timeout)?;
+    stream.set_read_timeout(Some(opts.timeout))?;
+    let bytes = query.to_bytes();
+    let mut framed = (bytes.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(&bytes);
+    stream.write_all(&framed)?;
+
+    let mut records = Vec::new();
+    let mut soas = 0;
+    while soas < 2 {
+        let mut length = [0u8; 2];
+        stream.read_exact(&mut length)?;
+        let mut message = vec![0u8; u16::from_be_bytes(length) as usize];
+        stream.read_exact(&mut message)?;
+        let response = DnsPacket::from_bytes(&message)?;
+        if response.flags.rcode != DnsRCode::NoError {
+            return Err(ResolveError::ServerFailure(server, response.flags.rcode));
+        }
+        if response.answers.is_empty() {
+            return Err(ResolveError::Malformed(String::from(
+                "Transfer ended early",
+            )));
+        }
+        for rr in response.answers {
+            if rr.rr_type == DnsRRType::SOA {
+                soas += 1;
+                // The closing SOA repeats the opening one
+                if soas == 2 {
+                    break;
+                }
+            }
+            records.push(rr);
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::dns::mock::{self, Behavior, MockNetwork, Script};
+    use crate::dns::protocol::SoaData;
+
+    const XFR: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 7);
+
+    #[test]
+    fn transfers_and_answers_referrals() {
+        let soa = DnsResourceRecord {
+            name: Vec::new(),
+            rr_type: DnsRRType::SOA,
+            class: DnsClass::IN,
+            ttl: 86400,
+            record: DnsRecordData::SOA(SoaData {
+                mname: mock::labels("a.root-servers.net"),
+                rname: mock::labels("nstld.verisign-grs.com"),
+                serial: 2025101700,
+                refresh: 1800,
+                retry: 900,
+                expire: 604800,
+                minimum: 86400,
+            }),
+        };
+        let zone = vec![
+            soa.to_owned(),
+            mock::ns("", "a.root-servers.net"),
+            mock::ns("com", "a.gtld-servers.net"),
+            mock::ns("com", "b.gtld-servers.net"),
+            mock::a("a.gtld-servers.net", Ipv4Addr::new(192, 5, 6, 30)),
+            mock::aaaa("a.gtld-servers.net", "2001:503:a83e::2:30".parse().unwrap()),
+            soa.to_owned(),
+        ];
+        let network = MockNetwork::new();
+        let server = network.serve(
+            XFR,
+            Script::new().on("", Some(DnsRRType::AXF), Behavior::Answer(zone)),
+        );
+
+        let mirror = RootMirror::new();
+        assert!(mirror
+            .answer(&mock::question("example.com", DnsRRType::A))
+            .is_none());
+        mirror
+            .refresh(&[server.addr], &ResolverOpts::default())
+            .unwrap();
+
+        let referral = mirror
+            .answer(&mock::question("www.EXAMPLE.com", DnsRRType::A))
+            .unwrap();
+        assert_eq!(referral.flags.rcode, DnsRCode::NoError);
+        assert_eq!(
+            referral.nameservers,
+            vec![
+                mock::ns("com", "a.gtld-servers.net"),
+                mock::ns("com", "b.gtld-servers.net")
+            ]
+        );
+        assert_eq!(referral.addl_recs.len(), 2);
+
+        let nxdomain = mirror
+            .answer(&mock::question("example.invalid", DnsRRType::A))
+            .unwrap();
+        assert_eq!(nxdomain.flags.rcode, DnsRCode::NXDomain);
+        assert_eq!(nxdomain.nameservers, vec![soa.to_owned()]);
+
+        // Questions about the root itself are answered from the apex
+        let apex = mirror.answer(&mock::question("", DnsRRType::NS)).unwrap();
+        assert!(apex.flags.aa_bit);
+        assert_eq!(apex.answers, vec![mock::ns("", "a.root-servers.net")]);
+        let apex = mirror.answer(&mock::question("", DnsRRType::SOA)).unwrap();
+        assert_eq!(apex.answers, vec![soa.to_owned()]);
+        let nodata = mirror.answer(&mock::question("", DnsRRType::MX)).unwrap();
+        assert_eq!(nodata.flags.rcode, DnsRCode::NoError);
+        assert!(nodata.answers.is_empty());
+        assert_eq!(nodata.nameservers, vec![soa]);
+    }
+}
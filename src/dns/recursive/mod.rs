@@ -1,75 +1,683 @@
 // Recursive resolver functionality
 
-mod root;
+mod delegations;
+mod mirror;
+pub mod root;
+mod sockets;
 
-use std::error::Error;
-use std::net::{IpAddr, UdpSocket};
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::config::{AddressPreference, DnssecMode, ResolverOpts};
+use super::debug;
+use super::error::ResolveError;
+use super::latency::ServerLatencies;
+pub use delegations::Delegations;
+pub use mirror::{transfer, RootMirror, ROOT_ZONE_SERVERS};
+pub use root::{reachable_preference, root_referral};
+use sockets::Outstanding;
+pub use sockets::{random_id, UpstreamSockets};
 
 use super::protocol::{
-    DnsClass, DnsFlags, DnsOpcode, DnsPacket, DnsQuestion, DnsRCode, DnsRRType, DnsRecordData,
-    DnsResourceRecord,
+    is_subdomain, merge_rrsets, name_from_string, name_to_string, names_equal, remove_opt,
+    report_channel, supports_edns, DnsClass, DnsFlags, DnsFormatError, DnsOpcode, DnsPacket,
+    DnsQuestion, DnsRCode, DnsRRType, DnsRecordData, DnsResourceRecord, EdnsOptions,
 };
+use super::suffix::SuffixTrie;
+use super::telemetry::{self, SpanKind};
+use super::trace::log;
 
-// Right now this doesn't use caching, doesn't try another nameserver if one fails, and a lot of
-// other little things I'd like to add to it.
-pub fn resolve_question(question: &DnsQuestion) -> Result<DnsPacket, Box<dyn Error>> {
-    // Query the root nameserver
-    let mut ns = root::get_root_nameserver();
-    loop {
-        println!("Asking authority at {:?} question: {:?}", ns, question);
-        let response = query_nameserver(question, ns)?;
-        println!("Got response from authority: {:?}", response);
-        // Check that the response had a nonzero status code, or return an error
-        if response.flags.rcode != DnsRCode::NoError {
-            if response.flags.rcode == DnsRCode::NXDomain {
-                return Ok(response);
-            }
+// How many root server addresses to try before giving up on a question: two servers, over both
+// families
+const MAX_ROOTS_TRIED: usize = 4;
+
+// How long the first server in a race gets to answer before the second is asked too. RFC 8305
+// suggests 250ms for connections.
+const RACE_DELAY: Duration = Duration::from_millis(250);
+
+// How many lookups deep one resolution can go, counting each CNAME followed and each nameserver
+// address looked up along the way
+const MAX_DEPTH: usize = 10;
+
+// How many queries one resolution can send, all told. Real resolutions take a handful; this stops
+// zones whose servers all need looking up, and lead to more of the same, from running away.
+const MAX_QUERIES: usize = 100;
+
+// Where recursion starts, and the port authorities are queried on. Outside of tests that's always
+// a root server on port 53; the tests point these at mock nameservers on loopback instead.
+#[derive(Clone, Debug)]
+pub struct RootHints {
+    // The root servers' addresses, in the order to try them. Shared between clones, so hints
+    // reloaded while serving reach every thread.
+    pub roots: Arc<RwLock<Vec<IpAddr>>>,
+    pub port: u16,
+    // A local copy of the root zone to answer for the root with, when it's loaded
+    pub mirror: Option<Arc<RootMirror>>,
+    // Zones whose nameservers we're told about instead of finding them through the root
+    pub stub_zones: SuffixTrie<StubZone>,
+    // How long each authority takes to answer, shared with the upstreams by the resolver
+    pub latencies: Arc<ServerLatencies>,
+    // Zones we've been referred to before, which resolution starts at instead of the root
+    pub delegations: Arc<Delegations>,
+}
+
+impl RootHints {
+    // Start recursion at the root servers, reached over the family we'd rather use first
+    pub fn with_preference(preference: AddressPreference) -> RootHints {
+        RootHints {
+            roots: Arc::new(RwLock::new(root::root_nameservers(preference))),
+            ..RootHints::default()
+        }
+    }
+
+    // Start recursion at the root servers from a hints file instead of the built in list
+    pub fn use_hints(
+        &self,
+        records: &[DnsResourceRecord],
+        preference: AddressPreference,
+    ) -> Result<(), String> {
+        *self.roots.write().unwrap() = root::hinted_roots(records, preference)?;
+        Ok(())
+    }
+
+    // The closest stub zone containing `name`, if any
+    pub fn stub_zone_for(&self, name: &[String]) -> Option<&StubZone> {
+        self.stub_zones.longest_match(name)
+    }
+
+    pub fn add_stub_zone(&mut self, stub: StubZone) {
+        self.stub_zones.insert(&stub.zone.to_owned(), stub);
+    }
+}
+
+// A zone served authoritatively at fixed addresses, e.g. an internal zone the public DNS doesn't
+// delegate to. Resolution of names in it starts at those servers and carries on from there as
+// usual, so delegations below the zone are still followed.
+#[derive(Clone, PartialEq, Debug)]
+pub struct StubZone {
+    pub zone: Vec<String>,
+    pub servers: Vec<IpAddr>,
+}
+
+// Parses "ZONE=IP[,IP...]", e.g. "internal.example=10.0.0.5,10.0.0.6"
+impl FromStr for StubZone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<StubZone, String> {
+        let (zone, servers) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Expected ZONE=IP[,IP...] for stub zone, got {:?}", s))?;
+        let servers = servers
+            .split(',')
+            .map(|ip| {
+                ip.parse()
+                    .map_err(|_| format!("Invalid stub zone server address {:?}", ip))
+            })
+            .collect::<Result<Vec<IpAddr>, String>>()?;
+        Ok(StubZone {
+            zone: name_from_string(zone)?,
+            servers,
+        })
+    }
+}
+
+impl Default for RootHints {
+    fn default() -> RootHints {
+        RootHints {
+            roots: Arc::new(RwLock::new(root::root_nameservers(
+                root::reachable_preference(),
+            ))),
+            port: 53,
+            mirror: None,
+            stub_zones: SuffixTrie::new(),
+            latencies: Arc::default(),
+            delegations: Arc::default(),
+        }
+    }
+}
+
+// Answers aren't cached here; the resolver does that. Zones we've been referred to before are,
+// so resolution starts at the nameserver of the closest one containing the name, and only goes
+// back to the root (or a stub zone) when there's none or its server stops answering.
+pub fn resolve_question(
+    question: &DnsQuestion,
+    hints: &RootHints,
+    opts: &ResolverOpts,
+) -> Result<DnsPacket, ResolveError> {
+    let mut resolution = Resolution::default();
+    let result = resolve_within(question, hints, opts, &mut resolution);
+    // The last authority we heard from before things went wrong is the one to tell about it
+    if let (Err(e), Some(agent), Some(reports)) = (&result, &resolution.agent, &opts.error_reports)
+    {
+        reports.report(question, e, agent, hints, opts);
+    }
+    result
+}
+
+// What resolving a client's question has been through so far, including the lookups it took
+// along the way: following CNAMEs, and finding the addresses of nameservers that came without
+// glue. Those are resolutions of their own, nested inside the first, so a CNAME pointing back
+// at itself or a nameserver that can only be found by asking it would go on forever without
+// this to notice.
+#[derive(Debug, Default)]
+struct Resolution {
+    // The questions being resolved, from the client's down to the lookup in hand
+    lookups: Vec<DnsQuestion>,
+    // How many queries we've sent to authorities
+    queries: usize,
+    // The error reporting agent the latest authority named, if it named one
+    agent: Option<Vec<String>>,
+}
+
+impl Resolution {
+    // Count a query about to be sent, or fail if we've sent all we're going to
+    fn count_query(&mut self) -> Result<(), ResolveError> {
+        if self.queries >= MAX_QUERIES {
+            return Err(ResolveError::Loop(format!(
+                "{} queries without an answer for {}",
+                self.queries, self.lookups[0]
+            )));
+        }
+        self.queries += 1;
+        Ok(())
+    }
+}
 
-            // TODO(dylan): Handle more errors. We might also get a SERVFAIL or similar, suggesting we
-            // should probably try another server
-            return Err(format!(
-                "Nonzero response code {:?} querying {:?}",
-                response.flags.rcode, ns
-            )
-            .into());
+// Resolve `question` as a step of `resolution`, unless it's a question we're already in the
+// middle of or we've gone too many lookups deep
+fn resolve_within(
+    question: &DnsQuestion,
+    hints: &RootHints,
+    opts: &ResolverOpts,
+    resolution: &mut Resolution,
+) -> Result<DnsPacket, ResolveError> {
+    if resolution
+        .lookups
+        .iter()
+        .any(|asked| same_question(asked, question))
+    {
+        return Err(ResolveError::Loop(format!(
+            "{} depends on its own answer",
+            question
+        )));
+    }
+    if resolution.lookups.len() >= MAX_DEPTH {
+        return Err(ResolveError::Loop(format!(
+            "{} lookups deep resolving {}",
+            MAX_DEPTH, resolution.lookups[0]
+        )));
+    }
+    resolution.lookups.push(question.to_owned());
+    let result = resolve_from_root(question, hints, opts, resolution);
+    resolution.lookups.pop();
+    result
+}
+
+fn same_question(a: &DnsQuestion, b: &DnsQuestion) -> bool {
+    names_equal(&a.qname, &b.qname) && a.qtype == b.qtype && a.qclass == b.qclass
+}
+
+fn resolve_from_root(
+    question: &DnsQuestion,
+    hints: &RootHints,
+    opts: &ResolverOpts,
+    resolution: &mut Resolution,
+) -> Result<DnsPacket, ResolveError> {
+    let stub = hints.stub_zone_for(&question.qname);
+    // The zone of the server that gave `response`
+    let (mut asked, mut response) =
+        match cached_delegation_response(question, stub, hints, opts, resolution) {
+            Some(started) => started,
+            None => first_response(question, stub, hints, opts, resolution)?,
         };
+    loop {
+        // Servers that failed have been passed over already, so this is NXDOMAIN, an answer, or
+        // a referral
+        if response.flags.rcode == DnsRCode::NXDomain {
+            return Ok(negative_answer(response));
+        }
 
         // If we got answers, we move on to answer handling!
         if response.answers.len() > 0 {
-            return handle_answers(response);
-        }
-
-        // Without an answer, we need to look at the next authority to query. Per RFC 1034, it's
-        // legal for the nameservers section to include the SOA for the nameserver we're talking
-        // to, as well as NS records for nameservers to talk to next. We'll just take the first NS
-        // record returned (this is a common pattern; NS records are often sent in random orders
-        // for this reason).
-        let mut ns_answer = None;
-        for rr in &response.nameservers {
-            if rr.rr_type == DnsRRType::NS {
-                ns_answer = Some(rr);
-                break;
+            return handle_answers(response, hints, opts, resolution);
+        }
+
+        // Without an answer, this is a referral to the next authority to query, or NODATA
+        let zone = match zone_cut(&response, question, &asked) {
+            Some(zone) => zone.to_owned(),
+            None => match lame(&response, question, &asked) {
+                Some(e) => return Err(e),
+                None => return Ok(negative_answer(response)),
+            },
+        };
+        response = follow_referral(question, &response, &zone, &asked, hints, opts, resolution)?;
+        asked = zone;
+    }
+}
+
+// The zone a response from a server for `asked` refers us to. Per RFC 1034, it's legal for the
+// nameservers section to include the SOA for the nameserver we're talking to, as well as NS
+// records for nameservers to talk to next. The zone being referred to is the one named by the
+// first NS record for a zone containing the name we asked about and below the zone of the server
+// we asked. A server can only hand out zones inside its own; one referring us up or sideways, to
+// a zone it has no say over, is broken or lying.
+fn zone_cut<'a>(
+    response: &'a DnsPacket,
+    question: &DnsQuestion,
+    asked: &[String],
+) -> Option<&'a [String]> {
+    response
+        .nameservers
+        .iter()
+        .find(|rr| {
+            rr.rr_type == DnsRRType::NS
+                && is_subdomain(&question.qname, &rr.name)
+                && rr.name.len() > asked.len()
+                && is_subdomain(&rr.name, asked)
+        })
+        .map(|rr| &rr.name[..])
+}
+
+// What's wrong with a NOERROR response from a server for `asked` that has no answers, no SOA to
+// make it NODATA (RFC 2308), and no referral we can follow: a lame server, which isn't serving the
+// zone it was delegated. None if the response is fine.
+fn lame(response: &DnsPacket, question: &DnsQuestion, asked: &[String]) -> Option<ResolveError> {
+    if response.flags.rcode != DnsRCode::NoError
+        || !response.answers.is_empty()
+        || response
+            .nameservers
+            .iter()
+            .any(|rr| rr.rr_type == DnsRRType::SOA)
+        || zone_cut(response, question, asked).is_some()
+    {
+        return None;
+    }
+    let referred = response
+        .nameservers
+        .iter()
+        .find(|rr| rr.rr_type == DnsRRType::NS && is_subdomain(&question.qname, &rr.name));
+    Some(match referred {
+        Some(rr) if names_equal(&rr.name, asked) => {
+            ResolveError::Loop(format!("Referred to {} again", name_to_string(asked)))
+        }
+        Some(rr) => ResolveError::Malformed(format!(
+            "Server for {} referred us to {}, outside its zone",
+            name_to_string(asked),
+            name_to_string(&rr.name)
+        )),
+        // In theory this is disallowed by spec
+        None => {
+            ResolveError::Malformed(String::from("No error, answer, or nameservers in response"))
+        }
+    })
+}
+
+// An authority's NXDOMAIN or NODATA, made into the answer a client should get (RFC 2308). The SOA
+// stays, with its TTL cut to the zone's negative TTL, so the client remembers the name's missing
+// for as long as it should (section 5), and so do any DNSSEC records proving it. Anything else the
+// authority sent along, like its NS records and their addresses, was for resolvers and goes. We
+// aren't the authority for the answer either.
+fn negative_answer(mut response: DnsPacket) -> DnsPacket {
+    response.flags.aa_bit = false;
+    response.nameservers.retain(|rr| {
+        matches!(
+            rr.rr_type,
+            DnsRRType::SOA | DnsRRType::NSEC | DnsRRType::NSEC3 | DnsRRType::RRSIG
+        )
+    });
+    for rr in response.nameservers.iter_mut() {
+        if let DnsRecordData::SOA(soa) = &rr.record {
+            rr.ttl = rr.ttl.min(soa.minimum);
+        }
+    }
+    // The OPT record stays until the response's own replaces it, for its extended rcode
+    response.addl_recs.retain(|rr| rr.rr_type == DnsRRType::OPT);
+    response
+}
+
+// Ask the nameservers `referral` names for `zone` in turn, until one gives a usable response.
+// Servers with glue in the referral go first, since the others' addresses have to be looked up
+// before they can be asked, and those are only looked up once they're needed. NS records are
+// often sent in a random order, which spreads our queries across the zone's servers. `parent` is
+// the zone of the server that sent the referral.
+fn follow_referral(
+    question: &DnsQuestion,
+    referral: &DnsPacket,
+    zone: &[String],
+    parent: &[String],
+    hints: &RootHints,
+    opts: &ResolverOpts,
+    resolution: &mut Resolution,
+) -> Result<DnsPacket, ResolveError> {
+    let nameservers: Vec<&DnsResourceRecord> = referral
+        .nameservers
+        .iter()
+        .filter(|rr| rr.rr_type == DnsRRType::NS && names_equal(&rr.name, zone))
+        .collect();
+    let ttl = nameservers.iter().map(|rr| rr.ttl).min().unwrap_or(0);
+    let mut glued = Vec::new();
+    let mut glueless = Vec::new();
+    for rr in nameservers {
+        let mut glue = glue_addresses(rr, zone, &referral.addl_recs);
+        if glue.is_empty() {
+            glueless.push(rr);
+        } else {
+            opts.address_preference.sort(&mut glue);
+            glued.extend(glue);
+        }
+    }
+    let mut attempts = Attempts::asking(zone);
+    let mut answered = ask_each(question, &glued, &mut attempts, hints, opts, resolution);
+    for rr in glueless {
+        if answered.is_some() {
+            break;
+        }
+        match nameserver_addresses(rr, hints, opts, resolution) {
+            Ok(addresses) => {
+                answered = ask_each(question, &addresses, &mut attempts, hints, opts, resolution)
+            }
+            Err(e) => {
+                log!(
+                    "Couldn't find the address of a nameserver for {}: {}",
+                    name_to_string(zone),
+                    e
+                );
+                attempts.lookup_failed(e);
             }
         }
-        if ns_answer == None {
-            // In theory this is disallowed by spec
-            return Err(format!("No error, answer, or nameservers from response").into());
+    }
+    match answered {
+        Some((ns, response)) => {
+            hints
+                .delegations
+                .insert(zone, parent, ns, ttl, Instant::now());
+            Ok(response)
         }
+        None => Err(attempts.give_up()),
+    }
+}
 
-        // We may have a glue record for this nameserver; use it if we find it
-        let glue_record_ip = find_glue_record_for_ns(ns_answer.unwrap(), &response.addl_recs);
-        match glue_record_ip {
-            None => {
-                ns = get_nameserver_address(ns_answer.unwrap())?;
+// The servers for `zone` that have failed to give a usable response so far, and how the last of
+// them failed. Zones sometimes list one host under several names, so a server that's failed isn't
+// asked again.
+#[derive(Debug, Default)]
+struct Attempts {
+    zone: Vec<String>,
+    failed: Vec<IpAddr>,
+    // Including nameservers whose addresses couldn't be found
+    failures: usize,
+    last_error: Option<ResolveError>,
+}
+
+impl Attempts {
+    fn asking(zone: &[String]) -> Attempts {
+        Attempts {
+            zone: zone.to_vec(),
+            ..Attempts::default()
+        }
+    }
+
+    fn server_failed(&mut self, server: IpAddr, error: ResolveError) {
+        log!("{} failed ({}), trying the next server", server, error);
+        self.failed.push(server);
+        self.lookup_failed(error);
+    }
+
+    fn lookup_failed(&mut self, error: ResolveError) {
+        self.failures += 1;
+        self.last_error = Some(error);
+    }
+
+    // The error to give up with once every server has failed: what went wrong, if there was only
+    // the one
+    fn give_up(self) -> ResolveError {
+        match self.last_error {
+            Some(e) if self.failures <= 1 => e,
+            Some(e) => ResolveError::AllServersFailed(e.to_string()),
+            None => ResolveError::AllServersFailed(String::from("No servers to ask")),
+        }
+    }
+}
+
+// The response from the nameserver of the closest zone containing the name that we've been
+// referred to before, unless a stub zone is closer, along with that zone. A server that's stopped
+// answering properly is forgotten, so resolution starts from further up instead.
+fn cached_delegation_response(
+    question: &DnsQuestion,
+    stub: Option<&StubZone>,
+    hints: &RootHints,
+    opts: &ResolverOpts,
+    resolution: &mut Resolution,
+) -> Option<(Vec<String>, DnsPacket)> {
+    let delegation = hints
+        .delegations
+        .closest(&question.qname, Instant::now())
+        .filter(|delegation| stub.is_none_or(|stub| delegation.zone.len() > stub.zone.len()))?;
+    match ask_usable(
+        question,
+        &delegation.zone,
+        delegation.server,
+        hints,
+        opts,
+        resolution,
+    ) {
+        Ok(response) => Some((delegation.zone, response)),
+        Err(_) => {
+            log!(
+                "Nameserver for {} failed, starting over without it",
+                name_to_string(&delegation.zone)
+            );
+            hints.delegations.forget(&delegation.zone);
+            None
+        }
+    }
+}
+
+// The first response on the way to an answer, and the zone it came from: the closest stub zone
+// containing the name if there is one, and otherwise the root, from one of its nameservers or our
+// copy of its zone. Each of the stub zone's servers is tried in turn, as are the first few roots;
+// any root will do, so one that doesn't answer is most likely our network's fault, and trying all
+// thirteen would only keep the client waiting.
+fn first_response(
+    question: &DnsQuestion,
+    stub: Option<&StubZone>,
+    hints: &RootHints,
+    opts: &ResolverOpts,
+    resolution: &mut Resolution,
+) -> Result<(Vec<String>, DnsPacket), ResolveError> {
+    let roots;
+    let (zone, servers) = match stub {
+        Some(stub) => (stub.zone.to_owned(), &stub.servers[..]),
+        None => {
+            if let Some(response) = hints.mirror.as_ref().and_then(|m| m.answer(question)) {
+                log!("Answered from root zone mirror: {:?}", response);
+                return Ok((Vec::new(), response));
+            }
+            roots = hints.roots.read().unwrap().to_owned();
+            (Vec::new(), &roots[..roots.len().min(MAX_ROOTS_TRIED)])
+        }
+    };
+    let mut attempts = Attempts::asking(&zone);
+    match ask_each(question, servers, &mut attempts, hints, opts, resolution) {
+        Some((_, response)) => Ok((zone, response)),
+        None => Err(attempts.give_up()),
+    }
+}
+
+// Ask `servers` in turn until one gives a usable response, skipping any that have already
+// failed. Racing, the next IPv6 and IPv4 servers are asked together.
+fn ask_each(
+    question: &DnsQuestion,
+    servers: &[IpAddr],
+    attempts: &mut Attempts,
+    hints: &RootHints,
+    opts: &ResolverOpts,
+    resolution: &mut Resolution,
+) -> Option<(IpAddr, DnsPacket)> {
+    let mut pending: VecDeque<IpAddr> = servers.iter().copied().collect();
+    while let Some(server) = pending.pop_front() {
+        if attempts.failed.contains(&server) {
+            continue;
+        }
+        let partner = match opts.address_preference {
+            AddressPreference::Race => pending
+                .iter()
+                .position(|other| {
+                    other.is_ipv6() != server.is_ipv6() && !attempts.failed.contains(other)
+                })
+                .and_then(|index| pending.remove(index)),
+            _ => None,
+        };
+        let answered = match partner {
+            Some(partner) => race(question, server, partner, attempts, hints, opts, resolution),
+            None => match ask_usable(question, &attempts.zone, server, hints, opts, resolution) {
+                Ok(response) => Some((server, response)),
+                Err(e) => {
+                    attempts.server_failed(server, e);
+                    None
+                }
+            },
+        };
+        if answered.is_some() {
+            return answered;
+        }
+    }
+    None
+}
+
+// Ask `first`, and `second` as well once `first` has failed or gone RACE_DELAY without answering,
+// going with whichever usable response comes back first. The slower query is left to finish on
+// its own.
+fn race(
+    question: &DnsQuestion,
+    first: IpAddr,
+    second: IpAddr,
+    attempts: &mut Attempts,
+    hints: &RootHints,
+    opts: &ResolverOpts,
+    resolution: &mut Resolution,
+) -> Option<(IpAddr, DnsPacket)> {
+    let (results, responses) = mpsc::channel();
+    let (start_now, head_start) = mpsc::channel::<()>();
+    let mut head_start = Some(head_start);
+    for server in [first, second] {
+        if let Err(e) = resolution.count_query() {
+            attempts.server_failed(server, e);
+            continue;
+        }
+        let wait = if server == second {
+            head_start.take()
+        } else {
+            None
+        };
+        let question = question.to_owned();
+        let addr = SocketAddr::new(server, hints.port);
+        let latencies = hints.latencies.clone();
+        let opts = opts.to_owned();
+        let results = results.clone();
+        thread::spawn(move || {
+            // Hanging up without a word means the first server answered
+            if let Some(wait) = wait {
+                if let Err(RecvTimeoutError::Disconnected) = wait.recv_timeout(RACE_DELAY) {
+                    return;
+                }
             }
-            Some(ip) => {
-                ns = ip;
+            let response = ask_authority(&question, addr, &latencies, &opts);
+            let _ = results.send((server, response));
+        });
+    }
+    drop(results);
+    let mut start_now = Some(start_now);
+    for (server, response) in responses {
+        let addr = SocketAddr::new(server, hints.port);
+        let zone = &attempts.zone;
+        match response.and_then(|response| usable(response, question, zone, addr, resolution)) {
+            Ok(response) => return Some((server, response)),
+            Err(e) => {
+                attempts.server_failed(server, e);
+                if let Some(start_now) = start_now.take() {
+                    let _ = start_now.send(());
+                }
             }
         }
     }
+    None
+}
+
+fn ask_usable(
+    question: &DnsQuestion,
+    zone: &[String],
+    ns: IpAddr,
+    hints: &RootHints,
+    opts: &ResolverOpts,
+    resolution: &mut Resolution,
+) -> Result<DnsPacket, ResolveError> {
+    resolution.count_query()?;
+    let addr = SocketAddr::new(ns, hints.port);
+    let response = ask_authority(question, addr, &hints.latencies, opts)?;
+    usable(response, question, zone, addr, resolution)
+}
+
+// The response from `server`, one of `zone`'s, if it's worth going on with: an answer, a
+// referral further down, NODATA, or NXDOMAIN. Any other rcode, or a lame response, is the server
+// failing, and is taken as an error so that the next server gets a chance. Failing servers can
+// still name an agent to report errors to, so that's kept track of either way.
+fn usable(
+    response: DnsPacket,
+    question: &DnsQuestion,
+    zone: &[String],
+    server: SocketAddr,
+    resolution: &mut Resolution,
+) -> Result<DnsPacket, ResolveError> {
+    if let Some(named) = report_channel(&response) {
+        resolution.agent = Some(named.to_vec());
+    }
+    if !matches!(response.flags.rcode, DnsRCode::NoError | DnsRCode::NXDomain) {
+        return Err(ResolveError::ServerFailure(server, response.flags.rcode));
+    }
+    match lame(&response, question, zone) {
+        Some(e) => Err(e),
+        None => Ok(response),
+    }
 }
 
-fn handle_answers(mut response: DnsPacket) -> Result<DnsPacket, Box<dyn Error>> {
+fn ask_authority(
+    question: &DnsQuestion,
+    addr: SocketAddr,
+    latencies: &ServerLatencies,
+    opts: &ResolverOpts,
+) -> Result<DnsPacket, ResolveError> {
+    log!(
+        "Asking authority at {:?} question: {:?}",
+        addr.ip(),
+        question
+    );
+    let mut span = telemetry::span("dns.delegation_step", SpanKind::Internal);
+    span.attribute("server.address", addr.ip().to_string());
+    span.question(question);
+    let started = Instant::now();
+    let response = query_nameserver(question, addr, opts);
+    latencies.record(addr, response.as_ref().ok().map(|_| started.elapsed()));
+    let response = response?;
+    log!("Got response from authority: {:?}", response);
+    if response.answers.is_empty() {
+        span.attribute("dns.referral", true);
+    }
+    Ok(response)
+}
+
+fn handle_answers(
+    mut response: DnsPacket,
+    hints: &RootHints,
+    opts: &ResolverOpts,
+    resolution: &mut Resolution,
+) -> Result<DnsPacket, ResolveError> {
     // If our answers have a CNAME, we have to (recursively) go lookup the CNAME too. If it has
     // multiple CNAMEs, or a CNAME and other records, it's breaking the spec; we'll just ignore
     // that case right now, though we might want to return a FORMERR or something?
@@ -86,15 +694,22 @@ fn handle_answers(mut response: DnsPacket) -> Result<DnsPacket, Box<dyn Error>>
                     qclass: response.questions[0].qclass,
                     qtype: response.questions[0].qtype,
                 };
-                // Note that resolve_question calls this function, so if our reply has another
+                // Note that resolve_within calls this function, so if our reply has another
                 // CNAME in it, that will be handled before it's returned back to us
-                let reply = resolve_question(&question)?;
+                let reply = resolve_within(&question, hints, opts, resolution)?;
 
-                // We add the answers, nameservers, and additional records from the CNAME reply to
-                // our original answer, but we don't change the question
+                // We add the answers and additional records from the CNAME reply to our original
+                // answer, but we don't change the question. The rcode and authority section are
+                // about the end of the chain (RFC 6604), so a target that doesn't exist makes the
+                // whole answer NXDOMAIN, with the target zone's SOA. Servers often repeat the
+                // chain so far in their replies, so the records are merged into RRsets rather
+                // than added twice.
                 response.answers.extend(reply.answers);
-                response.nameservers.extend(reply.nameservers);
+                response.answers = merge_rrsets(std::mem::take(&mut response.answers));
                 response.addl_recs.extend(reply.addl_recs);
+                response.addl_recs = merge_rrsets(std::mem::take(&mut response.addl_recs));
+                response.flags.rcode = reply.flags.rcode;
+                response.nameservers = reply.nameservers;
             }
             _ => (),
         }
@@ -102,75 +717,143 @@ fn handle_answers(mut response: DnsPacket) -> Result<DnsPacket, Box<dyn Error>>
     Ok(response)
 }
 
-fn find_glue_record_for_ns(
+// The addresses the additional section gives for the nameserver an NS record names. Only glue
+// inside the zone being referred to is taken; the server sending the referral has no say over
+// where names anywhere else are, so those get looked up like any other.
+fn glue_addresses(
     ns: &DnsResourceRecord,
-    records: &Vec<DnsResourceRecord>,
-) -> Option<IpAddr> {
+    zone: &[String],
+    records: &[DnsResourceRecord],
+) -> Vec<IpAddr> {
     let ns_name = match &ns.record {
         DnsRecordData::NS(name) => name,
         _ => panic!("NS record data is not stored properly"),
     };
-
-    for rr in records {
-        if &rr.name == ns_name {
-            match rr.record {
-                DnsRecordData::A(ip_addr) => return Some(IpAddr::V4(ip_addr)),
-                _ => (),
-            }
-        }
-    }
-    return None;
+    records
+        .iter()
+        .filter(|rr| names_equal(&rr.name, ns_name) && is_subdomain(&rr.name, zone))
+        .filter_map(|rr| match rr.record {
+            DnsRecordData::A(ip_addr) => Some(IpAddr::V4(ip_addr)),
+            DnsRecordData::AAAA(ip_addr) => Some(IpAddr::V6(ip_addr)),
+            _ => None,
+        })
+        .collect()
 }
 
-fn get_nameserver_address(ns: &DnsResourceRecord) -> Result<IpAddr, Box<dyn Error>> {
-    // TODO(dylan): We should detect an infinite loop being caused by a missing glue record. This
-    // can happen if we're asked to talk to, for instance, "ns.example.com" to find out where
-    // "example.com" is. We'll keep repeating the same NS lookup over and over.
+// The addresses of a nameserver that came without glue. They're looked up in the family we'd
+// rather use first, and only in the other if there are none; racing takes both.
+fn nameserver_addresses(
+    ns: &DnsResourceRecord,
+    hints: &RootHints,
+    opts: &ResolverOpts,
+    resolution: &mut Resolution,
+) -> Result<Vec<IpAddr>, ResolveError> {
+    // Being asked to talk to, for instance, "ns.example.com" to find out where "example.com" is
+    // would have us repeating the same lookup over and over; resolve_within catches that
     let ns_name = match &ns.record {
         DnsRecordData::NS(name) => name,
         _ => panic!("NS record data is not stored properly"),
     };
-    let question = DnsQuestion {
-        // Again, label copying seems inefficient
-        qname: ns_name.to_owned(),
-        // Again, hardcoding IPv4
-        qtype: DnsRRType::A,
-        qclass: DnsClass::IN,
+    let families = match opts.address_preference {
+        AddressPreference::Ipv4 => [DnsRRType::A, DnsRRType::AAAA],
+        AddressPreference::Ipv6 | AddressPreference::Race => [DnsRRType::AAAA, DnsRRType::A],
     };
-    // XXX this is definitely not a production server without loop detection
-    let result = resolve_question(&question)?;
-    for answer in &result.answers {
-        if answer.rr_type == DnsRRType::A {
-            match answer.record {
-                DnsRecordData::A(addr) => return Ok(IpAddr::V4(addr)),
-                _ => continue,
+    let mut addresses = Vec::new();
+    let mut last_error = None;
+    for qtype in families {
+        if !addresses.is_empty() && opts.address_preference != AddressPreference::Race {
+            break;
+        }
+        let question = DnsQuestion {
+            // Again, label copying seems inefficient
+            qname: ns_name.to_owned(),
+            qtype,
+            qclass: DnsClass::IN,
+        };
+        match resolve_within(&question, hints, opts, resolution) {
+            Ok(result) => {
+                addresses.extend(result.answers.iter().filter_map(|rr| match rr.record {
+                    DnsRecordData::A(addr) => Some(IpAddr::V4(addr)),
+                    DnsRecordData::AAAA(addr) => Some(IpAddr::V6(addr)),
+                    _ => None,
+                }))
             }
+            Err(e) => last_error = Some(e),
         }
     }
-    return Err(format!(
-        "Got result without A records when doing nameserver lookup: {:?}",
-        result
-    )
-    .into());
+    if addresses.is_empty() {
+        return Err(last_error.unwrap_or_else(|| {
+            ResolveError::AllServersFailed(format!(
+                "No addresses for nameserver {}",
+                name_to_string(ns_name)
+            ))
+        }));
+    }
+    Ok(addresses)
+}
+
+// Sends a question to an upstream recursive resolver and returns its reply as is, instead of
+// doing the recursion ourselves
+pub fn forward_question(
+    question: &DnsQuestion,
+    upstream: SocketAddr,
+    opts: &ResolverOpts,
+) -> Result<DnsPacket, ResolveError> {
+    // An upstream that validates will only tell us so if we ask (RFC 6840 section 5.7)
+    let authentic_data = opts.dnssec == DnssecMode::TrustUpstream;
+    send_query(question, upstream, true, authentic_data, opts)
 }
 
 // Sends a query to an authoritative nameserver
-fn query_nameserver(question: &DnsQuestion, ns: IpAddr) -> Result<DnsPacket, Box<dyn Error>> {
-    // Construct the query
+fn query_nameserver(
+    question: &DnsQuestion,
+    ns: SocketAddr,
+    opts: &ResolverOpts,
+) -> Result<DnsPacket, ResolveError> {
+    // Authorities won't recurse for us anyway, so don't ask
+    send_query(question, ns, false, false, opts)
+}
+
+fn send_query(
+    question: &DnsQuestion,
+    ns: SocketAddr,
+    recursion_desired: bool,
+    authentic_data: bool,
+    opts: &ResolverOpts,
+) -> Result<DnsPacket, ResolveError> {
+    let mut span = telemetry::span("dns.send", SpanKind::Client);
+    span.attribute("server.address", ns.ip().to_string());
+    span.attribute("server.port", i64::from(ns.port()));
+    span.attribute("network.transport", "udp");
+    span.question(question);
+    let result = exchange(question, ns, recursion_desired, authentic_data, opts);
+    match &result {
+        Ok(response) => span.attribute("dns.response.code", format!("{:?}", response.flags.rcode)),
+        Err(e) => span.error(&e.to_string()),
+    }
+    result
+}
+
+// A query for `question` as we send it to other servers, over any transport
+pub fn query_packet(
+    question: &DnsQuestion,
+    recursion_desired: bool,
+    authentic_data: bool,
+    opts: &ResolverOpts,
+) -> DnsPacket {
     let flags = DnsFlags {
         qr_bit: false,
         opcode: DnsOpcode::Query,
         aa_bit: false,
         tc_bit: false,
-        rd_bit: false,
+        rd_bit: recursion_desired,
         ra_bit: false,
-        ad_bit: false,
+        ad_bit: authentic_data,
         cd_bit: false,
         rcode: DnsRCode::NoError,
     };
-    let packet = DnsPacket {
-        // TODO real arbitrary ID instead of just hardcoded one
-        id: 42,
+    let mut packet = DnsPacket {
+        id: random_id(),
         flags,
         // TODO is copying the question the right thing to do here? We don't _really_ need another
         // object, we could potentially refactor packet to write bytes from references. qname is a
@@ -180,38 +863,853 @@ fn query_nameserver(question: &DnsQuestion, ns: IpAddr) -> Result<DnsPacket, Box
         nameservers: vec![],
         addl_recs: vec![],
     };
+    // Without EDNS, replies are capped at 512 bytes, a validating upstream can't tell us why
+    // validation failed (RFC 8914), and authorities can't name an agent to report errors to
+    // (RFC 9567)
+    EdnsOptions::new(opts.edns_payload_size).attach(&mut packet);
+    packet
+}
+
+fn exchange(
+    question: &DnsQuestion,
+    ns: SocketAddr,
+    recursion_desired: bool,
+    authentic_data: bool,
+    opts: &ResolverOpts,
+) -> Result<DnsPacket, ResolveError> {
+    // Send the query, and again each time we go `opts.timeout` without a reply
+    let outstanding = opts.sockets.register(ns, &opts.outbound.binding(ns))?;
+    let mut packet = query_packet(question, recursion_desired, authentic_data, opts);
+    packet.id = outstanding.id();
+    let mut query_bytes = packet.to_bytes();
+    let mut attempts = 0;
+    while attempts < opts.attempts {
+        attempts += 1;
+        debug::log_packet(&format!("Sending query to {}", ns), &query_bytes);
+        outstanding.send(&query_bytes)?;
+        let reply = match receive_reply(&outstanding, &packet, ns, opts) {
+            Some(reply) => reply,
+            None => {
+                log!("No reply from {} after {}ms", ns, opts.timeout.as_millis());
+                continue;
+            }
+        };
+        // Process the reply. One that doesn't parse may have been spoofed or mangled on the way,
+        // neither of which is likely over TCP, so ask again there before giving up on the server.
+        return match reply {
+            // A server that doesn't speak EDNS may say the query is malformed rather than ignore
+            // the OPT record, so ask it again without one (RFC 6891 section 7). That doesn't
+            // count as an attempt, since it did answer.
+            Ok(reply)
+                if reply.flags.rcode == DnsRCode::FormError
+                    && !supports_edns(&reply)
+                    && supports_edns(&packet) =>
+            {
+                log!("{} doesn't speak EDNS; asking again without it", ns);
+                remove_opt(&mut packet);
+                query_bytes = packet.to_bytes();
+                attempts -= 1;
+                continue;
+            }
+            // The rest of the reply didn't fit in a UDP message, so ask for all of it over TCP
+            // (RFC 7766 section 5)
+            Ok(reply) if reply.flags.tc_bit => {
+                log!("Truncated reply from {}; retrying over TCP", ns);
+                exchange_tcp(&packet, ns, opts)
+            }
+            Ok(reply) => Ok(reply),
+            Err(e) => {
+                log!("Unparseable reply from {} ({}); retrying over TCP", ns, e);
+                exchange_tcp(&packet, ns, opts)
+            }
+        };
+    }
+    Err(ResolveError::Timeout(ns))
+}
 
-    // Send the query
-    let socket = UdpSocket::bind("0.0.0.0:0")?;
-    socket.connect((ns, 53))?;
-    socket.send(&packet.to_bytes())?;
-    let mut buf = [0; 2048];
-    let amt = socket.recv(&mut buf)?;
+// Wait out `opts.timeout` for a reply to `query`. The sockets only pass on replies with the
+// query's ID from the server it went to; ones that still aren't for this question are ignored
+// rather than taken as the answer, since that's what a spoofer who guessed right would send.
+fn receive_reply(
+    outstanding: &Outstanding,
+    query: &DnsPacket,
+    ns: SocketAddr,
+    opts: &ResolverOpts,
+) -> Option<Result<DnsPacket, DnsFormatError>> {
+    let sent = Instant::now();
+    loop {
+        let reply = outstanding.recv(opts.timeout.saturating_sub(sent.elapsed()))?;
+        debug::log_packet(&format!("Received reply from {}", ns), &reply);
+        match DnsPacket::from_bytes(&reply) {
+            Ok(reply) if !is_reply_to(&reply, query) => {
+                log!("Ignoring a reply from {} to some other question", ns)
+            }
+            reply => return Some(reply),
+        }
+    }
+}
 
-    // Process the reply
-    let reply = DnsPacket::from_bytes(&buf[..amt])?;
+// Whether `reply` has the ID and question of `query` (RFC 5452 section 9.1). Names are compared
+// ignoring case, since not every server keeps it. A FORMERR may come without the question, when
+// the server couldn't make sense of it.
+fn is_reply_to(reply: &DnsPacket, query: &DnsPacket) -> bool {
+    if reply.id != query.id {
+        return false;
+    }
+    if reply.questions.is_empty() && reply.flags.rcode == DnsRCode::FormError {
+        return true;
+    }
+    reply.questions.len() == query.questions.len()
+        && reply
+            .questions
+            .iter()
+            .zip(&query.questions)
+            .all(|(answered, asked)| same_question(answered, asked))
+}
 
-    Ok(reply)
+// Send an already built query over TCP, which frames each message with its length
+fn exchange_tcp(
+    query: &DnsPacket,
+    ns: SocketAddr,
+    opts: &ResolverOpts,
+) -> Result<DnsPacket, ResolveError> {
+    let question = &query.questions[0];
+    let query_bytes = &query.to_bytes();
+    let mut span = telemetry::span("dns.send", SpanKind::Client);
+    span.attribute("server.address", ns.ip().to_string());
+    span.attribute("server.port", i64::from(ns.port()));
+    span.attribute("network.transport", "tcp");
+    span.question(question);
+    let result = (|| -> Result<DnsPacket, ResolveError> {
+        let mut stream = opts.outbound.binding(ns).connect(ns, opts.timeout)?;
+        stream.set_read_timeout(Some(opts.timeout))?;
+        let mut framed = (query_bytes.len() as u16).to_be_bytes().to_vec();
+        framed.extend_from_slice(query_bytes);
+        debug::log_packet(&format!("Sending query to {} over TCP", ns), query_bytes);
+        stream.write_all(&framed)?;
+        let mut length = [0u8; 2];
+        stream.read_exact(&mut length)?;
+        let mut reply = vec![0u8; u16::from_be_bytes(length) as usize];
+        stream.read_exact(&mut reply)?;
+        debug::log_packet(&format!("Received reply from {} over TCP", ns), &reply);
+        let reply = DnsPacket::from_bytes(&reply)?;
+        if !is_reply_to(&reply, query) {
+            return Err(ResolveError::Malformed(format!(
+                "{} answered some other query over TCP",
+                ns
+            )));
+        }
+        Ok(reply)
+    })();
+    match &result {
+        Ok(response) => span.attribute("dns.response.code", format!("{:?}", response.flags.rcode)),
+        Err(e) => span.error(&e.to_string()),
+    }
+    result
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    use std::net::{IpAddr, Ipv4Addr};
+    use std::net::{Ipv4Addr, Ipv6Addr};
 
-    use crate::dns::protocol;
+    use crate::dns::mock::{self, Behavior, MockNetwork, Script, Transport};
+    use crate::dns::protocol::{opt_record, SoaData};
+
+    const ROOT: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 2);
+    const COM: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 3);
+    const EXAMPLE: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 4);
+    const ANSWER: Ipv4Addr = Ipv4Addr::new(93, 184, 216, 34);
 
     #[test]
     fn test_ns_query() {
-        let question = protocol::DnsQuestion {
-            qname: vec!["google".to_owned(), "com".to_owned()],
-            qtype: protocol::DnsRRType::A,
-            qclass: protocol::DnsClass::IN,
-        };
-        // TODO not a great practice that this test requires a network connection
-        let ns = IpAddr::V4(Ipv4Addr::new(192, 203, 230, 10));
-        let packet = query_nameserver(&question, ns).expect("query should have worked");
-        println!("{:?}", packet);
+        let network = MockNetwork::new();
+        let server = network.serve(
+            ROOT,
+            Script::new().on(
+                "google.com",
+                Some(DnsRRType::A),
+                Behavior::Answer(vec![mock::a("google.com", ANSWER)]),
+            ),
+        );
+        let question = mock::question("google.com", DnsRRType::A);
+        let packet = query_nameserver(&question, server.addr, &ResolverOpts::default())
+            .expect("query should have worked");
+        assert_eq!(packet.answers, vec![mock::a("google.com", ANSWER)]);
+        assert_eq!(server.queries(), vec![(Transport::Udp, question)]);
+    }
+
+    #[test]
+    fn asks_again_without_edns() {
+        let network = MockNetwork::new();
+        let server = network.serve(
+            ROOT,
+            Script::new().otherwise(Behavior::NoEdns(Box::new(Behavior::Answer(vec![mock::a(
+                "old.test", ANSWER,
+            )])))),
+        );
+        let question = mock::question("old.test", DnsRRType::A);
+        // Being told FORMERR isn't a failed attempt
+        let opts = ResolverOpts {
+            attempts: 1,
+            ..ResolverOpts::default()
+        };
+        let packet = query_nameserver(&question, server.addr, &opts).unwrap();
+        assert_eq!(packet.answers, vec![mock::a("old.test", ANSWER)]);
+        assert_eq!(server.queries().len(), 2);
+        assert_eq!(
+            query_packet(&question, false, false, &opts)
+                .addl_recs
+                .iter()
+                .filter_map(EdnsOptions::from_record)
+                .map(|edns| edns.payload_size)
+                .collect::<Vec<_>>(),
+            vec![opts.edns_payload_size]
+        );
+    }
+
+    #[test]
+    fn replies_must_match_the_query() {
+        let question = mock::question("www.example.com", DnsRRType::A);
+        let opts = ResolverOpts::default();
+        let query = query_packet(&question, false, false, &opts);
+        // Not much of a test of randomness, but enough to catch a constant
+        let ids: Vec<u16> = (0..4)
+            .map(|_| query_packet(&question, false, false, &opts).id)
+            .collect();
+        assert!(ids.iter().any(|id| *id != ids[0]));
+
+        let mut reply = query.to_owned();
+        reply.flags.qr_bit = true;
+        reply.questions[0].qname = mock::labels("WWW.example.COM");
+        assert!(is_reply_to(&reply, &query));
+        reply.questions[0].qtype = DnsRRType::AAAA;
+        assert!(!is_reply_to(&reply, &query));
+        reply.questions.clear();
+        assert!(!is_reply_to(&reply, &query));
+        reply.flags.rcode = DnsRCode::FormError;
+        assert!(is_reply_to(&reply, &query));
+        reply.id = query.id.wrapping_add(1);
+        assert!(!is_reply_to(&reply, &query));
+    }
+
+    #[test]
+    fn resolves_through_delegations() {
+        let network = MockNetwork::new();
+        let root = network.serve(ROOT, Script::new().delegate("com", "a.nic.com", COM));
+        let com = network.serve(
+            COM,
+            Script::new().delegate("example.com", "ns.example.com", EXAMPLE),
+        );
+        let example = network.serve(
+            EXAMPLE,
+            Script::new().on(
+                "www.example.com",
+                Some(DnsRRType::A),
+                Behavior::Answer(vec![mock::a("www.example.com", ANSWER)]),
+            ),
+        );
+
+        let question = mock::question("www.example.com", DnsRRType::A);
+        let hints = network.hints(ROOT);
+        let result =
+            resolve_question(&question, &hints, &ResolverOpts::default()).expect("should resolve");
+        assert_eq!(result.answers, vec![mock::a("www.example.com", ANSWER)]);
+        for server in &[root, com, example] {
+            assert_eq!(
+                server.queries(),
+                vec![(Transport::Udp, question.to_owned())]
+            );
+        }
+        // Each authority's round trip was timed
+        let latencies = hints.latencies.snapshot();
+        assert_eq!(latencies.len(), 3);
+        assert!(latencies
+            .iter()
+            .all(|(_, histogram)| histogram.answered() == 1));
+    }
+
+    #[test]
+    fn starts_from_root_zone_mirror() {
+        let network = MockNetwork::new();
+        let soa = DnsResourceRecord {
+            name: Vec::new(),
+            rr_type: DnsRRType::SOA,
+            class: DnsClass::IN,
+            ttl: 86400,
+            record: DnsRecordData::Other(vec![0; 22]),
+        };
+        let zone = vec![
+            soa.to_owned(),
+            mock::ns("com", "a.nic.com"),
+            mock::a("a.nic.com", COM),
+            soa,
+        ];
+        let root = network.serve(
+            ROOT,
+            Script::new().on("", Some(DnsRRType::AXF), Behavior::Answer(zone)),
+        );
+        let _com = network.serve(
+            COM,
+            Script::new().on(
+                "example.com",
+                Some(DnsRRType::A),
+                Behavior::Answer(vec![mock::a("example.com", ANSWER)]),
+            ),
+        );
+        let opts = ResolverOpts::default();
+        let records = mirror::transfer(&[], root.addr, &opts).unwrap();
+        let mirror = RootMirror::new();
+        mirror.load(records);
+        let hints = RootHints {
+            mirror: Some(Arc::new(mirror)),
+            ..network.hints(ROOT)
+        };
+
+        let question = mock::question("example.com", DnsRRType::A);
+        let result = resolve_question(&question, &hints, &opts).expect("should resolve");
+        assert_eq!(result.answers, vec![mock::a("example.com", ANSWER)]);
+        // The root was only asked for its zone
+        assert_eq!(root.queries().len(), 1);
+        assert_eq!(root.queries()[0].0, Transport::Tcp);
+    }
+
+    #[test]
+    fn starts_from_stub_zones() {
+        let network = MockNetwork::new();
+        let root = network.serve(ROOT, Script::new());
+        // The first stub server is down, and the zone delegates further down
+        let down = network.serve(COM, Script::new().otherwise(Behavior::Silent));
+        let internal = network.serve(
+            EXAMPLE,
+            Script::new().delegate("dev.internal.example", "ns.dev.internal.example", ROOT),
+        );
+        let stub: StubZone = "internal.example=127.0.0.3,127.0.0.4".parse().unwrap();
+        let mut hints = network.hints(ROOT);
+        hints.add_stub_zone(stub.to_owned());
+        hints.add_stub_zone("other.example=127.0.0.5".parse().unwrap());
+        assert!(hints.stub_zone_for(&mock::labels("example")).is_none());
+        assert_eq!(
+            hints.stub_zone_for(&mock::labels("a.INTERNAL.example")),
+            Some(&stub)
+        );
+        assert!("internal.example".parse::<StubZone>().is_err());
+        assert!("internal.example=ns1".parse::<StubZone>().is_err());
+
+        let opts = ResolverOpts {
+            timeout: Duration::from_millis(50),
+            attempts: 1,
+            ..ResolverOpts::default()
+        };
+        let question = mock::question("www.dev.internal.example", DnsRRType::A);
+        // The mock at ROOT refuses, so this fails, but only after following the delegation
+        assert!(resolve_question(&question, &hints, &opts).is_err());
+        assert_eq!(down.queries().len(), 1);
+        assert_eq!(internal.queries().len(), 1);
+        assert_eq!(root.queries(), vec![(Transport::Udp, question)]);
+    }
+
+    #[test]
+    fn tries_other_roots() {
+        let network = MockNetwork::new();
+        let silent = network.serve(ROOT, Script::new().otherwise(Behavior::Silent));
+        let root = network.serve(
+            COM,
+            Script::new().otherwise(Behavior::Answer(vec![mock::a("example.com", ANSWER)])),
+        );
+        let hints = RootHints {
+            roots: Arc::new(RwLock::new(vec![IpAddr::V4(ROOT), IpAddr::V4(COM)])),
+            ..network.hints(ROOT)
+        };
+        let opts = ResolverOpts {
+            timeout: Duration::from_millis(50),
+            attempts: 2,
+            ..ResolverOpts::default()
+        };
+        let question = mock::question("example.com", DnsRRType::A);
+        let result = resolve_question(&question, &hints, &opts).expect("should resolve");
+        assert_eq!(result.answers, vec![mock::a("example.com", ANSWER)]);
+        // The silent root was given every attempt before moving on
+        assert_eq!(silent.queries().len(), 2);
+        assert_eq!(root.queries().len(), 1);
+    }
+
+    #[test]
+    fn tries_every_nameserver_in_a_referral() {
+        const LAME: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 5);
+        let network = MockNetwork::new();
+        let _root = network.serve(
+            ROOT,
+            Script::new()
+                .under(
+                    "example.com",
+                    Behavior::Referral(
+                        vec![
+                            mock::ns("example.com", "ns.elsewhere.test"),
+                            mock::ns("example.com", "ns0.example.com"),
+                            mock::ns("example.com", "ns1.example.com"),
+                            mock::ns("example.com", "ns2.example.com"),
+                            mock::ns("example.com", "ns3.example.com"),
+                        ],
+                        vec![
+                            mock::a("ns0.example.com", LAME),
+                            mock::a("ns1.example.com", COM),
+                            mock::a("ns2.example.com", COM),
+                            mock::a("ns3.example.com", EXAMPLE),
+                        ],
+                    ),
+                )
+                .under(
+                    "example.org",
+                    Behavior::Referral(
+                        vec![
+                            mock::ns("example.org", "ns1.example.org"),
+                            mock::ns("example.org", "ns.elsewhere.test"),
+                        ],
+                        vec![mock::a("ns1.example.org", COM)],
+                    ),
+                )
+                .under("elsewhere.test", Behavior::Rcode(DnsRCode::ServFail)),
+        );
+        let broken = network.serve(
+            COM,
+            Script::new().otherwise(Behavior::Rcode(DnsRCode::ServFail)),
+        );
+        // Lame: not serving example.com after all, and referring us back up to com
+        let lame = network.serve(
+            LAME,
+            Script::new().otherwise(Behavior::Referral(
+                vec![mock::ns("com", "a.nic.com")],
+                vec![],
+            )),
+        );
+        let example = network.serve(
+            EXAMPLE,
+            Script::new().otherwise(Behavior::Answer(vec![mock::a("example.com", ANSWER)])),
+        );
+        let hints = network.hints(ROOT);
+        let question = mock::question("example.com", DnsRRType::A);
+        let result = resolve_question(&question, &hints, &ResolverOpts::default()).unwrap();
+        assert_eq!(result.answers, vec![mock::a("example.com", ANSWER)]);
+        // The glueless server is left for last, a server listed twice is only asked once, and a
+        // lame server is passed over like a failing one
+        assert_eq!(lame.queries().len(), 1);
+        assert_eq!(broken.queries().len(), 1);
+        assert_eq!(example.queries().len(), 1);
+        // The one that answered is where the next question about the zone starts
+        let delegation = hints.delegations.closest(&question.qname, Instant::now());
+        assert_eq!(delegation.unwrap().server, IpAddr::V4(EXAMPLE));
+
+        // A zone whose servers all fail can't be resolved
+        let question = mock::question("example.org", DnsRRType::A);
+        let error = resolve_question(&question, &hints, &ResolverOpts::default()).unwrap_err();
+        assert!(matches!(error, ResolveError::AllServersFailed(_)));
+        assert_eq!(broken.queries().len(), 2);
+    }
+
+    #[test]
+    fn reaches_nameservers_over_either_family() {
+        let network = MockNetwork::new();
+        let _root = network.serve(
+            ROOT,
+            Script::new().under(
+                "example.com",
+                Behavior::Referral(
+                    vec![mock::ns("example.com", "ns.example.com")],
+                    vec![
+                        mock::a("ns.example.com", COM),
+                        mock::aaaa("ns.example.com", Ipv6Addr::LOCALHOST),
+                    ],
+                ),
+            ),
+        );
+        let silent = network.serve(COM, Script::new().otherwise(Behavior::Silent));
+        let v6 = network.serve(
+            Ipv6Addr::LOCALHOST,
+            Script::new().otherwise(Behavior::Answer(vec![mock::a("example.com", ANSWER)])),
+        );
+        let question = mock::question("example.com", DnsRRType::A);
+        let answered = |preference| {
+            let opts = ResolverOpts {
+                timeout: Duration::from_millis(50),
+                attempts: 1,
+                address_preference: preference,
+                ..ResolverOpts::default()
+            };
+            let result = resolve_question(&question, &network.hints(ROOT), &opts).unwrap();
+            assert_eq!(result.answers, vec![mock::a("example.com", ANSWER)]);
+        };
+
+        // The IPv6 address is there to fall back on
+        answered(AddressPreference::Ipv4);
+        assert_eq!((silent.queries().len(), v6.queries().len()), (1, 1));
+        // Racing, IPv4 only gets asked if IPv6 is slow to answer
+        answered(AddressPreference::Race);
+        thread::sleep(RACE_DELAY * 2);
+        assert_eq!((silent.queries().len(), v6.queries().len()), (1, 2));
+    }
+
+    #[test]
+    fn only_takes_referrals_down() {
+        let network = MockNetwork::new();
+        let _root = network.serve(ROOT, Script::new().delegate("com", "a.nic.com", COM));
+        let _com = network.serve(
+            COM,
+            Script::new().delegate("evil.com", "ns.evil.com", EXAMPLE),
+        );
+        // A server for evil.com claiming to know where com is
+        let evil = network.serve(
+            EXAMPLE,
+            Script::new()
+                .on(
+                    "ok.evil.com",
+                    None,
+                    Behavior::Answer(vec![mock::a("ok.evil.com", ANSWER)]),
+                )
+                .otherwise(Behavior::Referral(
+                    vec![mock::ns("com", "ns.evil.com")],
+                    vec![mock::a("ns.evil.com", EXAMPLE)],
+                )),
+        );
+        let hints = network.hints(ROOT);
+        let opts = ResolverOpts::default();
+        resolve_question(&mock::question("ok.evil.com", DnsRRType::A), &hints, &opts).unwrap();
+
+        // Starting from evil.com's cached delegation, the referral up to com isn't followed. The
+        // server counts as failing, so it's asked once more on the way down from the root.
+        let question = mock::question("www.evil.com", DnsRRType::A);
+        let error = resolve_question(&question, &hints, &opts).unwrap_err();
+        assert!(matches!(error, ResolveError::Malformed(_)), "{}", error);
+        assert_eq!(evil.queries().len(), 3);
+        let com = hints
+            .delegations
+            .closest(&mock::labels("www.example.com"), Instant::now())
+            .unwrap();
+        assert_eq!(
+            (com.zone, com.server),
+            (mock::labels("com"), IpAddr::V4(COM))
+        );
+
+        // Nor is glue for names outside the zone being referred to
+        let ns = mock::ns("evil.com", "ns.example.net");
+        let glue = [mock::a("ns.example.net", EXAMPLE)];
+        assert!(glue_addresses(&ns, &mock::labels("evil.com"), &glue).is_empty());
+        assert_eq!(
+            glue_addresses(&ns, &mock::labels("net"), &glue),
+            vec![IpAddr::V4(EXAMPLE)]
+        );
+    }
+
+    #[test]
+    fn stops_going_in_circles() {
+        let network = MockNetwork::new();
+        let root = network.serve(
+            ROOT,
+            Script::new()
+                // Only ns.example.com knows where ns.example.com is
+                .under(
+                    "example.com",
+                    Behavior::Referral(vec![mock::ns("example.com", "ns.example.com")], vec![]),
+                )
+                .on(
+                    "a.test",
+                    None,
+                    Behavior::Answer(vec![mock::cname("a.test", "b.test")]),
+                )
+                .on(
+                    "b.test",
+                    None,
+                    Behavior::Answer(vec![mock::cname("b.test", "A.test")]),
+                )
+                .delegate("loop.test", "ns.loop.test", COM),
+        );
+        let _com = network.serve(
+            COM,
+            Script::new().delegate("loop.test", "ns.loop.test", COM),
+        );
+        let hints = network.hints(ROOT);
+        let opts = ResolverOpts::default();
+        for name in ["www.example.com", "a.test", "www.loop.test"] {
+            let question = mock::question(name, DnsRRType::A);
+            let error = resolve_question(&question, &hints, &opts).unwrap_err();
+            assert!(
+                matches!(error, ResolveError::Loop(_)),
+                "{}: {}",
+                name,
+                error
+            );
+            assert_eq!(error.rcode(), Some(DnsRCode::ServFail));
+        }
+        // Each went round once before it was noticed, though the nameserver's address is looked
+        // for in both families
+        assert_eq!(root.queries().len(), 8);
+    }
+
+    #[test]
+    fn follows_cnames() {
+        let network = MockNetwork::new();
+        let _root = network.serve(
+            ROOT,
+            Script::new()
+                .on(
+                    "www.example.com",
+                    None,
+                    Behavior::Answer(vec![mock::cname("www.example.com", "web.example.com")]),
+                )
+                .on(
+                    "web.example.com",
+                    Some(DnsRRType::A),
+                    // Repeating the CNAME, and the address with two TTLs
+                    Behavior::Answer(vec![
+                        mock::cname("www.example.com", "web.example.com"),
+                        DnsResourceRecord {
+                            ttl: 60,
+                            ..mock::a("web.example.com", ANSWER)
+                        },
+                        mock::a("web.example.com", ANSWER),
+                    ]),
+                ),
+        );
+
+        let question = mock::question("www.example.com", DnsRRType::A);
+        let result = resolve_question(&question, &network.hints(ROOT), &ResolverOpts::default())
+            .expect("should resolve");
+        assert_eq!(
+            result.answers,
+            vec![
+                mock::cname("www.example.com", "web.example.com"),
+                DnsResourceRecord {
+                    ttl: 60,
+                    ..mock::a("web.example.com", ANSWER)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn looks_up_nameservers_without_glue() {
+        let network = MockNetwork::new();
+        // The root refers us to a nameserver without giving us its address, so we have to go
+        // resolve it (from the root again) before we can continue
+        let _root = network.serve(
+            ROOT,
+            Script::new()
+                .on(
+                    "ns.other.test",
+                    Some(DnsRRType::A),
+                    Behavior::Answer(vec![mock::a("ns.other.test", EXAMPLE)]),
+                )
+                .under(
+                    "example.com",
+                    Behavior::Referral(vec![mock::ns("example.com", "ns.other.test")], vec![]),
+                ),
+        );
+        let _example = network.serve(
+            EXAMPLE,
+            Script::new().on(
+                "example.com",
+                Some(DnsRRType::A),
+                Behavior::Answer(vec![mock::a("example.com", ANSWER)]),
+            ),
+        );
+
+        let question = mock::question("example.com", DnsRRType::A);
+        let result = resolve_question(&question, &network.hints(ROOT), &ResolverOpts::default())
+            .expect("should resolve");
+        assert_eq!(result.answers, vec![mock::a("example.com", ANSWER)]);
+    }
+
+    #[test]
+    fn matches_glue_regardless_of_case() {
+        let network = MockNetwork::new();
+        // If the glue didn't match the NS record, we'd try to look up the nameserver's address
+        // from the root, which would just refer us back again
+        let _root = network.serve(
+            ROOT,
+            Script::new().under(
+                "example.com",
+                Behavior::Referral(
+                    vec![mock::ns("example.com", "ns.example.com")],
+                    vec![mock::a("NS.Example.COM", EXAMPLE)],
+                ),
+            ),
+        );
+        let _example = network.serve(
+            EXAMPLE,
+            Script::new().otherwise(Behavior::Answer(vec![mock::a("example.com", ANSWER)])),
+        );
+
+        let question = mock::question("example.com", DnsRRType::A);
+        let result = resolve_question(&question, &network.hints(ROOT), &ResolverOpts::default())
+            .expect("should resolve");
+        assert_eq!(result.answers, vec![mock::a("example.com", ANSWER)]);
+    }
+
+    #[test]
+    fn passes_nxdomain_through() {
+        let network = MockNetwork::new();
+        let _root = network.serve(ROOT, Script::new().otherwise(Behavior::NXDomain(vec![])));
+
+        let question = mock::question("nope.test", DnsRRType::A);
+        let result = resolve_question(&question, &network.hints(ROOT), &ResolverOpts::default())
+            .expect("should resolve");
+        assert_eq!(result.flags.rcode, DnsRCode::NXDomain);
+    }
+
+    #[test]
+    fn negative_answers_keep_their_soa() {
+        let network = MockNetwork::new();
+        let soa = |zone: &str, minimum: u32| DnsResourceRecord {
+            name: mock::labels(zone),
+            rr_type: DnsRRType::SOA,
+            class: DnsClass::IN,
+            ttl: 300,
+            record: DnsRecordData::SOA(SoaData {
+                mname: mock::labels(&format!("ns.{}", zone)),
+                rname: mock::labels(&format!("hostmaster.{}", zone)),
+                serial: 1,
+                refresh: 3600,
+                retry: 600,
+                expire: 86400,
+                minimum,
+            }),
+        };
+        let _root = network.serve(
+            ROOT,
+            Script::new()
+                .on(
+                    "www.example.com",
+                    None,
+                    Behavior::Answer(vec![mock::cname("www.example.com", "gone.example.net")]),
+                )
+                .on(
+                    "gone.example.net",
+                    None,
+                    Behavior::NXDomain(vec![
+                        soa("example.net", 60),
+                        mock::ns("example.net", "ns.example.net"),
+                    ]),
+                )
+                .otherwise(Behavior::NoData(vec![soa("example.com", 300)])),
+        );
+        let hints = network.hints(ROOT);
+        let opts = ResolverOpts::default();
+
+        let question = mock::question("example.com", DnsRRType::AAAA);
+        let nodata = resolve_question(&question, &hints, &opts).expect("should resolve");
+        assert_eq!(nodata.flags.rcode, DnsRCode::NoError);
+        assert!(nodata.answers.is_empty());
+        assert_eq!(nodata.nameservers, vec![soa("example.com", 300)]);
+        assert!(!nodata.flags.aa_bit);
+
+        // The end of a CNAME chain decides the rcode and the SOA
+        let question = mock::question("www.example.com", DnsRRType::A);
+        let chased = resolve_question(&question, &hints, &opts).expect("should resolve");
+        assert_eq!(chased.flags.rcode, DnsRCode::NXDomain);
+        assert_eq!(chased.answers.len(), 1);
+        // Only the SOA, and only for as long as the zone's negative TTL
+        let mut negative = soa("example.net", 60);
+        negative.ttl = 60;
+        assert_eq!(chased.nameservers, vec![negative]);
+
+        let mut referral = nodata;
+        referral.addl_recs = vec![mock::a("ns.example.com", ANSWER), opt_record(1232)];
+        assert_eq!(negative_answer(referral).addl_recs, vec![opt_record(1232)]);
+    }
+
+    #[test]
+    fn waits_for_slow_servers() {
+        let network = MockNetwork::new();
+        let _root = network.serve(
+            ROOT,
+            Script::new().otherwise(Behavior::Delay(
+                Duration::from_millis(200),
+                Box::new(Behavior::Answer(vec![mock::a("slow.test", ANSWER)])),
+            )),
+        );
+
+        let question = mock::question("slow.test", DnsRRType::A);
+        let result = resolve_question(&question, &network.hints(ROOT), &ResolverOpts::default())
+            .expect("should resolve");
+        assert_eq!(result.answers, vec![mock::a("slow.test", ANSWER)]);
+    }
+
+    #[test]
+    fn fails_on_broken_servers() {
+        let network = MockNetwork::new();
+        let _root = network.serve(
+            ROOT,
+            Script::new()
+                .under("refused.test", Behavior::Rcode(DnsRCode::Refused))
+                .under("lame.test", Behavior::Lame),
+        );
+
+        let hints = network.hints(ROOT);
+        let opts = ResolverOpts::default();
+        for name in &["refused.test", "lame.test"] {
+            let question = mock::question(name, DnsRRType::A);
+            assert!(
+                resolve_question(&question, &hints, &opts).is_err(),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn retries_garbled_and_truncated_replies_over_tcp() {
+        let network = MockNetwork::new();
+        let root = network.serve(
+            ROOT,
+            Script::new()
+                .under(
+                    "garbled.test",
+                    Behavior::Garbled(Box::new(Behavior::Answer(vec![mock::a(
+                        "garbled.test",
+                        ANSWER,
+                    )]))),
+                )
+                .under(
+                    "truncated.test",
+                    Behavior::Truncated(Box::new(Behavior::Answer(vec![mock::a(
+                        "truncated.test",
+                        ANSWER,
+                    )]))),
+                ),
+        );
+
+        for name in ["garbled.test", "truncated.test"] {
+            let question = mock::question(name, DnsRRType::A);
+            let result =
+                resolve_question(&question, &network.hints(ROOT), &ResolverOpts::default())
+                    .expect("should resolve over TCP");
+            assert_eq!(result.answers, vec![mock::a(name, ANSWER)]);
+        }
+        let transports: Vec<Transport> = root.queries().iter().map(|(t, _)| *t).collect();
+        assert_eq!(
+            transports,
+            vec![
+                Transport::Udp,
+                Transport::Tcp,
+                Transport::Udp,
+                Transport::Tcp
+            ]
+        );
+    }
+
+    #[test]
+    fn retries_then_gives_up_on_silent_servers() {
+        let network = MockNetwork::new();
+        let root = network.serve(ROOT, Script::new().otherwise(Behavior::Silent));
+        let opts = ResolverOpts {
+            timeout: Duration::from_millis(100),
+            attempts: 3,
+            ..ResolverOpts::default()
+        };
+
+        let question = mock::question("silent.test", DnsRRType::A);
+        assert!(resolve_question(&question, &network.hints(ROOT), &opts).is_err());
+        assert_eq!(root.queries().len(), 3);
     }
 }
@@ -1,11 +1,236 @@
-use std::net::{IpAddr, Ipv4Addr};
-
-// For now, this is a hardcoded list of A and AAAA records for the root nameservers
-// Information from https://www.iana.org/domains/root/servers
-// TODO pull this from configuration or directly from the OS
-pub fn get_root_nameserver() -> IpAddr {
-    // This is the A record for e.root-servers.net operated by NASA (Ames Research Center)
-    // TODO this should support V6 addresses
-    // TODO this should support returning any root nameserver
-    IpAddr::V4(Ipv4Addr::new(192, 203, 230, 10))
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+
+use super::super::config::AddressPreference;
+use super::super::protocol::{
+    name_from_string, names_equal, DnsClass, DnsRRType, DnsRecordData, DnsResourceRecord,
+};
+
+// What the root zone gives its own NS records and their addresses
+const ROOT_TTL: u32 = 518400;
+
+// The root servers' names and addresses, from the root hints file IANA publishes
+// (https://www.iana.org/domains/root/files, last changed when b.root-servers.net was renumbered in
+// November 2023). Operators hardly ever move them, and a stale address only matters if every
+// other root is unreachable too. A newer hints file can be given with --root-hints.
+pub const ROOT_SERVERS: [RootServer; 13] = [
+    root_server(
+        "a.root-servers.net",
+        [198, 41, 0, 4],
+        [0x2001, 0x503, 0xba3e, 0, 0, 0, 0x2, 0x30],
+    ),
+    root_server(
+        "b.root-servers.net",
+        [170, 247, 170, 2],
+        [0x2801, 0x1b8, 0x10, 0, 0, 0, 0, 0xb],
+    ),
+    root_server(
+        "c.root-servers.net",
+        [192, 33, 4, 12],
+        [0x2001, 0x500, 0x2, 0, 0, 0, 0, 0xc],
+    ),
+    root_server(
+        "d.root-servers.net",
+        [199, 7, 91, 13],
+        [0x2001, 0x500, 0x2d, 0, 0, 0, 0, 0xd],
+    ),
+    root_server(
+        "e.root-servers.net",
+        [192, 203, 230, 10],
+        [0x2001, 0x500, 0xa8, 0, 0, 0, 0, 0xe],
+    ),
+    root_server(
+        "f.root-servers.net",
+        [192, 5, 5, 241],
+        [0x2001, 0x500, 0x2f, 0, 0, 0, 0, 0xf],
+    ),
+    root_server(
+        "g.root-servers.net",
+        [192, 112, 36, 4],
+        [0x2001, 0x500, 0x12, 0, 0, 0, 0, 0xd0d],
+    ),
+    root_server(
+        "h.root-servers.net",
+        [198, 97, 190, 53],
+        [0x2001, 0x500, 0x1, 0, 0, 0, 0, 0x53],
+    ),
+    root_server(
+        "i.root-servers.net",
+        [192, 36, 148, 17],
+        [0x2001, 0x7fe, 0, 0, 0, 0, 0, 0x53],
+    ),
+    root_server(
+        "j.root-servers.net",
+        [192, 58, 128, 30],
+        [0x2001, 0x503, 0xc27, 0, 0, 0, 0x2, 0x30],
+    ),
+    root_server(
+        "k.root-servers.net",
+        [193, 0, 14, 129],
+        [0x2001, 0x7fd, 0, 0, 0, 0, 0, 0x1],
+    ),
+    root_server(
+        "l.root-servers.net",
+        [199, 7, 83, 42],
+        [0x2001, 0x500, 0x9f, 0, 0, 0, 0, 0x42],
+    ),
+    root_server(
+        "m.root-servers.net",
+        [202, 12, 27, 33],
+        [0x2001, 0xdc3, 0, 0, 0, 0, 0, 0x35],
+    ),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RootServer {
+    pub name: &'static str,
+    pub v4: Ipv4Addr,
+    pub v6: Ipv6Addr,
+}
+
+impl RootServer {
+    // Both of the server's addresses, the one in the family we'd rather use first
+    pub fn addresses(&self, preference: AddressPreference) -> Vec<IpAddr> {
+        let mut addresses = vec![IpAddr::V4(self.v4), IpAddr::V6(self.v6)];
+        preference.sort(&mut addresses);
+        addresses
+    }
+}
+
+const fn root_server(name: &'static str, v4: [u8; 4], v6: [u16; 8]) -> RootServer {
+    RootServer {
+        name,
+        v4: Ipv4Addr::new(v4[0], v4[1], v4[2], v4[3]),
+        v6: Ipv6Addr::new(v6[0], v6[1], v6[2], v6[3], v6[4], v6[5], v6[6], v6[7]),
+    }
+}
+
+// Every root server's addresses, in the order they're lettered, with each server's address in the
+// family we'd rather use ahead of its other one
+pub fn root_nameservers(preference: AddressPreference) -> Vec<IpAddr> {
+    ROOT_SERVERS
+        .iter()
+        .flat_map(|server| server.addresses(preference))
+        .collect()
+}
+
+// Every root server's addresses from a hints file (the root's NS records and their addresses, in
+// master file format like IANA's named.root), in the order of their NS records and the same order
+// as root_nameservers within each server
+pub fn hinted_roots(
+    records: &[DnsResourceRecord],
+    preference: AddressPreference,
+) -> Result<Vec<IpAddr>, String> {
+    let servers = records.iter().filter_map(|rr| match &rr.record {
+        DnsRecordData::NS(server) if rr.name.is_empty() => Some(server),
+        _ => None,
+    });
+    let mut roots = Vec::new();
+    for server in servers {
+        let mut addresses: Vec<IpAddr> = records
+            .iter()
+            .filter(|rr| names_equal(&rr.name, server))
+            .filter_map(|rr| match &rr.record {
+                DnsRecordData::A(v4) => Some(IpAddr::V4(*v4)),
+                DnsRecordData::AAAA(v6) => Some(IpAddr::V6(*v6)),
+                _ => None,
+            })
+            .collect();
+        preference.sort(&mut addresses);
+        roots.extend(addresses);
+    }
+    if roots.is_empty() {
+        return Err(String::from("No root server in the hints has an address"));
+    }
+    Ok(roots)
+}
+
+// A referral to the root: NS records for every root server, and their addresses to go with them
+pub fn root_referral() -> (Vec<DnsResourceRecord>, Vec<DnsResourceRecord>) {
+    let mut nameservers = Vec::new();
+    let mut addresses = Vec::new();
+    for server in ROOT_SERVERS.iter() {
+        let name = name_from_string(server.name).unwrap();
+        let record = |rr_type, record| DnsResourceRecord {
+            name: name.to_owned(),
+            rr_type,
+            class: DnsClass::IN,
+            ttl: ROOT_TTL,
+            record,
+        };
+        nameservers.push(DnsResourceRecord {
+            name: Vec::new(),
+            ..record(DnsRRType::NS, DnsRecordData::NS(name.to_owned()))
+        });
+        addresses.push(record(DnsRRType::A, DnsRecordData::A(server.v4)));
+        addresses.push(record(DnsRRType::AAAA, DnsRecordData::AAAA(server.v6)));
+    }
+    (nameservers, addresses)
+}
+
+// The family to reach the roots over when we haven't been told: IPv4 unless this host has no
+// route to it, but does have one over IPv6. Preferring IPv6 is up to whoever runs us.
+pub fn reachable_preference() -> AddressPreference {
+    let root = ROOT_SERVERS[0];
+    if !routable(IpAddr::V4(root.v4)) && routable(IpAddr::V6(root.v6)) {
+        AddressPreference::Ipv6
+    } else {
+        AddressPreference::Ipv4
+    }
+}
+
+// Connecting a UDP socket doesn't send anything, but it does fail when there's no route
+fn routable(addr: IpAddr) -> bool {
+    let local = match addr {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    UdpSocket::bind((local, 0))
+        .and_then(|socket| socket.connect(SocketAddr::new(addr, 53)))
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roots_come_in_both_families() {
+        let v4_first = root_nameservers(AddressPreference::Ipv4);
+        let v6_first = root_nameservers(AddressPreference::Ipv6);
+        assert_eq!(v4_first.len(), 26);
+        assert_eq!(v6_first, root_nameservers(AddressPreference::Race));
+        assert!(v4_first.iter().step_by(2).all(IpAddr::is_ipv4));
+        assert!(v6_first.iter().step_by(2).all(IpAddr::is_ipv6));
+        assert_eq!(ROOT_SERVERS[4].name, "e.root-servers.net");
+        assert_eq!(v4_first[8], "192.203.230.10".parse::<IpAddr>().unwrap());
+        assert_eq!(v4_first[9], "2001:500:a8::e".parse::<IpAddr>().unwrap());
+        assert_eq!(v6_first[2], "2801:1b8:10::b".parse::<IpAddr>().unwrap());
+        for (letter, server) in ('a'..='m').zip(ROOT_SERVERS.iter()) {
+            assert_eq!(server.name, format!("{}.root-servers.net", letter));
+        }
+
+        let (nameservers, addresses) = root_referral();
+        assert_eq!(nameservers.len(), 13);
+        assert!(nameservers.iter().all(|rr| rr.name.is_empty()));
+        assert_eq!(addresses.len(), 26);
+
+        // A hints file is the same records, so the referral makes one. Servers are taken in the
+        // order their NS records are in.
+        let mut hints = [&addresses[2..], &nameservers[1..]].concat();
+        assert_eq!(
+            hinted_roots(&hints, AddressPreference::Ipv4),
+            Ok(v4_first[2..].to_vec())
+        );
+        assert_eq!(
+            hinted_roots(&hints, AddressPreference::Ipv6),
+            Ok(v6_first[2..].to_vec())
+        );
+        hints.retain(|rr| rr.rr_type != DnsRRType::AAAA);
+        assert!(hinted_roots(&hints, AddressPreference::Ipv6)
+            .unwrap()
+            .iter()
+            .all(IpAddr::is_ipv4));
+        hints.retain(|rr| rr.rr_type == DnsRRType::NS);
+        assert!(hinted_roots(&hints, AddressPreference::Ipv4).is_err());
+    }
 }
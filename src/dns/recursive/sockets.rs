@@ -0,0 +1,234 @@
+// The UDP sockets queries to other servers go out on. Rather than binding a socket for every query
+// and closing it after, a handful are bound for each local address the first time they're needed
+// and kept for as long as the resolver is around. Each has a thread reading replies off it and
+// handing each one to whichever query is waiting on it, matched by transaction ID and the server
+// it came from; a reply nobody's waiting on, like a late one or a spoofed one, goes no further.
+// IDs are random, so someone off the path can't guess them (RFC 5452 section 4.3).
+//
+// The server answers each client query on a thread of its own, so these are shared by all of
+// them. Queries are spread over the sockets in turn, which keeps the ports someone would have to
+// guess to spoof a reply from being just the one.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, OnceLock, Weak};
+use std::thread;
+use std::time::Duration;
+
+use rustls::crypto::SecureRandom;
+
+use crate::dns::outbound::Binding;
+use crate::dns::trace::{error, log};
+
+const SOCKETS_PER_ADDRESS: usize = 4;
+
+// How often reading threads check whether their socket's still wanted
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Big enough for any reply, whatever payload size we advertised
+const MAX_REPLY_SIZE: usize = 65535;
+
+#[derive(Default)]
+pub struct UpstreamSockets {
+    // By what they're bound to
+    sockets: Mutex<HashMap<Binding, Vec<Arc<SharedSocket>>>>,
+    // Which socket the next query goes out on
+    next: AtomicUsize,
+}
+
+impl fmt::Debug for UpstreamSockets {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sockets = self.sockets.lock().unwrap();
+        write!(
+            f,
+            "UpstreamSockets({:?})",
+            sockets.keys().collect::<Vec<_>>()
+        )
+    }
+}
+
+// Where replies go, by the ID of the query and the server it was sent to
+type Waiting = HashMap<(u16, SocketAddr), mpsc::Sender<Vec<u8>>>;
+
+struct SharedSocket {
+    socket: UdpSocket,
+    waiting: Mutex<Waiting>,
+}
+
+// A query waiting on a reply, with the transaction ID it has to be sent with. It stops waiting
+// when dropped.
+pub struct Outstanding {
+    socket: Arc<SharedSocket>,
+    id: u16,
+    server: SocketAddr,
+    replies: mpsc::Receiver<Vec<u8>>,
+}
+
+impl UpstreamSockets {
+    // Start waiting on a reply from `server`, on one of the sockets with this binding
+    pub fn register(&self, server: SocketAddr, binding: &Binding) -> io::Result<Outstanding> {
+        let socket = self.socket(binding)?;
+        let (sender, replies) = mpsc::channel();
+        let mut waiting = socket.waiting.lock().unwrap();
+        // IDs only have to be unique among the queries waiting on the same server
+        let id = loop {
+            let id = random_id();
+            if !waiting.contains_key(&(id, server)) {
+                break id;
+            }
+        };
+        waiting.insert((id, server), sender);
+        drop(waiting);
+        Ok(Outstanding {
+            socket,
+            id,
+            server,
+            replies,
+        })
+    }
+
+    fn socket(&self, binding: &Binding) -> io::Result<Arc<SharedSocket>> {
+        let mut sockets = self.sockets.lock().unwrap();
+        let bound = sockets.entry(binding.to_owned()).or_default();
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % SOCKETS_PER_ADDRESS;
+        if let Some(socket) = bound.get(index) {
+            return Ok(socket.clone());
+        }
+        let socket = Arc::new(SharedSocket {
+            socket: binding.udp_socket()?,
+            waiting: Mutex::new(HashMap::new()),
+        });
+        let reader = socket.socket.try_clone()?;
+        reader.set_read_timeout(Some(POLL_INTERVAL))?;
+        let shared = Arc::downgrade(&socket);
+        thread::spawn(move || read_replies(reader, shared));
+        bound.push(socket.clone());
+        Ok(socket)
+    }
+}
+
+impl Outstanding {
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    pub fn send(&self, query: &[u8]) -> io::Result<()> {
+        self.socket.socket.send_to(query, self.server)?;
+        Ok(())
+    }
+
+    // The next reply, or None if there isn't one within `timeout`
+    pub fn recv(&self, timeout: Duration) -> Option<Vec<u8>> {
+        self.replies.recv_timeout(timeout).ok()
+    }
+}
+
+impl Drop for Outstanding {
+    fn drop(&mut self) {
+        self.socket
+            .waiting
+            .lock()
+            .unwrap()
+            .remove(&(self.id, self.server));
+    }
+}
+
+// A transaction ID from the system's secure random number generator
+pub fn random_id() -> u16 {
+    static RANDOM: OnceLock<&'static dyn SecureRandom> = OnceLock::new();
+    let random = RANDOM.get_or_init(|| rustls::crypto::ring::default_provider().secure_random);
+    let mut id = [0; 2];
+    // Only fails if the OS can't give us randomness at all, which we can't answer safely without
+    random
+        .fill(&mut id)
+        .expect("no random numbers for transaction IDs");
+    u16::from_be_bytes(id)
+}
+
+// Hand each reply on `socket` to the query waiting on it, for as long as the socket's in use
+fn read_replies(socket: UdpSocket, shared: Weak<SharedSocket>) {
+    let mut buf = vec![0; MAX_REPLY_SIZE];
+    loop {
+        let received = socket.recv_from(&mut buf);
+        let shared = match shared.upgrade() {
+            Some(shared) => shared,
+            None => return,
+        };
+        let (amt, from) = match received {
+            Ok(received) => received,
+            // Which of these a timeout shows up as depends on the platform
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                continue
+            }
+            Err(e) => {
+                error!("Error reading replies: {}", e);
+                continue;
+            }
+        };
+        if amt < 2 {
+            continue;
+        }
+        let id = u16::from_be_bytes([buf[0], buf[1]]);
+        let waiting = shared.waiting.lock().unwrap();
+        match waiting.get(&(id, from)) {
+            Some(sender) => {
+                // The query may have given up in the meantime, which is fine
+                let _ = sender.send(buf[..amt].to_vec());
+            }
+            None => log!("Dropping reply from {} with unexpected ID {}", from, id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn replies_reach_the_query_they_answer() {
+        let sockets = UpstreamSockets::default();
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let local = Binding {
+            address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            interface: None,
+        };
+
+        let first = sockets.register(addr, &local).unwrap();
+        let second = sockets.register(addr, &local).unwrap();
+        first.send(&first.id().to_be_bytes()).unwrap();
+        second.send(&second.id().to_be_bytes()).unwrap();
+        let mut buf = [0; 16];
+        let mut from = Vec::new();
+        for _ in 0..2 {
+            let (amt, client) = server.recv_from(&mut buf).unwrap();
+            from.push(client);
+            server.send_to(&buf[..amt], client).unwrap();
+        }
+        // Different sockets, so different ports
+        assert_ne!(from[0], from[1]);
+        let timeout = Duration::from_secs(5);
+        assert_eq!(first.recv(timeout).unwrap()[..2], first.id().to_be_bytes());
+        assert_eq!(
+            second.recv(timeout).unwrap()[..2],
+            second.id().to_be_bytes()
+        );
+
+        // A reply from anywhere but the server goes nowhere, even with the right ID
+        let third = sockets.register(addr, &local).unwrap();
+        third.send(&third.id().to_be_bytes()).unwrap();
+        let (_, client) = server.recv_from(&mut buf).unwrap();
+        let impostor = UdpSocket::bind("127.0.0.1:0").unwrap();
+        impostor.send_to(&third.id().to_be_bytes(), client).unwrap();
+        assert!(third.recv(Duration::from_millis(100)).is_none());
+        server.send_to(&third.id().to_be_bytes(), client).unwrap();
+        assert!(third.recv(timeout).is_some());
+    }
+}
@@ -0,0 +1,806 @@
+// A client-side API on top of the resolver: look up addresses, mail servers, text records, and
+// reverse names without dealing with packets. Lookups either run through our own recursive
+// resolver or are forwarded to an upstream recursive server.
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+
+use super::access::AddressPrefix;
+use super::cache::{Cache, CacheStats};
+use super::config::{ResolverConfig, ResolverOpts};
+use super::error::ResolveError;
+use super::latency::LatencyHistogram;
+use super::pipeline::flatten_cnames;
+use super::protocol::{
+    merge_rrsets, name_from_string, name_to_string, names_equal, DnsClass, DnsPacket, DnsQuestion,
+    DnsRCode, DnsRRType, DnsRecordData, DnsResourceRecord,
+};
+use super::recursive::{self, RootHints};
+use super::telemetry::{self, SpanKind};
+use super::trace::log;
+use super::upstream::{UpstreamStatus, Upstreams};
+use super::zone::{self, Zone};
+
+// ALIASes pointing at ALIASes are followed at most this far
+const MAX_ALIAS_DEPTH: usize = 8;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MxRecord {
+    pub preference: u16,
+    // Presentation format, without the trailing dot
+    pub exchange: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    // Presentation format, without the trailing dot
+    pub target: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct Resolver {
+    config: ResolverConfig,
+    opts: ResolverOpts,
+    // Shared between clones, so every thread the server hands a query to sees the same cache
+    // and upstream health
+    cache: Arc<Mutex<Cache>>,
+    upstreams: Arc<Upstreams>,
+    // Starts out as the configured zones
+    zones: Arc<RwLock<Vec<Zone>>>,
+}
+
+impl Resolver {
+    pub fn new(config: ResolverConfig, mut opts: ResolverOpts) -> Resolver {
+        for upstream in config.upstreams.iter().flatten() {
+            if let Some(source) = &upstream.source {
+                opts.outbound
+                    .upstreams
+                    .insert(upstream.addr, source.to_owned());
+            }
+        }
+        let cache = Cache::new(opts.cache_size, opts.cache_memory);
+        let upstreams = Upstreams::new(&config.upstreams, &opts, &config.root_hints);
+        let mut zones = config.zones.to_owned();
+        for zone in zones.iter_mut() {
+            zone.override_ttls(&config.ttl_overrides);
+        }
+        Resolver {
+            config,
+            zones: Arc::new(RwLock::new(zones)),
+            opts,
+            cache: Arc::new(Mutex::new(cache)),
+            upstreams: Arc::new(upstreams),
+        }
+    }
+
+    // Resolves recursively from the usual root servers
+    pub fn recursive() -> Resolver {
+        Resolver::with_root_hints(RootHints::default())
+    }
+
+    pub fn with_root_hints(hints: RootHints) -> Resolver {
+        Resolver::new(ResolverConfig::recursive(hints), ResolverOpts::default())
+    }
+
+    // Forwards every query to the recursive resolver at `addr`
+    pub fn upstream(addr: SocketAddr) -> Resolver {
+        Resolver::new(ResolverConfig::upstreams(&[addr]), ResolverOpts::default())
+    }
+
+    // Start probing upstreams in the background. They're only checked passively, by how the
+    // queries we forward go, until this is called.
+    pub fn start_health_checks(&self) {
+        if !self.upstreams.is_empty() {
+            self.upstreams.start_health_checks(&self.opts);
+        }
+    }
+
+    pub fn upstream_statuses(&self) -> Vec<UpstreamStatus> {
+        self.upstreams.statuses()
+    }
+
+    // Round trip time histograms for every authority and upstream we've queried
+    pub fn server_latencies(&self) -> Vec<(SocketAddr, LatencyHistogram)> {
+        self.config.root_hints.latencies.snapshot()
+    }
+
+    pub fn opts(&self) -> &ResolverOpts {
+        &self.opts
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.lock().unwrap().stats()
+    }
+
+    // Approximate bytes used by the cache
+    pub fn cache_memory(&self) -> usize {
+        self.cache.lock().unwrap().memory()
+    }
+
+    // Everything cached, least recently used first, to be written out with `cache_file::write`
+    pub fn cache_entries(&self) -> Vec<(DnsQuestion, DnsPacket)> {
+        self.cache.lock().unwrap().entries(Instant::now())
+    }
+
+    // Cache entries read back with `cache_file::parse`
+    pub fn load_cache(&self, entries: Vec<(DnsQuestion, DnsPacket)>) {
+        let mut cache = self.cache.lock().unwrap();
+        let now = Instant::now();
+        for (question, response) in entries {
+            cache.insert(&question, &response, now);
+        }
+    }
+
+    // Every IPv4 and IPv6 address for `name`, IPv4 first unless the options say otherwise.
+    // Follows CNAMEs. If looking up one family fails it just contributes no addresses; only when
+    // both fail is it an error (the IPv4 one).
+    pub fn lookup_ip(&self, name: &str) -> Result<Vec<IpAddr>, ResolveError> {
+        let (v4, v6) = match (
+            self.lookup(name, DnsRRType::A),
+            self.lookup(name, DnsRRType::AAAA),
+        ) {
+            (Err(e), Err(_)) => return Err(e),
+            (v4, v6) => (v4.unwrap_or_default(), v6.unwrap_or_default()),
+        };
+        let mut v4: Vec<IpAddr> = v4
+            .into_iter()
+            .filter_map(|record| match record {
+                DnsRecordData::A(ip) => Some(IpAddr::V4(ip)),
+                _ => None,
+            })
+            .collect();
+        let mut v6: Vec<IpAddr> = v6
+            .into_iter()
+            .filter_map(|record| match record {
+                DnsRecordData::AAAA(ip) => Some(IpAddr::V6(ip)),
+                _ => None,
+            })
+            .collect();
+        if self.opts.prefer_ipv6 {
+            v6.append(&mut v4);
+            Ok(v6)
+        } else {
+            v4.append(&mut v6);
+            Ok(v4)
+        }
+    }
+
+    // Mail servers for `name`, most preferred first
+    pub fn lookup_mx(&self, name: &str) -> Result<Vec<MxRecord>, ResolveError> {
+        let mut exchanges = Vec::new();
+        for record in self.lookup(name, DnsRRType::MX)? {
+            if let DnsRecordData::MX(preference, exchange) = record {
+                exchanges.push(MxRecord {
+                    preference,
+                    exchange: display_name(&exchange),
+                });
+            }
+        }
+        exchanges.sort_by_key(|mx| mx.preference);
+        Ok(exchanges)
+    }
+
+    // Where the service at `name` (like _imaps._tcp.example.com) is offered, lowest priority first.
+    // A lone record with the root as its target means the service isn't offered at all, which
+    // comes back as no servers.
+    pub fn lookup_srv(&self, name: &str) -> Result<Vec<SrvRecord>, ResolveError> {
+        let mut servers = Vec::new();
+        for record in self.lookup(name, DnsRRType::SRV)? {
+            if let DnsRecordData::SRV(srv) = record {
+                if srv.target.is_empty() {
+                    continue;
+                }
+                servers.push(SrvRecord {
+                    priority: srv.priority,
+                    weight: srv.weight,
+                    port: srv.port,
+                    target: display_name(&srv.target),
+                });
+            }
+        }
+        servers.sort_by_key(|srv| srv.priority);
+        Ok(servers)
+    }
+
+    // Text records for `name`. A single TXT record can hold several strings (long SPF records
+    // are split up this way); they're joined back together here, one String per record. Bytes
+    // that aren't UTF-8 are replaced.
+    pub fn lookup_txt(&self, name: &str) -> Result<Vec<String>, ResolveError> {
+        let mut texts = Vec::new();
+        for record in self.lookup(name, DnsRRType::TXT)? {
+            if let DnsRecordData::TXT(strings) = record {
+                texts.push(String::from_utf8_lossy(&strings.concat()).into_owned());
+            }
+        }
+        Ok(texts)
+    }
+
+    // Names for `ip`, via PTR records under in-addr.arpa or ip6.arpa
+    pub fn reverse_lookup(&self, ip: IpAddr) -> Result<Vec<String>, ResolveError> {
+        let mut names = Vec::new();
+        for record in self.lookup(&reverse_name(ip), DnsRRType::PTR)? {
+            if let DnsRecordData::PTR(name) = record {
+                names.push(display_name(&name));
+            }
+        }
+        Ok(names)
+    }
+
+    // Ask a single question and get the full response back. Unlike the lookup functions, an
+    // error response code isn't turned into an Err here.
+    pub fn query(&self, name: &str, qtype: DnsRRType) -> Result<DnsPacket, ResolveError> {
+        let question = DnsQuestion {
+            qname: name_from_string(name).map_err(ResolveError::InvalidQuestion)?,
+            qtype,
+            qclass: DnsClass::IN,
+        };
+        self.resolve(&question)
+    }
+
+    // Answer a question the first way we can: from a zone we're authoritative for, from local
+    // data, from the cache, and finally the way the configuration says to, recursively or by
+    // forwarding it
+    pub fn resolve(&self, question: &DnsQuestion) -> Result<DnsPacket, ResolveError> {
+        self.resolve_within(question, 0)
+    }
+
+    // `depth` is how many ALIASes deep we already are
+    fn resolve_within(
+        &self,
+        question: &DnsQuestion,
+        depth: usize,
+    ) -> Result<DnsPacket, ResolveError> {
+        if let Some(response) = self.answer_locally_within(question, depth)? {
+            return Ok(response);
+        }
+        if let Some(response) = self.cached(question) {
+            return Ok(response);
+        }
+        let response = self.resolve_remotely(question)?;
+        self.cache_response(question, &response);
+        Ok(response)
+    }
+
+    // Swap in a new copy of a zone (or add it, if it's new), returning its serial. With
+    // `bump_serial`, a serial that hasn't gone up since the old copy is bumped past it.
+    pub fn replace_zone(&self, mut zone: Zone, bump_serial: bool) -> u32 {
+        zone.override_ttls(&self.config.ttl_overrides);
+        let mut zones = self.zones.write().unwrap();
+        match zones
+            .iter_mut()
+            .find(|old| names_equal(&old.origin, &zone.origin))
+        {
+            Some(old) => {
+                // Serials compare with wraparound (RFC 1982)
+                if bump_serial && (zone.serial().wrapping_sub(old.serial()) as i32) <= 0 {
+                    zone.set_serial(old.serial().wrapping_add(1));
+                }
+                *old = zone;
+                old.serial()
+            }
+            None => {
+                let serial = zone.serial();
+                zones.push(zone);
+                serial
+            }
+        }
+    }
+
+    // Change the zone with exactly this origin in place, if we serve it. `change` has the zone to
+    // itself until it returns, so changes can't interleave.
+    pub fn change_zone<T>(
+        &self,
+        origin: &[String],
+        change: impl FnOnce(&mut Zone) -> T,
+    ) -> Option<T> {
+        let mut zones = self.zones.write().unwrap();
+        let zone = zones
+            .iter_mut()
+            .find(|zone| names_equal(&zone.origin, origin))?;
+        let result = change(zone);
+        zone.override_ttls(&self.config.ttl_overrides);
+        Some(result)
+    }
+
+    // Start recursion at the root servers in `hints` from now on, on every clone of this resolver
+    pub fn replace_root_hints(&self, hints: &[DnsResourceRecord]) -> Result<(), String> {
+        self.config
+            .root_hints
+            .use_hints(hints, self.opts.address_preference)
+    }
+
+    // A copy of the zone with exactly this origin, if we serve it
+    pub fn zone(&self, origin: &[String]) -> Option<Zone> {
+        let zones = self.zones.read().unwrap();
+        zones
+            .iter()
+            .find(|zone| names_equal(&zone.origin, origin))
+            .cloned()
+    }
+
+    // Stop serving the zone with exactly this origin, returning whether we were
+    pub fn remove_zone(&self, origin: &[String]) -> bool {
+        let mut zones = self.zones.write().unwrap();
+        let before = zones.len();
+        zones.retain(|zone| !names_equal(&zone.origin, origin));
+        zones.len() < before
+    }
+
+    // The origin and serial of every zone we serve
+    pub fn zone_serials(&self) -> Vec<(Vec<String>, u32)> {
+        let zones = self.zones.read().unwrap();
+        zones
+            .iter()
+            .map(|zone| (zone.origin.to_owned(), zone.serial()))
+            .collect()
+    }
+
+    // An answer we have without asking anyone, from a zone or local data. The exception is an
+    // ALIAS in a zone, whose target is looked up (through the cache) to answer with.
+    pub fn answer_locally(
+        &self,
+        question: &DnsQuestion,
+    ) -> Result<Option<DnsPacket>, ResolveError> {
+        self.answer_locally_within(question, 0)
+    }
+
+    fn answer_locally_within(
+        &self,
+        question: &DnsQuestion,
+        depth: usize,
+    ) -> Result<Option<DnsPacket>, ResolveError> {
+        let (mut response, alias) = {
+            let zones = self.zones.read().unwrap();
+            match zone::zone_for(&zones, &question.qname) {
+                Some(zone) => (zone.answer(question), zone.alias_for(question).cloned()),
+                None => return Ok(zone::answer_from_records(&self.config.local_data, question)),
+            }
+        };
+        if let Some(alias) = alias {
+            if depth >= MAX_ALIAS_DEPTH {
+                return Err(ResolveError::Loop(format!(
+                    "ALIASes more than {} deep at {}",
+                    MAX_ALIAS_DEPTH,
+                    name_to_string(&alias.name)
+                )));
+            }
+            let target_question = DnsQuestion {
+                qname: alias.target.to_owned(),
+                ..question.to_owned()
+            };
+            let mut target = self.resolve_within(&target_question, depth + 1)?;
+            flatten_cnames(&target_question, &mut target);
+            let answers: Vec<DnsResourceRecord> = target
+                .answers
+                .into_iter()
+                .filter(|rr| rr.rr_type == question.qtype && names_equal(&rr.name, &alias.target))
+                .map(|rr| DnsResourceRecord {
+                    name: question.qname.to_owned(),
+                    ttl: rr.ttl.min(alias.ttl),
+                    ..rr
+                })
+                .collect();
+            // If the target has no addresses, the NODATA the zone gave us stands
+            if !answers.is_empty() {
+                response.answers = answers;
+                response.nameservers.clear();
+            }
+        }
+        Ok(Some(response))
+    }
+
+    pub fn cached(&self, question: &DnsQuestion) -> Option<DnsPacket> {
+        self.cached_for(question, None)
+    }
+
+    // The cached answer for a client in `subnet`, which can be one scoped to it
+    pub fn cached_for(
+        &self,
+        question: &DnsQuestion,
+        subnet: Option<&AddressPrefix>,
+    ) -> Option<DnsPacket> {
+        let mut span = telemetry::span("dns.cache.lookup", SpanKind::Internal);
+        span.question(question);
+        let cached = self
+            .cache
+            .lock()
+            .unwrap()
+            .get(question, subnet, Instant::now());
+        span.attribute("dns.cache.hit", cached.is_some());
+        if cached.is_some() {
+            log!("Answering {} from the cache", question);
+        }
+        cached
+    }
+
+    // Look a question up again whatever's cached, and cache the new answer. Questions we answer
+    // ourselves are left alone, since there's nothing to cache.
+    pub fn refresh(&self, question: &DnsQuestion) -> Result<(), ResolveError> {
+        if self.answer_locally(question)?.is_some() {
+            return Ok(());
+        }
+        let response = self.resolve_remotely(question)?;
+        self.cache_response(question, &response);
+        Ok(())
+    }
+
+    pub fn cache_response(&self, question: &DnsQuestion, response: &DnsPacket) {
+        let mut span = telemetry::span("dns.cache.insert", SpanKind::Internal);
+        span.question(question);
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(question, response, Instant::now());
+    }
+
+    // The closest delegation to `name` we know of, as NS records and whatever addresses for them
+    // we have, for pointing a client that doesn't want us recursing in the right direction. That's
+    // the nearest enclosing zone whose nameservers are in the cache, or failing that, the root.
+    pub fn closest_delegation(
+        &self,
+        name: &[String],
+    ) -> (Vec<DnsResourceRecord>, Vec<DnsResourceRecord>) {
+        let now = Instant::now();
+        let cache = self.cache.lock().unwrap();
+        let cached = |qname: &[String], qtype| -> Vec<DnsResourceRecord> {
+            let question = DnsQuestion {
+                qname: qname.to_vec(),
+                qtype,
+                qclass: DnsClass::IN,
+            };
+            cache
+                .peek(&question, now)
+                .map(|response| response.answers)
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|rr| rr.rr_type == qtype && names_equal(&rr.name, qname))
+                .collect()
+        };
+        for start in 0..name.len() {
+            let zone = &name[start..];
+            let nameservers = cached(zone, DnsRRType::NS);
+            let mut addresses = Vec::new();
+            for ns in &nameservers {
+                if let DnsRecordData::NS(target) = &ns.record {
+                    addresses.extend(cached(target, DnsRRType::A));
+                    addresses.extend(cached(target, DnsRRType::AAAA));
+                }
+            }
+            if !nameservers.is_empty() {
+                return (nameservers, addresses);
+            }
+        }
+        recursive::root_referral()
+    }
+
+    // Ask the network, recursively or by forwarding, skipping the cache. The cache lock isn't
+    // held meanwhile; two threads missing on the same question will both go to the network,
+    // which is wasteful but harmless. Stub zones are resolved by us even when everything else is
+    // forwarded.
+    pub fn resolve_remotely(&self, question: &DnsQuestion) -> Result<DnsPacket, ResolveError> {
+        let stub = self.config.root_hints.stub_zone_for(&question.qname);
+        let mut response = if self.upstreams.is_empty() || stub.is_some() {
+            recursive::resolve_question(question, &self.config.root_hints, &self.opts)?
+        } else {
+            let mut response = self.upstreams.forward(question, &self.opts)?;
+            // Upstreams don't always merge what they send either
+            for section in [
+                &mut response.answers,
+                &mut response.nameservers,
+                &mut response.addl_recs,
+            ] {
+                *section = merge_rrsets(std::mem::take(section));
+            }
+            response
+        };
+        // Whoever the answer came from, we aren't the authority for it
+        response.flags.aa_bit = false;
+        self.config.ttl_overrides.apply_to_response(&mut response);
+        Ok(response)
+    }
+
+    // The rdata of every answer of type `qtype`. A name with no records of the type (NODATA)
+    // gives an empty list; a name that doesn't exist at all, or any other error, gives an Err.
+    fn lookup(&self, name: &str, qtype: DnsRRType) -> Result<Vec<DnsRecordData>, ResolveError> {
+        let response = self.query(name, qtype)?;
+        match response.flags.rcode {
+            DnsRCode::NoError => (),
+            DnsRCode::NXDomain => return Err(ResolveError::NotFound(name.to_owned())),
+            rcode => return Err(ResolveError::ErrorResponse(name.to_owned(), rcode)),
+        }
+        // CNAMEs along the way are in the answers too; skip over them
+        Ok(response
+            .answers
+            .into_iter()
+            .filter(|rr| rr.rr_type == qtype)
+            .map(|rr| rr.record)
+            .collect())
+    }
+}
+
+// The name PTR records for `ip` live under: the address backwards, by octet for IPv4 and by
+// nibble for IPv6 (RFC 1035 section 3.5, RFC 3596 section 2.5)
+fn reverse_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets: Vec<String> = ip.octets().iter().rev().map(|o| o.to_string()).collect();
+            format!("{}.in-addr.arpa", octets.join("."))
+        }
+        IpAddr::V6(ip) => {
+            let mut nibbles = Vec::new();
+            for octet in ip.octets().iter().rev() {
+                nibbles.push(format!("{:x}", octet & 0xf));
+                nibbles.push(format!("{:x}", octet >> 4));
+            }
+            format!("{}.ip6.arpa", nibbles.join("."))
+        }
+    }
+}
+
+// Names handed back to callers drop the trailing dot, like most resolver libraries do
+fn display_name(name: &[String]) -> String {
+    let name = name_to_string(name);
+    match name.strip_suffix('.') {
+        Some(stripped) if !stripped.is_empty() => stripped.to_owned(),
+        _ => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use crate::dns::mock::{self, Behavior, MockNetwork, Script};
+
+    const ROOT: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 2);
+    const UPSTREAM: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 5);
+
+    #[test]
+    fn reverse_names_are_built_correctly() {
+        assert_eq!(
+            reverse_name(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))),
+            "1.2.0.192.in-addr.arpa"
+        );
+        assert_eq!(
+            reverse_name(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1))),
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa"
+        );
+    }
+
+    #[test]
+    fn looks_up_records_recursively() {
+        let v4 = Ipv4Addr::new(192, 0, 2, 1);
+        let v6 = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+        let network = MockNetwork::new();
+        let _root = network.serve(
+            ROOT,
+            Script::new()
+                .on(
+                    "www.example.com",
+                    Some(DnsRRType::A),
+                    Behavior::Answer(vec![mock::a("www.example.com", v4)]),
+                )
+                .on(
+                    "www.example.com",
+                    Some(DnsRRType::AAAA),
+                    Behavior::Answer(vec![mock::aaaa("www.example.com", v6)]),
+                )
+                .on(
+                    "v4only.example.com",
+                    Some(DnsRRType::A),
+                    Behavior::Answer(vec![mock::a("v4only.example.com", v4)]),
+                )
+                .on(
+                    "v4only.example.com",
+                    Some(DnsRRType::AAAA),
+                    Behavior::Rcode(DnsRCode::ServFail),
+                )
+                .on(
+                    "example.com",
+                    Some(DnsRRType::MX),
+                    Behavior::Answer(vec![
+                        mock::mx("example.com", 20, "backup.example.com"),
+                        mock::mx("example.com", 10, "mail.example.com"),
+                    ]),
+                )
+                .on(
+                    "1.2.0.192.in-addr.arpa",
+                    Some(DnsRRType::PTR),
+                    Behavior::Answer(vec![mock::ptr("1.2.0.192.in-addr.arpa", "www.example.com")]),
+                )
+                .on(
+                    "_imaps._tcp.example.com",
+                    Some(DnsRRType::SRV),
+                    Behavior::Answer(vec![
+                        mock::srv("_imaps._tcp.example.com", 20, 993, "backup.example.com"),
+                        mock::srv("_imaps._tcp.example.com", 10, 993, "mail.example.com"),
+                    ]),
+                )
+                .on("nope.example.com", None, Behavior::NXDomain(vec![])),
+        );
+        let resolver = Resolver::with_root_hints(network.hints(ROOT));
+
+        assert_eq!(
+            resolver.lookup_ip("www.example.com").unwrap(),
+            vec![IpAddr::V4(v4), IpAddr::V6(v6)]
+        );
+        assert_eq!(
+            resolver.lookup_mx("example.com").unwrap(),
+            vec![
+                MxRecord {
+                    preference: 10,
+                    exchange: String::from("mail.example.com"),
+                },
+                MxRecord {
+                    preference: 20,
+                    exchange: String::from("backup.example.com"),
+                },
+            ]
+        );
+        let servers = resolver.lookup_srv("_imaps._tcp.example.com").unwrap();
+        assert_eq!(
+            servers[0],
+            SrvRecord {
+                priority: 10,
+                weight: 0,
+                port: 993,
+                target: String::from("mail.example.com"),
+            }
+        );
+        assert_eq!(servers[1].target, "backup.example.com");
+        assert_eq!(
+            resolver.reverse_lookup(IpAddr::V4(v4)).unwrap(),
+            vec![String::from("www.example.com")]
+        );
+        // The failed IPv6 lookup doesn't cost us the IPv4 address
+        assert_eq!(
+            resolver.lookup_ip("v4only.example.com").unwrap(),
+            vec![IpAddr::V4(v4)]
+        );
+        assert!(matches!(
+            resolver.lookup_ip("nope.example.com"),
+            Err(ResolveError::NotFound(_))
+        ));
+        let invalid = resolver
+            .query("www..example.com", DnsRRType::A)
+            .unwrap_err();
+        assert!(matches!(invalid, ResolveError::InvalidQuestion(_)));
+        assert_eq!(invalid.rcode(), Some(DnsRCode::FormError));
+    }
+
+    #[test]
+    fn answers_locally_before_resolving() {
+        let network = MockNetwork::new();
+        let upstream = network.serve(
+            UPSTREAM,
+            Script::new().otherwise(Behavior::Answer(vec![mock::a(
+                "elsewhere.test",
+                Ipv4Addr::new(192, 0, 2, 99),
+            )])),
+        );
+        let origin = mock::labels("example.test");
+        let zone = Zone::parse(
+            &origin,
+            "@ 300 SOA ns hostmaster 1 2 3 4 5\nwww 300 A 192.0.2.1\nlocal 300 A 192.0.2.2",
+        )
+        .unwrap();
+        let config = ResolverConfig {
+            zones: vec![zone],
+            local_data: vec![
+                // Shadowed by the zone
+                zone::parse_record(&[], "local.example.test 60 A 192.0.2.3").unwrap(),
+                zone::parse_record(&[], "printer.lan 60 A 192.0.2.4").unwrap(),
+            ],
+            ..ResolverConfig::upstreams(&[upstream.addr])
+        };
+        let resolver = Resolver::new(config, ResolverOpts::default());
+
+        let www = resolver.query("www.example.test", DnsRRType::A).unwrap();
+        assert!(www.flags.aa_bit);
+        assert_eq!(www.answers.len(), 1);
+        let local = resolver.lookup_ip("local.example.test").unwrap();
+        assert_eq!(local, vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2))]);
+        assert!(resolver.lookup_ip("nope.example.test").is_err());
+
+        let printer = resolver.query("printer.lan", DnsRRType::AAAA).unwrap();
+        assert!(printer.flags.aa_bit);
+        assert!(printer.answers.is_empty());
+        assert!(upstream.queries().is_empty());
+
+        // Anything else is resolved, and not something we're the authority for
+        let elsewhere = resolver.query("elsewhere.test", DnsRRType::A).unwrap();
+        assert!(!elsewhere.flags.aa_bit);
+        assert_eq!(upstream.queries().len(), 1);
+    }
+
+    #[test]
+    fn answers_aliases_with_their_targets() {
+        let network = MockNetwork::new();
+        let upstream = network.serve(
+            UPSTREAM,
+            Script::new().otherwise(Behavior::Answer(vec![
+                mock::cname("lb.cdn.test", "edge.cdn.test"),
+                mock::a("edge.cdn.test", Ipv4Addr::new(192, 0, 2, 7)),
+            ])),
+        );
+        let origin = mock::labels("example.test");
+        let zone = Zone::parse(
+            &origin,
+            "@ 300 SOA ns hostmaster 1 2 3 4 5\n@ 60 ALIAS lb.cdn.test.\nloop 300 ALIAS loop",
+        )
+        .unwrap();
+        assert!(Zone::parse(
+            &origin,
+            "@ 300 SOA ns hm 1 2 3 4 5\n@ A 192.0.2.1\n@ ALIAS x."
+        )
+        .is_err());
+        let config = ResolverConfig {
+            zones: vec![zone],
+            ..ResolverConfig::upstreams(&[upstream.addr])
+        };
+        let resolver = Resolver::new(config, ResolverOpts::default());
+
+        for _ in 0..2 {
+            let apex = resolver.query("example.test", DnsRRType::A).unwrap();
+            assert!(apex.flags.aa_bit);
+            assert_eq!(
+                apex.answers,
+                vec![DnsResourceRecord {
+                    ttl: 60,
+                    ..mock::a("example.test", Ipv4Addr::new(192, 0, 2, 7))
+                }]
+            );
+        }
+        // The target's answer was cached
+        assert_eq!(upstream.queries().len(), 1);
+
+        // Other types at the name are the zone's own business
+        let mx = resolver.query("example.test", DnsRRType::MX).unwrap();
+        assert!(mx.answers.is_empty());
+        assert_eq!(upstream.queries().len(), 1);
+
+        assert!(matches!(
+            resolver.query("loop.example.test", DnsRRType::A),
+            Err(ResolveError::Loop(_))
+        ));
+    }
+
+    #[test]
+    fn forwards_to_upstream() {
+        let network = MockNetwork::new();
+        let upstream = network.serve(
+            UPSTREAM,
+            Script::new()
+                .on(
+                    "example.com",
+                    Some(DnsRRType::TXT),
+                    Behavior::Answer(vec![
+                        mock::txt("example.com", &["v=spf1 ", "-all"]),
+                        mock::txt("example.com", &["hello"]),
+                    ]),
+                )
+                // Upstreams pass NODATA answers along as is
+                .on("example.com", Some(DnsRRType::MX), Behavior::Answer(vec![])),
+        );
+        let resolver = Resolver::upstream(upstream.addr);
+
+        assert_eq!(
+            resolver.lookup_txt("example.com").unwrap(),
+            vec![String::from("v=spf1 -all"), String::from("hello")]
+        );
+        assert_eq!(resolver.lookup_mx("example.com").unwrap(), vec![]);
+
+        // Asking again is answered from the cache
+        let asked = upstream.queries().len();
+        assert_eq!(resolver.lookup_txt("example.com").unwrap().len(), 2);
+        assert_eq!(upstream.queries().len(), asked);
+        assert_eq!(resolver.cache_stats().hits, 1);
+        assert!(resolver.lookup_txt("bad..name").is_err());
+    }
+}
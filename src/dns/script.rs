@@ -0,0 +1,291 @@
+// Lua hooks in the query pipeline, for site-specific logic that doesn't belong in montague
+// itself. A script defines either or both of these global functions:
+//
+//   on_query(q)     Called before anything else looks at the query. q has name, type, and class
+//                   fields, and profile when the query came in on a listener with one. Return
+//                   nil to carry on, {name = "..."} to resolve another name in its place, or
+//                   {rcode = "...", answers = {...}} to answer right away.
+//   on_answer(q, r) Called with the answer on its way back. r has rcode and answers fields.
+//                   Return nil to leave it alone, or r with either changed.
+//
+// Names are in presentation format with the trailing dot, rcodes are their usual mnemonics
+// (NOERROR, NXDOMAIN, SERVFAIL, REFUSED, ...), and answers are lists of records in master file
+// format, e.g. "host.example. 300 A 192.0.2.1". A script that fails answers SERVFAIL rather than
+// letting queries past whatever it was meant to do.
+
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+use mlua::{Function, Lua, Table};
+
+use super::error::ResolveError;
+use super::pipeline::{self, Middleware, Next};
+use super::protocol::{
+    name_from_string, name_to_string, names_equal, DnsPacket, DnsQuestion, DnsRCode,
+    DnsResourceRecord,
+};
+use super::trace::log;
+use super::zone;
+
+// What on_query asked for
+enum QueryAction {
+    Continue,
+    Rename(Vec<String>),
+    Answer(DnsRCode, Vec<DnsResourceRecord>),
+}
+
+pub struct Script {
+    // Lua states can't be shared between threads, so queries take turns with it
+    lua: Mutex<Lua>,
+}
+
+impl Script {
+    pub fn load(path: &Path) -> Result<Script, Box<dyn Error>> {
+        let source = fs::read_to_string(path)?;
+        Ok(Script::from_source(&path.to_string_lossy(), &source)?)
+    }
+
+    pub fn from_source(name: &str, source: &str) -> Result<Script, String> {
+        let lua = Lua::new();
+        lua.load(source)
+            .set_name(name)
+            .exec()
+            .map_err(|e| e.to_string())?;
+        Ok(Script {
+            lua: Mutex::new(lua),
+        })
+    }
+
+    fn on_query(&self, question: &DnsQuestion, profile: Option<&str>) -> mlua::Result<QueryAction> {
+        let lua = self.lua.lock().unwrap();
+        let hook: Option<Function> = lua.globals().get("on_query")?;
+        let hook = match hook {
+            Some(hook) => hook,
+            None => return Ok(QueryAction::Continue),
+        };
+        let result: Option<Table> = hook.call(question_table(&lua, question, profile)?)?;
+        let result = match result {
+            Some(result) => result,
+            None => return Ok(QueryAction::Continue),
+        };
+        if let Some(name) = result.get::<_, Option<String>>("name")? {
+            return Ok(QueryAction::Rename(
+                name_from_string(&name).map_err(mlua::Error::external)?,
+            ));
+        }
+        let rcode = match result.get::<_, Option<String>>("rcode")? {
+            Some(rcode) => rcode_from_name(&rcode)?,
+            None => DnsRCode::NoError,
+        };
+        let answers = result
+            .get::<_, Option<Vec<String>>>("answers")?
+            .unwrap_or_default();
+        Ok(QueryAction::Answer(rcode, parse_records(&answers, &[])?))
+    }
+
+    fn on_answer(
+        &self,
+        question: &DnsQuestion,
+        profile: Option<&str>,
+        response: &mut DnsPacket,
+    ) -> mlua::Result<()> {
+        let lua = self.lua.lock().unwrap();
+        let hook: Option<Function> = lua.globals().get("on_answer")?;
+        let hook = match hook {
+            Some(hook) => hook,
+            None => return Ok(()),
+        };
+        let answer = lua.create_table()?;
+        answer.set("rcode", rcode_name(&response.flags.rcode))?;
+        let records: Vec<String> = response.answers.iter().map(|rr| rr.to_string()).collect();
+        answer.set("answers", records)?;
+        let result: Option<Table> =
+            hook.call((question_table(&lua, question, profile)?, answer))?;
+        let result = match result {
+            Some(result) => result,
+            None => return Ok(()),
+        };
+        if let Some(rcode) = result.get::<_, Option<String>>("rcode")? {
+            response.flags.rcode = rcode_from_name(&rcode)?;
+        }
+        if let Some(answers) = result.get::<_, Option<Vec<String>>>("answers")? {
+            response.answers = parse_records(&answers, &response.answers)?;
+        }
+        Ok(())
+    }
+}
+
+impl Middleware for Script {
+    fn name(&self) -> &str {
+        "script"
+    }
+
+    fn handle(&self, query: &DnsPacket, next: Next) -> Result<DnsPacket, ResolveError> {
+        let question = &query.questions[0];
+        let profile = next.context().profile.as_deref();
+        let mut response = match self.on_query(question, profile).map_err(script_error)? {
+            QueryAction::Continue => next.run(query)?,
+            QueryAction::Rename(name) => {
+                log!(
+                    "Script resolving {} in place of {}",
+                    name_to_string(&name),
+                    name_to_string(&question.qname)
+                );
+                let mut renamed = query.to_owned();
+                renamed.questions[0].qname = name.to_owned();
+                let mut response = next.run(&renamed)?;
+                // As far as the client can tell, it got an answer to what it asked
+                response.questions = query.questions.to_owned();
+                for rr in response.answers.iter_mut() {
+                    if names_equal(&rr.name, &name) {
+                        rr.name = question.qname.to_owned();
+                    }
+                }
+                response
+            }
+            QueryAction::Answer(rcode, answers) => {
+                let mut response = pipeline::error_response(query, rcode);
+                response.answers = answers;
+                response
+            }
+        };
+        self.on_answer(question, profile, &mut response)
+            .map_err(script_error)?;
+        Ok(response)
+    }
+}
+
+fn script_error(e: mlua::Error) -> ResolveError {
+    ResolveError::Internal(format!("Script failed: {}", e))
+}
+
+fn question_table<'lua>(
+    lua: &'lua Lua,
+    question: &DnsQuestion,
+    profile: Option<&str>,
+) -> mlua::Result<Table<'lua>> {
+    let table = lua.create_table()?;
+    table.set("name", name_to_string(&question.qname))?;
+    table.set("type", question.qtype.to_string())?;
+    table.set("class", question.qclass.to_string())?;
+    table.set("profile", profile)?;
+    Ok(table)
+}
+
+// Records back from a script. Ones it passed through untouched are kept as they were, so records
+// of types the master file parser doesn't know survive a script that doesn't care about them.
+fn parse_records(
+    records: &[String],
+    original: &[DnsResourceRecord],
+) -> mlua::Result<Vec<DnsResourceRecord>> {
+    records
+        .iter()
+        .map(
+            |record| match original.iter().find(|rr| rr.to_string() == *record) {
+                Some(rr) => Ok(rr.to_owned()),
+                None => zone::parse_record(&[], record).map_err(mlua::Error::external),
+            },
+        )
+        .collect()
+}
+
+fn rcode_name(rcode: &DnsRCode) -> String {
+    match rcode {
+        DnsRCode::FormError => String::from("FORMERR"),
+        _ => format!("{:?}", rcode).to_uppercase(),
+    }
+}
+
+fn rcode_from_name(name: &str) -> mlua::Result<DnsRCode> {
+    (0..16)
+        .filter_map(num::FromPrimitive::from_u8)
+        .find(|rcode| rcode_name(rcode).eq_ignore_ascii_case(name))
+        .ok_or_else(|| mlua::Error::external(format!("Unknown rcode {}", name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+
+    use crate::dns::mock;
+    use crate::dns::pipeline::Pipeline;
+    use crate::dns::protocol::DnsRRType;
+
+    // Answers every A query with one address
+    struct Fixed;
+
+    impl Middleware for Fixed {
+        fn name(&self) -> &str {
+            "fixed"
+        }
+
+        fn handle(&self, query: &DnsPacket, _next: Next) -> Result<DnsPacket, ResolveError> {
+            let mut response = pipeline::error_response(query, DnsRCode::NoError);
+            let name = name_to_string(&query.questions[0].qname);
+            response
+                .answers
+                .push(mock::a(&name, Ipv4Addr::new(192, 0, 2, 1)));
+            Ok(response)
+        }
+    }
+
+    const SCRIPT: &str = r#"
+        function on_query(q)
+            if q.name == "ads.example." then
+                return { rcode = "NXDOMAIN" }
+            elseif q.name == "router.lan." then
+                return { answers = { "router.lan. 60 A 192.168.1.1" } }
+            elseif q.name == "intranet." then
+                return { name = "intranet.corp.example." }
+            end
+        end
+
+        function on_answer(q, r)
+            if q.name == "tracked.example." then
+                r.answers = {}
+                return r
+            end
+        end
+    "#;
+
+    #[test]
+    fn scripts_rewrite_queries_and_answers() {
+        let script = Script::from_source("test", SCRIPT).unwrap();
+        let pipeline = Pipeline::new().then(Arc::new(script)).then(Arc::new(Fixed));
+        let run = |name| mock::run(&pipeline, &mock::query(name, DnsRRType::A)).unwrap();
+
+        let response = run("www.example");
+        assert_eq!(
+            response.answers,
+            vec![mock::a("www.example", Ipv4Addr::new(192, 0, 2, 1))]
+        );
+
+        assert_eq!(run("ads.example").flags.rcode, DnsRCode::NXDomain);
+
+        let mut router = mock::a("router.lan", Ipv4Addr::new(192, 168, 1, 1));
+        router.ttl = 60;
+        assert_eq!(run("router.lan").answers, vec![router]);
+
+        let response = run("intranet");
+        assert_eq!(response.questions[0].qname, mock::labels("intranet"));
+        assert_eq!(response.answers[0].name, mock::labels("intranet"));
+
+        let response = run("tracked.example");
+        assert_eq!(response.flags.rcode, DnsRCode::NoError);
+        assert!(response.answers.is_empty());
+
+        // Broken scripts fail closed
+        let broken = Script::from_source("broken", "function on_query(q) error('oops') end");
+        let pipeline = Pipeline::new()
+            .then(Arc::new(broken.unwrap()))
+            .then(Arc::new(Fixed));
+        let error = mock::run(&pipeline, &mock::query("www.example", DnsRRType::A)).unwrap_err();
+        assert_eq!(error.rcode(), Some(DnsRCode::ServFail));
+        assert!(Script::from_source("syntax", "function (").is_err());
+    }
+}
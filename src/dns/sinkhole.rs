@@ -0,0 +1,291 @@
+// Answering blocked names with addresses of our own, such as a local web page saying the name was
+// blocked, so people see why a site didn't load rather than a browser's generic error. An answer
+// counts as blocked when it carries an Extended DNS Error saying so (Blocked, Censored, or
+// Filtered), the way filtering upstreams mark theirs. Rules can also redirect NXDOMAINs, which is
+// opt-in: it breaks anything relying on names not existing, like search domains.
+//
+// Each rule covers a zone, for every client or only those with a given policy profile, and the
+// most specific rule for the name asked about applies; a profile's own rule wins over one for
+// everyone at the same zone. A and AAAA queries get the rule's addresses of that family, and
+// anything else (or a family the rule has no addresses for) gets an empty NOERROR, so the name
+// looks the same whatever's asked about it.
+
+use std::collections::HashMap;
+
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use super::error::ResolveError;
+use super::pipeline::{Middleware, Next};
+use super::protocol::{
+    name_from_string, name_to_string, DnsClass, DnsPacket, DnsRCode, DnsRRType, DnsRecordData,
+    DnsResourceRecord, EdeCode, ExtendedDnsError,
+};
+use super::suffix::SuffixTrie;
+use super::trace::log;
+
+// Short, so clients notice soon once a name stops being blocked
+const SINKHOLE_TTL: u32 = 60;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SinkholeRule {
+    // The policy profile the rule is for, or None for every client
+    pub profile: Option<String>,
+    pub zone: Vec<String>,
+    pub addresses: Vec<IpAddr>,
+    // Whether names that don't exist get redirected too, not only blocked ones
+    pub nxdomain: bool,
+}
+
+// Parses "[PROFILE:]ZONE=IP[,IP...]", e.g. "example.com=192.0.2.80,2001:db8::80" or
+// "kids:example.com=192.0.2.81", for blocked names only
+impl FromStr for SinkholeRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SinkholeRule, String> {
+        let (zone, addresses) = s.split_once('=').ok_or_else(|| {
+            format!(
+                "Expected [PROFILE:]ZONE=IP[,IP...] for sinkhole, got {:?}",
+                s
+            )
+        })?;
+        let (profile, zone) = match zone.split_once(':') {
+            Some(("", _)) => return Err(format!("Empty sinkhole profile in {:?}", s)),
+            Some((profile, zone)) => (Some(profile.to_owned()), zone),
+            None => (None, zone),
+        };
+        let addresses = addresses
+            .split(',')
+            .map(|ip| {
+                ip.parse()
+                    .map_err(|_| format!("Invalid sinkhole address {:?}", ip))
+            })
+            .collect::<Result<Vec<IpAddr>, String>>()?;
+        Ok(SinkholeRule {
+            profile,
+            zone: name_from_string(zone)?,
+            addresses,
+            nxdomain: false,
+        })
+    }
+}
+
+pub struct Sinkhole {
+    // Rules for every client
+    rules: SuffixTrie<SinkholeRule>,
+    // Rules for clients with a given profile
+    profiles: HashMap<String, SuffixTrie<SinkholeRule>>,
+}
+
+impl Sinkhole {
+    // A later rule for the same zone and profile replaces an earlier one
+    pub fn new(rules: Vec<SinkholeRule>) -> Sinkhole {
+        let mut sinkhole = Sinkhole {
+            rules: SuffixTrie::new(),
+            profiles: HashMap::new(),
+        };
+        for rule in rules {
+            let trie = match &rule.profile {
+                Some(profile) => sinkhole.profiles.entry(profile.to_owned()).or_default(),
+                None => &mut sinkhole.rules,
+            };
+            trie.insert(&rule.zone.to_owned(), rule);
+        }
+        sinkhole
+    }
+
+    fn rule_for(&self, name: &[String], profile: Option<&str>) -> Option<&SinkholeRule> {
+        let everyone = self.rules.longest_match(name);
+        let own = profile
+            .and_then(|profile| self.profiles.get(profile))
+            .and_then(|trie| trie.longest_match(name));
+        match (own, everyone) {
+            (Some(own), Some(everyone)) if everyone.zone.len() > own.zone.len() => Some(everyone),
+            (Some(own), _) => Some(own),
+            (None, everyone) => everyone,
+        }
+    }
+}
+
+impl Middleware for Sinkhole {
+    fn name(&self) -> &str {
+        "sinkhole"
+    }
+
+    fn handle(&self, query: &DnsPacket, next: Next) -> Result<DnsPacket, ResolveError> {
+        let context = next.context();
+        let question = &query.questions[0];
+        let rule = self.rule_for(&question.qname, context.profile.as_deref());
+        let mut response = next.run(query)?;
+        let rule = match rule {
+            Some(rule) => rule,
+            None => return Ok(response),
+        };
+        let reason = if is_blocked(&response) {
+            "blocked"
+        } else if rule.nxdomain && response.flags.rcode == DnsRCode::NXDomain {
+            "nonexistent"
+        } else {
+            return Ok(response);
+        };
+
+        let answers: Vec<DnsResourceRecord> = rule
+            .addresses
+            .iter()
+            .filter_map(|ip| match (question.qtype, ip) {
+                (DnsRRType::A, IpAddr::V4(ip)) => Some((DnsRRType::A, DnsRecordData::A(*ip))),
+                (DnsRRType::AAAA, IpAddr::V6(ip)) => {
+                    Some((DnsRRType::AAAA, DnsRecordData::AAAA(*ip)))
+                }
+                _ => None,
+            })
+            .map(|(rr_type, record)| DnsResourceRecord {
+                name: question.qname.to_owned(),
+                rr_type,
+                class: DnsClass::IN,
+                ttl: SINKHOLE_TTL,
+                record,
+            })
+            .collect();
+        log!(
+            "Sinkholing {} name {} ({:?}) for {} to {} addresses instead of {:?}",
+            reason,
+            name_to_string(&question.qname),
+            question.qtype,
+            context.client.ip(),
+            answers.len(),
+            response.flags.rcode
+        );
+        // Any Extended DNS Error stays, so clients that look can still tell what happened
+        response.flags.rcode = DnsRCode::NoError;
+        response.answers = answers;
+        response.nameservers.clear();
+        Ok(response)
+    }
+}
+
+fn is_blocked(response: &DnsPacket) -> bool {
+    ExtendedDnsError::from_packet(response).iter().any(|error| {
+        matches!(
+            error.code(),
+            Some(EdeCode::Blocked | EdeCode::Censored | EdeCode::Filtered)
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::dns::context::{QueryContext, Transport};
+    use crate::dns::mock;
+    use crate::dns::pipeline::Pipeline;
+
+    // Answers as the name's first label says: "blocked" with an EDE, "gone" with NXDOMAIN, and
+    // anything else with an address
+    struct Upstream;
+
+    impl Middleware for Upstream {
+        fn name(&self) -> &str {
+            "upstream"
+        }
+
+        fn handle(&self, query: &DnsPacket, _next: Next) -> Result<DnsPacket, ResolveError> {
+            let question = &query.questions[0];
+            let mut response = query.to_owned();
+            response.flags.qr_bit = true;
+            match question.qname[0].as_str() {
+                "blocked" => {
+                    response.flags.rcode = DnsRCode::NXDomain;
+                    ExtendedDnsError::new(EdeCode::Blocked, "ads").attach(&mut response);
+                }
+                "gone" => response.flags.rcode = DnsRCode::NXDomain,
+                _ => {
+                    response.answers =
+                        vec![mock::a("www.example.com", "192.0.2.1".parse().unwrap())]
+                }
+            }
+            Ok(response)
+        }
+    }
+
+    #[test]
+    fn sinkholes_blocked_and_nonexistent_names() {
+        let mut everywhere: SinkholeRule = ".=192.0.2.80,2001:db8::80".parse().unwrap();
+        everywhere.nxdomain = true;
+        let rules = vec![
+            everywhere,
+            "example.com=198.51.100.80".parse().unwrap(),
+            "kids:example.com=198.51.100.81".parse().unwrap(),
+            "kids:com=198.51.100.82".parse().unwrap(),
+        ];
+        assert!("example.com".parse::<SinkholeRule>().is_err());
+        assert!("example.com=nowhere".parse::<SinkholeRule>().is_err());
+        assert!(":example.com=192.0.2.80".parse::<SinkholeRule>().is_err());
+        let pipeline = Pipeline::new()
+            .then(Arc::new(Sinkhole::new(rules)))
+            .then(Arc::new(Upstream));
+        let ask_as = |profile: Option<&str>, name: &str, qtype: DnsRRType| {
+            let query = mock::query(name, qtype);
+            let client = "127.0.0.1:53000".parse().unwrap();
+            let mut context = QueryContext::new(&query, client, Transport::Udp, 0);
+            context.profile = profile.map(String::from);
+            let response = pipeline.run(&query, &context).unwrap();
+            let records: Vec<DnsRecordData> =
+                response.answers.into_iter().map(|rr| rr.record).collect();
+            (response.flags.rcode, records)
+        };
+        let ask = |name: &str, qtype: DnsRRType| ask_as(None, name, qtype);
+
+        // The most specific rule applies, and it only covers blocked names
+        assert_eq!(
+            ask("blocked.ads.example.com", DnsRRType::A),
+            (
+                DnsRCode::NoError,
+                vec![DnsRecordData::A("198.51.100.80".parse().unwrap())]
+            )
+        );
+        assert_eq!(
+            ask("blocked.ads.example.com", DnsRRType::AAAA),
+            (DnsRCode::NoError, vec![])
+        );
+        assert_eq!(
+            ask("gone.example.com", DnsRRType::A),
+            (DnsRCode::NXDomain, vec![])
+        );
+        // A profile's rules only apply to its clients, and win at the same zone
+        assert_eq!(
+            ask_as(Some("kids"), "blocked.ads.example.com", DnsRRType::A).1,
+            vec![DnsRecordData::A("198.51.100.81".parse().unwrap())]
+        );
+        assert_eq!(
+            ask_as(Some("kids"), "blocked.example.net", DnsRRType::A).1,
+            vec![DnsRecordData::A("192.0.2.80".parse().unwrap())]
+        );
+        assert_eq!(
+            ask_as(Some("lan"), "blocked.ads.example.com", DnsRRType::A).1,
+            vec![DnsRecordData::A("198.51.100.80".parse().unwrap())]
+        );
+        // Outside it, nonexistent names are redirected too
+        assert_eq!(
+            ask("gone.example.net", DnsRRType::AAAA),
+            (
+                DnsRCode::NoError,
+                vec![DnsRecordData::AAAA("2001:db8::80".parse().unwrap())]
+            )
+        );
+        assert_eq!(
+            ask("gone.example.net", DnsRRType::MX),
+            (DnsRCode::NoError, vec![])
+        );
+        assert_eq!(
+            ask("www.example.net", DnsRRType::A),
+            (
+                DnsRCode::NoError,
+                vec![DnsRecordData::A("192.0.2.1".parse().unwrap())]
+            )
+        );
+    }
+}
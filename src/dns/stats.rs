@@ -0,0 +1,213 @@
+// Server-wide counters and the list of queries currently being worked on, for diagnostics. The
+// report here is what gets logged on SIGUSR1.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use super::cache::CacheStats;
+use super::latency::LatencyHistogram;
+use super::memory::MemoryBudget;
+use super::protocol::DnsQuestion;
+use super::upstream::UpstreamStatus;
+
+#[derive(Clone, Debug)]
+struct InFlightQuery {
+    client: SocketAddr,
+    // Filled in once the query's been parsed
+    question: Option<String>,
+    started: Instant,
+}
+
+#[derive(Debug)]
+pub struct ServerStats {
+    started: Instant,
+    received: AtomicU64,
+    answered: AtomicU64,
+    failed: AtomicU64,
+    next_id: AtomicU64,
+    // Keyed by an id of our own rather than the DNS txid, which clients are free to reuse
+    in_flight: Mutex<BTreeMap<u64, InFlightQuery>>,
+}
+
+impl Default for ServerStats {
+    fn default() -> ServerStats {
+        ServerStats::new()
+    }
+}
+
+impl ServerStats {
+    pub fn new() -> ServerStats {
+        ServerStats {
+            started: Instant::now(),
+            received: AtomicU64::new(0),
+            answered: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            next_id: AtomicU64::new(0),
+            in_flight: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    // Count a query from `client` and list it as in flight until the returned tracker is dropped
+    pub fn begin(self: &Arc<Self>, client: SocketAddr) -> QueryTracker {
+        self.received.fetch_add(1, Ordering::Relaxed);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.in_flight.lock().unwrap().insert(
+            id,
+            InFlightQuery {
+                client,
+                question: None,
+                started: Instant::now(),
+            },
+        );
+        QueryTracker {
+            stats: self.clone(),
+            id,
+            answered: false,
+        }
+    }
+
+    // How many queries are being answered right now
+    pub fn in_flight_count(&self) -> usize {
+        self.in_flight.lock().unwrap().len()
+    }
+
+    // Uptime and query counts, a line each
+    pub fn summary(&self) -> Vec<String> {
+        vec![
+            format!("uptime: {}s", self.started.elapsed().as_secs()),
+            format!(
+                "queries: {} received, {} answered, {} failed",
+                self.received.load(Ordering::Relaxed),
+                self.answered.load(Ordering::Relaxed),
+                self.failed.load(Ordering::Relaxed)
+            ),
+        ]
+    }
+
+    // A human readable snapshot of everything we track. Cache, upstream, and memory numbers live
+    // with the resolver and the memory budget, so they're passed in.
+    pub fn report(
+        &self,
+        cache: CacheStats,
+        cache_memory: usize,
+        upstreams: &[UpstreamStatus],
+        latencies: &[(SocketAddr, LatencyHistogram)],
+        budget: &MemoryBudget,
+    ) -> String {
+        let mut out = String::new();
+        writeln!(out, "=== montague statistics ===").unwrap();
+        for line in self.summary() {
+            writeln!(out, "{}", line).unwrap();
+        }
+        writeln!(
+            out,
+            "cache: {} hits, {} misses, {} insertions, {} evictions, {} expirations, ~{} bytes",
+            cache.hits,
+            cache.misses,
+            cache.insertions,
+            cache.evictions,
+            cache.expirations,
+            cache_memory
+        )
+        .unwrap();
+        for upstream in upstreams {
+            writeln!(out, "upstream: {}", upstream).unwrap();
+        }
+        for (addr, histogram) in latencies {
+            writeln!(out, "latency: {} {}", addr, histogram).unwrap();
+        }
+        writeln!(
+            out,
+            "memory: ~{} bytes in flight, {} queries truncated, {} refused, {} dropped",
+            budget.in_flight(),
+            budget.truncated_count(),
+            budget.shed_count(),
+            budget.dropped_count()
+        )
+        .unwrap();
+        let in_flight = self.in_flight.lock().unwrap();
+        writeln!(out, "in flight: {}", in_flight.len()).unwrap();
+        for (id, query) in in_flight.iter() {
+            writeln!(
+                out,
+                "  [q{}] {} {} ({}ms)",
+                id,
+                query.client,
+                query.question.as_deref().unwrap_or("(not parsed yet)"),
+                query.started.elapsed().as_millis()
+            )
+            .unwrap();
+        }
+        out
+    }
+}
+
+// A query's entry in the in-flight list. Dropping it without calling `answered` counts the query
+// as failed.
+pub struct QueryTracker {
+    stats: Arc<ServerStats>,
+    id: u64,
+    answered: bool,
+}
+
+impl QueryTracker {
+    pub fn set_question(&self, question: &DnsQuestion) {
+        if let Some(query) = self.stats.in_flight.lock().unwrap().get_mut(&self.id) {
+            query.question = Some(question.to_string());
+        }
+    }
+
+    // The query's trace ID, which its log lines are tagged with
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn answered(mut self) {
+        self.answered = true;
+    }
+}
+
+impl Drop for QueryTracker {
+    fn drop(&mut self) {
+        let counter = if self.answered {
+            &self.stats.answered
+        } else {
+            &self.stats.failed
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        self.stats.in_flight.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::dns::mock;
+    use crate::dns::protocol::DnsRRType;
+
+    #[test]
+    fn report_lists_queries_in_flight() {
+        let stats = Arc::new(ServerStats::new());
+        let budget = MemoryBudget::new(0);
+        let client: SocketAddr = "192.0.2.1:5353".parse().unwrap();
+
+        let slow = stats.begin(client);
+        slow.set_question(&mock::question("slow.example.com", DnsRRType::A));
+        stats.begin(client).answered();
+        drop(stats.begin(client));
+
+        let report = stats.report(CacheStats::default(), 0, &[], &[], &budget);
+        assert!(report.contains("queries: 3 received, 1 answered, 1 failed"));
+        assert!(report.contains("in flight: 1\n"));
+        assert!(report.contains("192.0.2.1:5353 slow.example.com.\tIN\tA"));
+
+        slow.answered();
+        let report = stats.report(CacheStats::default(), 0, &[], &[], &budget);
+        assert!(report.contains("in flight: 0\n"));
+    }
+}
@@ -0,0 +1,77 @@
+// Opcode 2, server status (RFC 1035), and the other opcodes we don't implement. RFC 1035 never
+// said what a status request or response holds, so most servers answer NOTIMP, which is what we
+// do too unless told otherwise. Told otherwise, the answer is a few lines about how the server is
+// doing, as CH class TXT records owned by the root, in the spirit of version.bind.
+
+use super::pipeline::error_response;
+use super::protocol::{
+    DnsClass, DnsOpcode, DnsPacket, DnsRCode, DnsRRType, DnsRecordData, DnsResourceRecord,
+};
+use super::stats::ServerStats;
+
+// Whether the query has an opcode the query pipeline doesn't handle. Updates are answered on
+// their own before this is asked.
+pub fn is_other_opcode(query: &DnsPacket) -> bool {
+    query.flags.opcode != DnsOpcode::Query
+}
+
+// Answer a query with an opcode other than Query or Update. `stats` is what to report on for a
+// status request, if we're reporting at all.
+pub fn answer(query: &DnsPacket, stats: Option<&ServerStats>) -> DnsPacket {
+    let stats = match (query.flags.opcode, stats) {
+        (DnsOpcode::Status, Some(stats)) => stats,
+        _ => return error_response(query, DnsRCode::NotImp),
+    };
+    let mut response = error_response(query, DnsRCode::NoError);
+    let mut lines = vec![format!("montague {}", env!("CARGO_PKG_VERSION"))];
+    lines.extend(stats.summary());
+    response.answers = lines
+        .into_iter()
+        .map(|line| DnsResourceRecord {
+            name: Vec::new(),
+            rr_type: DnsRRType::TXT,
+            class: DnsClass::CH,
+            ttl: 0,
+            record: DnsRecordData::TXT(vec![line.into_bytes()]),
+        })
+        .collect();
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::dns::mock;
+
+    #[test]
+    fn status_is_answered_only_when_enabled() {
+        let stats = ServerStats::new();
+        let mut query = mock::query("example.com", DnsRRType::A);
+        query.id = 9;
+        query.flags.opcode = DnsOpcode::Status;
+        assert!(is_other_opcode(&query));
+
+        let refused = answer(&query, None);
+        assert_eq!(refused.flags.rcode, DnsRCode::NotImp);
+        assert_eq!(refused.id, 9);
+        assert_eq!(refused.flags.opcode, DnsOpcode::Status);
+        assert!(refused.flags.qr_bit && refused.flags.rd_bit);
+        assert!(refused.answers.is_empty());
+
+        let status = answer(&query, Some(&stats));
+        assert_eq!(status.flags.rcode, DnsRCode::NoError);
+        assert_eq!(status.answers.len(), 3);
+        assert!(status.answers.iter().all(|rr| rr.class == DnsClass::CH));
+        assert_eq!(
+            status.answers[1].record,
+            DnsRecordData::TXT(vec![b"uptime: 0s".to_vec()])
+        );
+
+        // Only status requests get one
+        query.flags.opcode = DnsOpcode::Zone;
+        assert_eq!(answer(&query, Some(&stats)).flags.rcode, DnsRCode::NotImp);
+        query.flags.opcode = DnsOpcode::Query;
+        assert!(!is_other_opcode(&query));
+    }
+}
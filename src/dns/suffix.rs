@@ -0,0 +1,210 @@
+// Matching names against large sets of zones, like blocklists, sinkhole rules, and stub zones.
+// Names are stored by their labels from the root down, so finding the most specific zone
+// containing a name takes one step per label of the name, however many zones there are.
+//
+// To keep millions of entries small, the trie is path compressed: a run of labels with nothing
+// stored partway along it and no branches off it is one edge rather than a node per label, which
+// is the common case for blocklists full of unrelated names. Each node's edges are kept in a
+// sorted Vec and binary searched by their first label, rather than a HashMap per node.
+
+#[derive(Clone, Debug)]
+pub struct SuffixTrie<T> {
+    root: Node<T>,
+    len: usize,
+}
+
+#[derive(Clone, Debug)]
+struct Node<T> {
+    value: Option<T>,
+    // Sorted by their first label, which is unique among a node's edges
+    edges: Vec<Edge<T>>,
+}
+
+#[derive(Clone, Debug)]
+struct Edge<T> {
+    // Lowercase, from the root down
+    labels: Box<[Box<str>]>,
+    node: Node<T>,
+}
+
+impl<T> Node<T> {
+    fn new(value: Option<T>) -> Node<T> {
+        Node {
+            value,
+            edges: Vec::new(),
+        }
+    }
+
+    fn find(&self, label: &str) -> Result<usize, usize> {
+        self.edges
+            .binary_search_by(|edge| edge.labels[0].as_ref().cmp(label))
+    }
+}
+
+impl<T> Default for SuffixTrie<T> {
+    fn default() -> SuffixTrie<T> {
+        SuffixTrie::new()
+    }
+}
+
+// Only insert and longest_match are used so far; the rest is for sets of names like blocklists
+impl<T> SuffixTrie<T> {
+    pub fn new() -> SuffixTrie<T> {
+        SuffixTrie {
+            root: Node::new(None),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Store `value` for the zone `name`, returning whatever was stored for it before
+    pub fn insert(&mut self, name: &[String], value: T) -> Option<T> {
+        let labels = key(name);
+        let mut node = &mut self.root;
+        let mut rest = &labels[..];
+        while !rest.is_empty() {
+            let index = match node.find(&rest[0]) {
+                Ok(index) => index,
+                Err(index) => {
+                    node.edges.insert(
+                        index,
+                        Edge {
+                            labels: rest.to_vec().into_boxed_slice(),
+                            node: Node::new(Some(value)),
+                        },
+                    );
+                    self.len += 1;
+                    return None;
+                }
+            };
+            let edge = &mut node.edges[index];
+            let common = edge
+                .labels
+                .iter()
+                .zip(rest)
+                .take_while(|(a, b)| a == b)
+                .count();
+            if common < edge.labels.len() {
+                // The name ends or branches off partway along this edge, so split it there
+                let below = Edge {
+                    labels: edge.labels[common..].to_vec().into_boxed_slice(),
+                    node: std::mem::replace(&mut edge.node, Node::new(None)),
+                };
+                edge.labels = edge.labels[..common].to_vec().into_boxed_slice();
+                edge.node.edges.push(below);
+            }
+            node = &mut edge.node;
+            rest = &rest[common..];
+        }
+        let previous = node.value.replace(value);
+        if previous.is_none() {
+            self.len += 1;
+        }
+        previous
+    }
+
+    // What's stored for exactly `name`
+    pub fn get(&self, name: &[String]) -> Option<&T> {
+        self.walk(name)
+            .pop()
+            .and_then(|(depth, value)| (depth == name.len()).then_some(value))
+    }
+
+    // What's stored for the most specific zone containing `name`, `name` itself included
+    pub fn longest_match(&self, name: &[String]) -> Option<&T> {
+        self.walk(name).pop().map(|(_, value)| value)
+    }
+
+    // Whether `name` is in any zone stored here
+    pub fn contains(&self, name: &[String]) -> bool {
+        self.longest_match(name).is_some()
+    }
+
+    // The values stored along `name`'s path from the root, with how many labels deep each is
+    fn walk(&self, name: &[String]) -> Vec<(usize, &T)> {
+        let labels = key(name);
+        let mut found = Vec::new();
+        let mut node = &self.root;
+        let mut rest = &labels[..];
+        loop {
+            if let Some(value) = &node.value {
+                found.push((labels.len() - rest.len(), value));
+            }
+            let edge = match rest.first().map(|label| node.find(label)) {
+                Some(Ok(index)) => &node.edges[index],
+                _ => return found,
+            };
+            if edge.labels.len() > rest.len() || edge.labels[..] != rest[..edge.labels.len()] {
+                return found;
+            }
+            node = &edge.node;
+            rest = &rest[edge.labels.len()..];
+        }
+    }
+}
+
+// A name's labels from the root down, lowercased since names compare without case
+fn key(name: &[String]) -> Vec<Box<str>> {
+    name.iter()
+        .rev()
+        .map(|label| label.to_ascii_lowercase().into_boxed_str())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::dns::mock::labels;
+
+    #[test]
+    fn finds_the_most_specific_zone() {
+        let mut trie = SuffixTrie::new();
+        assert!(trie.is_empty());
+        assert_eq!(trie.insert(&labels("ads.tracker.example.com"), 1), None);
+        assert_eq!(trie.insert(&labels("cdn.example.com"), 2), None);
+        // Splits the compressed example.com edge partway along
+        assert_eq!(trie.insert(&labels("example.com"), 3), None);
+        assert_eq!(trie.insert(&labels("example.net"), 4), None);
+        assert_eq!(trie.insert(&labels("EXAMPLE.com"), 5), Some(3));
+        assert_eq!(trie.len(), 4);
+
+        assert_eq!(
+            trie.longest_match(&labels("x.ads.Tracker.example.com")),
+            Some(&1)
+        );
+        assert_eq!(trie.longest_match(&labels("tracker.example.com")), Some(&5));
+        assert_eq!(trie.longest_match(&labels("img.cdn.example.com")), Some(&2));
+        assert_eq!(trie.longest_match(&labels("www.example.net")), Some(&4));
+        assert_eq!(trie.longest_match(&labels("example.org")), None);
+        assert_eq!(trie.longest_match(&labels("com")), None);
+        assert!(!trie.contains(&labels("tracker.example.org")));
+
+        assert_eq!(trie.get(&labels("example.com")), Some(&5));
+        assert_eq!(trie.get(&labels("tracker.example.com")), None);
+        assert_eq!(trie.get(&labels("ads.tracker.example.com")), Some(&1));
+
+        // The root zone contains everything
+        trie.insert(&[], 0);
+        assert_eq!(trie.longest_match(&labels("example.org")), Some(&0));
+        assert_eq!(trie.longest_match(&labels("www.example.net")), Some(&4));
+
+        let mut many = SuffixTrie::new();
+        for i in 0..10_000 {
+            many.insert(&labels(&format!("host{}.zone{}.example", i, i % 100)), i);
+        }
+        assert_eq!(many.len(), 10_000);
+        assert_eq!(
+            many.longest_match(&labels("www.host4242.zone42.example")),
+            Some(&4242)
+        );
+        assert_eq!(many.longest_match(&labels("host4242.zone43.example")), None);
+    }
+}
@@ -0,0 +1,348 @@
+// OpenTelemetry tracing: spans for handling a client's query, each step down the delegation
+// chain, the cache, and every query we send, exported over OTLP so resolutions show up in
+// whatever tracing backend is already collecting everything else. Like the trace IDs in the log,
+// the span being worked in is kept per thread; a new span is a child of it.
+//
+// Spans go out as OTLP/HTTP with JSON bodies (the protobuf encoding's JSON mapping), batched up
+// on a background thread. Until `start` is called, making spans costs next to nothing.
+
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::protocol::{name_to_string, DnsQuestion};
+use super::trace::{info, warning};
+
+// Spans are sent once this many are waiting, or this long after the first of them finished
+const BATCH_SIZE: usize = 512;
+const BATCH_DELAY: Duration = Duration::from_secs(1);
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+static SINK: OnceLock<Sender<FinishedSpan>> = OnceLock::new();
+
+thread_local! {
+    static CURRENT: Cell<Option<Context>> = const { Cell::new(None) };
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Context {
+    trace_id: u128,
+    span_id: u64,
+}
+
+// Who's calling whom, which backends use to draw a span (OTLP's SpanKind)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpanKind {
+    Internal = 1,
+    // Answering a client
+    Server = 2,
+    // Asking another server
+    Client = 3,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AttributeValue {
+    String(String),
+    Int(i64),
+    Bool(bool),
+}
+
+impl From<&str> for AttributeValue {
+    fn from(value: &str) -> Self {
+        AttributeValue::String(value.to_owned())
+    }
+}
+
+impl From<String> for AttributeValue {
+    fn from(value: String) -> Self {
+        AttributeValue::String(value)
+    }
+}
+
+impl From<i64> for AttributeValue {
+    fn from(value: i64) -> Self {
+        AttributeValue::Int(value)
+    }
+}
+
+impl From<bool> for AttributeValue {
+    fn from(value: bool) -> Self {
+        AttributeValue::Bool(value)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct FinishedSpan {
+    context: Context,
+    parent: Option<u64>,
+    name: &'static str,
+    kind: SpanKind,
+    start: SystemTime,
+    duration: Duration,
+    attributes: Vec<(&'static str, AttributeValue)>,
+    error: Option<String>,
+}
+
+// A span that's open until it's dropped. Everything the thread does meanwhile is inside it.
+pub struct Span {
+    // None when spans aren't being exported
+    inner: Option<OpenSpan>,
+}
+
+struct OpenSpan {
+    span: FinishedSpan,
+    started: Instant,
+    previous: Option<Context>,
+}
+
+pub fn span(name: &'static str, kind: SpanKind) -> Span {
+    if SINK.get().is_none() {
+        return Span { inner: None };
+    }
+    let parent = CURRENT.with(Cell::get);
+    let context = Context {
+        trace_id: parent.map_or_else(
+            || u128::from(random()) << 64 | u128::from(random()),
+            |p| p.trace_id,
+        ),
+        span_id: random(),
+    };
+    CURRENT.with(|current| current.set(Some(context)));
+    Span {
+        inner: Some(OpenSpan {
+            span: FinishedSpan {
+                context,
+                parent: parent.map(|p| p.span_id),
+                name,
+                kind,
+                start: SystemTime::now(),
+                duration: Duration::default(),
+                attributes: Vec::new(),
+                error: None,
+            },
+            started: Instant::now(),
+            previous: parent,
+        }),
+    }
+}
+
+impl Span {
+    pub fn attribute<V: Into<AttributeValue>>(&mut self, key: &'static str, value: V) {
+        if let Some(open) = &mut self.inner {
+            open.span.attributes.push((key, value.into()));
+        }
+    }
+
+    pub fn question(&mut self, question: &DnsQuestion) {
+        self.attribute("dns.question.name", name_to_string(&question.qname));
+        self.attribute("dns.question.type", question.qtype.to_string());
+    }
+
+    // Mark the span as failed
+    pub fn error(&mut self, message: &str) {
+        if let Some(open) = &mut self.inner {
+            open.span.error = Some(message.to_owned());
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some(mut open) = self.inner.take() {
+            CURRENT.with(|current| current.set(open.previous));
+            open.span.duration = open.started.elapsed();
+            if let Some(sink) = SINK.get() {
+                let _ = sink.send(open.span);
+            }
+        }
+    }
+}
+
+// Export spans to the OTLP/HTTP collector at `endpoint`, given as HOST:PORT with an optional
+// http:// in front
+pub fn start(endpoint: &str) -> Result<(), String> {
+    let host = endpoint.trim_start_matches("http://").trim_end_matches('/');
+    let addr = host
+        .to_socket_addrs()
+        .map_err(|e| format!("{}: {}", endpoint, e))?
+        .next()
+        .ok_or_else(|| format!("{} has no addresses", endpoint))?;
+    let (sender, receiver) = mpsc::channel();
+    SINK.set(sender)
+        .map_err(|_| String::from("Spans are already being exported"))?;
+    info!("Exporting traces to http://{}/v1/traces", host);
+    let host = host.to_owned();
+    thread::spawn(move || export(&receiver, addr, &host));
+    Ok(())
+}
+
+fn export(spans: &Receiver<FinishedSpan>, addr: SocketAddr, host: &str) {
+    loop {
+        let mut batch = match spans.recv() {
+            Ok(span) => vec![span],
+            Err(_) => return,
+        };
+        let deadline = Instant::now() + BATCH_DELAY;
+        while batch.len() < BATCH_SIZE {
+            match spans.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(span) => batch.push(span),
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+        if let Err(e) = post(addr, host, &encode(&batch)) {
+            warning!("Couldn't export {} spans to {}: {}", batch.len(), host, e);
+        }
+    }
+}
+
+fn post(addr: SocketAddr, host: &str, body: &str) -> io::Result<()> {
+    let mut stream = TcpStream::connect_timeout(&addr, EXPORT_TIMEOUT)?;
+    stream.set_read_timeout(Some(EXPORT_TIMEOUT))?;
+    stream.set_write_timeout(Some(EXPORT_TIMEOUT))?;
+    write!(
+        stream,
+        "POST /v1/traces HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        host,
+        body.len(),
+        body
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let status = response.lines().next().unwrap_or_default();
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!("collector said {:?}", status))),
+    }
+}
+
+// An ExportTraceServiceRequest in OTLP's JSON encoding
+fn encode(spans: &[FinishedSpan]) -> String {
+    let spans: Vec<String> = spans.iter().map(encode_span).collect();
+    format!(
+        "{{\"resourceSpans\":[{{\"resource\":{{\"attributes\":[{}]}},\
+         \"scopeSpans\":[{{\"scope\":{{\"name\":\"montague\"}},\"spans\":[{}]}}]}}]}}",
+        encode_attribute("service.name", &AttributeValue::from("montague")),
+        spans.join(",")
+    )
+}
+
+fn encode_span(span: &FinishedSpan) -> String {
+    let nanos = |time: SystemTime| {
+        time.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+    };
+    let mut json = format!(
+        "{{\"traceId\":\"{:032x}\",\"spanId\":\"{:016x}\",",
+        span.context.trace_id, span.context.span_id
+    );
+    if let Some(parent) = span.parent {
+        json.push_str(&format!("\"parentSpanId\":\"{:016x}\",", parent));
+    }
+    let attributes: Vec<String> = span
+        .attributes
+        .iter()
+        .map(|(key, value)| encode_attribute(key, value))
+        .collect();
+    // Status codes are 1 for OK and 2 for an error
+    let status = match &span.error {
+        Some(message) => format!("{{\"code\":2,\"message\":{}}}", json_string(message)),
+        None => String::from("{\"code\":1}"),
+    };
+    json.push_str(&format!(
+        "\"name\":{},\"kind\":{},\"startTimeUnixNano\":\"{}\",\"endTimeUnixNano\":\"{}\",\
+         \"attributes\":[{}],\"status\":{}}}",
+        json_string(span.name),
+        span.kind as u8,
+        nanos(span.start),
+        nanos(span.start + span.duration),
+        attributes.join(","),
+        status
+    ));
+    json
+}
+
+fn encode_attribute(key: &str, value: &AttributeValue) -> String {
+    // 64 bit integers are strings in OTLP's JSON, since JavaScript can't hold them
+    let value = match value {
+        AttributeValue::String(s) => format!("{{\"stringValue\":{}}}", json_string(s)),
+        AttributeValue::Int(i) => format!("{{\"intValue\":\"{}\"}}", i),
+        AttributeValue::Bool(b) => format!("{{\"boolValue\":{}}}", b),
+    };
+    format!("{{\"key\":{},\"value\":{}}}", json_string(key), value)
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// IDs only have to be unlikely to collide, not unpredictable. Each RandomState is seeded
+// randomly, and the counter keeps two IDs from the same one apart.
+fn random() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spans_nest_and_encode_as_otlp() {
+        let (sender, receiver) = mpsc::channel();
+        SINK.set(sender).unwrap();
+        {
+            let mut query = span("dns.query", SpanKind::Server);
+            query.attribute("dns.question.name", "example.com.");
+            let mut upstream = span("dns.upstream", SpanKind::Client);
+            upstream.attribute("net.peer.port", 53i64);
+            upstream.error("timed out");
+        }
+        // Other tests running alongside can make spans too, so only look at ours
+        let spans: Vec<FinishedSpan> = receiver
+            .try_iter()
+            .filter(|span| span.name == "dns.query" || span.name == "dns.upstream")
+            .collect();
+        assert_eq!(spans.len(), 2);
+        let (upstream, query) = (&spans[0], &spans[1]);
+        assert_eq!(upstream.context.trace_id, query.context.trace_id);
+        assert_eq!(upstream.parent, Some(query.context.span_id));
+        assert_eq!(query.parent, None);
+        assert_eq!(CURRENT.with(Cell::get), None);
+
+        let json = encode(&spans);
+        assert!(json.starts_with(
+            "{\"resourceSpans\":[{\"resource\":{\"attributes\":[{\"key\":\"service.name\""
+        ));
+        assert!(json.contains(&format!(
+            "\"parentSpanId\":\"{:016x}\",\"name\":\"dns.upstream\",\"kind\":3",
+            query.context.span_id
+        )));
+        assert!(json.contains("{\"key\":\"net.peer.port\",\"value\":{\"intValue\":\"53\"}}"));
+        assert!(json.contains("\"status\":{\"code\":2,\"message\":\"timed out\"}"));
+        assert_eq!(json_string("a \"b\"\n"), "\"a \\\"b\\\"\\n\"");
+    }
+}
@@ -0,0 +1,287 @@
+// The server's log. Every line has a level, and only those at or above the one given with
+// --log-level are written. Lines are also tied to the query they're about: the server answers each
+// query on a thread of its own, so the query being worked on is kept per thread, and everything
+// logged on that thread carries its trace ID, along with the client, question and transaction ID
+// once they're known. That's the question, every authority or upstream asked, retries, the cache,
+// the answer. Lines logged outside of any query, like health checks and zone reloads, have none.
+//
+// Lines are text by default, e.g. "DEBUG [q42] Asking ...", or with --log-format=json one JSON
+// object each, with the query's details as fields of their own for log collectors to index:
+//
+//     {"time":1760000000.123,"level":"debug","query":42,"client":"192.0.2.1:5353",
+//      "txid":4660,"qname":"example.com.","qtype":"A","message":"Asking ..."}
+
+use std::cell::RefCell;
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::protocol::{name_to_string, DnsPacket};
+use super::telemetry::json_string;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Level, String> {
+        match s {
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            _ => Err(format!(
+                "{:?} isn't a log level (error, warn, info or debug)",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("{:?} isn't a log format (text or json)", s)),
+        }
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static JSON: AtomicBool = AtomicBool::new(false);
+
+pub fn configure(level: Level, format: Format) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+    JSON.store(format == Format::Json, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+// What's known about the query a thread is working on
+#[derive(Clone, Debug, PartialEq)]
+struct Query {
+    id: u64,
+    client: SocketAddr,
+    txid: Option<u16>,
+    // The first question's name and type
+    question: Option<(String, String)>,
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Query>> = const { RefCell::new(None) };
+}
+
+// The thread is working on query `id` until this is dropped
+pub struct Trace {
+    previous: Option<Query>,
+}
+
+pub fn enter(id: u64, client: SocketAddr) -> Trace {
+    let query = Query {
+        id,
+        client,
+        txid: None,
+        question: None,
+    };
+    Trace {
+        previous: CURRENT.with(|current| current.replace(Some(query))),
+    }
+}
+
+impl Drop for Trace {
+    fn drop(&mut self) {
+        CURRENT.with(|current| *current.borrow_mut() = self.previous.take());
+    }
+}
+
+// Tie what's logged from here on to the transaction ID and question of `packet`, once it's parsed
+pub fn describe(packet: &DnsPacket) {
+    CURRENT.with(|current| {
+        if let Some(query) = current.borrow_mut().as_mut() {
+            query.txid = Some(packet.id);
+            query.question = packet.questions.first().map(|question| {
+                (
+                    name_to_string(&question.qname),
+                    format!("{:?}", question.qtype),
+                )
+            });
+        }
+    });
+}
+
+// Write a line at `level`, if the log takes lines that detailed. Called through the macros below,
+// so messages at levels that are turned off are never formatted.
+pub fn write(level: Level, message: fmt::Arguments) {
+    if !enabled(level) {
+        return;
+    }
+    let line = CURRENT.with(|current| {
+        let query = current.borrow();
+        if JSON.load(Ordering::Relaxed) {
+            json_line(level, query.as_ref(), message)
+        } else {
+            text_line(level, query.as_ref(), message)
+        }
+    });
+    println!("{}", line);
+}
+
+fn text_line(level: Level, query: Option<&Query>, message: fmt::Arguments) -> String {
+    let level = level.name().to_ascii_uppercase();
+    match query {
+        Some(query) => format!("{:<5} [q{}] {}", level, query.id, message),
+        None => format!("{:<5} {}", level, message),
+    }
+}
+
+fn json_line(level: Level, query: Option<&Query>, message: fmt::Arguments) -> String {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    let mut line = format!("{{\"time\":{:.3},\"level\":\"{}\"", time, level.name());
+    if let Some(query) = query {
+        line.push_str(&format!(
+            ",\"query\":{},\"client\":{}",
+            query.id,
+            json_string(&query.client.to_string())
+        ));
+        if let Some(txid) = query.txid {
+            line.push_str(&format!(",\"txid\":{}", txid));
+        }
+        if let Some((qname, qtype)) = &query.question {
+            line.push_str(&format!(
+                ",\"qname\":{},\"qtype\":{}",
+                json_string(qname),
+                json_string(qtype)
+            ));
+        }
+    }
+    line.push_str(&format!(
+        ",\"message\":{}}}",
+        json_string(&message.to_string())
+    ));
+    line
+}
+
+// The detail of what a query or background task is doing, e.g. "[q42] Asking ..."
+#[doc(hidden)]
+#[macro_export]
+macro_rules! log {
+    ($($arg:tt)*) => {
+        $crate::dns::trace::write($crate::dns::trace::Level::Debug, format_args!($($arg)*))
+    };
+}
+
+// What an operator would want to know about without asking: listeners, zones loaded and reloaded
+#[doc(hidden)]
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::dns::trace::write($crate::dns::trace::Level::Info, format_args!($($arg)*))
+    };
+}
+
+// Something went wrong, but we carried on
+#[doc(hidden)]
+#[macro_export]
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        $crate::dns::trace::write($crate::dns::trace::Level::Warn, format_args!($($arg)*))
+    };
+}
+
+// Something went wrong that shouldn't have, like a bug or a socket failing
+#[doc(hidden)]
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::dns::trace::write($crate::dns::trace::Level::Error, format_args!($($arg)*))
+    };
+}
+// Exported so the server binary can log too, and re-exported here, where they belong
+pub use crate::{error, info, log, warning};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::dns::mock;
+    use crate::dns::protocol::DnsRRType;
+
+    fn current() -> Option<u64> {
+        CURRENT.with(|current| current.borrow().as_ref().map(|query| query.id))
+    }
+
+    #[test]
+    fn traces_nest_and_stay_on_their_thread() {
+        let client = "192.0.2.1:5353".parse().unwrap();
+        assert_eq!(current(), None);
+        let outer = enter(1, client);
+        {
+            let _inner = enter(2, client);
+            assert_eq!(current(), Some(2));
+            std::thread::spawn(|| assert_eq!(current(), None))
+                .join()
+                .unwrap();
+        }
+        assert_eq!(current(), Some(1));
+
+        let mut query = mock::query("example.com", DnsRRType::AAAA);
+        query.id = 0x1234;
+        describe(&query);
+        let line = CURRENT.with(|current| {
+            json_line(
+                Level::Warn,
+                current.borrow().as_ref(),
+                format_args!("said \"{}\"", "hi"),
+            )
+        });
+        assert!(line.starts_with("{\"time\":"));
+        assert!(line.ends_with(
+            ",\"level\":\"warn\",\"query\":1,\"client\":\"192.0.2.1:5353\",\"txid\":4660,\
+             \"qname\":\"example.com.\",\"qtype\":\"AAAA\",\"message\":\"said \\\"hi\\\"\"}"
+        ));
+        assert_eq!(
+            text_line(Level::Info, None, format_args!("Reloaded")),
+            "INFO  Reloaded"
+        );
+        drop(outer);
+        assert_eq!(current(), None);
+
+        assert!(Level::Error < Level::Debug);
+        assert!(enabled(Level::Warn) && !enabled(Level::Debug));
+        assert!("verbose".parse::<Level>().is_err());
+        assert_eq!("json".parse(), Ok(Format::Json));
+    }
+}
@@ -0,0 +1,174 @@
+// Zone transfers to secondaries: AXFR, and IXFR answered from the zone's journal of updates when
+// it has every change the secondary is missing, or with the whole zone otherwise (RFC 1995
+// section 4 allows that). Who gets them is up to an AccessPolicy; anyone else is refused and
+// logged, since a transfer hands over the whole zone.
+
+use std::net::IpAddr;
+
+use super::access::{self, AccessPolicy};
+use super::journal;
+use super::pipeline::error_response;
+use super::protocol::{name_to_string, sign_tsig, DnsPacket, DnsRCode, DnsRRType, DnsRecordData};
+use super::resolver::Resolver;
+use super::trace::{info, log};
+
+pub fn is_transfer(query: &DnsPacket) -> bool {
+    query.questions.len() == 1
+        && matches!(query.questions[0].qtype, DnsRRType::AXF | DnsRRType::IXFR)
+}
+
+// Answer a transfer request, given as it came off the wire as well as parsed, since a TSIG
+// signature covers the exact bytes
+pub fn answer(
+    message: &[u8],
+    query: &DnsPacket,
+    client: IpAddr,
+    policy: &AccessPolicy,
+    resolver: &Resolver,
+) -> DnsPacket {
+    let question = &query.questions[0];
+    let zone_name = name_to_string(&question.qname);
+    let now = access::now();
+    let zone = match resolver.zone(&question.qname) {
+        Some(zone) => zone,
+        None => {
+            log!(
+                "Refused transfer of {} to {}: not a zone we serve",
+                zone_name,
+                client
+            );
+            return error_response(query, DnsRCode::NotAuth);
+        }
+    };
+    let signed = match policy.check(&question.qname, message, client, now) {
+        Ok(signed) => signed,
+        Err(refusal) => {
+            log!(
+                "Refused transfer of {} to {}: {}",
+                zone_name,
+                client,
+                refusal.reason
+            );
+            return refusal.response(query, message, now);
+        }
+    };
+
+    let mut response = error_response(query, DnsRCode::NoError);
+    response.flags.aa_bit = true;
+    // An IXFR carries the serial the client has; if it's current, our SOA alone says so
+    let client_serial = query.nameservers.iter().find_map(|rr| match &rr.record {
+        DnsRecordData::SOA(soa) if question.qtype == DnsRRType::IXFR => Some(soa.serial),
+        _ => None,
+    });
+    let changes = match (client_serial, &zone.journal) {
+        (Some(serial), Some(path)) => journal::changes_since(path, serial, zone.serial()),
+        _ => None,
+    };
+    response.answers = match (client_serial, changes) {
+        (Some(serial), _) if (zone.serial().wrapping_sub(serial) as i32) <= 0 => {
+            vec![zone.soa().to_owned()]
+        }
+        // The current SOA, each change as removals then additions, and the current SOA again
+        (_, Some(changes)) => {
+            let mut records = vec![zone.soa().to_owned()];
+            for diff in changes {
+                records.extend(diff.removed);
+                records.extend(diff.added);
+            }
+            records.push(zone.soa().to_owned());
+            records
+        }
+        _ => zone.transfer_records(),
+    };
+    info!(
+        "Transferring {} at serial {} to {} ({} records)",
+        zone_name,
+        zone.serial(),
+        client,
+        response.answers.len()
+    );
+    if let Some(signed) = signed {
+        sign_tsig(&mut response, &signed, now);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    use crate::dns::config::{ResolverConfig, ResolverOpts};
+    use crate::dns::mock;
+    use crate::dns::protocol::TsigKey;
+    use crate::dns::zone::Zone;
+
+    #[test]
+    fn transfers_need_an_allowed_address_and_key() {
+        let zone = Zone::parse(
+            &mock::labels("example.test"),
+            "@ 300 SOA ns hostmaster 5 2 3 4 5\n@ 300 NS ns\nns 300 A 192.0.2.53\n",
+        )
+        .unwrap();
+        let config = ResolverConfig {
+            zones: vec![zone],
+            ..ResolverConfig::default()
+        };
+        let resolver = Resolver::new(config, ResolverOpts::default());
+        let key: TsigKey = "transfer.key:c2VjcmV0".parse().unwrap();
+        let mut policy = AccessPolicy::new(vec![key]);
+        let secondary = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        let run = |policy: &AccessPolicy, query: &DnsPacket, client| {
+            answer(&query.to_bytes(), query, client, policy, &resolver)
+        };
+
+        let query = mock::query("example.test", DnsRRType::AXF);
+        assert_eq!(
+            run(&policy, &query, secondary).flags.rcode,
+            DnsRCode::Refused
+        );
+
+        policy.allow(
+            &mock::labels("example.test"),
+            vec!["192.0.2.0/24".parse().unwrap()],
+        );
+        let response = run(&policy, &query, secondary);
+        assert_eq!(response.flags.rcode, DnsRCode::NoError);
+        let types: Vec<DnsRRType> = response.answers.iter().map(|rr| rr.rr_type).collect();
+        assert_eq!(types.len(), 4);
+        assert_eq!(types[0], DnsRRType::SOA);
+        assert_eq!(types[3], DnsRRType::SOA);
+        let stranger = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1));
+        assert_eq!(
+            run(&policy, &query, stranger).flags.rcode,
+            DnsRCode::Refused
+        );
+        let elsewhere = mock::query("other.test", DnsRRType::AXF);
+        assert_eq!(
+            run(&policy, &elsewhere, secondary).flags.rcode,
+            DnsRCode::NotAuth
+        );
+
+        // An IXFR from a secondary that's up to date gets just the SOA
+        let mut ixfr = mock::query("example.test", DnsRRType::IXFR);
+        ixfr.nameservers = vec![resolver
+            .zone(&mock::labels("example.test"))
+            .unwrap()
+            .transfer_records()[0]
+            .to_owned()];
+        assert_eq!(run(&policy, &ixfr, secondary).answers.len(), 1);
+
+        // Once a key's required, the same request from the same address isn't enough
+        assert!(policy
+            .require_key(&mock::labels("example.test"), &mock::labels("no.such.key"))
+            .is_err());
+        policy
+            .require_key(&mock::labels("example.test"), &mock::labels("transfer.key"))
+            .unwrap();
+        assert_eq!(
+            run(&policy, &query, secondary).flags.rcode,
+            DnsRCode::NotAuth
+        );
+    }
+}
@@ -0,0 +1,112 @@
+// Configured TTLs for names, replacing whatever TTL their records come with. A rule applies to
+// records at and below its name, with the most specific rule winning. Served zones have them
+// applied as they're loaded, and answers from elsewhere as they come in, so the cache keeps them
+// for the overridden time too.
+
+use std::str::FromStr;
+
+use super::protocol::{is_subdomain, name_from_string, DnsPacket, DnsRRType, DnsResourceRecord};
+use super::zone;
+
+#[derive(Clone, Debug, Default)]
+pub struct TtlOverrides {
+    rules: Vec<TtlOverride>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct TtlOverride {
+    pub name: Vec<String>,
+    pub ttl: u32,
+}
+
+// "NAME=TTL", where the TTL can have units like a master file's ("1h30m")
+impl FromStr for TtlOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TtlOverride, String> {
+        let (name, ttl) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Expected NAME=TTL, got {:?}", s))?;
+        Ok(TtlOverride {
+            name: name_from_string(name)?,
+            ttl: zone::parse_ttl(ttl)?,
+        })
+    }
+}
+
+impl TtlOverrides {
+    pub fn new(rules: Vec<TtlOverride>) -> TtlOverrides {
+        TtlOverrides { rules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn ttl_for(&self, name: &[String]) -> Option<u32> {
+        self.rules
+            .iter()
+            .filter(|rule| is_subdomain(name, &rule.name))
+            .max_by_key(|rule| rule.name.len())
+            .map(|rule| rule.ttl)
+    }
+
+    pub fn apply<'a>(&self, records: impl IntoIterator<Item = &'a mut DnsResourceRecord>) {
+        if self.is_empty() {
+            return;
+        }
+        for rr in records {
+            // An OPT record's TTL field holds EDNS flags, not a TTL
+            if rr.rr_type == DnsRRType::OPT {
+                continue;
+            }
+            if let Some(ttl) = self.ttl_for(&rr.name) {
+                rr.ttl = ttl;
+            }
+        }
+    }
+
+    pub fn apply_to_response(&self, response: &mut DnsPacket) {
+        self.apply(
+            response
+                .answers
+                .iter_mut()
+                .chain(response.nameservers.iter_mut())
+                .chain(response.addl_recs.iter_mut()),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    use crate::dns::mock;
+    use crate::dns::protocol::opt_record;
+
+    #[test]
+    fn most_specific_override_wins() {
+        let overrides = TtlOverrides::new(vec![
+            "example.com=10m".parse().unwrap(),
+            "www.example.com=30".parse().unwrap(),
+        ]);
+        assert!("example.com".parse::<TtlOverride>().is_err());
+        assert!("example.com=soon".parse::<TtlOverride>().is_err());
+
+        let mut response = mock::query("www.example.com", DnsRRType::A);
+        response.answers = vec![
+            mock::cname("www.example.com", "web.example.com"),
+            mock::a("web.example.com", Ipv4Addr::new(192, 0, 2, 1)),
+        ];
+        response.nameservers = vec![mock::ns("example.org", "ns.example.org")];
+        response.addl_recs = vec![opt_record(1232)];
+        response.addl_recs[0].ttl = 0x8000;
+        overrides.apply_to_response(&mut response);
+        let ttls: Vec<u32> = response.answers.iter().map(|rr| rr.ttl).collect();
+        assert_eq!(ttls, vec![30, 600]);
+        assert_eq!(response.nameservers[0].ttl, 3600);
+        assert_eq!(response.addl_recs[0].ttl, 0x8000);
+    }
+}
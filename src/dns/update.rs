@@ -0,0 +1,424 @@
+// Dynamic updates (RFC 2136): changes to a zone sent as DNS messages, with prerequisites that have
+// to hold for the change to go through. Who may send them is up to an AccessPolicy. An accepted
+// update is written to the zone's journal before it's applied, so it survives a restart.
+//
+// An UPDATE message reuses the sections of a query: the question is the zone, the answers are
+// the prerequisites, and the authority records are the changes.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use super::access::{self, AccessPolicy};
+use super::journal;
+use super::pipeline::error_response;
+use super::protocol::{
+    is_subdomain, name_to_string, names_equal, sign_tsig, DnsClass, DnsOpcode, DnsPacket, DnsRCode,
+    DnsRRType, DnsRecordData, DnsResourceRecord, NameKey,
+};
+use super::resolver::Resolver;
+use super::trace::{error, info, log};
+use super::zone::{self, Zone, ZoneDiff};
+
+pub fn is_update(query: &DnsPacket) -> bool {
+    query.flags.opcode == DnsOpcode::Update
+}
+
+// Answer an update, given as it came off the wire as well as parsed, since a TSIG signature covers
+// the exact bytes
+pub fn answer(
+    message: &[u8],
+    query: &DnsPacket,
+    client: IpAddr,
+    policy: &AccessPolicy,
+    resolver: &Resolver,
+) -> DnsPacket {
+    if query.questions.len() != 1 || query.questions[0].qtype != DnsRRType::SOA {
+        return error_response(query, DnsRCode::FormError);
+    }
+    let origin = &query.questions[0].qname;
+    let zone_name = name_to_string(origin);
+    let now = access::now();
+    if resolver.zone(origin).is_none() {
+        log!(
+            "Refused update of {} from {}: not a zone we serve",
+            zone_name,
+            client
+        );
+        return error_response(query, DnsRCode::NotAuth);
+    }
+    let signed = match policy.check(origin, message, client, now) {
+        Ok(signed) => signed,
+        Err(refusal) => {
+            log!(
+                "Refused update of {} from {}: {}",
+                zone_name,
+                client,
+                refusal.reason
+            );
+            return refusal.response(query, message, now);
+        }
+    };
+
+    let result = resolver.change_zone(origin, |zone| {
+        check_prerequisites(zone, &query.answers)?;
+        let diff = match changes(zone, &query.nameservers)? {
+            Some(diff) => diff,
+            None => return Ok(None),
+        };
+        if let Some(path) = &zone.journal {
+            journal::append(path, &diff).map_err(|e| {
+                error!("Couldn't journal update to {}: {}", zone_name, e);
+                DnsRCode::ServFail
+            })?;
+        }
+        zone.apply(&diff).map_err(|e| {
+            error!("BUG: update to {} didn't apply: {}", zone_name, e);
+            DnsRCode::ServFail
+        })?;
+        Ok(Some(diff))
+    });
+    let rcode = match result {
+        Some(Ok(Some(diff))) => {
+            info!(
+                "Updated {} to serial {} for {} ({} records removed, {} added)",
+                zone_name,
+                diff.new_serial().unwrap_or_default(),
+                client,
+                diff.removed.len() - 1,
+                diff.added.len() - 1
+            );
+            DnsRCode::NoError
+        }
+        Some(Ok(None)) => DnsRCode::NoError,
+        Some(Err(rcode)) => {
+            log!(
+                "Update of {} from {} failed: {:?}",
+                zone_name,
+                client,
+                rcode
+            );
+            rcode
+        }
+        // Removed while we were checking who was asking
+        None => DnsRCode::NotAuth,
+    };
+    let mut response = error_response(query, rcode);
+    if let Some(signed) = signed {
+        sign_tsig(&mut response, &signed, now);
+    }
+    response
+}
+
+// Section 3.2. Every prerequisite has to hold for the update to go ahead.
+fn check_prerequisites(zone: &Zone, prerequisites: &[DnsResourceRecord]) -> Result<(), DnsRCode> {
+    // Whole RRsets that have to exist exactly as given
+    let mut rrsets: HashMap<(NameKey, u16), Vec<&DnsResourceRecord>> = HashMap::new();
+    for rr in prerequisites {
+        if rr.ttl != 0 {
+            return Err(DnsRCode::FormError);
+        }
+        if !is_subdomain(&rr.name, &zone.origin) {
+            return Err(DnsRCode::NotZone);
+        }
+        let records = zone.records_named(&rr.name);
+        let in_use = !records.is_empty() || zone.has_alias(&rr.name);
+        let rrset_exists = records
+            .iter()
+            .any(|existing| existing.rr_type == rr.rr_type);
+        match (rr.class, rr.rr_type) {
+            (DnsClass::ANY, _) | (DnsClass::NONE, _) if !has_no_data(rr) => {
+                return Err(DnsRCode::FormError)
+            }
+            (DnsClass::ANY, DnsRRType::ANY) if !in_use => return Err(DnsRCode::NXDomain),
+            (DnsClass::ANY, _) if rr.rr_type != DnsRRType::ANY && !rrset_exists => {
+                return Err(DnsRCode::NXRRSet)
+            }
+            (DnsClass::NONE, DnsRRType::ANY) if in_use => return Err(DnsRCode::YXDomain),
+            (DnsClass::NONE, _) if rr.rr_type != DnsRRType::ANY && rrset_exists => {
+                return Err(DnsRCode::YXRRSet)
+            }
+            (DnsClass::ANY, _) | (DnsClass::NONE, _) => (),
+            (DnsClass::IN, _) => rrsets
+                .entry((NameKey::new(&rr.name), rr.rr_type as u16))
+                .or_default()
+                .push(rr),
+            _ => return Err(DnsRCode::FormError),
+        }
+    }
+    for wanted in rrsets.into_values() {
+        let existing: Vec<DnsResourceRecord> = zone
+            .records_named(&wanted[0].name)
+            .into_iter()
+            .filter(|rr| rr.rr_type == wanted[0].rr_type)
+            .collect();
+        let same = existing.len() == wanted.len()
+            && wanted
+                .iter()
+                .all(|rr| existing.iter().any(|e| e.record == rr.record));
+        if !same {
+            return Err(DnsRCode::NXRRSet);
+        }
+    }
+    Ok(())
+}
+
+// Sections 3.4.1 and 3.4.2: what the update section does to the zone, as a diff that bumps the
+// serial, or None if it doesn't change anything
+fn changes(zone: &Zone, updates: &[DnsResourceRecord]) -> Result<Option<ZoneDiff>, DnsRCode> {
+    // Checked in full before anything's changed, so a bad update changes nothing
+    for rr in updates {
+        if !is_subdomain(&rr.name, &zone.origin) {
+            return Err(DnsRCode::NotZone);
+        }
+        let meta = matches!(
+            rr.rr_type,
+            DnsRRType::AXF | DnsRRType::IXFR | DnsRRType::OPT | DnsRRType::TSIG
+        );
+        match rr.class {
+            DnsClass::IN if meta || rr.rr_type == DnsRRType::ANY => {
+                return Err(DnsRCode::FormError)
+            }
+            // We can't keep what the master file parser can't read back
+            DnsClass::IN if !zone::is_supported_type(rr.rr_type) => return Err(DnsRCode::Refused),
+            DnsClass::ANY if rr.ttl != 0 || !has_no_data(rr) || meta => {
+                return Err(DnsRCode::FormError)
+            }
+            DnsClass::NONE if rr.ttl != 0 || meta || rr.rr_type == DnsRRType::ANY => {
+                return Err(DnsRCode::FormError)
+            }
+            DnsClass::IN | DnsClass::ANY | DnsClass::NONE => (),
+            _ => return Err(DnsRCode::FormError),
+        }
+    }
+
+    // The names the update touches, as they'll be afterwards
+    let mut names: HashMap<NameKey, (&[String], Vec<DnsResourceRecord>)> = HashMap::new();
+    let mut new_soa = None;
+    for rr in updates {
+        let apex = names_equal(&rr.name, &zone.origin);
+        let (_, records) = names
+            .entry(NameKey::new(&rr.name))
+            .or_insert_with(|| (&rr.name, zone.records_named(&rr.name)));
+        match rr.class {
+            DnsClass::IN if rr.rr_type == DnsRRType::SOA => {
+                // Only a newer serial replaces the SOA (RFC 1982 comparison)
+                let current = new_soa.as_ref().unwrap_or_else(|| zone.soa());
+                if apex && serial_of(rr).wrapping_sub(serial_of(current)) as i32 > 0 {
+                    new_soa = Some(rr.to_owned());
+                }
+            }
+            DnsClass::IN => {
+                // A CNAME can't share its name with anything else
+                let conflicts = records.iter().any(|existing| {
+                    (existing.rr_type == DnsRRType::CNAME) != (rr.rr_type == DnsRRType::CNAME)
+                });
+                let alias = zone.has_alias(&rr.name)
+                    && matches!(
+                        rr.rr_type,
+                        DnsRRType::CNAME | DnsRRType::A | DnsRRType::AAAA
+                    );
+                if conflicts || alias {
+                    continue;
+                }
+                records.retain(|existing| {
+                    existing.rr_type != rr.rr_type || existing.record != rr.record
+                });
+                records.push(rr.to_owned());
+            }
+            DnsClass::ANY => {
+                // The apex keeps its SOA and NS records no matter what
+                records.retain(|existing| {
+                    let protected =
+                        apex && matches!(existing.rr_type, DnsRRType::SOA | DnsRRType::NS);
+                    let matches = rr.rr_type == DnsRRType::ANY || existing.rr_type == rr.rr_type;
+                    protected || !matches
+                });
+            }
+            _ => {
+                let ns_left = records
+                    .iter()
+                    .filter(|existing| existing.rr_type == DnsRRType::NS)
+                    .count();
+                let last_ns = apex && rr.rr_type == DnsRRType::NS && ns_left <= 1;
+                if rr.rr_type == DnsRRType::SOA || last_ns {
+                    continue;
+                }
+                records.retain(|existing| {
+                    existing.rr_type != rr.rr_type || existing.record != rr.record
+                });
+            }
+        }
+    }
+
+    let mut removed = Vec::new();
+    let mut added = Vec::new();
+    for (name, after) in names.values() {
+        let before = zone.records_named(name);
+        let differs = |rr: &DnsResourceRecord, others: &[DnsResourceRecord]| {
+            rr.rr_type != DnsRRType::SOA && !others.contains(rr)
+        };
+        removed.extend(before.iter().filter(|rr| differs(rr, after)).cloned());
+        added.extend(after.iter().filter(|rr| differs(rr, &before)).cloned());
+    }
+    if removed.is_empty() && added.is_empty() && new_soa.is_none() {
+        return Ok(None);
+    }
+    // Every change to the zone gets a new serial, whether or not the update gave one (3.6)
+    let new_soa = new_soa.unwrap_or_else(|| {
+        let mut soa = zone.soa().to_owned();
+        if let DnsRecordData::SOA(data) = &mut soa.record {
+            data.serial = data.serial.wrapping_add(1);
+        }
+        soa
+    });
+    removed.insert(0, zone.soa().to_owned());
+    added.insert(0, new_soa);
+    Ok(Some(ZoneDiff { removed, added }))
+}
+
+fn has_no_data(rr: &DnsResourceRecord) -> bool {
+    rr.record == DnsRecordData::Other(Vec::new())
+}
+
+fn serial_of(rr: &DnsResourceRecord) -> u32 {
+    match &rr.record {
+        DnsRecordData::SOA(soa) => soa.serial,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    use crate::dns::config::{ResolverConfig, ResolverOpts};
+    use crate::dns::mock;
+
+    const ZONE: &str = "@ 300 SOA ns hostmaster 5 2 3 4 5
+@ 300 NS ns
+ns 300 A 192.0.2.53
+www 300 A 192.0.2.1
+www 300 A 192.0.2.2
+";
+
+    fn update(prerequisites: Vec<DnsResourceRecord>, updates: Vec<DnsResourceRecord>) -> DnsPacket {
+        let mut message = mock::query("example.test", DnsRRType::SOA);
+        message.flags.opcode = DnsOpcode::Update;
+        message.answers = prerequisites;
+        message.nameservers = updates;
+        message
+    }
+
+    // A record for the prerequisite and update sections, which use the class and TTL for their
+    // own purposes
+    fn special(name: &str, rr_type: DnsRRType, class: DnsClass) -> DnsResourceRecord {
+        DnsResourceRecord {
+            name: mock::labels(name),
+            rr_type,
+            class,
+            ttl: 0,
+            record: DnsRecordData::Other(Vec::new()),
+        }
+    }
+
+    #[test]
+    fn updates_follow_rfc_2136() {
+        let origin = mock::labels("example.test");
+        let config = ResolverConfig {
+            zones: vec![Zone::parse(&origin, ZONE).unwrap()],
+            ..ResolverConfig::default()
+        };
+        let resolver = Resolver::new(config, ResolverOpts::default());
+        let mut policy = AccessPolicy::new(Vec::new());
+        let client = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 9));
+        let run = |policy: &AccessPolicy, message: &DnsPacket| {
+            answer(&message.to_bytes(), message, client, policy, &resolver)
+                .flags
+                .rcode
+        };
+        let records = |name: &str, rr_type| -> Vec<DnsRecordData> {
+            let zone = resolver.zone(&origin).unwrap();
+            zone.records_named(&mock::labels(name))
+                .into_iter()
+                .filter(|rr| rr.rr_type == rr_type)
+                .map(|rr| rr.record)
+                .collect()
+        };
+        let serial = || resolver.zone(&origin).unwrap().serial();
+        let mail = mock::a("mail.example.test", Ipv4Addr::new(192, 0, 2, 25));
+        let add_mail = update(Vec::new(), vec![mail.to_owned()]);
+
+        assert_eq!(run(&policy, &add_mail), DnsRCode::Refused);
+        policy.allow(&origin, vec!["192.0.2.0/24".parse().unwrap()]);
+
+        // Only if mail doesn't exist yet, which it doesn't
+        let mut create = add_mail.to_owned();
+        create.answers = vec![special("mail.example.test", DnsRRType::ANY, DnsClass::NONE)];
+        assert_eq!(run(&policy, &create), DnsRCode::NoError);
+        assert_eq!(
+            records("mail.example.test", DnsRRType::A),
+            vec![mail.record.to_owned()]
+        );
+        assert_eq!(serial(), 6);
+        // ...and now it does, so nothing happens
+        assert_eq!(run(&policy, &create), DnsRCode::YXDomain);
+        assert_eq!(serial(), 6);
+        // Adding what's already there isn't a change
+        assert_eq!(run(&policy, &add_mail), DnsRCode::NoError);
+        assert_eq!(serial(), 6);
+
+        // A prerequisite on the exact contents of an RRset
+        let www = |last| mock::a("www.example.test", Ipv4Addr::new(192, 0, 2, last));
+        let mut exact = vec![www(1), www(2)];
+        exact.iter_mut().for_each(|rr| rr.ttl = 0);
+        let swap = update(
+            exact.to_owned(),
+            vec![
+                special("www.example.test", DnsRRType::A, DnsClass::ANY),
+                www(3),
+            ],
+        );
+        assert_eq!(run(&policy, &swap), DnsRCode::NoError);
+        assert_eq!(
+            records("www.example.test", DnsRRType::A),
+            vec![www(3).record]
+        );
+        assert_eq!(run(&policy, &swap), DnsRCode::NXRRSet);
+
+        // A CNAME can't join other data, and the apex keeps its last NS
+        let cname = mock::cname("www.example.test", "elsewhere.example");
+        let mut ns = mock::ns("example.test", "ns.example.test");
+        ns.class = DnsClass::NONE;
+        ns.ttl = 0;
+        assert_eq!(
+            run(&policy, &update(Vec::new(), vec![cname, ns])),
+            DnsRCode::NoError
+        );
+        assert!(records("www.example.test", DnsRRType::CNAME).is_empty());
+        assert_eq!(records("example.test", DnsRRType::NS).len(), 1);
+        assert_eq!(serial(), 7);
+
+        // Deleting a whole name, and things that aren't allowed at all
+        let delete = update(
+            Vec::new(),
+            vec![special("mail.example.test", DnsRRType::ANY, DnsClass::ANY)],
+        );
+        assert_eq!(run(&policy, &delete), DnsRCode::NoError);
+        assert!(records("mail.example.test", DnsRRType::A).is_empty());
+        let outside = update(
+            Vec::new(),
+            vec![mock::a("www.example.org", Ipv4Addr::new(192, 0, 2, 1))],
+        );
+        assert_eq!(run(&policy, &outside), DnsRCode::NotZone);
+        let mut sshfp = www(4);
+        sshfp.rr_type = DnsRRType::SSHFP;
+        sshfp.record = DnsRecordData::Other(vec![0; 8]);
+        assert_eq!(
+            run(&policy, &update(Vec::new(), vec![sshfp])),
+            DnsRCode::Refused
+        );
+        assert_eq!(serial(), 8);
+    }
+}
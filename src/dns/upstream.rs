@@ -0,0 +1,591 @@
+// The upstream resolvers we forward to, and how healthy each one looks. Health comes from two
+// places: the outcome of every query we forward, and a background thread that probes each
+// upstream on an interval so a dead one is noticed (and a recovered one brought back) even when
+// no queries are going its way.
+//
+// Upstreams are arranged in failover groups in priority order, e.g. a corporate resolver first
+// and a public one second. Queries go to the active group first. When every upstream in it has
+// been unhealthy for `failover_after`, the next group with a healthy upstream takes over; when a
+// higher priority group has been healthy again for `failback_after`, it takes back over. The
+// delays keep a flapping upstream from bouncing traffic back and forth.
+//
+// Upstreams with a TLS name are asked over DNS over TLS, through a pool of connections kept open
+// to each one; the rest are asked over plain UDP, unless they designate an encrypted resolver of
+// their own (RFC 9462) and we've been told to look for one.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rustls::RootCertStore;
+
+use super::config::{ResolverOpts, Upstream};
+use super::ddr;
+use super::dot::DotPool;
+use super::error::ResolveError;
+use super::protocol::{DnsClass, DnsPacket, DnsQuestion, DnsRCode, DnsRRType, ExtendedDnsError};
+use super::recursive::{self, RootHints};
+use super::telemetry::{self, SpanKind};
+use super::trace::{info, log, warning};
+
+// Weight given to each new latency sample in the moving average
+const LATENCY_WEIGHT: f64 = 0.2;
+// How long to wait before asking an upstream that designated nothing again
+const REDISCOVER_AFTER: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct UpstreamStatus {
+    pub addr: SocketAddr,
+    // Index of the failover group this upstream belongs to, 0 being the highest priority
+    pub group: usize,
+    pub healthy: bool,
+    // When `healthy` last changed, or when we started if it never has
+    pub since: Instant,
+    pub successes: u64,
+    pub failures: u64,
+    // Failures since the last success
+    pub consecutive_failures: u32,
+    // Moving average of successful round trips
+    pub latency: Option<Duration>,
+}
+
+impl fmt::Display for UpstreamStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let total = self.successes + self.failures;
+        let success_rate = if total == 0 {
+            100.0
+        } else {
+            self.successes as f64 * 100.0 / total as f64
+        };
+        write!(
+            f,
+            "{} (group {}) {}: {:.1}% of {} queries succeeded",
+            self.addr,
+            self.group,
+            if self.healthy { "healthy" } else { "UNHEALTHY" },
+            success_rate,
+            total
+        )?;
+        if let Some(latency) = self.latency {
+            write!(f, ", ~{}ms", latency.as_millis())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    statuses: Vec<UpstreamStatus>,
+    active_group: usize,
+}
+
+impl State {
+    fn groups(&self) -> usize {
+        self.statuses.iter().map(|s| s.group + 1).max().unwrap_or(0)
+    }
+
+    // How long `group` has had a healthy upstream, or None if it has none
+    fn up_for(&self, group: usize, now: Instant) -> Option<Duration> {
+        self.members(group)
+            .filter(|s| s.healthy)
+            .map(|s| now.saturating_duration_since(s.since))
+            .max()
+    }
+
+    // How long every upstream in `group` has been unhealthy, or None if one is healthy
+    fn down_for(&self, group: usize, now: Instant) -> Option<Duration> {
+        if self.members(group).any(|s| s.healthy) {
+            return None;
+        }
+        self.members(group)
+            .map(|s| now.saturating_duration_since(s.since))
+            .min()
+    }
+
+    fn members(&self, group: usize) -> impl Iterator<Item = &UpstreamStatus> {
+        self.statuses.iter().filter(move |s| s.group == group)
+    }
+}
+
+#[derive(Debug)]
+pub struct Upstreams {
+    state: Mutex<State>,
+    // Consecutive failures before an upstream is taken out of rotation
+    unhealthy_after: u32,
+    failover_after: Duration,
+    failback_after: Duration,
+    hints: RootHints,
+    // For the upstreams we talk to over TLS, whether configured that way or designated
+    pools: RwLock<HashMap<SocketAddr, Arc<DotPool>>>,
+    // The plain upstreams to look for designated resolvers for, and when to next look
+    designations: Mutex<HashMap<SocketAddr, Instant>>,
+}
+
+impl Upstreams {
+    // Round trip times go into the hints' latencies as well as the moving average each status
+    // keeps. TLSA records for DANE are looked up starting at the hints too.
+    pub fn new(groups: &[Vec<Upstream>], opts: &ResolverOpts, hints: &RootHints) -> Upstreams {
+        let now = Instant::now();
+        let mut statuses = Vec::new();
+        let mut pools = HashMap::new();
+        let mut designations = HashMap::new();
+        for (group, upstreams) in groups.iter().enumerate() {
+            for upstream in upstreams {
+                if let Some(profile) = &upstream.tls {
+                    // Profiles were checked when the upstream was parsed
+                    let pool = DotPool::new(upstream.addr, profile, hints, opts)
+                        .expect("valid privacy profile");
+                    pools.insert(upstream.addr, Arc::new(pool));
+                } else if opts.discover_designated {
+                    designations.insert(upstream.addr, now);
+                }
+            }
+            statuses.extend(upstreams.iter().map(|upstream| UpstreamStatus {
+                addr: upstream.addr,
+                group,
+                healthy: true,
+                since: now,
+                successes: 0,
+                failures: 0,
+                consecutive_failures: 0,
+                latency: None,
+            }));
+        }
+        Upstreams {
+            state: Mutex::new(State {
+                statuses,
+                active_group: 0,
+            }),
+            unhealthy_after: opts.unhealthy_after,
+            failover_after: opts.failover_after,
+            failback_after: opts.failback_after,
+            hints: hints.to_owned(),
+            pools: RwLock::new(pools),
+            designations: Mutex::new(designations),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state.lock().unwrap().statuses.is_empty()
+    }
+
+    pub fn candidates(&self) -> Vec<SocketAddr> {
+        self.candidates_at(Instant::now())
+    }
+
+    // Upstreams to try, in order: the healthy ones in the active group, then healthy ones in the
+    // other groups by priority, then the unhealthy ones as a last resort, since trying a server
+    // that's probably down beats not trying at all
+    fn candidates_at(&self, now: Instant) -> Vec<SocketAddr> {
+        let mut state = self.state.lock().unwrap();
+        self.update_active_group(&mut state, now);
+        let active = state.active_group;
+        let mut ordered: Vec<&UpstreamStatus> = state.statuses.iter().collect();
+        // Stable, so configuration order breaks ties
+        ordered.sort_by_key(|s| (!s.healthy, s.group != active, s.group));
+        ordered.iter().map(|s| s.addr).collect()
+    }
+
+    fn update_active_group(&self, state: &mut State, now: Instant) {
+        let active = state.active_group;
+        // Fail back to the highest priority group that's been up long enough
+        if let Some(group) = (0..active)
+            .find(|g| matches!(state.up_for(*g, now), Some(up) if up >= self.failback_after))
+        {
+            info!("Upstream group {} has recovered, failing back to it", group);
+            state.active_group = group;
+            return;
+        }
+        // Fail over if the active group has been down long enough and something else is up
+        if matches!(state.down_for(active, now), Some(down) if down >= self.failover_after) {
+            let groups = state.groups();
+            let next = (active + 1..groups)
+                .chain(0..active)
+                .find(|g| state.up_for(*g, now).is_some());
+            if let Some(group) = next {
+                warning!(
+                    "Upstream group {} has been down for {}s, failing over to group {}",
+                    active,
+                    self.failover_after.as_secs(),
+                    group
+                );
+                state.active_group = group;
+            }
+        }
+    }
+
+    pub fn statuses(&self) -> Vec<UpstreamStatus> {
+        self.state.lock().unwrap().statuses.to_owned()
+    }
+
+    // Record how a query to `addr` went: its round trip time, or None if it failed
+    pub fn record(&self, addr: SocketAddr, outcome: Option<Duration>) {
+        self.record_at(addr, outcome, Instant::now());
+    }
+
+    fn record_at(&self, addr: SocketAddr, outcome: Option<Duration>, now: Instant) {
+        self.hints.latencies.record(addr, outcome);
+        let mut state = self.state.lock().unwrap();
+        let status = match state.statuses.iter_mut().find(|s| s.addr == addr) {
+            Some(status) => status,
+            None => return,
+        };
+        match outcome {
+            Some(rtt) => {
+                status.successes += 1;
+                status.consecutive_failures = 0;
+                if !status.healthy {
+                    info!("Upstream {} is healthy again", addr);
+                    status.healthy = true;
+                    status.since = now;
+                }
+                status.latency = Some(match status.latency {
+                    Some(average) => {
+                        average.mul_f64(1.0 - LATENCY_WEIGHT) + rtt.mul_f64(LATENCY_WEIGHT)
+                    }
+                    None => rtt,
+                });
+            }
+            None => {
+                status.failures += 1;
+                status.consecutive_failures += 1;
+                if status.healthy && status.consecutive_failures >= self.unhealthy_after {
+                    warning!(
+                        "Upstream {} failed {} times in a row, taking it out of rotation",
+                        addr,
+                        status.consecutive_failures
+                    );
+                    status.healthy = false;
+                    status.since = now;
+                }
+            }
+        }
+    }
+
+    // Forward a question to the first upstream that gives a usable answer, recording how each
+    // one we try does
+    pub fn forward(
+        &self,
+        question: &DnsQuestion,
+        opts: &ResolverOpts,
+    ) -> Result<DnsPacket, ResolveError> {
+        let mut span = telemetry::span("dns.forward", SpanKind::Internal);
+        span.question(question);
+        let mut last_error = String::from("No upstreams configured");
+        for addr in self.candidates() {
+            let started = Instant::now();
+            match self.ask(question, addr, opts) {
+                Ok(response) if usable(&response) => {
+                    self.record(addr, Some(started.elapsed()));
+                    return Ok(response);
+                }
+                Ok(response) => {
+                    self.record(addr, None);
+                    last_error =
+                        ResolveError::ServerFailure(addr, response.flags.rcode).to_string();
+                }
+                Err(e) => {
+                    self.record(addr, None);
+                    last_error = e.to_string();
+                }
+            }
+        }
+        span.error(&last_error);
+        Err(ResolveError::AllServersFailed(last_error))
+    }
+
+    fn ask(
+        &self,
+        question: &DnsQuestion,
+        addr: SocketAddr,
+        opts: &ResolverOpts,
+    ) -> Result<DnsPacket, ResolveError> {
+        let pool = self.pools.read().unwrap().get(&addr).cloned();
+        match pool {
+            Some(pool) => pool.query(question, opts),
+            None => recursive::forward_question(question, addr, opts),
+        }
+    }
+
+    // Probe every upstream once with a query for the root's NS records, which any working
+    // recursive resolver can answer (usually from cache)
+    pub fn probe_all(&self, opts: &ResolverOpts) {
+        let question = DnsQuestion {
+            qname: Vec::new(),
+            qtype: DnsRRType::NS,
+            qclass: DnsClass::IN,
+        };
+        let addrs: Vec<SocketAddr> = self.statuses().iter().map(|s| s.addr).collect();
+        for addr in addrs {
+            let started = Instant::now();
+            let outcome = match self.ask(&question, addr, opts) {
+                Ok(response) if usable(&response) => Some(started.elapsed()),
+                _ => None,
+            };
+            self.record(addr, outcome);
+        }
+    }
+
+    // Upgrade plain upstreams to the encrypted resolvers they designate, for each one we haven't
+    // asked yet or whose designation has expired
+    pub fn discover_designated(&self, opts: &ResolverOpts) {
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        self.discover_designated_at(roots, opts, Instant::now());
+    }
+
+    fn discover_designated_at(&self, roots: RootCertStore, opts: &ResolverOpts, now: Instant) {
+        let due: Vec<SocketAddr> = self
+            .designations
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, next)| **next <= now)
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in due {
+            let next = match ddr::discover(addr, roots.to_owned(), &self.hints, opts) {
+                Ok(Some((pool, ttl))) => {
+                    log!(
+                        "Upstream {} designates {}, asking it over TLS instead",
+                        addr,
+                        pool.addr()
+                    );
+                    self.pools.write().unwrap().insert(addr, Arc::new(pool));
+                    now + ttl.max(opts.health_check_interval)
+                }
+                Ok(None) => {
+                    self.pools.write().unwrap().remove(&addr);
+                    now + REDISCOVER_AFTER
+                }
+                // Whatever it designated before still stands
+                Err(e) => {
+                    warning!("{}", e);
+                    now + REDISCOVER_AFTER
+                }
+            };
+            self.designations.lock().unwrap().insert(addr, next);
+        }
+    }
+
+    // Probe every `opts.health_check_interval` on a background thread, for as long as these
+    // upstreams are still in use. Designated resolvers are looked for straight away.
+    pub fn start_health_checks(self: &Arc<Self>, opts: &ResolverOpts) {
+        let upstreams: Weak<Upstreams> = Arc::downgrade(self);
+        let opts = opts.to_owned();
+        thread::spawn(move || loop {
+            match upstreams.upgrade() {
+                Some(upstreams) => upstreams.discover_designated(&opts),
+                None => return,
+            }
+            thread::sleep(opts.health_check_interval);
+            match upstreams.upgrade() {
+                Some(upstreams) => upstreams.probe_all(&opts),
+                None => return,
+            }
+        });
+    }
+}
+
+// SERVFAIL and REFUSED mean the upstream couldn't or wouldn't help; anything else is an answer.
+// The exception is a SERVFAIL because DNSSEC validation failed: that's the upstream doing its job,
+// and any other validating upstream would say the same.
+fn usable(response: &DnsPacket) -> bool {
+    match response.flags.rcode {
+        DnsRCode::ServFail => ExtendedDnsError::from_packet(response)
+            .iter()
+            .any(ExtendedDnsError::is_dnssec),
+        DnsRCode::Refused => false,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    use crate::dns::config::DnssecMode;
+    use crate::dns::mock::{self, Behavior, MockNetwork, MockTlsServer, Script};
+    use crate::dns::protocol::{DnsRecordData, EdeCode};
+
+    const BROKEN: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 5);
+    const WORKING: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 6);
+
+    #[test]
+    fn unhealthy_upstreams_leave_rotation() {
+        let network = MockNetwork::new();
+        let broken = network.serve(
+            BROKEN,
+            Script::new().otherwise(Behavior::Rcode(DnsRCode::ServFail)),
+        );
+        let working = network.serve(
+            WORKING,
+            Script::new().otherwise(Behavior::Answer(vec![mock::ns("", "a.root-servers.net")])),
+        );
+        let opts = ResolverOpts {
+            unhealthy_after: 2,
+            ..ResolverOpts::default()
+        };
+        let upstreams = Upstreams::new(
+            &[vec![broken.addr.into(), working.addr.into()]],
+            &opts,
+            &RootHints::default(),
+        );
+        let question = mock::question("example.com", DnsRRType::A);
+
+        // The broken upstream gets tried first until it's failed twice
+        for _ in 0..3 {
+            upstreams
+                .forward(&question, &opts)
+                .expect("should fall back");
+        }
+        assert_eq!(broken.queries().len(), 2);
+        assert_eq!(working.queries().len(), 3);
+        assert_eq!(upstreams.candidates(), vec![working.addr, broken.addr]);
+
+        let statuses = upstreams.statuses();
+        assert!(!statuses[0].healthy);
+        assert_eq!(statuses[0].consecutive_failures, 2);
+        assert!(statuses[1].healthy);
+        assert_eq!(statuses[1].successes, 3);
+        assert!(statuses[1].latency.is_some());
+        assert!(statuses[0]
+            .to_string()
+            .contains("UNHEALTHY: 0.0% of 2 queries"));
+
+        // Probes keep checking on it, and a success brings it back
+        upstreams.probe_all(&opts);
+        assert_eq!(broken.queries().len(), 3);
+        upstreams.record(broken.addr, Some(Duration::from_millis(5)));
+        assert_eq!(upstreams.candidates(), vec![broken.addr, working.addr]);
+    }
+
+    #[test]
+    fn dnssec_failures_are_answers() {
+        let network = MockNetwork::new();
+        let error = ExtendedDnsError::new(EdeCode::SignatureExpired, "example.com RRSIG expired");
+        let validating = network.serve(
+            BROKEN,
+            Script::new().otherwise(Behavior::Extended(
+                DnsRCode::ServFail,
+                vec![error.to_owned()],
+            )),
+        );
+        let other = network.serve(WORKING, Script::new());
+        let opts = ResolverOpts {
+            dnssec: DnssecMode::TrustUpstream,
+            ..ResolverOpts::default()
+        };
+        let upstreams = Upstreams::new(
+            &[vec![validating.addr.into(), other.addr.into()]],
+            &opts,
+            &RootHints::default(),
+        );
+
+        let response = upstreams
+            .forward(&mock::question("example.com", DnsRRType::A), &opts)
+            .unwrap();
+        assert_eq!(response.flags.rcode, DnsRCode::ServFail);
+        assert_eq!(ExtendedDnsError::from_packet(&response), vec![error]);
+        assert!(other.queries().is_empty());
+        assert_eq!(upstreams.statuses()[0].successes, 1);
+    }
+
+    #[test]
+    fn failover_groups_have_hysteresis() {
+        let primary: SocketAddr = "192.0.2.1:53".parse().unwrap();
+        let secondary: SocketAddr = "198.51.100.1:53".parse().unwrap();
+        let opts = ResolverOpts {
+            unhealthy_after: 1,
+            failover_after: Duration::from_secs(30),
+            failback_after: Duration::from_secs(60),
+            ..ResolverOpts::default()
+        };
+        let upstreams = Upstreams::new(
+            &[vec![primary.into()], vec![secondary.into()]],
+            &opts,
+            &RootHints::default(),
+        );
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        // The primary going down only reorders queries until it's been down for 30s
+        upstreams.record_at(primary, None, at(0));
+        assert_eq!(upstreams.candidates_at(at(10)), vec![secondary, primary]);
+        assert_eq!(upstreams.state.lock().unwrap().active_group, 0);
+        assert_eq!(upstreams.candidates_at(at(30)), vec![secondary, primary]);
+        assert_eq!(upstreams.state.lock().unwrap().active_group, 1);
+
+        // Once it's back, the secondary stays active until the primary has been up for 60s
+        upstreams.record_at(primary, Some(Duration::from_millis(5)), at(40));
+        assert_eq!(upstreams.candidates_at(at(50)), vec![secondary, primary]);
+        upstreams.record_at(primary, None, at(60));
+        upstreams.record_at(primary, Some(Duration::from_millis(5)), at(70));
+        assert_eq!(upstreams.candidates_at(at(120)), vec![secondary, primary]);
+        assert_eq!(upstreams.candidates_at(at(130)), vec![primary, secondary]);
+        assert_eq!(upstreams.state.lock().unwrap().active_group, 0);
+    }
+    #[test]
+    fn plain_upstreams_upgrade_to_designated_resolvers() {
+        // Verification needs the certificate to be valid for the designating upstream's address
+        let tls = MockTlsServer::start(&["dot.test", "127.0.0.9"], Ipv4Addr::new(192, 0, 2, 53));
+        let designation = ddr::Designation {
+            priority: 1,
+            target: mock::labels("dot.test"),
+            alpn: vec![String::from("dot")],
+            port: Some(tls.addr.port()),
+            addresses: vec![tls.addr.ip()],
+            dohpath: None,
+        };
+        let mut svcb = mock::a(ddr::DESIGNATION_NAME, Ipv4Addr::LOCALHOST);
+        svcb.rr_type = DnsRRType::SVCB;
+        svcb.record = DnsRecordData::Other(designation.to_bytes());
+        let script = || {
+            Script::new()
+                .on(
+                    ddr::DESIGNATION_NAME,
+                    Some(DnsRRType::SVCB),
+                    Behavior::Answer(vec![svcb.to_owned()]),
+                )
+                .otherwise(Behavior::Answer(vec![mock::a(
+                    "www.example.com",
+                    Ipv4Addr::new(192, 0, 2, 1),
+                )]))
+        };
+        let network = MockNetwork::new();
+        let designating = network.serve(Ipv4Addr::new(127, 0, 0, 9), script());
+        // Designates the same endpoint, but can't vouch for it
+        let impostor = network.serve(Ipv4Addr::new(127, 0, 0, 10), script());
+        let opts = ResolverOpts {
+            discover_designated: true,
+            ..ResolverOpts::default()
+        };
+        let ask = |upstream: SocketAddr| {
+            let upstreams = Upstreams::new(&[vec![upstream.into()]], &opts, &RootHints::default());
+            upstreams.discover_designated_at(tls.roots(), &opts, Instant::now());
+            let response = upstreams
+                .forward(&mock::question("www.example.com", DnsRRType::A), &opts)
+                .unwrap();
+            response.answers[0].record.to_owned()
+        };
+
+        assert_eq!(
+            ask(designating.addr),
+            DnsRecordData::A(Ipv4Addr::new(192, 0, 2, 53))
+        );
+        assert_eq!(designating.queries().len(), 1);
+        assert_eq!(
+            ask(impostor.addr),
+            DnsRecordData::A(Ipv4Addr::new(192, 0, 2, 1))
+        );
+        assert_eq!(impostor.queries().len(), 2);
+        // The impostor's designation never got as far as a query
+        assert_eq!(tls.handshakes().0, 1);
+    }
+}
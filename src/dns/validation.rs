@@ -0,0 +1,76 @@
+// Optional strict checking of the names we handle. DNS itself allows pretty much any bytes in a
+// label, but some deployments only ever expect hostnames and would rather reject anything else
+// outright. The default is to not check at all.
+
+use std::str::FromStr;
+
+use super::protocol::{check_hostname, DnsPacket, DnsRecordData, SrvData};
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum HostnameValidation {
+    // Don't check names
+    #[default]
+    Off,
+    // Check names and log violations, but otherwise handle the query as normal
+    Log,
+    // Reject queries for names that aren't valid hostnames with FORMERR, and fail queries whose
+    // answers contain them
+    Strict,
+}
+
+impl FromStr for HostnameValidation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<HostnameValidation, String> {
+        match s {
+            "off" => Ok(HostnameValidation::Off),
+            "log" => Ok(HostnameValidation::Log),
+            "strict" => Ok(HostnameValidation::Strict),
+            _ => Err(format!(
+                "Unknown hostname validation mode {:?} (expected off, log, or strict)",
+                s
+            )),
+        }
+    }
+}
+
+// Describe each name in the question section that isn't a valid hostname
+pub fn question_violations(packet: &DnsPacket) -> Vec<String> {
+    let mut violations = Vec::new();
+    for question in &packet.questions {
+        if let Err(e) = check_hostname(&question.qname) {
+            violations.push(format!("question {}: {}", question, e));
+        }
+    }
+    violations
+}
+
+// Describe each name in the answer, authority, and additional sections that isn't a valid
+// hostname. This covers owner names as well as the names NS, CNAME, PTR, and MX records point to.
+pub fn record_violations(packet: &DnsPacket) -> Vec<String> {
+    let mut violations = Vec::new();
+    let records = packet
+        .answers
+        .iter()
+        .chain(packet.nameservers.iter())
+        .chain(packet.addl_recs.iter());
+    for rr in records {
+        if let Err(e) = check_hostname(&rr.name) {
+            violations.push(format!("record {}: {}", rr, e));
+            continue;
+        }
+        match &rr.record {
+            DnsRecordData::NS(target)
+            | DnsRecordData::CNAME(target)
+            | DnsRecordData::PTR(target)
+            | DnsRecordData::MX(_, target)
+            | DnsRecordData::SRV(SrvData { target, .. }) => {
+                if let Err(e) = check_hostname(target) {
+                    violations.push(format!("record {}: target {}", rr, e));
+                }
+            }
+            _ => (),
+        }
+    }
+    violations
+}
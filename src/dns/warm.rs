@@ -0,0 +1,117 @@
+// Warming the cache: looking up names we expect to be asked about before anyone asks, so the first
+// clients after a restart don't all wait on cold lookups. The list is a file with a name on each
+// line, optionally followed by the types to look up for it (A and AAAA if there aren't any), as in
+//
+//     www.example.com
+//     example.com MX TXT
+//
+// Anything after a # is a comment. Names are looked up one at a time in the background, and again
+// every interval if there is one, which keeps them fresh in the cache even once they've expired.
+
+use std::thread;
+use std::time::Duration;
+
+use super::protocol::{name_from_string, DnsClass, DnsQuestion, DnsRRType};
+use super::resolver::Resolver;
+use super::trace::{info, warning};
+
+// What's looked up for a name without any types after it
+const DEFAULT_TYPES: [DnsRRType; 2] = [DnsRRType::A, DnsRRType::AAAA];
+
+pub fn parse(text: &str) -> Result<Vec<DnsQuestion>, String> {
+    let mut questions = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut tokens = line.split_whitespace();
+        let name = match tokens.next() {
+            Some(name) => name,
+            None => continue,
+        };
+        let qname = name_from_string(name).map_err(|e| format!("line {}: {}", number + 1, e))?;
+        let mut types = tokens
+            .map(|token| token.parse::<DnsRRType>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("line {}: {}", number + 1, e))?;
+        if types.is_empty() {
+            types = DEFAULT_TYPES.to_vec();
+        }
+        questions.extend(types.into_iter().map(|qtype| DnsQuestion {
+            qname: qname.to_owned(),
+            qtype,
+            qclass: DnsClass::IN,
+        }));
+    }
+    Ok(questions)
+}
+
+// Look up every question afresh and cache the answers, returning how many could be looked up
+pub fn warm(resolver: &Resolver, questions: &[DnsQuestion]) -> usize {
+    let mut warmed = 0;
+    for question in questions {
+        match resolver.refresh(question) {
+            Ok(()) => warmed += 1,
+            Err(e) => warning!("Couldn't warm the cache with {}: {}", question, e),
+        }
+    }
+    warmed
+}
+
+// Warm the cache in the background, once or every `interval`
+pub fn start(resolver: Resolver, questions: Vec<DnsQuestion>, interval: Option<Duration>) {
+    thread::spawn(move || loop {
+        let warmed = warm(&resolver, &questions);
+        info!(
+            "Warmed the cache with {} of {} questions",
+            warmed,
+            questions.len()
+        );
+        match interval {
+            Some(interval) => thread::sleep(interval),
+            None => break,
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    use crate::dns::config::{ResolverConfig, ResolverOpts};
+    use crate::dns::mock::{self, Behavior, MockNetwork, Script};
+
+    #[test]
+    fn listed_names_are_cached() {
+        let questions = parse("# Popular names\nwww.example.com\n\nexample.com MX  # mail\n")
+            .expect("list should parse");
+        assert_eq!(
+            questions,
+            vec![
+                mock::question("www.example.com", DnsRRType::A),
+                mock::question("www.example.com", DnsRRType::AAAA),
+                mock::question("example.com", DnsRRType::MX),
+            ]
+        );
+        assert!(parse("www.example.com A NOTATYPE").is_err());
+
+        let network = MockNetwork::new();
+        let upstream = network.serve(
+            Ipv4Addr::new(127, 0, 0, 5),
+            Script::new().otherwise(Behavior::Answer(vec![mock::a(
+                "www.example.com",
+                Ipv4Addr::new(192, 0, 2, 1),
+            )])),
+        );
+        let resolver = Resolver::new(
+            ResolverConfig::upstreams(&[upstream.addr]),
+            ResolverOpts::default(),
+        );
+        assert_eq!(warm(&resolver, &questions[..1]), 1);
+        assert_eq!(upstream.queries().len(), 1);
+        assert!(resolver.cached(&questions[0]).is_some());
+        // Warming again asks again, even though the answer is cached
+        warm(&resolver, &questions[..1]);
+        assert_eq!(upstream.queries().len(), 2);
+    }
+}
@@ -0,0 +1,1237 @@
+// Zones we're authoritative for, loaded from master files (RFC 1035 section 5). Questions for
+// names in one of these are answered from it directly, with the AA bit set, instead of being
+// resolved.
+//
+// The master file parser handles the common subset: $ORIGIN and $TTL, omitted owners, TTLs, and
+// classes, parentheses, comments, and quoted strings, for the record types we can represent.
+// $INCLUDE and $GENERATE aren't supported.
+//
+// Zones can also have ALIAS records ("@ 300 ALIAS lb.cdn.example."), which aren't real records:
+// A and AAAA questions for their name are answered with the target's addresses, looked up when
+// asked. That's the one way to point a zone's apex at a name hosted elsewhere, since the apex
+// can't have a CNAME.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::protocol::{
+    is_subdomain, name_from_string, name_to_string, names_equal, DnsClass, DnsFlags, DnsOpcode,
+    DnsPacket, DnsQuestion, DnsRCode, DnsRRType, DnsRecordData, DnsResourceRecord, NameKey, RRset,
+    SoaData, SrvData,
+};
+use super::ttl::TtlOverrides;
+
+// CNAME chains within a zone are followed at most this far
+const MAX_CNAME_CHAIN: usize = 8;
+
+#[derive(Clone, Debug)]
+pub struct Zone {
+    pub origin: Vec<String>,
+    soa: DnsResourceRecord,
+    records: HashMap<NameKey, Vec<RRset>>,
+    aliases: HashMap<NameKey, Alias>,
+    // Where dynamic updates to the zone are kept, for zones loaded from files
+    pub journal: Option<PathBuf>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Alias {
+    pub name: Vec<String>,
+    // Caps the TTL of the addresses it's answered with
+    pub ttl: u32,
+    pub target: Vec<String>,
+}
+
+// A change to a zone, the way IXFR carries one (RFC 1995 section 4): the old SOA and the records
+// taken out, then the new SOA and the records put in
+#[derive(Clone, PartialEq, Debug)]
+pub struct ZoneDiff {
+    pub removed: Vec<DnsResourceRecord>,
+    pub added: Vec<DnsResourceRecord>,
+}
+
+impl ZoneDiff {
+    pub fn old_serial(&self) -> Option<u32> {
+        soa_serial(self.removed.first()?)
+    }
+
+    pub fn new_serial(&self) -> Option<u32> {
+        soa_serial(self.added.first()?)
+    }
+}
+
+fn soa_serial(rr: &DnsResourceRecord) -> Option<u32> {
+    match &rr.record {
+        DnsRecordData::SOA(soa) => Some(soa.serial),
+        _ => None,
+    }
+}
+
+// What a line of a master file can hold
+enum Entry {
+    Record(DnsResourceRecord),
+    Alias(Alias),
+}
+
+impl Zone {
+    // `default_ttl` is for records without a TTL when the file has no $TTL
+    pub fn load(
+        origin: &[String],
+        path: &Path,
+        default_ttl: Option<u32>,
+    ) -> Result<Zone, Box<dyn Error>> {
+        let text = fs::read_to_string(path)?;
+        Zone::parse_with_default_ttl(origin, &text, default_ttl)
+            .map_err(|e| format!("{}: {}", path.display(), e).into())
+    }
+
+    pub fn parse(origin: &[String], text: &str) -> Result<Zone, String> {
+        Zone::parse_with_default_ttl(origin, text, None)
+    }
+
+    pub fn parse_with_default_ttl(
+        origin: &[String],
+        text: &str,
+        default_ttl: Option<u32>,
+    ) -> Result<Zone, String> {
+        let mut records = Vec::new();
+        let mut aliases = Vec::new();
+        for entry in parse_entries(origin, text, default_ttl)? {
+            match entry {
+                Entry::Record(rr) => records.push(rr),
+                Entry::Alias(alias) => aliases.push(alias),
+            }
+        }
+        let mut zone = Zone::from_records(origin, records)?;
+        for alias in aliases {
+            zone.add_alias(alias)?;
+        }
+        Ok(zone)
+    }
+
+    fn add_alias(&mut self, alias: Alias) -> Result<(), String> {
+        let key = NameKey::new(&alias.name);
+        if !is_subdomain(&alias.name, &self.origin) {
+            return Err(format!(
+                "{} is outside the zone {}",
+                name_to_string(&alias.name),
+                name_to_string(&self.origin)
+            ));
+        }
+        // Like a CNAME, an ALIAS says what the name's addresses are; it can't share them
+        let sets = self.records.entry(key.to_owned()).or_default();
+        let conflicts = sets.iter().any(|set| {
+            matches!(
+                set.rr_type,
+                DnsRRType::CNAME | DnsRRType::A | DnsRRType::AAAA
+            )
+        });
+        if conflicts || self.aliases.contains_key(&key) {
+            return Err(format!(
+                "The ALIAS at {} can't share its name with a CNAME, addresses, or another ALIAS",
+                name_to_string(&alias.name)
+            ));
+        }
+        self.aliases.insert(key, alias);
+        Ok(())
+    }
+
+    // Replace the TTLs of records with overrides, including the SOA's (and so negative answers')
+    pub fn override_ttls(&mut self, overrides: &TtlOverrides) {
+        for set in self.records.values_mut().flatten() {
+            if let Some(ttl) = overrides.ttl_for(&set.name) {
+                set.ttl = ttl;
+            }
+        }
+        overrides.apply(std::iter::once(&mut self.soa));
+        for alias in self.aliases.values_mut() {
+            if let Some(ttl) = overrides.ttl_for(&alias.name) {
+                alias.ttl = ttl;
+            }
+        }
+    }
+
+    // The ALIAS that answers `question`, if there is one
+    pub fn alias_for(&self, question: &DnsQuestion) -> Option<&Alias> {
+        if !matches!(question.qtype, DnsRRType::A | DnsRRType::AAAA) {
+            return None;
+        }
+        self.aliases.get(&NameKey::new(&question.qname))
+    }
+
+    // A zone needs exactly one SOA, at its apex, and nothing outside it
+    pub fn from_records(
+        origin: &[String],
+        records: Vec<DnsResourceRecord>,
+    ) -> Result<Zone, String> {
+        let mut soa = None;
+        let mut by_name: HashMap<NameKey, Vec<RRset>> = HashMap::new();
+        for rr in records {
+            if !is_subdomain(&rr.name, origin) {
+                return Err(format!(
+                    "{} is outside the zone {}",
+                    name_to_string(&rr.name),
+                    name_to_string(origin)
+                ));
+            }
+            if rr.rr_type == DnsRRType::SOA {
+                if !names_equal(&rr.name, origin) || soa.is_some() {
+                    return Err(String::from("A zone has exactly one SOA, at its apex"));
+                }
+                soa = Some(rr.to_owned());
+            }
+            RRset::add_to(by_name.entry(NameKey::new(&rr.name)).or_default(), rr);
+        }
+        Ok(Zone {
+            origin: origin.to_vec(),
+            soa: soa.ok_or_else(|| format!("{} has no SOA", name_to_string(origin)))?,
+            records: by_name,
+            aliases: HashMap::new(),
+            journal: None,
+        })
+    }
+
+    pub fn serial(&self) -> u32 {
+        match &self.soa.record {
+            DnsRecordData::SOA(soa) => soa.serial,
+            _ => 0,
+        }
+    }
+
+    pub fn set_serial(&mut self, serial: u32) {
+        let mut soa = self.soa.to_owned();
+        if let DnsRecordData::SOA(data) = &mut soa.record {
+            data.serial = serial;
+        }
+        self.replace_soa(soa);
+    }
+
+    // The SOA is kept twice, once on its own and once with the apex's RRsets
+    fn replace_soa(&mut self, soa: DnsResourceRecord) {
+        let apex = self.records.entry(NameKey::new(&self.origin)).or_default();
+        apex.retain(|set| set.rr_type != DnsRRType::SOA);
+        apex.insert(0, RRset::new(soa.to_owned()));
+        self.soa = soa;
+    }
+
+    pub fn rrsets(&self) -> impl Iterator<Item = &RRset> {
+        self.records.values().flatten()
+    }
+
+    pub fn records(&self) -> impl Iterator<Item = DnsResourceRecord> + '_ {
+        self.rrsets().flat_map(RRset::to_records)
+    }
+
+    pub fn soa(&self) -> &DnsResourceRecord {
+        &self.soa
+    }
+
+    // The records at exactly this name, with no wildcards involved
+    pub fn records_named(&self, name: &[String]) -> Vec<DnsResourceRecord> {
+        self.records
+            .get(&NameKey::new(name))
+            .into_iter()
+            .flatten()
+            .flat_map(RRset::to_records)
+            .collect()
+    }
+
+    pub fn has_alias(&self, name: &[String]) -> bool {
+        self.aliases.contains_key(&NameKey::new(name))
+    }
+
+    // Make a change to the zone, which has to start from the serial it's at
+    pub fn apply(&mut self, diff: &ZoneDiff) -> Result<(), String> {
+        let new_soa = match (diff.old_serial(), diff.added.first()) {
+            (Some(serial), Some(soa)) if serial == self.serial() && soa_serial(soa).is_some() => {
+                soa.to_owned()
+            }
+            _ => {
+                return Err(format!(
+                    "A change to {} has to go from serial {}",
+                    name_to_string(&self.origin),
+                    self.serial()
+                ))
+            }
+        };
+        for rr in diff.removed[1..].iter().chain(&diff.added[1..]) {
+            if !is_subdomain(&rr.name, &self.origin) || rr.rr_type == DnsRRType::SOA {
+                return Err(format!("Can't change {} in this zone", rr));
+            }
+        }
+        for rr in &diff.removed[1..] {
+            let key = NameKey::new(&rr.name);
+            if let Some(sets) = self.records.get_mut(&key) {
+                for set in sets.iter_mut().filter(|set| set.holds(rr)) {
+                    set.remove(&rr.record);
+                }
+                sets.retain(|set| !set.is_empty());
+                if sets.is_empty() && !self.aliases.contains_key(&key) {
+                    self.records.remove(&key);
+                }
+            }
+        }
+        for rr in &diff.added[1..] {
+            let sets = self.records.entry(NameKey::new(&rr.name)).or_default();
+            // The change says what the TTL is now, for the whole RRset
+            RRset::add_to(sets, rr.to_owned()).ttl = rr.ttl;
+        }
+        self.replace_soa(new_soa);
+        Ok(())
+    }
+
+    // The zone as a master file that loads back into the same zone
+    pub fn to_master_file(&self) -> String {
+        let mut records: Vec<DnsResourceRecord> = self
+            .records()
+            .filter(|rr| rr.rr_type != DnsRRType::SOA)
+            .collect();
+        // Parents before children, and the rest in a stable order
+        records.sort_by_cached_key(|rr| {
+            let labels: Vec<String> = rr
+                .name
+                .iter()
+                .rev()
+                .map(|l| l.to_ascii_lowercase())
+                .collect();
+            (labels, rr.rr_type as u16, rr.to_string())
+        });
+        let mut text = format!("{}\n", self.soa);
+        for rr in records {
+            text.push_str(&format!("{}\n", rr));
+        }
+        let mut aliases: Vec<&Alias> = self.aliases.values().collect();
+        aliases.sort_by_key(|alias| name_to_string(&alias.name));
+        for alias in aliases {
+            text.push_str(&format!(
+                "{}\t{}\tALIAS\t{}\n",
+                name_to_string(&alias.name),
+                alias.ttl,
+                name_to_string(&alias.target)
+            ));
+        }
+        text
+    }
+
+    // The whole zone as a transfer sends it: the SOA, everything else, and the SOA again to mark
+    // the end (RFC 5936 section 2.2). ALIASes aren't records anyone else would understand, so
+    // they're left out.
+    pub fn transfer_records(&self) -> Vec<DnsResourceRecord> {
+        let mut records = vec![self.soa.to_owned()];
+        records.extend(self.records().filter(|rr| rr.rr_type != DnsRRType::SOA));
+        records.push(self.soa.to_owned());
+        records
+    }
+
+    // Answer a question for a name in this zone (RFC 1034 section 4.3.2)
+    pub fn answer(&self, question: &DnsQuestion) -> DnsPacket {
+        let mut response = authoritative_response(question);
+        let mut qname = question.qname.to_owned();
+        for _ in 0..MAX_CNAME_CHAIN {
+            if !is_subdomain(&qname, &self.origin) {
+                // A CNAME out of the zone; the client can chase it from here
+                return response;
+            }
+            if let Some(cut) = self.delegation_for(&qname) {
+                // Below a zone cut, the data isn't ours to give; refer to the child zone's servers
+                response.flags.aa_bit = !response.answers.is_empty();
+                response.addl_recs = self.glue_for(&cut);
+                response.nameservers = cut;
+                return response;
+            }
+            let sets = match self.rrsets_at(&qname) {
+                Some(sets) => sets,
+                None => {
+                    if !self.is_empty_non_terminal(&qname) {
+                        response.flags.rcode = DnsRCode::NXDomain;
+                    }
+                    response.nameservers.push(self.negative_soa());
+                    return response;
+                }
+            };
+            let matching: Vec<DnsResourceRecord> = sets
+                .iter()
+                .filter(|set| set.rr_type == question.qtype || question.qtype == DnsRRType::ANY)
+                .flat_map(RRset::to_records)
+                .collect();
+            if !matching.is_empty() {
+                response.answers.extend(matching);
+                return response;
+            }
+            let cname = sets
+                .iter()
+                .find(|set| set.rr_type == DnsRRType::CNAME)
+                .and_then(|set| set.to_records().into_iter().next());
+            match cname {
+                Some(cname) => {
+                    response.answers.push(cname.to_owned());
+                    if let DnsRecordData::CNAME(target) = &cname.record {
+                        qname = target.to_owned();
+                    }
+                }
+                None => {
+                    // NODATA: the name exists, but has nothing of this type
+                    response.nameservers.push(self.negative_soa());
+                    return response;
+                }
+            }
+        }
+        response
+    }
+
+    // The RRsets at `name`, including ones synthesized from a wildcard (RFC 4592)
+    fn rrsets_at(&self, name: &[String]) -> Option<Vec<RRset>> {
+        if let Some(sets) = self.records.get(&NameKey::new(name)) {
+            return Some(sets.to_owned());
+        }
+        // A wildcard only applies if the name doesn't exist at all, and is only checked at the
+        // closest encloser (the deepest ancestor that does exist)
+        if self.is_empty_non_terminal(name) {
+            return None;
+        }
+        let encloser = (1..name.len()).map(|skip| &name[skip..]).find(|ancestor| {
+            self.records.contains_key(&NameKey::new(ancestor))
+                || self.is_empty_non_terminal(ancestor)
+        })?;
+        let mut wildcard = vec![String::from("*")];
+        wildcard.extend_from_slice(encloser);
+        let sets = self.records.get(&NameKey::new(&wildcard))?;
+        Some(
+            sets.iter()
+                .map(|set| {
+                    let mut set = set.to_owned();
+                    set.name = name.to_vec();
+                    set
+                })
+                .collect(),
+        )
+    }
+
+    // A name with no records of its own but some below it, like b.example in a zone that only
+    // has a.b.example. It exists, so questions for it get NODATA rather than NXDOMAIN.
+    fn is_empty_non_terminal(&self, name: &[String]) -> bool {
+        self.rrsets()
+            .any(|set| set.name.len() > name.len() && is_subdomain(&set.name, name))
+    }
+
+    // The NS records of the highest zone cut between the apex and `name`, if there is one
+    fn delegation_for(&self, name: &[String]) -> Option<Vec<DnsResourceRecord>> {
+        (self.origin.len() + 1..=name.len())
+            .map(|depth| &name[name.len() - depth..])
+            .find_map(|ancestor| {
+                self.records
+                    .get(&NameKey::new(ancestor))?
+                    .iter()
+                    .find(|set| set.rr_type == DnsRRType::NS)
+                    .map(RRset::to_records)
+            })
+    }
+
+    fn glue_for(&self, nameservers: &[DnsResourceRecord]) -> Vec<DnsResourceRecord> {
+        let mut glue = Vec::new();
+        for ns in nameservers {
+            if let DnsRecordData::NS(ns_name) = &ns.record {
+                if let Some(sets) = self.records.get(&NameKey::new(ns_name)) {
+                    glue.extend(
+                        sets.iter()
+                            .filter(|set| matches!(set.rr_type, DnsRRType::A | DnsRRType::AAAA))
+                            .flat_map(RRset::to_records),
+                    );
+                }
+            }
+        }
+        glue
+    }
+
+    // The SOA for the authority section of a negative answer, whose TTL is how long the answer
+    // can be cached (RFC 2308 section 3)
+    fn negative_soa(&self) -> DnsResourceRecord {
+        let mut soa = self.soa.to_owned();
+        if let DnsRecordData::SOA(data) = &soa.record {
+            soa.ttl = soa.ttl.min(data.minimum);
+        }
+        soa
+    }
+}
+
+// Answer a question from a loose collection of records (local data) if any of them are for its
+// name: with the ones of the right type, or a CNAME, or NODATA
+pub fn answer_from_records(
+    records: &[DnsResourceRecord],
+    question: &DnsQuestion,
+) -> Option<DnsPacket> {
+    let at_name: Vec<&DnsResourceRecord> = records
+        .iter()
+        .filter(|rr| names_equal(&rr.name, &question.qname))
+        .collect();
+    if at_name.is_empty() {
+        return None;
+    }
+    let mut response = authoritative_response(question);
+    let matching = at_name
+        .iter()
+        .filter(|rr| rr.rr_type == question.qtype || question.qtype == DnsRRType::ANY);
+    response.answers.extend(matching.map(|rr| (*rr).to_owned()));
+    if response.answers.is_empty() {
+        let cname = at_name.iter().find(|rr| rr.rr_type == DnsRRType::CNAME);
+        response.answers.extend(cname.map(|rr| (*rr).to_owned()));
+    }
+    Some(response)
+}
+
+// An empty authoritative answer to `question`, to fill in
+fn authoritative_response(question: &DnsQuestion) -> DnsPacket {
+    DnsPacket {
+        id: 0,
+        flags: DnsFlags {
+            qr_bit: true,
+            opcode: DnsOpcode::Query,
+            aa_bit: true,
+            tc_bit: false,
+            rd_bit: false,
+            ra_bit: false,
+            ad_bit: false,
+            cd_bit: false,
+            rcode: DnsRCode::NoError,
+        },
+        questions: vec![question.to_owned()],
+        answers: Vec::new(),
+        nameservers: Vec::new(),
+        addl_recs: Vec::new(),
+    }
+}
+
+// The zone among `zones` closest to `name`, i.e. the one with the longest origin containing it
+pub fn zone_for<'a>(zones: &'a [Zone], name: &[String]) -> Option<&'a Zone> {
+    zones
+        .iter()
+        .filter(|zone| is_subdomain(name, &zone.origin))
+        .max_by_key(|zone| zone.origin.len())
+}
+
+// Where we are in a master file: the things later lines inherit from earlier ones
+struct ParseState {
+    origin: Vec<String>,
+    default_ttl: Option<u32>,
+    last_owner: Option<Vec<String>>,
+    last_ttl: Option<u32>,
+}
+
+pub fn parse_master_file(origin: &[String], text: &str) -> Result<Vec<DnsResourceRecord>, String> {
+    parse_entries(origin, text, None)?
+        .into_iter()
+        .map(|entry| match entry {
+            Entry::Record(rr) => Ok(rr),
+            Entry::Alias(_) => Err(String::from("ALIAS records can only be served from zones")),
+        })
+        .collect()
+}
+
+fn parse_entries(
+    origin: &[String],
+    text: &str,
+    default_ttl: Option<u32>,
+) -> Result<Vec<Entry>, String> {
+    let mut state = ParseState {
+        origin: origin.to_vec(),
+        default_ttl,
+        last_owner: None,
+        last_ttl: None,
+    };
+    let mut entries = Vec::new();
+    for LogicalLine {
+        number,
+        owner_omitted,
+        tokens,
+    } in logical_lines(text)?
+    {
+        let result = match tokens[0].as_str() {
+            "$ORIGIN" => tokens
+                .get(1)
+                .ok_or_else(|| String::from("$ORIGIN needs a name"))
+                .and_then(|name| parse_name(name, &state.origin))
+                .map(|name| state.origin = name),
+            "$TTL" => tokens
+                .get(1)
+                .ok_or_else(|| String::from("$TTL needs a value"))
+                .and_then(|ttl| parse_ttl(ttl))
+                .map(|ttl| state.default_ttl = Some(ttl)),
+            directive if directive.starts_with('$') => {
+                Err(format!("{} isn't supported", directive))
+            }
+            _ => parse_record_tokens(&mut state, owner_omitted, &tokens)
+                .map(|entry| entries.push(entry)),
+        };
+        result.map_err(|e| format!("line {}: {}", number, e))?;
+    }
+    Ok(entries)
+}
+
+// Parse a single record in master file format, e.g. "host.lan. 300 IN A 192.0.2.1". Relative
+// names are relative to `origin`.
+pub fn parse_record(origin: &[String], line: &str) -> Result<DnsResourceRecord, String> {
+    let mut records = parse_master_file(origin, line)?;
+    match records.len() {
+        1 => Ok(records.remove(0)),
+        _ => Err(format!("Expected one record, got {:?}", line)),
+    }
+}
+
+fn parse_record_tokens(
+    state: &mut ParseState,
+    owner_omitted: bool,
+    tokens: &[String],
+) -> Result<Entry, String> {
+    let mut tokens = tokens.iter();
+    let owner = if owner_omitted {
+        state
+            .last_owner
+            .to_owned()
+            .ok_or_else(|| String::from("First record has no owner name"))?
+    } else {
+        parse_name(tokens.next().unwrap(), &state.origin)?
+    };
+    // TTL and class can come in either order, and either can be left out
+    let mut ttl = None;
+    let rr_type = loop {
+        let token = tokens
+            .next()
+            .ok_or_else(|| String::from("Record has no type"))?;
+        if token.starts_with(|c: char| c.is_ascii_digit()) {
+            ttl = Some(parse_ttl(token)?);
+        } else if token.eq_ignore_ascii_case("IN") {
+            continue;
+        } else if matches!(token.to_ascii_uppercase().as_str(), "CH" | "HS" | "CS") {
+            return Err(format!("Class {} isn't supported", token));
+        } else if token.eq_ignore_ascii_case("ALIAS") {
+            break None;
+        } else {
+            break Some(parse_type(token)?);
+        }
+    };
+    let ttl = ttl
+        .or(state.default_ttl)
+        .or(state.last_ttl)
+        .ok_or_else(|| String::from("Record has no TTL and there's no $TTL"))?;
+    let rdata: Vec<&String> = tokens.collect();
+    let entry = match rr_type {
+        Some(rr_type) => Entry::Record(DnsResourceRecord {
+            name: owner.to_owned(),
+            rr_type,
+            class: DnsClass::IN,
+            ttl,
+            record: parse_rdata(rr_type, &rdata, &state.origin)?,
+        }),
+        None => match rdata.as_slice() {
+            [target] => Entry::Alias(Alias {
+                name: owner.to_owned(),
+                ttl,
+                target: parse_name(target, &state.origin)?,
+            }),
+            _ => return Err(String::from("ALIAS record needs a target")),
+        },
+    };
+    state.last_owner = Some(owner);
+    state.last_ttl = Some(ttl);
+    Ok(entry)
+}
+
+// Whether records of this type can be in a zone, which is whether the master file parser can read
+// them back
+pub fn is_supported_type(rr_type: DnsRRType) -> bool {
+    parse_type(&rr_type.to_string()).is_ok()
+}
+
+fn parse_type(token: &str) -> Result<DnsRRType, String> {
+    match token.to_ascii_uppercase().as_str() {
+        "A" => Ok(DnsRRType::A),
+        "AAAA" => Ok(DnsRRType::AAAA),
+        "NS" => Ok(DnsRRType::NS),
+        "CNAME" => Ok(DnsRRType::CNAME),
+        "PTR" => Ok(DnsRRType::PTR),
+        "MX" => Ok(DnsRRType::MX),
+        "TXT" => Ok(DnsRRType::TXT),
+        "SOA" => Ok(DnsRRType::SOA),
+        "HINFO" => Ok(DnsRRType::HINFO),
+        "RP" => Ok(DnsRRType::RP),
+        "SRV" => Ok(DnsRRType::SRV),
+        "URI" => Ok(DnsRRType::URI),
+        _ => Err(format!("Record type {} isn't supported", token)),
+    }
+}
+
+fn parse_rdata(
+    rr_type: DnsRRType,
+    rdata: &[&String],
+    origin: &[String],
+) -> Result<DnsRecordData, String> {
+    let expect = |count: usize| {
+        if rdata.len() == count {
+            Ok(())
+        } else {
+            Err(format!(
+                "{} record needs {} fields, got {}",
+                rr_type,
+                count,
+                rdata.len()
+            ))
+        }
+    };
+    let number = |token: &str| -> Result<u32, String> {
+        token
+            .parse()
+            .map_err(|_| format!("Invalid number {:?}", token))
+    };
+    match rr_type {
+        DnsRRType::A => {
+            expect(1)?;
+            Ok(DnsRecordData::A(rdata[0].parse().map_err(|_| {
+                format!("Invalid IPv4 address {:?}", rdata[0])
+            })?))
+        }
+        DnsRRType::AAAA => {
+            expect(1)?;
+            Ok(DnsRecordData::AAAA(rdata[0].parse().map_err(|_| {
+                format!("Invalid IPv6 address {:?}", rdata[0])
+            })?))
+        }
+        DnsRRType::NS => {
+            expect(1)?;
+            Ok(DnsRecordData::NS(parse_name(rdata[0], origin)?))
+        }
+        DnsRRType::CNAME => {
+            expect(1)?;
+            Ok(DnsRecordData::CNAME(parse_name(rdata[0], origin)?))
+        }
+        DnsRRType::PTR => {
+            expect(1)?;
+            Ok(DnsRecordData::PTR(parse_name(rdata[0], origin)?))
+        }
+        DnsRRType::MX => {
+            expect(2)?;
+            let preference = rdata[0]
+                .parse()
+                .map_err(|_| format!("Invalid MX preference {:?}", rdata[0]))?;
+            Ok(DnsRecordData::MX(preference, parse_name(rdata[1], origin)?))
+        }
+        DnsRRType::TXT => {
+            if rdata.is_empty() {
+                return Err(String::from("TXT record needs at least one string"));
+            }
+            let strings = rdata
+                .iter()
+                .map(|s| unescape(s))
+                .collect::<Result<Vec<Vec<u8>>, String>>()?;
+            if strings.iter().any(|s| s.len() > 255) {
+                return Err(String::from("TXT strings are limited to 255 bytes"));
+            }
+            Ok(DnsRecordData::TXT(strings))
+        }
+        DnsRRType::SOA => {
+            expect(7)?;
+            Ok(DnsRecordData::SOA(SoaData {
+                mname: parse_name(rdata[0], origin)?,
+                rname: parse_name(rdata[1], origin)?,
+                serial: number(rdata[2])?,
+                refresh: parse_ttl(rdata[3])?,
+                retry: parse_ttl(rdata[4])?,
+                expire: parse_ttl(rdata[5])?,
+                minimum: parse_ttl(rdata[6])?,
+            }))
+        }
+        DnsRRType::HINFO => {
+            expect(2)?;
+            let cpu = unescape(rdata[0])?;
+            let os = unescape(rdata[1])?;
+            if cpu.len() > 255 || os.len() > 255 {
+                return Err(String::from("HINFO strings are limited to 255 bytes"));
+            }
+            Ok(DnsRecordData::HINFO(cpu, os))
+        }
+        DnsRRType::RP => {
+            expect(2)?;
+            Ok(DnsRecordData::RP(
+                parse_name(rdata[0], origin)?,
+                parse_name(rdata[1], origin)?,
+            ))
+        }
+        DnsRRType::SRV => {
+            expect(4)?;
+            let field = |token: &str| {
+                token
+                    .parse()
+                    .map_err(|_| format!("Invalid SRV priority, weight, or port {:?}", token))
+            };
+            Ok(DnsRecordData::SRV(SrvData {
+                priority: field(rdata[0])?,
+                weight: field(rdata[1])?,
+                port: field(rdata[2])?,
+                target: parse_name(rdata[3], origin)?,
+            }))
+        }
+        DnsRRType::URI => {
+            expect(3)?;
+            let field = |token: &str| {
+                token
+                    .parse()
+                    .map_err(|_| format!("Invalid URI priority or weight {:?}", token))
+            };
+            let target = unescape(rdata[2])?;
+            if target.is_empty() {
+                return Err(String::from("URI record needs a target"));
+            }
+            Ok(DnsRecordData::URI(
+                field(rdata[0])?,
+                field(rdata[1])?,
+                target,
+            ))
+        }
+        _ => Err(format!("Record type {} isn't supported", rr_type)),
+    }
+}
+
+// "@" is the origin, names ending in an unescaped dot are absolute, and anything else is
+// relative to the origin
+fn parse_name(token: &str, origin: &[String]) -> Result<Vec<String>, String> {
+    if token == "@" {
+        return Ok(origin.to_vec());
+    }
+    let mut name = name_from_string(token)?;
+    let absolute = token.ends_with('.') && !token.ends_with("\\.");
+    if !absolute {
+        name.extend_from_slice(origin);
+    }
+    Ok(name)
+}
+
+// A TTL in seconds, or BIND style with units: 1h30m, 2d, 1w
+pub fn parse_ttl(token: &str) -> Result<u32, String> {
+    let invalid = || format!("Invalid TTL {:?}", token);
+    if let Ok(seconds) = token.parse() {
+        return Ok(seconds);
+    }
+    let mut total: u32 = 0;
+    let mut value: u32 = 0;
+    let mut digits = false;
+    for c in token.chars() {
+        if let Some(digit) = c.to_digit(10) {
+            value = value
+                .checked_mul(10)
+                .and_then(|v| v.checked_add(digit))
+                .ok_or_else(invalid)?;
+            digits = true;
+            continue;
+        }
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        if !digits {
+            return Err(invalid());
+        }
+        total = value
+            .checked_mul(unit)
+            .and_then(|v| total.checked_add(v))
+            .ok_or_else(invalid)?;
+        value = 0;
+        digits = false;
+    }
+    if digits {
+        return Err(invalid());
+    }
+    Ok(total)
+}
+
+// The contents of a quoted string (or a bare word) as bytes, with \X and \DDD escapes resolved
+fn unescape(token: &str) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    let mut bytes = token.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte != b'\\' {
+            out.push(byte);
+            continue;
+        }
+        match bytes.next() {
+            Some(digit) if digit.is_ascii_digit() => {
+                let digits = [digit, bytes.next().unwrap_or(0), bytes.next().unwrap_or(0)];
+                if !digits.iter().all(|d| d.is_ascii_digit()) {
+                    return Err(format!("Bad \\DDD escape in {:?}", token));
+                }
+                let value = digits
+                    .iter()
+                    .fold(0u32, |acc, d| acc * 10 + (d - b'0') as u32);
+                if value > 255 {
+                    return Err(format!("Escape \\{} out of range in {:?}", value, token));
+                }
+                out.push(value as u8);
+            }
+            Some(escaped) => out.push(escaped),
+            None => return Err(format!("Dangling backslash in {:?}", token)),
+        }
+    }
+    Ok(out)
+}
+
+// A record or directive, which can span several lines of the file inside parentheses
+struct LogicalLine {
+    // Where it starts, for error messages
+    number: usize,
+    // Whether it started with whitespace, which means the owner name was left out
+    owner_omitted: bool,
+    tokens: Vec<String>,
+}
+
+// Split a master file into logical lines of tokens, joining lines inside parentheses and dropping
+// comments. Quotes are removed from quoted strings; escapes are left for whoever interprets the
+// token.
+fn logical_lines(text: &str) -> Result<Vec<LogicalLine>, String> {
+    let mut lines = Vec::new();
+    let mut tokens: Vec<String> = Vec::new();
+    let mut start = (0, false);
+    let mut depth = 0;
+    for (index, line) in text.lines().enumerate() {
+        if depth == 0 {
+            start = (index + 1, line.starts_with([' ', '\t']));
+        }
+        let mut chars = line.chars().peekable();
+        let mut token: Option<String> = None;
+        let mut quoted = false;
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => {
+                    let t = token.get_or_insert_with(String::new);
+                    t.push(c);
+                    if let Some(escaped) = chars.next() {
+                        t.push(escaped);
+                    }
+                }
+                '"' if quoted => {
+                    quoted = false;
+                    tokens.push(token.take().unwrap_or_default());
+                }
+                '"' => {
+                    if let Some(t) = token.take() {
+                        tokens.push(t);
+                    }
+                    quoted = true;
+                    token = Some(String::new());
+                }
+                _ if quoted => token.get_or_insert_with(String::new).push(c),
+                ';' => break,
+                '(' | ')' | ' ' | '\t' => {
+                    if let Some(t) = token.take() {
+                        tokens.push(t);
+                    }
+                    match c {
+                        '(' => depth += 1,
+                        ')' if depth == 0 => {
+                            return Err(format!("line {}: unbalanced ')'", index + 1))
+                        }
+                        ')' => depth -= 1,
+                        _ => (),
+                    }
+                }
+                _ => token.get_or_insert_with(String::new).push(c),
+            }
+        }
+        if quoted {
+            return Err(format!("line {}: unterminated string", index + 1));
+        }
+        if let Some(t) = token {
+            tokens.push(t);
+        }
+        if depth == 0 && !tokens.is_empty() {
+            lines.push(LogicalLine {
+                number: start.0,
+                owner_omitted: start.1,
+                tokens: std::mem::take(&mut tokens),
+            });
+        }
+    }
+    if depth != 0 {
+        return Err(String::from("unbalanced '(' at end of file"));
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    use crate::dns::mock;
+
+    const EXAMPLE: &str = r#"
+$ORIGIN example.com.
+$TTL 1h
+@       IN  SOA ns1 hostmaster (
+                2021010101 ; serial
+                2h 1h 2w 300 )
+        IN  NS  ns1
+        IN  MX  10 mail
+ns1         A   192.0.2.1
+mail    60  A   192.0.2.2
+www         CNAME   web.internal
+web.internal    A   192.0.2.3
+*.wild      TXT "hello \"world\"" two
+sub         NS  ns.sub
+ns.sub      A   192.0.2.4
+"#;
+
+    fn zone() -> Zone {
+        Zone::parse(&mock::labels("example.com"), EXAMPLE).expect("should parse")
+    }
+
+    #[test]
+    fn parses_master_files() {
+        let zone = zone();
+        assert_eq!(zone.serial(), 2021010101);
+        assert_eq!(zone.records().count(), 10);
+        assert_eq!(
+            zone.records_named(&mock::labels("mail.example.com"))[0],
+            DnsResourceRecord {
+                ttl: 60,
+                ..mock::a("mail.example.com", Ipv4Addr::new(192, 0, 2, 2))
+            }
+        );
+
+        let origin = mock::labels("example.com");
+        assert!(Zone::parse(&origin, "@ 300 A 192.0.2.1").is_err());
+        assert!(Zone::parse(&origin, "@ 300 SOA ns1 hm 1 2 3 4\n").is_err());
+        assert!(Zone::parse(&origin, "other.net. 300 A 192.0.2.1").is_err());
+        assert!(parse_master_file(&origin, "@ 300 WKS 192.0.2.1 6 25").is_err());
+        assert!(parse_master_file(&origin, "@ 300 HINFO x86_64").is_err());
+        assert!(parse_master_file(&origin, "@ 300 A (192.0.2.1").is_err());
+        assert!(parse_master_file(&origin, "@ A 192.0.2.1").is_err());
+        let mut defaulted = Zone::parse_with_default_ttl(
+            &origin,
+            "@ SOA ns hm 1 2 3 4 5\nwww A 192.0.2.1",
+            Some(90),
+        )
+        .unwrap();
+        assert!(defaulted.records().all(|rr| rr.ttl == 90));
+        defaulted.override_ttls(&TtlOverrides::new(vec!["www.example.com=5"
+            .parse()
+            .unwrap()]));
+        let ttls: Vec<u32> = defaulted.records().map(|rr| rr.ttl).collect();
+        assert!(ttls.contains(&5) && ttls.contains(&90));
+        assert_eq!(parse_ttl("1h30m"), Ok(5400));
+        assert!(parse_ttl("h").is_err());
+        assert!(parse_ttl("10x").is_err());
+        assert_eq!(
+            parse_record(&[], "host.lan 300 IN A 192.0.2.9"),
+            Ok(DnsResourceRecord {
+                ttl: 300,
+                ..mock::a("host.lan", Ipv4Addr::new(192, 0, 2, 9))
+            })
+        );
+    }
+
+    #[test]
+    fn answers_authoritatively() {
+        let zone = zone();
+        let answer = |name: &str, qtype| zone.answer(&mock::question(name, qtype));
+
+        let mail = answer("MAIL.example.com", DnsRRType::A);
+        assert!(mail.flags.aa_bit);
+        assert_eq!(mail.answers.len(), 1);
+
+        // CNAMEs are followed within the zone
+        let www = answer("www.example.com", DnsRRType::A);
+        assert_eq!(www.answers.len(), 2);
+        assert_eq!(
+            www.answers[1].record,
+            DnsRecordData::A(Ipv4Addr::new(192, 0, 2, 3))
+        );
+
+        // NODATA, including for empty non-terminals, and NXDOMAIN carry the SOA with the
+        // negative TTL
+        for (name, rcode) in &[
+            ("mail.example.com", DnsRCode::NoError),
+            ("internal.example.com", DnsRCode::NoError),
+            ("nope.example.com", DnsRCode::NXDomain),
+        ] {
+            let response = answer(name, DnsRRType::TXT);
+            assert_eq!(&response.flags.rcode, rcode);
+            assert!(response.answers.is_empty());
+            assert_eq!(response.nameservers[0].rr_type, DnsRRType::SOA);
+            assert_eq!(response.nameservers[0].ttl, 300);
+        }
+
+        let wild = answer("a.wild.example.com", DnsRRType::TXT);
+        assert_eq!(wild.answers[0].name, mock::labels("a.wild.example.com"));
+        assert_eq!(
+            wild.answers[0].record,
+            DnsRecordData::TXT(vec![b"hello \"world\"".to_vec(), b"two".to_vec()])
+        );
+
+        // Below a zone cut, we refer rather than answer
+        let referral = answer("www.sub.example.com", DnsRRType::A);
+        assert!(!referral.flags.aa_bit);
+        assert_eq!(referral.nameservers.len(), 1);
+        assert_eq!(referral.addl_recs.len(), 1);
+
+        let zones = vec![zone];
+        assert!(zone_for(&zones, &mock::labels("x.example.com")).is_some());
+        assert!(zone_for(&zones, &mock::labels("example.net")).is_none());
+    }
+
+    #[test]
+    fn serves_service_names() {
+        let zone = Zone::parse(
+            &mock::labels("example.com"),
+            r#"
+$TTL 300
+@                   SOA     ns1 hostmaster 1 2 3 4 5
+_dmarc              TXT     "v=DMARC1; p=reject"
+@                   RP      hostmaster.example.com. _contact
+_443._tcp.www       CNAME   _tlsa.example.net.
+*._domainkey        TXT     "v=DKIM1; p="
+_sip._udp           MX      10 _mail
+_ftp._tcp           URI     10 1 "ftp://ftp1.example.com/public"
+_ftp._tcp           HINFO   "x86_64" "Linux"
+_xmpp-server._tcp   SRV     5 0 5269 xmpp
+"#,
+        )
+        .expect("should parse");
+        let answer = |name: &str, qtype| zone.answer(&mock::question(name, qtype));
+
+        let dmarc = answer("_DMARC.example.com", DnsRRType::TXT);
+        assert_eq!(dmarc.flags.rcode, DnsRCode::NoError);
+        assert_eq!(
+            dmarc.answers[0].record,
+            DnsRecordData::TXT(vec![b"v=DMARC1; p=reject".to_vec()])
+        );
+        assert_eq!(
+            answer("example.com", DnsRRType::RP).answers[0].record,
+            DnsRecordData::RP(
+                mock::labels("hostmaster.example.com"),
+                mock::labels("_contact.example.com")
+            )
+        );
+        let tlsa = answer("_443._tcp.www.example.com", DnsRRType::TLSA);
+        assert_eq!(
+            tlsa.answers[0].record,
+            DnsRecordData::CNAME(mock::labels("_tlsa.example.net"))
+        );
+        let dkim = answer("selector1._domainkey.example.com", DnsRRType::TXT);
+        assert_eq!(dkim.answers.len(), 1);
+        assert_eq!(
+            answer("_sip._udp.example.com", DnsRRType::MX).answers[0].record,
+            DnsRecordData::MX(10, mock::labels("_mail.example.com"))
+        );
+        assert_eq!(
+            answer("_ftp._tcp.example.com", DnsRRType::URI).answers[0].record,
+            DnsRecordData::URI(10, 1, b"ftp://ftp1.example.com/public".to_vec())
+        );
+        assert_eq!(
+            answer("_xmpp-server._tcp.example.com", DnsRRType::SRV).answers[0].record,
+            DnsRecordData::SRV(SrvData {
+                priority: 5,
+                weight: 0,
+                port: 5269,
+                target: mock::labels("xmpp.example.com"),
+            })
+        );
+        assert_eq!(
+            answer("_ftp._tcp.example.com", DnsRRType::HINFO).answers[0].record,
+            DnsRecordData::HINFO(b"x86_64".to_vec(), b"Linux".to_vec())
+        );
+        // The labels above them are empty non-terminals, not missing
+        assert_eq!(
+            answer("_tcp.www.example.com", DnsRRType::A).flags.rcode,
+            DnsRCode::NoError
+        );
+        assert_eq!(
+            answer("_25._tcp.www.example.com", DnsRRType::TLSA)
+                .flags
+                .rcode,
+            DnsRCode::NXDomain
+        );
+    }
+
+    // Whether a name exists decides NXDOMAIN against NODATA (RFC 2308), whatever types it has
+    // (RFC 4074), whether it exists only because of names below it, and whether a wildcard stands
+    // in for it (RFC 4592)
+    #[test]
+    fn tells_nxdomain_from_nodata() {
+        let zone = Zone::parse(
+            &mock::labels("example"),
+            r#"
+$ORIGIN example.
+$TTL 1h
+@           SOA ns hostmaster 1 2h 1h 2w 300
+@           NS  ns
+ns          A   192.0.2.1
+host        A   192.0.2.2
+a.b.c       A   192.0.2.3
+*.wild      TXT "wild"
+x.ent.wild  A   192.0.2.4
+alias       CNAME host
+dangling    CNAME nothing
+cut         NS  ns.elsewhere.test.
+"#,
+        )
+        .expect("should parse");
+        use DnsRCode::{NXDomain, NoError};
+        use DnsRRType::{A, AAAA, TXT};
+        // Name, type, rcode, and how many answers
+        let cases = [
+            ("host", A, NoError, 1),
+            // An IPv4-only name has no AAAA, but it's still there
+            ("host", AAAA, NoError, 0),
+            ("HOST", AAAA, NoError, 0),
+            ("nothing", A, NXDomain, 0),
+            ("below.host", A, NXDomain, 0),
+            // Empty non-terminals exist, and so do names made empty by a wildcard below them
+            ("c", TXT, NoError, 0),
+            ("b.c", A, NoError, 0),
+            ("x.c", A, NXDomain, 0),
+            ("wild", TXT, NoError, 0),
+            // A wildcard answers for names that don't exist, with whatever types it has
+            ("any.wild", TXT, NoError, 1),
+            ("any.wild", A, NoError, 0),
+            ("deeper.any.wild", TXT, NoError, 1),
+            ("*.wild", TXT, NoError, 1),
+            // ...but not for names that do, or for names below them
+            ("ent.wild", TXT, NoError, 0),
+            ("other.ent.wild", TXT, NXDomain, 0),
+            // At the end of a CNAME chain, with the CNAME in the answer (RFC 6604)
+            ("alias", AAAA, NoError, 1),
+            ("dangling", A, NXDomain, 1),
+        ];
+        for (name, qtype, rcode, answers) in cases.iter() {
+            let qname = format!("{}.example", name);
+            let response = zone.answer(&mock::question(&qname, *qtype));
+            let case = format!("{} {}", qname, qtype);
+            assert_eq!(&response.flags.rcode, rcode, "{}", case);
+            assert_eq!(response.answers.len(), *answers, "{}", case);
+            assert!(response.flags.aa_bit, "{}", case);
+            let negative = *rcode == NXDomain
+                || response
+                    .answers
+                    .last()
+                    .is_none_or(|rr| rr.rr_type != *qtype);
+            if negative {
+                assert_eq!(response.nameservers.len(), 1, "{}", case);
+                assert_eq!(response.nameservers[0].rr_type, DnsRRType::SOA, "{}", case);
+                assert_eq!(response.nameservers[0].ttl, 300, "{}", case);
+            } else {
+                assert!(response.nameservers.is_empty(), "{}", case);
+            }
+        }
+
+        // Names at and below a zone cut aren't ours to say anything about
+        let referral = zone.answer(&mock::question("nothing.cut.example", A));
+        assert_eq!(referral.flags.rcode, NoError);
+        assert!(!referral.flags.aa_bit);
+        assert_eq!(referral.nameservers[0].rr_type, DnsRRType::NS);
+    }
+}
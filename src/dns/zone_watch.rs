@@ -0,0 +1,185 @@
+// Reloading zone files when they change. The directories holding them are watched rather than
+// the files themselves, since most editors save by writing a new file and renaming it over the
+// old one. A zone that no longer loads is logged and the old copy kept serving.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+
+use super::journal;
+use super::protocol::name_to_string;
+use super::resolver::Resolver;
+use super::trace::{error, info, warning};
+use super::zone::Zone;
+
+// Saves tend to come as a burst of events; wait this long for the rest before reloading
+const SETTLE_TIME: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ZoneFile {
+    pub origin: Vec<String>,
+    pub path: PathBuf,
+    pub default_ttl: Option<u32>,
+}
+
+impl ZoneFile {
+    // The zone as of its file plus any updates journaled since
+    pub fn load(&self) -> Result<Zone, String> {
+        let mut zone =
+            Zone::load(&self.origin, &self.path, self.default_ttl).map_err(|e| e.to_string())?;
+        let journal = journal::path_for(&self.path);
+        let replayed = journal::replay(&mut zone, &journal)?;
+        if replayed > 0 {
+            info!(
+                "Replayed {} updates to {} from {}",
+                replayed,
+                name_to_string(&self.origin),
+                journal.display()
+            );
+        }
+        zone.journal = Some(journal);
+        Ok(zone)
+    }
+}
+
+// Reloads zones for as long as it's kept around
+pub struct ZoneWatcher {
+    _watcher: RecommendedWatcher,
+}
+
+// `bump_serial` keeps a reloaded zone's serial increasing even if whoever edited it forgot to,
+// so secondaries notice the change
+pub fn watch(
+    files: Vec<ZoneFile>,
+    resolver: Resolver,
+    bump_serial: bool,
+) -> notify::Result<ZoneWatcher> {
+    let (events, changes) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(events)?;
+    let directories: HashSet<PathBuf> = files.iter().map(|file| directory_of(&file.path)).collect();
+    for directory in &directories {
+        watcher.watch(directory, RecursiveMode::NonRecursive)?;
+    }
+    thread::spawn(move || {
+        // Ends once the watcher, and so the sending end, is dropped
+        while let Ok(event) = changes.recv() {
+            let mut changed: HashSet<PathBuf> = HashSet::new();
+            let mut collect = |event: notify::Result<notify::Event>| match event {
+                Ok(event) if !event.kind.is_access() => changed.extend(event.paths),
+                Ok(_) => (),
+                Err(e) => error!("Error watching zone files: {}", e),
+            };
+            collect(event);
+            thread::sleep(SETTLE_TIME);
+            while let Ok(event) = changes.try_recv() {
+                collect(event);
+            }
+            for file in &files {
+                if changed.iter().any(|path| same_file(path, &file.path)) {
+                    reload(file, &resolver, bump_serial);
+                }
+            }
+        }
+    });
+    Ok(ZoneWatcher { _watcher: watcher })
+}
+
+pub fn reload(file: &ZoneFile, resolver: &Resolver, bump_serial: bool) {
+    let origin = name_to_string(&file.origin);
+    match file.load() {
+        Ok(zone) => {
+            let serial = resolver.replace_zone(zone, bump_serial);
+            info!("Reloaded zone {} at serial {}", origin, serial);
+        }
+        Err(e) => warning!("Keeping the old copy of zone {}: {}", origin, e),
+    }
+}
+
+fn directory_of(path: &Path) -> PathBuf {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+// Events come with absolute paths, which the configured ones may not be
+fn same_file(event_path: &Path, path: &Path) -> bool {
+    match (event_path.canonicalize(), path.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        // A file that's just been renamed away can't be canonicalized; fall back to its name
+        _ => event_path.file_name() == path.file_name() && event_path.ends_with(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::net::Ipv4Addr;
+    use std::time::Instant;
+
+    use crate::dns::config::{ResolverConfig, ResolverOpts};
+    use crate::dns::mock;
+    use crate::dns::protocol::{DnsRRType, DnsRecordData};
+
+    fn zone_text(serial: u32, ip: &str) -> String {
+        format!(
+            "@ 300 SOA ns hostmaster {} 2 3 4 5\nwww 300 A {}\n",
+            serial, ip
+        )
+    }
+
+    #[test]
+    fn reloads_changed_zones() {
+        let dir = std::env::temp_dir().join(format!("montague-zone-watch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = ZoneFile {
+            origin: mock::labels("example.test"),
+            path: dir.join("example.test.zone"),
+            default_ttl: None,
+        };
+        fs::write(&file.path, zone_text(5, "192.0.2.1")).unwrap();
+        let config = ResolverConfig {
+            zones: vec![file.load().unwrap()],
+            ..ResolverConfig::default()
+        };
+        let resolver = Resolver::new(config, ResolverOpts::default());
+        let _watcher = watch(vec![file.to_owned()], resolver.to_owned(), true).unwrap();
+        let www = || {
+            let response = resolver.query("www.example.test", DnsRRType::A).unwrap();
+            response.answers[0].record.to_owned()
+        };
+        let serial = || {
+            let response = resolver.query("example.test", DnsRRType::SOA).unwrap();
+            match &response.answers[0].record {
+                DnsRecordData::SOA(soa) => soa.serial,
+                _ => panic!("Not an SOA"),
+            }
+        };
+
+        // Saved the way editors do, by renaming a new file over the old one, with the serial
+        // left alone
+        let temp = dir.join("example.test.zone.tmp");
+        fs::write(&temp, zone_text(5, "192.0.2.2")).unwrap();
+        fs::rename(&temp, &file.path).unwrap();
+        let expected = DnsRecordData::A(Ipv4Addr::new(192, 0, 2, 2));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while www() != expected && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(www(), expected);
+        assert_eq!(serial(), 6);
+
+        // A broken zone leaves the old one in place
+        fs::write(&file.path, "www 300 A 192.0.2.3\n").unwrap();
+        thread::sleep(SETTLE_TIME * 5);
+        assert_eq!(www(), expected);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,269 @@
+// DNS over HTTPS (RFC 8484), on listeners given as --listen=ADDR:443/https. A query is a request
+// for /dns-query, either a GET with the message base64url encoded in its `dns` parameter, or a
+// POST with the message as its body; the answer comes back as the body of the response.
+//
+// This is HTTP/1.1 over the same TLS as DNS over TLS, which every DoH client can fall back to,
+// though the RFC would rather it were HTTP/2. Connections are kept open between requests, but
+// HTTP/1.1 has to answer them in the order they were asked, so each is answered before the next
+// is read.
+
+use std::io::{self, BufRead, Read, Write};
+
+use crate::dns::protocol::{DnsPacket, DnsRRType};
+
+const PATH: &str = "/dns-query";
+const CONTENT_TYPE: &str = "application/dns-message";
+
+// A request line and headers longer than this aren't read, and the connection's closed
+const MAX_HEADER_SIZE: u64 = 8 * 1024;
+
+// The largest a DNS message can be, and so a POST's body
+const MAX_BODY_SIZE: usize = u16::MAX as usize;
+
+#[derive(Debug, PartialEq)]
+pub struct Request {
+    pub method: String,
+    // The path, with any query string
+    pub target: String,
+    content_type: Option<String>,
+    content_length: Option<usize>,
+    // Whether the client asked for the connection to be closed once this is answered
+    pub close: bool,
+    body: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    Ok,
+    BadRequest,
+    NotFound,
+    MethodNotAllowed,
+    PayloadTooLarge,
+    UnsupportedMediaType,
+    ServiceUnavailable,
+}
+
+impl Status {
+    fn line(self) -> &'static str {
+        match self {
+            Status::Ok => "200 OK",
+            Status::BadRequest => "400 Bad Request",
+            Status::NotFound => "404 Not Found",
+            Status::MethodNotAllowed => "405 Method Not Allowed",
+            Status::PayloadTooLarge => "413 Payload Too Large",
+            Status::UnsupportedMediaType => "415 Unsupported Media Type",
+            Status::ServiceUnavailable => "503 Service Unavailable",
+        }
+    }
+}
+
+// The next request on a connection, or None once the client has closed it
+pub fn read_request(stream: &mut impl BufRead) -> io::Result<Option<Request>> {
+    let mut header = (&mut *stream).take(MAX_HEADER_SIZE);
+    let mut line = String::new();
+    if header.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some("HTTP/1.1" | "HTTP/1.0")) => (method, target),
+        _ => {
+            return Err(invalid(format!(
+                "Not an HTTP request: {:?}",
+                line.trim_end()
+            )))
+        }
+    };
+    let mut request = Request {
+        method: method.to_owned(),
+        target: target.to_owned(),
+        content_type: None,
+        content_length: None,
+        close: line.trim_end().ends_with("HTTP/1.0"),
+        body: Vec::new(),
+    };
+    loop {
+        let mut line = String::new();
+        if header.read_line(&mut line)? == 0 {
+            return Err(invalid(String::from("Headers too long or cut off")));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid(format!("Not a header: {:?}", line)))?;
+        let value = value.trim();
+        match name.to_ascii_lowercase().as_str() {
+            "content-type" => request.content_type = Some(value.to_ascii_lowercase()),
+            "content-length" => {
+                let length = value
+                    .parse()
+                    .map_err(|_| invalid(format!("Bad Content-Length {:?}", value)))?;
+                request.content_length = Some(length);
+            }
+            "connection" => request.close = value.eq_ignore_ascii_case("close"),
+            // There'd be no telling where the body ended
+            "transfer-encoding" => {
+                return Err(invalid(String::from("Chunked bodies aren't taken")))
+            }
+            _ => (),
+        }
+    }
+    match request.content_length {
+        Some(length) if length > MAX_BODY_SIZE => request.close = true,
+        Some(length) => {
+            request.body = vec![0; length];
+            stream.read_exact(&mut request.body)?;
+        }
+        None => (),
+    }
+    Ok(Some(request))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// The DNS message a request asks, or the status to turn it down with
+pub fn dns_message(request: &Request) -> Result<Vec<u8>, Status> {
+    let (path, query) = request
+        .target
+        .split_once('?')
+        .unwrap_or((&request.target, ""));
+    if path != PATH {
+        return Err(Status::NotFound);
+    }
+    match request.method.as_str() {
+        "GET" => {
+            let encoded = query
+                .split('&')
+                .find_map(|param| param.strip_prefix("dns="))
+                .ok_or(Status::BadRequest)?;
+            // Clients are meant to leave the padding off, but not all do
+            base64::decode_config(encoded.trim_end_matches('='), base64::URL_SAFE_NO_PAD)
+                .map_err(|_| Status::BadRequest)
+        }
+        "POST" => {
+            if request
+                .content_length
+                .is_some_and(|length| length > MAX_BODY_SIZE)
+            {
+                return Err(Status::PayloadTooLarge);
+            }
+            let content_type = request.content_type.as_deref().unwrap_or_default();
+            let media_type = content_type.split(';').next().unwrap_or_default().trim();
+            if media_type != CONTENT_TYPE {
+                return Err(Status::UnsupportedMediaType);
+            }
+            Ok(request.body.to_owned())
+        }
+        _ => Err(Status::MethodNotAllowed),
+    }
+}
+
+// Send a response, with `message` as its body if it has one. An answer can be cached by HTTP
+// caches for as long as the shortest TTL in it lasts (RFC 8484 section 5.1).
+pub fn write_response(stream: &mut impl Write, status: Status, message: &[u8]) -> io::Result<()> {
+    let mut response = format!(
+        "HTTP/1.1 {}\r\nContent-Length: {}\r\n",
+        status.line(),
+        message.len()
+    );
+    if status == Status::Ok {
+        response.push_str(&format!("Content-Type: {}\r\n", CONTENT_TYPE));
+        if let Some(max_age) = max_age(message) {
+            response.push_str(&format!("Cache-Control: max-age={}\r\n", max_age));
+        }
+    }
+    if status == Status::MethodNotAllowed {
+        response.push_str("Allow: GET, POST\r\n");
+    }
+    response.push_str("\r\n");
+    let mut bytes = response.into_bytes();
+    bytes.extend_from_slice(message);
+    // Written in one go, so the headers and body don't go out as separate TLS records
+    stream.write_all(&bytes)
+}
+
+fn max_age(message: &[u8]) -> Option<u32> {
+    let packet = DnsPacket::from_bytes(message).ok()?;
+    packet
+        .answers
+        .iter()
+        .chain(&packet.nameservers)
+        .filter(|rr| rr.rr_type != DnsRRType::OPT)
+        .map(|rr| rr.ttl)
+        .min()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+    use std::net::Ipv4Addr;
+
+    use crate::bench;
+    use crate::dns::protocol::{name_from_string, DnsClass, DnsRecordData, DnsResourceRecord};
+
+    #[test]
+    fn queries_come_by_get_or_post() {
+        let name = name_from_string("example.com").unwrap();
+        let query = bench::query_bytes(name.to_owned(), DnsRRType::A);
+        let encoded = base64::encode_config(&query, base64::URL_SAFE_NO_PAD);
+        let wire = format!(
+            "GET /dns-query?dns={} HTTP/1.1\r\nHost: dns.test\r\n\r\n\
+             POST /dns-query HTTP/1.1\r\nContent-Type: application/dns-message\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n",
+            encoded,
+            query.len()
+        );
+        let mut wire = wire.into_bytes();
+        wire.extend_from_slice(&query);
+        let mut stream = Cursor::new(wire);
+
+        let get = read_request(&mut stream).unwrap().unwrap();
+        assert_eq!(get.method, "GET");
+        assert!(!get.close);
+        assert_eq!(dns_message(&get).unwrap(), query);
+        let post = read_request(&mut stream).unwrap().unwrap();
+        assert!(post.close);
+        assert_eq!(dns_message(&post).unwrap(), query);
+        assert_eq!(read_request(&mut stream).unwrap(), None);
+
+        let request = |wire: &str| read_request(&mut Cursor::new(wire.to_owned()));
+        let status = |wire: &str| dns_message(&request(wire).unwrap().unwrap()).unwrap_err();
+        assert_eq!(status("GET /other HTTP/1.1\r\n\r\n"), Status::NotFound);
+        assert_eq!(
+            status("GET /dns-query HTTP/1.1\r\n\r\n"),
+            Status::BadRequest
+        );
+        assert_eq!(
+            status("PUT /dns-query HTTP/1.1\r\n\r\n"),
+            Status::MethodNotAllowed
+        );
+        assert_eq!(
+            status("POST /dns-query HTTP/1.1\r\nContent-Type: text/plain\r\n\r\n"),
+            Status::UnsupportedMediaType
+        );
+        assert!(request("SSH-2.0-OpenSSH\r\n").is_err());
+        assert!(request("POST /dns-query HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n").is_err());
+
+        let mut response = DnsPacket::from_bytes(&query).unwrap();
+        response.answers = vec![DnsResourceRecord {
+            name,
+            rr_type: DnsRRType::A,
+            class: DnsClass::IN,
+            ttl: 300,
+            record: DnsRecordData::A(Ipv4Addr::new(192, 0, 2, 1)),
+        }];
+        let mut written = Vec::new();
+        write_response(&mut written, Status::Ok, &response.to_bytes()).unwrap();
+        let written = String::from_utf8_lossy(&written);
+        assert!(written.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(written.contains("Content-Type: application/dns-message\r\n"));
+        assert!(written.contains("Cache-Control: max-age=300\r\n"));
+    }
+}
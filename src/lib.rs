@@ -0,0 +1,22 @@
+// montague as a library, for programs that want to resolve names the way the server does without
+// running it. `Resolver` resolves recursively from the root servers, or forwards to upstreams, and
+// answers with whole packets from `protocol`:
+//
+//     use montague::protocol::{name_from_string, DnsClass, DnsQuestion, DnsRRType};
+//     use montague::Resolver;
+//
+//     let question = DnsQuestion {
+//         qname: name_from_string("example.com")?,
+//         qtype: DnsRRType::A,
+//         qclass: DnsClass::IN,
+//     };
+//     let response = Resolver::recursive().resolve(&question)?;
+//
+// Everything the server is built from is under `dns`, for anything more involved.
+
+pub mod dns;
+
+pub use dns::config::{ResolverConfig, ResolverOpts};
+pub use dns::error::ResolveError;
+pub use dns::protocol;
+pub use dns::resolver::Resolver;
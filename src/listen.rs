@@ -0,0 +1,136 @@
+// Where the server takes queries. Each --listen=ADDR:PORT[/TRANSPORT][?OPTION&OPTION...] is a
+// listener of its own, with its own say over who may use it and what they get:
+//
+//   allow=PREFIX      Only answer clients in this range. Repeatable; without any, anyone may.
+//   profile=NAME      The policy profile queries through this listener are tagged with.
+//   transparent       Take transparently proxied queries, as --tproxy does.
+//
+// The transport is udp, tcp, tls or https, and udp when it isn't given.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+
+use crate::dns::access::AddressPrefix;
+use crate::dns::context::Transport;
+
+// Where we listen without any --listen
+const DEFAULT_PORT: u16 = 5300;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Listener {
+    pub addr: SocketAddr,
+    pub transport: Transport,
+    pub allow: Vec<AddressPrefix>,
+    pub profile: Option<String>,
+    pub transparent: bool,
+}
+
+impl Listener {
+    // Localhost, for when no listeners are given; there's one for each transport
+    pub fn local(transport: Transport) -> Listener {
+        Listener {
+            transport,
+            ..Listener::new(SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                DEFAULT_PORT,
+            ))
+        }
+    }
+
+    // The listener --tproxy sets up
+    pub fn transparent(addr: SocketAddr) -> Listener {
+        Listener {
+            transparent: true,
+            ..Listener::new(addr)
+        }
+    }
+
+    fn new(addr: SocketAddr) -> Listener {
+        Listener {
+            addr,
+            transport: Transport::Udp,
+            allow: Vec::new(),
+            profile: None,
+            transparent: false,
+        }
+    }
+
+    pub fn allows(&self, client: IpAddr) -> bool {
+        self.allow.is_empty() || self.allow.iter().any(|prefix| prefix.contains(client))
+    }
+}
+
+impl FromStr for Listener {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (endpoint, options) = s.split_once('?').unwrap_or((s, ""));
+        // IPv6 addresses are bracketed, so the last slash is always the transport's
+        let (addr, transport) = match endpoint.rsplit_once('/') {
+            Some((addr, "udp")) => (addr, Transport::Udp),
+            Some((addr, "tcp")) => (addr, Transport::Tcp),
+            Some((addr, "tls")) => (addr, Transport::Tls),
+            Some((addr, "https")) => (addr, Transport::Https),
+            Some((_, transport)) => return Err(format!("Unknown transport {}", transport)),
+            None => (endpoint, Transport::Udp),
+        };
+        let mut listener = Listener::new(
+            addr.parse()
+                .map_err(|_| format!("{} isn't an ADDR:PORT", addr))?,
+        );
+        listener.transport = transport;
+        for option in options.split('&').filter(|option| !option.is_empty()) {
+            match option.split_once('=') {
+                Some(("allow", prefix)) => listener.allow.push(prefix.parse()?),
+                Some(("profile", name)) if !name.is_empty() => {
+                    listener.profile = Some(name.to_owned())
+                }
+                None if option == "transparent" => listener.transparent = true,
+                _ => return Err(format!("Unknown listener option {}", option)),
+            }
+        }
+        Ok(listener)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listeners_parse() {
+        let listener: Listener =
+            "192.168.1.1:53/udp?allow=192.168.1.0/24&allow=10.0.0.0/8&profile=lan"
+                .parse()
+                .unwrap();
+        assert_eq!(listener.addr, "192.168.1.1:53".parse().unwrap());
+        assert_eq!(listener.transport, Transport::Udp);
+        assert_eq!(listener.profile.as_deref(), Some("lan"));
+        assert!(listener.allows("192.168.1.20".parse().unwrap()));
+        assert!(listener.allows("10.1.2.3".parse().unwrap()));
+        assert!(!listener.allows("172.16.0.1".parse().unwrap()));
+
+        let listener: Listener = "[::1]:5300?transparent".parse().unwrap();
+        assert_eq!(listener.addr, "[::1]:5300".parse().unwrap());
+        assert!(listener.transparent);
+        // No ranges means no restrictions
+        assert!(listener.allows("203.0.113.1".parse().unwrap()));
+
+        assert_eq!(
+            "127.0.0.1:53/tcp".parse::<Listener>().unwrap().transport,
+            Transport::Tcp
+        );
+        assert_eq!(
+            "[::]:443/https".parse::<Listener>().unwrap().transport,
+            Transport::Https
+        );
+        assert_eq!(
+            "[::]:853/tls".parse::<Listener>().unwrap().transport,
+            Transport::Tls
+        );
+        assert!("127.0.0.1:53/sctp".parse::<Listener>().is_err());
+        assert!("127.0.0.1".parse::<Listener>().is_err());
+        assert!("127.0.0.1:53?allow=lan".parse::<Listener>().is_err());
+        assert!("127.0.0.1:53?views".parse::<Listener>().is_err());
+    }
+}
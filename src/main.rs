@@ -1,106 +1,1343 @@
 use std::error;
+use std::fs;
+use std::io::{self, Read, Write};
 use std::net;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
+use montague::dns;
 use socket2::{Domain, Socket, Type};
 
-mod dns;
+mod bench;
+mod config_file;
+mod decode;
+mod https;
+mod listen;
+mod reload;
+mod replay;
+#[cfg(target_os = "linux")]
+mod sandbox;
+#[cfg(windows)]
+mod service;
+mod tcp;
+mod tls;
+#[cfg(target_os = "linux")]
+mod tproxy;
+mod udp;
+mod zonediff;
 
+use dns::access::{AccessPolicy, AddressPrefix};
+use dns::analytics::Analytics;
+use dns::cache_file;
+use dns::config::{AddressPreference, ResolverConfig, ResolverOpts, Upstream};
+use dns::context::{QueryContext, Transport};
+#[cfg(unix)]
+use dns::control;
+use dns::ddr::{Designations, Endpoint};
+use dns::debug;
+use dns::error::ResolveError;
+use dns::error_report::ReportChannel;
+use dns::journal;
+use dns::lookalike::Lookalikes;
+use dns::memory::{MemoryBudget, Pressure};
+use dns::outbound::{Outbound, Source};
+use dns::pipeline::{self, FlattenCnames, Middleware, OneQuestion, Pipeline, QuestionPolicy};
 use dns::protocol;
-use dns::recursive;
+use dns::query_log::QueryLog;
+use dns::rate_limit::{self, RateLimiter, Verdict};
+use dns::recursive::{reachable_preference, RootHints, RootMirror, ROOT_ZONE_SERVERS};
+use dns::resolver::Resolver;
+use dns::sinkhole::{Sinkhole, SinkholeRule};
+use dns::stats::{QueryTracker, ServerStats};
+use dns::status;
+use dns::telemetry::{self, SpanKind};
+use dns::trace::{self, error, info, log, warning};
+use dns::transfer;
+use dns::ttl::TtlOverrides;
+use dns::update;
+use dns::validation::HostnameValidation;
+use dns::warm;
+use dns::zone;
+use dns::zone_watch::{self, ZoneFile};
+use listen::Listener;
+use reload::Reloadable;
+use udp::{BatchReceiver, Datagram};
+
+// What we count each in-flight query as costing against --memory-limit: the receive buffer, the
+// parsed packets, and the bookkeeping of a recursive resolution. A guess, but a stable one.
+const IN_FLIGHT_QUERY_COST: usize = 16 * 1024;
+// And each open TCP, TLS or HTTPS connection: room for the largest message in each direction
+const CONNECTION_COST: usize = 2 * 64 * 1024;
+
+// How long queries being answered get to finish once we're told to shut down, and how often we
+// check whether they have
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+const DRAIN_POLL: Duration = Duration::from_millis(50);
+
+// Connections waiting to be accepted on each TCP listener
+const TCP_BACKLOG: i32 = 128;
+
+// What every client can take a response of over UDP
+const UDP_MIN_RESPONSE_SIZE: usize = 512;
 
 // Make Result<T> an alias for a result with a boxed error in it. This lets
 // us write methods that return multiple different types of errors more easily,
 // but has the drawback that we can't statically determine what is in the box.
 type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
 
-// Main server thread entry point. Creates a response to a received query.
-fn resolve_query(buf: &[u8]) -> Result<protocol::DnsPacket> {
+// Main server thread entry point. Creates a response to a received query, or says why there
+// shouldn't be one.
+fn resolve_query(
+    buf: &[u8],
+    server: &Server,
+    client: net::SocketAddr,
+    listener: &Listener,
+    tracker: &QueryTracker,
+) -> std::result::Result<protocol::DnsPacket, ResolveError> {
     // Process the DNS packet received and print out some data from it
     let packet = match protocol::DnsPacket::from_bytes(buf) {
-        Ok(x) => Ok(x),
+        Ok(x) => x,
         Err(e) => {
-            println!("Invalid format!");
-            match e.get_error_response() {
+            log!("Invalid format!");
+            return match e.get_error_response() {
                 Some(response) => {
-                    println!("Returning response {:?}", response);
-                    return Ok(response);
+                    log!("Returning response {:?}", response);
+                    Ok(response)
                 }
-                None => {
-                    println!("Not enough info to build a response, dropping connection");
+                None => Err(ResolveError::Unanswerable(e.to_string())),
+            };
+        }
+    };
+    trace::describe(&packet);
+    log!("DNS Packet Received: {:?}", packet);
+    let mut context = QueryContext::new(&packet, client, listener.transport, tracker.id());
+    context.profile = listener.profile.to_owned();
+    let client = context.client.ip();
+    if let Some(question) = packet.questions.first() {
+        tracker.set_question(question);
+        if let Some(log) = &server.query_log {
+            log.record(client, question);
+        }
+        if let Some(analytics) = &server.analytics {
+            analytics.client(client, context.received);
+        }
+    }
+    // Transfers and updates are between us and whoever manages our zones, not something for the
+    // query pipeline
+    if update::is_update(&packet) {
+        return Ok(update::answer(
+            buf,
+            &packet,
+            client,
+            &server.updates,
+            &server.resolver,
+        ));
+    }
+    // Nor are status requests, or anything else that isn't a plain query
+    if status::is_other_opcode(&packet) {
+        let stats = server.answer_status.then(|| &*server.stats);
+        return Ok(status::answer(&packet, stats));
+    }
+    if transfer::is_transfer(&packet) {
+        return Ok(transfer::answer(
+            buf,
+            &packet,
+            client,
+            &server.transfers,
+            &server.resolver,
+        ));
+    }
+    server.pipeline.run(&packet, &context)
+}
+
+// How big a response to the query in `bytes` can be: as big as a message can be over TCP (and
+// TLS and HTTPS), and over UDP what the query's EDNS says the client can take. Queries that don't
+// parse get responses small enough for anyone.
+fn response_limit(bytes: &[u8], transport: Transport) -> usize {
+    match transport {
+        Transport::Tcp | Transport::Tls | Transport::Https => u16::MAX.into(),
+        Transport::Udp => protocol::DnsPacket::from_bytes(bytes)
+            .map_or(UDP_MIN_RESPONSE_SIZE, |query| {
+                protocol::udp_response_limit(&query)
+            }),
+    }
+}
+
+// Hand a response off to the sending thread, cut down to `limit` bytes with the TC bit set if it
+// doesn't fit. `local` is the address to send it from, when that isn't the socket's own
+// (transparent proxying).
+fn respond(
+    responses: &mpsc::Sender<Datagram>,
+    packet: &protocol::DnsPacket,
+    limit: usize,
+    dest: net::SocketAddr,
+    local: Option<net::SocketAddr>,
+) -> Result<()> {
+    // Send the results back to the client
+    log!("Returning results: {:?}", packet);
+    let response_bytes = packet.to_bytes_within(limit);
+    debug::log_packet(&format!("Sending to {}", dest), &response_bytes);
+    responses.send(Datagram {
+        bytes: response_bytes,
+        addr: dest,
+        local,
+    })?;
+    Ok(())
+}
+
+// Send responses as they're finished, batching up any that finish at about the same time
+fn send_responses(socket: net::UdpSocket, responses: mpsc::Receiver<Datagram>) {
+    while let Ok(first) = responses.recv() {
+        let mut batch = vec![first];
+        while batch.len() < udp::BATCH_SIZE {
+            match responses.try_recv() {
+                Ok(datagram) => batch.push(datagram),
+                Err(_) => break,
+            }
+        }
+        // Responses we're sending on someone else's behalf each need a socket of their own
+        let (transparent, batch): (Vec<Datagram>, Vec<Datagram>) =
+            batch.into_iter().partition(|d| d.local.is_some());
+        for datagram in &transparent {
+            if let Err(e) = send_transparent(datagram) {
+                warning!("Error sending response to {}: {}", datagram.addr, e);
+            }
+        }
+        if let Err(e) = udp::send_batch(&socket, &batch) {
+            error!("Error sending responses: {}", e);
+        }
+    }
+}
+
+// Send responses down a TCP or TLS connection as they're finished, in whatever order that is
+fn send_tcp_responses(mut stream: impl Write, responses: mpsc::Receiver<Datagram>) {
+    for datagram in responses {
+        if let Err(e) = tcp::write_message(&mut stream, &datagram.bytes) {
+            warning!("Error sending response to {}: {}", datagram.addr, e);
+            return;
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn send_transparent(datagram: &Datagram) -> Result<()> {
+    Ok(tproxy::send_from(datagram.local.unwrap(), datagram)?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_transparent(_datagram: &Datagram) -> Result<()> {
+    Err("transparent proxying is only supported on Linux".into())
+}
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("bench") => bench::run(&args[1..]),
+        Some("decode") => decode::run(&args[1..]),
+        Some("replay") => replay::run(&args[1..]),
+        Some("compact-journal") => compact_journal(&args[1..]),
+        Some("zonediff") => zonediff::run(&args[1..]),
+        #[cfg(windows)]
+        Some("service") => service::run(&args[1..]),
+        _ => run_server(&args),
+    }
+}
+
+// `montague compact-journal ORIGIN ZONE_FILE [DEFAULT_TTL]`: fold the updates journaled for a
+// zone into its file
+fn compact_journal(args: &[String]) -> Result<()> {
+    let (origin, path, default_ttl) = match args {
+        [origin, path] => (origin, path, None),
+        [origin, path, ttl] => (origin, path, Some(zone::parse_ttl(ttl)?)),
+        _ => return Err("Usage: montague compact-journal ORIGIN ZONE_FILE [DEFAULT_TTL]".into()),
+    };
+    let origin = protocol::name_from_string(origin)?;
+    let (changes, serial) = journal::compact(&origin, Path::new(path), default_ttl)?;
+    println!(
+        "Compacted {} changes into {} at serial {}",
+        changes, path, serial
+    );
+    Ok(())
+}
+
+// Parse the server's flags and serve until something goes wrong
+fn run_server(args: &[String]) -> Result<()> {
+    #[cfg(unix)]
+    let command_line = args.to_vec();
+    let args = config_file::expand(args)?;
+    let mut hostname_validation = HostnameValidation::default();
+    let mut question_policy = QuestionPolicy::default();
+    let mut memory_limit = 0;
+    let mut sandbox = false;
+    let mut listeners = Vec::new();
+    let mut upstreams = Vec::new();
+    let mut outbound = Vec::new();
+    let mut designated = Vec::new();
+    let mut root_mirror = false;
+    let mut address_preference = None;
+    let mut query_timeout = None;
+    let mut query_attempts = None;
+    let mut tls_cert = None;
+    let mut query_rate = 0;
+    let mut response_rate = 0;
+    let mut slip = rate_limit::DEFAULT_SLIP;
+    let mut tls_key = None;
+    let mut log_level = trace::Level::Info;
+    let mut log_format = trace::Format::Text;
+    let mut discover_designated = false;
+    let mut report_errors = false;
+    let mut report_channel = None;
+    let mut answer_status = false;
+    let mut watch_zones = false;
+    let mut bump_serials = false;
+    let mut stub_zones = Vec::new();
+    let mut ttl_overrides = Vec::new();
+    let mut local_data = Vec::new();
+    let mut script = None;
+    let mut query_log = None;
+    let mut cache_file = None;
+    let mut control_socket = None;
+    let mut warm_list = None;
+    let mut warm_interval = None;
+    let mut analytics = None;
+    let mut otlp_endpoint = None;
+    let mut flatten = Vec::new();
+    let mut sinkholes = Vec::new();
+    let mut lookalikes = None;
+    let mut tsig_keys = Vec::new();
+    let mut transfer_acls = Vec::new();
+    let mut transfer_keys = Vec::new();
+    let mut update_acls = Vec::new();
+    let mut update_keys = Vec::new();
+    for arg in &args {
+        match arg.as_str() {
+            "--debug-packets" => debug::set_packet_debug(true),
+            "--sandbox" => sandbox = true,
+            "--root-mirror" => root_mirror = true,
+            "--prefer-ipv6" => address_preference = Some(AddressPreference::Ipv6),
+            "--report-errors" => report_errors = true,
+            "--ddr" => discover_designated = true,
+            "--status-opcode" => answer_status = true,
+            "--watch-zones" => watch_zones = true,
+            "--zone-auto-serial" => bump_serials = true,
+            _ if arg.starts_with("--hostname-validation=") => {
+                hostname_validation = arg["--hostname-validation=".len()..].parse()?;
+            }
+            _ if arg.starts_with("--multiple-questions=") => {
+                question_policy = arg["--multiple-questions=".len()..].parse()?;
+            }
+            _ if arg.starts_with("--otlp-endpoint=") => {
+                otlp_endpoint = Some(&arg["--otlp-endpoint=".len()..]);
+            }
+            _ if arg.starts_with("--query-log=") => {
+                // Opened now, since the sandbox won't let us later
+                let path = Path::new(&arg["--query-log=".len()..]);
+                query_log = Some(Arc::new(QueryLog::open(path)?));
+            }
+            _ if arg.starts_with("--analytics=") => {
+                let windows = arg["--analytics=".len()..]
+                    .split(',')
+                    .map(|window| zone::parse_ttl(window).map(|s| Duration::from_secs(s.into())))
+                    .collect::<std::result::Result<Vec<_>, _>>()?;
+                analytics = Some(Arc::new(Analytics::new(&windows)));
+            }
+            _ if arg.starts_with("--cache-file=") => {
+                cache_file = Some(PathBuf::from(&arg["--cache-file=".len()..]));
+            }
+            _ if arg.starts_with("--control=") => {
+                control_socket = Some(PathBuf::from(&arg["--control=".len()..]));
+            }
+            _ if arg.starts_with("--warm-cache=") => {
+                let path = Path::new(&arg["--warm-cache=".len()..]);
+                let text =
+                    fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+                warm_list =
+                    Some(warm::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))?);
+            }
+            _ if arg.starts_with("--warm-cache-interval=") => {
+                let seconds = zone::parse_ttl(&arg["--warm-cache-interval=".len()..])?;
+                warm_interval = Some(Duration::from_secs(seconds.into()));
+            }
+            _ if arg.starts_with("--memory-limit=") => {
+                let megabytes: usize = arg["--memory-limit=".len()..].parse()?;
+                memory_limit = megabytes * 1024 * 1024;
+            }
+            _ if arg.starts_with("--upstream=") => {
+                // Each flag is a failover group, with its members separated by commas
+                let group = arg["--upstream=".len()..]
+                    .split(',')
+                    .map(|upstream| upstream.parse::<Upstream>())
+                    .collect::<std::result::Result<Vec<_>, _>>()?;
+                upstreams.push(group);
+            }
+            _ if arg.starts_with("--report-channel=") => {
+                let agent = &arg["--report-channel=".len()..];
+                report_channel = Some(protocol::name_from_string(agent)?);
+            }
+            _ if arg.starts_with("--outbound=") => {
+                outbound.push(arg["--outbound=".len()..].parse::<Source>()?);
+            }
+            _ if arg.starts_with("--designate=") => {
+                designated.push(arg["--designate=".len()..].parse::<Endpoint>()?);
+            }
+            // Zones and root hints, picked out separately so SIGHUP can pick them out again
+            _ if Reloadable::takes(arg) => (),
+            _ if arg.starts_with("--ttl-override=") => {
+                ttl_overrides.push(arg["--ttl-override=".len()..].parse()?);
+            }
+            _ if arg.starts_with("--tsig-key=") => {
+                tsig_keys.push(arg["--tsig-key=".len()..].parse::<protocol::TsigKey>()?);
+            }
+            _ if arg.starts_with("--allow-transfer=") => {
+                transfer_acls.push(parse_zone_acl(&arg["--allow-transfer=".len()..])?);
+            }
+            _ if arg.starts_with("--transfer-key=") => {
+                transfer_keys.push(parse_zone_key(&arg["--transfer-key=".len()..])?);
+            }
+            _ if arg.starts_with("--allow-update=") => {
+                update_acls.push(parse_zone_acl(&arg["--allow-update=".len()..])?);
+            }
+            _ if arg.starts_with("--update-key=") => {
+                update_keys.push(parse_zone_key(&arg["--update-key=".len()..])?);
+            }
+            _ if arg.starts_with("--local-data=") => {
+                local_data.push(zone::parse_record(&[], &arg["--local-data=".len()..])?);
+            }
+            _ if arg.starts_with("--stub-zone=") => {
+                stub_zones.push(arg["--stub-zone=".len()..].parse()?);
+            }
+            _ if arg.starts_with("--flatten-cnames=") => {
+                flatten.push(protocol::name_from_string(
+                    &arg["--flatten-cnames=".len()..],
+                )?);
+            }
+            "--flag-lookalikes" => lookalikes = Some(Vec::new()),
+            _ if arg.starts_with("--flag-lookalikes=") => {
+                let protected = arg["--flag-lookalikes=".len()..]
+                    .split(',')
+                    .map(String::from)
+                    .collect();
+                lookalikes = Some(protected);
+            }
+            _ if arg.starts_with("--sinkhole=") => {
+                sinkholes.push(arg["--sinkhole=".len()..].parse::<SinkholeRule>()?);
+            }
+            _ if arg.starts_with("--sinkhole-nxdomain=") => {
+                let mut rule = arg["--sinkhole-nxdomain=".len()..].parse::<SinkholeRule>()?;
+                rule.nxdomain = true;
+                sinkholes.push(rule);
+            }
+            _ if arg.starts_with("--script=") => {
+                script = Some(load_script(Path::new(&arg["--script=".len()..]))?);
+            }
+            _ if arg.starts_with("--log-level=") => {
+                log_level = arg["--log-level=".len()..].parse()?;
+            }
+            _ if arg.starts_with("--log-format=") => {
+                log_format = arg["--log-format=".len()..].parse()?;
+            }
+            _ if arg.starts_with("--address-preference=") => {
+                address_preference = Some(arg["--address-preference=".len()..].parse()?);
+            }
+            _ if arg.starts_with("--query-timeout=") => {
+                let millis: u64 = arg["--query-timeout=".len()..].parse()?;
+                if millis == 0 {
+                    return Err("--query-timeout has to be at least 1ms".into());
                 }
+                query_timeout = Some(Duration::from_millis(millis));
+            }
+            _ if arg.starts_with("--query-attempts=") => {
+                let attempts: usize = arg["--query-attempts=".len()..].parse()?;
+                if attempts == 0 {
+                    return Err("--query-attempts has to be at least 1".into());
+                }
+                query_attempts = Some(attempts);
+            }
+            _ if arg.starts_with("--rate-limit=") => {
+                query_rate = arg["--rate-limit=".len()..].parse()?;
+            }
+            _ if arg.starts_with("--response-rate-limit=") => {
+                response_rate = arg["--response-rate-limit=".len()..].parse()?;
             }
-            Err(e)
+            _ if arg.starts_with("--rrl-slip=") => {
+                slip = arg["--rrl-slip=".len()..].parse()?;
+            }
+            _ if arg.starts_with("--tls-cert=") => {
+                tls_cert = Some(PathBuf::from(&arg["--tls-cert=".len()..]));
+            }
+            _ if arg.starts_with("--tls-key=") => {
+                tls_key = Some(PathBuf::from(&arg["--tls-key=".len()..]));
+            }
+            _ if arg.starts_with("--listen=") => {
+                listeners.push(arg["--listen=".len()..].parse::<Listener>()?);
+            }
+            _ if arg.starts_with("--tproxy=") => {
+                let addr = arg["--tproxy=".len()..].parse::<net::SocketAddr>()?;
+                listeners.push(Listener::transparent(addr));
+            }
+            _ => return Err(format!("Unknown argument: {}", arg).into()),
         }
-    }?;
-    println!("DNS Packet Received: {:?}", packet);
+    }
+    trace::configure(log_level, log_format);
+    let reloadable = Reloadable::from_args(&args)?;
 
-    // Confirm that the DNS packet contains exactly 1 question, or return an error
-    // NOTE: The exact semantics of what to do with multiple questions as part of the same query is
-    // unclear. Technically, they're allowed by RFC 1035, but there's practical issues (e.g. if two
-    // different domains are queried for, what does an NXDOMAIN status code in the header
-    // indicate?). Real nameservers seem to generally just discard (ignore) the additional
-    // questions; rejecting them is a bit meaner.
-    if packet.questions.len() != 1 {
-        println!(
-            "Question count was {}, we require it be 1",
-            packet.questions.len()
-        );
-        return Err("Dropping out, implement a better thing here".into());
+    if listeners.is_empty() {
+        listeners.push(Listener::local(Transport::Udp));
+        listeners.push(Listener::local(Transport::Tcp));
+    }
+    if let Some(listener) = listeners
+        .iter()
+        .find(|l| l.transparent && l.transport != Transport::Udp)
+    {
+        return Err(format!(
+            "{}: transparent proxying is only supported over UDP",
+            listener.addr
+        )
+        .into());
+    }
+    // Read now, since the sandbox won't let us later
+    let tls = match (tls_cert, tls_key) {
+        (Some(cert), Some(key)) => Some(tls::server_config(&cert, &key)?),
+        (None, None) => None,
+        _ => return Err("--tls-cert and --tls-key go together".into()),
+    };
+    if tls.is_none()
+        && listeners
+            .iter()
+            .any(|l| matches!(l.transport, Transport::Tls | Transport::Https))
+    {
+        return Err("TLS listeners need a certificate, from --tls-cert and --tls-key".into());
+    }
+    if watch_zones && sandbox {
+        return Err("--watch-zones needs the filesystem access --sandbox takes away".into());
+    }
+    if warm_interval.is_some() && warm_list.is_none() {
+        return Err("--warm-cache-interval needs a --warm-cache list to warm with".into());
+    }
+    if control_socket.is_some() && sandbox {
+        return Err("--control needs the filesystem access --sandbox takes away".into());
+    }
+    if cache_file.is_some() && sandbox {
+        return Err("--cache-file needs the filesystem access --sandbox takes away".into());
+    }
+    let zones = reloadable
+        .zone_files
+        .iter()
+        .map(ZoneFile::load)
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    let transfers = access_policy(&tsig_keys, transfer_acls, transfer_keys)?;
+    let updates = access_policy(&tsig_keys, update_acls, update_keys)?;
+    let mut config = ResolverConfig {
+        upstreams,
+        zones,
+        local_data,
+        ttl_overrides: TtlOverrides::new(ttl_overrides),
+        ..ResolverConfig::default()
+    };
+    if let Some(preference) = address_preference {
+        config.root_hints = RootHints::with_preference(preference);
+    }
+    let defaults = ResolverOpts::default();
+    let opts = ResolverOpts {
+        address_preference: address_preference.unwrap_or_else(reachable_preference),
+        timeout: query_timeout.unwrap_or(defaults.timeout),
+        attempts: query_attempts.unwrap_or(defaults.attempts),
+        discover_designated,
+        outbound: Outbound {
+            sources: outbound,
+            ..Outbound::default()
+        },
+        error_reports: report_errors.then(Arc::default),
+        ..defaults
+    };
+    if let Some(path) = &reloadable.root_hints {
+        let hints = reload::load_root_hints(path)?;
+        config
+            .root_hints
+            .use_hints(&hints, opts.address_preference)?;
+    }
+    for stub in stub_zones {
+        config.root_hints.add_stub_zone(stub);
+    }
+    if root_mirror {
+        let mirror = Arc::new(RootMirror::new());
+        let servers = ROOT_ZONE_SERVERS
+            .iter()
+            .map(|ip| net::SocketAddr::new(*ip, 53))
+            .collect();
+        mirror.start(servers, &opts);
+        config.root_hints.mirror = Some(mirror);
+    }
+    if let Some(endpoint) = otlp_endpoint {
+        telemetry::start(endpoint)?;
+    }
+    let resolver = Resolver::new(config, opts);
+    let mut pipeline = Pipeline::standard(
+        resolver.to_owned(),
+        hostname_validation,
+        protocol::EdnsRegistry::new(),
+    );
+    pipeline.replace("question", Arc::new(OneQuestion::new(question_policy)))?;
+    // resolver.arpa is ours to answer even without anything to designate, and before any zone
+    pipeline.insert_before("local", Arc::new(Designations::new(&designated)))?;
+    if let Some(script) = script {
+        // Scripts see queries once we know they're well formed, before anything answers them
+        pipeline.insert_after("hostnames", script)?;
+    }
+    if let Some(analytics) = &analytics {
+        // Outside of hostname validation, scripts, and flattening, so it counts what they refuse
+        pipeline.insert_after("question", analytics.clone())?;
+    }
+    if let Some(protected) = lookalikes {
+        let lookalikes = Lookalikes::new(&protected, analytics.clone());
+        pipeline.insert_after("question", Arc::new(lookalikes))?;
+    }
+    if let Some(agent) = report_channel {
+        // Outside everything, so it sees whether the finished answer is authoritative
+        pipeline.insert_before("finish", Arc::new(ReportChannel::new(agent)))?;
+    }
+    if !sinkholes.is_empty() {
+        // Outside the script too, so it redirects whatever the script blocks
+        pipeline.insert_after("hostnames", Arc::new(Sinkhole::new(sinkholes)))?;
+    }
+    if !flatten.is_empty() {
+        // Outside the script, so it flattens whatever the script answers too
+        pipeline.insert_after("hostnames", Arc::new(FlattenCnames::new(flatten)))?;
+    }
+    let server = Server {
+        resolver,
+        pipeline: Arc::new(pipeline),
+        transfers: Arc::new(transfers),
+        updates: Arc::new(updates),
+        budget: Arc::new(MemoryBudget::new(memory_limit)),
+        stats: Arc::new(ServerStats::new()),
+        connections: tcp::Slots::new(tcp::MAX_CONNECTIONS),
+        query_log,
+        analytics,
+        answer_status,
+        tls,
+        rate_limiter: (query_rate != 0 || response_rate != 0)
+            .then(|| Arc::new(RateLimiter::new(query_rate, response_rate, slip))),
+        stopping: Arc::default(),
     };
+    #[cfg(unix)]
+    report_stats_on_sigusr1(
+        server.stats.clone(),
+        server.resolver.to_owned(),
+        server.budget.clone(),
+        server.analytics.clone(),
+    )?;
+    if let Some(path) = cache_file {
+        import_cache(&server.resolver, &path)?;
+        #[cfg(unix)]
+        export_cache_on_sigusr2(server.resolver.to_owned(), path)?;
+    }
+    server.resolver.start_health_checks();
+    if let Some(questions) = warm_list {
+        warm::start(server.resolver.to_owned(), questions, warm_interval);
+    }
+    if let Some(path) = control_socket {
+        start_control(
+            &server.resolver,
+            &reloadable.zone_files,
+            bump_serials,
+            &path,
+        )?;
+    }
+    // Watches for as long as the server runs
+    let _zone_watcher = if watch_zones && !reloadable.zone_files.is_empty() {
+        Some(zone_watch::watch(
+            reloadable.zone_files.to_owned(),
+            server.resolver.to_owned(),
+            bump_serials,
+        )?)
+    } else {
+        None
+    };
+    #[cfg(unix)]
+    reload_on_sighup(
+        command_line,
+        reloadable,
+        server.resolver.to_owned(),
+        bump_serials,
+        sandbox,
+    )?;
+    // Sockets have to be set up before the sandbox takes away the permissions to do so
+    let sockets = listeners
+        .into_iter()
+        .map(|listener| {
+            let socket = match listener.transport {
+                Transport::Tcp | Transport::Tls | Transport::Https => {
+                    Bound::Tcp(bind_tcp_listener(listener.addr)?)
+                }
+                Transport::Udp if listener.transparent => {
+                    Bound::Udp(bind_transparent(listener.addr)?)
+                }
+                Transport::Udp => Bound::Udp(bind_listener(listener.addr)?),
+            };
+            Ok((Arc::new(listener), socket))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if sandbox {
+        enter_sandbox()?;
+    }
+    server.serve(sockets)
+}
 
-    // Run a recursive query on our one question
-    let mut results = recursive::resolve_question(&packet.questions[0])?;
-    // Use the originating txid
-    results.id = packet.id;
-    // Set the RA bit TODO this should probably be owned by the resolver code
-    results.flags.ra_bit = true;
+#[cfg(target_os = "linux")]
+fn enter_sandbox() -> Result<()> {
+    // Nothing is read from disk once we're serving
+    sandbox::enter(&[])?;
+    info!("Sandbox enabled");
+    Ok(())
+}
 
-    Ok(results)
+#[cfg(not(target_os = "linux"))]
+fn enter_sandbox() -> Result<()> {
+    Err("--sandbox is only supported on Linux".into())
 }
 
-// Listen on localhost (127.0.0.1) UDP port 5300 and reads up to 1500 bytes
-fn receive(socket: &net::UdpSocket) -> Result<([u8; 1500], usize, std::net::SocketAddr)> {
-    // Receive data from the user.
-    // TODO(dylan): Up to an MTU of 1500, consider using an alloc here
-    let mut buf = [0; 1500];
-    let (amt, src) = socket.recv_from(&mut buf)?;
-    println!("Data received: {} bytes", amt);
+// Log a statistics snapshot every time we get SIGUSR1 (`kill -USR1 <pid>`)
+#[cfg(unix)]
+fn report_stats_on_sigusr1(
+    stats: Arc<ServerStats>,
+    resolver: Resolver,
+    budget: Arc<MemoryBudget>,
+    analytics: Option<Arc<Analytics>>,
+) -> Result<()> {
+    let mut signals = signal_hook::iterator::Signals::new([signal_hook::consts::SIGUSR1])?;
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            info!(
+                "{}",
+                stats.report(
+                    resolver.cache_stats(),
+                    resolver.cache_memory(),
+                    &resolver.upstream_statuses(),
+                    &resolver.server_latencies(),
+                    &budget
+                )
+            );
+            if let Some(analytics) = &analytics {
+                info!("{}", analytics.report(Instant::now()).trim_end());
+            }
+        }
+    });
+    Ok(())
+}
 
-    Ok((buf, amt, src))
+#[cfg(unix)]
+fn start_control(
+    resolver: &Resolver,
+    zone_files: &[ZoneFile],
+    bump_serials: bool,
+    path: &Path,
+) -> Result<()> {
+    let control = control::Control::new(resolver.to_owned(), zone_files, bump_serials);
+    control::listen(control, path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    info!("Taking commands on {}", path.display());
+    Ok(())
 }
 
-fn respond(
-    socket: &net::UdpSocket,
-    packet: &protocol::DnsPacket,
-    dest: std::net::SocketAddr,
+#[cfg(not(unix))]
+fn start_control(
+    _resolver: &Resolver,
+    _zone_files: &[ZoneFile],
+    _bump_serials: bool,
+    _path: &Path,
 ) -> Result<()> {
-    // Send the results back to the client
-    println!("Returning results: {:?}", packet);
-    let response_bytes = &packet.to_bytes();
-    socket.send_to(&response_bytes, dest)?;
+    Err("--control is only supported on Unix".into())
+}
+
+// Start with whatever's in the --cache-file, if there's one yet
+fn import_cache(resolver: &Resolver, path: &Path) -> Result<()> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(format!("{}: {}", path.display(), e).into()),
+    };
+    let entries = cache_file::parse(&text, SystemTime::now())
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    info!(
+        "Loaded {} cache entries from {}",
+        entries.len(),
+        path.display()
+    );
+    resolver.load_cache(entries);
     Ok(())
 }
 
-fn main() -> Result<()> {
-    loop {
-        // Open a socket for this listener
-        let socket = Socket::new(Domain::ipv4(), Type::dgram(), None)?;
-        socket.set_reuse_port(true)?;
-        socket.bind(&"127.0.0.1:5300".parse::<net::SocketAddr>().unwrap().into())?;
-        let socket = socket.into_udp_socket();
-
-        let (buf, amt, client) = receive(&socket)?;
+// Write the cache out to the --cache-file every time we get SIGUSR2 (`kill -USR2 <pid>`)
+#[cfg(unix)]
+fn export_cache_on_sigusr2(resolver: Resolver, path: PathBuf) -> Result<()> {
+    let mut signals = signal_hook::iterator::Signals::new([signal_hook::consts::SIGUSR2])?;
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            let entries = resolver.cache_entries();
+            // Written next to the file and renamed over it, so nothing reading the file sees
+            // half of it
+            let partial = path.with_extension("partial");
+            let written = fs::write(&partial, cache_file::write(&entries, SystemTime::now()))
+                .and_then(|_| fs::rename(&partial, &path));
+            match written {
+                Ok(()) => info!(
+                    "Wrote {} cache entries to {}",
+                    entries.len(),
+                    path.display()
+                ),
+                Err(e) => warning!("Error writing the cache to {}: {}", path.display(), e),
+            }
+        }
+    });
+    Ok(())
+}
+
+// Reload zones and root hints every time we get SIGHUP (`kill -HUP <pid>`), from the flags and
+// config file as they are now
+#[cfg(unix)]
+fn reload_on_sighup(
+    command_line: Vec<String>,
+    mut running: Reloadable,
+    resolver: Resolver,
+    bump_serials: bool,
+    sandboxed: bool,
+) -> Result<()> {
+    let mut signals = signal_hook::iterator::Signals::new([signal_hook::consts::SIGHUP])?;
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            if sandboxed {
+                warning!("Not reloading, since --sandbox keeps files from being read again");
+                continue;
+            }
+            match reload::reload(&command_line, &running, &resolver, bump_serials) {
+                Ok(reloaded) => running = reloaded,
+                Err(e) => warning!("Not reloading: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+// Shut down on SIGTERM or SIGINT (`kill <pid>`, or ^C), by saying so on the channel listeners
+// report stopping on. A second one doesn't wait for the first to finish.
+#[cfg(unix)]
+fn stop_on_sigterm(stopped: mpsc::Sender<std::result::Result<(), String>>) -> Result<()> {
+    use signal_hook::consts::{SIGINT, SIGTERM};
+    let mut signals = signal_hook::iterator::Signals::new([SIGTERM, SIGINT])?;
+    thread::spawn(move || {
+        for (received, signal) in signals.forever().enumerate() {
+            if received > 0 {
+                warning!(
+                    "Got signal {} again, exiting without finishing queries",
+                    signal
+                );
+                std::process::exit(1);
+            }
+            stopped.send(Ok(())).ok();
+        }
+    });
+    Ok(())
+}
+
+fn domain_for(addr: net::SocketAddr) -> Domain {
+    if addr.is_ipv4() {
+        Domain::ipv4()
+    } else {
+        Domain::ipv6()
+    }
+}
+
+// Listen for UDP queries on `addr`
+fn bind_listener(addr: net::SocketAddr) -> Result<net::UdpSocket> {
+    let socket = Socket::new(domain_for(addr), Type::dgram(), None)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    // Windows has no SO_REUSEPORT, but SO_REUSEADDR there allows the same sharing
+    #[cfg(windows)]
+    socket.set_reuse_address(true)?;
+    socket
+        .bind(&addr.into())
+        .map_err(|e| format!("Can't listen on {}: {}", addr, e))?;
+    Ok(socket.into_udp_socket())
+}
+
+// Listen for TCP connections on `addr`
+fn bind_tcp_listener(addr: net::SocketAddr) -> Result<net::TcpListener> {
+    let socket = Socket::new(domain_for(addr), Type::stream(), None)?;
+    // So a restart doesn't have to wait out the last run's connections in TIME_WAIT
+    socket.set_reuse_address(true)?;
+    socket
+        .bind(&addr.into())
+        .map_err(|e| format!("Can't listen on {}: {}", addr, e))?;
+    socket.listen(TCP_BACKLOG)?;
+    Ok(socket.into_tcp_listener())
+}
+
+// "ZONE=PREFIX[,PREFIX...]", for --allow-transfer and --allow-update
+fn parse_zone_acl(arg: &str) -> Result<(Vec<String>, Vec<AddressPrefix>)> {
+    let (zone, prefixes) = arg
+        .split_once('=')
+        .ok_or("Expected ZONE=PREFIX[,PREFIX...]")?;
+    let prefixes = prefixes
+        .split(',')
+        .map(|prefix| prefix.parse())
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    Ok((protocol::name_from_string(zone)?, prefixes))
+}
+
+// "ZONE=KEY", for --transfer-key and --update-key
+fn parse_zone_key(arg: &str) -> Result<(Vec<String>, Vec<String>)> {
+    let (zone, key) = arg.split_once('=').ok_or("Expected ZONE=KEY")?;
+    Ok((
+        protocol::name_from_string(zone)?,
+        protocol::name_from_string(key)?,
+    ))
+}
+
+fn access_policy(
+    keys: &[protocol::TsigKey],
+    acls: Vec<(Vec<String>, Vec<AddressPrefix>)>,
+    required_keys: Vec<(Vec<String>, Vec<String>)>,
+) -> Result<AccessPolicy> {
+    let mut policy = AccessPolicy::new(keys.to_vec());
+    for (zone, prefixes) in acls {
+        policy.allow(&zone, prefixes);
+    }
+    for (zone, key) in required_keys {
+        policy.require_key(&zone, &key)?;
+    }
+    Ok(policy)
+}
+
+#[cfg(feature = "scripting")]
+fn load_script(path: &Path) -> Result<Arc<dyn Middleware>> {
+    info!("Running query hooks from {}", path.display());
+    Ok(Arc::new(dns::script::Script::load(path)?))
+}
+
+#[cfg(not(feature = "scripting"))]
+fn load_script(_path: &Path) -> Result<Arc<dyn Middleware>> {
+    Err("--script needs montague built with the scripting feature".into())
+}
+
+#[cfg(target_os = "linux")]
+fn bind_transparent(addr: net::SocketAddr) -> Result<net::UdpSocket> {
+    info!("Accepting transparently proxied queries on {}", addr);
+    Ok(tproxy::bind(addr)?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_transparent(_addr: net::SocketAddr) -> Result<net::UdpSocket> {
+    Err("--tproxy is only supported on Linux".into())
+}
+
+// What a listener takes queries on
+enum Bound {
+    Udp(net::UdpSocket),
+    Tcp(net::TcpListener),
+}
+
+// Everything a query needs, shared by every thread answering one
+#[derive(Clone)]
+struct Server {
+    resolver: Resolver,
+    pipeline: Arc<Pipeline>,
+    transfers: Arc<AccessPolicy>,
+    updates: Arc<AccessPolicy>,
+    budget: Arc<MemoryBudget>,
+    stats: Arc<ServerStats>,
+    // TCP, TLS and HTTPS connections open at once
+    connections: Arc<tcp::Slots>,
+    query_log: Option<Arc<QueryLog>>,
+    analytics: Option<Arc<Analytics>>,
+    // Whether to answer status requests (opcode 2) rather than saying they're not implemented
+    answer_status: bool,
+    // The certificate TLS listeners present, if there are any
+    tls: Option<Arc<rustls::ServerConfig>>,
+    // Limits on queries and responses over UDP, from --rate-limit and --response-rate-limit
+    rate_limiter: Option<Arc<RateLimiter>>,
+    // Set once we're shutting down, after which new queries are dropped
+    stopping: Arc<AtomicBool>,
+}
+
+impl Server {
+    // Answer queries on every listener, each on a thread of its own, until one of them fails or
+    // we're told to shut down
+    fn serve(&self, listeners: Vec<(Arc<Listener>, Bound)>) -> Result<()> {
+        let (stopped, first_stopped) = mpsc::channel();
+        #[cfg(unix)]
+        stop_on_sigterm(stopped.to_owned())?;
+        for (listener, socket) in listeners {
+            let server = self.to_owned();
+            let stopped = stopped.to_owned();
+            thread::spawn(move || {
+                let result = match socket {
+                    Bound::Udp(socket) => server.listen(&listener, socket),
+                    Bound::Tcp(socket) => server.listen_tcp(&listener, socket),
+                };
+                let result =
+                    result.map_err(|e| format!("Listener on {} failed: {}", listener.addr, e));
+                stopped.send(result).ok();
+            });
+        }
+        first_stopped.recv()??;
+        self.drain();
+        Ok(())
+    }
+
+    // Stop taking queries, and give the ones already being answered until DRAIN_TIMEOUT to finish.
+    // The sockets stay open meanwhile, so their answers can still go out.
+    fn drain(&self) {
+        self.stopping.store(true, Ordering::Relaxed);
+        let deadline = Instant::now() + DRAIN_TIMEOUT;
+        let in_flight = || self.stats.in_flight_count();
+        info!(
+            "Shutting down once {} queries in flight are answered",
+            in_flight()
+        );
+        while in_flight() > 0 {
+            if Instant::now() >= deadline {
+                warning!("Shutting down with {} queries unanswered", in_flight());
+                return;
+            }
+            thread::sleep(DRAIN_POLL);
+        }
+        // The last answers may still be on their way to the threads that send them
+        thread::sleep(DRAIN_POLL);
+        info!("Shut down");
+    }
+
+    fn listen(&self, listener: &Arc<Listener>, socket: net::UdpSocket) -> Result<()> {
+        let (responses, outgoing) = mpsc::channel();
+        let send_socket = socket.try_clone()?;
+        thread::spawn(move || send_responses(send_socket, outgoing));
+
+        let mut receiver = BatchReceiver::new();
+        let mut breaker = udp::ErrorBreaker::new(udp::FAILING_SOCKET_WINDOW);
+        loop {
+            // Transparently proxied queries come one at a time, since each one's original
+            // destination arrives alongside it
+            let received = if listener.transparent {
+                recv_transparent(&socket).map(|datagram| vec![datagram])
+            } else {
+                receiver.recv(&socket)
+            };
+            let datagrams = match received {
+                Ok(datagrams) => {
+                    breaker.success();
+                    datagrams
+                }
+                Err(e) => {
+                    error!("Error receiving queries: {}", e);
+                    let backoff = breaker.failure(e)?;
+                    thread::sleep(backoff);
+                    continue;
+                }
+            };
+            for datagram in datagrams {
+                self.handle_datagram(datagram, listener, &responses, None)?;
+            }
+        }
+    }
+
+    fn listen_tcp(&self, listener: &Arc<Listener>, socket: net::TcpListener) -> Result<()> {
+        let mut breaker = udp::ErrorBreaker::new(udp::FAILING_SOCKET_WINDOW);
+        loop {
+            let (stream, client) = match socket.accept() {
+                Ok(accepted) => {
+                    breaker.success();
+                    accepted
+                }
+                Err(e) => {
+                    error!("Error accepting connections: {}", e);
+                    let backoff = breaker.failure(e)?;
+                    thread::sleep(backoff);
+                    continue;
+                }
+            };
+            let connection = match self.connections.try_take() {
+                Some(connection) => connection,
+                None => {
+                    warning!("Too many connections open, closing one from {}", client);
+                    continue;
+                }
+            };
+            let server = self.to_owned();
+            let listener = listener.to_owned();
+            thread::spawn(move || {
+                let _connection = connection;
+                if let Err(e) = server.serve_connection(stream, client, &listener) {
+                    log!("Closing connection from {}: {}", client, e);
+                }
+            });
+        }
+    }
+
+    // Answer a TCP, TLS or HTTPS client's queries until they close the connection or go quiet.
+    // Over TCP and TLS, each query is answered as it arrives, and the connection's left open until
+    // every query asked on it has been answered.
+    fn serve_connection(
+        &self,
+        stream: net::TcpStream,
+        client: net::SocketAddr,
+        listener: &Arc<Listener>,
+    ) -> Result<()> {
+        // Held for as long as the connection's open, since its buffers are
+        let _reservation = self.budget.reserve(CONNECTION_COST);
+        // Also how long a client gets to finish the TLS handshake
+        stream.set_read_timeout(Some(tcp::IDLE_TIMEOUT))?;
+        stream.set_write_timeout(Some(tcp::IDLE_TIMEOUT))?;
+        match (&self.tls, listener.transport) {
+            (Some(config), Transport::Tls) => {
+                let (reader, writer) = tls::accept(config, stream)?;
+                self.answer_messages(reader, writer, client, listener)
+            }
+            (Some(config), Transport::Https) => {
+                let (reader, writer) = tls::accept(config, stream)?;
+                self.answer_requests(reader, writer, client, listener)
+            }
+            _ => {
+                let writer = stream.try_clone()?;
+                self.answer_messages(stream, writer, client, listener)
+            }
+        }
+    }
+
+    fn answer_messages(
+        &self,
+        mut stream: impl Read,
+        writer: impl Write + Send + 'static,
+        client: net::SocketAddr,
+        listener: &Arc<Listener>,
+    ) -> Result<()> {
+        let (responses, outgoing) = mpsc::channel();
+        thread::spawn(move || send_tcp_responses(writer, outgoing));
+        let pipelined = tcp::Slots::new(tcp::MAX_PIPELINED);
+        loop {
+            // Nothing more is read until the client has a query to spare
+            let slot = pipelined.take();
+            let bytes = match tcp::read_message(&mut stream) {
+                Ok(Some(bytes)) => bytes,
+                Ok(None) => return Ok(()),
+                Err(e) if tcp::timed_out(&e) => {
+                    log!("Closing idle connection from {}", client);
+                    return Ok(());
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let message = Datagram {
+                bytes,
+                addr: client,
+                local: None,
+            };
+            self.handle_datagram(message, listener, &responses, Some(slot))?;
+        }
+    }
+
+    // Answer a DNS over HTTPS client's requests one at a time, since HTTP/1.1 responses have to go
+    // back in the order they were asked for
+    fn answer_requests(
+        &self,
+        reader: impl Read,
+        mut writer: impl Write,
+        client: net::SocketAddr,
+        listener: &Arc<Listener>,
+    ) -> Result<()> {
+        let mut reader = io::BufReader::new(reader);
+        loop {
+            let request = match https::read_request(&mut reader) {
+                Ok(Some(request)) => request,
+                Ok(None) => return Ok(()),
+                Err(e) if tcp::timed_out(&e) => {
+                    log!("Closing idle connection from {}", client);
+                    return Ok(());
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let (status, message) = match https::dns_message(&request) {
+                Ok(bytes) => {
+                    // A channel of its own, so we know when the query's been answered, or dropped
+                    let (responses, answer) = mpsc::channel();
+                    let message = Datagram {
+                        bytes,
+                        addr: client,
+                        local: None,
+                    };
+                    self.handle_datagram(message, listener, &responses, None)?;
+                    drop(responses);
+                    match answer.recv() {
+                        Ok(response) => (https::Status::Ok, response.bytes),
+                        Err(_) => (https::Status::ServiceUnavailable, Vec::new()),
+                    }
+                }
+                Err(status) => {
+                    log!(
+                        "Turning down {} {} from {}",
+                        request.method,
+                        request.target,
+                        client
+                    );
+                    (status, Vec::new())
+                }
+            };
+            https::write_response(&mut writer, status, &message)?;
+            if request.close {
+                return Ok(());
+            }
+        }
+    }
+
+    // What to send in place of `response`, once response rate limiting has had its say: the
+    // response itself, an empty truncated one, or nothing at all
+    fn rate_limit(
+        &self,
+        response: &protocol::DnsPacket,
+        client: net::SocketAddr,
+        listener: &Listener,
+    ) -> Option<protocol::DnsPacket> {
+        let limiter = match (&self.rate_limiter, listener.transport) {
+            (Some(limiter), Transport::Udp) => limiter,
+            _ => return Some(response.to_owned()),
+        };
+        match limiter.check_response(client.ip(), response, Instant::now()) {
+            Verdict::Send => Some(response.to_owned()),
+            Verdict::Slip => {
+                log!(
+                    "Over the response rate limit, truncating response to {}",
+                    client
+                );
+                Some(rate_limit::slipped(response))
+            }
+            Verdict::Drop => {
+                log!(
+                    "Over the response rate limit, dropping response to {}",
+                    client
+                );
+                None
+            }
+        }
+    }
+
+    // Answer one query from a client, on a thread of its own unless we're short on memory. Queries
+    // over TCP, TLS and HTTPS come through here too, as if each message were a datagram; `slot`,
+    // if there is one, is held until the query's been answered.
+    fn handle_datagram(
+        &self,
+        datagram: Datagram,
+        listener: &Arc<Listener>,
+        responses: &mpsc::Sender<Datagram>,
+        slot: Option<tcp::Slot>,
+    ) -> Result<()> {
+        let Datagram {
+            bytes,
+            addr: client,
+            local,
+        } = datagram;
+        if self.stopping.load(Ordering::Relaxed) {
+            log!("Shutting down, dropping query from {}", client);
+            return Ok(());
+        }
+        if !listener.allows(client.ip()) {
+            log!("Refusing query from {} on {}", client, listener.addr);
+            if let Ok(query) = protocol::DnsPacket::from_bytes(&bytes) {
+                let refused = pipeline::error_response(&query, protocol::DnsRCode::Refused);
+                let limit = response_limit(&bytes, listener.transport);
+                respond(responses, &refused, limit, client, local)?;
+            }
+            return Ok(());
+        }
+        if let (Some(limiter), Transport::Udp) = (&self.rate_limiter, listener.transport) {
+            if !limiter.allow_query(client.ip(), Instant::now()) {
+                log!("Over the rate limit, dropping query from {}", client);
+                return Ok(());
+            }
+        }
+        match self.budget.pressure(self.resolver.cache_memory()) {
+            Pressure::Normal => (),
+            // Over a connection, the client has nowhere better to go
+            Pressure::Truncate if listener.transport != Transport::Udp => (),
+            Pressure::Truncate => {
+                log!("Near memory limit, truncating response to {}", client);
+                if let Ok(query) = protocol::DnsPacket::from_bytes(&bytes) {
+                    let response = pipeline::error_response(&query, protocol::DnsRCode::NoError);
+                    let truncated = rate_limit::slipped(&response);
+                    let limit = response_limit(&bytes, listener.transport);
+                    respond(responses, &truncated, limit, client, local)?;
+                }
+                return Ok(());
+            }
+            Pressure::Shed => {
+                // Refusing is cheap enough to do right here; if the query doesn't even parse,
+                // it isn't worth a response
+                warning!("Near memory limit, refusing query from {}", client);
+                if let Ok(query) = protocol::DnsPacket::from_bytes(&bytes) {
+                    let refused = pipeline::error_response(&query, protocol::DnsRCode::Refused);
+                    let limit = response_limit(&bytes, listener.transport);
+                    respond(responses, &refused, limit, client, local)?;
+                }
+                return Ok(());
+            }
+            Pressure::Drop => {
+                warning!("Over memory limit, dropping query from {}", client);
+                return Ok(());
+            }
+        }
+        let reservation = self.budget.reserve(IN_FLIGHT_QUERY_COST);
+        let tracker = self.stats.begin(client);
+        let server = self.to_owned();
+        let listener = listener.to_owned();
+        let responses = responses.to_owned();
         thread::spawn(move || {
-            let response = resolve_query(&buf[0..amt]);
+            // Held until the query's been answered
+            let _reservation = reservation;
+            let _slot = slot;
+            // Everything logged from here on is about this query
+            let _trace = trace::enter(tracker.id(), client);
+            log!("Data received from {}: {} bytes", client, bytes.len());
+            debug::log_packet(&format!("Received from {}", client), &bytes);
+            let mut span = telemetry::span("dns.query", SpanKind::Server);
+            span.attribute("client.address", client.ip().to_string());
+            let response = resolve_query(&bytes, &server, client, &listener, &tracker);
             match response {
                 Ok(response) => {
-                    respond(&socket, &response, client).unwrap();
+                    if let Some(question) = response.questions.first() {
+                        span.question(question);
+                    }
+                    span.attribute("dns.response.code", format!("{:?}", response.flags.rcode));
+                    let response = match server.rate_limit(&response, client, &listener) {
+                        Some(response) => response,
+                        None => return,
+                    };
+                    // Only a TCP client hanging up early stops a response going out
+                    let limit = response_limit(&bytes, listener.transport);
+                    match respond(&responses, &response, limit, client, local) {
+                        Ok(()) => tracker.answered(),
+                        Err(e) => warning!("Couldn't respond to {}: {}", client, e),
+                    }
                 }
                 Err(error) => {
-                    println!("Error processing response! {:?}", error);
+                    span.error(&error.to_string());
+                    log!("Dropping query from {}: {}", client, error);
                 }
             }
         });
+        Ok(())
     }
 }
+
+#[cfg(target_os = "linux")]
+fn recv_transparent(socket: &net::UdpSocket) -> io::Result<Datagram> {
+    tproxy::recv(socket)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn recv_transparent(_socket: &net::UdpSocket) -> io::Result<Datagram> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--tproxy is only supported on Linux",
+    ))
+}
@@ -0,0 +1,167 @@
+// Reloading on SIGHUP (`kill -HUP <pid>`), without closing any listening socket. The flags are
+// read again, along with any --config file they name, and the zone files and root hints they
+// give are loaded afresh. Zones that are no longer configured stop being served.
+//
+// Everything else the flags set, like listeners, upstreams and limits, is fixed once the server's
+// running. If any of it has changed, that gets logged, and the change waits for a restart. The
+// same goes for zones added since --watch-zones and --control started, which those don't know
+// about.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config_file;
+use crate::dns::protocol::{
+    name_from_string, name_to_string, names_equal, DnsResourceRecord, NameKey,
+};
+use crate::dns::resolver::Resolver;
+use crate::dns::trace::{info, warning};
+use crate::dns::ttl::TtlOverride;
+use crate::dns::zone;
+use crate::dns::zone_watch::{self, ZoneFile};
+
+// The part of the server's flags a reload can change
+#[derive(Debug, Default, PartialEq)]
+pub struct Reloadable {
+    pub zone_files: Vec<ZoneFile>,
+    pub root_hints: Option<PathBuf>,
+    // Every other flag, which a reload leaves as it was
+    others: Vec<String>,
+}
+
+impl Reloadable {
+    // Split the reloadable flags out of `args`, once any --config has been expanded
+    pub fn from_args(args: &[String]) -> Result<Reloadable, String> {
+        let mut reloadable = Reloadable::default();
+        let mut default_ttls = HashMap::new();
+        for arg in args {
+            if let Some(zone) = arg.strip_prefix("--zone=") {
+                let (origin, path) = zone.split_once('=').ok_or("Expected --zone=ORIGIN=PATH")?;
+                reloadable.zone_files.push(ZoneFile {
+                    origin: name_from_string(origin)?,
+                    path: PathBuf::from(path),
+                    default_ttl: None,
+                });
+            } else if let Some(rule) = arg.strip_prefix("--zone-default-ttl=") {
+                let rule: TtlOverride = rule.parse()?;
+                default_ttls.insert(NameKey::new(&rule.name), rule.ttl);
+            } else if let Some(path) = arg.strip_prefix("--root-hints=") {
+                reloadable.root_hints = Some(PathBuf::from(path));
+            } else {
+                reloadable.others.push(arg.to_owned());
+            }
+        }
+        // Applied once all the flags are in, since they can come in any order
+        for file in reloadable.zone_files.iter_mut() {
+            file.default_ttl = default_ttls.get(&NameKey::new(&file.origin)).copied();
+        }
+        Ok(reloadable)
+    }
+
+    // Whether `arg` is one of the flags picked out by `from_args`
+    pub fn takes(arg: &str) -> bool {
+        ["--zone=", "--zone-default-ttl=", "--root-hints="]
+            .iter()
+            .any(|flag| arg.starts_with(flag))
+    }
+}
+
+pub fn load_root_hints(path: &Path) -> Result<Vec<DnsResourceRecord>, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Can't read root hints {}: {}", path.display(), e))?;
+    zone::parse_master_file(&[], &text).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+// Reload from the command line `args`, given the server's running with `running`. Nothing changes
+// if the flags or root hints can't be read; a zone that doesn't load keeps its old copy.
+pub fn reload(
+    args: &[String],
+    running: &Reloadable,
+    resolver: &Resolver,
+    bump_serials: bool,
+) -> Result<Reloadable, String> {
+    let reloaded = Reloadable::from_args(&config_file::expand(args)?)?;
+    let hints = match &reloaded.root_hints {
+        Some(path) => Some(load_root_hints(path)?),
+        None => None,
+    };
+    if reloaded.others != running.others
+        || (running.root_hints.is_some() && reloaded.root_hints.is_none())
+    {
+        warning!("Only zones and root hints are reloaded; other changes need a restart");
+    }
+    if let (Some(path), Some(hints)) = (&reloaded.root_hints, hints) {
+        resolver.replace_root_hints(&hints)?;
+        info!("Reloaded root hints from {}", path.display());
+    }
+    for file in &reloaded.zone_files {
+        zone_watch::reload(file, resolver, bump_serials);
+    }
+    for file in &running.zone_files {
+        let kept = reloaded
+            .zone_files
+            .iter()
+            .any(|reloaded| names_equal(&reloaded.origin, &file.origin));
+        if !kept && resolver.remove_zone(&file.origin) {
+            info!("Stopped serving zone {}", name_to_string(&file.origin));
+        }
+    }
+    Ok(reloaded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::dns::config::{ResolverConfig, ResolverOpts};
+
+    #[test]
+    fn zones_come_and_go() {
+        let dir = std::env::temp_dir().join(format!("montague-reload-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let zone_path = dir.join("example.test.zone");
+        fs::write(
+            &zone_path,
+            "@ 300 SOA ns hostmaster 1 2 3 4 5\nwww 300 A 192.0.2.1\n",
+        )
+        .unwrap();
+        let config_path = dir.join("montague.conf");
+        let zone_flag = format!("zone = example.test={}", zone_path.display());
+        fs::write(
+            &config_path,
+            format!("{}\nzone-default-ttl = example.test=60\n", zone_flag),
+        )
+        .unwrap();
+        let args = vec![
+            format!("--config={}", config_path.display()),
+            String::from("--sandbox"),
+        ];
+
+        let resolver = Resolver::new(ResolverConfig::default(), ResolverOpts::default());
+        let running = Reloadable::from_args(&config_file::expand(&args).unwrap()).unwrap();
+        assert_eq!(running.zone_files[0].default_ttl, Some(60));
+        assert!(Reloadable::takes("--root-hints=named.root") && !Reloadable::takes("--sandbox"));
+        // Loads zones the server didn't have
+        let reloaded = reload(&args, &Reloadable::default(), &resolver, false).unwrap();
+        assert_eq!(reloaded, running);
+        assert!(resolver
+            .zone(&name_from_string("example.test").unwrap())
+            .is_some());
+
+        // A config file that doesn't parse changes nothing
+        fs::write(&config_path, "zone example.test\n").unwrap();
+        assert!(reload(&args, &running, &resolver, false).is_err());
+        assert!(resolver
+            .zone(&name_from_string("example.test").unwrap())
+            .is_some());
+
+        fs::write(&config_path, "").unwrap();
+        let reloaded = reload(&args, &running, &resolver, false).unwrap();
+        assert!(reloaded.zone_files.is_empty());
+        assert!(resolver
+            .zone(&name_from_string("example.test").unwrap())
+            .is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
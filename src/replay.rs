@@ -0,0 +1,370 @@
+// `montague replay`: send queries captured from real traffic to a server again, spaced out the way
+// they originally arrived (or faster), to see how the cache and policies hold up against it.
+// Queries come from a --query-log file or from a pcap capture, where every DNS query sent to
+// port 53 is replayed as it was on the wire.
+
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::bench::{query_bytes, Results};
+use super::dns::query_log;
+use super::Result;
+
+const USAGE: &str = "Usage: montague replay SERVER[:PORT] FILE [OPTIONS]
+Sends the queries in FILE, a montague query log (--query-log) or a pcap
+capture, to SERVER with the same timing they were originally received with,
+and reports how it kept up.
+
+  --speed=N    replay N times faster than the original traffic (default 1)
+  --port=PORT  for pcaps, the port queries were sent to (default 53)
+  --timeout=MS how long to wait before counting a query as lost
+               (default 1000)";
+
+pub fn run(args: &[String]) -> Result<()> {
+    let mut positional = Vec::new();
+    let mut speed: f64 = 1.0;
+    let mut port = 53;
+    let mut timeout = Duration::from_millis(1000);
+    for arg in args {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            _ if arg.starts_with("--speed=") => {
+                speed = arg["--speed=".len()..].parse()?;
+            }
+            _ if arg.starts_with("--port=") => {
+                port = arg["--port=".len()..].parse()?;
+            }
+            _ if arg.starts_with("--timeout=") => {
+                timeout = Duration::from_millis(arg["--timeout=".len()..].parse()?);
+            }
+            _ if arg.starts_with("--") => return Err(USAGE.into()),
+            _ => positional.push(arg),
+        }
+    }
+    let (server, path) = match positional[..] {
+        [server, path] => (server, path),
+        _ => return Err(USAGE.into()),
+    };
+    let server = match server.parse::<SocketAddr>() {
+        Ok(server) => server,
+        Err(_) => SocketAddr::new(server.parse::<IpAddr>()?, 53),
+    };
+    if !speed.is_finite() || speed <= 0.0 {
+        return Err("--speed has to be more than 0".into());
+    }
+    let bytes = fs::read(path)?;
+    let queries = if is_pcap(&bytes) {
+        read_pcap(&bytes, port)?
+    } else {
+        read_query_log(&String::from_utf8(bytes)?)?
+    };
+    let span = match queries.last() {
+        Some((offset, _)) => *offset,
+        None => return Err(format!("{} has no queries in it", path).into()),
+    };
+    println!(
+        "Replaying {} queries from {:.1}s of traffic to {} at {}x",
+        queries.len(),
+        span.as_secs_f64(),
+        server,
+        speed
+    );
+    let start = Instant::now();
+    let mut results = replay(server, queries, speed, timeout)?;
+    print!("{}", results.report(start.elapsed()));
+    Ok(())
+}
+
+// Send every query at its time, with a thread collecting the answers as they come in. IDs are
+// handed out in order, so they're only reused once 65536 queries later.
+fn replay(
+    server: SocketAddr,
+    queries: Vec<(Duration, Vec<u8>)>,
+    speed: f64,
+    timeout: Duration,
+) -> Result<Results> {
+    let local = match server {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let socket = UdpSocket::bind((local, 0))?;
+    socket.connect(server)?;
+    socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+    let pending: Arc<Mutex<HashMap<u16, Instant>>> = Arc::new(Mutex::new(HashMap::new()));
+    let done_sending = Arc::new(AtomicBool::new(false));
+    let receiver = {
+        let socket = socket.try_clone()?;
+        let pending = Arc::clone(&pending);
+        let done_sending = Arc::clone(&done_sending);
+        thread::spawn(move || collect_answers(socket, &pending, &done_sending, timeout))
+    };
+
+    let start = Instant::now();
+    let mut sent = 0;
+    for (id, (offset, mut query)) in queries.into_iter().enumerate() {
+        let due = start + offset.div_f64(speed);
+        let now = Instant::now();
+        if due > now {
+            thread::sleep(due - now);
+        }
+        let id = id as u16;
+        query[..2].copy_from_slice(&id.to_be_bytes());
+        pending.lock().unwrap().insert(id, Instant::now());
+        socket.send(&query)?;
+        sent += 1;
+    }
+    done_sending.store(true, Ordering::Relaxed);
+    let mut results = receiver
+        .join()
+        .map_err(|_| "The thread collecting answers panicked")?;
+    results.sent = sent;
+    results.lost += pending.lock().unwrap().len();
+    Ok(results)
+}
+
+fn collect_answers(
+    socket: UdpSocket,
+    pending: &Mutex<HashMap<u16, Instant>>,
+    done_sending: &AtomicBool,
+    timeout: Duration,
+) -> Results {
+    let mut results = Results::default();
+    let mut buf = [0u8; 65535];
+    let mut finished_at = None;
+    loop {
+        // Once everything's been sent, wait out the timeout for the stragglers
+        if done_sending.load(Ordering::Relaxed) {
+            let finished = *finished_at.get_or_insert_with(Instant::now);
+            if pending.lock().unwrap().is_empty() || finished.elapsed() >= timeout {
+                return results;
+            }
+        }
+        let size = match socket.recv(&mut buf) {
+            Ok(size) if size >= 12 => size,
+            _ => continue,
+        };
+        let id = u16::from_be_bytes([buf[0], buf[1]]);
+        let sent = match pending.lock().unwrap().remove(&id) {
+            Some(sent) => sent,
+            None => continue,
+        };
+        let latency = sent.elapsed();
+        if latency > timeout {
+            results.lost += 1;
+        } else {
+            results.answered(latency, &buf[..size]);
+        }
+    }
+}
+
+// Queries from a query log, each with how long after the first one it arrived. Queries answered
+// on different threads can be logged slightly out of order, so they're sorted back into it.
+fn read_query_log(text: &str) -> Result<Vec<(Duration, Vec<u8>)>> {
+    let mut logged = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        if !line.trim().is_empty() {
+            logged.push(
+                query_log::parse_line(line).map_err(|e| format!("line {}: {}", number + 1, e))?,
+            );
+        }
+    }
+    logged.sort_by_key(|query| query.time);
+    let first = logged.first().map(|query| query.time).unwrap_or_default();
+    Ok(logged
+        .into_iter()
+        .map(|query| (query.time - first, query_bytes(query.qname, query.qtype)))
+        .collect())
+}
+
+// Classic libpcap files start with a magic number giving their byte order and whether
+// timestamps are in microseconds or nanoseconds
+const PCAP_MICROS: u32 = 0xa1b2_c3d4;
+const PCAP_NANOS: u32 = 0xa1b2_3c4d;
+
+fn is_pcap(bytes: &[u8]) -> bool {
+    bytes.len() >= 24
+        && [PCAP_MICROS, PCAP_NANOS]
+            .iter()
+            .any(|magic| bytes[..4] == magic.to_le_bytes() || bytes[..4] == magic.to_be_bytes())
+}
+
+// The DNS queries to `port` in a pcap, with when each was captured relative to the first. Only
+// UDP is looked at, and fragmented datagrams are skipped.
+fn read_pcap(bytes: &[u8], port: u16) -> Result<Vec<(Duration, Vec<u8>)>> {
+    let little_endian =
+        bytes[..4] == PCAP_MICROS.to_le_bytes() || bytes[..4] == PCAP_NANOS.to_le_bytes();
+    let nanos = bytes[..4] == PCAP_NANOS.to_le_bytes() || bytes[..4] == PCAP_NANOS.to_be_bytes();
+    let u32_at = |pos: usize| {
+        let field = [bytes[pos], bytes[pos + 1], bytes[pos + 2], bytes[pos + 3]];
+        if little_endian {
+            u32::from_le_bytes(field)
+        } else {
+            u32::from_be_bytes(field)
+        }
+    };
+    let link_type = u32_at(20);
+    if !matches!(link_type, 0 | 1 | 12 | 101 | 113 | 228 | 229 | 276) {
+        return Err(format!("Captures with link type {} aren't supported", link_type).into());
+    }
+    let mut queries = Vec::new();
+    let mut first = None;
+    let mut pos = 24;
+    while pos + 16 <= bytes.len() {
+        let fraction = u64::from(u32_at(pos + 4));
+        let time = Duration::from_secs(u64::from(u32_at(pos)))
+            + Duration::from_nanos(if nanos { fraction } else { fraction * 1000 });
+        let length = u32_at(pos + 8) as usize;
+        let frame = bytes
+            .get(pos + 16..pos + 16 + length)
+            .ok_or("The capture ends partway through a packet")?;
+        pos += 16 + length;
+        let payload = match link_payload(link_type, frame, little_endian)
+            .and_then(udp_payload)
+            .filter(|(dest, _)| *dest == port)
+        {
+            Some((_, payload)) => payload,
+            None => continue,
+        };
+        // Only queries, not the responses going the other way
+        if payload.len() < 12 || payload[2] & 0x80 != 0 {
+            continue;
+        }
+        let first = *first.get_or_insert(time);
+        queries.push((time.saturating_sub(first), payload.to_vec()));
+    }
+    Ok(queries)
+}
+
+// The IP packet in a captured frame, for the link types captures of DNS traffic usually have
+fn link_payload(link_type: u32, frame: &[u8], little_endian: bool) -> Option<&[u8]> {
+    match link_type {
+        // BSD loopback, with the address family in the capturing machine's byte order
+        0 => {
+            let family = frame.get(..4)?;
+            let family = if little_endian {
+                u32::from_le_bytes([family[0], family[1], family[2], family[3]])
+            } else {
+                u32::from_be_bytes([family[0], family[1], family[2], family[3]])
+            };
+            match family {
+                2 | 24 | 28 | 30 => frame.get(4..),
+                _ => None,
+            }
+        }
+        // Ethernet, possibly with a VLAN tag
+        1 => {
+            let mut header = 14;
+            let mut ether_type = u16::from_be_bytes([*frame.get(12)?, *frame.get(13)?]);
+            if ether_type == 0x8100 {
+                ether_type = u16::from_be_bytes([*frame.get(16)?, *frame.get(17)?]);
+                header += 4;
+            }
+            match ether_type {
+                0x0800 | 0x86dd => frame.get(header..),
+                _ => None,
+            }
+        }
+        // Raw IP, under its several numbers
+        12 | 101 | 228 | 229 => Some(frame),
+        // Linux cooked captures (`tcpdump -i any`), versions 1 and 2
+        113 => match u16::from_be_bytes([*frame.get(14)?, *frame.get(15)?]) {
+            0x0800 | 0x86dd => frame.get(16..),
+            _ => None,
+        },
+        276 => match u16::from_be_bytes([*frame.first()?, *frame.get(1)?]) {
+            0x0800 | 0x86dd => frame.get(20..),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// The destination port and payload of a UDP datagram in an IP packet
+fn udp_payload(packet: &[u8]) -> Option<(u16, &[u8])> {
+    let udp = match packet.first()? >> 4 {
+        4 => {
+            let header = usize::from(packet[0] & 0xf) * 4;
+            let fragment = u16::from_be_bytes([*packet.get(6)?, *packet.get(7)?]);
+            // Any fragment, first or not, has more fragments or a nonzero offset
+            if *packet.get(9)? != 17 || fragment & 0x3fff != 0 {
+                return None;
+            }
+            packet.get(header..)?
+        }
+        6 if *packet.get(6)? == 17 => packet.get(40..)?,
+        _ => return None,
+    };
+    let dest = u16::from_be_bytes([*udp.get(2)?, *udp.get(3)?]);
+    let length = usize::from(u16::from_be_bytes([*udp.get(4)?, *udp.get(5)?]));
+    Some((dest, udp.get(8..length.max(8))?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::dns::protocol::{DnsPacket, DnsRRType};
+
+    // A little-endian, microsecond pcap of Ethernet frames, each holding `payload` in a UDP
+    // datagram to `port`
+    fn pcap(packets: &[(u32, u32, u16, &[u8])]) -> Vec<u8> {
+        let mut bytes = PCAP_MICROS.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&[2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0, 0]);
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        for (secs, micros, port, payload) in packets {
+            let mut frame = vec![0; 12];
+            frame.extend_from_slice(&[0x08, 0x00]);
+            let total = 20 + 8 + payload.len() as u16;
+            frame.extend_from_slice(&[0x45, 0, (total >> 8) as u8, total as u8, 0, 0, 0, 0]);
+            frame.extend_from_slice(&[64, 17, 0, 0, 192, 0, 2, 1, 192, 0, 2, 53]);
+            frame.extend_from_slice(&40000u16.to_be_bytes());
+            frame.extend_from_slice(&port.to_be_bytes());
+            frame.extend_from_slice(&(8 + payload.len() as u16).to_be_bytes());
+            frame.extend_from_slice(&[0, 0]);
+            frame.extend_from_slice(payload);
+            for field in &[*secs, *micros, frame.len() as u32, frame.len() as u32] {
+                bytes.extend_from_slice(&field.to_le_bytes());
+            }
+            bytes.extend_from_slice(&frame);
+        }
+        bytes
+    }
+
+    #[test]
+    fn queries_come_out_of_captures_and_logs() {
+        let query = query_bytes(
+            vec![String::from("example"), String::from("com")],
+            DnsRRType::MX,
+        );
+        let mut response = query.to_owned();
+        response[2] |= 0x80;
+        let capture = pcap(&[
+            (100, 500_000, 53, &query),
+            (100, 900_000, 5353, &query),
+            (101, 0, 40000, &response),
+            (102, 0, 53, &query),
+        ]);
+        assert!(is_pcap(&capture));
+        let queries = read_pcap(&capture, 53).unwrap();
+        assert_eq!(queries.len(), 2);
+        assert_eq!(queries[0].0, Duration::from_secs(0));
+        assert_eq!(queries[1].0, Duration::from_millis(1500));
+        let replayed = DnsPacket::from_bytes(&queries[1].1).unwrap();
+        assert_eq!(replayed.questions[0].qtype, DnsRRType::MX);
+
+        let log = "1760745600.250000 192.0.2.10 example.com. IN A\n\
+                   1760745601.000000 192.0.2.11 example.org. IN AAAA\n";
+        assert!(!is_pcap(log.as_bytes()));
+        let queries = read_query_log(log).unwrap();
+        assert_eq!(queries.len(), 2);
+        assert_eq!(queries[1].0, Duration::from_millis(750));
+    }
+}
@@ -0,0 +1,194 @@
+// Running as a Windows service: `montague service install [FLAGS]` registers the service (with
+// the given server flags), `montague service uninstall` removes it, and the service control
+// manager starts it as `montague service run [FLAGS]`. Services have no console, so lifecycle
+// events and errors go to the Application event log.
+
+use std::ffi::OsString;
+use std::fs;
+use std::iter;
+use std::path::PathBuf;
+use std::ptr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use windows_service::service::{
+    ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+    ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+use windows_service::{define_windows_service, service_dispatcher};
+use windows_sys::Win32::System::EventLog::{
+    DeregisterEventSource, RegisterEventSourceW, ReportEventW, EVENTLOG_ERROR_TYPE,
+    EVENTLOG_INFORMATION_TYPE,
+};
+
+use super::Result;
+
+const SERVICE_NAME: &str = "montague";
+const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+
+const USAGE: &str = "Usage: montague service install [SERVER FLAGS] | uninstall | run";
+
+pub fn run(args: &[String]) -> Result<()> {
+    match args.first().map(String::as_str) {
+        Some("install") => install(&args[1..]),
+        Some("uninstall") => uninstall(),
+        // Blocks until the service is stopped
+        Some("run") => Ok(service_dispatcher::start(SERVICE_NAME, ffi_service_main)?),
+        _ => Err(USAGE.into()),
+    }
+}
+
+// Where a service keeps its files: %ProgramData%\montague. The service runs with this as its
+// working directory, so relative paths in its flags resolve here.
+pub fn config_dir() -> PathBuf {
+    let program_data =
+        std::env::var_os("ProgramData").unwrap_or_else(|| OsString::from(r"C:\ProgramData"));
+    PathBuf::from(program_data).join("montague")
+}
+
+fn install(server_args: &[String]) -> Result<()> {
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )?;
+    let launch_arguments = ["service", "run"]
+        .iter()
+        .map(OsString::from)
+        .chain(server_args.iter().map(OsString::from))
+        .collect();
+    let info = ServiceInfo {
+        name: OsString::from(SERVICE_NAME),
+        display_name: OsString::from("Montague DNS resolver"),
+        service_type: SERVICE_TYPE,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: std::env::current_exe()?,
+        launch_arguments,
+        dependencies: vec![],
+        // Run as LocalSystem
+        account_name: None,
+        account_password: None,
+    };
+    let service = manager.create_service(&info, ServiceAccess::CHANGE_CONFIG)?;
+    service.set_description("Recursive DNS resolver")?;
+    fs::create_dir_all(config_dir())?;
+    println!(
+        "Installed service {}; files go in {}",
+        SERVICE_NAME,
+        config_dir().display()
+    );
+    Ok(())
+}
+
+fn uninstall() -> Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    let service = manager.open_service(
+        SERVICE_NAME,
+        ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+    )?;
+    if service.query_status()?.current_state != ServiceState::Stopped {
+        service.stop()?;
+    }
+    // Windows finishes deleting it once every handle to it is closed
+    service.delete()?;
+    println!("Uninstalled service {}", SERVICE_NAME);
+    Ok(())
+}
+
+define_windows_service!(ffi_service_main, service_main);
+
+fn service_main(_arguments: Vec<OsString>) {
+    if let Err(e) = run_service() {
+        log_event(EVENTLOG_ERROR_TYPE, &format!("Service failed: {}", e));
+    }
+}
+
+fn run_service() -> Result<()> {
+    // Either the service control manager asking us to stop, or the server giving up
+    let (stop_tx, stop_rx) = mpsc::channel::<Option<String>>();
+    let control_tx = stop_tx.clone();
+    let status_handle =
+        service_control_handler::register(SERVICE_NAME, move |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                let _ = control_tx.send(None);
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        })?;
+    let set_state = |state: ServiceState, exit_code: u32| {
+        status_handle.set_service_status(ServiceStatus {
+            service_type: SERVICE_TYPE,
+            current_state: state,
+            controls_accepted: if state == ServiceState::Running {
+                ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN
+            } else {
+                ServiceControlAccept::empty()
+            },
+            exit_code: ServiceExitCode::ServiceSpecific(exit_code),
+            checkpoint: 0,
+            wait_hint: Duration::default(),
+            process_id: None,
+        })
+    };
+
+    fs::create_dir_all(config_dir())?;
+    std::env::set_current_dir(config_dir())?;
+    // Our own launch arguments are `service run [FLAGS]`
+    let server_args: Vec<String> = std::env::args().skip(3).collect();
+    thread::spawn(move || {
+        if let Err(e) = super::run_server(&server_args) {
+            let _ = stop_tx.send(Some(e.to_string()));
+        }
+    });
+    set_state(ServiceState::Running, 0)?;
+    log_event(EVENTLOG_INFORMATION_TYPE, "Service started");
+
+    // The server thread is blocked in recv, so there's no stopping it cleanly; once we report
+    // Stopped and return, the process exits and takes it with it.
+    match stop_rx.recv() {
+        Ok(Some(error)) => {
+            log_event(EVENTLOG_ERROR_TYPE, &format!("Server stopped: {}", error));
+            set_state(ServiceState::Stopped, 1)?;
+        }
+        _ => {
+            log_event(EVENTLOG_INFORMATION_TYPE, "Service stopped");
+            set_state(ServiceState::Stopped, 0)?;
+        }
+    }
+    Ok(())
+}
+
+// Write a message to the Application event log. Without a registered message file Event Viewer
+// prefixes it with a note that the description can't be found, but the message is all there.
+fn log_event(event_type: u16, message: &str) {
+    let source = wide(SERVICE_NAME);
+    let message = wide(message);
+    let strings = [message.as_ptr()];
+    unsafe {
+        let handle = RegisterEventSourceW(ptr::null(), source.as_ptr());
+        if handle == 0 {
+            return;
+        }
+        ReportEventW(
+            handle,
+            event_type,
+            0,
+            0,
+            ptr::null_mut(),
+            strings.len() as u16,
+            0,
+            strings.as_ptr(),
+            ptr::null(),
+        );
+        DeregisterEventSource(handle);
+    }
+}
+
+// A null-terminated UTF-16 string, as the W functions want
+fn wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(iter::once(0)).collect()
+}
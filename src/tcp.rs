@@ -0,0 +1,154 @@
+// DNS over TCP (RFC 7766). Each message is preceded by its length as two bytes, and a client can
+// send as many queries as it likes down one connection, without waiting for the answers; they're
+// sent back as they're finished, which needn't be in the order they were asked.
+
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+// How long a connection can sit without a query before we close it. RFC 7766 section 6.2.3
+// suggests seconds rather than minutes, since every open connection costs us a thread.
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Connections open at once, across every TCP, TLS and HTTPS listener; past this, new ones are
+// closed as soon as they're accepted (RFC 7766 section 6.2.2)
+pub const MAX_CONNECTIONS: usize = 1024;
+
+// Queries on one connection being answered at once. Once a client has this many outstanding we
+// stop reading from its connection until one's answered, so a single client can't start
+// unlimited resolutions.
+pub const MAX_PIPELINED: usize = 16;
+
+// A count of things in use out of a fixed number, like connections or the queries on one. Each
+// slot taken is given back when the returned Slot is dropped.
+#[derive(Debug)]
+pub struct Slots {
+    max: usize,
+    taken: Mutex<usize>,
+    freed: Condvar,
+}
+
+pub struct Slot {
+    slots: Arc<Slots>,
+}
+
+impl Slots {
+    pub fn new(max: usize) -> Arc<Slots> {
+        Arc::new(Slots {
+            max,
+            taken: Mutex::new(0),
+            freed: Condvar::new(),
+        })
+    }
+
+    // A slot if there's one free right now
+    pub fn try_take(self: &Arc<Self>) -> Option<Slot> {
+        let mut taken = self.taken.lock().unwrap();
+        if *taken >= self.max {
+            return None;
+        }
+        *taken += 1;
+        Some(Slot {
+            slots: self.clone(),
+        })
+    }
+
+    // A slot, waiting for one to be given back if they're all taken
+    pub fn take(self: &Arc<Self>) -> Slot {
+        let mut taken = self.taken.lock().unwrap();
+        while *taken >= self.max {
+            taken = self.freed.wait(taken).unwrap();
+        }
+        *taken += 1;
+        Slot {
+            slots: self.clone(),
+        }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        *self.slots.taken.lock().unwrap() -= 1;
+        self.slots.freed.notify_one();
+    }
+}
+
+// The next message on a connection, or None once the client has closed it
+pub fn read_message(stream: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut length = [0u8; 2];
+    // A connection closed between messages is how clients say they're done
+    match stream.read(&mut length[..1])? {
+        0 => return Ok(None),
+        _ => stream.read_exact(&mut length[1..])?,
+    }
+    let mut message = vec![0u8; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut message)?;
+    Ok(Some(message))
+}
+
+// Whether a read failed because the client went quiet for longer than IDLE_TIMEOUT
+pub fn timed_out(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+pub fn write_message(stream: &mut impl Write, message: &[u8]) -> io::Result<()> {
+    if message.len() > u16::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("a {} byte message is too long for TCP", message.len()),
+        ));
+    }
+    // Written in one go, so the length and message don't go out as separate segments
+    let mut framed = (message.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(message);
+    stream.write_all(&framed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    #[test]
+    fn messages_are_length_prefixed() {
+        let mut wire = Vec::new();
+        write_message(&mut wire, b"first").unwrap();
+        write_message(&mut wire, b"").unwrap();
+        write_message(&mut wire, b"second").unwrap();
+        assert_eq!(&wire[..7], b"\x00\x05first");
+        assert!(write_message(&mut wire, &vec![0; 70_000]).is_err());
+
+        let mut stream = Cursor::new(wire);
+        assert_eq!(read_message(&mut stream).unwrap().unwrap(), b"first");
+        assert_eq!(read_message(&mut stream).unwrap().unwrap(), b"");
+        assert_eq!(read_message(&mut stream).unwrap().unwrap(), b"second");
+        assert_eq!(read_message(&mut stream).unwrap(), None);
+        // Closing partway through a message is an error, not the end of the conversation
+        let mut cut_off = Cursor::new(b"\x00\x05fir".to_vec());
+        assert!(read_message(&mut cut_off).is_err());
+    }
+
+    #[test]
+    fn slots_run_out_and_come_back() {
+        let slots = Slots::new(2);
+        let first = slots.try_take().unwrap();
+        let second = slots.take();
+        assert!(slots.try_take().is_none());
+        drop(first);
+        let third = slots.try_take().unwrap();
+
+        // Waiting for a slot picks up the next one given back
+        let waiting = {
+            let slots = slots.clone();
+            std::thread::spawn(move || drop(slots.take()))
+        };
+        drop(second);
+        waiting.join().unwrap();
+        drop(third);
+        assert_eq!(*slots.taken.lock().unwrap(), 0);
+    }
+}
@@ -0,0 +1,200 @@
+// DNS over TLS (RFC 7858) for our own clients, on listeners given as --listen=ADDR:853/tls. Once
+// the handshake is done, a connection is just a TCP connection inside TLS: the same two-byte
+// length framing, and the same freedom to send queries without waiting for answers.
+//
+// That last part is why a connection isn't a rustls StreamOwned: answers go out from a thread of
+// their own while the next query is being read, so the reading and writing halves each get a clone
+// of the socket, and only share the TLS state itself, which is locked just long enough to move
+// bytes in or out of it.
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ServerConfig, ServerConnection};
+
+// TLS as configured with --tls-cert and --tls-key: a PEM certificate chain, leaf first, and the
+// PEM private key that goes with it
+pub fn server_config(cert_path: &Path, key_path: &Path) -> Result<Arc<ServerConfig>, String> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("{}: {}", cert_path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("{}: no certificates", cert_path.display()));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| format!("{}: {}", key_path.display(), e))?;
+    let config =
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+            .map_err(|e| format!("{}: {}", key_path.display(), e))?;
+    Ok(Arc::new(config))
+}
+
+// Do the handshake on a client's connection, then split it into the half queries are read from
+// and the half answers are written to
+pub fn accept(
+    config: &Arc<ServerConfig>,
+    mut socket: TcpStream,
+) -> io::Result<(TlsReader, TlsWriter)> {
+    let mut connection = ServerConnection::new(config.to_owned()).map_err(tls_error)?;
+    while connection.is_handshaking() {
+        connection.complete_io(&mut socket)?;
+    }
+    let connection = Arc::new(Mutex::new(connection));
+    let reader = TlsReader {
+        connection: connection.to_owned(),
+        socket: socket.try_clone()?,
+        incoming: vec![0; 16 * 1024],
+    };
+    let writer = TlsWriter { connection, socket };
+    Ok((reader, writer))
+}
+
+fn tls_error(e: rustls::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+pub struct TlsReader {
+    connection: Arc<Mutex<ServerConnection>>,
+    socket: TcpStream,
+    incoming: Vec<u8>,
+}
+
+impl Read for TlsReader {
+    // Reads what's already been decrypted, and otherwise waits on the socket (without holding the
+    // lock, so answers can go out meanwhile) for more
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            {
+                let mut connection = self.connection.lock().unwrap();
+                match connection.reader().read(buf) {
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                    result => return result,
+                }
+            }
+            let read = self.socket.read(&mut self.incoming)?;
+            let mut connection = self.connection.lock().unwrap();
+            let mut incoming = &self.incoming[..read];
+            // Reading nothing tells rustls the client has gone, which the next read reports
+            loop {
+                connection.read_tls(&mut incoming)?;
+                connection.process_new_packets().map_err(tls_error)?;
+                if incoming.is_empty() {
+                    break;
+                }
+            }
+            // Like alerts, or new session tickets
+            while connection.wants_write() {
+                connection.write_tls(&mut self.socket)?;
+            }
+        }
+    }
+}
+
+pub struct TlsWriter {
+    connection: Arc<Mutex<ServerConnection>>,
+    socket: TcpStream,
+}
+
+impl Write for TlsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut connection = self.connection.lock().unwrap();
+        let written = connection.writer().write(buf)?;
+        while connection.wants_write() {
+            connection.write_tls(&mut self.socket)?;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        while connection.wants_write() {
+            connection.write_tls(&mut self.socket)?;
+        }
+        self.socket.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::convert::TryFrom;
+    use std::fs;
+    use std::net::TcpListener;
+    use std::thread;
+
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+    use crate::tcp;
+
+    fn pem(label: &str, der: &[u8]) -> String {
+        let encoded = base64::encode(der);
+        let lines: Vec<&str> = encoded
+            .as_bytes()
+            .chunks(64)
+            .map(|line| std::str::from_utf8(line).unwrap())
+            .collect();
+        format!(
+            "-----BEGIN {}-----\n{}\n-----END {}-----\n",
+            label,
+            lines.join("\n"),
+            label
+        )
+    }
+
+    #[test]
+    fn queries_and_answers_cross_in_tls() {
+        let certified = rcgen::generate_simple_self_signed(vec![String::from("dns.test")]).unwrap();
+        let dir = std::env::temp_dir();
+        let cert_path = dir.join(format!("montague-{}.crt", std::process::id()));
+        let key_path = dir.join(format!("montague-{}.key", std::process::id()));
+        fs::write(&cert_path, pem("CERTIFICATE", certified.cert.der())).unwrap();
+        fs::write(
+            &key_path,
+            pem("PRIVATE KEY", &certified.signing_key.serialize_der()),
+        )
+        .unwrap();
+        let config = server_config(&cert_path, &key_path).unwrap();
+        assert!(server_config(&key_path, &key_path).is_err());
+        fs::remove_file(&cert_path).unwrap();
+        fs::remove_file(&key_path).unwrap();
+
+        // Echoes each message back from the writing half, as answers are sent
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            let (mut reader, mut writer) = accept(&config, socket).unwrap();
+            while let Some(message) = tcp::read_message(&mut reader).unwrap() {
+                tcp::write_message(&mut writer, &message).unwrap();
+            }
+        });
+
+        let mut roots = RootCertStore::empty();
+        roots.add(certified.cert.der().to_owned()).unwrap();
+        let client_config =
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+        let connection = ClientConnection::new(
+            Arc::new(client_config),
+            ServerName::try_from("dns.test").unwrap(),
+        )
+        .unwrap();
+        let mut stream = StreamOwned::new(connection, TcpStream::connect(addr).unwrap());
+        // Both go out before either answer is read
+        tcp::write_message(&mut stream, b"first").unwrap();
+        tcp::write_message(&mut stream, b"second").unwrap();
+        assert_eq!(tcp::read_message(&mut stream).unwrap().unwrap(), b"first");
+        assert_eq!(tcp::read_message(&mut stream).unwrap().unwrap(), b"second");
+    }
+}
@@ -0,0 +1,154 @@
+// Transparent proxying (--tproxy=ADDR): accept DNS traffic redirected to us by an iptables or
+// nftables TPROXY rule, and answer it as if we were the server the client was actually talking
+// to. This is for gateways that intercept all port 53 traffic passing through them, e.g.
+//
+//     iptables -t mangle -A PREROUTING -p udp --dport 53 -j TPROXY --on-port 5353 \
+//         --tproxy-mark 1
+//     ip rule add fwmark 1 lookup 100
+//     ip route add local 0.0.0.0/0 dev lo table 100
+//
+// The kernel tells us where each datagram was originally headed, and we send the response from
+// that address. Both halves need IP_TRANSPARENT, and so CAP_NET_ADMIN. IPv4 only for now.
+
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::os::unix::io::AsRawFd;
+
+use socket2::{Domain, Socket, Type};
+
+use super::udp::{Datagram, MAX_DATAGRAM};
+
+fn set_option(socket: &Socket, level: libc::c_int, option: libc::c_int) -> io::Result<()> {
+    let enable: libc::c_int = 1;
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            option,
+            &enable as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn transparent_socket() -> io::Result<Socket> {
+    let socket = Socket::new(Domain::ipv4(), Type::dgram(), None)?;
+    set_option(&socket, libc::SOL_IP, libc::IP_TRANSPARENT)?;
+    Ok(socket)
+}
+
+// The socket TPROXY rules deliver to
+pub fn bind(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = transparent_socket()?;
+    // Ask for each datagram's original destination
+    set_option(&socket, libc::SOL_IP, libc::IP_RECVORIGDSTADDR)?;
+    // Clients talking to us directly rather than being redirected get answers from this same
+    // address, which needs a second socket bound to it
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket.into_udp_socket())
+}
+
+// Receive one datagram, with `local` set to the address the client sent it to
+pub fn recv(socket: &UdpSocket) -> io::Result<Datagram> {
+    let mut buf = [0u8; MAX_DATAGRAM];
+    let mut client: libc::sockaddr_in = unsafe { mem::zeroed() };
+    // Plenty of room for the one control message we asked for
+    let mut control = [0u8; 64];
+    let mut iovec = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut header: libc::msghdr = unsafe { mem::zeroed() };
+    header.msg_name = &mut client as *mut libc::sockaddr_in as *mut libc::c_void;
+    header.msg_namelen = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+    header.msg_iov = &mut iovec;
+    header.msg_iovlen = 1;
+    header.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    header.msg_controllen = control.len();
+
+    let amt = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut header, 0) };
+    if amt < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut local = None;
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&header);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_IP && (*cmsg).cmsg_type == libc::IP_ORIGDSTADDR {
+                let addr = *(libc::CMSG_DATA(cmsg) as *const libc::sockaddr_in);
+                local = Some(to_socket_addr(&addr));
+            }
+            cmsg = libc::CMSG_NXTHDR(&header, cmsg);
+        }
+    }
+    if local.is_none() {
+        return Err(io::Error::other(
+            "datagram arrived without its original destination",
+        ));
+    }
+    Ok(Datagram {
+        bytes: buf[..amt as usize].to_vec(),
+        addr: to_socket_addr(&client),
+        local,
+    })
+}
+
+// Send a datagram from `from`, an address that isn't ours. Binding a transparent socket to it
+// is what lets us do that.
+pub fn send_from(from: SocketAddr, datagram: &Datagram) -> io::Result<()> {
+    let socket = transparent_socket()?;
+    // Other responses from the same address may be in flight on their own sockets
+    socket.set_reuse_address(true)?;
+    socket.bind(&from.into())?;
+    socket.send_to(&datagram.bytes, &datagram.addr.into())?;
+    Ok(())
+}
+
+fn to_socket_addr(addr: &libc::sockaddr_in) -> SocketAddr {
+    SocketAddr::V4(SocketAddrV4::new(
+        Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+        u16::from_be(addr.sin_port),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_original_destination() {
+        // Without CAP_NET_ADMIN we can't make transparent sockets at all, so there's nothing to
+        // test
+        let socket = match bind("127.0.0.1:0".parse().unwrap()) {
+            Ok(socket) => socket,
+            Err(e) if e.raw_os_error() == Some(libc::EPERM) => return,
+            Err(e) => panic!("{}", e),
+        };
+        let server = socket.local_addr().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(b"hello", server).unwrap();
+
+        // No TPROXY rule redirected this, so the original destination is just us
+        let datagram = recv(&socket).unwrap();
+        assert_eq!(datagram.bytes, b"hello".to_vec());
+        assert_eq!(datagram.addr, client.local_addr().unwrap());
+        assert_eq!(datagram.local, Some(server));
+
+        let reply = Datagram {
+            bytes: b"goodbye".to_vec(),
+            addr: datagram.addr,
+            local: None,
+        };
+        send_from(server, &reply).unwrap();
+        let mut buf = [0; 16];
+        let (amt, from) = client.recv_from(&mut buf).unwrap();
+        assert_eq!((&buf[..amt], from), (&b"goodbye"[..], server));
+    }
+}
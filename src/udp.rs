@@ -0,0 +1,330 @@
+// Batched UDP I/O for the server's hot loop. On Linux, recvmmsg and sendmmsg move up to
+// BATCH_SIZE datagrams per syscall, which matters a lot once we're handling many queries a
+// second; elsewhere these fall back to a datagram at a time.
+
+use std::cmp;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+// Most datagrams moved in one call
+pub const BATCH_SIZE: usize = 32;
+// Queries bigger than an Ethernet MTU aren't worth accepting over UDP
+pub const MAX_DATAGRAM: usize = 1500;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Datagram {
+    pub bytes: Vec<u8>,
+    // Where it came from, or where it's going
+    pub addr: SocketAddr,
+    // Our end: the address it was sent to, or should be sent from. Only set when transparent
+    // proxying, where that isn't the address of the socket it came in on.
+    pub local: Option<SocketAddr>,
+}
+
+// Receive buffers, kept between calls so the hot loop isn't allocating them every time
+pub struct BatchReceiver {
+    buffers: Vec<[u8; MAX_DATAGRAM]>,
+}
+
+impl BatchReceiver {
+    pub fn new() -> BatchReceiver {
+        BatchReceiver {
+            buffers: vec![[0; MAX_DATAGRAM]; BATCH_SIZE],
+        }
+    }
+
+    // Block until at least one datagram arrives, then return it along with any others that are
+    // already waiting
+    #[cfg(target_os = "linux")]
+    pub fn recv(&mut self, socket: &UdpSocket) -> io::Result<Vec<Datagram>> {
+        use std::mem;
+        use std::os::unix::io::AsRawFd;
+        use std::ptr;
+
+        use socket2::SockAddr;
+
+        let mut addrs: Vec<libc::sockaddr_storage> = vec![unsafe { mem::zeroed() }; BATCH_SIZE];
+        let mut iovecs: Vec<libc::iovec> = self
+            .buffers
+            .iter_mut()
+            .map(|buffer| libc::iovec {
+                iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buffer.len(),
+            })
+            .collect();
+        let mut headers: Vec<libc::mmsghdr> = iovecs
+            .iter_mut()
+            .zip(addrs.iter_mut())
+            .map(|(iovec, addr)| {
+                let mut header: libc::mmsghdr = unsafe { mem::zeroed() };
+                header.msg_hdr.msg_name = addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+                header.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as u32;
+                header.msg_hdr.msg_iov = iovec;
+                header.msg_hdr.msg_iovlen = 1;
+                header
+            })
+            .collect();
+
+        // MSG_WAITFORONE: block for the first datagram, but not for the rest of the batch
+        let count = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                headers.as_mut_ptr(),
+                BATCH_SIZE as u32,
+                libc::MSG_WAITFORONE,
+                ptr::null_mut(),
+            )
+        };
+        if count < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut datagrams = Vec::with_capacity(count as usize);
+        for (i, header) in headers.iter().take(count as usize).enumerate() {
+            let addr = unsafe {
+                SockAddr::from_raw_parts(
+                    &addrs[i] as *const libc::sockaddr_storage as *const libc::sockaddr,
+                    header.msg_hdr.msg_namelen,
+                )
+            };
+            // Anything that isn't IPv4 or IPv6 can't be a client of ours
+            if let Some(addr) = addr.as_std() {
+                datagrams.push(Datagram {
+                    bytes: self.buffers[i][..header.msg_len as usize].to_vec(),
+                    addr,
+                    local: None,
+                });
+            }
+        }
+        Ok(datagrams)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn recv(&mut self, socket: &UdpSocket) -> io::Result<Vec<Datagram>> {
+        let (amt, addr) = socket.recv_from(&mut self.buffers[0])?;
+        Ok(vec![Datagram {
+            bytes: self.buffers[0][..amt].to_vec(),
+            addr,
+            local: None,
+        }])
+    }
+}
+
+// How long receives can fail without a single success before we give up on the socket
+pub const FAILING_SOCKET_WINDOW: Duration = Duration::from_secs(30);
+// Longest we'll wait between receives while they're failing
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+// Errors that are about one datagram or one moment rather than the socket. The classic is a
+// client's ICMP port unreachable, which some platforms report on the next receive as a
+// connection reset.
+pub fn is_transient(error: &io::Error) -> bool {
+    match error.kind() {
+        io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::Interrupted
+        | io::ErrorKind::WouldBlock
+        | io::ErrorKind::TimedOut => true,
+        // Out of buffer space for now; the kernel will have some again shortly
+        #[cfg(unix)]
+        _ if error.raw_os_error() == Some(libc::ENOBUFS) => true,
+        _ => false,
+    }
+}
+
+// Decides whether the receive loop keeps going after an error. Transient errors are shrugged
+// off; anything else backs off a little more each time, and only once receives have failed
+// for FAILING_SOCKET_WINDOW without a success in between is the socket written off.
+pub struct ErrorBreaker {
+    window: Duration,
+    failing_since: Option<Instant>,
+    backoff: Duration,
+}
+
+impl ErrorBreaker {
+    pub fn new(window: Duration) -> ErrorBreaker {
+        ErrorBreaker {
+            window,
+            failing_since: None,
+            backoff: Duration::from_millis(0),
+        }
+    }
+
+    pub fn success(&mut self) {
+        self.failing_since = None;
+        self.backoff = Duration::from_millis(0);
+    }
+
+    // Ok with how long to wait before receiving again, or the error back if it's time to stop
+    pub fn failure(&mut self, error: io::Error) -> io::Result<Duration> {
+        self.failure_at(error, Instant::now())
+    }
+
+    fn failure_at(&mut self, error: io::Error, now: Instant) -> io::Result<Duration> {
+        if is_transient(&error) {
+            return Ok(Duration::from_millis(0));
+        }
+        let since = *self.failing_since.get_or_insert(now);
+        if now.duration_since(since) >= self.window {
+            return Err(error);
+        }
+        self.backoff = cmp::min(
+            cmp::max(self.backoff * 2, Duration::from_millis(1)),
+            MAX_BACKOFF,
+        );
+        Ok(self.backoff)
+    }
+}
+
+// Send every datagram in `datagrams`, in as few syscalls as we can
+#[cfg(target_os = "linux")]
+pub fn send_batch(socket: &UdpSocket, datagrams: &[Datagram]) -> io::Result<()> {
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    use socket2::SockAddr;
+
+    for chunk in datagrams.chunks(BATCH_SIZE) {
+        let addrs: Vec<SockAddr> = chunk.iter().map(|d| SockAddr::from(d.addr)).collect();
+        let mut iovecs: Vec<libc::iovec> = chunk
+            .iter()
+            .map(|d| libc::iovec {
+                // sendmmsg doesn't write through this, despite the type
+                iov_base: d.bytes.as_ptr() as *mut libc::c_void,
+                iov_len: d.bytes.len(),
+            })
+            .collect();
+        let mut headers: Vec<libc::mmsghdr> = iovecs
+            .iter_mut()
+            .zip(addrs.iter())
+            .map(|(iovec, addr)| {
+                let mut header: libc::mmsghdr = unsafe { mem::zeroed() };
+                header.msg_hdr.msg_name = addr.as_ptr() as *mut libc::c_void;
+                header.msg_hdr.msg_namelen = addr.len();
+                header.msg_hdr.msg_iov = iovec;
+                header.msg_hdr.msg_iovlen = 1;
+                header
+            })
+            .collect();
+
+        // sendmmsg can stop partway (e.g. if the socket buffer fills), so keep going from
+        // wherever it left off
+        let mut sent = 0;
+        while sent < headers.len() {
+            let count = unsafe {
+                libc::sendmmsg(
+                    socket.as_raw_fd(),
+                    headers[sent..].as_mut_ptr(),
+                    (headers.len() - sent) as u32,
+                    0,
+                )
+            };
+            if count < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            sent += count as usize;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn send_batch(socket: &UdpSocket, datagrams: &[Datagram]) -> io::Result<()> {
+    for datagram in datagrams {
+        socket.send_to(&datagram.bytes, datagram.addr)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batches_round_trip() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client_addr = client.local_addr().unwrap();
+        let messages: Vec<Vec<u8>> = (0..3u8).map(|i| vec![i; 10 + i as usize]).collect();
+        for message in &messages {
+            client
+                .send_to(message, server.local_addr().unwrap())
+                .unwrap();
+        }
+
+        // Loopback delivery is immediate, but don't count on all three landing in one batch
+        let mut receiver = BatchReceiver::new();
+        let mut received = Vec::new();
+        while received.len() < messages.len() {
+            received.extend(receiver.recv(&server).unwrap());
+        }
+        let expected: Vec<Datagram> = messages
+            .iter()
+            .map(|bytes| Datagram {
+                bytes: bytes.to_owned(),
+                addr: client_addr,
+                local: None,
+            })
+            .collect();
+        assert_eq!(received, expected);
+
+        // Echo them all back in one batch
+        let replies: Vec<Datagram> = received
+            .iter()
+            .map(|d| Datagram {
+                bytes: d.bytes.iter().map(|b| b + 100).collect(),
+                addr: d.addr,
+                local: None,
+            })
+            .collect();
+        send_batch(&server, &replies).unwrap();
+        let mut buf = [0; MAX_DATAGRAM];
+        for reply in &replies {
+            let amt = client.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..amt], &reply.bytes[..]);
+        }
+    }
+
+    #[test]
+    fn breaker_trips_only_on_persistent_failures() {
+        let mut breaker = ErrorBreaker::new(Duration::from_secs(30));
+        let start = Instant::now();
+        let reset = || io::Error::from(io::ErrorKind::ConnectionReset);
+        let broken = || io::Error::from(io::ErrorKind::PermissionDenied);
+
+        // Port unreachables never add up to anything
+        for i in 0..1000 {
+            let later = start + Duration::from_secs(i);
+            assert_eq!(
+                breaker.failure_at(reset(), later).unwrap(),
+                Duration::from_millis(0)
+            );
+        }
+
+        // Other errors back off, and a success starts over
+        assert_eq!(
+            breaker.failure_at(broken(), start).unwrap(),
+            Duration::from_millis(1)
+        );
+        assert_eq!(
+            breaker.failure_at(broken(), start).unwrap(),
+            Duration::from_millis(2)
+        );
+        breaker.success();
+        let later = start + Duration::from_secs(20);
+        assert_eq!(
+            breaker.failure_at(broken(), later).unwrap(),
+            Duration::from_millis(1)
+        );
+        assert!(breaker
+            .failure_at(broken(), later + Duration::from_secs(29))
+            .is_ok());
+
+        // Until they've gone on for long enough
+        let error = breaker
+            .failure_at(broken(), later + Duration::from_secs(30))
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    }
+}
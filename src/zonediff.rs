@@ -0,0 +1,173 @@
+// `montague zonediff`: compare two versions of a zone, each read from a zone file or transferred
+// from a server, and print the RRsets that were added, removed, or changed. Handy for checking an
+// edited zone file against what's being served before pushing it out.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::process;
+
+use super::dns::config::ResolverOpts;
+use super::dns::protocol::{
+    self, canonical_rr_bytes, compare_names, sort_canonical, DnsResourceRecord,
+};
+use super::dns::recursive;
+use super::dns::zone::Zone;
+use super::Result;
+
+const USAGE: &str = "Usage: montague zonediff ORIGIN OLD NEW
+Compares two versions of a zone and prints the RRsets that differ. OLD and NEW
+are zone files, or @ADDRESS[:PORT] to transfer the zone from a server (AXFR).
+Exits with status 1 if the zones differ.";
+
+pub fn run(args: &[String]) -> Result<()> {
+    let (origin, old, new) = match args {
+        [flag] if flag == "-h" || flag == "--help" => {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+        [origin, old, new] => (origin, old, new),
+        _ => return Err(USAGE.into()),
+    };
+    let origin = protocol::name_from_string(origin)?;
+    let changes = diff(load(&origin, old)?, load(&origin, new)?);
+    if changes.is_empty() {
+        println!("No differences");
+        return Ok(());
+    }
+    for change in &changes {
+        print!("{}", change);
+    }
+    process::exit(1);
+}
+
+// The zone's records from a file, or from a server if the source starts with @. ALIASes only
+// exist in our zone files and can't be transferred, so they're left out of both.
+fn load(origin: &[String], source: &str) -> Result<Vec<DnsResourceRecord>> {
+    match source.strip_prefix('@') {
+        Some(server) => {
+            let server = match server.parse::<SocketAddr>() {
+                Ok(server) => server,
+                Err(_) => SocketAddr::new(server.parse::<IpAddr>()?, 53),
+            };
+            Ok(recursive::transfer(
+                origin,
+                server,
+                &ResolverOpts::default(),
+            )?)
+        }
+        None => Ok(Zone::load(origin, Path::new(source), None)?
+            .records()
+            .collect()),
+    }
+}
+
+enum RRsetChange {
+    Added(Vec<DnsResourceRecord>),
+    Removed(Vec<DnsResourceRecord>),
+    Changed(Vec<DnsResourceRecord>, Vec<DnsResourceRecord>),
+}
+
+impl fmt::Display for RRsetChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (old, new): (&[DnsResourceRecord], &[DnsResourceRecord]) = match self {
+            RRsetChange::Added(new) => (&[], new),
+            RRsetChange::Removed(old) => (old, &[]),
+            RRsetChange::Changed(old, new) => (old, new),
+        };
+        for rr in old {
+            writeln!(f, "- {}", rr)?;
+        }
+        for rr in new {
+            writeln!(f, "+ {}", rr)?;
+        }
+        Ok(())
+    }
+}
+
+// The RRsets that differ between two versions of a zone, in canonical order. An RRset has
+// changed if any of its records or its TTL has.
+fn diff(old: Vec<DnsResourceRecord>, new: Vec<DnsResourceRecord>) -> Vec<RRsetChange> {
+    let mut old = rrsets(old).into_iter().peekable();
+    let mut new = rrsets(new).into_iter().peekable();
+    let mut changes = Vec::new();
+    loop {
+        let order = match (old.peek(), new.peek()) {
+            (Some(a), Some(b)) => compare_rrsets(a, b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => return changes,
+        };
+        match order {
+            Ordering::Less => changes.push(RRsetChange::Removed(old.next().unwrap())),
+            Ordering::Greater => changes.push(RRsetChange::Added(new.next().unwrap())),
+            Ordering::Equal => {
+                let (a, b) = (old.next().unwrap(), new.next().unwrap());
+                let bytes = |set: &[DnsResourceRecord]| -> Vec<Vec<u8>> {
+                    set.iter().map(canonical_rr_bytes).collect()
+                };
+                if bytes(&a) != bytes(&b) {
+                    changes.push(RRsetChange::Changed(a, b));
+                }
+            }
+        }
+    }
+}
+
+// Records grouped into RRsets by owner, class, and type, in canonical order
+fn rrsets(mut records: Vec<DnsResourceRecord>) -> Vec<Vec<DnsResourceRecord>> {
+    sort_canonical(&mut records);
+    let mut sets: Vec<Vec<DnsResourceRecord>> = Vec::new();
+    for rr in records {
+        match sets.last_mut() {
+            Some(set) if compare_rrsets(set, std::slice::from_ref(&rr)) == Ordering::Equal => {
+                set.push(rr)
+            }
+            _ => sets.push(vec![rr]),
+        }
+    }
+    sets
+}
+
+fn compare_rrsets(a: &[DnsResourceRecord], b: &[DnsResourceRecord]) -> Ordering {
+    let (a, b) = (&a[0], &b[0]);
+    compare_names(&a.name, &b.name)
+        .then(a.class.to_u16().cmp(&b.class.to_u16()))
+        .then((a.rr_type as u16).cmp(&(b.rr_type as u16)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::dns::zone;
+
+    #[test]
+    fn changes_are_by_rrset() {
+        let origin = protocol::name_from_string("example.test").unwrap();
+        let parse = |text: &str| zone::parse_master_file(&origin, text).unwrap();
+        let old = parse(
+            "$TTL 300\n@ SOA ns hostmaster 1 2 3 4 5\nns A 192.0.2.53\n\
+             www A 192.0.2.1\nwww A 192.0.2.2\nold TXT gone\n",
+        );
+        let new = parse(
+            "$TTL 300\n@ SOA ns hostmaster 1 2 3 4 5\nNS A 192.0.2.53\n\
+             www A 192.0.2.2\nwww A 192.0.2.3\nnew TXT here\n",
+        );
+        let changes: Vec<String> = diff(old, new).iter().map(|c| c.to_string()).collect();
+        assert_eq!(changes.len(), 3);
+        assert!(changes[0].starts_with("+ new.example.test."));
+        assert!(changes[1].starts_with("- old.example.test."));
+        // A changed RRset is shown whole, old then new
+        assert_eq!(changes[2].lines().count(), 4);
+        assert!(changes[2].lines().nth(1).unwrap().ends_with("192.0.2.2"));
+        assert!(changes[2].lines().nth(2).unwrap().starts_with('+'));
+
+        let ttl = parse("@ 60 SOA ns hostmaster 1 2 3 4 5\n");
+        let same = parse("@ 60 SOA NS hostmaster 1 2 3 4 5\n");
+        let longer = parse("@ 120 SOA ns hostmaster 1 2 3 4 5\n");
+        assert!(diff(ttl.to_owned(), same).is_empty());
+        assert_eq!(diff(ttl, longer).len(), 1);
+    }
+}